@@ -0,0 +1,460 @@
+//! Typed async client for the ayaFlow agent's HTTP API.
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), ayaflow_client::Error> {
+//! use ayaflow_client::{Client, HistoryParams};
+//!
+//! let client = Client::new("http://127.0.0.1:3000")?.with_token("secret");
+//! let stats = client.stats().await?;
+//! let rows = client
+//!     .history(&HistoryParams { protocol: Some("tcp".into()), ..Default::default() })
+//!     .await?;
+//! println!("{} packets, {} stored rows", stats.total_packets, rows.len());
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Only plain `http://` is supported; put a TLS-terminating proxy in front
+//! of the agent for anything else.  Admin methods need the agent's
+//! `api.admin_token` and fail with a 401 or 404 `Error::Api` without it.
+
+mod stream;
+mod types;
+
+use std::fmt;
+use std::time::Duration;
+
+use ayaflow_common::http;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio_tungstenite::tungstenite;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+pub use ayaflow_common::api::*;
+pub use stream::EventStream;
+pub use types::*;
+
+/// Error from a client call.
+#[derive(Debug)]
+pub enum Error {
+    /// The base URL is not `http://host[:port][/base/path]`.
+    InvalidUrl(String),
+    Io(std::io::Error),
+    Timeout,
+    /// The response was not the HTTP or JSON expected.
+    Protocol(String),
+    /// The agent replied with an error body.  `status` is None for errors
+    /// pushed on `/api/stream`.
+    Api {
+        status: Option<u16>,
+        /// Machine-readable, e.g. "bad_request" or "unauthorized".
+        code: String,
+        message: String,
+    },
+    WebSocket(Box<tungstenite::Error>),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidUrl(url) => write!(f, "invalid base URL {:?}", url),
+            Error::Io(e) => e.fmt(f),
+            Error::Timeout => f.write_str("request timed out"),
+            Error::Protocol(reason) => f.write_str(reason),
+            Error::Api { status: Some(status), code, message } => {
+                write!(f, "{} ({}): {}", code, status, message)
+            }
+            Error::Api { status: None, code, message } => write!(f, "{}: {}", code, message),
+            Error::WebSocket(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::WebSocket(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<http::Error> for Error {
+    fn from(e: http::Error) -> Self {
+        match e {
+            http::Error::Io(e) => Error::Io(e),
+            http::Error::Timeout(_) => Error::Timeout,
+            http::Error::Protocol(reason) => Error::Protocol(reason),
+        }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Protocol(format!("unexpected response: {}", e))
+    }
+}
+
+impl From<tungstenite::Error> for Error {
+    fn from(e: tungstenite::Error) -> Self {
+        Error::WebSocket(Box::new(e))
+    }
+}
+
+/// `{"error": {"code": ..., "message": ...}}`, the agent's error body.
+#[derive(Deserialize)]
+pub(crate) struct ApiErrorBody {
+    error: ApiErrorDetail,
+}
+
+#[derive(Deserialize)]
+struct ApiErrorDetail {
+    code: String,
+    message: String,
+}
+
+impl ApiErrorBody {
+    pub(crate) fn into_error(self, status: Option<u16>) -> Error {
+        Error::Api { status, code: self.error.code, message: self.error.message }
+    }
+}
+
+/// Body of `PUT /api/blocklist`.
+#[derive(Serialize)]
+struct BlocklistUpdate<'a> {
+    entries: &'a [String],
+}
+
+/// A handle on one agent.  Cheap to clone; every call opens its own
+/// connection.
+#[derive(Debug, Clone)]
+pub struct Client {
+    /// `host:port`.
+    host: String,
+    /// The agent's `api.base_path`, without a trailing slash.
+    base_path: String,
+    token: Option<String>,
+    timeout: Duration,
+}
+
+impl Client {
+    /// A client for the agent at `base_url`, e.g. `http://10.0.0.2:3000` or
+    /// `http://proxy/ayaflow` for an agent served under a base path.
+    pub fn new(base_url: &str) -> Result<Self, Error> {
+        let invalid = || Error::InvalidUrl(base_url.to_string());
+        let rest = base_url.strip_prefix("http://").ok_or_else(invalid)?;
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => rest.split_at(slash),
+            None => (rest, ""),
+        };
+        if authority.is_empty() || authority.contains('@') || path.contains(['?', '#']) {
+            return Err(invalid());
+        }
+        // A port is required to connect; default it unless the authority
+        // already ends in one (an IPv6 literal ends in "]").
+        let has_port = authority.rsplit_once(':').is_some_and(|(_, port)| !port.contains(']'));
+        let host = if has_port { authority.to_string() } else { format!("{}:80", authority) };
+        Ok(Self {
+            host,
+            base_path: path.trim_end_matches('/').to_string(),
+            token: None,
+            timeout: Duration::from_secs(30),
+        })
+    }
+
+    /// Send `Authorization: Bearer <token>`, as admin routes require.
+    pub fn with_token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// Give up on a request after `timeout` (30 seconds by default),
+    /// including the time to read the body.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Health is returned for a 503 too: the agent answers with the same
+    /// body when a critical component is down.
+    pub async fn health(&self) -> Result<HealthResponse, Error> {
+        let response = self.send("GET", "/api/health", None).await?;
+        if response.status == 503 {
+            return Ok(serde_json::from_slice(&response.body)?);
+        }
+        decode(response)
+    }
+
+    pub async fn version(&self) -> Result<VersionInfo, Error> {
+        self.get("/api/version", &()).await
+    }
+
+    pub async fn stats(&self) -> Result<StatsResponse, Error> {
+        self.get("/api/stats", &()).await
+    }
+
+    pub async fn live(&self) -> Result<LiveResponse, Error> {
+        self.get("/api/live", &()).await
+    }
+
+    pub async fn connections(&self, params: &ConnectionsParams) -> Result<ConnectionPage, Error> {
+        self.get("/api/connections", params).await
+    }
+
+    /// Every live connection, from a JSON lines `/api/connections/export`.
+    pub async fn export_connections(&self) -> Result<ConnectionExport, Error> {
+        let response = self.send("GET", "/api/connections/export?format=jsonl", None).await?;
+        check(&response)?;
+        parse_export(&response.body)
+    }
+
+    pub async fn history(&self, params: &HistoryParams) -> Result<Vec<HistoryRow>, Error> {
+        self.get("/api/history", params).await
+    }
+
+    pub async fn alerts(&self, params: &AlertParams) -> Result<Vec<StoredAlert>, Error> {
+        self.get("/api/alerts", params).await
+    }
+
+    /// Sensors that pushed to this agent since it started.
+    pub async fn fleet(&self) -> Result<Vec<FleetMember>, Error> {
+        self.get("/api/fleet", &()).await
+    }
+
+    pub async fn blocklist(&self) -> Result<BlocklistStatus, Error> {
+        self.get("/api/blocklist", &()).await
+    }
+
+    /// Any GET endpoint as untyped JSON, for those without a method here.
+    pub async fn get_json(&self, path: &str) -> Result<serde_json::Value, Error> {
+        decode(self.send("GET", path, None).await?)
+    }
+
+    /// Totals every second, plus a connection watch once subscribed.
+    pub async fn stream(&self) -> Result<EventStream, Error> {
+        let url = format!("ws://{}{}/api/stream", self.host, self.base_path);
+        let mut request = url.into_client_request()?;
+        if let Some(token) = &self.token {
+            let value = format!("Bearer {}", token)
+                .parse()
+                .map_err(|_| Error::Protocol("token is not a valid header value".into()))?;
+            request.headers_mut().insert("authorization", value);
+        }
+        let connect = tokio_tungstenite::connect_async(request);
+        let (socket, _) = tokio::time::timeout(self.timeout, connect)
+            .await
+            .map_err(|_| Error::Timeout)??;
+        Ok(EventStream::new(socket))
+    }
+
+    // ── Admin ─────────────────────────────────────────────────────────────────
+
+    /// Zero the live counters and connection table, and with `include_db`
+    /// delete every stored packet.
+    pub async fn reset(&self, include_db: bool) -> Result<ResetResponse, Error> {
+        let path = format!("/api/admin/reset?include_db={}", include_db);
+        decode(self.send("POST", &path, None).await?)
+    }
+
+    /// The running config, secrets redacted.  Untyped: its shape follows
+    /// the agent's config file.
+    pub async fn config(&self) -> Result<serde_json::Value, Error> {
+        self.get_json("/api/config").await
+    }
+
+    /// The `/api/debug/dump` diagnostic bundle, untyped.
+    pub async fn debug_dump(&self) -> Result<serde_json::Value, Error> {
+        self.get_json("/api/debug/dump").await
+    }
+
+    /// Replace the blocklist with `entries`, addresses or CIDRs.
+    pub async fn set_blocklist(&self, entries: &[String]) -> Result<BlocklistStatus, Error> {
+        let body = serde_json::to_vec(&BlocklistUpdate { entries })?;
+        decode(self.send("PUT", "/api/blocklist", Some(body)).await?)
+    }
+
+    /// Mark an alert handled; `by` defaults to "admin" on the agent.
+    pub async fn ack_alert(&self, id: i64, by: Option<&str>) -> Result<StoredAlert, Error> {
+        let mut path = format!("/api/alerts/{}/ack", id);
+        if let Some(by) = by {
+            path.push('?');
+            path.push_str(&query(&[("by", by)])?);
+        }
+        decode(self.send("POST", &path, None).await?)
+    }
+
+    /// The whole database as a SQLite file.
+    pub async fn export_snapshot(&self) -> Result<Vec<u8>, Error> {
+        let response = self.send("GET", "/api/export/snapshot", None).await?;
+        check(&response)?;
+        Ok(response.body)
+    }
+
+    pub async fn backfill_dns(&self) -> Result<BackfillProgress, Error> {
+        self.get("/api/admin/backfill-dns", &()).await
+    }
+
+    /// Reverse DNS cache statistics; fails with a 409 unless the agent
+    /// runs with `resolve_dns`.
+    pub async fn dns_cache(&self) -> Result<DnsCacheStats, Error> {
+        self.get("/api/dns/cache", &()).await
+    }
+
+    /// Drop `ip`'s reverse DNS cache entry, or every entry.
+    pub async fn flush_dns(&self, ip: Option<std::net::IpAddr>) -> Result<DnsFlushResponse, Error> {
+        let path = match ip {
+            Some(ip) => format!("/api/dns/flush?ip={}", ip),
+            None => "/api/dns/flush".to_string(),
+        };
+        decode(self.send("POST", &path, None).await?)
+    }
+
+    /// Start a hostname backfill; fails with a 409 while one is running.
+    pub async fn start_backfill_dns(
+        &self,
+        params: &BackfillParams,
+    ) -> Result<BackfillProgress, Error> {
+        let path = format!("/api/admin/backfill-dns?{}", query(params)?);
+        decode(self.send("POST", &path, None).await?)
+    }
+
+    async fn get<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        params: &impl Serialize,
+    ) -> Result<T, Error> {
+        let query = query(params)?;
+        let target = match query.is_empty() {
+            true => path.to_string(),
+            false => format!("{}?{}", path, query),
+        };
+        decode(self.send("GET", &target, None).await?)
+    }
+
+    async fn send(
+        &self,
+        method: &str,
+        path: &str,
+        json_body: Option<Vec<u8>>,
+    ) -> Result<http::Response, Error> {
+        let target = format!("{}{}", self.base_path, path);
+        let authorization = self.token.as_ref().map(|token| format!("Bearer {}", token));
+        let mut headers = vec![("Accept", "application/json")];
+        if let Some(authorization) = &authorization {
+            headers.push(("Authorization", authorization));
+        }
+        let body = json_body.unwrap_or_default();
+        if !body.is_empty() {
+            headers.push(("Content-Type", "application/json"));
+        }
+        let request = http::Request {
+            method,
+            target: &target,
+            host: &self.host,
+            headers: &headers,
+            body: &body,
+        };
+        Ok(http::send(&self.host, &request, self.timeout).await?)
+    }
+}
+
+fn query(params: &impl Serialize) -> Result<String, Error> {
+    serde_urlencoded::to_string(params)
+        .map_err(|e| Error::Protocol(format!("cannot encode query: {}", e)))
+}
+
+/// A JSON lines dump: `{"snapshot": ...}`, then one connection per line.
+fn parse_export(body: &[u8]) -> Result<ConnectionExport, Error> {
+    #[derive(Deserialize)]
+    struct HeaderLine {
+        snapshot: DumpHeader,
+    }
+    let mut lines = body.split(|&b| b == b'\n').filter(|line| !line.is_empty());
+    let first = lines.next().ok_or_else(|| Error::Protocol("empty export".into()))?;
+    let header: HeaderLine = serde_json::from_slice(first)?;
+    let connections = lines.map(serde_json::from_slice).collect::<Result<_, _>>()?;
+    Ok(ConnectionExport { header: header.snapshot, connections })
+}
+
+fn check(response: &http::Response) -> Result<(), Error> {
+    if (200..300).contains(&response.status) {
+        return Ok(());
+    }
+    let status = Some(response.status);
+    match serde_json::from_slice::<ApiErrorBody>(&response.body) {
+        Ok(body) => Err(body.into_error(status)),
+        Err(_) => Err(Error::Api {
+            status,
+            code: "http".into(),
+            message: String::from_utf8_lossy(&response.body).into_owned(),
+        }),
+    }
+}
+
+fn decode<T: DeserializeOwned>(response: http::Response) -> Result<T, Error> {
+    check(&response)?;
+    Ok(serde_json::from_slice(&response.body)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base_urls() {
+        let client = Client::new("http://127.0.0.1:3000").unwrap();
+        assert_eq!((client.host.as_str(), client.base_path.as_str()), ("127.0.0.1:3000", ""));
+        let client = Client::new("http://proxy/ayaflow/").unwrap();
+        assert_eq!((client.host.as_str(), client.base_path.as_str()), ("proxy:80", "/ayaflow"));
+        assert_eq!(Client::new("http://[::1]").unwrap().host, "[::1]:80");
+        assert_eq!(Client::new("http://[::1]:8080/").unwrap().host, "[::1]:8080");
+        for url in ["https://host", "host:3000", "http://", "http://u@host", "http://h/?q"] {
+            assert!(matches!(Client::new(url), Err(Error::InvalidUrl(_))), "{}", url);
+        }
+    }
+
+    #[test]
+    fn test_query_skips_unset_params() {
+        assert_eq!(query(&HistoryParams::default()).unwrap(), "");
+        let params = HistoryParams {
+            limit: Some(5),
+            ip: Some("10.0.0.1".parse().unwrap()),
+            direction: Some(FlowDirection::Inbound),
+            ..Default::default()
+        };
+        assert_eq!(query(&params).unwrap(), "limit=5&ip=10.0.0.1&direction=inbound");
+        let params =
+            ConnectionsParams { sort: Some(ConnectionSort::LastSeen), ..Default::default() };
+        assert_eq!(query(&params).unwrap(), "sort=last_seen");
+    }
+
+    #[test]
+    fn test_parse_export() {
+        let body = concat!(
+            r#"{"snapshot":{"taken_at":"2026-01-01T00:00:00.000Z","taken_at_ms":0,"#,
+            r#""connections":0,"total_packets":5,"total_bytes":300}}"#,
+            "\n",
+        );
+        let export = parse_export(body.as_bytes()).unwrap();
+        assert_eq!((export.header.total_packets, export.connections.len()), (5, 0));
+        assert!(matches!(parse_export(b""), Err(Error::Protocol(_))));
+        assert!(matches!(parse_export(b"{}\n"), Err(Error::Protocol(_))));
+    }
+
+    #[test]
+    fn test_error_bodies() {
+        let body = br#"{"error":{"code":"unauthorized","message":"missing token"}}"#;
+        let response = http::Response { status: 401, body: body.to_vec() };
+        let error = check(&response).unwrap_err();
+        assert_eq!(error.to_string(), "unauthorized (401): missing token");
+        let response = http::Response { status: 502, body: b"bad gateway".to_vec() };
+        assert!(matches!(check(&response), Err(Error::Api { code, .. }) if code == "http"));
+    }
+}
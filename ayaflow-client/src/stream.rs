@@ -0,0 +1,78 @@
+//! The `/api/stream` WebSocket as a stream of typed pushes.
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_util::{SinkExt, Stream, StreamExt};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+use crate::types::ConnectionFilter;
+use crate::StreamEvent;
+use crate::{ApiErrorBody, Error};
+
+/// Pushes from `/api/stream`, one per second and one after each
+/// subscription change.  A message the agent rejected yields
+/// `Error::Api` and the stream goes on.
+pub struct EventStream {
+    socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+
+impl EventStream {
+    pub(crate) fn new(socket: WebSocketStream<MaybeTlsStream<TcpStream>>) -> Self {
+        Self { socket }
+    }
+
+    /// Also push the connections matching `filter`, replacing any earlier
+    /// subscription.
+    pub async fn watch(&mut self, filter: &ConnectionFilter) -> Result<(), Error> {
+        self.send(serde_json::json!({ "watch": filter })).await
+    }
+
+    /// Go back to totals only.
+    pub async fn unwatch(&mut self) -> Result<(), Error> {
+        self.send(serde_json::json!({ "watch": null })).await
+    }
+
+    pub async fn close(mut self) -> Result<(), Error> {
+        self.socket.close(None).await?;
+        Ok(())
+    }
+
+    async fn send(&mut self, message: serde_json::Value) -> Result<(), Error> {
+        self.socket.send(Message::Text(message.to_string())).await?;
+        Ok(())
+    }
+}
+
+impl Stream for EventStream {
+    type Item = Result<StreamEvent, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            let message = match self.socket.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(message))) => message,
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e.into()))),
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            };
+            let text = match message {
+                Message::Text(text) => text,
+                Message::Close(_) => return Poll::Ready(None),
+                // Pings are answered by tungstenite.
+                _ => continue,
+            };
+            return Poll::Ready(Some(parse_frame(&text)));
+        }
+    }
+}
+
+fn parse_frame(text: &str) -> Result<StreamEvent, Error> {
+    let value: serde_json::Value = serde_json::from_str(text)?;
+    if value.get("error").is_some() {
+        let body: ApiErrorBody = serde_json::from_value(value)?;
+        return Err(body.into_error(None));
+    }
+    Ok(serde_json::from_value(value)?)
+}
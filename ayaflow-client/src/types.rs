@@ -0,0 +1,107 @@
+//! Request parameters of the agent's API, and the export as one value.
+//!
+//! The response bodies are the agent's own, from `ayaflow_common::api`, and
+//! re-exported at the crate root.
+
+use std::net::IpAddr;
+
+use ayaflow_common::api::{
+    Cast, ConnectionId, ConnectionSort, DumpHeader, DumpedConnection, FlowDirection, SortOrder,
+};
+use serde::Serialize;
+
+/// Filters for `/api/history`; every field is optional.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct HistoryParams {
+    pub limit: Option<usize>,
+    /// Earliest timestamp, milliseconds since the Unix epoch.
+    pub from: Option<i64>,
+    /// Latest timestamp, milliseconds since the Unix epoch.
+    pub to: Option<i64>,
+    pub ip: Option<IpAddr>,
+    pub interface: Option<String>,
+    /// "aa:bb:cc:dd:ee:ff".
+    pub mac: Option<String>,
+    pub direction: Option<FlowDirection>,
+    /// Port category, e.g. "web".
+    pub category: Option<String>,
+    pub instance: Option<String>,
+    /// e.g. "tcp" or "icmp".
+    pub protocol: Option<String>,
+    pub icmp_type: Option<u8>,
+}
+
+/// Sorting, paging and filters for `/api/connections`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ConnectionsParams {
+    pub sort: Option<ConnectionSort>,
+    pub order: Option<SortOrder>,
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+    pub ip: Option<IpAddr>,
+    pub port: Option<u16>,
+    pub protocol: Option<String>,
+    pub interface: Option<String>,
+    pub direction: Option<FlowDirection>,
+    /// Destination class; every class when unset.
+    pub cast: Option<Cast>,
+    /// Matches both directions.
+    pub connection_id: Option<ConnectionId>,
+}
+
+/// Filters for `/api/alerts`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct AlertParams {
+    pub limit: Option<usize>,
+    /// Only alerts with a lower id: pass the last id of a page to get the
+    /// next one.
+    pub before_id: Option<i64>,
+    /// "info", "warning" or "critical".
+    pub severity: Option<String>,
+    pub rule: Option<String>,
+    /// Milliseconds since the Unix epoch.
+    pub since: Option<i64>,
+    pub acked: Option<bool>,
+    pub instance: Option<String>,
+}
+
+/// Options for starting a hostname backfill; unset fields take the agent's
+/// defaults.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct BackfillParams {
+    /// Only packets captured at or after this time (epoch ms).
+    pub since: Option<i64>,
+    pub batch: Option<usize>,
+    /// Reverse lookups started per second, at most.
+    pub rate: Option<u32>,
+    pub concurrency: Option<usize>,
+    pub skip_private: bool,
+    /// Start from the first row instead of where the last run stopped.
+    pub restart: bool,
+}
+
+/// A `/api/stream` watch subscription.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ConnectionFilter {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip: Option<IpAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interface: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub direction: Option<FlowDirection>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cast: Option<Cast>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connection_id: Option<ConnectionId>,
+}
+
+/// A `/api/connections/export` dump.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionExport {
+    pub header: DumpHeader,
+    pub connections: Vec<DumpedConnection>,
+}
@@ -0,0 +1,1174 @@
+//! Response bodies of the agent's HTTP API, shared by the agent, which
+//! serves them, and `ayaflow-client`, which reads them.
+//!
+//! Each is defined once, here.  The agent's connection table holds live
+//! state (instants, table keys) and serializes its entries by hand; for
+//! those this module has the wire form, `ConnectionEntry` and
+//! `ConnectionStats`, and the agent documents its schema with them.
+
+use core::fmt;
+use core::str::FromStr;
+use core::time::Duration;
+use std::format;
+use std::string::String;
+use std::vec::Vec;
+
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::api_schema;
+use crate::openapi::{object_schema, string_enum, ApiSchema, Value};
+use crate::{BLOCKLIST_DROPPED, BLOCKLIST_MATCHED};
+
+// ── Health ────────────────────────────────────────────────────────────────────
+
+/// Health of one component, or of the agent as a whole.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ComponentStatus {
+    Ok,
+    /// Running, but its last operation failed (or a non-critical component
+    /// is down).
+    Degraded,
+    /// Stopped, panicked, or silent for longer than its heartbeat deadline.
+    Down,
+}
+
+impl ApiSchema for ComponentStatus {
+    fn schema() -> Value {
+        string_enum(&["ok", "degraded", "down"])
+    }
+}
+
+/// Whether this instance captures traffic, reported by `/api/health`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CaptureState {
+    Enabled,
+    Disabled,
+}
+
+impl ApiSchema for CaptureState {
+    fn schema() -> Value {
+        string_enum(&["enabled", "disabled"])
+    }
+}
+
+api_schema! {
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct HealthResponse {
+        pub status: ComponentStatus,
+        /// "disabled" in API-only mode: live counters stay at zero.
+        pub capture: CaptureState,
+        pub active_connections: usize,
+        pub total_packets: u64,
+        pub components: Vec<ComponentHealth>,
+        /// Present while dual-writing to `secondary_db_url`.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub secondary_storage: Option<SecondaryStatus>,
+        /// Present once a file was written to `connection_snapshots.dir`.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub connection_snapshot: Option<SnapshotInfo>,
+    }
+}
+
+api_schema! {
+    /// Status of one background task as reported by `/api/health`.
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct ComponentHealth {
+        pub name: String,
+        pub status: ComponentStatus,
+        /// Whether this component being down takes the whole agent down.
+        pub critical: bool,
+        pub last_heartbeat_ms_ago: u64,
+        pub last_error: Option<String>,
+        pub last_error_ms_ago: Option<u64>,
+    }
+}
+
+api_schema! {
+    /// How far the `secondary_db_url` backend trails the primary, in
+    /// `/api/health`.
+    #[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+    pub struct SecondaryStatus {
+        /// Rows the primary committed since startup that the secondary has
+        /// not.
+        pub lag_rows: u64,
+        /// Writer events waiting for the secondary.
+        pub queued_events: usize,
+        /// Events dropped because that queue was full; their rows never
+        /// reach the secondary.
+        pub dropped_events: u64,
+        /// Failed transactions and row inserts, including ones retried.
+        pub write_failures: u64,
+    }
+}
+
+api_schema! {
+    /// The newest connection snapshot written to `connection_snapshots.dir`.
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct SnapshotInfo {
+        pub path: String,
+        pub taken_at_ms: i64,
+        /// Connections written to the file.
+        pub connections: u64,
+        pub bytes: u64,
+    }
+}
+
+// ── Version ───────────────────────────────────────────────────────────────────
+
+api_schema! {
+    /// Build and runtime details of this agent.
+    #[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+    pub struct VersionInfo {
+        pub version: String,
+        /// Absent when built without a git checkout.
+        pub git_commit: Option<String>,
+        /// Absent when built without a `Cargo.lock`.
+        pub aya_version: Option<String>,
+        /// SHA-256 of the embedded eBPF object, lowercase hex.
+        pub ebpf_sha256: String,
+        /// Event layout hash the object was built for; absent for objects
+        /// from before it was embedded.
+        pub ebpf_abi_hash: Option<String>,
+        /// Absent when `/proc` cannot be read.
+        pub kernel_release: Option<String>,
+        /// Empty in API-only mode.
+        pub attach: AttachStatus,
+        /// Network interfaces on the host, sorted.
+        pub interfaces: Vec<String>,
+        /// Whether the kernel exposes its BTF in `/sys/kernel/btf/vmlinux`.
+        pub btf_available: bool,
+        /// Empty in API-only mode.
+        pub loaded: LoadedObject,
+    }
+}
+
+api_schema! {
+    /// Where the eBPF programs are attached.
+    #[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+    pub struct AttachStatus {
+        pub interface: String,
+        /// One entry per attached hook, e.g. "tc ingress".
+        pub hooks: Vec<String>,
+        /// Whether ayaflow added the clsact qdisc (and removes it on exit).
+        pub created_qdisc: bool,
+    }
+}
+
+api_schema! {
+    /// The eBPF programs and maps as the kernel reports them after loading.
+    #[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+    pub struct LoadedObject {
+        pub programs: Vec<LoadedProgram>,
+        pub maps: Vec<LoadedMap>,
+    }
+}
+
+api_schema! {
+    /// One loaded eBPF program.
+    #[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+    pub struct LoadedProgram {
+        pub name: String,
+        /// Instructions the verifier processed; absent before kernel 5.16.
+        pub verified_instructions: Option<u32>,
+        /// Bytecode size after the verifier's rewrites; absent when the
+        /// kernel does not report it.
+        pub translated_bytes: Option<u32>,
+        /// Zero when the JIT is off.
+        pub jited_bytes: u32,
+    }
+}
+
+api_schema! {
+    /// One map used by the loaded programs.
+    #[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+    pub struct LoadedMap {
+        /// As the kernel stores it, truncated to 15 bytes.
+        pub name: String,
+        pub key_size: u32,
+        pub value_size: u32,
+        pub max_entries: u32,
+    }
+}
+
+// ── Stats ─────────────────────────────────────────────────────────────────────
+
+api_schema! {
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct StatsResponse {
+        pub uptime_seconds: u64,
+        pub total_packets: u64,
+        pub total_bytes: u64,
+        pub active_connections: usize,
+        /// Lifetime averages (totals divided by uptime).
+        pub packets_per_second: f64,
+        pub bytes_per_second: f64,
+        /// Rates over the last 1s and 60s.
+        pub pps_1s: f64,
+        pub pps_60s: f64,
+        pub bps_1s: f64,
+        pub bps_60s: f64,
+        /// Live TCP connections per best-effort state.
+        pub tcp_states: TcpStateCounts,
+        /// Totals per direction relative to `local_networks`, across all
+        /// interfaces.
+        pub flow_directions: FlowDirectionTotals,
+        /// Totals per destination class, across all interfaces.
+        pub casts: CastTotals,
+        /// Connection entries created and expired, across all interfaces.
+        pub connections_created_total: u64,
+        pub connections_expired_total: u64,
+        /// New connections per second over the last 1s and 60s, across all
+        /// interfaces.
+        pub new_connections_1s: f64,
+        pub new_connections_60s: f64,
+        /// Connections the idle sweep removed per second over the last 60s.
+        pub expired_connections_60s: f64,
+        /// Kernel time in the eBPF programs; absent without `bpf_stats`
+        /// support.
+        pub bpf_runtime: Option<BpfRuntimeSummary>,
+        /// Peak utilization and capacity left over the last minute; absent
+        /// without capture and for the first second.
+        pub headroom: Option<HeadroomReport>,
+    }
+}
+
+api_schema! {
+    /// Live TCP connections in each state, one per direction.
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+    pub struct TcpStateCounts {
+        pub new: usize,
+        pub established: usize,
+        pub closing: usize,
+        pub closed: usize,
+    }
+}
+
+impl TcpStateCounts {
+    /// `(state, count)` pairs, for exporting as labels.
+    pub fn by_state(&self) -> [(&'static str, usize); 4] {
+        [
+            ("new", self.new),
+            ("established", self.established),
+            ("closing", self.closing),
+            ("closed", self.closed),
+        ]
+    }
+}
+
+api_schema! {
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+    pub struct DirectionTotals {
+        pub packets: u64,
+        pub bytes: u64,
+    }
+}
+
+api_schema! {
+    /// Lifetime totals per direction relative to `local_networks`.
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+    pub struct FlowDirectionTotals {
+        /// Remote to local: downloads.
+        pub inbound: DirectionTotals,
+        /// Local to remote: uploads.
+        pub outbound: DirectionTotals,
+        pub internal: DirectionTotals,
+        pub external: DirectionTotals,
+    }
+}
+
+api_schema! {
+    /// Lifetime totals per destination class.
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+    pub struct CastTotals {
+        pub unicast: DirectionTotals,
+        pub multicast: DirectionTotals,
+        pub broadcast: DirectionTotals,
+    }
+}
+
+api_schema! {
+    /// Kernel time spent in the eBPF programs, from `bpf_stats`.
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct BpfRuntimeSummary {
+        /// Program runs, one per packet per hook.
+        pub run_count: u64,
+        pub runtime_ns: u64,
+        /// Runtime divided by runs.
+        pub avg_ns_per_packet: f64,
+    }
+}
+
+api_schema! {
+    /// Peak utilization over the last minute; see "Capacity headroom" in the
+    /// README.
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct HeadroomReport {
+        /// Seconds the figures cover, up to 60.
+        pub window_seconds: f64,
+        /// Largest ring buffer drain and its share of the batch the poller
+        /// reads at most; a full batch means events were waiting.
+        pub ring_batch_peak: usize,
+        pub ring_batch_ratio: f64,
+        /// Fullest internal channel, as a share of its capacity.
+        pub queue_peak_ratio: f64,
+        pub queue_peak_name: Option<String>,
+        /// Longest storage flush and its share of the flush interval.
+        pub flush_peak_ms: f64,
+        pub flush_ratio: f64,
+        /// Process CPU time per second, in cores, and its share of those
+        /// available.
+        pub cpu_cores: f64,
+        pub cpu_ratio: f64,
+        /// 1 minus the largest ratio above, at least 0.
+        pub headroom_ratio: f64,
+        /// Which ratio sets `headroom_ratio`: "ring_buffer", "queue",
+        /// "flush" or "cpu".
+        pub limited_by: String,
+    }
+}
+
+impl HeadroomReport {
+    /// Each ratio by the name `limited_by` uses.
+    pub fn ratios(&self) -> [(&'static str, f64); 4] {
+        [
+            ("ring_buffer", self.ring_batch_ratio),
+            ("queue", self.queue_peak_ratio),
+            ("flush", self.flush_ratio),
+            ("cpu", self.cpu_ratio),
+        ]
+    }
+
+    /// The report over `samples`; None without any.
+    pub fn estimate(samples: &[HeadroomSample], limits: HeadroomLimits) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+        let share = |value: f64, limit: f64| if limit > 0.0 { value / limit } else { 0.0 };
+        let elapsed: Duration = samples.iter().map(|s| s.elapsed).sum();
+        let cpu: Duration = samples.iter().map(|s| s.cpu).sum();
+        let ring_batch_peak = samples.iter().map(|s| s.drain_peak).max().unwrap_or(0);
+        let flush_peak = samples.iter().map(|s| s.flush_peak).max().unwrap_or_default();
+        let queue_peak = samples
+            .iter()
+            .filter_map(|s| s.queue_peak.as_ref())
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+        let cpu_cores = share(cpu.as_secs_f64(), elapsed.as_secs_f64());
+
+        let mut report = Self {
+            window_seconds: elapsed.as_secs_f64(),
+            ring_batch_peak,
+            ring_batch_ratio: share(ring_batch_peak as f64, limits.drain_batch as f64),
+            queue_peak_ratio: queue_peak.map_or(0.0, |(_, ratio)| *ratio),
+            queue_peak_name: queue_peak.map(|(name, _)| name.clone()),
+            flush_peak_ms: flush_peak.as_secs_f64() * 1000.0,
+            flush_ratio: share(flush_peak.as_secs_f64(), limits.flush_interval.as_secs_f64()),
+            cpu_cores,
+            cpu_ratio: share(cpu_cores, limits.cores as f64),
+            headroom_ratio: 1.0,
+            limited_by: String::new(),
+        };
+        // The first of equal ratios names the limit, so an idle sensor is
+        // limited by the ring buffer at headroom 1.
+        let (limited_by, peak) = report
+            .ratios()
+            .into_iter()
+            .fold(("ring_buffer", f64::MIN), |max, r| if r.1 > max.1 { r } else { max });
+        report.headroom_ratio = (1.0 - peak).max(0.0);
+        report.limited_by = limited_by.into();
+        Some(report)
+    }
+}
+
+/// One sampling interval's peaks, for `HeadroomReport::estimate`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HeadroomSample {
+    pub elapsed: Duration,
+    pub drain_peak: usize,
+    /// The deepest any channel got in the interval, and that depth over
+    /// its capacity.
+    pub queue_peak: Option<(String, f64)>,
+    pub flush_peak: Duration,
+    /// Process CPU time, user and system, used during the interval.
+    pub cpu: Duration,
+}
+
+/// What `HeadroomReport::estimate` measures the samples against.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HeadroomLimits {
+    /// Most events the poller reads per drain.
+    pub drain_batch: usize,
+    /// How often the storage writer flushes.
+    pub flush_interval: Duration,
+    pub cores: usize,
+}
+
+// ── Connections ───────────────────────────────────────────────────────────────
+
+/// A flow's direction relative to the local networks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FlowDirection {
+    /// Remote source, local destination (download).
+    Inbound,
+    /// Local source, remote destination (upload).
+    Outbound,
+    /// Both ends local.
+    Internal,
+    /// Neither end local.
+    External,
+}
+
+impl FlowDirection {
+    pub const ALL: [FlowDirection; 4] = [
+        FlowDirection::Inbound,
+        FlowDirection::Outbound,
+        FlowDirection::Internal,
+        FlowDirection::External,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FlowDirection::Inbound => "inbound",
+            FlowDirection::Outbound => "outbound",
+            FlowDirection::Internal => "internal",
+            FlowDirection::External => "external",
+        }
+    }
+}
+
+impl FromStr for FlowDirection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        FlowDirection::ALL
+            .into_iter()
+            .find(|d| d.as_str() == s)
+            .ok_or_else(|| format!("invalid flow direction {:?}", s))
+    }
+}
+
+impl ApiSchema for FlowDirection {
+    fn schema() -> Value {
+        string_enum(&["inbound", "outbound", "internal", "external"])
+    }
+}
+
+/// Whether a packet's destination is one host, a group or a whole network.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Cast {
+    Unicast,
+    /// 224.0.0.0/4 or ff00::/8, e.g. mDNS and SSDP.
+    Multicast,
+    /// 255.255.255.255 or a local network's broadcast address.
+    Broadcast,
+}
+
+impl ApiSchema for Cast {
+    fn schema() -> Value {
+        string_enum(&["unicast", "multicast", "broadcast"])
+    }
+}
+
+/// Best-effort TCP state of one direction of a connection.
+///
+/// The live table keys each direction separately and may miss packets, so
+/// this is inferred from whatever flags were seen: a flow first seen
+/// mid-stream counts as established, and `closed` needs a FIN from both
+/// directions or a RST from either.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TcpState {
+    /// SYN or SYN+ACK seen; the handshake is not yet acknowledged.
+    New,
+    Established,
+    /// FIN seen in this direction only.
+    Closing,
+    /// FIN seen in both directions, or a RST.
+    Closed,
+}
+
+impl ApiSchema for TcpState {
+    fn schema() -> Value {
+        string_enum(&["new", "established", "closing", "closed"])
+    }
+}
+
+/// What the classifier did with a packet that matched the blocklist.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlocklistMatch {
+    /// Let through and reported.
+    Flagged,
+    /// Dropped by the hook.
+    Dropped,
+}
+
+impl BlocklistMatch {
+    /// Decode `PacketEvent::blocklist`.
+    pub fn from_ebpf(value: u8) -> Option<Self> {
+        match value {
+            BLOCKLIST_MATCHED => Some(BlocklistMatch::Flagged),
+            BLOCKLIST_DROPPED => Some(BlocklistMatch::Dropped),
+            _ => None,
+        }
+    }
+}
+
+impl ApiSchema for BlocklistMatch {
+    fn schema() -> Value {
+        string_enum(&["flagged", "dropped"])
+    }
+}
+
+/// Stable identifier of a connection, served as `connection_id`: 64-bit
+/// FNV-1a over the canonical 4-tuple, as 16 lowercase hex digits.
+///
+/// The canonical 4-tuple is 36 bytes, both endpoints as 16 address bytes
+/// (IPv4 mapped into IPv6) and 2 big-endian port bytes, the endpoint whose
+/// 18 bytes compare lower first.  Both directions therefore share an id,
+/// and anyone can compute it from the addresses and ports alone.  The
+/// protocol is left out because the table is keyed without it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ConnectionId(pub u64);
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+impl FromStr for ConnectionId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("{:?} is not a connection_id (16 hex digits)", s);
+        if s.len() != 16 || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(invalid());
+        }
+        u64::from_str_radix(s, 16).map(Self).map_err(|_| invalid())
+    }
+}
+
+impl Serialize for ConnectionId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ConnectionId {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl ApiSchema for ConnectionId {
+    fn schema() -> Value {
+        serde_json::json!({ "type": "string", "pattern": "^[0-9a-f]{16}$" })
+    }
+}
+
+/// Sort column for connection queries.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionSort {
+    Bytes,
+    #[default]
+    Packets,
+    LastSeen,
+    /// Current throughput (`instant_bps`).
+    Rate,
+}
+
+impl ApiSchema for ConnectionSort {
+    fn schema() -> Value {
+        string_enum(&["bytes", "packets", "last_seen", "rate"])
+    }
+}
+
+/// Sort direction for connection queries.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+impl ApiSchema for SortOrder {
+    fn schema() -> Value {
+        string_enum(&["asc", "desc"])
+    }
+}
+
+api_schema! {
+    /// One direction of a live connection as served.
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct ConnectionStats {
+        pub protocol: String,
+        pub bytes_sent: u64,
+        pub bytes_received: u64,
+        pub packets_count: u64,
+        /// Transport payload bytes in both directions.
+        pub payload_bytes: u64,
+        /// Lowest and highest TTL / hop limit seen.
+        pub ttl_min: Option<u8>,
+        pub ttl_max: Option<u8>,
+        /// TCP data segments whose sequence number did not advance, and
+        /// their share of the packets.
+        pub retransmits: u32,
+        pub retransmit_ratio: f64,
+        /// Interface the most recent packet was seen on.
+        pub interface: String,
+        /// Direction relative to `local_networks`.
+        pub flow_direction: Option<FlowDirection>,
+        /// Class of the destination address.
+        pub cast: Option<Cast>,
+        /// GeoIP country of the remote end, looked up when the connection
+        /// was first seen; absent without `geoip.country_db`.
+        pub country: Option<String>,
+        /// Ethernet addresses of the most recent packet; absent on L3
+        /// interfaces and for kernel-aggregated flows.
+        pub src_mac: Option<String>,
+        pub dst_mac: Option<String>,
+        /// Absent for other protocols and kernel-aggregated flows.
+        pub tcp_state: Option<TcpState>,
+        /// What the classifier last did with a blocklisted packet; absent
+        /// if none matched.
+        pub blocklist: Option<BlocklistMatch>,
+        /// Whether the reverse direction was seen.
+        pub bidirectional: bool,
+        /// Bytes per second over the last rate sample.
+        pub instant_bps: u64,
+        /// Mean gap between packets and its jitter, once there are enough
+        /// packets to time.
+        pub interarrival_mean_ms: Option<f64>,
+        pub jitter_ms: Option<f64>,
+        pub last_seen_ms_ago: u64,
+    }
+}
+
+api_schema! {
+    /// A single row of a connection query result.
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct ConnectionEntry {
+        /// "src_ip:src_port -> dst_ip:dst_port".
+        pub connection: String,
+        pub connection_id: ConnectionId,
+        pub stats: ConnectionStats,
+        /// Service name of the lower port.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub service: Option<String>,
+        /// Names from the `devices:` map for the connection's MACs, and the
+        /// vendors their OUIs belong to.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub src_device: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub dst_device: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub src_vendor: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub dst_vendor: Option<String>,
+    }
+}
+
+/// One page of connections plus the number of connections matching the
+/// filter.  The agent fills it with its live entries.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConnectionPage<E = ConnectionEntry> {
+    pub total: usize,
+    pub connections: Vec<E>,
+}
+
+impl<E: ApiSchema> ApiSchema for ConnectionPage<E> {
+    fn schema() -> Value {
+        object_schema(&[
+            ("total", usize::schema(), true),
+            ("connections", Vec::<E>::schema(), true),
+        ])
+    }
+}
+
+/// Body of `/api/live`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LiveResponse<E = ConnectionEntry> {
+    pub connections: Vec<E>,
+    pub total_packets: u64,
+    pub total_bytes: u64,
+    /// Transport payload bytes, excluding IP and TCP/UDP headers.
+    pub total_payload_bytes: u64,
+}
+
+impl<E: ApiSchema> ApiSchema for LiveResponse<E> {
+    fn schema() -> Value {
+        object_schema(&[
+            ("connections", Vec::<E>::schema(), true),
+            ("total_packets", u64::schema(), true),
+            ("total_bytes", u64::schema(), true),
+            ("total_payload_bytes", u64::schema(), true),
+        ])
+    }
+}
+
+/// One `/api/stream` push, sent every second and after each subscription
+/// change.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StreamEvent<E = ConnectionEntry> {
+    pub total_packets: u64,
+    pub total_bytes: u64,
+    pub active_connections: usize,
+    pub pps_1s: f64,
+    pub bps_1s: f64,
+    pub new_connections_1s: f64,
+    /// Up to 100 connections matching the watch filter, by bytes; only
+    /// while subscribed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub watch: Option<ConnectionPage<E>>,
+}
+
+api_schema! {
+    /// When a `/api/connections/export` dump was taken and what the agent
+    /// had counted by then; the first line of a JSON lines dump, under
+    /// `"snapshot"`.
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct DumpHeader {
+        /// RFC 3339.
+        pub taken_at: String,
+        pub taken_at_ms: i64,
+        /// Entries in the table when the dump started; connections created or
+        /// expired while it ran may make the rows differ by a few.
+        pub connections: usize,
+        pub total_packets: u64,
+        pub total_bytes: u64,
+    }
+}
+
+/// One dumped connection: the `/api/connections` entry plus when it was
+/// first and last seen, in epoch milliseconds.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DumpedConnection<E = ConnectionEntry> {
+    #[serde(flatten)]
+    pub entry: E,
+    pub first_seen: i64,
+    pub last_seen: i64,
+}
+
+impl<E: ApiSchema> ApiSchema for DumpedConnection<E> {
+    fn schema() -> Value {
+        let mut schema = E::schema();
+        schema["properties"]["first_seen"] = i64::schema();
+        schema["properties"]["last_seen"] = i64::schema();
+        if let Some(required) = schema["required"].as_array_mut() {
+            required.extend(["first_seen", "last_seen"].map(Value::from));
+        }
+        schema
+    }
+}
+
+// ── Stored packets ────────────────────────────────────────────────────────────
+
+api_schema! {
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct PacketMetadata {
+        pub timestamp: i64,
+        pub src_ip: String,
+        pub dst_ip: String,
+        pub src_port: u16,
+        pub dst_port: u16,
+        pub protocol: String,
+        pub length: usize,
+        /// Transport payload bytes, excluding IP and TCP/UDP headers (0 for
+        /// other protocols and rows stored before payload tracking).
+        pub payload_length: usize,
+        /// Packet direction: "ingress" or "egress".
+        pub direction: String,
+        /// Direction relative to `local_networks` (None for rows stored
+        /// before flow directions were recorded).
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub flow_direction: Option<FlowDirection>,
+        /// Unicast, multicast or broadcast destination.  Set at capture;
+        /// filled in for stored rows when served by the API.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub cast: Option<Cast>,
+        /// Interface the packet was seen on; empty for rows stored before
+        /// interfaces were recorded.
+        pub interface: String,
+        /// Ethernet source address, "aa:bb:cc:dd:ee:ff" (None on L3
+        /// interfaces, for aggregated rows and rows stored before MACs).
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub src_mac: Option<String>,
+        /// Ethernet destination address, as `src_mac`.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub dst_mac: Option<String>,
+        /// IPv4 TTL / IPv6 hop limit (None for aggregated or pre-TTL rows).
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub ttl: Option<u8>,
+        /// DSCP code point, 0-63 (None for aggregated or pre-DSCP rows).
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub dscp: Option<u8>,
+        /// DSCP class name such as "EF", "AF41" or "CS0".
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub dscp_class: Option<String>,
+        /// ICMP or ICMPv6 type and code, also carried in `dst_port` as
+        /// `type << 8 | code` (None for other protocols).
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub icmp_type: Option<u8>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub icmp_code: Option<u8>,
+        /// Message name such as "echo-request" or
+        /// "dest-unreachable/fragmentation-needed".
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub icmp_name: Option<String>,
+        /// Reverse-DNS hostname for source IP (None when DNS resolution is disabled).
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub src_hostname: Option<String>,
+        /// Reverse-DNS hostname for destination IP (None when DNS resolution is disabled).
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub dst_hostname: Option<String>,
+        /// Domain name from DNS query or TLS SNI (None when deep_inspect is disabled).
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub domain: Option<String>,
+        /// ISO 3166 country code of the remote end (the source of inbound
+        /// traffic, the destination otherwise), from `geoip.country_db`.
+        /// None without that database or for addresses it does not list.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub country: Option<String>,
+        /// Autonomous system number of the remote end, from `geoip.asn_db`.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub asn: Option<u32>,
+        /// Service name of the lower port, e.g. "https".  Filled in when
+        /// served by the API, not stored.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub service: Option<String>,
+    }
+}
+
+/// Whether a stored row is one captured packet or a window summary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RowKind {
+    Raw,
+    Aggregated,
+}
+
+impl ApiSchema for RowKind {
+    fn schema() -> Value {
+        string_enum(&["raw", "aggregated"])
+    }
+}
+
+/// A stored row as served by `/api/history` and `ayaflow query`: the packet
+/// columns plus what the row stands for.  For aggregated rows `length` and
+/// `payload_length` are window totals.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryRow {
+    #[serde(flatten)]
+    pub packet: PacketMetadata,
+    pub kind: RowKind,
+    /// Packets the row summarizes; 1 for raw rows.
+    pub packet_count: u64,
+    /// The agent that stored the row; null for rows from before instances
+    /// were recorded.
+    pub instance: Option<String>,
+    /// The TCP or UDP service port, by `service_port_rule`.  Filled in
+    /// when served by the API, not stored.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub service_port: Option<u16>,
+}
+
+impl ApiSchema for HistoryRow {
+    fn schema() -> Value {
+        let mut schema = PacketMetadata::schema();
+        schema["properties"]["kind"] = RowKind::schema();
+        schema["properties"]["packet_count"] = u64::schema();
+        schema["properties"]["instance"] = Option::<String>::schema();
+        schema["properties"]["service_port"] = Option::<u16>::schema();
+        if let Some(required) = schema["required"].as_array_mut() {
+            required.extend(["kind", "packet_count"].map(Value::from));
+        }
+        schema
+    }
+}
+
+api_schema! {
+    /// A row of the `alerts` table.  Firings of a rule for a subject fold
+    /// into one row until it is acknowledged; the next firing after that
+    /// starts a new row.
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct StoredAlert {
+        pub id: i64,
+        pub rule: String,
+        pub severity: String,
+        pub subject: String,
+        /// Description from the latest firing.
+        pub message: String,
+        /// First and latest firing, milliseconds since the Unix epoch.
+        pub first_seen: i64,
+        pub last_seen: i64,
+        /// Firings folded into the row.
+        pub count: u64,
+        pub acked: bool,
+        pub acked_by: Option<String>,
+        /// Milliseconds since the Unix epoch.
+        pub acked_at: Option<i64>,
+        /// The agent that raised it; null for rows from before instances
+        /// and for an unnamed agent.
+        pub instance: Option<String>,
+    }
+}
+
+// ── Fleet ─────────────────────────────────────────────────────────────────────
+
+api_schema! {
+    /// A sensor that pushed to this agent, as served by `/api/fleet`.
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct FleetMember {
+        pub instance: String,
+        /// When its last batch arrived, milliseconds since the Unix epoch.
+        pub last_push: i64,
+        /// Batches and rows taken from it since this agent started.
+        pub batches_received: u64,
+        pub rows_received: u64,
+        /// As of its last batch.
+        pub stats: SensorStats,
+    }
+}
+
+api_schema! {
+    /// Live counters a sensor sends with each push.
+    #[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+    pub struct SensorStats {
+        pub uptime_seconds: u64,
+        pub total_packets: u64,
+        pub total_bytes: u64,
+        pub active_connections: usize,
+        pub pps_60s: f64,
+        pub bps_60s: f64,
+    }
+}
+
+// ── Admin ─────────────────────────────────────────────────────────────────────
+
+api_schema! {
+    /// The blocklist as served by `/api/blocklist`.
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct BlocklistStatus {
+        /// Whether matching packets are dropped rather than only flagged.
+        pub enforcing: bool,
+        /// Whether the entries are loaded into the kernel; false without
+        /// capture.
+        pub attached: bool,
+        pub entries: Vec<String>,
+        /// Packets and bytes seen with a blocklisted address, dropped or
+        /// not.  Only per-packet events carry the flag, so these stay zero
+        /// under kernel aggregation.
+        pub matched_packets: u64,
+        pub matched_bytes: u64,
+        /// Packets the kernel dropped, counted even when their events were
+        /// lost.
+        pub dropped_packets: u64,
+    }
+}
+
+api_schema! {
+    /// What `POST /api/admin/reset` cleared.
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct ResetResponse {
+        pub packets_cleared: u64,
+        pub bytes_cleared: u64,
+        pub connections_cleared: usize,
+        /// Rows deleted from the packets table; absent unless `include_db`.
+        pub db_rows_deleted: Option<usize>,
+    }
+}
+
+api_schema! {
+    /// Reverse DNS cache contents and effectiveness.
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct DnsCacheStats {
+        /// Cached addresses, expired ones included.
+        pub entries: usize,
+        /// Cached lookups that found no name.
+        pub unresolved: usize,
+        /// Entries past their TTL, refreshed when next seen.
+        pub expired: usize,
+        /// Addresses queued for or being resolved.
+        pub pending: usize,
+        /// Capture-path lookups answered from a fresh entry since startup,
+        /// once per address per batch.
+        pub hits: u64,
+        /// Those that found no entry or an expired one, and were queued.
+        pub misses: u64,
+        pub ttl_seconds: u64,
+        pub failed_ttl_seconds: u64,
+    }
+}
+
+api_schema! {
+    /// What `POST /api/dns/flush` dropped.
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct DnsFlushResponse {
+        pub entries_removed: usize,
+    }
+}
+
+/// Where a backfill run stands.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackfillState {
+    /// No run has been started since the agent came up.
+    #[default]
+    Idle,
+    Running,
+    Finished,
+    Failed,
+}
+
+impl ApiSchema for BackfillState {
+    fn schema() -> Value {
+        string_enum(&["idle", "running", "finished", "failed"])
+    }
+}
+
+api_schema! {
+    /// Progress of a hostname backfill, updated after every batch.
+    #[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+    pub struct BackfillProgress {
+        pub state: BackfillState,
+        /// The last packet row processed; the next run resumes after it.
+        pub position: i64,
+        /// The highest packet row when the last batch was read.
+        pub last_row: i64,
+        /// Rows lacking a hostname that were read.
+        pub rows_scanned: u64,
+        /// Addresses given a hostname they lacked.
+        pub hostnames_filled: u64,
+        pub addresses_resolved: u64,
+        /// Addresses with no PTR record, or whose lookup timed out.
+        pub addresses_unresolved: u64,
+        /// Unroutable addresses passed over with `skip_private`.
+        pub addresses_skipped: u64,
+        /// Why a failed run stopped.
+        pub error: Option<String>,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::openapi::conforms;
+
+    #[test]
+    fn test_blocklist_match_from_ebpf() {
+        assert_eq!(BlocklistMatch::from_ebpf(BLOCKLIST_MATCHED), Some(BlocklistMatch::Flagged));
+        assert_eq!(BlocklistMatch::from_ebpf(BLOCKLIST_DROPPED), Some(BlocklistMatch::Dropped));
+        assert_eq!(BlocklistMatch::from_ebpf(0), None);
+    }
+
+    #[test]
+    fn test_history_row_flattens_packet() {
+        let row = HistoryRow {
+            packet: PacketMetadata {
+                timestamp: 1,
+                src_ip: "10.0.0.2".into(),
+                dst_ip: "10.0.0.1".into(),
+                src_port: 40000,
+                dst_port: 443,
+                protocol: "TCP".into(),
+                length: 60,
+                payload_length: 0,
+                direction: "ingress".into(),
+                flow_direction: Some(FlowDirection::Inbound),
+                cast: None,
+                interface: "eth0".into(),
+                src_mac: None,
+                dst_mac: None,
+                ttl: Some(64),
+                dscp: None,
+                dscp_class: None,
+                icmp_type: None,
+                icmp_code: None,
+                icmp_name: None,
+                src_hostname: None,
+                dst_hostname: None,
+                domain: None,
+                country: None,
+                asn: None,
+                service: None,
+            },
+            kind: RowKind::Raw,
+            packet_count: 1,
+            instance: None,
+            service_port: Some(443),
+        };
+        let json = serde_json::to_value(&row).unwrap();
+        assert_eq!(json["length"], 60);
+        assert_eq!(json["kind"], "raw");
+        conforms(&json, &HistoryRow::schema()).unwrap();
+        assert_eq!(serde_json::from_value::<HistoryRow>(json).unwrap(), row);
+    }
+
+    const LIMITS: HeadroomLimits = HeadroomLimits {
+        drain_batch: 256,
+        flush_interval: Duration::from_secs(2),
+        cores: 4,
+    };
+
+    fn second(drain_peak: usize, queue: f64, flush_ms: u64, cpu_ms: u64) -> HeadroomSample {
+        HeadroomSample {
+            elapsed: Duration::from_secs(1),
+            drain_peak,
+            queue_peak: Some(("storage".into(), queue)),
+            flush_peak: Duration::from_millis(flush_ms),
+            cpu: Duration::from_millis(cpu_ms),
+        }
+    }
+
+    #[test]
+    fn test_estimate_takes_peaks_and_names_the_limit() {
+        assert!(HeadroomReport::estimate(&[], LIMITS).is_none());
+
+        let samples = [
+            second(64, 0.10, 200, 500),
+            second(128, 0.25, 900, 700),
+            second(32, 0.05, 100, 300),
+        ];
+        let report = HeadroomReport::estimate(&samples, LIMITS).unwrap();
+        assert_eq!(report.window_seconds, 3.0);
+        assert_eq!((report.ring_batch_peak, report.ring_batch_ratio), (128, 0.5));
+        assert_eq!(report.queue_peak_ratio, 0.25);
+        assert_eq!(report.queue_peak_name.as_deref(), Some("storage"));
+        assert_eq!((report.flush_peak_ms, report.flush_ratio), (900.0, 0.45));
+        // 1.5s of CPU over 3s is half a core, an eighth of four.
+        assert_eq!((report.cpu_cores, report.cpu_ratio), (0.5, 0.125));
+        assert_eq!(report.limited_by, "ring_buffer");
+        assert_eq!(report.headroom_ratio, 0.5);
+
+        // A flush longer than the interval leaves no headroom.
+        let report = HeadroomReport::estimate(&[second(0, 0.0, 3000, 0)], LIMITS).unwrap();
+        assert_eq!((report.limited_by.as_str(), report.flush_ratio), ("flush", 1.5));
+        assert_eq!(report.headroom_ratio, 0.0);
+    }
+
+    #[test]
+    fn test_estimate_idle_and_cpu_bound() {
+        let idle = HeadroomSample {
+            elapsed: Duration::from_secs(1),
+            ..HeadroomSample::default()
+        };
+        let report = HeadroomReport::estimate(&[idle.clone(), idle], LIMITS).unwrap();
+        assert_eq!(report.headroom_ratio, 1.0);
+        assert_eq!(report.limited_by, "ring_buffer");
+        assert!(report.queue_peak_name.is_none());
+
+        // Three of four cores busy over two seconds.
+        let busy = [second(10, 0.0, 10, 2500), second(10, 0.0, 10, 3500)];
+        let report = HeadroomReport::estimate(&busy, LIMITS).unwrap();
+        assert_eq!((report.cpu_cores, report.cpu_ratio), (3.0, 0.75));
+        assert_eq!(report.limited_by, "cpu");
+        assert_eq!(report.headroom_ratio, 0.25);
+    }
+}
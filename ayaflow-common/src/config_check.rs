@@ -0,0 +1,253 @@
+//! Config checks shared by both agents' `Config::validate`.
+//!
+//! A check records a problem instead of failing, so one run reports every
+//! mistake in a file at once.  Each problem names the field as it is
+//! written in the YAML, e.g. `storage.flush_max_rows`, and the error names
+//! the file it came from.
+
+use std::fmt;
+use std::format;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::string::{String, ToString};
+use std::vec::Vec;
+
+use ipnet::IpNet;
+
+/// One invalid setting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigProblem {
+    /// Dotted path of the field, e.g. `api.trusted_proxies`.
+    pub field: String,
+    pub message: String,
+}
+
+/// Problems found so far.
+#[derive(Debug, Default)]
+pub struct ConfigProblems {
+    problems: Vec<ConfigProblem>,
+}
+
+impl ConfigProblems {
+    pub fn push(&mut self, field: &str, message: impl fmt::Display) {
+        self.problems.push(ConfigProblem {
+            field: field.to_string(),
+            message: message.to_string(),
+        });
+    }
+
+    /// Record `message` against `field` unless `ok`.
+    pub fn ensure(&mut self, ok: bool, field: &str, message: impl fmt::Display) {
+        if !ok {
+            self.push(field, message);
+        }
+    }
+
+    /// Every entry must be a CIDR such as `10.0.0.0/8`.
+    pub fn cidrs(&mut self, field: &str, cidrs: &[String]) {
+        for cidr in cidrs {
+            if parse_cidr(cidr).is_none() {
+                self.push(field, format!("invalid CIDR {:?} (write e.g. 10.0.0.0/8)", cidr));
+            }
+        }
+    }
+
+    /// Port 0 cannot be matched or listened on deliberately.
+    pub fn ports(&mut self, field: &str, ports: impl IntoIterator<Item = u16>) {
+        if ports.into_iter().any(|port| port == 0) {
+            self.push(field, "ports must be between 1 and 65535");
+        }
+    }
+
+    /// The agent must be able to create or write the file at `path`.
+    pub fn writable_file(&mut self, field: &str, path: &Path) {
+        if let Err(message) = check_writable(path) {
+            self.push(field, message);
+        }
+    }
+
+    /// A network interface of this name exists on the host.
+    pub fn interface(&mut self, field: &str, name: &str) {
+        let known = !name.is_empty()
+            && !name.contains('/')
+            && Path::new("/sys/class/net").join(name).exists();
+        self.ensure(known, field, format!("no network interface named {:?}", name));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    pub fn problems(&self) -> &[ConfigProblem] {
+        &self.problems
+    }
+
+    /// `Ok` without problems, or an error listing them all against `file`
+    /// (None when the config came from the command line alone).
+    pub fn into_result(self, file: Option<&Path>) -> Result<(), ConfigError> {
+        if self.problems.is_empty() {
+            return Ok(());
+        }
+        Err(ConfigError {
+            file: file.map(Path::to_path_buf),
+            problems: self.problems,
+        })
+    }
+}
+
+/// Every problem found in one config.
+#[derive(Debug)]
+pub struct ConfigError {
+    pub file: Option<PathBuf>,
+    pub problems: Vec<ConfigProblem>,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let source = match &self.file {
+            Some(file) => file.display().to_string(),
+            None => "command line".to_string(),
+        };
+        for (i, problem) in self.problems.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}: {}: {}", source, problem.field, problem.message)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// `address/prefix` with the prefix in range for the address family.
+pub fn parse_cidr(cidr: &str) -> Option<IpNet> {
+    cidr.parse().ok()
+}
+
+fn check_writable(path: &Path) -> Result<(), String> {
+    if path.exists() {
+        return fs::OpenOptions::new()
+            .append(true)
+            .open(path)
+            .map(drop)
+            .map_err(|e| format!("cannot write {}: {}", path.display(), e));
+    }
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    if !dir.is_dir() {
+        return Err(format!("directory {} does not exist", dir.display()));
+    }
+    // Creating a file is the only reliable test; permissions alone miss
+    // read-only mounts.
+    let probe = dir.join(format!(".ayaflow-check-{}", std::process::id()));
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe)
+        .map_err(|e| format!("cannot create files in {}: {}", dir.display(), e))?;
+    let _ = fs::remove_file(&probe);
+    Ok(())
+}
+
+/// Shorten serde's "unknown field `x`, expected one of ..." by naming the
+/// closest known field instead of listing every one.  The field path before
+/// it and the line and column after it are kept.  Other messages are
+/// returned unchanged.
+pub fn describe_unknown_field(message: &str) -> String {
+    let Some((path, rest)) = message.split_once("unknown field `") else {
+        return message.to_string();
+    };
+    let Some((unknown, expected)) = rest.split_once('`') else {
+        return message.to_string();
+    };
+    let location = expected.rfind(" at line ").map_or("", |at| &expected[at..]);
+    // Known names are the backquoted tokens that follow.
+    let known = expected.split('`').skip(1).step_by(2);
+    let closest = known
+        .map(|name| (edit_distance(unknown, name), name))
+        .filter(|(distance, _)| *distance <= unknown.len().max(3) / 3)
+        .min();
+    match closest {
+        Some((_, name)) => {
+            format!("{}unknown field `{}` (did you mean `{}`?){}", path, unknown, name, location)
+        }
+        None => format!("{}unknown field `{}`{}", path, unknown, location),
+    }
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = (above + 1).min(row[j] + 1).min(diagonal + usize::from(ca != *cb));
+            diagonal = above;
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec;
+
+    #[test]
+    fn test_problems_name_field_and_file() {
+        let mut problems = ConfigProblems::default();
+        problems.cidrs("allowed_ips", &["10.0.0.0/8".into(), "10.0.0.1".into()]);
+        problems.cidrs("local_networks", &["fd00::/129".into(), "::/0".into()]);
+        problems.ports("jitter.ports", [5060, 0]);
+        problems.ensure(true, "port", "unused");
+        let fields: Vec<&str> = problems.problems().iter().map(|p| p.field.as_str()).collect();
+        assert_eq!(fields, ["allowed_ips", "local_networks", "jitter.ports"]);
+
+        let error = problems.into_result(Some(Path::new("/etc/ayaflow.yaml"))).unwrap_err();
+        let text = error.to_string();
+        assert_eq!(text.lines().count(), 3);
+        assert!(text.starts_with("/etc/ayaflow.yaml: allowed_ips: invalid CIDR \"10.0.0.1\""));
+        assert!(ConfigProblems::default().into_result(None).is_ok());
+    }
+
+    #[test]
+    fn test_writable_file() {
+        let dir = std::env::temp_dir();
+        let mut problems = ConfigProblems::default();
+        problems.writable_file("db_path", &dir.join("ayaflow-check-test.db"));
+        assert!(problems.is_empty(), "{:?}", problems);
+        problems.writable_file("db_path", Path::new("/nonexistent-ayaflow/traffic.db"));
+        assert_eq!(problems.problems().len(), 1);
+        assert!(problems.problems()[0].message.contains("does not exist"));
+    }
+
+    #[test]
+    fn test_describe_unknown_field() {
+        let serde = "unknown field `aggregation_window_secs`, expected one of `port`, \
+                     `aggregation_window_seconds`, `aggregation_key` at line 2 column 1";
+        assert_eq!(
+            describe_unknown_field(serde),
+            "unknown field `aggregation_window_secs` (did you mean `aggregation_window_seconds`?) \
+             at line 2 column 1"
+        );
+        let far = "unknown field `colour`, expected one of `port`, `interface`";
+        assert_eq!(describe_unknown_field(far), "unknown field `colour`");
+        let nested = "storage: unknown field `flush_max_row`, expected one of \
+                      `flush_max_rows` at line 3 column 3";
+        assert_eq!(
+            describe_unknown_field(nested),
+            "storage: unknown field `flush_max_row` (did you mean `flush_max_rows`?) \
+             at line 3 column 3"
+        );
+        assert_eq!(describe_unknown_field("invalid type: string"), "invalid type: string");
+        let cases = vec![("kitten", "sitting", 3), ("", "abc", 3), ("same", "same", 0)];
+        for (a, b, distance) in cases {
+            assert_eq!(edit_distance(a, b), distance);
+        }
+    }
+}
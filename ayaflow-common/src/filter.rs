@@ -0,0 +1,548 @@
+//! Packet filters shared by both capture binaries.
+//!
+//! A `FilterSpec` is the YAML form: lists of addresses or CIDRs, ports or
+//! port ranges (each entry may be a comma list), and protocol names.  A
+//! packet passes when, for every list that is not empty, one of its
+//! entries matches: either end's address or port, or the protocol.
+//! Addresses prefixed with "!" exclude instead, and win over any include.
+//! An empty spec lets everything through.  `capture_filter` decides what
+//! reaches the live state and the writer; `storage_filter` only what
+//! reaches the writer.
+
+use core::net::IpAddr;
+use core::str::FromStr;
+use std::string::{String, ToString};
+use std::vec::Vec;
+
+use ipnet::{IpNet, Ipv4Net};
+
+/// Which packets a filter lets through (`capture_filter:` and
+/// `storage_filter:` in the YAML config).
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FilterSpec {
+    /// Addresses or CIDRs, e.g. "10.1.0.0/16", either end must be in;
+    /// "!10.1.5.0/24" excludes, neither end may be in.
+    #[serde(default)]
+    pub ips: Vec<String>,
+    /// Ports or inclusive ranges, e.g. "443", "8000-8100" or
+    /// "80,443,30000-32767", either end must be on.  Only TCP and UDP
+    /// packets have ports.
+    #[serde(default)]
+    pub ports: Vec<String>,
+    /// Protocol names, e.g. "TCP" or "ICMP"; case does not matter.
+    #[serde(default)]
+    pub protocols: Vec<String>,
+}
+
+impl FilterSpec {
+    pub fn is_empty(&self) -> bool {
+        self.ips.is_empty() && self.ports.is_empty() && self.protocols.is_empty()
+    }
+}
+
+/// Addresses and CIDRs to include, and those after a "!" to exclude.
+/// IPv4-mapped IPv6 addresses and prefixes count as IPv4, so
+/// "::ffff:10.0.0.1" is in 10.0.0.0/8 and "::ffff:10.0.0.0/104" is
+/// 10.0.0.0/8.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IpFilter {
+    include: Vec<IpNet>,
+    exclude: Vec<IpNet>,
+}
+
+impl IpFilter {
+    /// Parse `entries`, naming the first that is not an address or CIDR.
+    pub fn new<S: AsRef<str>>(entries: &[S]) -> Result<Self, String> {
+        let mut filter = Self::default();
+        for entry in entries {
+            let entry = entry.as_ref().trim();
+            let (list, net) = match entry.strip_prefix('!') {
+                Some(net) => (&mut filter.exclude, net.trim()),
+                None => (&mut filter.include, entry),
+            };
+            let net = net
+                .parse::<IpNet>()
+                .or_else(|_| net.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| std::format!("{:?} is not an address or CIDR", entry))?;
+            list.push(unmap_net(net.trunc()));
+        }
+        Ok(filter)
+    }
+
+    /// True when it lets every address through.
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    /// The nets either end must be in, unless empty; IPv4-mapped ones as
+    /// IPv4.
+    pub fn includes(&self) -> &[IpNet] {
+        &self.include
+    }
+
+    /// Whether either end is included, or nothing has to be, and neither
+    /// is excluded.  None is an address that did not parse, such as those
+    /// of non-IP frames: it is in no net.
+    pub fn matches(&self, src: Option<IpAddr>, dst: Option<IpAddr>) -> bool {
+        let (src, dst) = (src.map(unmap_addr), dst.map(unmap_addr));
+        let in_any = |nets: &[IpNet], ip: Option<IpAddr>| {
+            ip.is_some_and(|ip| nets.iter().any(|net| net.contains(&ip)))
+        };
+        if in_any(&self.exclude, src) || in_any(&self.exclude, dst) {
+            return false;
+        }
+        self.include.is_empty() || in_any(&self.include, src) || in_any(&self.include, dst)
+    }
+
+    /// `matches` for addresses as events and rows carry them.
+    pub fn matches_str(&self, src: &str, dst: &str) -> bool {
+        self.is_empty() || self.matches(src.parse().ok(), dst.parse().ok())
+    }
+}
+
+impl core::fmt::Display for IpFilter {
+    /// The entries as parsed, e.g. "10.0.0.0/8 !10.0.5.0/24".
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let included = self.include.iter().map(|net| (net, ""));
+        let excluded = self.exclude.iter().map(|net| (net, "!"));
+        for (i, (net, bang)) in included.chain(excluded).enumerate() {
+            let sep = if i > 0 { " " } else { "" };
+            write!(f, "{}{}{}", sep, bang, net)?;
+        }
+        Ok(())
+    }
+}
+
+fn unmap_addr(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        v4 => v4,
+    }
+}
+
+/// ::ffff:0:0/96 and the prefixes within it, as IPv4.
+fn unmap_net(net: IpNet) -> IpNet {
+    match net {
+        IpNet::V6(v6) if v6.prefix_len() >= 96 => match v6.network().to_ipv4_mapped() {
+            Some(v4) => Ipv4Net::new(v4, v6.prefix_len() - 96).map_or(net, IpNet::V4),
+            None => net,
+        },
+        _ => net,
+    }
+}
+
+/// Ports and inclusive ranges, sorted and merged so a lookup is a binary
+/// search.  Parsed from "80,443,30000-32767"; empty matches no port.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PortSet {
+    ranges: Vec<(u16, u16)>,
+}
+
+impl PortSet {
+    /// Overlapping and adjacent ranges are merged.
+    pub fn from_ranges(ranges: impl IntoIterator<Item = (u16, u16)>) -> Self {
+        let mut sorted: Vec<(u16, u16)> = ranges.into_iter().collect();
+        sorted.sort_unstable();
+        let mut merged: Vec<(u16, u16)> = Vec::with_capacity(sorted.len());
+        for (lo, hi) in sorted {
+            match merged.last_mut() {
+                Some(last) if lo <= last.1.saturating_add(1) => last.1 = last.1.max(hi),
+                _ => merged.push((lo, hi)),
+            }
+        }
+        Self { ranges: merged }
+    }
+
+    pub fn contains(&self, port: u16) -> bool {
+        // The first range that does not end below `port` is the only one
+        // that can hold it.
+        let i = self.ranges.partition_point(|&(_, hi)| hi < port);
+        self.ranges.get(i).is_some_and(|&(lo, _)| lo <= port)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// Disjoint inclusive ranges in ascending order.
+    pub fn ranges(&self) -> &[(u16, u16)] {
+        &self.ranges
+    }
+}
+
+impl FromStr for PortSet {
+    type Err = String;
+
+    /// Comma-separated ports and ranges, naming the first token that is
+    /// neither.
+    fn from_str(s: &str) -> Result<Self, String> {
+        let ranges = s
+            .split(',')
+            .map(|token| parse_port_range(token.trim()))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self::from_ranges(ranges))
+    }
+}
+
+impl core::fmt::Display for PortSet {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for (i, &(lo, hi)) in self.ranges.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            match lo == hi {
+                true => write!(f, "{}", lo)?,
+                false => write!(f, "{}-{}", lo, hi)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl serde::Serialize for PortSet {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for PortSet {
+    /// A single port number, as configs have always had, or a list string.
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(serde::Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Port(u16),
+            List(String),
+        }
+        match Raw::deserialize(deserializer)? {
+            Raw::Port(port) => Ok(Self::from_ranges([(port, port)])),
+            Raw::List(list) => list.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+/// A `FilterSpec` parsed for matching packets.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrafficFilter {
+    ips: IpFilter,
+    ports: PortSet,
+    protocols: Vec<String>,
+}
+
+impl TrafficFilter {
+    /// Parse `spec`, naming the first entry that is not an address, CIDR,
+    /// port or range.
+    pub fn new(spec: &FilterSpec) -> Result<Self, String> {
+        let ips = IpFilter::new(&spec.ips).map_err(|e| std::format!("ips: {}", e))?;
+        let sets = spec
+            .ports
+            .iter()
+            .map(|entry| entry.parse::<PortSet>().map_err(|e| std::format!("ports: {}", e)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let ports = PortSet::from_ranges(sets.iter().flat_map(|set| set.ranges().iter().copied()));
+        Ok(Self {
+            ips,
+            ports,
+            protocols: spec.protocols.clone(),
+        })
+    }
+
+    /// True when it lets everything through.
+    pub fn is_empty(&self) -> bool {
+        self.ips.is_empty() && self.ports.is_empty() && self.protocols.is_empty()
+    }
+
+    /// Whether a packet passes.  Addresses that do not parse, such as those
+    /// of non-IP frames, match no `ips` entry, and packets other than TCP
+    /// and UDP match no `ports` entry: ICMP events carry `type << 8 | code`
+    /// where the ports would be.
+    pub fn matches(
+        &self,
+        src_ip: &str,
+        dst_ip: &str,
+        src_port: u16,
+        dst_port: u16,
+        protocol: &str,
+    ) -> bool {
+        if !self.protocols.is_empty()
+            && !self
+                .protocols
+                .iter()
+                .any(|p| p.eq_ignore_ascii_case(protocol))
+        {
+            return false;
+        }
+        let has_ports = ["TCP", "UDP"].iter().any(|p| p.eq_ignore_ascii_case(protocol));
+        let on_port = |port: u16| has_ports && self.ports.contains(port);
+        if !self.ports.is_empty() && !on_port(src_port) && !on_port(dst_port) {
+            return false;
+        }
+        self.ips.matches_str(src_ip, dst_ip)
+    }
+
+    /// The `ports` list, which the eBPF binary also loads into the kernel.
+    pub fn ports(&self) -> &PortSet {
+        &self.ports
+    }
+
+    /// The `ips` list, whose includes the eBPF binary also loads into the
+    /// kernel.
+    pub fn ips(&self) -> &IpFilter {
+        &self.ips
+    }
+}
+
+/// "443" or "8000-8100", as an inclusive range.
+fn parse_port_range(entry: &str) -> Result<(u16, u16), String> {
+    let invalid = || std::format!("{:?} is not a port or range like 8000-8100", entry);
+    let (lo, hi) = entry.split_once('-').unwrap_or((entry, entry));
+    let lo: u16 = lo.trim().parse().map_err(|_| invalid())?;
+    let hi: u16 = hi.trim().parse().map_err(|_| invalid())?;
+    if lo > hi {
+        return Err(invalid());
+    }
+    Ok((lo, hi))
+}
+
+/// The `capture_filter` and `storage_filter` of a config, parsed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Filters {
+    pub capture: TrafficFilter,
+    pub storage: TrafficFilter,
+}
+
+impl Filters {
+    /// Errors start with the field, e.g. "storage_filter.ips: ...".
+    pub fn new(capture: &FilterSpec, storage: &FilterSpec) -> Result<Self, String> {
+        let parse = |field: &str, spec| {
+            TrafficFilter::new(spec).map_err(|e| std::format!("{}.{}", field, e))
+        };
+        Ok(Self {
+            capture: parse("capture_filter", capture)?,
+            storage: parse("storage_filter", storage)?,
+        })
+    }
+}
+
+impl core::fmt::Display for FilterSpec {
+    /// A one-line summary for the startup log, e.g.
+    /// "ips 10.1.0.0/16, ports 443".
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let parts: Vec<String> = [
+            ("ips", &self.ips),
+            ("ports", &self.ports),
+            ("protocols", &self.protocols),
+        ]
+        .into_iter()
+        .filter(|(_, entries)| !entries.is_empty())
+        .map(|(name, entries)| std::format!("{} {}", name, entries.join(" ")))
+        .collect();
+        if parts.is_empty() {
+            return f.write_str("everything");
+        }
+        f.write_str(&parts.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(ips: &[&str], ports: &[&str], protocols: &[&str]) -> TrafficFilter {
+        let owned = |list: &[&str]| list.iter().map(|s| s.to_string()).collect();
+        let spec = FilterSpec {
+            ips: owned(ips),
+            ports: owned(ports),
+            protocols: owned(protocols),
+        };
+        TrafficFilter::new(&spec).unwrap()
+    }
+
+    #[test]
+    fn test_cidrs_port_ranges_and_protocols() {
+        let f = filter(
+            &["10.1.0.0/16", "2001:db8::/32", "192.0.2.7"],
+            &["443", "8000-8100"],
+            &["tcp"],
+        );
+        assert!(f.matches("10.1.2.3", "203.0.113.1", 51000, 443, "TCP"));
+        assert!(f.matches("203.0.113.1", "192.0.2.7", 8050, 51000, "TCP"));
+        assert!(f.matches("2001:db8::1", "2001:4860::1", 8000, 1, "TCP"));
+        assert!(!f.matches("10.2.0.1", "203.0.113.1", 51000, 443, "TCP"));
+        assert!(!f.matches("10.1.2.3", "203.0.113.1", 51000, 8101, "TCP"));
+        assert!(!f.matches("10.1.2.3", "203.0.113.1", 51000, 443, "UDP"));
+        assert!(!f.matches("0.0.0.0/x", "not-an-ip", 51000, 443, "TCP"));
+
+        // An ICMP echo request's type and code read as 2048, not a port.
+        let high = filter(&[], &["1024-65535"], &[]);
+        assert!(high.matches("10.0.0.2", "10.0.0.1", 40000, 2048, "udp"));
+        assert!(!high.matches("10.0.0.2", "10.0.0.1", 0, 2048, "ICMP"));
+        assert!(!high.matches("fd00::2", "fd00::1", 0, 32768, "ICMPv6"));
+
+        let everything = filter(&[], &[], &[]);
+        assert!(everything.is_empty());
+        assert!(everything.matches("", "", 0, 0, "ARP"));
+        assert!(filter(&["0.0.0.0/0"], &[], &[]).matches("198.51.100.1", "", 0, 0, "UDP"));
+    }
+
+    #[test]
+    fn test_invalid_entries_are_named() {
+        let spec = |ips: Vec<String>, ports: Vec<String>| FilterSpec {
+            ips,
+            ports,
+            protocols: vec![],
+        };
+        let err = Filters::new(
+            &FilterSpec::default(),
+            &spec(vec!["10.0.0.0/33".into()], vec![]),
+        );
+        assert_eq!(
+            err.unwrap_err(),
+            "storage_filter.ips: \"10.0.0.0/33\" is not an address or CIDR"
+        );
+        for port in ["8100-8000", "http", "70000", "80,,443"] {
+            let err = TrafficFilter::new(&spec(vec![], vec![port.into()])).unwrap_err();
+            assert!(err.starts_with("ports: "), "{}", err);
+        }
+        let err = TrafficFilter::new(&spec(vec![], vec!["80, 443,ssh".into()])).unwrap_err();
+        assert_eq!(err, "ports: \"ssh\" is not a port or range like 8000-8100");
+    }
+
+    #[test]
+    fn test_ip_excludes_win_over_includes() {
+        let ip = |s: &str| Some(s.parse::<IpAddr>().unwrap());
+        let f = IpFilter::new(&["10.0.0.0/8", "!10.0.5.0/24", "! 10.0.6.7"]).unwrap();
+        assert!(f.matches(ip("10.1.2.3"), ip("203.0.113.1")));
+        assert!(f.matches(ip("203.0.113.1"), ip("10.0.4.255")));
+        assert!(!f.matches(ip("10.0.5.1"), ip("203.0.113.1")));
+        assert!(!f.matches(ip("10.0.6.7"), ip("10.1.2.3")));
+        assert!(f.matches(ip("10.0.6.8"), ip("10.1.2.3")));
+        // An excluded end rejects even when the other end is included.
+        assert!(!f.matches(ip("10.1.2.3"), ip("10.0.5.9")));
+        assert!(!f.matches(ip("192.0.2.1"), ip("203.0.113.1")));
+        assert!(!f.matches(None, None));
+
+        // The order of the entries does not matter.
+        let reversed = IpFilter::new(&["!10.0.5.0/24", "10.0.0.0/8"]).unwrap();
+        assert!(!reversed.matches(ip("10.0.5.1"), None));
+        assert!(reversed.matches(ip("10.0.4.1"), None));
+
+        // Only excludes: everything else passes, unparsed addresses too.
+        let f = IpFilter::new(&["!2001:db8::/32"]).unwrap();
+        assert!(f.matches(ip("2001:db9::1"), ip("198.51.100.1")));
+        assert!(!f.matches(ip("2001:db8::1"), ip("198.51.100.1")));
+        assert!(f.matches(None, None));
+        assert!(f.matches_str("", "not-an-ip"));
+
+        assert!(IpFilter::new::<&str>(&[]).unwrap().is_empty());
+        let f = IpFilter::new(&["!10.0.5.9/24", "10.0.0.0/8", "::ffff:192.0.2.1"]).unwrap();
+        assert_eq!(f.to_string(), "10.0.0.0/8 192.0.2.1/32 !10.0.5.0/24");
+        let nets = ["10.0.0.0/8", "192.0.2.1/32"].map(|net| net.parse::<IpNet>().unwrap());
+        assert_eq!(f.includes(), nets);
+        for entry in ["!", "!10.0.0.0/33", "10.0.0.1/", "host"] {
+            let err = IpFilter::new(&[entry]).unwrap_err();
+            assert_eq!(err, std::format!("{:?} is not an address or CIDR", entry));
+        }
+    }
+
+    #[test]
+    fn test_ipv4_mapped_addresses_count_as_ipv4() {
+        let ip = |s: &str| Some(s.parse::<IpAddr>().unwrap());
+        let f = IpFilter::new(&["10.0.0.0/8", "!10.0.5.0/24"]).unwrap();
+        assert!(f.matches(ip("::ffff:10.1.2.3"), None));
+        assert!(!f.matches(ip("::ffff:10.0.5.1"), ip("10.1.2.3")));
+        assert!(!f.matches(ip("::ffff:192.0.2.1"), None));
+
+        // Mapped prefixes are IPv4 ones, in either notation.
+        let mapped = IpFilter::new(&["::ffff:10.0.0.0/104", "!::ffff:10.0.5.0/120"]).unwrap();
+        assert_eq!(mapped, f);
+        assert!(mapped.matches(ip("10.200.0.1"), None));
+        assert!(!mapped.matches(ip("10.0.5.200"), None));
+        let whole = IpFilter::new(&["::ffff:0.0.0.0/96"]).unwrap();
+        assert!(whole.matches(ip("198.51.100.1"), None));
+        assert!(!whole.matches(ip("2001:db8::1"), None));
+
+        // A shorter IPv6 prefix stays IPv6, and the IPv4-compatible form
+        // ::a.b.c.d is not mapped.
+        let v6 = IpFilter::new(&["::/64"]).unwrap();
+        assert!(v6.matches(ip("::1"), None));
+        assert!(!v6.matches(ip("10.0.0.1"), None));
+        let compat = IpFilter::new(&["::10.0.0.1"]).unwrap();
+        assert!(!compat.matches(ip("10.0.0.1"), None));
+    }
+
+    #[test]
+    fn test_port_set_parses_lists_and_merges() {
+        let set: PortSet = " 443 , 80,30000-32767,8000-8100,8050-8200,8201".parse().unwrap();
+        assert_eq!(
+            set.ranges(),
+            [(80, 80), (443, 443), (8000, 8201), (30000, 32767)]
+        );
+        assert_eq!(set.to_string(), "80,443,8000-8201,30000-32767");
+        assert_eq!(set.to_string().parse::<PortSet>().unwrap(), set);
+        assert_eq!("0-65535".parse::<PortSet>().unwrap().ranges(), [(0, 65535)]);
+        assert_eq!("65535".parse::<PortSet>().unwrap().ranges(), [(65535, 65535)]);
+
+        for (input, token) in [
+            ("", ""),
+            ("80,", ""),
+            ("80,http", "http"),
+            ("80,443-80", "443-80"),
+            ("1-2-3", "1-2-3"),
+            ("65536", "65536"),
+            ("-5", "-5"),
+        ] {
+            let err = input.parse::<PortSet>().unwrap_err();
+            assert_eq!(
+                err,
+                std::format!("{:?} is not a port or range like 8000-8100", token),
+                "{:?}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_port_set_contains() {
+        let set: PortSet = "22,80,443,1000-1999,30000-32767".parse().unwrap();
+        for port in [22, 80, 443, 1000, 1500, 1999, 30000, 32767] {
+            assert!(set.contains(port), "{}", port);
+        }
+        for port in [0, 21, 23, 79, 444, 999, 2000, 29999, 32768, 65535] {
+            assert!(!set.contains(port), "{}", port);
+        }
+        assert!(!PortSet::default().contains(0));
+
+        // Against a linear scan over every port.
+        let ranges = [(5, 9), (7, 12), (100, 100), (101, 101), (60000, 65535)];
+        let set = PortSet::from_ranges(ranges);
+        assert_eq!(set.ranges(), [(5, 12), (100, 101), (60000, 65535)]);
+        for port in 0..=u16::MAX {
+            let expected = ranges.iter().any(|&(lo, hi)| (lo..=hi).contains(&port));
+            assert_eq!(set.contains(port), expected, "{}", port);
+        }
+    }
+
+    #[test]
+    fn test_port_set_serde() {
+        use serde::de::value::{Error, StrDeserializer, U16Deserializer};
+        use serde::Deserialize;
+
+        let port = PortSet::deserialize(U16Deserializer::<Error>::new(443)).unwrap();
+        assert_eq!(port.ranges(), [(443, 443)]);
+        let list = PortSet::deserialize(StrDeserializer::<Error>::new("80,8000-8100")).unwrap();
+        assert_eq!(list.ranges(), [(80, 80), (8000, 8100)]);
+        let err = PortSet::deserialize(StrDeserializer::<Error>::new("80,web")).unwrap_err();
+        assert!(err.to_string().contains("\"web\" is not a port"), "{}", err);
+    }
+
+    #[test]
+    fn test_spec_entries_may_be_lists() {
+        let f = filter(&[], &["80,443", "8000-8100"], &[]);
+        assert_eq!(f.ports().ranges(), [(80, 80), (443, 443), (8000, 8100)]);
+        assert!(f.matches("", "", 51000, 443, "TCP"));
+        assert!(f.matches("", "", 8100, 51000, "UDP"));
+        assert!(!f.matches("", "", 51000, 444, "TCP"));
+    }
+}
@@ -0,0 +1,223 @@
+//! Just enough of an HTTP/1.1 client for plain-HTTP peers: one request per
+//! connection, closed by the server after the response, so the body runs
+//! to EOF unless it is chunked or sized.
+//!
+//! The agent's webhooks, reports, fleet pushes and ClickHouse backend use
+//! it, and so does ayaflow-client.
+
+use core::fmt;
+use core::time::Duration;
+use std::format;
+use std::string::{String, ToString};
+use std::vec::Vec;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Most headers a response may carry.
+const MAX_HEADERS: usize = 64;
+
+pub struct Request<'a> {
+    pub method: &'a str,
+    /// Everything after the authority, e.g. `/api/history?limit=10`.
+    pub target: &'a str,
+    /// The `Host` header: the authority as written.
+    pub host: &'a str,
+    /// Sent after `Host`.  `Content-Length` and `Connection: close` are
+    /// added.
+    pub headers: &'a [(&'a str, &'a str)],
+    pub body: &'a [u8],
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// Connecting, writing or reading failed.
+    Io(std::io::Error),
+    /// No complete response within the timeout.
+    Timeout(Duration),
+    /// The response was not valid HTTP.
+    Protocol(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => e.fmt(f),
+            Error::Timeout(timeout) => write!(f, "timed out after {:?}", timeout),
+            Error::Protocol(reason) => f.write_str(reason),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// Send `request` to `address`, `host:port`, and read the whole response,
+/// giving up after `timeout`.
+pub async fn send(
+    address: &str,
+    request: &Request<'_>,
+    timeout: Duration,
+) -> Result<Response, Error> {
+    tokio::time::timeout(timeout, exchange(address, request))
+        .await
+        .map_err(|_| Error::Timeout(timeout))?
+}
+
+async fn exchange(address: &str, request: &Request<'_>) -> Result<Response, Error> {
+    let mut head = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\n",
+        request.method, request.target, request.host
+    );
+    for (name, value) in request.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str(&format!(
+        "Content-Length: {}\r\nConnection: close\r\n\r\n",
+        request.body.len()
+    ));
+    let mut raw = head.into_bytes();
+    raw.extend_from_slice(request.body);
+
+    let mut stream = TcpStream::connect(address).await?;
+    stream.write_all(&raw).await?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    parse(&response)
+}
+
+/// A complete response as read off the connection.
+pub fn parse(raw: &[u8]) -> Result<Response, Error> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut response = httparse::Response::new(&mut headers);
+    let head_len = match response.parse(raw) {
+        Ok(httparse::Status::Complete(len)) => len,
+        Ok(httparse::Status::Partial) => {
+            return Err(Error::Protocol("connection closed mid-response".into()))
+        }
+        Err(e) => return Err(Error::Protocol(format!("invalid response: {}", e))),
+    };
+    let status = response.code.unwrap_or_default();
+    let header = |name: &str| {
+        response
+            .headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case(name))
+            .and_then(|h| core::str::from_utf8(h.value).ok())
+    };
+    let body = &raw[head_len..];
+    let body = if header("transfer-encoding").is_some_and(|v| v.contains("chunked")) {
+        dechunk(body)?
+    } else if let Some(len) = header("content-length").and_then(|v| v.trim().parse().ok()) {
+        body.get(..len)
+            .ok_or_else(|| Error::Protocol("body shorter than Content-Length".into()))?
+            .to_vec()
+    } else {
+        body.to_vec()
+    };
+    Ok(Response { status, body })
+}
+
+fn dechunk(mut body: &[u8]) -> Result<Vec<u8>, Error> {
+    let invalid = || Error::Protocol("invalid chunked body".to_string());
+    let mut out = Vec::new();
+    loop {
+        let line_end = body.windows(2).position(|w| w == b"\r\n").ok_or_else(invalid)?;
+        let size = core::str::from_utf8(&body[..line_end]).map_err(|_| invalid())?;
+        // Chunk extensions follow a semicolon.
+        let size = size.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| invalid())?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Ok(out);
+        }
+        out.extend_from_slice(body.get(..size).ok_or_else(invalid)?);
+        body = body.get(size + 2..).ok_or_else(invalid)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_parse_bodies() {
+        let sized = b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\n{}";
+        let response = parse(sized).unwrap();
+        assert_eq!((response.status, response.body.as_slice()), (200, &b"{}"[..]));
+
+        let chunked = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                        3\r\nabc\r\n2;ext=1\r\nde\r\n0\r\n\r\n";
+        assert_eq!(parse(chunked).unwrap().body, b"abcde");
+
+        let to_eof = b"HTTP/1.1 503 Service Unavailable\r\n\r\ndown";
+        let response = parse(to_eof).unwrap();
+        assert_eq!((response.status, response.body.as_slice()), (503, &b"down"[..]));
+        assert!(!response.is_success());
+
+        assert!(parse(b"HTTP/1.1 200 OK\r\ncontent-length: 9\r\n\r\nab").is_err());
+        assert!(parse(b"HTTP/1.1 200").is_err());
+        assert!(parse(b"HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\nzz\r\n").is_err());
+        let cut = b"HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n5\r\nab";
+        assert!(parse(cut).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_send_writes_request_and_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut chunk = [0; 1024];
+            while !request.ends_with(b"ping") {
+                let n = socket.read(&mut chunk).await.unwrap();
+                request.extend_from_slice(&chunk[..n]);
+            }
+            socket.write_all(b"HTTP/1.1 201 Created\r\n\r\npong").await.unwrap();
+            drop(socket);
+            // Accepted but never answered.
+            let (socket, _) = listener.accept().await.unwrap();
+            (String::from_utf8(request).unwrap(), socket)
+        });
+
+        let request = Request {
+            method: "POST",
+            target: "/hook?x=1",
+            host: "example.test",
+            headers: &[("Content-Type", "text/plain")],
+            body: b"ping",
+        };
+        let response = send(&address, &request, Duration::from_secs(5)).await.unwrap();
+        assert_eq!((response.status, response.body.as_slice()), (201, &b"pong"[..]));
+        assert!(response.is_success());
+
+        let timeout = Duration::from_millis(50);
+        let e = send(&address, &request, timeout).await.unwrap_err();
+        assert!(matches!(e, Error::Timeout(t) if t == timeout), "{}", e);
+        let (written, _socket) = server.await.unwrap();
+        assert_eq!(
+            written,
+            "POST /hook?x=1 HTTP/1.1\r\nHost: example.test\r\nContent-Type: text/plain\r\n\
+             Content-Length: 4\r\nConnection: close\r\n\r\nping"
+        );
+    }
+}
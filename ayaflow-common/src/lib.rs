@@ -1,4 +1,19 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
+
+// Userspace-only helpers need the standard library.
+#[cfg(all(any(feature = "user", feature = "api", feature = "http"), not(test)))]
+extern crate std;
+
+#[cfg(feature = "api")]
+pub mod api;
+#[cfg(feature = "user")]
+pub mod config_check;
+#[cfg(feature = "user")]
+pub mod filter;
+#[cfg(feature = "http")]
+pub mod http;
+#[cfg(feature = "api")]
+pub mod openapi;
 
 /// Packet metadata passed from the eBPF TC hook to userspace via a RingBuf.
 ///
@@ -8,13 +23,25 @@
 ///
 /// Addresses are stored as 16 bytes to support both IPv4 and IPv6:
 ///   - IPv4: stored in IPv4-mapped-IPv6 format
-///            [0,0,0,0, 0,0,0,0, 0,0,0xff,0xff, a,b,c,d]
+///     `[0,0,0,0, 0,0,0,0, 0,0,0xff,0xff, a,b,c,d]`
 ///   - IPv6: raw 128-bit address.
+///
 /// The `addr_type` field discriminates: 4 = IPv4, 6 = IPv6.
+///
+/// With `capture_non_ip` enabled, ARP and other non-IP frames use the same
+/// struct with `addr_type` 0, zeroed addresses and ports, and only
+/// `ether_type`, `direction`, `pkt_len`, `ifindex` and the MACs filled in.
+///
+/// Every event starts with `version` and `size`, so userspace can tell an
+/// event from an eBPF object built against another layout (see `parse`).
 #[repr(C)]
 #[derive(Clone, Copy)]
 #[cfg_attr(feature = "user", derive(serde::Serialize, serde::Deserialize))]
 pub struct PacketEvent {
+    /// `EVENT_VERSION` of the classifier that wrote the event.
+    pub version: u16,
+    /// `EVENT_SIZE` of the classifier that wrote the event.
+    pub size: u16,
     /// Source IP address (16 bytes, see struct doc for encoding).
     pub src_addr: [u8; 16],
     /// Destination IP address (16 bytes, see struct doc for encoding).
@@ -29,12 +56,155 @@ pub struct PacketEvent {
     pub direction: u8,
     /// Address family: 4 = IPv4, 6 = IPv6.
     pub addr_type: u8,
-    /// Padding to maintain alignment.
-    pub _pad: [u8; 1],
+    /// IPv4 TTL or IPv6 hop limit (occupies what used to be padding).
+    pub ttl: u8,
     /// Total packet length from the IP header.
     pub pkt_len: u32,
+    /// TCP sequence number (host byte order); 0 for other protocols.
+    pub tcp_seq: u32,
+    /// Transport segment length as the headers describe it: `pkt_len` minus
+    /// the IP header for TCP, the UDP length field for UDP.
+    pub l4_len: u16,
+    /// IPv4 DSCP/ECN byte or IPv6 traffic class (DSCP in the top six bits).
+    pub tos: u8,
+    /// Transport header length: the TCP data offset in bytes, or 8 for UDP.
+    /// Payload bytes are `l4_len - l4_header_len`, computed in userspace.
+    pub l4_header_len: u8,
+    /// Index of the interface the packet was seen on.
+    pub ifindex: u32,
+    /// Ethernet frame type (host byte order): `ETHERTYPE_IPV4` or
+    /// `ETHERTYPE_IPV6` for IP packets, including those on L3 interfaces.
+    pub ether_type: u16,
+    /// TCP flags byte (`TCP_FIN`, `TCP_SYN`, ...); 0 for other protocols.
+    pub tcp_flags: u8,
+    /// `BLOCKLIST_MATCHED` or `BLOCKLIST_DROPPED` when an address is on the
+    /// blocklist, else 0.  Was padding, so older events read as no match.
+    pub blocklist: u8,
+    /// Ethernet source address; zero on L3 interfaces, which have none.
+    pub src_mac: [u8; 6],
+    /// Ethernet destination address; zero on L3 interfaces.
+    pub dst_mac: [u8; 6],
+    /// Aligns `ktime_ns`; always zero, so every byte of an event is set.
+    pub _pad: [u8; 4],
+    /// `bpf_ktime_get_ns()` when the packet was classified; 0 for non-IP
+    /// frames.  Monotonic, so gaps between packets are exact even when the
+    /// ring buffer is drained in bursts.
+    pub ktime_ns: u64,
+}
+
+/// Layout version of `PacketEvent`.  Bump it whenever a field is added,
+/// moved or resized.  Events from before the header start with an address,
+/// whose leading bytes are zero for IPv4.
+pub const EVENT_VERSION: u16 = 1;
+
+/// Size of `PacketEvent` in this build.
+pub const EVENT_SIZE: u16 = core::mem::size_of::<PacketEvent>() as u16;
+
+/// Why a ring buffer item was not read as a `PacketEvent`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventError {
+    /// Too short to hold the header.
+    Truncated { len: usize },
+    /// Written with another `EVENT_VERSION`.
+    Version { found: u16 },
+    /// The header or the item itself disagrees with `EVENT_SIZE`.
+    Size { header: u16, item: usize },
+}
+
+impl core::fmt::Display for EventError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            EventError::Truncated { len } => write!(f, "{}-byte event has no header", len),
+            EventError::Version { found } => {
+                write!(f, "event version {} (expected {})", found, EVENT_VERSION)
+            }
+            EventError::Size { header, item } => write!(
+                f,
+                "event of {} bytes with header size {} (expected {})",
+                item, header, EVENT_SIZE
+            ),
+        }
+    }
 }
 
+impl PacketEvent {
+    /// Read a ring buffer item, checking its header first so an event from
+    /// a mismatched classifier is reported rather than misread.
+    pub fn parse(item: &[u8]) -> Result<Self, EventError> {
+        if item.len() < 4 {
+            return Err(EventError::Truncated { len: item.len() });
+        }
+        let version = u16::from_ne_bytes([item[0], item[1]]);
+        let size = u16::from_ne_bytes([item[2], item[3]]);
+        if version != EVENT_VERSION {
+            return Err(EventError::Version { found: version });
+        }
+        if size != EVENT_SIZE || item.len() != EVENT_SIZE as usize {
+            return Err(EventError::Size { header: size, item: item.len() });
+        }
+        // The length was checked above, and every bit pattern is valid.
+        Ok(unsafe { core::ptr::read_unaligned(item.as_ptr() as *const PacketEvent) })
+    }
+
+    /// `parse` for callers that only skip bad items, such as readers of
+    /// pinned maps.  `bytes` may have any alignment.
+    #[cfg(feature = "user")]
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        Self::parse(bytes).ok()
+    }
+
+    /// The event as the classifier writes it to the ring buffer.
+    #[cfg(feature = "user")]
+    pub fn as_bytes(&self) -> &[u8] {
+        // repr(C) with no implicit padding, so every byte is initialized.
+        unsafe {
+            core::slice::from_raw_parts(self as *const Self as *const u8, EVENT_SIZE as usize)
+        }
+    }
+}
+
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+pub const ETHERTYPE_IPV6: u16 = 0x86dd;
+
+// TCP header flag bits, as in byte 13 of the header.
+pub const TCP_FIN: u8 = 0x01;
+pub const TCP_SYN: u8 = 0x02;
+pub const TCP_RST: u8 = 0x04;
+pub const TCP_ACK: u8 = 0x10;
+
+/// Bytes of an ICMP or ICMPv6 header the classifier requires: type, code,
+/// checksum and four type-specific bytes.
+pub const ICMP_HEADER_LEN: usize = 8;
+
+/// `PacketEvent::blocklist`: an address matched and the packet passed.
+pub const BLOCKLIST_MATCHED: u8 = 1;
+/// `PacketEvent::blocklist`: an address matched and the packet was dropped.
+pub const BLOCKLIST_DROPPED: u8 = 2;
+
+/// Entries the kernel `BLOCKLIST` trie holds.
+pub const BLOCKLIST_MAX_ENTRIES: u32 = 4096;
+
+/// Values of CONFIG[5], what the classifier does with blocklisted packets.
+pub const BLOCKLIST_OFF: u32 = 0;
+pub const BLOCKLIST_FLAG: u32 = 1;
+pub const BLOCKLIST_DROP: u32 = 2;
+
+/// Single ports the kernel `FILTER_PORTS` map holds for the port prefilter.
+pub const PORT_FILTER_MAX_PORTS: u32 = 1024;
+/// Ranges the kernel `FILTER_PORT_RANGES` array holds, each packed as
+/// `lo << 16 | hi`.
+pub const PORT_FILTER_MAX_RANGES: u32 = 16;
+/// CONFIG[6] with the port prefilter off; otherwise one more than the
+/// number of ranges in use.
+pub const PORT_FILTER_OFF: u32 = 0;
+
+/// Prefixes the kernel `FILTER_IPS` trie holds for the address prefilter.
+pub const IP_FILTER_MAX_ENTRIES: u32 = 1024;
+/// Values of CONFIG[7], whether the classifier checks `FILTER_IPS`.
+pub const IP_FILTER_OFF: u32 = 0;
+pub const IP_FILTER_ON: u32 = 1;
+
 /// Maximum bytes of L7 payload forwarded from eBPF to userspace.
 ///
 /// 256 bytes is enough for virtually all DNS queries and TLS ClientHello
@@ -75,9 +245,218 @@ pub struct PayloadEvent {
     pub payload: [u8; MAX_PAYLOAD_LEN],
 }
 
+#[cfg(feature = "user")]
+impl PayloadEvent {
+    /// Read a `PAYLOAD_EVENTS` ring buffer item of exactly this struct's
+    /// size.  `bytes` may have any alignment.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != core::mem::size_of::<Self>() {
+            return None;
+        }
+        // The length was checked above, and every bit pattern is valid.
+        Some(unsafe { core::ptr::read_unaligned(bytes.as_ptr() as *const Self) })
+    }
+}
+
+/// Key of the kernel-side per-CPU flow aggregation map.
+///
+/// Only used when `kernel_aggregation` is enabled: the classifier then
+/// accumulates counters per key instead of emitting one `PacketEvent` per
+/// packet.  Field meanings match `PacketEvent`; padding must be zeroed so
+/// identical flows hash identically.
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct FlowKey {
+    pub src_addr: [u8; 16],
+    pub dst_addr: [u8; 16],
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub protocol: u8,
+    pub direction: u8,
+    pub addr_type: u8,
+    pub _pad: [u8; 1],
+    pub ifindex: u32,
+}
+
+/// Per-CPU counters stored in the flow aggregation map.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct FlowCounters {
+    pub packets: u64,
+    pub bytes: u64,
+    /// Transport payload bytes (wire bytes minus IP and L4 headers).
+    pub payload_bytes: u64,
+}
+
+/// Index into the kernel `COUNTERS` per-CPU array: flow map inserts that
+/// failed because the map was full.
+pub const COUNTER_FLOW_OVERFLOW: u32 = 0;
+
+/// Index into `COUNTERS`: packet events dropped because the `EVENTS` ring
+/// buffer was full.
+pub const COUNTER_RING_BUF_DROPS: u32 = 1;
+
+/// Index into `COUNTERS`: packets dropped because an address was on the
+/// blocklist.
+pub const COUNTER_BLOCKLIST_DROPS: u32 = 2;
+
+/// Entries the kernel `FLOWS` map holds.
+pub const FLOW_MAP_MAX_ENTRIES: u32 = 65536;
+
+/// Indices into the kernel `CONFIG` array of runtime flags, which userspace
+/// writes at load time.
+pub const CONFIG_DEEP_INSPECT: u32 = 0;
+pub const CONFIG_ENABLE_IPV6: u32 = 1;
+pub const CONFIG_KERNEL_AGGREGATION: u32 = 2;
+pub const CONFIG_L3_INTERFACE: u32 = 3;
+pub const CONFIG_CAPTURE_NON_IP: u32 = 4;
+pub const CONFIG_BLOCKLIST: u32 = 5;
+pub const CONFIG_PORT_FILTER: u32 = 6;
+pub const CONFIG_IP_FILTER: u32 = 7;
+/// Entries in `CONFIG`.
+pub const CONFIG_LEN: u32 = 8;
+
+/// A map the classifier defines and the loader takes by name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KernelMap {
+    pub name: &'static str,
+    pub key_size: u32,
+    pub value_size: u32,
+    /// 0 for the ring buffers, which the loader sizes.
+    pub max_entries: u32,
+}
+
+const fn kernel_map(name: &'static str, key: usize, value: usize, max_entries: u32) -> KernelMap {
+    KernelMap { name, key_size: key as u32, value_size: value as u32, max_entries }
+}
+
+/// LPM trie keys: a `u32` prefix length, then an IPv6 or IPv4-mapped address.
+const LPM_KEY_SIZE: usize = 4 + 16;
+
+/// Every map of the classifier.
+pub const KERNEL_MAPS: [KernelMap; 9] = [
+    kernel_map("EVENTS", 0, 0, 0),
+    kernel_map("PAYLOAD_EVENTS", 0, 0, 0),
+    kernel_map(
+        "FLOWS",
+        core::mem::size_of::<FlowKey>(),
+        core::mem::size_of::<FlowCounters>(),
+        FLOW_MAP_MAX_ENTRIES,
+    ),
+    kernel_map("COUNTERS", 4, 8, 3),
+    kernel_map("BLOCKLIST", LPM_KEY_SIZE, 1, BLOCKLIST_MAX_ENTRIES),
+    kernel_map("FILTER_PORTS", 2, 1, PORT_FILTER_MAX_PORTS),
+    kernel_map("FILTER_PORT_RANGES", 4, 4, PORT_FILTER_MAX_RANGES),
+    kernel_map("FILTER_IPS", LPM_KEY_SIZE, 1, IP_FILTER_MAX_ENTRIES),
+    kernel_map("CONFIG", 4, 4, CONFIG_LEN),
+];
+
+/// Version of the contract between the classifier and userspace that the
+/// sizes in `ABI_HASH` do not show.  Bump it whenever a map, a `CONFIG`
+/// index or the meaning of a value changes.
+pub const ABI_VERSION: u32 = 2;
+
+/// Identifies the contract between the classifier and userspace:
+/// `ABI_VERSION`, `EVENT_VERSION`, the size of every struct they share,
+/// the `CONFIG` indices and every map in `KERNEL_MAPS`.
+pub const ABI_HASH: u32 = abi_hash(
+    &[
+        ABI_VERSION,
+        EVENT_VERSION as u32,
+        EVENT_SIZE as u32,
+        core::mem::size_of::<PayloadEvent>() as u32,
+        core::mem::size_of::<FlowKey>() as u32,
+        core::mem::size_of::<FlowCounters>() as u32,
+        CONFIG_DEEP_INSPECT,
+        CONFIG_ENABLE_IPV6,
+        CONFIG_KERNEL_AGGREGATION,
+        CONFIG_L3_INTERFACE,
+        CONFIG_CAPTURE_NON_IP,
+        CONFIG_BLOCKLIST,
+        CONFIG_PORT_FILTER,
+        CONFIG_IP_FILTER,
+        CONFIG_LEN,
+    ],
+    &KERNEL_MAPS,
+);
+
+/// Start of `ABI_MARKER`.
+pub const ABI_MARKER_PREFIX: &[u8] = b"AYAFLOW_ABI=";
+
+/// `ABI_MARKER_PREFIX` followed by `ABI_HASH` in hex.  The eBPF object
+/// embeds it, so the loader can find which contract the object was built
+/// against.
+pub const ABI_MARKER: [u8; 20] = abi_marker(ABI_HASH);
+
+/// FNV-1a over the words' little-endian bytes, then each map's name,
+/// sizes and capacity.
+const fn abi_hash(words: &[u32], maps: &[KernelMap]) -> u32 {
+    let mut hash: u32 = 0x811c_9dc5;
+    let mut i = 0;
+    while i < words.len() {
+        hash = fnv1a(hash, &words[i].to_le_bytes());
+        i += 1;
+    }
+    let mut m = 0;
+    while m < maps.len() {
+        let map = &maps[m];
+        // The length keeps "AB" + "C" apart from "A" + "BC".
+        hash = fnv1a(hash, &(map.name.len() as u32).to_le_bytes());
+        hash = fnv1a(hash, map.name.as_bytes());
+        hash = fnv1a(hash, &map.key_size.to_le_bytes());
+        hash = fnv1a(hash, &map.value_size.to_le_bytes());
+        hash = fnv1a(hash, &map.max_entries.to_le_bytes());
+        m += 1;
+    }
+    hash
+}
+
+const fn fnv1a(mut hash: u32, bytes: &[u8]) -> u32 {
+    let mut i = 0;
+    while i < bytes.len() {
+        hash ^= bytes[i] as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+        i += 1;
+    }
+    hash
+}
+
+const fn abi_marker(hash: u32) -> [u8; 20] {
+    const HEX: &[u8; 16] = b"0123456789abcdef";
+    let mut marker = [0u8; 20];
+    let mut i = 0;
+    while i < ABI_MARKER_PREFIX.len() {
+        marker[i] = ABI_MARKER_PREFIX[i];
+        i += 1;
+    }
+    let mut j = 0;
+    while j < 8 {
+        marker[12 + j] = HEX[((hash >> (28 - 4 * j)) & 0xf) as usize];
+        j += 1;
+    }
+    marker
+}
+
+/// The `ABI_HASH` an eBPF object was built with, from its embedded marker;
+/// None for objects from before the marker.
+pub fn embedded_abi_hash(object: &[u8]) -> Option<u32> {
+    let start = object
+        .windows(ABI_MARKER_PREFIX.len())
+        .position(|window| window == ABI_MARKER_PREFIX)?
+        + ABI_MARKER_PREFIX.len();
+    let hex = core::str::from_utf8(object.get(start..start + 8)?).ok()?;
+    u32::from_str_radix(hex, 16).ok()
+}
+
 #[cfg(feature = "user")]
 unsafe impl aya::Pod for PacketEvent {}
 
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for FlowKey {}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for FlowCounters {}
+
 #[cfg(feature = "user")]
 unsafe impl aya::Pod for PayloadEvent {}
 
@@ -93,3 +472,309 @@ pub fn ipv4_mapped(addr: u32) -> [u8; 16] {
         octets[0], octets[1], octets[2], octets[3],
     ]
 }
+
+/// Granularity of the storage writer's aggregated rows.
+///
+/// Shared by both storage writers so a given setting collapses traffic the
+/// same way in each.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "user",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum AggregationKey {
+    /// One row per (src_ip, src_port, dst_ip, dst_port, protocol).
+    #[default]
+    Connection,
+    /// One row per (src_ip, dst_ip, protocol); both ports are dropped.
+    HostPair,
+    /// As `HostPair`, but the service port is kept and only the client's
+    /// ephemeral port is dropped.
+    HostPairPort,
+}
+
+impl AggregationKey {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AggregationKey::Connection => "connection",
+            AggregationKey::HostPair => "host_pair",
+            AggregationKey::HostPairPort => "host_pair_port",
+        }
+    }
+
+    /// The `(src_port, dst_port)` a packet is aggregated under.  Dropped
+    /// ports are 0, which is also what the aggregated row stores.
+    pub fn key_ports(self, src_port: u16, dst_port: u16) -> (u16, u16) {
+        match self {
+            AggregationKey::Connection => (src_port, dst_port),
+            AggregationKey::HostPair => (0, 0),
+            AggregationKey::HostPairPort => match service_side(src_port, dst_port) {
+                ServiceSide::Src => (src_port, 0),
+                ServiceSide::Dst => (0, dst_port),
+            },
+        }
+    }
+}
+
+impl core::str::FromStr for AggregationKey {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "connection" => Ok(AggregationKey::Connection),
+            "host_pair" => Ok(AggregationKey::HostPair),
+            "host_pair_port" => Ok(AggregationKey::HostPairPort),
+            _ => Err("expected connection, host_pair, or host_pair_port"),
+        }
+    }
+}
+
+/// Which end of a flow is the server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ServiceSide {
+    Src,
+    Dst,
+}
+
+/// Guess the server end of a flow from its ports: the lower-numbered port
+/// is the service port.  Well-known (< 1024) and registered ports sit below
+/// every OS's ephemeral range, so this also picks them over a client's
+/// source port.  Equal ports (including 0, no transport header) count as
+/// the destination.
+pub fn service_side(src_port: u16, dst_port: u16) -> ServiceSide {
+    if src_port < dst_port {
+        ServiceSide::Src
+    } else {
+        ServiceSide::Dst
+    }
+}
+
+/// First port of the Linux ephemeral range, where clients' source ports
+/// come from.
+pub const EPHEMERAL_PORT_MIN: u16 = 32768;
+
+/// How a flow's service port is picked from its two ports, wherever the
+/// agent groups traffic by service.  Both rules ignore a zeroed port, as
+/// in aggregated rows, and give a request and its reply the same port.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "user",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum ServicePortRule {
+    /// The lower-numbered port, as `service_side`.
+    #[default]
+    Lower,
+    /// As `Lower`, but a flow between two ephemeral ports (32768 and up)
+    /// has no service port.
+    NonEphemeral,
+}
+
+impl ServicePortRule {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ServicePortRule::Lower => "lower",
+            ServicePortRule::NonEphemeral => "non_ephemeral",
+        }
+    }
+
+    /// The server end of a flow, None when it has no service port.
+    pub fn service_side(self, src_port: u16, dst_port: u16) -> Option<ServiceSide> {
+        let (side, port) = match (src_port, dst_port) {
+            (0, 0) => return None,
+            (0, _) => (ServiceSide::Dst, dst_port),
+            (_, 0) => (ServiceSide::Src, src_port),
+            _ => match service_side(src_port, dst_port) {
+                ServiceSide::Src => (ServiceSide::Src, src_port),
+                ServiceSide::Dst => (ServiceSide::Dst, dst_port),
+            },
+        };
+        match self {
+            ServicePortRule::NonEphemeral if port >= EPHEMERAL_PORT_MIN => None,
+            _ => Some(side),
+        }
+    }
+
+    pub fn service_port(self, src_port: u16, dst_port: u16) -> Option<u16> {
+        self.service_side(src_port, dst_port).map(|side| match side {
+            ServiceSide::Src => src_port,
+            ServiceSide::Dst => dst_port,
+        })
+    }
+}
+
+/// The storage sampling gate both agents apply after updating live state:
+/// of the packets it is asked about, it keeps every `rate`th.  A rate of 0
+/// or 1 keeps them all.
+///
+/// The counter wraps after 2^32 packets.  A rate that does not divide 2^32
+/// then keeps two packets in a row once, which is harmless.
+#[derive(Clone, Copy, Debug)]
+pub struct Sampler {
+    rate: u32,
+    counter: u32,
+}
+
+impl Sampler {
+    pub fn new(rate: u32) -> Self {
+        Self {
+            rate: rate.max(1),
+            counter: 0,
+        }
+    }
+
+    /// The rate in effect, 1 when everything is kept.
+    pub fn rate(&self) -> u32 {
+        self.rate
+    }
+
+    /// Count one packet and say whether storage keeps it.
+    #[inline]
+    pub fn keep(&mut self) -> bool {
+        self.counter = self.counter.wrapping_add(1);
+        self.counter.is_multiple_of(self.rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sampler_gate() {
+        let kept = |sampler: &mut Sampler, n: usize| -> Vec<bool> {
+            (0..n).map(|_| sampler.keep()).collect()
+        };
+        for rate in [0, 1] {
+            let mut sampler = Sampler::new(rate);
+            assert_eq!(sampler.rate(), 1);
+            assert!(kept(&mut sampler, 5).into_iter().all(|keep| keep));
+        }
+        let mut sampler = Sampler::new(3);
+        assert_eq!(kept(&mut sampler, 6), [false, false, true, false, false, true]);
+
+        // Across the wrap a power of two stays evenly spaced...
+        let mut sampler = Sampler { rate: 4, counter: u32::MAX - 5 };
+        assert_eq!(
+            kept(&mut sampler, 9),
+            [false, true, false, false, false, true, false, false, false]
+        );
+        // ...while other rates keep u32::MAX and 0 back to back, then carry on.
+        let mut sampler = Sampler { rate: 3, counter: u32::MAX - 3 };
+        assert_eq!(kept(&mut sampler, 7), [false, false, true, true, false, false, true]);
+    }
+
+    #[test]
+    fn test_packet_event_layout() {
+        // The header stays first; fields added later go at the end.
+        assert_eq!(core::mem::offset_of!(PacketEvent, version), 0);
+        assert_eq!(core::mem::offset_of!(PacketEvent, size), 2);
+        assert_eq!(core::mem::offset_of!(PacketEvent, src_addr), 4);
+        assert_eq!(core::mem::offset_of!(PacketEvent, ifindex), 56);
+        assert_eq!(core::mem::offset_of!(PacketEvent, ether_type), 60);
+        assert_eq!(core::mem::offset_of!(PacketEvent, blocklist), 63);
+        assert_eq!(core::mem::offset_of!(PacketEvent, src_mac), 64);
+        assert_eq!(core::mem::offset_of!(PacketEvent, _pad), 76);
+        assert_eq!(core::mem::offset_of!(PacketEvent, ktime_ns), 80);
+        assert_eq!(core::mem::size_of::<PacketEvent>(), 88);
+    }
+
+    #[test]
+    fn test_parse_checks_header() {
+        let mut item = [0u8; EVENT_SIZE as usize + 8];
+        item[..2].copy_from_slice(&EVENT_VERSION.to_ne_bytes());
+        item[2..4].copy_from_slice(&EVENT_SIZE.to_ne_bytes());
+        item[4 + 15] = 7;
+        let event = PacketEvent::parse(&item[..EVENT_SIZE as usize]).unwrap();
+        assert_eq!(event.src_addr[15], 7);
+
+        // A newer classifier's longer event, or one cut short.
+        let err = |bytes: &[u8]| PacketEvent::parse(bytes).err();
+        let size = |header, item| Some(EventError::Size { header, item });
+        assert_eq!(err(&item), size(EVENT_SIZE, item.len()));
+        assert_eq!(err(&item[..40]), size(EVENT_SIZE, 40));
+        assert_eq!(err(&item[..3]), Some(EventError::Truncated { len: 3 }));
+
+        // An event from before the header: an IPv4-mapped address first.
+        assert_eq!(err(&[0u8; 80]), Some(EventError::Version { found: 0 }));
+        item[2..4].copy_from_slice(&80u16.to_ne_bytes());
+        assert_eq!(err(&item[..80]), size(80, 80));
+    }
+
+    #[test]
+    fn test_abi_marker() {
+        let mut object = b"\x7fELF....".to_vec();
+        assert_eq!(embedded_abi_hash(&object), None);
+        object.extend_from_slice(&ABI_MARKER);
+        object.extend_from_slice(b"\0rest");
+        assert_eq!(embedded_abi_hash(&object), Some(ABI_HASH));
+        let version = EVENT_VERSION as u32;
+        assert_ne!(abi_hash(&[version + 1], &[]), abi_hash(&[version], &[]));
+
+        // A map renamed, resized or with another capacity is another ABI.
+        let base = abi_hash(&[], &KERNEL_MAPS);
+        let changed = |change: fn(&mut KernelMap)| {
+            let mut maps = KERNEL_MAPS;
+            change(&mut maps[5]);
+            abi_hash(&[], &maps)
+        };
+        assert_ne!(changed(|map| map.name = "FILTER_PORTZ"), base);
+        assert_ne!(changed(|map| map.key_size = 4), base);
+        assert_ne!(changed(|map| map.value_size = 2), base);
+        assert_ne!(changed(|map| map.max_entries += 1), base);
+        assert_eq!(changed(|_| ()), base);
+        let names: Vec<&str> = KERNEL_MAPS.iter().map(|map| map.name).collect();
+        assert!(names.contains(&"FILTER_PORTS") && names.contains(&"CONFIG"));
+    }
+
+    #[test]
+    fn test_service_side() {
+        // Requests and the replies to them agree on the server.
+        assert_eq!(service_side(51000, 443), ServiceSide::Dst);
+        assert_eq!(service_side(443, 51000), ServiceSide::Src);
+        // Registered ports beat Linux (32768+) and IANA (49152+) ephemerals.
+        assert_eq!(service_side(40112, 5432), ServiceSide::Dst);
+        assert_eq!(service_side(8080, 61000), ServiceSide::Src);
+        // Both well-known (e.g. rsh from a privileged source port).
+        assert_eq!(service_side(1022, 514), ServiceSide::Dst);
+        assert_eq!(service_side(0, 0), ServiceSide::Dst);
+        assert_eq!(service_side(53, 53), ServiceSide::Dst);
+    }
+
+    #[test]
+    fn test_service_port_rules() {
+        for rule in [ServicePortRule::Lower, ServicePortRule::NonEphemeral] {
+            assert_eq!(rule.service_port(51000, 443), Some(443));
+            assert_eq!(rule.service_port(443, 51000), Some(443));
+            // Both registered: the lower one, whichever way the packet goes.
+            assert_eq!(rule.service_port(443, 8443), Some(443));
+            assert_eq!(rule.service_port(8443, 443), Some(443));
+            // A zeroed side from host_pair_port aggregation is skipped.
+            assert_eq!(rule.service_port(5432, 0), Some(5432));
+            assert_eq!(rule.service_port(0, 0), None);
+        }
+        // Two ephemeral ports, as between peer-to-peer clients.
+        assert_eq!(ServicePortRule::Lower.service_port(51000, 60001), Some(51000));
+        assert_eq!(ServicePortRule::NonEphemeral.service_port(51000, 60001), None);
+        assert_eq!(ServicePortRule::NonEphemeral.service_port(60001, 32767), Some(32767));
+        assert_eq!(ServicePortRule::Lower.service_port(0, 51000), Some(51000));
+        assert_eq!(ServicePortRule::NonEphemeral.service_port(0, 51000), None);
+    }
+
+    #[test]
+    fn test_key_ports() {
+        assert_eq!(AggregationKey::Connection.key_ports(51000, 443), (51000, 443));
+        assert_eq!(AggregationKey::HostPair.key_ports(51000, 443), (0, 0));
+        assert_eq!(AggregationKey::HostPairPort.key_ports(51000, 443), (0, 443));
+        assert_eq!(AggregationKey::HostPairPort.key_ports(443, 51000), (443, 0));
+        for key in [
+            AggregationKey::Connection,
+            AggregationKey::HostPair,
+            AggregationKey::HostPairPort,
+        ] {
+            assert_eq!(key.as_str().parse(), Ok(key));
+        }
+    }
+}
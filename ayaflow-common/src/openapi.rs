@@ -0,0 +1,280 @@
+//! Minimal OpenAPI 3 schema generation, for the agent's API document and
+//! the types in `api`.
+//!
+//! Types served by the API implement `ApiSchema`, normally through the
+//! `api_schema!` macro, which wraps the struct definition itself so the
+//! documented fields cannot drift from the serialized ones.  Types with
+//! custom serde representations implement the trait by hand and are checked
+//! against a serialized sample in the tests.
+
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::format;
+use std::string::{String, ToString};
+use std::vec::Vec;
+
+use serde_json::{json, Map};
+
+pub use serde_json::Value;
+
+/// A type that can describe its JSON representation as an OpenAPI schema.
+pub trait ApiSchema {
+    /// Whether an object field of this type is always present.
+    const REQUIRED: bool = true;
+
+    fn schema() -> Value;
+}
+
+macro_rules! impl_primitive_schema {
+    ($($ty:ty => $schema:tt),* $(,)?) => {
+        $(
+            impl ApiSchema for $ty {
+                fn schema() -> Value {
+                    json!($schema)
+                }
+            }
+        )*
+    };
+}
+
+impl_primitive_schema! {
+    bool => { "type": "boolean" },
+    u8 => { "type": "integer", "minimum": 0, "maximum": 255 },
+    u16 => { "type": "integer", "minimum": 0, "maximum": 65535 },
+    u32 => { "type": "integer", "format": "int64", "minimum": 0 },
+    u64 => { "type": "integer", "format": "int64", "minimum": 0 },
+    usize => { "type": "integer", "format": "int64", "minimum": 0 },
+    i64 => { "type": "integer", "format": "int64" },
+    f64 => { "type": "number", "format": "double" },
+    String => { "type": "string" },
+    IpAddr => { "type": "string", "format": "ip" },
+    ipnet::IpNet => { "type": "string", "format": "cidr" },
+}
+
+impl<T: ApiSchema> ApiSchema for Option<T> {
+    const REQUIRED: bool = false;
+
+    fn schema() -> Value {
+        T::schema()
+    }
+}
+
+impl<T: ApiSchema> ApiSchema for Vec<T> {
+    fn schema() -> Value {
+        json!({ "type": "array", "items": T::schema() })
+    }
+}
+
+impl<T: ApiSchema> ApiSchema for BTreeMap<String, T> {
+    fn schema() -> Value {
+        json!({ "type": "object", "additionalProperties": T::schema() })
+    }
+}
+
+/// Free-form JSON, such as the effective configuration.
+impl ApiSchema for Value {
+    fn schema() -> Value {
+        json!({ "type": "object" })
+    }
+}
+
+/// Build an object schema from `(name, schema, required)` triples.
+pub fn object_schema(fields: &[(&str, Value, bool)]) -> Value {
+    let mut properties = Map::new();
+    let mut required = Vec::new();
+    for (name, schema, is_required) in fields {
+        properties.insert(name.to_string(), schema.clone());
+        if *is_required {
+            required.push(Value::from(*name));
+        }
+    }
+    json!({ "type": "object", "properties": properties, "required": required })
+}
+
+/// Schema for a string enum with the given serialized variant names.
+pub fn string_enum(variants: &[&str]) -> Value {
+    json!({ "type": "string", "enum": variants })
+}
+
+/// Turn a query-parameter struct's object schema into OpenAPI parameters.
+///
+/// Every query parameter in this API is optional (absent ones fall back to a
+/// default), so none are marked required.
+pub fn query_parameters<T: ApiSchema>() -> Value {
+    let schema = T::schema();
+    let params: Vec<Value> = schema["properties"]
+        .as_object()
+        .map(|props| {
+            props
+                .iter()
+                .map(|(name, schema)| {
+                    json!({
+                        "name": name,
+                        "in": "query",
+                        "required": false,
+                        "schema": schema,
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    Value::from(params)
+}
+
+/// Check `value` against a schema built by this module: JSON types, enum
+/// values, integer bounds and address formats; required properties present
+/// and not null, optional ones null or absent, and no undocumented ones.
+/// String patterns are not checked.  Returns where the first mismatch is,
+/// as a path from `$`.
+pub fn conforms(value: &Value, schema: &Value) -> Result<(), String> {
+    check(value, schema, "$")
+}
+
+fn check(value: &Value, schema: &Value, path: &str) -> Result<(), String> {
+    let fail = |what: &str| Err(format!("{}: {}, got {}", path, what, value));
+    if let Some(variants) = schema["enum"].as_array() {
+        if !variants.contains(value) {
+            return fail("not one of the enum values");
+        }
+    }
+    match schema["type"].as_str() {
+        Some("boolean") if !value.is_boolean() => fail("expected a boolean"),
+        Some("number") if !value.is_number() => fail("expected a number"),
+        Some("integer") => {
+            if !(value.is_i64() || value.is_u64()) {
+                return fail("expected an integer");
+            }
+            let n = value.as_f64().unwrap_or_default();
+            if schema["minimum"].as_f64().is_some_and(|min| n < min)
+                || schema["maximum"].as_f64().is_some_and(|max| n > max)
+            {
+                return fail("out of range");
+            }
+            Ok(())
+        }
+        Some("string") => {
+            let Some(s) = value.as_str() else {
+                return fail("expected a string");
+            };
+            let valid = match schema["format"].as_str() {
+                Some("ip") => s.parse::<IpAddr>().is_ok(),
+                Some("cidr") => s.parse::<ipnet::IpNet>().is_ok(),
+                _ => true,
+            };
+            if valid {
+                Ok(())
+            } else {
+                fail("not in the documented format")
+            }
+        }
+        Some("array") => {
+            let Some(items) = value.as_array() else {
+                return fail("expected an array");
+            };
+            for (i, item) in items.iter().enumerate() {
+                check(item, &schema["items"], &format!("{}[{}]", path, i))?;
+            }
+            Ok(())
+        }
+        Some("object") => {
+            let Some(object) = value.as_object() else {
+                return fail("expected an object");
+            };
+            check_object(object, schema, path)
+        }
+        _ => Ok(()),
+    }
+}
+
+fn check_object(object: &Map<String, Value>, schema: &Value, path: &str) -> Result<(), String> {
+    let required = |name: &str| {
+        schema["required"]
+            .as_array()
+            .is_some_and(|names| names.iter().any(|n| n == name))
+    };
+    if let Some(properties) = schema["properties"].as_object() {
+        for (name, property) in properties {
+            let field = format!("{}.{}", path, name);
+            match object.get(name) {
+                None | Some(Value::Null) if required(name) => {
+                    return Err(format!("{}: required but missing or null", field));
+                }
+                None | Some(Value::Null) => {}
+                Some(value) => check(value, property, &field)?,
+            }
+        }
+    }
+    let additional = &schema["additionalProperties"];
+    for (name, value) in object {
+        let field = format!("{}.{}", path, name);
+        if additional.is_object() {
+            check(value, additional, &field)?;
+        } else if schema["properties"].is_object() && schema["properties"].get(name).is_none() {
+            return Err(format!("{}: not documented", field));
+        }
+    }
+    Ok(())
+}
+
+/// Define a struct and derive its `ApiSchema` from the same field list.
+///
+/// Fields are documented under their Rust names, so structs that rename
+/// fields with serde attributes must implement `ApiSchema` by hand.
+#[macro_export]
+macro_rules! api_schema {
+    (
+        $(#[$attr:meta])*
+        $vis:vis struct $name:ident {
+            $( $(#[$field_attr:meta])* $field_vis:vis $field:ident : $ty:ty ),* $(,)?
+        }
+    ) => {
+        $(#[$attr])*
+        $vis struct $name {
+            $( $(#[$field_attr])* $field_vis $field : $ty ),*
+        }
+
+        impl $crate::openapi::ApiSchema for $name {
+            fn schema() -> $crate::openapi::Value {
+                $crate::openapi::object_schema(&[
+                    $((
+                        stringify!($field),
+                        <$ty as $crate::openapi::ApiSchema>::schema(),
+                        <$ty as $crate::openapi::ApiSchema>::REQUIRED,
+                    )),*
+                ])
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    api_schema! {
+        #[derive(serde::Serialize)]
+        struct Sample {
+            count: u8,
+            name: Option<String>,
+            peers: Vec<IpAddr>,
+        }
+    }
+
+    #[test]
+    fn test_conforms() {
+        let schema = Sample::schema();
+        let check = |value: Value| conforms(&value, &schema);
+        assert_eq!(check(json!({ "count": 1, "name": "a", "peers": ["10.0.0.1"] })), Ok(()));
+        assert_eq!(check(json!({ "count": 1, "name": null, "peers": [] })), Ok(()));
+        assert_eq!(check(json!({ "count": 1, "peers": [] })), Ok(()));
+
+        let fails = |value, reason: &str| check(value).unwrap_err().starts_with(reason);
+        assert!(fails(json!({ "peers": [] }), "$.count: required"));
+        assert!(fails(json!({ "count": null, "peers": [] }), "$.count: required"));
+        assert!(fails(json!({ "count": 256, "peers": [] }), "$.count: out of range"));
+        assert!(fails(json!({ "count": "1", "peers": [] }), "$.count: expected an integer"));
+        assert!(fails(json!({ "count": 1, "peers": ["host"] }), "$.peers[0]: not in"));
+        assert!(fails(json!({ "count": 1, "peers": [], "extra": 1 }), "$.extra: not documented"));
+        assert!(fails(json!({ "count": 1, "name": 2, "peers": [] }), "$.name: expected a string"));
+    }
+}
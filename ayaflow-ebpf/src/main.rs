@@ -3,15 +3,32 @@
 #![allow(non_upper_case_globals)]
 
 use aya_ebpf::{
-    bindings::{__sk_buff, TC_ACT_PIPE},
+    bindings::{
+        __sk_buff,
+        xdp_action::{XDP_DROP, XDP_PASS},
+        xdp_md, BPF_F_NO_PREALLOC, TC_ACT_PIPE, TC_ACT_SHOT,
+    },
+    helpers::bpf_ktime_get_ns,
     macros::map,
-    maps::{Array, RingBuf},
-    programs::TcContext,
+    maps::{
+        lpm_trie::{Key, LpmTrie},
+        Array, HashMap, PerCpuArray, PerCpuHashMap, RingBuf,
+    },
+    programs::{TcContext, XdpContext},
+};
+use ayaflow_common::{
+    ipv4_mapped, FlowCounters, FlowKey, PacketEvent, PayloadEvent, BLOCKLIST_DROP,
+    BLOCKLIST_DROPPED, BLOCKLIST_MATCHED, BLOCKLIST_MAX_ENTRIES, BLOCKLIST_OFF, CONFIG_BLOCKLIST,
+    CONFIG_CAPTURE_NON_IP, CONFIG_DEEP_INSPECT, CONFIG_ENABLE_IPV6, CONFIG_IP_FILTER,
+    CONFIG_KERNEL_AGGREGATION, CONFIG_L3_INTERFACE, CONFIG_LEN, CONFIG_PORT_FILTER,
+    COUNTER_BLOCKLIST_DROPS, COUNTER_FLOW_OVERFLOW, COUNTER_RING_BUF_DROPS, ETHERTYPE_IPV4,
+    ETHERTYPE_IPV6, EVENT_SIZE, EVENT_VERSION, FLOW_MAP_MAX_ENTRIES, ICMP_HEADER_LEN,
+    IP_FILTER_MAX_ENTRIES, IP_FILTER_OFF, MAX_PAYLOAD_LEN, ABI_MARKER, PORT_FILTER_MAX_PORTS,
+    PORT_FILTER_MAX_RANGES, PORT_FILTER_OFF,
 };
-use ayaflow_common::{ipv4_mapped, PacketEvent, PayloadEvent, MAX_PAYLOAD_LEN};
 use core::ptr;
 use network_types::{
-    eth::{EthHdr, EtherType},
+    eth::EthHdr,
     ip::{IpProto, Ipv4Hdr, Ipv6Hdr},
     tcp::TcpHdr,
     udp::UdpHdr,
@@ -30,11 +47,56 @@ static EVENTS: RingBuf = RingBuf::with_byte_size(256 * 1024, 0);
 #[map]
 static PAYLOAD_EVENTS: RingBuf = RingBuf::with_byte_size(256 * 1024, 0);
 
+/// Per-CPU flow counters -- only written to when kernel aggregation is
+/// enabled via CONFIG[2].  Userspace sweeps and clears it every window.
+#[map]
+static FLOWS: PerCpuHashMap<FlowKey, FlowCounters> =
+    PerCpuHashMap::with_max_entries(FLOW_MAP_MAX_ENTRIES, 0);
+
+/// The layout contract this object was built against, found by the loader
+/// in the object's bytes (see `embedded_abi_hash`).
+#[no_mangle]
+#[used]
+static AYAFLOW_ABI: [u8; 20] = ABI_MARKER;
+
+/// Per-CPU diagnostic counters (see `COUNTER_*` in ayaflow-common).
+#[map]
+static COUNTERS: PerCpuArray<u64> = PerCpuArray::with_max_entries(3, 0);
+
+/// Blocklisted prefixes, keyed by IPv4-mapped-IPv6 address (IPv4 prefixes
+/// are 96 bits longer).  Only consulted when CONFIG[5] is not
+/// `BLOCKLIST_OFF`.
+#[map]
+static BLOCKLIST: LpmTrie<[u8; 16], u8> =
+    LpmTrie::with_max_entries(BLOCKLIST_MAX_ENTRIES, BPF_F_NO_PREALLOC);
+
+/// Single ports of the port prefilter.  Only consulted when CONFIG[6] is
+/// not `PORT_FILTER_OFF`.
+#[map]
+static FILTER_PORTS: HashMap<u16, u8> = HashMap::with_max_entries(PORT_FILTER_MAX_PORTS, 0);
+
+/// Inclusive port ranges of the prefilter, `lo << 16 | hi`; CONFIG[6] - 1
+/// of them are in use.
+#[map]
+static FILTER_PORT_RANGES: Array<u32> = Array::with_max_entries(PORT_FILTER_MAX_RANGES, 0);
+
+/// Included prefixes of the address prefilter, keyed like `BLOCKLIST`.
+/// Only consulted when CONFIG[7] is not `IP_FILTER_OFF`.
+#[map]
+static FILTER_IPS: LpmTrie<[u8; 16], u8> =
+    LpmTrie::with_max_entries(IP_FILTER_MAX_ENTRIES, BPF_F_NO_PREALLOC);
+
 /// Runtime configuration flags (written by userspace at load time).
-///   Index 0: deep_inspect  (0 = off, 1 = on)
-///   Index 1: enable_ipv6   (0 = off, 1 = on)
+///   Index 0: deep_inspect        (0 = off, 1 = on)
+///   Index 1: enable_ipv6         (0 = off, 1 = on)
+///   Index 2: kernel_aggregation  (0 = per-packet events, 1 = FLOWS map)
+///   Index 3: l3_interface        (0 = Ethernet frames, 1 = bare IP packets)
+///   Index 4: capture_non_ip      (0 = drop non-IP frames, 1 = emit them)
+///   Index 5: blocklist           (`BLOCKLIST_OFF`, `_FLAG` or `_DROP`)
+///   Index 6: port prefilter      (`PORT_FILTER_OFF`, or 1 + ranges in use)
+///   Index 7: address prefilter   (`IP_FILTER_OFF` or `IP_FILTER_ON`)
 #[map]
-static CONFIG: Array<u32> = Array::with_max_entries(2, 0);
+static CONFIG: Array<u32> = Array::with_max_entries(CONFIG_LEN, 0);
 
 /// TC classifier entry point.
 ///
@@ -50,47 +112,228 @@ pub fn ayaflow(ctx: *mut __sk_buff) -> i32 {
     // On TC ingress, ingress_ifindex is set to the interface index (non-zero).
     // On TC egress, ingress_ifindex is 0.
     let direction: u8 = if unsafe { (*ctx).ingress_ifindex } != 0 { 0 } else { 1 };
+    let ifindex = unsafe { (*ctx).ifindex };
     let ctx = unsafe { TcContext::new(ctx) };
-    try_classify(&ctx, direction)
+    if try_classify(ctx.data(), ctx.data_end(), Hook::new(direction, ifindex)) {
+        TC_ACT_SHOT
+    } else {
+        TC_ACT_PIPE
+    }
+}
+
+/// XDP entry point -- a cheaper, driver-level alternative to the TC hook.
+///
+/// XDP only runs on the receive path, so every event is tagged as ingress.
+/// Userspace pairs it with the TC egress hook when both directions are
+/// requested.  Same section naming caveat as the classifier above.
+#[no_mangle]
+#[link_section = "xdp/ayaflow_xdp"]
+pub fn ayaflow_xdp(ctx: *mut xdp_md) -> u32 {
+    let ifindex = unsafe { (*ctx).ingress_ifindex };
+    let ctx = XdpContext::new(ctx);
+    if try_classify(ctx.data(), ctx.data_end(), Hook::new(0, ifindex)) {
+        XDP_DROP
+    } else {
+        XDP_PASS
+    }
 }
 
+/// Where a packet was observed: direction tag (0 = ingress, 1 = egress) and
+/// interface index, copied into every event and flow key, plus the frame's
+/// Ethernet addresses once parsed (events only; flow keys have no MACs).
+#[derive(Clone, Copy)]
+struct Hook {
+    direction: u8,
+    ifindex: u32,
+    src_mac: [u8; 6],
+    dst_mac: [u8; 6],
+}
+
+impl Hook {
+    #[inline(always)]
+    fn new(direction: u8, ifindex: u32) -> Self {
+        Self {
+            direction,
+            ifindex,
+            src_mac: [0u8; 6],
+            dst_mac: [0u8; 6],
+        }
+    }
+}
+
+/// Hook-agnostic parsing shared by the TC and XDP entry points.  Both hooks
+/// only observe, so the caller lets the packet through unless this returns
+/// true: the blocklist is enforced and an address is on it.
 #[inline(always)]
-fn try_classify(ctx: &TcContext, direction: u8) -> i32 {
-    // -- Ethernet ----------------------------------------------------------
-    let data = ctx.data();
-    let data_end = ctx.data_end();
+fn try_classify(data: usize, data_end: usize, mut hook: Hook) -> bool {
+    // CONFIG[3] -- on L3 interfaces (tun, WireGuard) there is no Ethernet
+    // header; the IP version nibble tells the two families apart.
+    let l3_interface = match unsafe { CONFIG.get(CONFIG_L3_INTERFACE) } {
+        Some(flag) => *flag == 1,
+        None => false,
+    };
+    if l3_interface {
+        if data + 1 > data_end {
+            return false;
+        }
+        let version = unsafe { ptr::read_unaligned(data as *const u8) } >> 4;
+        return match version {
+            4 => classify_ipv4(hook, data, data_end),
+            6 => classify_ipv6_if_enabled(hook, data, data_end),
+            _ => false,
+        };
+    }
 
+    // -- Ethernet ----------------------------------------------------------
     let eth_end = data + EthHdr::LEN;
     if eth_end > data_end {
-        return TC_ACT_PIPE;
+        return false;
     }
+    // Read the raw field: non-IP frames carry values EtherType has no
+    // variant for.
     let eth_hdr = data as *const EthHdr;
-    let ether_type = unsafe { ptr::read_unaligned(ptr::addr_of!((*eth_hdr).ether_type)) };
+    hook.src_mac = unsafe { ptr::read_unaligned(ptr::addr_of!((*eth_hdr).src_addr)) };
+    hook.dst_mac = unsafe { ptr::read_unaligned(ptr::addr_of!((*eth_hdr).dst_addr)) };
+    let ether_type = u16::from_be(unsafe {
+        ptr::read_unaligned(ptr::addr_of!((*eth_hdr).ether_type) as *const u16)
+    });
 
     match ether_type {
-        EtherType::Ipv4 => classify_ipv4(ctx, direction, eth_end, data_end),
-        EtherType::Ipv6 => {
-            // Check CONFIG[1] -- if IPv6 capture is disabled, skip.
-            if let Some(flag) = unsafe { CONFIG.get(1) } {
-                if *flag == 1 {
-                    return classify_ipv6(ctx, direction, eth_end, data_end);
-                }
+        ETHERTYPE_IPV4 => classify_ipv4(hook, eth_end, data_end),
+        ETHERTYPE_IPV6 => classify_ipv6_if_enabled(hook, eth_end, data_end),
+        other => {
+            classify_non_ip_if_enabled(hook, other, (data_end - data) as u32);
+            false
+        }
+    }
+}
+
+/// Check CONFIG[4] -- emit a bare event for ARP and other non-IP frames.
+/// Skipped under kernel aggregation, whose FLOWS key has no ethertype.
+#[inline(always)]
+fn classify_non_ip_if_enabled(hook: Hook, ether_type: u16, frame_len: u32) {
+    let enabled = matches!(unsafe { CONFIG.get(CONFIG_CAPTURE_NON_IP) }, Some(flag) if *flag == 1);
+    let kernel_aggregation =
+        matches!(unsafe { CONFIG.get(CONFIG_KERNEL_AGGREGATION) }, Some(flag) if *flag == 1);
+    if !enabled || kernel_aggregation {
+        return;
+    }
+    // Same reservation size as IP events; the zeroed fields read as
+    // "no addresses, no transport".
+    if let Some(mut buf) = EVENTS.reserve::<PacketEvent>(0) {
+        let p = buf.as_mut_ptr() as *mut PacketEvent;
+        unsafe {
+            ptr::write_bytes(p, 0, 1);
+            ptr::write(ptr::addr_of_mut!((*p).version), EVENT_VERSION);
+            ptr::write(ptr::addr_of_mut!((*p).size), EVENT_SIZE);
+            ptr::write(ptr::addr_of_mut!((*p).direction), hook.direction);
+            ptr::write(ptr::addr_of_mut!((*p).pkt_len), frame_len);
+            ptr::write(ptr::addr_of_mut!((*p).ifindex), hook.ifindex);
+            ptr::write(ptr::addr_of_mut!((*p).ether_type), ether_type);
+            ptr::write(ptr::addr_of_mut!((*p).src_mac), hook.src_mac);
+            ptr::write(ptr::addr_of_mut!((*p).dst_mac), hook.dst_mac);
+        }
+        buf.submit(0);
+    } else {
+        count(COUNTER_RING_BUF_DROPS);
+    }
+}
+
+/// Check CONFIG[1] -- if IPv6 capture is disabled, skip.
+#[inline(always)]
+fn classify_ipv6_if_enabled(hook: Hook, ip_start: usize, data_end: usize) -> bool {
+    match unsafe { CONFIG.get(CONFIG_ENABLE_IPV6) } {
+        Some(flag) if *flag == 1 => classify_ipv6(hook, ip_start, data_end),
+        _ => false,
+    }
+}
+
+/// Check CONFIG[5] and the BLOCKLIST trie: 0 when neither address is
+/// listed, else the `PacketEvent::blocklist` value for the packet.
+#[inline(always)]
+fn blocklist_verdict(src_addr: [u8; 16], dst_addr: [u8; 16]) -> u8 {
+    let mode = match unsafe { CONFIG.get(CONFIG_BLOCKLIST) } {
+        Some(mode) => *mode,
+        None => BLOCKLIST_OFF,
+    };
+    if mode == BLOCKLIST_OFF {
+        return 0;
+    }
+    let listed = BLOCKLIST.get(&Key::new(128, src_addr)).is_some()
+        || BLOCKLIST.get(&Key::new(128, dst_addr)).is_some();
+    if !listed {
+        0
+    } else if mode == BLOCKLIST_DROP {
+        count(COUNTER_BLOCKLIST_DROPS);
+        BLOCKLIST_DROPPED
+    } else {
+        BLOCKLIST_MATCHED
+    }
+}
+
+/// Check CONFIG[6] and the prefilter maps: whether a TCP or UDP packet on
+/// these ports is accounted.  Userspace applies the whole capture filter
+/// again, so this only needs to turn away packets it would drop.
+#[inline(always)]
+fn ports_pass(src_port: u16, dst_port: u16) -> bool {
+    let mode = match unsafe { CONFIG.get(CONFIG_PORT_FILTER) } {
+        Some(mode) => *mode,
+        None => PORT_FILTER_OFF,
+    };
+    if mode == PORT_FILTER_OFF {
+        return true;
+    }
+    // SAFETY: the values are only tested for presence.
+    if unsafe { FILTER_PORTS.get(&src_port).is_some() || FILTER_PORTS.get(&dst_port).is_some() } {
+        return true;
+    }
+    // A constant bound keeps the loop within what the verifier accepts.
+    let mut i = 0;
+    while i < PORT_FILTER_MAX_RANGES && i + 1 < mode {
+        if let Some(range) = unsafe { FILTER_PORT_RANGES.get(i) } {
+            let (lo, hi) = ((*range >> 16) as u16, *range as u16);
+            if (lo <= src_port && src_port <= hi) || (lo <= dst_port && dst_port <= hi) {
+                return true;
             }
-            TC_ACT_PIPE
         }
-        _ => TC_ACT_PIPE,
+        i += 1;
     }
+    false
 }
 
-/// Parse and emit events for IPv4 packets.
+/// Check CONFIG[7] and the FILTER_IPS trie: whether a packet between these
+/// addresses is accounted.  Like `ports_pass`, only the includes are
+/// checked; userspace applies the excludes.
 #[inline(always)]
-fn classify_ipv4(ctx: &TcContext, direction: u8, ip_start: usize, data_end: usize) -> i32 {
-    let ip_end = ip_start + Ipv4Hdr::LEN;
-    if ip_end > data_end {
-        return TC_ACT_PIPE;
+fn ips_pass(src_addr: [u8; 16], dst_addr: [u8; 16]) -> bool {
+    let mode = match unsafe { CONFIG.get(CONFIG_IP_FILTER) } {
+        Some(mode) => *mode,
+        None => IP_FILTER_OFF,
+    };
+    mode == IP_FILTER_OFF
+        || FILTER_IPS.get(&Key::new(128, src_addr)).is_some()
+        || FILTER_IPS.get(&Key::new(128, dst_addr)).is_some()
+}
+
+/// Parse and emit events for IPv4 packets.  Returns whether to drop it.
+#[inline(always)]
+fn classify_ipv4(hook: Hook, ip_start: usize, data_end: usize) -> bool {
+    if ip_start + Ipv4Hdr::LEN > data_end {
+        return false;
     }
     let ip_hdr = ip_start as *const Ipv4Hdr;
+    // IHL is the low nibble of the first byte, in 32-bit words.  Masking keeps
+    // it within 0..=60 bytes for the verifier; below 20 is malformed.
+    let version_ihl: u8 = unsafe { ptr::read_unaligned(ip_start as *const u8) };
+    let ip_header_len = (version_ihl & 0x0f) as usize * 4;
+    if ip_header_len < Ipv4Hdr::LEN {
+        return false;
+    }
+    // Options push the transport header back; its reads check against data_end.
+    let ip_end = ip_start + ip_header_len;
     let proto = unsafe { ptr::read_unaligned(ptr::addr_of!((*ip_hdr).proto)) };
+    let ttl = unsafe { ptr::read_unaligned(ptr::addr_of!((*ip_hdr).ttl)) };
+    let tos = unsafe { ptr::read_unaligned(ptr::addr_of!((*ip_hdr).tos)) };
     let src_addr_raw = u32::from_be(unsafe { ptr::read_unaligned(ptr::addr_of!((*ip_hdr).src_addr)) });
     let dst_addr_raw = u32::from_be(unsafe { ptr::read_unaligned(ptr::addr_of!((*ip_hdr).dst_addr)) });
     let pkt_len =
@@ -98,19 +341,28 @@ fn classify_ipv4(ctx: &TcContext, direction: u8, ip_start: usize, data_end: usiz
 
     let src_addr = ipv4_mapped(src_addr_raw);
     let dst_addr = ipv4_mapped(dst_addr_raw);
+    let blocklist = blocklist_verdict(src_addr, dst_addr);
 
-    classify_transport(ctx, direction, proto, src_addr, dst_addr, 4, pkt_len, ip_end, data_end)
+    classify_transport(
+        hook, proto, src_addr, dst_addr, 4, ttl, tos, pkt_len, ip_header_len as u32, blocklist,
+        ip_end, data_end,
+    );
+    blocklist == BLOCKLIST_DROPPED
 }
 
-/// Parse and emit events for IPv6 packets.
+/// Parse and emit events for IPv6 packets.  Returns whether to drop it.
 #[inline(always)]
-fn classify_ipv6(ctx: &TcContext, direction: u8, ip_start: usize, data_end: usize) -> i32 {
+fn classify_ipv6(hook: Hook, ip_start: usize, data_end: usize) -> bool {
     let ip_end = ip_start + Ipv6Hdr::LEN;
     if ip_end > data_end {
-        return TC_ACT_PIPE;
+        return false;
     }
     let ip_hdr = ip_start as *const Ipv6Hdr;
     let proto = unsafe { ptr::read_unaligned(ptr::addr_of!((*ip_hdr).next_hdr)) };
+    let hop_limit = unsafe { ptr::read_unaligned(ptr::addr_of!((*ip_hdr).hop_limit)) };
+    // The traffic class straddles the first two bytes: version(4) tc(8) flow(20).
+    let vtc: [u8; 2] = unsafe { ptr::read_unaligned(ip_start as *const [u8; 2]) };
+    let traffic_class = (vtc[0] << 4) | (vtc[1] >> 4);
     let pkt_len =
         u16::from_be(unsafe { ptr::read_unaligned(ptr::addr_of!((*ip_hdr).payload_len)) }) as u32
             + Ipv6Hdr::LEN as u32; // payload_len excludes the 40-byte header itself
@@ -123,69 +375,149 @@ fn classify_ipv6(ctx: &TcContext, direction: u8, ip_start: usize, data_end: usiz
         ptr::read_unaligned(ptr::addr_of!((*ip_hdr).dst_addr) as *const [u8; 16])
     };
 
-    classify_transport(ctx, direction, proto, src_addr, dst_addr, 6, pkt_len, ip_end, data_end)
+    let blocklist = blocklist_verdict(src_addr, dst_addr);
+
+    classify_transport(
+        hook, proto, src_addr, dst_addr, 6, hop_limit, traffic_class, pkt_len,
+        Ipv6Hdr::LEN as u32, blocklist, ip_end, data_end,
+    );
+    blocklist == BLOCKLIST_DROPPED
 }
 
-/// Shared transport-layer (TCP/UDP) parsing and event emission for both IPv4
-/// and IPv6 flows.
+/// Shared transport-layer (TCP/UDP/ICMP) parsing and event emission for both
+/// IPv4 and IPv6 flows.  ICMP and ICMPv6 report source port 0 and
+/// `type << 8 | code` as the destination port, as NetFlow does.  Other
+/// protocols are not reported, though the blocklist verdict the caller
+/// computed still applies to them.  TCP and UDP packets the port prefilter
+/// turns away are not accounted either, but still feed L7 inspection.
 #[inline(always)]
 fn classify_transport(
-    ctx: &TcContext,
-    direction: u8,
+    hook: Hook,
     proto: IpProto,
     src_addr: [u8; 16],
     dst_addr: [u8; 16],
     addr_type: u8,
+    ttl: u8,
+    tos: u8,
     pkt_len: u32,
+    ip_header_len: u32,
+    blocklist: u8,
     transport_start: usize,
     data_end: usize,
-) -> i32 {
-    let (src_port, dst_port, payload_offset) = match proto {
-        IpProto::Tcp => {
-            let tcp_end = transport_start + TcpHdr::LEN;
-            if tcp_end > data_end {
-                return TC_ACT_PIPE;
+) {
+    let (src_port, dst_port, payload_offset, tcp_seq, tcp_flags, l4_len, l4_header_len) =
+        match proto {
+            IpProto::Tcp => {
+                let tcp_end = transport_start + TcpHdr::LEN;
+                if tcp_end > data_end {
+                    return;
+                }
+                let tcp_hdr = transport_start as *const TcpHdr;
+                let sport =
+                    u16::from_be(unsafe { ptr::read_unaligned(ptr::addr_of!((*tcp_hdr).source)) });
+                let dport =
+                    u16::from_be(unsafe { ptr::read_unaligned(ptr::addr_of!((*tcp_hdr).dest)) });
+                let seq =
+                    u32::from_be(unsafe { ptr::read_unaligned(ptr::addr_of!((*tcp_hdr).seq)) });
+                // The flags byte follows the data offset; within TcpHdr::LEN.
+                let flags = unsafe { ptr::read_unaligned((transport_start + 13) as *const u8) };
+                // TCP data offset is stored in doff(), measured in 32-bit words.
+                // The 4-bit field bounds the header at 60 bytes.
+                let doff = unsafe { (*tcp_hdr).doff() } & 0x0f;
+                let tcp_header_len = doff as usize * 4;
+                let l4_len = pkt_len.saturating_sub(ip_header_len) as u16;
+                let payload_offset = transport_start + tcp_header_len;
+                (sport, dport, payload_offset, seq, flags, l4_len, tcp_header_len as u8)
             }
-            let tcp_hdr = transport_start as *const TcpHdr;
-            let sport =
-                u16::from_be(unsafe { ptr::read_unaligned(ptr::addr_of!((*tcp_hdr).source)) });
-            let dport =
-                u16::from_be(unsafe { ptr::read_unaligned(ptr::addr_of!((*tcp_hdr).dest)) });
-            // TCP data offset is stored in doff(), measured in 32-bit words.
-            let doff = unsafe { (*tcp_hdr).doff() };
-            let tcp_header_len = doff as usize * 4;
-            (sport, dport, transport_start + tcp_header_len)
-        }
-        IpProto::Udp => {
-            let udp_end = transport_start + UdpHdr::LEN;
-            if udp_end > data_end {
-                return TC_ACT_PIPE;
+            IpProto::Udp => {
+                let udp_end = transport_start + UdpHdr::LEN;
+                if udp_end > data_end {
+                    return;
+                }
+                let udp_hdr = transport_start as *const UdpHdr;
+                let sport =
+                    u16::from_be(unsafe { ptr::read_unaligned(ptr::addr_of!((*udp_hdr).source)) });
+                let dport =
+                    u16::from_be(unsafe { ptr::read_unaligned(ptr::addr_of!((*udp_hdr).dest)) });
+                let udp_len =
+                    u16::from_be(unsafe { ptr::read_unaligned(ptr::addr_of!((*udp_hdr).len)) });
+                (sport, dport, udp_end, 0, 0, udp_len, UdpHdr::LEN as u8)
             }
-            let udp_hdr = transport_start as *const UdpHdr;
-            let sport =
-                u16::from_be(unsafe { ptr::read_unaligned(ptr::addr_of!((*udp_hdr).source)) });
-            let dport =
-                u16::from_be(unsafe { ptr::read_unaligned(ptr::addr_of!((*udp_hdr).dest)) });
-            (sport, dport, udp_end)
-        }
-        _ => return TC_ACT_PIPE,
-    };
+            IpProto::Icmp | IpProto::Ipv6Icmp => {
+                let icmp_end = transport_start + ICMP_HEADER_LEN;
+                if icmp_end > data_end {
+                    return;
+                }
+                let kind = unsafe { ptr::read_unaligned(transport_start as *const u8) };
+                let code = unsafe { ptr::read_unaligned((transport_start + 1) as *const u8) };
+                let l4_len = pkt_len.saturating_sub(ip_header_len) as u16;
+                let dport = (kind as u16) << 8 | code as u16;
+                (0, dport, icmp_end, 0, 0, l4_len, ICMP_HEADER_LEN as u8)
+            }
+            _ => return,
+        };
 
-    // -- Emit L3/L4 event (always) -----------------------------------------
-    if let Some(mut buf) = EVENTS.reserve::<PacketEvent>(0) {
-        let p = buf.as_mut_ptr() as *mut PacketEvent;
-        unsafe {
-            ptr::write(ptr::addr_of_mut!((*p).src_addr), src_addr);
-            ptr::write(ptr::addr_of_mut!((*p).dst_addr), dst_addr);
-            ptr::write(ptr::addr_of_mut!((*p).src_port), src_port);
-            ptr::write(ptr::addr_of_mut!((*p).dst_port), dst_port);
-            ptr::write(ptr::addr_of_mut!((*p).protocol), proto as u8);
-            ptr::write(ptr::addr_of_mut!((*p).direction), direction);
-            ptr::write(ptr::addr_of_mut!((*p).addr_type), addr_type);
-            ptr::write(ptr::addr_of_mut!((*p)._pad), [0u8; 1]);
-            ptr::write(ptr::addr_of_mut!((*p).pkt_len), pkt_len);
+    let Hook { direction, ifindex, .. } = hook;
+    let ether_type = if addr_type == 4 { ETHERTYPE_IPV4 } else { ETHERTYPE_IPV6 };
+
+    // -- Account the packet: per-CPU flow map or per-packet event ------------
+    let kernel_aggregation = match unsafe { CONFIG.get(CONFIG_KERNEL_AGGREGATION) } {
+        Some(flag) => *flag == 1,
+        None => false,
+    };
+    let accounted = ips_pass(src_addr, dst_addr)
+        && match proto {
+            IpProto::Tcp | IpProto::Udp => ports_pass(src_port, dst_port),
+            _ => true,
+        };
+    // A packet the prefilters turned away is not accounted; L7
+    // inspection below still runs.
+    if accounted {
+        if kernel_aggregation {
+            let key = FlowKey {
+                src_addr,
+                dst_addr,
+                src_port,
+                dst_port,
+                protocol: proto as u8,
+                direction,
+                addr_type,
+                _pad: [0u8; 1],
+                ifindex,
+            };
+            let payload = l4_len.saturating_sub(l4_header_len as u16);
+            aggregate_flow(&key, pkt_len, payload as u32);
+        } else if let Some(mut buf) = EVENTS.reserve::<PacketEvent>(0) {
+            let p = buf.as_mut_ptr() as *mut PacketEvent;
+            unsafe {
+                ptr::write(ptr::addr_of_mut!((*p).version), EVENT_VERSION);
+                ptr::write(ptr::addr_of_mut!((*p).size), EVENT_SIZE);
+                ptr::write(ptr::addr_of_mut!((*p).src_addr), src_addr);
+                ptr::write(ptr::addr_of_mut!((*p).dst_addr), dst_addr);
+                ptr::write(ptr::addr_of_mut!((*p).src_port), src_port);
+                ptr::write(ptr::addr_of_mut!((*p).dst_port), dst_port);
+                ptr::write(ptr::addr_of_mut!((*p).protocol), proto as u8);
+                ptr::write(ptr::addr_of_mut!((*p).direction), direction);
+                ptr::write(ptr::addr_of_mut!((*p).addr_type), addr_type);
+                ptr::write(ptr::addr_of_mut!((*p).ttl), ttl);
+                ptr::write(ptr::addr_of_mut!((*p).pkt_len), pkt_len);
+                ptr::write(ptr::addr_of_mut!((*p).tcp_seq), tcp_seq);
+                ptr::write(ptr::addr_of_mut!((*p).l4_len), l4_len);
+                ptr::write(ptr::addr_of_mut!((*p).tos), tos);
+                ptr::write(ptr::addr_of_mut!((*p).l4_header_len), l4_header_len);
+                ptr::write(ptr::addr_of_mut!((*p).ifindex), ifindex);
+                ptr::write(ptr::addr_of_mut!((*p).ether_type), ether_type);
+                ptr::write(ptr::addr_of_mut!((*p).tcp_flags), tcp_flags);
+                ptr::write(ptr::addr_of_mut!((*p).blocklist), blocklist);
+                ptr::write(ptr::addr_of_mut!((*p).src_mac), hook.src_mac);
+                ptr::write(ptr::addr_of_mut!((*p).dst_mac), hook.dst_mac);
+                ptr::write(ptr::addr_of_mut!((*p)._pad), [0u8; 4]);
+                ptr::write(ptr::addr_of_mut!((*p).ktime_ns), bpf_ktime_get_ns());
+            }
+            buf.submit(0);
+        } else {
+            count(COUNTER_RING_BUF_DROPS);
         }
-        buf.submit(0);
     }
 
     // -- Conditionally emit L7 payload event -------------------------------
@@ -194,14 +526,43 @@ fn classify_transport(
         || (proto == IpProto::Udp && (dst_port == 53 || src_port == 53));
 
     if wants_payload {
-        if let Some(flag) = unsafe { CONFIG.get(0) } {
+        if let Some(flag) = unsafe { CONFIG.get(CONFIG_DEEP_INSPECT) } {
             if *flag == 1 {
-                emit_payload(ctx, src_addr, dst_addr, addr_type, src_port, dst_port, proto as u8, direction, pkt_len, payload_offset, data_end);
+                emit_payload(src_addr, dst_addr, addr_type, src_port, dst_port, proto as u8, direction, pkt_len, payload_offset, data_end);
             }
         }
     }
+}
 
-    TC_ACT_PIPE
+/// Add one packet to this CPU's counters for `key`, inserting on first sight.
+/// A full map bumps the overflow counter instead.
+#[inline(always)]
+fn aggregate_flow(key: &FlowKey, pkt_len: u32, payload_len: u32) {
+    if let Some(counters) = FLOWS.get_ptr_mut(key) {
+        // Per-CPU values: no other CPU touches this slot, plain adds suffice.
+        unsafe {
+            (*counters).packets += 1;
+            (*counters).bytes += pkt_len as u64;
+            (*counters).payload_bytes += payload_len as u64;
+        }
+        return;
+    }
+    let initial = FlowCounters {
+        packets: 1,
+        bytes: pkt_len as u64,
+        payload_bytes: payload_len as u64,
+    };
+    if FLOWS.insert(key, &initial, 0).is_err() {
+        count(COUNTER_FLOW_OVERFLOW);
+    }
+}
+
+/// Bump this CPU's slot of diagnostic counter `index`.
+#[inline(always)]
+fn count(index: u32) {
+    if let Some(counter) = COUNTERS.get_ptr_mut(index) {
+        unsafe { *counter += 1 };
+    }
 }
 
 /// Copy up to MAX_PAYLOAD_LEN bytes of L7 payload into the PAYLOAD_EVENTS
@@ -209,7 +570,6 @@ fn classify_transport(
 /// eBPF verifier.
 #[inline(always)]
 fn emit_payload(
-    _ctx: &TcContext,
     src_addr: [u8; 16],
     dst_addr: [u8; 16],
     addr_type: u8,
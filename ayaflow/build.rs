@@ -0,0 +1,38 @@
+//! Embeds the git commit and the resolved aya version, reported by
+//! `/api/version`.  Either is left unset when it cannot be found, e.g. when
+//! building from a tarball without `.git` or `Cargo.lock`.
+
+use std::process::Command;
+
+fn main() {
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/refs");
+    println!("cargo:rerun-if-changed=../Cargo.lock");
+    // Packagers without a checkout can pass the commit in.
+    println!("cargo:rerun-if-env-changed=AYAFLOW_GIT_COMMIT");
+
+    let commit = std::env::var("AYAFLOW_GIT_COMMIT").ok().or_else(|| {
+        let output = Command::new("git").args(["rev-parse", "--short=12", "HEAD"]).output();
+        output
+            .ok()
+            .filter(|output| output.status.success())
+            .and_then(|output| String::from_utf8(output.stdout).ok())
+    });
+    if let Some(commit) = commit.map(|c| c.trim().to_string()).filter(|c| !c.is_empty()) {
+        println!("cargo:rustc-env=AYAFLOW_GIT_COMMIT={}", commit);
+    }
+
+    let lock = std::fs::read_to_string("../Cargo.lock").unwrap_or_default();
+    if let Some(version) = locked_version(&lock, "aya") {
+        println!("cargo:rustc-env=AYAFLOW_AYA_VERSION={}", version);
+    }
+}
+
+/// The version `Cargo.lock` pins `package` to.
+fn locked_version<'a>(lock: &'a str, package: &str) -> Option<&'a str> {
+    let name = format!("name = \"{}\"", package);
+    let mut lines = lock.lines();
+    lines.find(|line| line.trim() == name)?;
+    let version = lines.next()?.trim().strip_prefix("version = \"")?;
+    version.strip_suffix('"')
+}
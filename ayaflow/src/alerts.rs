@@ -0,0 +1,404 @@
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::Ordering;
+use tokio::time::{Duration, Instant};
+
+use crate::icmp::IcmpMessage;
+use crate::openapi::api_schema;
+use crate::rates::RateSampler;
+use crate::state::{TrafficCounters, PacketMetadata};
+
+pub use ayaflow_common::api::StoredAlert;
+
+/// DSCP code point for Expedited Forwarding (voice).
+pub const DSCP_EF: u8 = 46;
+
+/// Window over which the EF rate rule averages.
+const EF_RATE_WINDOW: Duration = Duration::from_secs(10);
+
+/// Window over which the connection churn rule averages.
+const CHURN_WINDOW: Duration = Duration::from_secs(10);
+
+/// Window over which the ICMP unreachable rule counts per source.
+const UNREACHABLE_WINDOW: Duration = Duration::from_secs(10);
+
+/// Alert rule configuration (the `alerts:` section of the YAML config).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AlertsConfig {
+    /// Raise an alert when a packet arrives with a TTL / hop limit below
+    /// this value.  Low TTLs point at routing loops or very long paths.
+    #[serde(default)]
+    pub ttl_below: Option<u8>,
+
+    /// Raise an alert when EF-marked traffic averages more than this many
+    /// bytes per second over 10s.  Flags hosts abusing the voice class.
+    #[serde(default)]
+    pub ef_rate_above_bps: Option<u64>,
+
+    /// Raise an alert when more than this many connections per second are
+    /// created, averaged over 10s.  Flags scans and connection floods.
+    #[serde(default)]
+    pub new_connections_above: Option<u64>,
+
+    /// Raise an alert when one source sends more than this many ICMP or
+    /// ICMPv6 destination-unreachable messages within 10s.  Flags a router
+    /// or host rejecting a burst of traffic.
+    #[serde(default)]
+    pub icmp_unreachable_above: Option<u64>,
+
+    /// Minimum seconds between two alerts for the same rule and subject.
+    #[serde(default = "default_cooldown_seconds")]
+    pub cooldown_seconds: u64,
+
+    /// Delete stored alerts last seen more than this many seconds ago;
+    /// 0 keeps them regardless of age.
+    #[serde(default = "default_retention_seconds")]
+    pub retention_seconds: u64,
+
+    /// Keep at most this many stored alerts, deleting the least recently
+    /// seen first; 0 means no cap.
+    #[serde(default = "default_max_stored")]
+    pub max_stored: usize,
+}
+
+fn default_cooldown_seconds() -> u64 {
+    60
+}
+
+fn default_retention_seconds() -> u64 {
+    30 * 24 * 3600
+}
+
+fn default_max_stored() -> usize {
+    10_000
+}
+
+impl Default for AlertsConfig {
+    fn default() -> Self {
+        Self {
+            ttl_below: None,
+            ef_rate_above_bps: None,
+            new_connections_above: None,
+            icmp_unreachable_above: None,
+            cooldown_seconds: default_cooldown_seconds(),
+            retention_seconds: default_retention_seconds(),
+            max_stored: default_max_stored(),
+        }
+    }
+}
+
+impl AlertsConfig {
+    /// True when at least one rule is configured.
+    pub fn any_enabled(&self) -> bool {
+        self.ttl_below.is_some()
+            || self.ef_rate_above_bps.is_some()
+            || self.new_connections_above.is_some()
+            || self.icmp_unreachable_above.is_some()
+    }
+}
+
+api_schema! {
+    /// A raised alert, as stored in the `alerts` table and returned by the API.
+    #[derive(Debug, Clone, Serialize)]
+    pub struct Alert {
+        /// Milliseconds since the Unix epoch.
+        pub timestamp: i64,
+        /// Rule name, e.g. "ttl_below".
+        pub rule: String,
+        /// "info", "warning", or "critical".
+        pub severity: String,
+        /// What the alert is about (an IP or connection key).
+        pub subject: String,
+        /// Human-readable description.
+        pub message: String,
+    }
+}
+
+/// Alert severities, least severe first.
+pub const SEVERITIES: [&str; 3] = ["info", "warning", "critical"];
+
+/// Evaluates alert rules against observed traffic.
+///
+/// Repeats for the same (rule, subject) pair are suppressed for
+/// `cooldown_seconds` so a single misbehaving host cannot flood the table.
+pub struct AlertEngine {
+    config: AlertsConfig,
+    last_fired: DashMap<(String, String), Instant>,
+    ef_rates: RateSampler,
+    churn_rates: RateSampler,
+    /// Unreachable messages per source since the start of its window.
+    unreachable: DashMap<String, (Instant, u64)>,
+}
+
+impl AlertEngine {
+    pub fn new(config: AlertsConfig) -> Self {
+        Self {
+            config,
+            last_fired: DashMap::new(),
+            ef_rates: RateSampler::new(),
+            churn_rates: RateSampler::new(),
+            unreachable: DashMap::new(),
+        }
+    }
+
+    /// Check per-packet rules.  Returns the alerts that fire.  Every rule
+    /// sees every packet, so one rule firing does not hide the packet from
+    /// another's count.
+    pub fn check_packet(&self, packet: &PacketMetadata) -> Vec<Alert> {
+        [self.check_ttl(packet), self.check_unreachable(packet)]
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    fn check_ttl(&self, packet: &PacketMetadata) -> Option<Alert> {
+        let threshold = self.config.ttl_below?;
+        let ttl = packet.ttl?;
+        if ttl >= threshold {
+            return None;
+        }
+        self.fire(
+            "ttl_below",
+            "warning",
+            packet.src_ip.clone(),
+            format!(
+                "TTL {} from {} to {}:{} is below {}",
+                ttl, packet.src_ip, packet.dst_ip, packet.dst_port, threshold
+            ),
+        )
+    }
+
+    fn check_unreachable(&self, packet: &PacketMetadata) -> Option<Alert> {
+        let threshold = self.config.icmp_unreachable_above?;
+        let message = IcmpMessage::from_flow(&packet.protocol, packet.dst_port)?;
+        if !message.is_unreachable() {
+            return None;
+        }
+        let now = Instant::now();
+        let count = {
+            let mut window = self.unreachable.entry(packet.src_ip.clone()).or_insert((now, 0));
+            if now.duration_since(window.0) >= UNREACHABLE_WINDOW {
+                *window = (now, 0);
+            }
+            window.1 += 1;
+            window.1
+        };
+        if count <= threshold {
+            return None;
+        }
+        self.fire(
+            "icmp_unreachable_above",
+            "warning",
+            packet.src_ip.clone(),
+            format!(
+                "{} sent {} unreachable messages within 10s, latest {} to {}",
+                packet.src_ip,
+                count,
+                message.name(),
+                packet.dst_ip
+            ),
+        )
+    }
+
+    /// Sample the EF counters taken at `at` and check the EF rate rule.
+    /// Meant to be called about once a second.
+    pub fn check_ef_traffic(&self, at: std::time::Instant, ef: &TrafficCounters) -> Option<Alert> {
+        let threshold = self.config.ef_rate_above_bps?;
+        self.ef_rates.record(
+            at,
+            ef.packets.load(Ordering::Relaxed),
+            ef.bytes.load(Ordering::Relaxed),
+        );
+        let bps = self.ef_rates.rate(EF_RATE_WINDOW).bps;
+        if bps <= threshold as f64 {
+            return None;
+        }
+        self.fire(
+            "ef_rate_above",
+            "warning",
+            "EF".to_string(),
+            format!("EF traffic at {:.0} B/s exceeds {} B/s", bps, threshold),
+        )
+    }
+
+    /// Sample the lifetime count of created connections taken at `at` and
+    /// check the churn rule.  Meant to be called about once a second.
+    pub fn check_connection_churn(&self, at: std::time::Instant, created: u64) -> Option<Alert> {
+        let threshold = self.config.new_connections_above?;
+        self.churn_rates.record(at, created, 0);
+        let per_second = self.churn_rates.rate(CHURN_WINDOW).pps;
+        if per_second <= threshold as f64 {
+            return None;
+        }
+        self.fire(
+            "new_connections_above",
+            "warning",
+            "connections".to_string(),
+            format!("{:.0} new connections/s exceeds {}/s", per_second, threshold),
+        )
+    }
+
+    /// Build an alert unless the (rule, subject) pair is still cooling down.
+    fn fire(&self, rule: &str, severity: &str, subject: String, message: String) -> Option<Alert> {
+        let now = Instant::now();
+        let cooldown = Duration::from_secs(self.config.cooldown_seconds);
+        let key = (rule.to_string(), subject.clone());
+        if let Some(last) = self.last_fired.get(&key) {
+            if now.duration_since(*last) < cooldown {
+                return None;
+            }
+        }
+        self.last_fired.insert(key, now);
+        Some(Alert {
+            timestamp: chrono::Utc::now().timestamp_millis(),
+            rule: rule.to_string(),
+            severity: severity.to_string(),
+            subject,
+            message,
+        })
+    }
+
+    /// Forget cooldown entries older than the cooldown period and closed
+    /// unreachable windows.
+    pub fn cleanup(&self) {
+        let now = Instant::now();
+        let cooldown = Duration::from_secs(self.config.cooldown_seconds);
+        self.last_fired
+            .retain(|_, last| now.duration_since(*last) < cooldown);
+        self.unreachable
+            .retain(|_, (start, _)| now.duration_since(*start) < UNREACHABLE_WINDOW);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    fn packet(ttl: Option<u8>) -> PacketMetadata {
+        PacketMetadata {
+            src_ip: "10.0.0.7".into(),
+            ttl,
+            ..test_support::packet()
+        }
+    }
+
+    #[test]
+    fn test_ttl_below_fires_once_per_cooldown() {
+        let engine = AlertEngine::new(AlertsConfig {
+            ttl_below: Some(5),
+            ..Default::default()
+        });
+
+        assert!(engine.check_packet(&packet(Some(64))).is_empty());
+        assert!(engine.check_packet(&packet(None)).is_empty());
+
+        let alert = engine.check_packet(&packet(Some(2))).pop().expect("rule should fire");
+        assert_eq!(alert.rule, "ttl_below");
+        assert_eq!(alert.subject, "10.0.0.7");
+
+        // Same subject within the cooldown is suppressed.
+        assert!(engine.check_packet(&packet(Some(1))).is_empty());
+    }
+
+    #[test]
+    fn test_ef_rate_above_fires_on_sustained_rate() {
+        let engine = AlertEngine::new(AlertsConfig {
+            ef_rate_above_bps: Some(1000),
+            ..Default::default()
+        });
+        let ef = TrafficCounters::default();
+        let start = std::time::Instant::now();
+        let at = |secs| start + std::time::Duration::from_secs(secs);
+
+        assert!(engine.check_ef_traffic(at(0), &ef).is_none());
+        ef.bytes.store(5000, Ordering::Relaxed);
+        assert!(engine.check_ef_traffic(at(5), &ef).is_none()); // exactly 1000 B/s
+
+        ef.bytes.store(20_000, Ordering::Relaxed);
+        let alert = engine.check_ef_traffic(at(10), &ef).expect("rule should fire");
+        assert_eq!(alert.rule, "ef_rate_above");
+        assert_eq!(alert.subject, "EF");
+    }
+
+    #[test]
+    fn test_new_connections_above_fires_on_sustained_churn() {
+        let engine = AlertEngine::new(AlertsConfig {
+            new_connections_above: Some(100),
+            ..Default::default()
+        });
+        let start = std::time::Instant::now();
+        let at = |secs| start + std::time::Duration::from_secs(secs);
+
+        assert!(engine.check_connection_churn(at(0), 0).is_none());
+        assert!(engine.check_connection_churn(at(5), 500).is_none()); // exactly 100/s
+        let alert = engine.check_connection_churn(at(10), 2000).expect("rule should fire");
+        assert_eq!(alert.rule, "new_connections_above");
+        assert_eq!(alert.subject, "connections");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_icmp_unreachable_burst_per_source() {
+        let engine = AlertEngine::new(AlertsConfig {
+            icmp_unreachable_above: Some(3),
+            ..Default::default()
+        });
+        let icmp = |src: &str, kind: u8| PacketMetadata {
+            src_ip: src.into(),
+            src_port: 0,
+            dst_port: u16::from_be_bytes([kind, 1]),
+            protocol: "ICMP".into(),
+            ..packet(Some(64))
+        };
+
+        // Echo requests and unreachables from another source do not count.
+        for _ in 0..10 {
+            assert!(engine.check_packet(&icmp("192.0.2.1", 8)).is_empty());
+        }
+        for _ in 0..3 {
+            assert!(engine.check_packet(&icmp("192.0.2.1", 3)).is_empty());
+            assert!(engine.check_packet(&icmp("192.0.2.2", 3)).is_empty());
+        }
+        let alert = engine.check_packet(&icmp("192.0.2.1", 3)).pop().expect("rule should fire");
+        assert_eq!(alert.rule, "icmp_unreachable_above");
+        assert_eq!(alert.subject, "192.0.2.1");
+        assert!(alert.message.contains("dest-unreachable/host-unreachable"), "{}", alert.message);
+
+        // A new window starts the count over.
+        tokio::time::advance(UNREACHABLE_WINDOW).await;
+        engine.cleanup();
+        assert!(engine.unreachable.is_empty());
+        assert!(engine.check_packet(&icmp("192.0.2.2", 3)).is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_low_ttl_unreachables_still_count() {
+        let engine = AlertEngine::new(AlertsConfig {
+            ttl_below: Some(5),
+            icmp_unreachable_above: Some(2),
+            ..Default::default()
+        });
+        let unreachable = PacketMetadata {
+            src_port: 0,
+            dst_port: u16::from_be_bytes([3, 1]),
+            protocol: "ICMP".into(),
+            ..packet(Some(1))
+        };
+
+        let rules = |alerts: Vec<Alert>| alerts.into_iter().map(|a| a.rule).collect::<Vec<_>>();
+        assert_eq!(rules(engine.check_packet(&unreachable)), ["ttl_below"]);
+        assert!(engine.check_packet(&unreachable).is_empty());
+        assert_eq!(rules(engine.check_packet(&unreachable)), ["icmp_unreachable_above"]);
+    }
+
+    #[test]
+    fn test_no_rules_never_fire() {
+        let engine = AlertEngine::new(AlertsConfig::default());
+        assert!(engine.check_packet(&packet(Some(0))).is_empty());
+        let ef = TrafficCounters::default();
+        ef.bytes.store(u64::MAX / 2, Ordering::Relaxed);
+        assert!(engine.check_ef_traffic(std::time::Instant::now(), &ef).is_none());
+        assert!(engine.check_connection_churn(std::time::Instant::now(), u64::MAX).is_none());
+    }
+}
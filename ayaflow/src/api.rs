@@ -1,59 +1,319 @@
-use crate::state::TrafficState;
-use crate::storage::Storage;
+use crate::state::{
+    ConnectionEntry, ConnectionFilter, ConnectionId, ConnectionKey, ConnectionPage, ConnectionSort,
+    is_protocol_name, CleanupMetrics, PeerTotals, QosClass, ResetCounts, SortOrder, SubnetPrefixes,
+    TopBy, TopTalker, TrafficState,
+};
+use crate::alerts::{StoredAlert, SEVERITIES};
+use crate::asymmetry::AsymmetryReport;
+use crate::backfill::{BackfillJob, BackfillOptions, BackfillProgress};
+use crate::blocklist::{Blocklist, BlocklistStatus};
+use crate::cardinality::CardinalityReport;
+use crate::categories::CategoryTotals;
+use crate::config::{ApiConfig, Config, ConfigSource};
+use crate::connection_export::{ConnectionDump, ExportFormat, SnapshotLog};
+use crate::devices::{DeviceNames, MacAddr};
+use crate::diagnostics::{DiagnosticDump, Diagnostics};
+use crate::dns::{DnsCache, DnsCacheStats};
+use crate::fleet::{FleetMember, FleetMembers, IngestBatch, IngestResponse};
+use crate::geoip::CountryMatch;
+use crate::health::{ComponentStatus, HealthRegistry};
+use crate::icmp::IcmpReport;
+use crate::locality::{CastSelection, FlowDirection};
+use crate::migrate::Secondary;
+use crate::openapi::{api_schema, query_parameters, ApiSchema};
+use crate::reports::{Report, ReportFormat};
+use crate::services::ServiceNames;
+use crate::storage::{
+    AlertFilter, HistoryRow, HostUsageRow, PacketFilter, StorageBackend, StorageError,
+    StorageMetrics, StorageResult, StoredConnectionTotals, StoredTalker, TopColumn,
+    UsageGranularity,
+};
+use crate::version::VersionInfo;
+use ayaflow_common::api::StreamEvent;
 use axum::{
-    extract::{ConnectInfo, Query, State, WebSocketUpgrade, ws::{Message, WebSocket}},
-    http::StatusCode,
+    extract::{
+        rejection::{JsonRejection, PathRejection},
+        ws::{Message, WebSocket},
+        ConnectInfo, DefaultBodyLimit, FromRequestParts, Path, State, WebSocketUpgrade,
+    },
+    http::{header, request::Parts, Extensions, HeaderMap, HeaderValue, StatusCode, Version},
     middleware,
-    response::IntoResponse,
-    routing::get,
+    response::{Html, IntoResponse, Redirect, Response},
+    routing::{get, on, MethodFilter, MethodRouter},
     Json, Router,
 };
+use dashmap::DashMap;
 use ipnet::IpNet;
 use prometheus_client::encoding::text::encode;
+use prometheus_client::encoding::EncodeLabelSet;
 use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
 use prometheus_client::metrics::gauge::Gauge;
 use prometheus_client::registry::Registry;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use std::net::SocketAddr;
-use std::sync::atomic::Ordering;
-use std::sync::Arc;
-use std::time::Instant;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::Semaphore;
+use tower_http::compression::predicate::{Predicate, SizeAbove};
+use tower_http::compression::CompressionLayer;
+
+pub use ayaflow_common::api::{
+    AttachStatus, CaptureState, DnsFlushResponse, HealthResponse, ResetResponse, StatsResponse,
+};
 
 pub struct AppState {
     pub traffic: Arc<TrafficState>,
-    pub storage: Arc<Storage>,
+    pub storage: Arc<dyn StorageBackend>,
+    pub health: Arc<HealthRegistry>,
     pub start_time: Instant,
+    /// Served by `GET /api/config`; fixed at startup.
+    pub config: Arc<ConfigResponse>,
+    /// Port names attached to served connections and history rows.
+    pub services: Arc<ServiceNames>,
+    /// Device names and vendors attached to served connections.
+    pub devices: Arc<DeviceNames>,
+    /// Disabled in API-only mode, where nothing feeds the live state.
+    pub capture: CaptureState,
+    /// Queues and caches included in `/api/debug/dump`.
+    pub diagnostics: Arc<Diagnostics>,
+    /// Served by `/api/blocklist` and replaced through it.
+    pub blocklist: Arc<Blocklist>,
+    /// The hostname backfill started by `POST /api/admin/backfill-dns`.
+    pub backfill: Arc<BackfillJob>,
+    /// Served by `/api/version`.
+    pub version: Arc<VersionInfo>,
+    /// Sensors that pushed to `/api/ingest`, served by `/api/fleet`.
+    pub fleet: Arc<FleetMembers>,
+    /// The `secondary_db_url` backend written alongside `storage`.
+    pub secondary: Option<Arc<Secondary>>,
+    /// The newest file written to `connection_snapshots.dir`.
+    pub connection_snapshots: Arc<SnapshotLog>,
+}
+
+impl AppState {
+    /// Attach service and device names to connections about to be served.
+    pub fn label_connections(&self, entries: &mut [ConnectionEntry]) {
+        self.services.label_connections(entries);
+        self.devices.label_connections(entries);
+    }
+
+    /// Attach service names and destination classes to stored rows.
+    fn label_rows(&self, rows: &mut [HistoryRow]) {
+        self.services.label_packets(rows.iter_mut().map(|row| &mut row.packet));
+        for row in rows {
+            let packet = &mut row.packet;
+            packet.cast = Some(self.traffic.cast(&packet.dst_ip));
+            row.service_port =
+                self.services.service_port(packet.src_port, packet.dst_port, &packet.protocol);
+        }
+    }
 }
 
 // ── Prometheus Metrics ────────────────────────────────────────────────────────
 
+/// Label set for per-interface counters.  Traffic whose interface is unknown
+/// (e.g. restored from a snapshot) is exported with an empty name.
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct InterfaceLabels {
+    interface: String,
+}
+
+/// Label set for the distinct-address gauges: "1m", "5m" or "60m".
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct WindowLabels {
+    window: String,
+}
+
+/// Label set for per-category counters.
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct CategoryLabels {
+    category: String,
+}
+
+/// Label set for the eBPF runtime counters: the program's name in the
+/// object.
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ProgramLabels {
+    program: String,
+}
+
+/// Label set for the headroom utilization gauge: "ring_buffer", "queue",
+/// "flush" or "cpu".
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ResourceLabels {
+    resource: String,
+}
+
+/// Label set for the TCP state gauge: "new", "established", "closing" or
+/// "closed".
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct TcpStateLabels {
+    state: String,
+}
+
+/// Exported counter fed from a source total that goes back to zero when the
+/// totals are reset.  Deltas are taken against the last total seen rather
+/// than the exported value, so the counter keeps counting after a reset.
+#[derive(Default)]
+struct SyncedCounter {
+    counter: Counter,
+    last_seen: AtomicU64,
+}
+
+impl SyncedCounter {
+    fn sync(&self, total: u64) {
+        let last = self.last_seen.swap(total, Ordering::Relaxed);
+        self.counter.inc_by(delta_since(last, total));
+    }
+
+    /// The source was reset: count everything it reports from now on.
+    fn rebase(&self) {
+        self.last_seen.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Labelled variant of `SyncedCounter`, per interface unless stated.
+struct SyncedFamily<L = InterfaceLabels> {
+    family: Family<L, Counter>,
+    last_seen: DashMap<L, u64>,
+}
+
+impl<L> Default for SyncedFamily<L>
+where
+    L: Clone + std::hash::Hash + Eq,
+{
+    fn default() -> Self {
+        Self {
+            family: Family::default(),
+            last_seen: DashMap::new(),
+        }
+    }
+}
+
+impl<L> SyncedFamily<L>
+where
+    L: Clone + std::hash::Hash + Eq + EncodeLabelSet + Send + Sync + std::fmt::Debug + 'static,
+{
+    fn sync(&self, labels: &L, total: u64) {
+        let last = self.last_seen.insert(labels.clone(), total).unwrap_or(0);
+        self.family
+            .get_or_create(labels)
+            .inc_by(delta_since(last, total));
+    }
+
+    fn rebase(&self) {
+        self.last_seen.clear();
+    }
+}
+
+/// Growth of a source total since `last`.  A total below `last` means the
+/// source was reset in between, so all of it is new.
+fn delta_since(last: u64, total: u64) -> u64 {
+    if total < last {
+        total
+    } else {
+        total - last
+    }
+}
+
 struct Metrics {
     registry: Registry,
-    packets_total: Counter,
-    bytes_total: Counter,
+    packets_total: SyncedFamily,
+    bytes_total: SyncedFamily,
+    payload_bytes_total: SyncedFamily,
+    category_packets_total: SyncedFamily<CategoryLabels>,
+    category_bytes_total: SyncedFamily<CategoryLabels>,
     active_connections: Gauge,
-    deep_inspect_packets_total: Counter,
-    domains_resolved_total: Counter,
+    deep_inspect_packets_total: SyncedCounter,
+    domains_resolved_total: SyncedCounter,
+    kernel_flow_overflows_total: SyncedCounter,
+    ring_buf_drops_total: SyncedCounter,
+    ring_buf_size_bytes: Gauge,
+    malformed_events_total: SyncedCounter,
+    clock_backward_steps_total: SyncedCounter,
+    bpf_run_count_total: SyncedFamily<ProgramLabels>,
+    bpf_runtime_ns_total: SyncedFamily<ProgramLabels>,
+    headroom_ratio: Gauge<f64, AtomicU64>,
+    headroom_utilization: Family<ResourceLabels, Gauge<f64, AtomicU64>>,
+    blocklist_drops_total: SyncedCounter,
+    tcp_retransmits_total: SyncedCounter,
+    forwarded_duplicates_total: SyncedCounter,
+    connections_created_total: SyncedCounter,
+    connections_expired_total: SyncedCounter,
+    distinct_src_ips: Family<WindowLabels, Gauge>,
+    distinct_dst_ips: Family<WindowLabels, Gauge>,
+    tcp_connections: Family<TcpStateLabels, Gauge>,
+    secondary_lag_rows: Gauge,
+    secondary_queued_events: Gauge,
+    secondary_dropped_events_total: SyncedCounter,
+    secondary_write_failures_total: SyncedCounter,
+    /// `TrafficState::resets` as of the last scrape.  Held while syncing so
+    /// concurrent scrapes cannot claim the same delta twice.
+    resets_seen: Mutex<u64>,
 }
 
 impl Metrics {
-    fn new() -> Self {
+    fn new(storage: &StorageMetrics, cleanup: &CleanupMetrics) -> Self {
         let mut registry = Registry::default();
-        let packets_total = Counter::default();
-        let bytes_total = Counter::default();
+        let packets_total = SyncedFamily::default();
+        let bytes_total = SyncedFamily::default();
+        let payload_bytes_total = SyncedFamily::default();
+        let category_packets_total = SyncedFamily::default();
+        let category_bytes_total = SyncedFamily::default();
         let active_connections = Gauge::default();
-        let deep_inspect_packets_total = Counter::default();
-        let domains_resolved_total = Counter::default();
+        let deep_inspect_packets_total = SyncedCounter::default();
+        let domains_resolved_total = SyncedCounter::default();
+        let kernel_flow_overflows_total = SyncedCounter::default();
+        let ring_buf_drops_total = SyncedCounter::default();
+        let ring_buf_size_bytes = Gauge::default();
+        let malformed_events_total = SyncedCounter::default();
+        let clock_backward_steps_total = SyncedCounter::default();
+        let bpf_run_count_total = SyncedFamily::default();
+        let bpf_runtime_ns_total = SyncedFamily::default();
+        let headroom_ratio = Gauge::<f64, AtomicU64>::default();
+        let headroom_utilization = Family::<ResourceLabels, Gauge<f64, AtomicU64>>::default();
+        let blocklist_drops_total = SyncedCounter::default();
+        let tcp_retransmits_total = SyncedCounter::default();
+        let forwarded_duplicates_total = SyncedCounter::default();
+        let connections_created_total = SyncedCounter::default();
+        let connections_expired_total = SyncedCounter::default();
+        let distinct_src_ips = Family::<WindowLabels, Gauge>::default();
+        let distinct_dst_ips = Family::<WindowLabels, Gauge>::default();
+        let tcp_connections = Family::<TcpStateLabels, Gauge>::default();
+        let secondary_lag_rows = Gauge::default();
+        let secondary_queued_events = Gauge::default();
+        let secondary_dropped_events_total = SyncedCounter::default();
+        let secondary_write_failures_total = SyncedCounter::default();
 
         registry.register(
             "ayaflow_packets",
             "Total number of observed packets",
-            packets_total.clone(),
+            packets_total.family.clone(),
         );
         registry.register(
             "ayaflow_bytes",
             "Total bytes observed",
-            bytes_total.clone(),
+            bytes_total.family.clone(),
+        );
+        registry.register(
+            "ayaflow_payload_bytes",
+            "Total transport payload bytes observed, excluding IP and TCP/UDP headers",
+            payload_bytes_total.family.clone(),
+        );
+        registry.register(
+            "ayaflow_category_packets",
+            "Packets per application category of the service port",
+            category_packets_total.family.clone(),
+        );
+        registry.register(
+            "ayaflow_category_bytes",
+            "Bytes per application category of the service port",
+            category_bytes_total.family.clone(),
         );
         registry.register(
             "ayaflow_active_connections",
@@ -63,241 +323,4471 @@ impl Metrics {
         registry.register(
             "ayaflow_deep_inspect_packets",
             "Total L7 payload events processed by deep inspection",
-            deep_inspect_packets_total.clone(),
+            deep_inspect_packets_total.counter.clone(),
         );
         registry.register(
             "ayaflow_domains_resolved",
             "Total domains resolved from DNS queries and TLS SNI",
-            domains_resolved_total.clone(),
+            domains_resolved_total.counter.clone(),
+        );
+        registry.register(
+            "ayaflow_kernel_flow_overflows",
+            "Flows not recorded because the kernel aggregation map was full",
+            kernel_flow_overflows_total.counter.clone(),
+        );
+        registry.register(
+            "ayaflow_ring_buf_drops",
+            "Packet events dropped in the kernel because the ring buffer was full",
+            ring_buf_drops_total.counter.clone(),
+        );
+        registry.register(
+            "ayaflow_ring_buf_size_bytes",
+            "Size of the kernel's packet event ring buffer, set by ringbuf_size_kb",
+            ring_buf_size_bytes.clone(),
+        );
+        registry.register(
+            "ayaflow_malformed_events",
+            "Ring buffer events skipped because their layout did not match this build",
+            malformed_events_total.counter.clone(),
+        );
+        registry.register(
+            "ayaflow_clock_backward_steps",
+            "Times the system clock stepped back; window boundaries hold until it catches up",
+            clock_backward_steps_total.counter.clone(),
+        );
+        registry.register(
+            "ayaflow_bpf_run_count",
+            "Runs of each eBPF program counted by the kernel (needs bpf_stats)",
+            bpf_run_count_total.family.clone(),
+        );
+        registry.register(
+            "ayaflow_bpf_runtime_ns",
+            "Nanoseconds the kernel spent in each eBPF program (needs bpf_stats)",
+            bpf_runtime_ns_total.family.clone(),
+        );
+        registry.register(
+            "ayaflow_headroom_ratio",
+            "Capacity left over the last minute: 1 minus the largest utilization",
+            headroom_ratio.clone(),
+        );
+        registry.register(
+            "ayaflow_headroom_utilization",
+            "Peak share of each limit used over the last minute",
+            headroom_utilization.clone(),
+        );
+        registry.register(
+            "ayaflow_blocklist_drops",
+            "Packets dropped in the kernel because an address was blocklisted",
+            blocklist_drops_total.counter.clone(),
+        );
+        registry.register(
+            "ayaflow_tcp_retransmits",
+            "TCP retransmissions detected across all connections",
+            tcp_retransmits_total.counter.clone(),
+        );
+        registry.register(
+            "ayaflow_forwarded_duplicates",
+            "Second sightings of forwarded or mirrored packets left uncounted",
+            forwarded_duplicates_total.counter.clone(),
+        );
+        registry.register(
+            "ayaflow_connections_created",
+            "Connection entries created in the live table",
+            connections_created_total.counter.clone(),
+        );
+        registry.register(
+            "ayaflow_connections_expired",
+            "Connection entries removed from the live table after going idle",
+            connections_expired_total.counter.clone(),
+        );
+        registry.register(
+            "ayaflow_distinct_src_ips",
+            "Estimated distinct source addresses over the window",
+            distinct_src_ips.clone(),
+        );
+        registry.register(
+            "ayaflow_distinct_dst_ips",
+            "Estimated distinct destination addresses over the window",
+            distinct_dst_ips.clone(),
+        );
+        registry.register(
+            "ayaflow_tcp_connections",
+            "Live TCP connections (one per direction) by best-effort state",
+            tcp_connections.clone(),
+        );
+        registry.register(
+            "ayaflow_secondary_storage_lag_rows",
+            "Rows the primary backend committed that secondary_db_url has not",
+            secondary_lag_rows.clone(),
+        );
+        registry.register(
+            "ayaflow_secondary_storage_queued_events",
+            "Writer events waiting for the secondary_db_url writer",
+            secondary_queued_events.clone(),
+        );
+        registry.register(
+            "ayaflow_secondary_storage_dropped_events",
+            "Writer events not passed to secondary_db_url because its queue was full",
+            secondary_dropped_events_total.counter.clone(),
+        );
+        registry.register(
+            "ayaflow_secondary_storage_write_failures",
+            "Failed transactions and row inserts on secondary_db_url",
+            secondary_write_failures_total.counter.clone(),
         );
+        storage.register(&mut registry);
+        cleanup.register(&mut registry);
 
         Self {
             registry,
             packets_total,
             bytes_total,
+            payload_bytes_total,
+            category_packets_total,
+            category_bytes_total,
             active_connections,
             deep_inspect_packets_total,
             domains_resolved_total,
+            kernel_flow_overflows_total,
+            ring_buf_drops_total,
+            ring_buf_size_bytes,
+            malformed_events_total,
+            clock_backward_steps_total,
+            bpf_run_count_total,
+            bpf_runtime_ns_total,
+            headroom_ratio,
+            headroom_utilization,
+            blocklist_drops_total,
+            tcp_retransmits_total,
+            forwarded_duplicates_total,
+            connections_created_total,
+            connections_expired_total,
+            distinct_src_ips,
+            distinct_dst_ips,
+            tcp_connections,
+            secondary_lag_rows,
+            secondary_queued_events,
+            secondary_dropped_events_total,
+            secondary_write_failures_total,
+            resets_seen: Mutex::new(0),
         }
     }
 }
 
-// ── Response Types ────────────────────────────────────────────────────────────
+// ── Errors ────────────────────────────────────────────────────────────────────
+
+/// Error returned by API handlers and middleware.
+///
+/// Every variant renders as `{ "error": { "code", "message" } }` with a
+/// matching status code.
+#[derive(Debug)]
+pub enum ApiError {
+    /// Invalid query parameters (400).
+    BadRequest(String),
+    /// Missing or wrong bearer token (401).
+    Unauthorized,
+    /// Client address not in the allowlist (403).
+    Forbidden,
+    /// Unknown route or resource (404).
+    NotFound(String),
+    /// The request clashes with work already in progress (409).
+    Conflict(String),
+    /// Per-client rate limit exceeded (429), retry after this many seconds.
+    RateLimited(u64),
+    /// Storage query failed (500), or a snapshot was refused (503).
+    Storage(StorageError),
+    /// Unexpected server-side failure (500).
+    Internal(String),
+    /// Request did not complete within the configured timeout (503).
+    Timeout,
+}
 
-#[derive(Serialize)]
-pub struct HealthResponse {
-    status: String,
-    active_connections: usize,
-    total_packets: u64,
+impl ApiError {
+    fn status_and_code(&self) -> (StatusCode, &'static str) {
+        match self {
+            ApiError::BadRequest(_) => (StatusCode::BAD_REQUEST, "bad_request"),
+            ApiError::Unauthorized => (StatusCode::UNAUTHORIZED, "unauthorized"),
+            ApiError::Forbidden => (StatusCode::FORBIDDEN, "forbidden"),
+            ApiError::NotFound(_) => (StatusCode::NOT_FOUND, "not_found"),
+            ApiError::Conflict(_) => (StatusCode::CONFLICT, "conflict"),
+            ApiError::RateLimited(_) => (StatusCode::TOO_MANY_REQUESTS, "rate_limited"),
+            ApiError::Storage(StorageError::Snapshot(_)) => {
+                (StatusCode::SERVICE_UNAVAILABLE, "snapshot_unavailable")
+            }
+            #[cfg(feature = "clickhouse")]
+            ApiError::Storage(StorageError::ClickHouse(e)) if e.is_unreachable() => {
+                (StatusCode::SERVICE_UNAVAILABLE, "storage_unavailable")
+            }
+            #[cfg(feature = "postgres")]
+            ApiError::Storage(StorageError::Postgres(e)) if e.is_unreachable() => {
+                (StatusCode::SERVICE_UNAVAILABLE, "storage_unavailable")
+            }
+            ApiError::Storage(_) => (StatusCode::INTERNAL_SERVER_ERROR, "storage_error"),
+            ApiError::Internal(_) => (StatusCode::INTERNAL_SERVER_ERROR, "internal_error"),
+            ApiError::Timeout => (StatusCode::SERVICE_UNAVAILABLE, "timeout"),
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            ApiError::BadRequest(msg)
+            | ApiError::NotFound(msg)
+            | ApiError::Conflict(msg)
+            | ApiError::Internal(msg) => msg.clone(),
+            ApiError::Unauthorized => "missing or invalid token".to_string(),
+            ApiError::Forbidden => "client address is not allowed".to_string(),
+            ApiError::RateLimited(secs) => format!("rate limit exceeded, retry in {}s", secs),
+            ApiError::Storage(e) => e.to_string(),
+            ApiError::Timeout => "request timed out".to_string(),
+        }
+    }
+
+    /// `{ "error": { "code", "message" } }`, also sent as a WebSocket frame.
+    fn body(&self) -> serde_json::Value {
+        serde_json::json!({
+            "error": { "code": self.status_and_code().1, "message": self.message() },
+        })
+    }
 }
 
-#[derive(Serialize)]
-pub struct StatsResponse {
-    uptime_seconds: u64,
-    total_packets: u64,
-    total_bytes: u64,
-    active_connections: usize,
-    packets_per_second: f64,
-    bytes_per_second: f64,
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let (status, _) = self.status_and_code();
+        if let ApiError::Storage(ref e) = self {
+            tracing::error!("Storage query failed: {}", e);
+        }
+        let body = Json(self.body());
+        match self {
+            ApiError::RateLimited(secs) => {
+                (status, [(header::RETRY_AFTER, secs.to_string())], body).into_response()
+            }
+            ApiError::Timeout => (status, [(header::RETRY_AFTER, "1")], body).into_response(),
+            ApiError::Unauthorized => {
+                (status, [(header::WWW_AUTHENTICATE, "Bearer")], body).into_response()
+            }
+            _ => (status, body).into_response(),
+        }
+    }
 }
 
-#[derive(Deserialize)]
-pub struct HistoryParams {
-    limit: Option<usize>,
+impl From<StorageError> for ApiError {
+    fn from(e: StorageError) -> Self {
+        ApiError::Storage(e)
+    }
 }
 
-// ── Router ────────────────────────────────────────────────────────────────────
+/// Checks on query parameters beyond what deserializing them enforces.
+/// Errors name the offending parameter.
+trait Validate {
+    fn validate(&self) -> Result<(), ApiError>;
+}
 
-pub fn router(state: Arc<AppState>, allowed_ips: &[String]) -> Router {
-    let metrics = Arc::new(Metrics::new());
-
-    let mut app = Router::new()
-        .route("/api/live", get(get_live_stats))
-        .route("/api/history", get(get_history))
-        .route("/api/health", get(get_health))
-        .route("/api/stats", get(get_stats))
-        .route("/api/stream", get(ws_handler))
-        .route("/metrics", get({
-            let m = metrics.clone();
-            let s = state.clone();
-            move || get_metrics(s.clone(), m.clone())
-        }));
+/// Query parameters that deserialized and passed `Validate`.  Unlike
+/// `Query`, a value that fails to deserialize is reported with the name of
+/// its parameter.
+struct ValidQuery<T>(T);
 
-    // Apply IP allowlist middleware if configured.
-    if !allowed_ips.is_empty() {
-        let nets: Arc<Vec<IpNet>> = Arc::new(
-            allowed_ips
-                .iter()
-                .filter_map(|s| s.parse::<IpNet>().ok())
-                .collect(),
-        );
-        app = app.layer(middleware::from_fn(move |req, next| {
-            let nets = nets.clone();
-            ip_allowlist(req, next, nets)
-        }));
+#[axum::async_trait]
+impl<T, S> FromRequestParts<S> for ValidQuery<T>
+where
+    T: DeserializeOwned + Validate,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, ApiError> {
+        let query = parts.uri.query().unwrap_or_default();
+        let deserializer =
+            serde_urlencoded::Deserializer::new(form_urlencoded::parse(query.as_bytes()));
+        let params: T = serde_path_to_error::deserialize(deserializer).map_err(|e| {
+            let message = e.inner().to_string();
+            let message: String = message.chars().take(200).collect();
+            match e.path().to_string().as_str() {
+                "." => ApiError::BadRequest(format!("invalid query string: {}", message)),
+                param => ApiError::BadRequest(format!("invalid {}: {}", param, message)),
+            }
+        })?;
+        params.validate()?;
+        Ok(ValidQuery(params))
     }
+}
 
-    app.with_state(state)
+/// Largest `limit` any listing endpoint serves.
+const MAX_LIMIT: usize = 1000;
+
+/// Reject a `limit` outside `1..=MAX_LIMIT`.
+fn check_limit(limit: Option<usize>) -> Result<(), ApiError> {
+    match limit {
+        Some(n) if !(1..=MAX_LIMIT).contains(&n) => Err(ApiError::BadRequest(format!(
+            "limit must be between 1 and {}, got {}",
+            MAX_LIMIT, n
+        ))),
+        _ => Ok(()),
+    }
 }
 
-// ── IP Allowlist Middleware ────────────────────────────────────────────────────
+/// Reject timestamps before the epoch and a range whose start is after its
+/// end.
+fn check_range(from: Option<i64>, to: Option<i64>) -> Result<(), ApiError> {
+    for (name, value) in [("from", from), ("to", to)] {
+        if let Some(value) = value.filter(|v| *v < 0) {
+            return Err(ApiError::BadRequest(format!(
+                "{} must be milliseconds since the Unix epoch, got {}",
+                name, value
+            )));
+        }
+    }
+    match (from, to) {
+        (Some(from), Some(to)) if from > to => Err(ApiError::BadRequest(format!(
+            "from ({}) must not be after to ({})",
+            from, to
+        ))),
+        _ => Ok(()),
+    }
+}
 
-async fn ip_allowlist(
-    req: axum::extract::Request,
-    next: middleware::Next,
-    allowed: Arc<Vec<IpNet>>,
-) -> impl IntoResponse {
-    if let Some(connect_info) = req.extensions().get::<ConnectInfo<SocketAddr>>() {
-        let ip = connect_info.0.ip();
-        if allowed.iter().any(|net| net.contains(&ip)) {
-            return next.run(req).await.into_response();
+/// A free-form name of 1 to `max` bytes without control characters.
+fn check_label(field: &str, value: Option<&str>, max: usize) -> Result<(), ApiError> {
+    match value {
+        Some(value) if !(1..=max).contains(&value.len()) || value.contains(char::is_control) => {
+            Err(ApiError::BadRequest(format!(
+                "{} must be 1 to {} bytes without control characters",
+                field, max
+            )))
         }
-        return StatusCode::FORBIDDEN.into_response();
+        _ => Ok(()),
     }
-    // If there is no ConnectInfo, allow (should not happen with into_make_service_with_connect_info).
-    next.run(req).await.into_response()
 }
 
-// ── Handlers ──────────────────────────────────────────────────────────────────
+/// Linux interface names are at most 15 bytes (IFNAMSIZ less the NUL) and
+/// contain no '/' or whitespace.
+fn check_interface(interface: Option<&str>) -> Result<(), ApiError> {
+    let Some(name) = interface else {
+        return Ok(());
+    };
+    let valid = (1..=15).contains(&name.len())
+        && !name.contains(|c: char| c == '/' || c.is_whitespace() || c.is_control());
+    if valid {
+        return Ok(());
+    }
+    let shown: String = name.chars().take(32).collect();
+    Err(ApiError::BadRequest(format!(
+        "interface must be an interface name of 1 to 15 bytes, got {:?}",
+        shown
+    )))
+}
 
-async fn get_health(State(state): State<Arc<AppState>>) -> Json<HealthResponse> {
-    Json(HealthResponse {
-        status: "ok".to_string(),
-        active_connections: state.traffic.active_connections.load(Ordering::Relaxed),
-        total_packets: state.traffic.total_packets.load(Ordering::Relaxed),
-    })
+// ── Response Types ────────────────────────────────────────────────────────────
+
+api_schema! {
+    #[derive(Deserialize)]
+    pub struct HistoryParams {
+        limit: Option<usize>,
+        /// Earliest timestamp, milliseconds since the Unix epoch.
+        from: Option<i64>,
+        /// Latest timestamp, milliseconds since the Unix epoch.
+        to: Option<i64>,
+        /// Only packets to or from this address.
+        ip: Option<IpAddr>,
+        /// Only packets seen on this interface.
+        interface: Option<String>,
+        /// Only packets to or from this MAC address.
+        mac: Option<MacAddr>,
+        /// Only packets in this direction relative to `local_networks`.
+        direction: Option<FlowDirection>,
+        /// Only packets whose service port is in this category, e.g. "web".
+        category: Option<String>,
+        /// Only rows stored by this instance.
+        instance: Option<String>,
+        /// Only packets of this protocol, e.g. "tcp" or "icmp".
+        protocol: Option<String>,
+        /// Only ICMP and ICMPv6 packets of this type, e.g. 11 for time
+        /// exceeded.
+        icmp_type: Option<u8>,
+        /// Only packets whose remote end is in this country, e.g. "CN", or
+        /// "unknown" for those GeoIP did not place.
+        country: Option<CountryMatch>,
+        /// Only packets whose remote end is in this autonomous system.
+        asn: Option<u32>,
+    }
 }
 
-async fn get_stats(State(state): State<Arc<AppState>>) -> Json<StatsResponse> {
-    let uptime = state.start_time.elapsed().as_secs();
-    let total_packets = state.traffic.total_packets.load(Ordering::Relaxed);
-    let total_bytes = state.traffic.total_bytes.load(Ordering::Relaxed);
-    let active_connections = state.traffic.active_connections.load(Ordering::Relaxed);
+api_schema! {
+    #[derive(Deserialize)]
+    pub struct InterfaceParams {
+        /// Only traffic seen on this interface, e.g. "eth0".
+        interface: Option<String>,
+    }
+}
 
-    let packets_per_second = if uptime > 0 {
-        total_packets as f64 / uptime as f64
-    } else {
-        0.0
-    };
-    let bytes_per_second = if uptime > 0 {
-        total_bytes as f64 / uptime as f64
-    } else {
-        0.0
-    };
+/// Longest a `/api/live?wait=true` request is held, in seconds.
+const MAX_LIVE_WAIT_SECONDS: u64 = 60;
+const DEFAULT_LIVE_WAIT_SECONDS: u64 = 30;
 
-    Json(StatsResponse {
-        uptime_seconds: uptime,
-        total_packets,
-        total_bytes,
-        active_connections,
-        packets_per_second,
-        bytes_per_second,
-    })
+api_schema! {
+    #[derive(Deserialize)]
+    pub struct LiveParams {
+        /// Only traffic seen on this interface, e.g. "eth0".
+        interface: Option<String>,
+        /// With an `If-None-Match` naming the current ETag, hold the request
+        /// until the live table changes or `timeout` passes, then answer
+        /// 200 or 304.
+        #[serde(default)]
+        wait: bool,
+        /// Seconds to hold a `wait` request, 1 to 60; 30 by default.
+        timeout: Option<u64>,
+    }
 }
 
-async fn get_live_stats(State(state): State<Arc<AppState>>) -> Json<serde_json::Value> {
-    let mut connections: Vec<_> = state
-        .traffic
-        .connections
-        .iter()
-        .map(|entry| {
-            let (key, stats) = entry.pair();
-            serde_json::json!({
-                "connection": key,
-                "stats": stats
-            })
-        })
-        .collect();
+impl LiveParams {
+    /// How long to hold the request, when it asks to be held.
+    fn wait(&self) -> Option<Duration> {
+        let seconds = self.timeout.unwrap_or(DEFAULT_LIVE_WAIT_SECONDS);
+        self.wait.then(|| Duration::from_secs(seconds))
+    }
 
-    connections.sort_by(|a, b| {
-        let count_a = a["stats"]["packets_count"].as_u64().unwrap_or(0);
-        let count_b = b["stats"]["packets_count"].as_u64().unwrap_or(0);
-        count_b.cmp(&count_a)
-    });
+    /// The same for a request not yet routed, so `request_timeout` can
+    /// leave long-polls their wait.
+    fn wait_requested(uri: &axum::http::Uri) -> Option<Duration> {
+        if !uri.path().ends_with("/api/live") {
+            return None;
+        }
+        let params = axum::extract::Query::<LiveParams>::try_from_uri(uri).ok()?;
+        params.validate().ok()?;
+        params.wait()
+    }
+}
 
-    connections.truncate(50);
+api_schema! {
+    #[derive(Deserialize)]
+    pub struct AlertParams {
+        limit: Option<usize>,
+        /// Only alerts with a lower id: pass the last id of a page to get
+        /// the next one.
+        before_id: Option<i64>,
+        /// "info", "warning" or "critical".
+        severity: Option<String>,
+        /// Rule name, e.g. "ttl_below".
+        rule: Option<String>,
+        /// Only alerts that fired at or after this time, milliseconds since
+        /// the Unix epoch.
+        since: Option<i64>,
+        /// Only acknowledged (true) or unacknowledged (false) alerts.
+        acked: Option<bool>,
+        /// Only alerts raised by this instance.
+        instance: Option<String>,
+    }
+}
 
-    Json(serde_json::json!({
-        "connections": connections,
-        "total_packets": state.traffic.total_packets.load(Ordering::Relaxed),
-        "total_bytes": state.traffic.total_bytes.load(Ordering::Relaxed),
-    }))
+api_schema! {
+    #[derive(Deserialize)]
+    pub struct AckParams {
+        /// Who handled the alert; "admin" when absent.
+        by: Option<String>,
+    }
 }
 
-async fn get_history(
-    State(state): State<Arc<AppState>>,
-    Query(params): Query<HistoryParams>,
-) -> Json<serde_json::Value> {
-    let limit = params.limit.unwrap_or(100).min(1000);
-    match state.storage.query_history(limit) {
-        Ok(data) => Json(serde_json::json!(data)),
-        Err(e) => Json(serde_json::json!({ "error": e.to_string() })),
+api_schema! {
+    #[derive(Deserialize)]
+    pub struct ConnectionsParams {
+        #[serde(default)]
+        sort: ConnectionSort,
+        #[serde(default)]
+        order: SortOrder,
+        limit: Option<usize>,
+        #[serde(default)]
+        offset: usize,
+        ip: Option<IpAddr>,
+        port: Option<u16>,
+        protocol: Option<String>,
+        interface: Option<String>,
+        direction: Option<FlowDirection>,
+        /// Only connections to this class of destination; all by default.
+        cast: Option<CastSelection>,
+        /// Both directions of one connection.
+        connection_id: Option<ConnectionId>,
     }
 }
 
-async fn get_metrics(state: Arc<AppState>, metrics: Arc<Metrics>) -> impl IntoResponse {
-    // Sync counters from atomic state into prometheus gauges/counters.
-    let total_pkts = state.traffic.total_packets.load(Ordering::Relaxed);
-    let total_b = state.traffic.total_bytes.load(Ordering::Relaxed);
-    let active = state.traffic.active_connections.load(Ordering::Relaxed);
+api_schema! {
+    #[derive(Deserialize)]
+    pub struct TopParams {
+        #[serde(default)]
+        by: TopBy,
+        /// IPv4 prefix length for `*_subnet` groupings.
+        prefix: Option<u8>,
+        /// IPv6 prefix length for `*_subnet` groupings.
+        prefix6: Option<u8>,
+        limit: Option<usize>,
+        /// Only connections to this class of destination; unicast by
+        /// default, so discovery and broadcast chatter stays out.
+        cast: Option<CastSelection>,
+        /// Rank the rows this instance stored, such as a fleet sensor's on
+        /// the central agent, rather than live connections.
+        instance: Option<String>,
+        /// With `instance`, start of the range, milliseconds since the
+        /// Unix epoch; the last hour by default.
+        from: Option<i64>,
+        /// With `instance`, end of the range.
+        to: Option<i64>,
+    }
+}
 
-    // Counter::inner() returns the current value; we need to set to the absolute value.
-    // prometheus-client Counters are monotonic so we increment by the delta.
-    let current_pkts = metrics.packets_total.get();
-    if total_pkts > current_pkts {
-        metrics.packets_total.inc_by(total_pkts - current_pkts);
+api_schema! {
+    #[derive(Deserialize)]
+    pub struct UsageParams {
+        ip: Option<IpAddr>,
+        /// Start of the range, milliseconds since the Unix epoch.
+        from: Option<i64>,
+        /// End of the range, milliseconds since the Unix epoch.
+        to: Option<i64>,
+        #[serde(default)]
+        granularity: UsageGranularity,
     }
-    let current_b = metrics.bytes_total.get();
-    if total_b > current_b {
-        metrics.bytes_total.inc_by(total_b - current_b);
+}
+
+api_schema! {
+    #[derive(Deserialize)]
+    pub struct PeerParams {
+        /// Remote address to look up; all peers when absent.
+        ip: Option<IpAddr>,
+        /// Start of the range, milliseconds since the Unix epoch.
+        from: Option<i64>,
+        /// End of the range, milliseconds since the Unix epoch.
+        to: Option<i64>,
     }
-    metrics.active_connections.set(active as i64);
+}
 
-    // L7 deep inspection counters.
-    let deep_pkts = state.traffic.deep_inspect_packets.load(Ordering::Relaxed);
-    let current_deep = metrics.deep_inspect_packets_total.get();
-    if deep_pkts > current_deep {
-        metrics.deep_inspect_packets_total.inc_by(deep_pkts - current_deep);
+api_schema! {
+    #[derive(Deserialize)]
+    pub struct ConnectionParams {
+        /// The 4-tuple, in either direction.
+        src_ip: IpAddr,
+        src_port: u16,
+        dst_ip: IpAddr,
+        dst_port: u16,
+        /// Stored rows to return, newest first.
+        limit: Option<usize>,
     }
-    let domains = state.traffic.domains_resolved.load(Ordering::Relaxed);
-    let current_domains = metrics.domains_resolved_total.get();
-    if domains > current_domains {
-        metrics.domains_resolved_total.inc_by(domains - current_domains);
+}
+
+api_schema! {
+    #[derive(Deserialize)]
+    pub struct ConnectionIdParams {
+        /// Stored rows to return, newest first.
+        limit: Option<usize>,
     }
+}
 
-    let mut buf = String::new();
-    encode(&mut buf, &metrics.registry).unwrap();
-    (
-        [(axum::http::header::CONTENT_TYPE, "text/plain; version=0.0.4")],
-        buf,
-    )
+api_schema! {
+    #[derive(Deserialize)]
+    pub struct AsymmetryParams {
+        /// Unidirectional flows listed; 20 when absent.
+        limit: Option<usize>,
+    }
 }
 
-async fn ws_handler(
-    ws: WebSocketUpgrade,
-    State(state): State<Arc<AppState>>,
-) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| handle_socket(socket, state))
+api_schema! {
+    #[derive(Deserialize)]
+    pub struct ConnectionExportParams {
+        #[serde(default)]
+        format: ExportFormat,
+    }
 }
 
-async fn handle_socket(mut socket: WebSocket, state: Arc<AppState>) {
-    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(1));
+api_schema! {
+    #[derive(Deserialize)]
+    pub struct ReportParams {
+        /// Period ending now, like "90m", "24h" or "7d"; 24h when absent.
+        period: Option<String>,
+        /// Entries in each top list.
+        limit: Option<usize>,
+        #[serde(default)]
+        format: ReportFormat,
+        /// Only rows and alerts stored by this instance; live figures are
+        /// this agent's either way.
+        instance: Option<String>,
+    }
+}
 
-    loop {
-        interval.tick().await;
+api_schema! {
+    /// Body of `PUT /api/blocklist`.
+    #[derive(Deserialize)]
+    pub struct BlocklistUpdate {
+        /// Addresses and CIDRs that replace the current entries.
+        entries: Vec<String>,
+    }
+}
 
-        let stats = serde_json::json!({
-            "total_packets": state.traffic.total_packets.load(Ordering::Relaxed),
-            "total_bytes": state.traffic.total_bytes.load(Ordering::Relaxed),
-            "active_connections": state.traffic.active_connections.load(Ordering::Relaxed),
-        });
+api_schema! {
+    #[derive(Deserialize)]
+    pub struct ResetParams {
+        /// Also delete every stored packet from SQLite.
+        #[serde(default)]
+        include_db: bool,
+    }
+}
 
-        if socket
-            .send(Message::Text(stats.to_string().into()))
-            .await
-            .is_err()
-        {
-            break;
+impl Validate for HistoryParams {
+    fn validate(&self) -> Result<(), ApiError> {
+        check_limit(self.limit)?;
+        check_range(self.from, self.to)?;
+        check_interface(self.interface.as_deref())?;
+        check_label("instance", self.instance.as_deref(), 128)?;
+        check_protocol(self.protocol.as_deref())
+    }
+}
+
+impl Validate for InterfaceParams {
+    fn validate(&self) -> Result<(), ApiError> {
+        check_interface(self.interface.as_deref())
+    }
+}
+
+impl Validate for LiveParams {
+    fn validate(&self) -> Result<(), ApiError> {
+        check_interface(self.interface.as_deref())?;
+        match self.timeout {
+            Some(t) if !(1..=MAX_LIVE_WAIT_SECONDS).contains(&t) => Err(ApiError::BadRequest(
+                format!("timeout must be between 1 and {}, got {}", MAX_LIVE_WAIT_SECONDS, t),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Validate for AlertParams {
+    fn validate(&self) -> Result<(), ApiError> {
+        check_limit(self.limit)?;
+        check_range(self.since, None)?;
+        check_label("rule", self.rule.as_deref(), 128)?;
+        check_label("instance", self.instance.as_deref(), 128)?;
+        match self.severity.as_deref() {
+            Some(severity) if !SEVERITIES.contains(&severity) => {
+                let shown: String = severity.chars().take(32).collect();
+                Err(ApiError::BadRequest(format!(
+                    "severity must be one of {}, got {:?}",
+                    SEVERITIES.join(", "),
+                    shown
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Validate for AckParams {
+    fn validate(&self) -> Result<(), ApiError> {
+        check_label("by", self.by.as_deref(), 64)
+    }
+}
+
+impl Validate for ConnectionsParams {
+    fn validate(&self) -> Result<(), ApiError> {
+        check_limit(self.limit)?;
+        check_interface(self.interface.as_deref())?;
+        check_protocol(self.protocol.as_deref())
+    }
+}
+
+fn check_protocol(protocol: Option<&str>) -> Result<(), ApiError> {
+    match protocol {
+        Some(protocol) if !is_protocol_name(protocol) => {
+            let shown: String = protocol.chars().take(32).collect();
+            Err(ApiError::BadRequest(format!(
+                "protocol must be TCP, UDP, ICMP, ICMPv6, ARP, IP(<number>) or ETH(0x<hex>), \
+                 got {:?}",
+                shown
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+impl Validate for TopParams {
+    fn validate(&self) -> Result<(), ApiError> {
+        check_limit(self.limit)?;
+        let (prefix, prefix6) = self.prefixes();
+        if prefix > 32 {
+            return Err(ApiError::BadRequest(format!(
+                "prefix must be an IPv4 prefix length of at most 32, got {}",
+                prefix
+            )));
+        }
+        if prefix6 > 128 {
+            return Err(ApiError::BadRequest(format!(
+                "prefix6 must be an IPv6 prefix length of at most 128, got {}",
+                prefix6
+            )));
         }
+        if self.instance.is_none() {
+            if self.from.is_some() || self.to.is_some() {
+                return Err(ApiError::BadRequest("from and to need instance".into()));
+            }
+            return Ok(());
+        }
+        if matches!(self.by, TopBy::SrcSubnet | TopBy::DstSubnet) {
+            return Err(ApiError::BadRequest(
+                "by: stored rows rank by src_ip, dst_ip or port, not by subnet".into(),
+            ));
+        }
+        if self.cast.is_some() {
+            return Err(ApiError::BadRequest("cast applies to live connections only".into()));
+        }
+        check_range(self.from, self.to)
+    }
+}
+
+impl TopParams {
+    fn prefixes(&self) -> (u8, u8) {
+        (self.prefix.unwrap_or(24), self.prefix6.unwrap_or(64))
+    }
+}
+
+impl Validate for UsageParams {
+    fn validate(&self) -> Result<(), ApiError> {
+        check_range(self.from, self.to)
+    }
+}
+
+impl Validate for PeerParams {
+    fn validate(&self) -> Result<(), ApiError> {
+        check_range(self.from, self.to)
+    }
+}
+
+impl Validate for ConnectionParams {
+    fn validate(&self) -> Result<(), ApiError> {
+        check_limit(self.limit)
+    }
+}
+
+impl Validate for ConnectionIdParams {
+    fn validate(&self) -> Result<(), ApiError> {
+        check_limit(self.limit)
+    }
+}
+
+impl Validate for AsymmetryParams {
+    fn validate(&self) -> Result<(), ApiError> {
+        check_limit(self.limit)
+    }
+}
+
+impl Validate for ConnectionExportParams {
+    fn validate(&self) -> Result<(), ApiError> {
+        Ok(())
+    }
+}
+
+impl Validate for DnsFlushParams {
+    fn validate(&self) -> Result<(), ApiError> {
+        Ok(())
+    }
+}
+
+impl Validate for ReportParams {
+    fn validate(&self) -> Result<(), ApiError> {
+        check_limit(self.limit)?;
+        check_label("instance", self.instance.as_deref(), 128)?;
+        match self.period.as_deref().map(crate::reports::parse_period) {
+            Some(Err(e)) => Err(ApiError::BadRequest(e)),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Validate for ResetParams {
+    fn validate(&self) -> Result<(), ApiError> {
+        Ok(())
+    }
+}
+
+api_schema! {
+    #[derive(Deserialize)]
+    pub struct BackfillParams {
+        /// Only packets captured at or after this time (epoch ms).
+        since: Option<i64>,
+        /// Rows updated per transaction (default 1000).
+        batch: Option<usize>,
+        /// Reverse lookups started per second, at most (default 20).
+        rate: Option<u32>,
+        /// Reverse lookups in flight at once (default 4).
+        concurrency: Option<usize>,
+        /// Skip private, loopback, link-local and other unroutable addresses.
+        #[serde(default)]
+        skip_private: bool,
+        /// Start from the first row instead of where the last run stopped.
+        #[serde(default)]
+        restart: bool,
+    }
+}
+
+impl BackfillParams {
+    fn options(&self) -> BackfillOptions {
+        let defaults = BackfillOptions::default();
+        BackfillOptions {
+            since: self.since,
+            batch_rows: self.batch.unwrap_or(defaults.batch_rows),
+            lookups_per_second: self.rate.unwrap_or(defaults.lookups_per_second),
+            concurrency: self.concurrency.unwrap_or(defaults.concurrency),
+            skip_private: self.skip_private,
+            restart: self.restart,
+        }
+    }
+}
+
+impl Validate for BackfillParams {
+    fn validate(&self) -> Result<(), ApiError> {
+        if let Some(since) = self.since.filter(|s| *s < 0) {
+            return Err(ApiError::BadRequest(format!(
+                "since must be milliseconds since the Unix epoch, got {}",
+                since
+            )));
+        }
+        self.options().validate().map_err(ApiError::BadRequest)
+    }
+}
+
+api_schema! {
+    /// The configuration the agent is running with.
+    #[derive(Debug, Clone, Default, Serialize)]
+    pub struct ConfigResponse {
+        /// YAML file passed with `--config`, if any.
+        pub config_file: Option<String>,
+        /// Effective configuration after file and CLI merging, secrets
+        /// redacted.
+        pub config: serde_json::Value,
+        /// "default", "file" or "cli" per field; fields of the `api` and
+        /// `alerts` sections are keyed like `api.rate_limit_per_second`.
+        pub sources: BTreeMap<String, ConfigSource>,
+        pub attach: AttachStatus,
+    }
+}
+
+impl ConfigResponse {
+    pub fn new(config: &Config, config_file: Option<String>, attach: AttachStatus) -> Self {
+        Self {
+            config_file,
+            config: serde_json::to_value(config.redacted()).unwrap_or_default(),
+            sources: config.source_map(),
+            attach,
+        }
+    }
+}
+
+api_schema! {
+    #[derive(Deserialize)]
+    pub struct DnsFlushParams {
+        /// Only this address's entry; every entry when absent.
+        ip: Option<IpAddr>,
+    }
+}
+
+/// `/api/live` with the agent's live entries.
+pub type LiveResponse = ayaflow_common::api::LiveResponse<ConnectionEntry>;
+
+api_schema! {
+    /// One connection as served by `/api/connection`: what the live table
+    /// holds for it and what was stored.
+    #[derive(Serialize)]
+    pub struct ConnectionDetail {
+        /// The requested direction first, then the reverse, each only while
+        /// still tracked.  For an id several connections share, each one's
+        /// pair in turn.
+        live: Vec<ConnectionEntry>,
+        /// Newest stored rows in either direction.
+        history: Vec<HistoryRow>,
+        /// Over every stored row, not only those in `history`.
+        stored: StoredConnectionTotals,
+    }
+}
+
+// ── Routes ────────────────────────────────────────────────────────────────────
+
+/// Who may call a route, which decides the router and layers it gets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Access {
+    Public,
+    /// Public, but shares the storage query cap.
+    Storage,
+    /// Only routed when an admin token is configured, and requires it.
+    Admin,
+    /// Only routed when an ingest token is configured, and requires it.
+    Ingest,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Verb {
+    Get,
+    Post,
+    Put,
+}
+
+impl Verb {
+    fn filter(self) -> MethodFilter {
+        match self {
+            Verb::Get => MethodFilter::GET,
+            Verb::Post => MethodFilter::POST,
+            Verb::Put => MethodFilter::PUT,
+        }
+    }
+
+    /// Its key in an OpenAPI path item.
+    fn key(self) -> &'static str {
+        match self {
+            Verb::Get => "get",
+            Verb::Post => "post",
+            Verb::Put => "put",
+        }
+    }
+}
+
+/// What handlers need besides `AppState`.
+struct RouteContext {
+    state: Arc<AppState>,
+    metrics: Arc<Metrics>,
+    base_path: String,
+}
+
+/// One method on one path: how `router` serves it and how
+/// `openapi_document` describes it.
+struct Route {
+    verb: Verb,
+    /// In axum's syntax, `:name` for a path parameter.
+    path: &'static str,
+    access: Access,
+    handler: fn(&RouteContext, MethodFilter) -> MethodRouter<Arc<AppState>>,
+    /// The OpenAPI operation, without the security requirement `access`
+    /// implies.
+    operation: fn() -> serde_json::Value,
+}
+
+/// Every route of the API.  The dashboard and the fallback are added by
+/// `router` and are not part of the document.
+const ROUTES: &[Route] = &[
+    Route {
+        verb: Verb::Get,
+        path: "/api/live",
+        access: Access::Public,
+        handler: |_, method| on(method, get_live_stats),
+        operation: || {
+            json_op(
+                "Top 50 active connections by packet count; \
+                 honours If-None-Match and long-polls with wait=true",
+                query_parameters::<LiveParams>(),
+                LiveResponse::schema(),
+            )
+        },
+    },
+    Route {
+        verb: Verb::Get,
+        path: "/api/connections",
+        access: Access::Public,
+        handler: |_, method| on(method, get_connections),
+        operation: || {
+            json_op(
+                "Sorted, filtered, paged live connections",
+                query_parameters::<ConnectionsParams>(),
+                ConnectionPage::schema(),
+            )
+        },
+    },
+    Route {
+        verb: Verb::Get,
+        path: "/api/connections/export",
+        access: Access::Public,
+        handler: |_, method| on(method, get_connections_export),
+        operation: || {
+            let text = || json!({ "schema": { "type": "string" } });
+            operation(
+                "Every live connection as JSON lines or CSV, after a header",
+                query_parameters::<ConnectionExportParams>(),
+                json!({
+                    "200": {
+                        "description": "Connection dump",
+                        "content": { "application/x-ndjson": text(), "text/csv": text() },
+                    },
+                }),
+            )
+        },
+    },
+    Route {
+        verb: Verb::Get,
+        path: "/api/top",
+        access: Access::Public,
+        handler: |_, method| on(method, get_top),
+        operation: || {
+            json_op(
+                "Top talkers by bytes, per IP, subnet or service port",
+                query_parameters::<TopParams>(),
+                Vec::<TopTalker>::schema(),
+            )
+        },
+    },
+    Route {
+        verb: Verb::Get,
+        path: "/api/qos",
+        access: Access::Public,
+        handler: |_, method| on(method, get_qos),
+        operation: || {
+            json_op("Packets and bytes per DSCP class", json!([]), Vec::<QosClass>::schema())
+        },
+    },
+    Route {
+        verb: Verb::Get,
+        path: "/api/cardinality",
+        access: Access::Public,
+        handler: |_, method| on(method, get_cardinality),
+        operation: || {
+            json_op(
+                "Estimated distinct source and destination IPs",
+                json!([]),
+                CardinalityReport::schema(),
+            )
+        },
+    },
+    Route {
+        verb: Verb::Get,
+        path: "/api/icmp",
+        access: Access::Public,
+        handler: |_, method| on(method, get_icmp),
+        operation: || {
+            json_op(
+                "ICMP counts per type and recent senders of path errors",
+                json!([]),
+                IcmpReport::schema(),
+            )
+        },
+    },
+    Route {
+        verb: Verb::Get,
+        path: "/api/asymmetric",
+        access: Access::Public,
+        handler: |_, method| on(method, get_asymmetric),
+        operation: || {
+            json_op(
+                "Flows seen in one direction only, and the busiest of them",
+                query_parameters::<AsymmetryParams>(),
+                AsymmetryReport::schema(),
+            )
+        },
+    },
+    Route {
+        verb: Verb::Get,
+        path: "/api/dns/cache",
+        access: Access::Public,
+        handler: |_, method| on(method, get_dns_cache),
+        operation: || {
+            json_op(
+                "Reverse DNS cache size, hits, misses and TTLs",
+                json!([]),
+                DnsCacheStats::schema(),
+            )
+        },
+    },
+    Route {
+        verb: Verb::Get,
+        path: "/api/categories",
+        access: Access::Public,
+        handler: |_, method| on(method, get_categories),
+        operation: || {
+            json_op(
+                "Packets and bytes per application category",
+                json!([]),
+                Vec::<CategoryTotals>::schema(),
+            )
+        },
+    },
+    Route {
+        verb: Verb::Get,
+        path: "/api/blocklist",
+        access: Access::Public,
+        handler: |_, method| on(method, get_blocklist),
+        operation: || {
+            json_op("Blocklist entries and match counters", json!([]), BlocklistStatus::schema())
+        },
+    },
+    Route {
+        verb: Verb::Get,
+        path: "/api/history",
+        access: Access::Storage,
+        handler: |_, method| on(method, get_history),
+        operation: || {
+            json_op(
+                "Recent packets from SQLite",
+                query_parameters::<HistoryParams>(),
+                Vec::<HistoryRow>::schema(),
+            )
+        },
+    },
+    Route {
+        verb: Verb::Get,
+        path: "/api/alerts",
+        access: Access::Storage,
+        handler: |_, method| on(method, get_alerts),
+        operation: || {
+            json_op(
+                "Alerts, newest first, with repeats folded into one row",
+                query_parameters::<AlertParams>(),
+                Vec::<StoredAlert>::schema(),
+            )
+        },
+    },
+    Route {
+        verb: Verb::Get,
+        path: "/api/usage",
+        access: Access::Storage,
+        handler: |_, method| on(method, get_usage),
+        operation: || {
+            json_op(
+                "Per-local-host traffic by hour or day",
+                query_parameters::<UsageParams>(),
+                Vec::<HostUsageRow>::schema(),
+            )
+        },
+    },
+    Route {
+        verb: Verb::Get,
+        path: "/api/peers",
+        access: Access::Storage,
+        handler: |_, method| on(method, get_peers),
+        operation: || {
+            json_op(
+                "Per-day totals for remote addresses of expired connections",
+                query_parameters::<PeerParams>(),
+                Vec::<PeerTotals>::schema(),
+            )
+        },
+    },
+    Route {
+        verb: Verb::Get,
+        path: "/api/connection",
+        access: Access::Storage,
+        handler: |_, method| on(method, get_connection),
+        operation: || {
+            json_op(
+                "Live and stored data for one connection, either direction",
+                query_parameters::<ConnectionParams>(),
+                ConnectionDetail::schema(),
+            )
+        },
+    },
+    Route {
+        verb: Verb::Get,
+        path: "/api/connection/:id",
+        access: Access::Storage,
+        handler: |_, method| on(method, get_connection_by_id),
+        operation: || {
+            json_op(
+                "The same, looked up by connection_id while it is live",
+                with_path_id(ConnectionId::schema(), query_parameters::<ConnectionIdParams>()),
+                ConnectionDetail::schema(),
+            )
+        },
+    },
+    Route {
+        verb: Verb::Get,
+        path: "/api/report",
+        access: Access::Storage,
+        handler: |_, method| on(method, get_report),
+        operation: || {
+            json_op(
+                "Stored totals, top talkers and alerts over a period",
+                query_parameters::<ReportParams>(),
+                Report::schema(),
+            )
+        },
+    },
+    Route {
+        verb: Verb::Get,
+        path: "/api/health",
+        access: Access::Public,
+        handler: |_, method| on(method, get_health),
+        operation: || {
+            json_op("Health check with basic counters", json!([]), HealthResponse::schema())
+        },
+    },
+    Route {
+        verb: Verb::Get,
+        path: "/api/version",
+        access: Access::Public,
+        handler: |_, method| on(method, get_version),
+        operation: || {
+            json_op(
+                "Build, eBPF object, kernel and attachment details",
+                json!([]),
+                VersionInfo::schema(),
+            )
+        },
+    },
+    Route {
+        verb: Verb::Get,
+        path: "/api/stats",
+        access: Access::Public,
+        handler: |_, method| on(method, get_stats),
+        operation: || {
+            json_op(
+                "Uptime, throughput, connection counts",
+                query_parameters::<InterfaceParams>(),
+                StatsResponse::schema(),
+            )
+        },
+    },
+    Route {
+        verb: Verb::Get,
+        path: "/api/fleet",
+        access: Access::Public,
+        handler: |_, method| on(method, get_fleet),
+        operation: || {
+            json_op(
+                "Sensors that pushed to /api/ingest and their last stats",
+                json!([]),
+                Vec::<FleetMember>::schema(),
+            )
+        },
+    },
+    Route {
+        verb: Verb::Get,
+        path: "/api/stream",
+        access: Access::Public,
+        handler: |_, method| on(method, ws_handler),
+        operation: || {
+            json!({
+                "summary": "WebSocket push of stats every 1s",
+                "responses": { "101": { "description": "Switching to WebSocket" } },
+            })
+        },
+    },
+    Route {
+        verb: Verb::Get,
+        path: "/api/openapi.json",
+        access: Access::Public,
+        handler: |context, method| {
+            let base_path = context.base_path.clone();
+            on(method, move || get_openapi(base_path.clone()))
+        },
+        operation: || {
+            json!({
+                "summary": "This document",
+                "responses": { "200": { "description": "OpenAPI 3 document" } },
+            })
+        },
+    },
+    Route {
+        verb: Verb::Get,
+        path: "/api/docs",
+        access: Access::Public,
+        handler: |_, method| on(method, get_docs),
+        operation: || {
+            json!({
+                "summary": "Interactive explorer for this document",
+                "responses": { "200": content("OK", "text/html", String::schema()) },
+            })
+        },
+    },
+    Route {
+        verb: Verb::Get,
+        path: "/metrics",
+        access: Access::Public,
+        handler: |context, method| {
+            let m = context.metrics.clone();
+            let s = context.state.clone();
+            on(method, move || get_metrics(s.clone(), m.clone()))
+        },
+        operation: || {
+            json!({
+                "summary": "Prometheus text-format metrics",
+                "responses": { "200": content("OK", "text/plain", String::schema()) },
+            })
+        },
+    },
+    Route {
+        verb: Verb::Post,
+        path: "/api/admin/reset",
+        access: Access::Admin,
+        handler: |_, method| on(method, admin_reset),
+        operation: || {
+            json_op(
+                "Zero live counters and drop connections (admin token)",
+                query_parameters::<ResetParams>(),
+                ResetResponse::schema(),
+            )
+        },
+    },
+    Route {
+        verb: Verb::Post,
+        path: "/api/dns/flush",
+        access: Access::Admin,
+        handler: |_, method| on(method, post_dns_flush),
+        operation: || {
+            json_op(
+                "Drop reverse DNS cache entries, or one address's (admin token)",
+                query_parameters::<DnsFlushParams>(),
+                DnsFlushResponse::schema(),
+            )
+        },
+    },
+    Route {
+        verb: Verb::Get,
+        path: "/api/admin/backfill-dns",
+        access: Access::Admin,
+        handler: |_, method| on(method, get_backfill_dns),
+        operation: || {
+            json_op(
+                "Progress of the hostname backfill (admin token)",
+                json!([]),
+                BackfillProgress::schema(),
+            )
+        },
+    },
+    Route {
+        verb: Verb::Post,
+        path: "/api/admin/backfill-dns",
+        access: Access::Admin,
+        handler: |_, method| on(method, post_backfill_dns),
+        operation: || {
+            operation(
+                "Start looking up hostnames stored packets lack (admin token)",
+                query_parameters::<BackfillParams>(),
+                json!({
+                    "202": content("Started", "application/json", BackfillProgress::schema()),
+                    "409": content(
+                        "A backfill is already running",
+                        "application/json",
+                        error_schema(),
+                    ),
+                }),
+            )
+        },
+    },
+    Route {
+        verb: Verb::Get,
+        path: "/api/config",
+        access: Access::Admin,
+        handler: |_, method| on(method, get_config),
+        operation: || {
+            json_op(
+                "Effective configuration and attach status (admin token)",
+                json!([]),
+                ConfigResponse::schema(),
+            )
+        },
+    },
+    Route {
+        verb: Verb::Get,
+        path: "/api/debug/dump",
+        access: Access::Admin,
+        handler: |_, method| on(method, get_debug_dump),
+        operation: || {
+            json_op(
+                "Everything worth attaching to a bug report (admin token)",
+                json!([]),
+                DiagnosticDump::schema(),
+            )
+        },
+    },
+    Route {
+        verb: Verb::Put,
+        path: "/api/blocklist",
+        access: Access::Admin,
+        handler: |_, method| on(method, put_blocklist),
+        operation: || {
+            with_body(
+                json_op(
+                    "Replace the blocklist entries (admin token)",
+                    json!([]),
+                    BlocklistStatus::schema(),
+                ),
+                BlocklistUpdate::schema(),
+            )
+        },
+    },
+    Route {
+        verb: Verb::Post,
+        path: "/api/alerts/:id/ack",
+        access: Access::Admin,
+        handler: |_, method| on(method, ack_alert),
+        operation: || {
+            json_op(
+                "Acknowledge an alert (admin token)",
+                with_path_id(i64::schema(), query_parameters::<AckParams>()),
+                StoredAlert::schema(),
+            )
+        },
+    },
+    Route {
+        verb: Verb::Get,
+        path: "/api/export/snapshot",
+        access: Access::Admin,
+        handler: |_, method| on(method, get_export_snapshot),
+        operation: || {
+            let file = json!({ "type": "string", "format": "binary" });
+            operation(
+                "Download a consistent copy of the database (admin token)",
+                json!([]),
+                json!({
+                    "200": content("SQLite database file", "application/vnd.sqlite3", file),
+                }),
+            )
+        },
+    },
+    Route {
+        verb: Verb::Post,
+        path: "/api/ingest",
+        access: Access::Ingest,
+        handler: |_, method| on(method, post_ingest),
+        operation: || {
+            with_body(
+                json_op(
+                    "Store a sensor's pushed summaries (ingest token)",
+                    json!([]),
+                    IngestResponse::schema(),
+                ),
+                IngestBatch::schema(),
+            )
+        },
+    },
+];
+
+// ── Router ────────────────────────────────────────────────────────────────────
+
+pub fn router(
+    state: Arc<AppState>,
+    allowed_ips: &[String],
+    serve_ui: bool,
+    limits: &ApiConfig,
+) -> Router {
+    let context = RouteContext {
+        metrics: Arc::new(Metrics::new(state.storage.metrics(), &state.traffic.cleanup_metrics)),
+        state: state.clone(),
+        base_path: limits.base_path().to_string(),
+    };
+    let routes = |access: Access| {
+        ROUTES
+            .iter()
+            .filter(|route| route.access == access)
+            .fold(Router::new(), |router, route| {
+                router.route(route.path, (route.handler)(&context, route.verb.filter()))
+            })
+    };
+
+    // Storage-backed routes share a concurrency cap so API readers cannot
+    // monopolise the SQLite mutex and starve the writer.
+    let queries = Arc::new(Semaphore::new(limits.max_concurrent_queries.max(1)));
+    let storage_routes = routes(Access::Storage).layer(middleware::from_fn(move |req, next| {
+        let queries = queries.clone();
+        concurrency_limit(req, next, queries)
+    }));
+    let mut app = routes(Access::Public).merge(storage_routes);
+
+    // Admin routes only exist when a token is configured.  `/api/config`
+    // is among them: even redacted, it maps out the deployment.
+    if let Some(token) = limits.admin_token.clone().filter(|t| !t.is_empty()) {
+        let token: Arc<str> = token.into();
+        let admin_routes = routes(Access::Admin).layer(middleware::from_fn(move |req, next| {
+            let token = token.clone();
+            require_token(req, next, token)
+        }));
+        app = app.merge(admin_routes);
+    }
+    if let Some(token) = limits.ingest_token.clone().filter(|t| !t.is_empty()) {
+        let token: Arc<str> = token.into();
+        let ingest_routes = routes(Access::Ingest)
+            .layer(DefaultBodyLimit::max(crate::fleet::MAX_INGEST_BYTES))
+            .layer(middleware::from_fn(move |req, next| {
+                let token = token.clone();
+                require_token(req, next, token)
+            }));
+        app = app.merge(ingest_routes);
+    }
+
+    // The dashboard is added before the middleware layers below so it is
+    // subject to the same access control as the API.  It fetches relative
+    // URLs, so under a base path it is served at the path with a trailing
+    // slash and the bare path redirects there.
+    let base_path = limits.base_path();
+    if base_path.is_empty() {
+        if serve_ui {
+            app = app.route("/", get(get_dashboard));
+        }
+    } else {
+        app = Router::new().nest(base_path, app);
+        if serve_ui {
+            let slash = format!("{}/", base_path);
+            app = app.route(&slash, get(get_dashboard)).route(
+                base_path,
+                get(move || async move { Redirect::permanent(&slash) }),
+            );
+        }
+    }
+    app = app.fallback(not_found);
+    let trusted_proxies = Arc::new(limits.trusted_proxies());
+
+    if limits.compression {
+        app = compress(app, base_path);
+    }
+
+    if limits.request_timeout_seconds > 0 {
+        let timeout = Duration::from_secs(limits.request_timeout_seconds);
+        app = app.layer(middleware::from_fn(move |req, next| {
+            request_timeout(req, next, timeout)
+        }));
+    }
+
+    if limits.rate_limit_per_second > 0.0 {
+        let limiter = Arc::new(RateLimiter::new(
+            limits.rate_limit_per_second,
+            limits.rate_limit_burst,
+        ));
+        let trusted = trusted_proxies.clone();
+        app = app.layer(middleware::from_fn(move |req, next| {
+            let limiter = limiter.clone();
+            let trusted = trusted.clone();
+            rate_limit(req, next, limiter, trusted)
+        }));
+    }
+
+    // Apply IP allowlist middleware if configured.
+    if !allowed_ips.is_empty() {
+        let nets: Arc<Vec<IpNet>> = Arc::new(
+            allowed_ips
+                .iter()
+                .filter_map(|s| s.parse::<IpNet>().ok())
+                .collect(),
+        );
+        app = app.layer(middleware::from_fn(move |req, next| {
+            let nets = nets.clone();
+            let trusted = trusted_proxies.clone();
+            ip_allowlist(req, next, nets, trusted)
+        }));
+    }
+
+    app.with_state(state)
+}
+
+// ── OpenAPI Document ──────────────────────────────────────────────────────────
+
+/// Build the OpenAPI document for every route in `ROUTES`.
+///
+/// Schemas come from the request and response types themselves (see
+/// `openapi.rs`); the summaries are in `ROUTES`.
+pub fn openapi_document() -> serde_json::Value {
+    let mut paths = serde_json::Map::new();
+    for route in ROUTES {
+        let mut operation = (route.operation)();
+        let scheme = match route.access {
+            Access::Admin => Some("adminToken"),
+            Access::Ingest => Some("ingestToken"),
+            Access::Public | Access::Storage => None,
+        };
+        if let Some(scheme) = scheme {
+            operation["security"] = json!([{ scheme: [] }]);
+        }
+        let item = paths.entry(openapi_path(route.path)).or_insert_with(|| json!({}));
+        item[route.verb.key()] = operation;
+    }
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "ayaFlow",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": paths,
+        "components": {
+            "securitySchemes": {
+                "adminToken": { "type": "http", "scheme": "bearer" },
+                "ingestToken": { "type": "http", "scheme": "bearer" },
+            },
+        },
+    })
+}
+
+/// `path` with axum's `:name` parameters written OpenAPI's way, `{name}`.
+fn openapi_path(path: &str) -> String {
+    path.split('/')
+        .map(|segment| match segment.strip_prefix(':') {
+            Some(name) => format!("{{{}}}", name),
+            None => segment.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// An operation with these `responses`, and the JSON error any route may
+/// answer with instead.
+fn operation(
+    summary: &str,
+    parameters: serde_json::Value,
+    mut responses: serde_json::Value,
+) -> serde_json::Value {
+    responses["default"] = content("Error", "application/json", error_schema());
+    json!({ "summary": summary, "parameters": parameters, "responses": responses })
+}
+
+/// An operation answering with `body` as JSON.
+fn json_op(
+    summary: &str,
+    parameters: serde_json::Value,
+    body: serde_json::Value,
+) -> serde_json::Value {
+    operation(summary, parameters, json!({ "200": content("OK", "application/json", body) }))
+}
+
+/// A response whose body is `media_type` with this schema.
+fn content(description: &str, media_type: &str, schema: serde_json::Value) -> serde_json::Value {
+    json!({ "description": description, "content": { media_type: { "schema": schema } } })
+}
+
+/// `operation` taking `schema` as a required JSON request body.
+fn with_body(mut operation: serde_json::Value, schema: serde_json::Value) -> serde_json::Value {
+    operation["requestBody"] = json!({
+        "required": true,
+        "content": { "application/json": { "schema": schema } },
+    });
+    operation
+}
+
+/// Query `parameters` after the `id` path parameter.
+fn with_path_id(schema: serde_json::Value, mut parameters: serde_json::Value) -> serde_json::Value {
+    if let Some(params) = parameters.as_array_mut() {
+        params.insert(0, json!({ "name": "id", "in": "path", "required": true, "schema": schema }));
+    }
+    parameters
+}
+
+fn error_schema() -> serde_json::Value {
+    crate::openapi::object_schema(&[(
+        "error",
+        crate::openapi::object_schema(&[
+            ("code", String::schema(), true),
+            ("message", String::schema(), true),
+        ]),
+        true,
+    )])
+}
+
+/// The document, with the base path as its server URL when routes are
+/// served under one.
+async fn get_openapi(base_path: String) -> Json<serde_json::Value> {
+    let mut doc = openapi_document();
+    if !base_path.is_empty() {
+        doc["servers"] = serde_json::json!([{ "url": base_path }]);
+    }
+    Json(doc)
+}
+
+// ── IP Allowlist Middleware ────────────────────────────────────────────────────
+
+/// Who sent a request, as far as the allowlist and rate limiter go.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Client {
+    /// No socket address, i.e. the Unix socket.
+    Local,
+    Ip(IpAddr),
+    /// A trusted proxy forwarded an `X-Forwarded-For` entry that is not an
+    /// address; the real client is unknown.
+    Unknown { peer: IpAddr },
+}
+
+/// The socket peer, or, when the peer is one of `trusted` proxies, the
+/// rightmost `X-Forwarded-For` entry that is not a trusted proxy too.  The
+/// header is never read for untrusted peers, who could put anything in it.
+/// If every hop is trusted the leftmost one is the client.
+fn client(req: &axum::extract::Request, trusted: &[IpNet]) -> Client {
+    let Some(ConnectInfo(peer)) = req.extensions().get::<ConnectInfo<SocketAddr>>() else {
+        return Client::Local;
+    };
+    let peer = peer.ip();
+    let is_trusted = |ip: &IpAddr| trusted.iter().any(|net| net.contains(ip));
+    if !is_trusted(&peer) {
+        return Client::Ip(peer);
+    }
+    let mut client = peer;
+    let hops = req.headers().get_all("x-forwarded-for").iter().rev();
+    for value in hops {
+        let Ok(value) = value.to_str() else {
+            return Client::Unknown { peer };
+        };
+        for hop in value.rsplit(',') {
+            let Ok(ip) = hop.trim().parse::<IpAddr>() else {
+                return Client::Unknown { peer };
+            };
+            if !is_trusted(&ip) {
+                return Client::Ip(ip);
+            }
+            client = ip;
+        }
+    }
+    Client::Ip(client)
+}
+
+async fn ip_allowlist(
+    req: axum::extract::Request,
+    next: middleware::Next,
+    allowed: Arc<Vec<IpNet>>,
+    trusted: Arc<Vec<IpNet>>,
+) -> impl IntoResponse {
+    match client(&req, &trusted) {
+        // Unix socket clients are vetted by the socket's file mode.
+        Client::Local => next.run(req).await.into_response(),
+        Client::Ip(ip) if allowed.iter().any(|net| net.contains(&ip)) => {
+            next.run(req).await.into_response()
+        }
+        _ => ApiError::Forbidden.into_response(),
+    }
+}
+
+// ── Token Middleware ──────────────────────────────────────────────────────────
+
+async fn require_token(
+    req: axum::extract::Request,
+    next: middleware::Next,
+    token: Arc<str>,
+) -> impl IntoResponse {
+    let presented = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    match presented {
+        Some(presented) if constant_time_eq(presented.as_bytes(), token.as_bytes()) => {
+            next.run(req).await.into_response()
+        }
+        _ => ApiError::Unauthorized.into_response(),
+    }
+}
+
+/// Compare without an early exit, so response timing does not reveal how
+/// much of a guessed token was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// ── Rate Limiting & Request Limits ────────────────────────────────────────────
+
+/// Per-client-IP token bucket.
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: DashMap<IpAddr, TokenBucket>,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Buckets are pruned once the map grows past this many clients.
+const RATE_LIMIT_MAX_CLIENTS: usize = 10_000;
+
+impl RateLimiter {
+    pub fn new(rate: f64, burst: u32) -> Self {
+        Self {
+            rate,
+            burst: f64::from(burst.max(1)),
+            buckets: DashMap::new(),
+        }
+    }
+
+    /// Take one token for `ip`.  On rejection, returns how long until a
+    /// token becomes available.
+    pub fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        let now = Instant::now();
+        if self.buckets.len() > RATE_LIMIT_MAX_CLIENTS {
+            // A bucket idle long enough to refill completely is equivalent
+            // to a fresh one, so dropping it loses nothing.
+            let full_after = Duration::from_secs_f64(self.burst / self.rate);
+            self.buckets
+                .retain(|_, bucket| now.duration_since(bucket.last_refill) < full_after);
+        }
+
+        let mut bucket = self.buckets.entry(ip).or_insert(TokenBucket {
+            tokens: self.burst,
+            last_refill: now,
+        });
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+}
+
+async fn rate_limit(
+    req: axum::extract::Request,
+    next: middleware::Next,
+    limiter: Arc<RateLimiter>,
+    trusted: Arc<Vec<IpNet>>,
+) -> axum::response::Response {
+    let ip = match client(&req, &trusted) {
+        Client::Local => return next.run(req).await,
+        Client::Ip(ip) | Client::Unknown { peer: ip } => ip,
+    };
+    match limiter.check(ip) {
+        Ok(()) => next.run(req).await,
+        Err(wait) => {
+            let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+            ApiError::RateLimited(retry_after).into_response()
+        }
+    }
+}
+
+/// Paths never compressed: the WebSocket upgrade and the compact metrics text.
+const UNCOMPRESSED_PATHS: &[&str] = &["/api/stream", "/metrics"];
+/// Bodies known to be smaller are sent uncompressed.
+const MIN_COMPRESS_LEN: u16 = 1024;
+
+/// Response extension telling the compression layer to leave a body alone.
+#[derive(Clone, Copy)]
+struct Uncompressed;
+
+/// Whether `path` is one of `UNCOMPRESSED_PATHS` under `base_path`.  The
+/// layer wraps the nested router, so it sees the full path.
+fn uncompressed_path(path: &str, base_path: &str) -> bool {
+    path.strip_prefix(base_path)
+        .is_some_and(|path| UNCOMPRESSED_PATHS.contains(&path))
+}
+
+/// The compression predicate only sees the response, so responses to
+/// `UNCOMPRESSED_PATHS` are marked on the way out.
+async fn mark_uncompressed(
+    req: axum::extract::Request,
+    next: middleware::Next,
+    base_path: Arc<str>,
+) -> axum::response::Response {
+    let skipped = uncompressed_path(req.uri().path(), &base_path);
+    let mut response = next.run(req).await;
+    if skipped {
+        response.extensions_mut().insert(Uncompressed);
+    }
+    response
+}
+
+fn not_marked_uncompressed(
+    _: StatusCode,
+    _: Version,
+    _: &HeaderMap,
+    extensions: &Extensions,
+) -> bool {
+    extensions.get::<Uncompressed>().is_none()
+}
+
+/// Gzip or Brotli responses for clients that accept them, except small
+/// bodies and `UNCOMPRESSED_PATHS`.
+fn compress<S: Clone + Send + Sync + 'static>(app: Router<S>, base_path: &str) -> Router<S> {
+    let base_path: Arc<str> = Arc::from(base_path);
+    let predicate = SizeAbove::new(MIN_COMPRESS_LEN).and(not_marked_uncompressed);
+    app.layer(middleware::from_fn(move |req, next| {
+        mark_uncompressed(req, next, base_path.clone())
+    }))
+    .layer(CompressionLayer::new().compress_when(predicate))
+    .layer(middleware::map_response(weaken_encoded_etag))
+}
+
+/// A strong ETag is weakened on an encoded variant, whose bytes differ;
+/// `If-None-Match` compares weakly, so it still revalidates.
+async fn weaken_encoded_etag(mut response: Response) -> Response {
+    if !response.headers().contains_key(header::CONTENT_ENCODING) {
+        return response;
+    }
+    if let Some(tag) = response.headers().get(header::ETAG) {
+        if !tag.as_bytes().starts_with(b"W/") {
+            let mut weak = b"W/".to_vec();
+            weak.extend_from_slice(tag.as_bytes());
+            if let Ok(weak) = HeaderValue::from_bytes(&weak) {
+                response.headers_mut().insert(header::ETAG, weak);
+            }
+        }
+    }
+    response
+}
+
+async fn concurrency_limit(
+    req: axum::extract::Request,
+    next: middleware::Next,
+    permits: Arc<Semaphore>,
+) -> axum::response::Response {
+    // Queue for a permit; the request timeout bounds how long we wait.
+    let Ok(_permit) = permits.acquire().await else {
+        return ApiError::Internal("query limiter closed".to_string()).into_response();
+    };
+    next.run(req).await
+}
+
+async fn request_timeout(
+    req: axum::extract::Request,
+    next: middleware::Next,
+    timeout: Duration,
+) -> axum::response::Response {
+    let timeout = timeout + LiveParams::wait_requested(req.uri()).unwrap_or_default();
+    match tokio::time::timeout(timeout, next.run(req)).await {
+        Ok(response) => response,
+        Err(_) => ApiError::Timeout.into_response(),
+    }
+}
+
+// ── Handlers ──────────────────────────────────────────────────────────────────
+
+/// Single-page dashboard, embedded at compile time so it works air-gapped.
+const DASHBOARD_HTML: &str = include_str!("../static/index.html");
+
+async fn get_dashboard() -> Html<&'static str> {
+    Html(DASHBOARD_HTML)
+}
+
+/// API explorer over `/api/openapi.json`, embedded for the same reason.
+const DOCS_HTML: &str = include_str!("../static/docs.html");
+
+async fn get_docs() -> Html<&'static str> {
+    Html(DOCS_HTML)
+}
+
+/// 503 when a critical component is down, so load balancers and service
+/// managers stop treating the agent as healthy.
+async fn get_health(State(state): State<Arc<AppState>>) -> (StatusCode, Json<HealthResponse>) {
+    let (status, components) = state.health.report();
+    let code = if status == ComponentStatus::Down {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::OK
+    };
+    let body = HealthResponse {
+        status,
+        capture: state.capture,
+        active_connections: state.traffic.active_connections.load(Ordering::Relaxed),
+        total_packets: state.traffic.total_packets.load(Ordering::Relaxed),
+        components,
+        secondary_storage: state.secondary.as_ref().map(|s| s.status(state.storage.as_ref())),
+        connection_snapshot: state.connection_snapshots.last(),
+    };
+    (code, Json(body))
+}
+
+async fn get_stats(
+    State(state): State<Arc<AppState>>,
+    ValidQuery(params): ValidQuery<InterfaceParams>,
+) -> Result<Json<StatsResponse>, ApiError> {
+    let uptime = state.start_time.elapsed().as_secs();
+    let totals = state.traffic.totals(params.interface.as_deref());
+    let tcp_states = state.traffic.tcp_state_counts(params.interface.as_deref());
+    let active_connections = match params.interface {
+        None => state.traffic.active_connections.load(Ordering::Relaxed),
+        Some(interface) => {
+            let filter = ConnectionFilter {
+                interface: Some(interface),
+                ..Default::default()
+            };
+            state.traffic.count_connections(&filter)
+        }
+    };
+
+    let packets_per_second = if uptime > 0 {
+        totals.packets as f64 / uptime as f64
+    } else {
+        0.0
+    };
+    let bytes_per_second = if uptime > 0 {
+        totals.bytes as f64 / uptime as f64
+    } else {
+        0.0
+    };
+
+    let churn = state.traffic.churn();
+    Ok(Json(StatsResponse {
+        uptime_seconds: uptime,
+        total_packets: totals.packets,
+        total_bytes: totals.bytes,
+        active_connections,
+        packets_per_second,
+        bytes_per_second,
+        pps_1s: totals.last_second.pps,
+        pps_60s: totals.last_minute.pps,
+        bps_1s: totals.last_second.bps,
+        bps_60s: totals.last_minute.bps,
+        tcp_states,
+        flow_directions: state.traffic.flow_direction_totals(),
+        casts: state.traffic.cast_totals(),
+        connections_created_total: churn.created,
+        connections_expired_total: churn.expired,
+        new_connections_1s: churn.created_1s,
+        new_connections_60s: churn.created_60s,
+        expired_connections_60s: churn.expired_60s,
+        bpf_runtime: state.traffic.bpf_runtime.summary(),
+        headroom: state.traffic.headroom.report(),
+    }))
+}
+
+/// `/api/live` with the live table's generation as its ETag.  A request
+/// naming the current one gets 304, after waiting for a change first when
+/// it asks to.
+async fn get_live_stats(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    ValidQuery(params): ValidQuery<LiveParams>,
+) -> Result<Response, ApiError> {
+    let mut generation = state.traffic.generation();
+    let cached = headers.get(header::IF_NONE_MATCH);
+    if cached.is_some_and(|tags| etag_matches(tags, generation)) {
+        if let Some(wait) = params.wait() {
+            generation = state.traffic.wait_for_change(generation, wait).await;
+        }
+        if cached.is_some_and(|tags| etag_matches(tags, generation)) {
+            return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, etag(generation))])
+                .into_response());
+        }
+    }
+
+    let totals = state.traffic.totals(params.interface.as_deref());
+    let filter = ConnectionFilter {
+        interface: params.interface,
+        ..Default::default()
+    };
+    let mut page = state.traffic.query_connections(
+        &filter,
+        ConnectionSort::Packets,
+        SortOrder::Desc,
+        0,
+        50,
+    );
+    state.label_connections(&mut page.connections);
+
+    let live = LiveResponse {
+        connections: page.connections,
+        total_packets: totals.packets,
+        total_bytes: totals.bytes,
+        total_payload_bytes: totals.payload_bytes,
+    };
+    Ok(([(header::ETAG, etag(generation))], Json(live)).into_response())
+}
+
+/// The generation behind this run's nonce.  Generations count from 0 at
+/// every start, so without it a tag kept across a restart could match a
+/// different table.
+fn etag(generation: u64) -> String {
+    format!("\"{:x}-{}\"", boot_nonce(), generation)
+}
+
+/// Fixed for the life of the process: the start time in nanoseconds, mixed
+/// with the pid.
+fn boot_nonce() -> u64 {
+    static NONCE: OnceLock<u64> = OnceLock::new();
+    *NONCE.get_or_init(|| {
+        let since_epoch = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH);
+        since_epoch.unwrap_or_default().as_nanos() as u64 ^ u64::from(std::process::id()) << 32
+    })
+}
+
+/// Whether an `If-None-Match` list names this generation's ETag.  Weak
+/// validators compare equal to strong ones, as RFC 9110 asks for GET.
+fn etag_matches(tags: &HeaderValue, generation: u64) -> bool {
+    let current = etag(generation);
+    let Ok(tags) = tags.to_str() else {
+        return false;
+    };
+    tags.split(',').map(str::trim).any(|tag| {
+        tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == current
+    })
+}
+
+async fn get_connections(
+    State(state): State<Arc<AppState>>,
+    ValidQuery(params): ValidQuery<ConnectionsParams>,
+) -> Result<Json<ConnectionPage>, ApiError> {
+    let filter = ConnectionFilter {
+        ip: params.ip,
+        port: params.port,
+        protocol: params.protocol,
+        interface: params.interface,
+        direction: params.direction,
+        cast: params.cast.and_then(CastSelection::class),
+        connection_id: params.connection_id,
+    };
+    let limit = params.limit.unwrap_or(50);
+    let mut page = state.traffic.query_connections(
+        &filter,
+        params.sort,
+        params.order,
+        params.offset,
+        limit,
+    );
+    state.label_connections(&mut page.connections);
+    Ok(Json(page))
+}
+
+/// Every live connection, streamed one shard of the table at a time.
+async fn get_connections_export(
+    State(state): State<Arc<AppState>>,
+    ValidQuery(params): ValidQuery<ConnectionExportParams>,
+) -> Response {
+    let dump = ConnectionDump::new(state, params.format);
+    let headers = [
+        (header::CONTENT_TYPE, params.format.content_type().to_string()),
+        (
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}\"", dump.file_name()),
+        ),
+    ];
+    let chunks = futures_util::stream::iter(
+        dump.map(|chunk| Ok::<_, std::convert::Infallible>(axum::body::Bytes::from(chunk))),
+    );
+    (headers, axum::body::Body::from_stream(chunks)).into_response()
+}
+
+async fn get_top(
+    State(state): State<Arc<AppState>>,
+    ValidQuery(params): ValidQuery<TopParams>,
+) -> Result<Json<Vec<TopTalker>>, ApiError> {
+    let (prefix, prefix6) = params.prefixes();
+    let prefixes = SubnetPrefixes::new(prefix, prefix6).expect("prefixes are validated");
+    let limit = params.limit.unwrap_or(10);
+    let cast = params.cast.unwrap_or(CastSelection::Unicast).class();
+    let Some(instance) = params.instance else {
+        return Ok(Json(state.traffic.top_talkers(params.by, prefixes, cast, limit)));
+    };
+    let column = match params.by {
+        TopBy::SrcIp => TopColumn::SrcIp,
+        TopBy::DstIp => TopColumn::DstIp,
+        TopBy::Country => TopColumn::Country,
+        _ => TopColumn::DstPort,
+    };
+    let from = params.from.unwrap_or_else(|| chrono::Utc::now().timestamp_millis() - 3_600_000);
+    let filter = PacketFilter {
+        instance: Some(instance),
+        from: Some(from),
+        to: params.to,
+        ..Default::default()
+    };
+    let Json(stored) =
+        run_query(&state, move |storage| storage.query_top(column, &filter, limit)).await?;
+    Ok(Json(stored.into_iter().filter_map(|talker| stored_top(column, talker)).collect()))
+}
+
+/// A stored group as a top talker.  Stored rows are windows rather than
+/// connections, so `connections` and `hosts` are left at 0.
+fn stored_top(column: TopColumn, talker: StoredTalker) -> Option<TopTalker> {
+    let (subnet, port, country) = match column {
+        TopColumn::DstPort => (None, Some(talker.key.parse().ok()?), None),
+        TopColumn::Country => (None, None, Some(talker.key)),
+        _ => (Some(IpNet::from(talker.key.parse::<IpAddr>().ok()?)), None, None),
+    };
+    Some(TopTalker {
+        subnet,
+        port,
+        country,
+        bytes: talker.bytes,
+        packets: talker.packets,
+        connections: 0,
+        hosts: 0,
+    })
+}
+
+async fn get_version(State(state): State<Arc<AppState>>) -> Json<VersionInfo> {
+    Json(state.version.as_ref().clone())
+}
+
+async fn get_qos(State(state): State<Arc<AppState>>) -> Json<Vec<QosClass>> {
+    Json(state.traffic.qos_breakdown())
+}
+
+async fn get_categories(State(state): State<Arc<AppState>>) -> Json<Vec<CategoryTotals>> {
+    Json(crate::categories::totals(&state.traffic))
+}
+
+async fn get_cardinality(State(state): State<Arc<AppState>>) -> Json<CardinalityReport> {
+    Json(state.traffic.cardinality.report())
+}
+
+async fn get_icmp(State(state): State<Arc<AppState>>) -> Json<IcmpReport> {
+    Json(state.traffic.icmp.report())
+}
+
+fn dns_cache(state: &AppState) -> Result<&DnsCache, ApiError> {
+    state
+        .diagnostics
+        .dns()
+        .ok_or_else(|| ApiError::Conflict("reverse DNS is off; set resolve_dns".into()))
+}
+
+async fn get_dns_cache(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<DnsCacheStats>, ApiError> {
+    Ok(Json(dns_cache(&state)?.stats()))
+}
+
+/// Flushed addresses are looked up again when next seen.  Stored rows show
+/// names from the `hostnames` table, so a new name replaces the old one on
+/// every row with the address.
+async fn post_dns_flush(
+    State(state): State<Arc<AppState>>,
+    ValidQuery(params): ValidQuery<DnsFlushParams>,
+) -> Result<Json<DnsFlushResponse>, ApiError> {
+    let cache = dns_cache(&state)?;
+    let entries_removed = match params.ip {
+        Some(ip) => usize::from(cache.remove(&ip)),
+        None => cache.flush(),
+    };
+    match params.ip {
+        Some(ip) => tracing::info!("Reverse DNS cache entry for {} flushed", ip),
+        None => tracing::info!("Reverse DNS cache flushed, {} entries dropped", entries_removed),
+    }
+    Ok(Json(DnsFlushResponse { entries_removed }))
+}
+
+async fn get_asymmetric(
+    State(state): State<Arc<AppState>>,
+    ValidQuery(params): ValidQuery<AsymmetryParams>,
+) -> Json<AsymmetryReport> {
+    let traffic = &state.traffic;
+    let mut report = traffic.asymmetry.report(traffic, params.limit.unwrap_or(20));
+    state.label_connections(&mut report.samples);
+    Json(report)
+}
+
+async fn get_history(
+    State(state): State<Arc<AppState>>,
+    ValidQuery(params): ValidQuery<HistoryParams>,
+) -> Result<Json<Vec<HistoryRow>>, ApiError> {
+    let limit = params.limit.unwrap_or(100);
+    let categories = state.traffic.categories();
+    let category = match params.category {
+        Some(name) => match categories.find(&name) {
+            Some(id) => Some(categories.port_match(id)),
+            None => {
+                let shown: String = name.chars().take(32).collect();
+                return Err(ApiError::BadRequest(format!(
+                    "category must be one of {}, got {:?}",
+                    categories.names().join(", "),
+                    shown
+                )));
+            }
+        },
+        None => None,
+    };
+    let filter = PacketFilter {
+        from: params.from,
+        to: params.to,
+        ip: params.ip.map(|ip| ip.to_string()),
+        interface: params.interface,
+        mac: params.mac.map(|mac| mac.to_string()),
+        direction: params.direction,
+        category,
+        instance: params.instance,
+        protocol: params.protocol,
+        icmp_type: params.icmp_type,
+        country: params.country,
+        asn: params.asn,
+    };
+    let Json(mut rows) = run_query(&state, move |storage| {
+        if filter == PacketFilter::default() {
+            storage.query_history(limit)
+        } else {
+            storage.query_packets(&filter, limit)
+        }
+    })
+    .await?;
+    state.label_rows(&mut rows);
+    Ok(Json(rows))
+}
+
+async fn get_alerts(
+    State(state): State<Arc<AppState>>,
+    ValidQuery(params): ValidQuery<AlertParams>,
+) -> Result<Json<Vec<StoredAlert>>, ApiError> {
+    let limit = params.limit.unwrap_or(100);
+    let filter = AlertFilter {
+        before_id: params.before_id,
+        severity: params.severity,
+        rule: params.rule,
+        since: params.since,
+        acked: params.acked,
+        instance: params.instance,
+    };
+    run_query(&state, move |storage| storage.query_alerts(&filter, limit)).await
+}
+
+async fn ack_alert(
+    State(state): State<Arc<AppState>>,
+    id: Result<Path<i64>, PathRejection>,
+    ValidQuery(params): ValidQuery<AckParams>,
+) -> Result<Json<StoredAlert>, ApiError> {
+    let Path(id) = id.map_err(|e| ApiError::BadRequest(e.body_text()))?;
+    let by = params.by.unwrap_or_else(|| "admin".to_string());
+    let Json(alert) = run_query(&state, move |storage| storage.ack_alert(id, &by)).await?;
+    let alert = alert.ok_or_else(|| ApiError::NotFound(format!("no alert with id {}", id)))?;
+    let by = alert.acked_by.as_deref().unwrap_or_default();
+    tracing::info!("Alert {} ({}) acknowledged by {}", id, alert.rule, by);
+    Ok(Json(alert))
+}
+
+async fn get_usage(
+    State(state): State<Arc<AppState>>,
+    ValidQuery(params): ValidQuery<UsageParams>,
+) -> Result<Json<Vec<HostUsageRow>>, ApiError> {
+    let from = params.from.unwrap_or(0);
+    let to = params.to.unwrap_or(i64::MAX);
+    let ip = params.ip.map(|ip| ip.to_string());
+    run_query(&state, move |storage| {
+        storage.query_usage(ip.as_deref(), from, to, params.granularity)
+    })
+    .await
+}
+
+async fn get_peers(
+    State(state): State<Arc<AppState>>,
+    ValidQuery(params): ValidQuery<PeerParams>,
+) -> Result<Json<Vec<PeerTotals>>, ApiError> {
+    let from = params.from.unwrap_or(0);
+    let to = params.to.unwrap_or(i64::MAX);
+    let ip = params.ip.map(|ip| ip.to_string());
+    run_query(&state, move |storage| storage.query_peers(ip.as_deref(), from, to)).await
+}
+
+async fn get_report(
+    State(state): State<Arc<AppState>>,
+    ValidQuery(params): ValidQuery<ReportParams>,
+) -> Result<Response, ApiError> {
+    let period = params.period.as_deref().map(crate::reports::parse_period);
+    let period = period.and_then(Result::ok).unwrap_or(crate::reports::DEFAULT_PERIOD);
+    let top = params.limit.unwrap_or(10);
+    let now = chrono::Utc::now().timestamp_millis();
+    let traffic = state.traffic.clone();
+    let instance = params.instance;
+    let Json(report) = run_query(&state, move |storage| {
+        crate::reports::build(storage, &traffic, now, period, top, instance)
+    })
+    .await?;
+    let (content_type, body) = params.format.render(&report);
+    Ok(([(header::CONTENT_TYPE, content_type)], body).into_response())
+}
+
+async fn get_connection(
+    State(state): State<Arc<AppState>>,
+    ValidQuery(params): ValidQuery<ConnectionParams>,
+) -> Result<Json<ConnectionDetail>, ApiError> {
+    let key = ConnectionKey {
+        src_ip: params.src_ip,
+        src_port: params.src_port,
+        dst_ip: params.dst_ip,
+        dst_port: params.dst_port,
+    };
+    connection_detail(&state, vec![key], params.limit.unwrap_or(100)).await
+}
+
+/// `/api/connection` by `connection_id`.  Only live connections can be
+/// found this way.  On a hash collision each connection with the id is
+/// looked up by its own 4-tuple and the detail covers them all.
+async fn get_connection_by_id(
+    State(state): State<Arc<AppState>>,
+    id: Result<Path<ConnectionId>, PathRejection>,
+    ValidQuery(params): ValidQuery<ConnectionIdParams>,
+) -> Result<Json<ConnectionDetail>, ApiError> {
+    let Path(id) = id.map_err(|e| ApiError::BadRequest(e.body_text()))?;
+    let keys = state.traffic.connections_with_id(id);
+    if keys.is_empty() {
+        return Err(ApiError::NotFound(format!("no live connection with id {}", id)));
+    }
+    connection_detail(&state, keys, params.limit.unwrap_or(100)).await
+}
+
+/// The detail of `keys[0]`, or of every key for colliding ids, with the
+/// newest `limit` rows across them.
+async fn connection_detail(
+    state: &AppState,
+    keys: Vec<ConnectionKey>,
+    limit: usize,
+) -> Result<Json<ConnectionDetail>, ApiError> {
+    let queried = keys.clone();
+    let Json((mut history, stored)) = run_query(state, move |storage| {
+        let mut history = Vec::new();
+        let mut stored = StoredConnectionTotals::default();
+        for key in &queried {
+            let (rows, totals) = storage.query_connection(key, limit)?;
+            history.extend(rows);
+            stored.add(&totals);
+        }
+        history.sort_by_key(|row| std::cmp::Reverse(row.packet.timestamp));
+        history.truncate(limit);
+        Ok((history, stored))
+    })
+    .await?;
+    // Read after the query, so the live figures are never the older ones.
+    let mut live: Vec<ConnectionEntry> =
+        keys.iter().flat_map(|key| state.traffic.connection_pair(key)).collect();
+    state.label_connections(&mut live);
+    state.label_rows(&mut history);
+    Ok(Json(ConnectionDetail { live, history, stored }))
+}
+
+async fn admin_reset(
+    State(state): State<Arc<AppState>>,
+    ValidQuery(params): ValidQuery<ResetParams>,
+) -> Result<Json<ResetResponse>, ApiError> {
+    let db_rows_deleted = if params.include_db {
+        let Json(rows) = run_query(&state, |s| s.clear_packets()).await?;
+        Some(rows)
+    } else {
+        None
+    };
+    let ResetCounts {
+        packets,
+        bytes,
+        connections,
+    } = state.traffic.reset();
+    tracing::warn!(
+        "Admin reset: cleared {} packets, {} bytes, {} connections{}",
+        packets,
+        bytes,
+        connections,
+        db_rows_deleted.map_or(String::new(), |n| format!(", {} stored rows", n)),
+    );
+    Ok(Json(ResetResponse {
+        packets_cleared: packets,
+        bytes_cleared: bytes,
+        connections_cleared: connections,
+        db_rows_deleted,
+    }))
+}
+
+async fn get_backfill_dns(State(state): State<Arc<AppState>>) -> Json<BackfillProgress> {
+    Json(state.backfill.progress())
+}
+
+async fn post_backfill_dns(
+    State(state): State<Arc<AppState>>,
+    ValidQuery(params): ValidQuery<BackfillParams>,
+) -> Result<(StatusCode, Json<BackfillProgress>), ApiError> {
+    let started = state.backfill.start(state.storage.clone(), params.options());
+    let progress = started
+        .ok_or_else(|| ApiError::Conflict("a hostname backfill is already running".into()))?;
+    tracing::warn!("Admin started a hostname backfill");
+    Ok((StatusCode::ACCEPTED, Json(progress)))
+}
+
+async fn get_blocklist(State(state): State<Arc<AppState>>) -> Json<BlocklistStatus> {
+    Json(state.blocklist.status(&state.traffic))
+}
+
+async fn get_fleet(State(state): State<Arc<AppState>>) -> Json<Vec<FleetMember>> {
+    Json(state.fleet.list())
+}
+
+/// Store a sensor's pushed buckets as its own, through this agent's storage
+/// backend.  The sensor keeps the batch until this answers 2xx.
+async fn post_ingest(
+    State(state): State<Arc<AppState>>,
+    body: Result<Json<IngestBatch>, JsonRejection>,
+) -> Result<Json<IngestResponse>, ApiError> {
+    let Json(batch) = body.map_err(|e| ApiError::BadRequest(e.body_text()))?;
+    batch.validate().map_err(ApiError::BadRequest)?;
+    let key = batch.key();
+    if !state.fleet.claim(&key) {
+        return Ok(Json(IngestResponse { rows: 0, duplicate: true }));
+    }
+    let secondary = state.secondary.clone();
+    let stored = run_query(&state, move |storage| {
+        if !batch.buckets.is_empty() {
+            storage.insert_ingested(&batch.instance, &batch.buckets)?;
+            if let Some(secondary) = secondary {
+                secondary.insert_ingested(&batch.instance, &batch.buckets);
+            }
+        }
+        Ok(batch)
+    })
+    .await;
+    let Json(batch) = stored.inspect_err(|_| state.fleet.release(&key))?;
+    state.fleet.record(&batch, chrono::Utc::now().timestamp_millis());
+    Ok(Json(IngestResponse { rows: batch.buckets.len(), duplicate: false }))
+}
+
+async fn put_blocklist(
+    State(state): State<Arc<AppState>>,
+    body: Result<Json<BlocklistUpdate>, JsonRejection>,
+) -> Result<Json<BlocklistStatus>, ApiError> {
+    let Json(update) = body.map_err(|e| ApiError::BadRequest(e.body_text()))?;
+    let nets = crate::blocklist::parse_entries(&update.entries).map_err(ApiError::BadRequest)?;
+    let count = nets.len();
+    state
+        .blocklist
+        .replace(nets)
+        .map_err(|e| ApiError::Internal(e.to_string()))?;
+    tracing::warn!("Admin replaced the blocklist with {} entries", count);
+    Ok(Json(state.blocklist.status(&state.traffic)))
+}
+
+async fn get_config(State(state): State<Arc<AppState>>) -> Json<ConfigResponse> {
+    Json(state.config.as_ref().clone())
+}
+
+async fn get_debug_dump(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<DiagnosticDump>, ApiError> {
+    tokio::task::spawn_blocking(move || crate::diagnostics::dump(&state))
+        .await
+        .map(Json)
+        .map_err(|e| ApiError::Internal(e.to_string()))
+}
+
+/// The whole database as a SQLite file, streamed from a snapshot that is
+/// deleted once sent.
+async fn get_export_snapshot(State(state): State<Arc<AppState>>) -> Result<Response, ApiError> {
+    use tokio::io::AsyncReadExt;
+
+    let storage = state.storage.clone();
+    let snapshot = tokio::task::spawn_blocking(move || storage.snapshot())
+        .await
+        .map_err(|e| ApiError::Internal(e.to_string()))??;
+    let file = tokio::fs::File::from_std(snapshot.file);
+    let chunks = futures_util::stream::unfold(file, |mut file| async move {
+        let mut chunk = vec![0; 64 * 1024];
+        match file.read(&mut chunk).await {
+            Ok(0) => None,
+            Ok(n) => {
+                chunk.truncate(n);
+                Some((Ok(axum::body::Bytes::from(chunk)), file))
+            }
+            Err(e) => Some((Err(e), file)),
+        }
+    });
+    let name = format!("ayaflow-{}.db", chrono::Utc::now().format("%Y%m%dT%H%M%SZ"));
+    let headers = [
+        (header::CONTENT_TYPE, "application/vnd.sqlite3".to_string()),
+        (header::CONTENT_LENGTH, snapshot.bytes.to_string()),
+        (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", name)),
+    ];
+    Ok((headers, axum::body::Body::from_stream(chunks)).into_response())
+}
+
+async fn not_found(uri: axum::http::Uri) -> ApiError {
+    ApiError::NotFound(format!("no route for {}", uri.path()))
+}
+
+/// Run a storage query on the blocking pool, so a slow query holds a
+/// blocking thread rather than a runtime worker and the request timeout can
+/// still fire.
+async fn run_query<T, F>(state: &AppState, query: F) -> Result<Json<T>, ApiError>
+where
+    T: Send + 'static,
+    F: FnOnce(&dyn StorageBackend) -> StorageResult<T> + Send + 'static,
+{
+    let storage = state.storage.clone();
+    tokio::task::spawn_blocking(move || query(storage.as_ref()))
+        .await
+        .map_err(|e| ApiError::Internal(e.to_string()))?
+        .map(Json)
+        .map_err(ApiError::from)
+}
+
+async fn get_metrics(state: Arc<AppState>, metrics: Arc<Metrics>) -> impl IntoResponse {
+    // Sync counters from atomic state into prometheus gauges/counters.
+    // prometheus-client Counters are monotonic so we increment by the delta.
+    let traffic = &state.traffic;
+    let mut resets_seen = metrics.resets_seen.lock().unwrap();
+    let resets = traffic.resets.load(Ordering::Relaxed);
+    if *resets_seen != resets {
+        // Kernel flow-map overflows survive a reset and keep their baseline.
+        metrics.packets_total.rebase();
+        metrics.bytes_total.rebase();
+        metrics.payload_bytes_total.rebase();
+        metrics.category_packets_total.rebase();
+        metrics.category_bytes_total.rebase();
+        metrics.deep_inspect_packets_total.rebase();
+        metrics.domains_resolved_total.rebase();
+        metrics.tcp_retransmits_total.rebase();
+        metrics.forwarded_duplicates_total.rebase();
+        metrics.connections_created_total.rebase();
+        metrics.connections_expired_total.rebase();
+        *resets_seen = resets;
+    }
+    let mut known_packets = 0;
+    let mut known_bytes = 0;
+    let mut known_payload = 0;
+    for entry in traffic.interfaces.iter() {
+        let labels = InterfaceLabels {
+            interface: entry.key().clone(),
+        };
+        let packets = entry.packets.load(Ordering::Relaxed);
+        let bytes = entry.bytes.load(Ordering::Relaxed);
+        let payload = entry.payload_bytes.load(Ordering::Relaxed);
+        metrics.packets_total.sync(&labels, packets);
+        metrics.bytes_total.sync(&labels, bytes);
+        metrics.payload_bytes_total.sync(&labels, payload);
+        known_packets += packets;
+        known_bytes += bytes;
+        known_payload += payload;
+    }
+    let unknown = InterfaceLabels {
+        interface: String::new(),
+    };
+    let total_pkts = traffic.total_packets.load(Ordering::Relaxed);
+    let total_b = traffic.total_bytes.load(Ordering::Relaxed);
+    let total_payload = traffic.total_payload_bytes.load(Ordering::Relaxed);
+    metrics
+        .packets_total
+        .sync(&unknown, total_pkts.saturating_sub(known_packets));
+    metrics
+        .bytes_total
+        .sync(&unknown, total_b.saturating_sub(known_bytes));
+    metrics
+        .payload_bytes_total
+        .sync(&unknown, total_payload.saturating_sub(known_payload));
+
+    let categories = traffic.categories().names().iter();
+    for (name, (packets, bytes)) in categories.zip(traffic.category_totals()) {
+        let labels = CategoryLabels {
+            category: name.clone(),
+        };
+        metrics.category_packets_total.sync(&labels, packets);
+        metrics.category_bytes_total.sync(&labels, bytes);
+    }
+
+    let active = traffic.active_connections.load(Ordering::Relaxed);
+    metrics.active_connections.set(active as i64);
+
+    // L7 deep inspection counters.
+    metrics
+        .deep_inspect_packets_total
+        .sync(traffic.deep_inspect_packets.load(Ordering::Relaxed));
+    metrics
+        .domains_resolved_total
+        .sync(traffic.domains_resolved.load(Ordering::Relaxed));
+    metrics
+        .kernel_flow_overflows_total
+        .sync(traffic.kernel_flow_overflows.load(Ordering::Relaxed));
+    metrics
+        .ring_buf_drops_total
+        .sync(traffic.ring_buf_drops.load(Ordering::Relaxed));
+    metrics
+        .ring_buf_size_bytes
+        .set(traffic.ring_buf_size_bytes.load(Ordering::Relaxed) as i64);
+    metrics
+        .malformed_events_total
+        .sync(traffic.malformed_events.load(Ordering::Relaxed));
+    metrics.clock_backward_steps_total.sync(traffic.clock.backward_steps());
+    for program in traffic.bpf_runtime.programs() {
+        let labels = ProgramLabels { program: program.name };
+        metrics.bpf_run_count_total.sync(&labels, program.run_count);
+        metrics.bpf_runtime_ns_total.sync(&labels, program.runtime_ns);
+    }
+    if let Some(report) = traffic.headroom.report() {
+        metrics.headroom_ratio.set(report.headroom_ratio);
+        for (resource, ratio) in report.ratios() {
+            let labels = ResourceLabels { resource: resource.to_string() };
+            metrics.headroom_utilization.get_or_create(&labels).set(ratio);
+        }
+    }
+    metrics
+        .blocklist_drops_total
+        .sync(traffic.blocklist_drops.load(Ordering::Relaxed));
+    metrics
+        .tcp_retransmits_total
+        .sync(traffic.tcp_retransmits.load(Ordering::Relaxed));
+    metrics
+        .forwarded_duplicates_total
+        .sync(traffic.forwarded_duplicates.load(Ordering::Relaxed));
+    let churn = traffic.churn();
+    metrics.connections_created_total.sync(churn.created);
+    metrics.connections_expired_total.sync(churn.expired);
+    for window in traffic.cardinality.report().windows {
+        let labels = WindowLabels { window: window.window };
+        metrics.distinct_src_ips.get_or_create(&labels).set(window.src_ips as i64);
+        metrics.distinct_dst_ips.get_or_create(&labels).set(window.dst_ips as i64);
+    }
+    for (name, count) in traffic.tcp_state_counts(None).by_state() {
+        let labels = TcpStateLabels { state: name.to_string() };
+        metrics.tcp_connections.get_or_create(&labels).set(count as i64);
+    }
+    drop(resets_seen);
+    if let Some(secondary) = &state.secondary {
+        let status = secondary.status(state.storage.as_ref());
+        metrics.secondary_lag_rows.set(status.lag_rows as i64);
+        metrics.secondary_queued_events.set(status.queued_events as i64);
+        metrics.secondary_dropped_events_total.sync(status.dropped_events);
+        metrics.secondary_write_failures_total.sync(status.write_failures);
+    }
+
+    let mut buf = String::new();
+    encode(&mut buf, &metrics.registry).unwrap();
+    (
+        [(axum::http::header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        buf,
+    )
+}
+
+async fn ws_handler(
+    ws: WebSocketUpgrade,
+    State(state): State<Arc<AppState>>,
+) -> impl IntoResponse {
+    ws.on_upgrade(move |socket| handle_socket(socket, state))
+}
+
+/// Most connections a `/api/stream` watch returns per push, by bytes.
+const WATCH_LIMIT: usize = 100;
+
+/// A client message on `/api/stream`.  `{"watch": {...}}` replaces the
+/// subscription; `{"watch": null}` clears it.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StreamRequest {
+    watch: Option<ConnectionFilter>,
+}
+
+/// Push global totals every second, plus the connections matching the
+/// client's watch filter when it has one.  Client messages are handled as
+/// they arrive; unparseable ones get an error frame and the session goes on.
+async fn handle_socket(mut socket: WebSocket, state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(1));
+    let mut watch: Option<ConnectionFilter> = None;
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            message = socket.recv() => {
+                let request = match message {
+                    Some(Ok(Message::Text(text))) => serde_json::from_str::<StreamRequest>(&text)
+                        .map_err(|e| format!("invalid stream message: {}", e)),
+                    Some(Ok(Message::Binary(_))) => Err("expected a JSON text message".to_string()),
+                    // Pings are answered by the WebSocket layer itself.
+                    Some(Ok(Message::Ping(_) | Message::Pong(_))) => continue,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                };
+                match request {
+                    Ok(request) => {
+                        // Push the new subscription's result now and restart
+                        // the one-second cadence from here.
+                        watch = request.watch;
+                        interval.reset();
+                    }
+                    Err(message) => {
+                        let error = ApiError::BadRequest(message).body().to_string();
+                        if socket.send(Message::Text(error)).await.is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                }
+            }
+        }
+
+        let frame = stream_frame(&state, watch.as_ref());
+        let text = serde_json::to_string(&frame).unwrap_or_default();
+        if socket.send(Message::Text(text)).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// One `/api/stream` push.  `watch` is only present while subscribed.
+fn stream_frame(
+    state: &AppState,
+    watch: Option<&ConnectionFilter>,
+) -> StreamEvent<ConnectionEntry> {
+    let traffic = &state.traffic;
+    let last_second = traffic.rates.rate(Duration::from_secs(1));
+    let watch = watch.map(|filter| {
+        let mut page = traffic.query_connections(
+            filter,
+            ConnectionSort::Bytes,
+            SortOrder::Desc,
+            0,
+            WATCH_LIMIT,
+        );
+        state.label_connections(&mut page.connections);
+        page
+    });
+    StreamEvent {
+        total_packets: traffic.total_packets.load(Ordering::Relaxed),
+        total_bytes: traffic.total_bytes.load(Ordering::Relaxed),
+        active_connections: traffic.active_connections.load(Ordering::Relaxed),
+        pps_1s: last_second.pps,
+        bps_1s: last_second.bps,
+        new_connections_1s: traffic.churn().created_1s,
+        watch,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alerts::Alert;
+    use crate::headroom::{HeadroomLimits, HeadroomSampler};
+    use crate::icmp::IcmpMessage;
+    use crate::locality::LocalNetworks;
+    use crate::state::{AggregatedBucket, PacketMetadata};
+    use crate::storage::Storage;
+    use crate::test_support;
+    use axum::body::Body;
+    use axum::http::Request;
+    use tower::ServiceExt;
+
+    fn test_state() -> Arc<AppState> {
+        Arc::new(test_support::app_state())
+    }
+
+    fn request_from(ip: [u8; 4], uri: &str) -> Request<Body> {
+        let mut req = Request::builder().uri(uri).body(Body::empty()).unwrap();
+        req.extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from((ip, 40000))));
+        req
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_returns_429_with_retry_after() {
+        let limits = ApiConfig {
+            rate_limit_per_second: 0.5,
+            rate_limit_burst: 2,
+            ..Default::default()
+        };
+        let app = router(test_state(), &[], false, &limits);
+
+        for _ in 0..2 {
+            let resp = app
+                .clone()
+                .oneshot(request_from([10, 0, 0, 1], "/api/health"))
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+
+        let resp = app
+            .clone()
+            .oneshot(request_from([10, 0, 0, 1], "/api/health"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers()[header::RETRY_AFTER], "2");
+        assert_eq!(json_body(resp).await["error"]["code"], "rate_limited");
+
+        // Buckets are per client IP.
+        let resp = app
+            .oneshot(request_from([10, 0, 0, 2], "/api/health"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    async fn json_body(resp: Response) -> serde_json::Value {
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    async fn get(uri: &str) -> Response {
+        let app = router(test_state(), &[], false, &ApiConfig::default());
+        app.oneshot(request_from([10, 0, 0, 1], uri)).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_base_path_nests_routes() {
+        let limits = ApiConfig {
+            base_path: "/ayaflow/".to_string(),
+            ..Default::default()
+        };
+        let app = router(test_state(), &[], true, &limits);
+        let status = |uri: &'static str| {
+            let resp = app.clone().oneshot(request_from([10, 0, 0, 1], uri));
+            async move { resp.await.unwrap().status() }
+        };
+        assert_eq!(status("/ayaflow/api/stats").await, StatusCode::OK);
+        assert_eq!(status("/ayaflow/metrics").await, StatusCode::OK);
+        assert_eq!(status("/ayaflow/").await, StatusCode::OK);
+        assert_eq!(status("/ayaflow").await, StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(status("/api/stats").await, StatusCode::NOT_FOUND);
+        assert_eq!(status("/").await, StatusCode::NOT_FOUND);
+        assert_eq!(status("/ayaflow/api/nope").await, StatusCode::NOT_FOUND);
+
+        let resp = app.clone().oneshot(request_from([10, 0, 0, 1], "/ayaflow/api/openapi.json"));
+        let doc = json_body(resp.await.unwrap()).await;
+        assert_eq!(doc["servers"][0]["url"], "/ayaflow");
+        assert!(doc["paths"].get("/api/stats").is_some());
+
+        // The explorer fetches the document relative to itself.
+        let resp = app.clone().oneshot(request_from([10, 0, 0, 1], "/ayaflow/api/docs"));
+        let resp = resp.await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp.headers()[header::CONTENT_TYPE].to_str().unwrap().starts_with("text/html"));
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        assert!(std::str::from_utf8(&body).unwrap().contains(r#"fetch("openapi.json")"#));
+
+        // The compression layer sees the prefixed paths: metrics stay plain,
+        // the rest is still compressed.
+        let gzipped = |uri: &'static str| {
+            let mut req = request_from([10, 0, 0, 1], uri);
+            req.headers_mut()
+                .insert(header::ACCEPT_ENCODING, "gzip".parse().unwrap());
+            let resp = app.clone().oneshot(req);
+            async move {
+                let resp = resp.await.unwrap();
+                let encoding = resp.headers().get(header::CONTENT_ENCODING).cloned();
+                let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+                (encoding.is_some(), body.len())
+            }
+        };
+        let (encoded, len) = gzipped("/ayaflow/metrics").await;
+        assert!(!encoded && len >= 1024, "metrics gzipped or too small to tell: {}", len);
+        assert!(gzipped("/ayaflow/api/openapi.json").await.0);
+    }
+
+    #[tokio::test]
+    async fn test_forwarded_for_only_from_trusted_proxies() {
+        let limits = ApiConfig {
+            trusted_proxies: vec!["10.0.0.1/32".to_string(), "10.0.0.2/32".to_string()],
+            ..Default::default()
+        };
+        let app = router(test_state(), &["192.168.1.0/24".to_string()], false, &limits);
+        let status = |peer: [u8; 4], forwarded: &[&str]| {
+            let mut req = request_from(peer, "/api/stats");
+            for value in forwarded {
+                req.headers_mut().append("x-forwarded-for", value.parse().unwrap());
+            }
+            let resp = app.clone().oneshot(req);
+            async move { resp.await.unwrap().status() }
+        };
+
+        // Through a trusted proxy the rightmost untrusted hop is the client.
+        assert_eq!(status([10, 0, 0, 1], &["192.168.1.5"]).await, StatusCode::OK);
+        assert_eq!(status([10, 0, 0, 1], &["203.0.113.9"]).await, StatusCode::FORBIDDEN);
+        assert_eq!(
+            status([10, 0, 0, 1], &["192.168.1.5, 10.0.0.2"]).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status([10, 0, 0, 1], &["192.168.1.5", "10.0.0.2"]).await,
+            StatusCode::OK
+        );
+        // A spoofed allowed address left of the real client does not help.
+        assert_eq!(
+            status([10, 0, 0, 1], &["192.168.1.5, 203.0.113.9"]).await,
+            StatusCode::FORBIDDEN
+        );
+        // Garbage from a trusted proxy leaves the client unknown.
+        assert_eq!(status([10, 0, 0, 1], &["unknown"]).await, StatusCode::FORBIDDEN);
+        // No header: the proxy itself is the client.
+        assert_eq!(status([10, 0, 0, 1], &[]).await, StatusCode::FORBIDDEN);
+
+        // Anyone else's header is ignored entirely.
+        assert_eq!(status([203, 0, 113, 9], &["192.168.1.5"]).await, StatusCode::FORBIDDEN);
+        assert_eq!(status([192, 168, 1, 7], &["203.0.113.9"]).await, StatusCode::OK);
+
+        // Without trusted proxies the header is never read.
+        let allowed = ["192.168.1.0/24".to_string()];
+        let app = router(test_state(), &allowed, false, &ApiConfig::default());
+        let mut req = request_from([10, 0, 0, 1], "/api/stats");
+        req.headers_mut().insert("x-forwarded-for", "192.168.1.5".parse().unwrap());
+        assert_eq!(app.oneshot(req).await.unwrap().status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_bad_request_errors() {
+        let long_interface = format!("/api/stats?interface={}", "e".repeat(60_000));
+        for (uri, param) in [
+            ("/api/history?limit=0", "limit"),
+            ("/api/history?limit=5000", "limit"),
+            ("/api/history?limit=abc", "limit"),
+            ("/api/history?from=2000&to=1000", "from"),
+            ("/api/history?from=-5", "from"),
+            ("/api/history?to=-1", "to"),
+            ("/api/history?ip=nope", "ip"),
+            ("/api/history?interface=eth0/1", "interface"),
+            ("/api/history?protocol=SCTP", "protocol"),
+            ("/api/history?icmp_type=256", "icmp_type"),
+            ("/api/history?country=CHN", "country"),
+            ("/api/history?country=c1", "country"),
+            ("/api/history?asn=-1", "asn"),
+            ("/api/alerts?limit=0", "limit"),
+            ("/api/connections?ip=not-an-ip", "ip"),
+            ("/api/connections?limit=1001", "limit"),
+            ("/api/connections?protocol=SCTP", "protocol"),
+            ("/api/connections?protocol=IP(256)", "protocol"),
+            ("/api/top?by=src_subnet&prefix=33", "prefix"),
+            ("/api/top?by=src_subnet&prefix6=129", "prefix6"),
+            ("/api/top?prefix=99", "prefix"),
+            ("/api/top?from=1000", "instance"),
+            ("/api/top?instance=edge-1&by=dst_subnet", "by"),
+            ("/api/top?instance=edge-1&cast=all", "cast"),
+            ("/api/top?instance=edge-1&from=2000&to=1000", "from"),
+            ("/api/usage?from=2000&to=1000", "from"),
+            ("/api/usage?from=-1", "from"),
+            ("/api/usage?granularity=week", "granularity"),
+            ("/api/peers?ip=185.1.2", "ip"),
+            ("/api/peers?from=2000&to=1000", "from"),
+            ("/api/report?period=2w", "period"),
+            ("/api/report?format=pdf", "format"),
+            (long_interface.as_str(), "interface"),
+        ] {
+            let resp = get(uri).await;
+            let uri = &uri[..uri.len().min(60)];
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", uri);
+            let body = json_body(resp).await;
+            assert_eq!(body["error"]["code"], "bad_request", "{}", uri);
+            let message = body["error"]["message"].as_str().unwrap();
+            assert!(message.contains(param), "{}: {}", uri, message);
+            assert!(message.len() < 300, "{}: {}", uri, message);
+        }
+
+        // Known protocol names pass in any case.
+        for protocol in ["tcp", "UDP", "arp", "icmpv6", "IP(1)", "ETH(0x88cc)"] {
+            let resp = get(&format!("/api/connections?protocol={}", protocol)).await;
+            assert_eq!(resp.status(), StatusCode::OK, "{}", protocol);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_junk_parameters_never_fail_the_server() {
+        let endpoints: [(&str, &[&str]); 9] = [
+            ("/api/history", &["limit", "from", "to", "ip", "interface", "mac", "direction"]),
+            ("/api/connections", &[
+                "sort", "order", "limit", "offset", "ip", "port", "protocol", "interface",
+                "direction", "cast", "connection_id",
+            ]),
+            ("/api/top", &["by", "prefix", "prefix6", "limit", "cast", "instance", "from", "to"]),
+            ("/api/usage", &["ip", "from", "to", "granularity"]),
+            ("/api/peers", &["ip", "from", "to"]),
+            ("/api/connection", &["src_ip", "src_port", "dst_ip", "dst_port", "limit"]),
+            ("/api/alerts", &["limit", "before_id", "severity", "rule", "since", "acked"]),
+            ("/api/stats", &["interface"]),
+            ("/api/live", &["interface", "wait", "timeout"]),
+        ];
+        let long = "9".repeat(10_000);
+        let junk = [
+            "", "0", "-1", "1e3", "NaN", "true", "%00", "%FF%FE", "%20", "[]", "::", "::1",
+            "999.1.1.1", "10.0.0.0/8", "-9223372036854775808", "9223372036854775807",
+            "18446744073709551616", "IP(256)", "ETH(0x)", "eth0%0A", long.as_str(),
+        ];
+        let app = router(test_state(), &[], false, &ApiConfig::default());
+        for (path, params) in endpoints {
+            for param in params {
+                for value in junk {
+                    let uri = format!("{}?{}={}", path, param, value);
+                    let resp = app.clone().oneshot(request_from([10, 0, 0, 1], &uri)).await;
+                    let resp = resp.unwrap();
+                    let shown = &uri[..uri.len().min(60)];
+                    let status = resp.status();
+                    assert!(
+                        status == StatusCode::OK || status == StatusCode::BAD_REQUEST,
+                        "{}: {}",
+                        shown,
+                        status
+                    );
+                    if status == StatusCode::BAD_REQUEST {
+                        let body = json_body(resp).await;
+                        assert_eq!(body["error"]["code"], "bad_request", "{}", shown);
+                    }
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unknown_route_is_404() {
+        let resp = get("/api/nope").await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let body = json_body(resp).await;
+        assert_eq!(body["error"]["code"], "not_found");
+        assert_eq!(body["error"]["message"], "no route for /api/nope");
+    }
+
+    #[tokio::test]
+    async fn test_storage_error_is_500() {
+        let error = StorageError::from(rusqlite::Error::InvalidQuery);
+        let resp = ApiError::from(error).into_response();
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = json_body(resp).await;
+        assert_eq!(body["error"]["code"], "storage_error");
+        assert!(body["error"]["message"].is_string());
+    }
+
+    #[tokio::test]
+    async fn test_openapi_paths_are_routed() {
+        let doc = openapi_document();
+        let paths = doc["paths"].as_object().unwrap();
+        assert!(paths.len() >= 10);
+        for path in paths.keys() {
+            if path == "/api/stream" {
+                continue; // Needs a WebSocket upgrade request.
+            }
+            if path == "/api/dns/cache" {
+                continue; // Needs resolve_dns; see test_dns_cache_endpoints.
+            }
+            if path.starts_with("/api/connection/") || path == "/api/connection" {
+                continue; // Needs a live connection; see test_connection_joins_live_and_stored.
+            }
+            let Some(op) = paths[path].get("get") else { continue };
+            if op.get("security").is_some() {
+                continue; // Needs the admin token; see test_config_endpoint.
+            }
+            let resp = get(path).await;
+            assert_eq!(resp.status(), StatusCode::OK, "{}", path);
+        }
+
+        let params = &paths["/api/connections"]["get"]["parameters"];
+        let names: Vec<&str> = params
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|p| p["name"].as_str().unwrap())
+            .collect();
+        assert!(names.contains(&"sort") && names.contains(&"ip"));
+
+        // Every route is documented, with its security from where it is routed.
+        for route in ROUTES {
+            let op = &paths[&openapi_path(route.path)][route.verb.key()];
+            assert!(op["summary"].is_string(), "{}", route.path);
+            let secured = matches!(route.access, Access::Admin | Access::Ingest);
+            assert_eq!(op.get("security").is_some(), secured, "{}", route.path);
+        }
+        let ack = &paths["/api/alerts/{id}/ack"]["post"];
+        assert_eq!(ack["security"][0]["adminToken"], json!([]));
+        assert_eq!(ack["parameters"][0]["in"], "path");
+        assert!(paths["/api/blocklist"]["get"].get("security").is_none());
+        assert!(paths["/api/blocklist"]["put"]["requestBody"]["required"] == true);
+    }
+
+    #[tokio::test]
+    async fn test_report_formats() {
+        let resp = get("/api/report?period=7d&limit=5").await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = json_body(resp).await;
+        assert_eq!(body["to"].as_i64().unwrap() - body["from"].as_i64().unwrap(), 7 * 86_400_000);
+        assert_eq!(body["stored"]["rows"], 0);
+
+        let resp = get("/api/report?format=markdown").await;
+        let content_type = resp.headers()[header::CONTENT_TYPE].to_str().unwrap();
+        assert!(content_type.starts_with("text/markdown"), "{}", content_type);
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        assert!(body.starts_with(b"# ayaFlow report\n"));
+    }
+
+    #[tokio::test]
+    async fn test_connections_export_formats() {
+        let resp = get("/api/connections/export?format=csv").await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "text/csv; charset=utf-8");
+        let disposition = resp.headers()[header::CONTENT_DISPOSITION].to_str().unwrap();
+        assert!(disposition.starts_with("attachment; filename=\"connections-"));
+        assert!(disposition.ends_with(".csv\""), "{}", disposition);
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        assert!(body.starts_with(b"# ayaflow connection snapshot "));
+
+        let resp = get("/api/connections/export").await;
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "application/x-ndjson");
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        let header: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(header["snapshot"]["connections"], 0);
+
+        let resp = get("/api/connections/export?format=xml").await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_large_history_is_gzipped() {
+        use crate::storage::StorageEvent;
+        use std::io::Read;
+
+        let state = test_state();
+        let (tx, rx) = tokio::sync::mpsc::channel(2000);
+        let storage = state.storage.clone();
+        let heartbeat = state.health.register("storage_writer", true, None);
+        tokio::spawn(crate::storage::supervise_writer(storage, rx, 0, heartbeat));
+        // The raw writer flushes as soon as 1000 packets are buffered.
+        for i in 0..1000 {
+            let packet = PacketMetadata {
+                timestamp: i,
+                src_ip: format!("10.0.0.{}", i % 200),
+                dst_ip: "192.168.1.1".into(),
+                length: 1500,
+                payload_length: 1448,
+                ttl: Some(64),
+                ..test_support::packet()
+            };
+            tx.send(StorageEvent::Packets(vec![packet])).await.unwrap();
+        }
+        while state.storage.query_packets(&PacketFilter::default(), 1000).unwrap().len() < 1000 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let app = router(state, &[], false, &ApiConfig::default());
+        let plain = app
+            .clone()
+            .oneshot(request_from([10, 0, 0, 1], "/api/history?limit=1000"))
+            .await
+            .unwrap();
+        assert!(plain.headers().get(header::CONTENT_ENCODING).is_none());
+        let plain = axum::body::to_bytes(plain.into_body(), usize::MAX).await.unwrap();
+
+        let encoded = |accept: &'static str| {
+            let mut req = request_from([10, 0, 0, 1], "/api/history?limit=1000");
+            req.headers_mut()
+                .insert(header::ACCEPT_ENCODING, accept.parse().unwrap());
+            let resp = app.clone().oneshot(req);
+            async move {
+                let resp = resp.await.unwrap();
+                assert_eq!(resp.status(), StatusCode::OK);
+                let encoding = resp.headers()[header::CONTENT_ENCODING].clone();
+                let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+                (encoding, body)
+            }
+        };
+        let (encoding, br) = encoded("br;q=1.0, gzip;q=0.8").await;
+        assert_eq!(encoding, "br");
+        assert!(br.len() < plain.len() / 4, "{} vs {}", br.len(), plain.len());
+
+        let (encoding, gz) = encoded("gzip").await;
+        assert_eq!(encoding, "gzip");
+        assert!(gz.len() < plain.len() / 4, "{} vs {}", gz.len(), plain.len());
+        let mut decoded = Vec::new();
+        flate2::read::GzDecoder::new(&gz[..]).read_to_end(&mut decoded).unwrap();
+        let a: serde_json::Value = serde_json::from_slice(&decoded).unwrap();
+        let b: serde_json::Value = serde_json::from_slice(&plain).unwrap();
+        assert_eq!(a, b);
+        assert_eq!(a.as_array().unwrap().len(), 1000);
+    }
+
+    #[test]
+    fn test_uncompressed_paths_under_base_path() {
+        assert!(uncompressed_path("/metrics", ""));
+        assert!(uncompressed_path("/ayaflow/api/stream", "/ayaflow"));
+        assert!(uncompressed_path("/ayaflow/metrics", "/ayaflow"));
+        assert!(!uncompressed_path("/metrics", "/ayaflow"));
+        assert!(!uncompressed_path("/ayaflow/api/stats", "/ayaflow"));
+        assert!(!uncompressed_path("/ayaflowmetrics", "/ayaflow"));
+    }
+
+    #[tokio::test]
+    async fn test_compression_skips_bodiless_and_small_responses() {
+        let fetch = |status: StatusCode, body: String| async move {
+            let handler = move || {
+                let mut response = (status, body.clone()).into_response();
+                let tag = HeaderValue::from_static("\"7\"");
+                response.headers_mut().insert(header::ETAG, tag);
+                async move { response }
+            };
+            let app = Router::new().route("/etag", axum::routing::get(handler));
+            let app = compress(app, "");
+            let mut req = request_from([10, 0, 0, 1], "/etag");
+            req.headers_mut().insert(header::ACCEPT_ENCODING, "gzip".parse().unwrap());
+            app.oneshot(req).await.unwrap()
+        };
+
+        let resp = fetch(StatusCode::OK, "x".repeat(4096)).await;
+        assert_eq!(resp.headers()[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(resp.headers()[header::VARY], "accept-encoding");
+        assert_eq!(resp.headers()[header::ETAG], "W/\"7\"");
+
+        // No Content-Length header: the size comes from the body.
+        let resp = fetch(StatusCode::OK, "{\"small\":true}".to_string()).await;
+        assert!(resp.headers().get(header::CONTENT_ENCODING).is_none());
+        assert_eq!(resp.headers()[header::ETAG], "\"7\"");
+
+        for status in [StatusCode::NOT_MODIFIED, StatusCode::NO_CONTENT] {
+            let resp = fetch(status, String::new()).await;
+            assert!(resp.headers().get(header::CONTENT_ENCODING).is_none(), "{}", status);
+            assert!(resp.headers().get(header::VARY).is_none(), "{}", status);
+            assert_eq!(resp.headers()[header::ETAG], "\"7\"");
+        }
+    }
+
+    fn sample_packet(length: usize) -> PacketMetadata {
+        PacketMetadata {
+            length,
+            ..test_support::packet()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_live_etag_and_long_poll() {
+        let state = test_state();
+        let app = router(state.clone(), &[], false, &ApiConfig::default());
+        let get = |uri: &str, tag: Option<&HeaderValue>| {
+            let mut req = request_from([10, 0, 0, 1], uri);
+            if let Some(tag) = tag {
+                req.headers_mut().insert(header::IF_NONE_MATCH, tag.clone());
+            }
+            app.clone().oneshot(req)
+        };
+
+        let resp = get("/api/live", None).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let tag = resp.headers()[header::ETAG].clone();
+        let nonce = format!("\"{:x}-", boot_nonce());
+        assert!(tag.to_str().unwrap().starts_with(&nonce), "{:?}", tag);
+        let resp = get("/api/live", Some(&tag)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(resp.headers()[header::ETAG], tag);
+        // The same generation from an earlier run is a different table.
+        let generation = state.traffic.generation();
+        let earlier = HeaderValue::from_str(&format!("\"1-{}\"", generation)).unwrap();
+        assert!(!etag_matches(&earlier, generation));
+        assert_eq!(get("/api/live", Some(&earlier)).await.unwrap().status(), StatusCode::OK);
+        let listed = HeaderValue::from_str(&format!("\"x\", W/{}", tag.to_str().unwrap()));
+        let resp = get("/api/live", Some(&listed.unwrap())).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+
+        // A packet moves the ETag on.
+        state.traffic.update(&sample_packet(100));
+        let resp = get("/api/live", Some(&tag)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let tag = resp.headers()[header::ETAG].clone();
+
+        // Nothing changes: the long-poll times out with 304.
+        let started = Instant::now();
+        let resp = get("/api/live?wait=true&timeout=1", Some(&tag)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        assert!(started.elapsed() >= Duration::from_secs(1));
+
+        // An update wakes a waiting request with the new table.
+        let waiting = tokio::spawn(get("/api/live?wait=true&timeout=30", Some(&tag)));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let started = Instant::now();
+        state.traffic.update(&sample_packet(200));
+        let resp = waiting.await.unwrap().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_ne!(resp.headers()[header::ETAG], tag);
+        assert_eq!(json_body(resp).await["total_bytes"], 300);
+
+        let resp = get("/api/live?wait=true&timeout=61", None).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_interface_filter_and_metric_labels() {
+        let state = test_state();
+        for (interface, length) in [("eth0", 1000), ("eth0", 500), ("wlan0", 70)] {
+            let packet = PacketMetadata {
+                interface: interface.into(),
+                payload_length: length - 40,
+                ..sample_packet(length)
+            };
+            state.traffic.update(&packet);
+        }
+        let app = router(state, &[], false, &ApiConfig::default());
+        let get = |uri: &'static str| app.clone().oneshot(request_from([10, 0, 0, 1], uri));
+
+        let body = json_body(get("/api/stats?interface=eth0").await.unwrap()).await;
+        assert_eq!(body["total_packets"], 2);
+        assert_eq!(body["total_bytes"], 1500);
+        let body = json_body(get("/api/stats?interface=bond0").await.unwrap()).await;
+        assert_eq!(body["total_packets"], 0);
+        let body = json_body(get("/api/stats").await.unwrap()).await;
+        assert_eq!(body["total_packets"], 3);
+
+        // The connection was last seen on wlan0.
+        let body = json_body(get("/api/live?interface=wlan0").await.unwrap()).await;
+        assert_eq!(body["connections"].as_array().unwrap().len(), 1);
+        assert_eq!(body["total_bytes"], 70);
+        assert_eq!(body["total_payload_bytes"], 30);
+        assert_eq!(body["connections"][0]["stats"]["payload_bytes"], 1450);
+        let body = json_body(get("/api/live?interface=eth0").await.unwrap()).await;
+        assert_eq!(body["connections"].as_array().unwrap().len(), 0);
+
+        let resp = get("/metrics").await.unwrap();
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        let text = String::from_utf8(bytes.to_vec()).unwrap();
+        assert!(text.contains("ayaflow_bytes_total{interface=\"eth0\"} 1500"), "{}", text);
+        assert!(text.contains("ayaflow_bytes_total{interface=\"wlan0\"} 70"), "{}", text);
+        let payload = "ayaflow_payload_bytes_total{interface=\"eth0\"} 1420";
+        assert!(text.contains(payload), "{}", text);
+        assert!(text.contains("ayaflow_storage_db_size_bytes "), "{}", text);
+        assert!(text.contains("ayaflow_connections_created_total 1\n"), "{}", text);
+        assert!(text.contains("ayaflow_connections_expired_total 0\n"), "{}", text);
+        assert!(text.contains("ayaflow_connection_cleanup_pause_seconds_count 0"), "{}", text);
+    }
+
+    #[tokio::test]
+    async fn test_cardinality_endpoint_and_gauges() {
+        let state = test_state();
+        for i in 0..20 {
+            state.traffic.update(&PacketMetadata {
+                src_ip: format!("10.0.0.{}", i),
+                ..sample_packet(100)
+            });
+        }
+        let app = router(state, &[], false, &ApiConfig::default());
+        let get = |uri: &'static str| app.clone().oneshot(request_from([10, 0, 0, 1], uri));
+
+        let body = json_body(get("/api/cardinality").await.unwrap()).await;
+        assert_eq!(body["windows"][1]["window"], "5m");
+        assert_eq!(body["windows"][1]["src_ips"], 20);
+        assert_eq!(body["windows"][1]["dst_ips"], 1);
+        assert_eq!(body["recent"], serde_json::json!([]));
+
+        let resp = get("/metrics").await.unwrap();
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        let text = String::from_utf8(bytes.to_vec()).unwrap();
+        assert!(text.contains("ayaflow_distinct_src_ips{window=\"60m\"} 20"), "{}", text);
+        assert!(text.contains("ayaflow_distinct_dst_ips{window=\"1m\"} 1"), "{}", text);
+    }
+
+    #[tokio::test]
+    async fn test_tcp_states_on_stats_and_metrics() {
+        use crate::state::TcpSegment;
+        use ayaflow_common::{TCP_ACK, TCP_SYN};
+        let state = test_state();
+        let syn = Some(TcpSegment { seq: 0, payload_len: 0, flags: TCP_SYN });
+        let ack = Some(TcpSegment { seq: 1, payload_len: 0, flags: TCP_ACK });
+        state.traffic.update_with_segment(&sample_packet(60), syn);
+        let established = PacketMetadata { src_port: 40001, ..sample_packet(60) };
+        state.traffic.update_with_segment(&established, syn);
+        state.traffic.update_with_segment(&established, ack);
+        let app = router(state, &[], false, &ApiConfig::default());
+        let get = |uri: &'static str| app.clone().oneshot(request_from([10, 0, 0, 1], uri));
+
+        let body = json_body(get("/api/stats").await.unwrap()).await;
+        assert_eq!(body["tcp_states"]["new"], 1);
+        assert_eq!(body["tcp_states"]["established"], 1);
+        assert_eq!(body["tcp_states"]["closed"], 0);
+        let body = json_body(get("/api/connections?sort=packets").await.unwrap()).await;
+        assert_eq!(body["connections"][0]["stats"]["tcp_state"], "established");
+
+        let resp = get("/metrics").await.unwrap();
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        let text = String::from_utf8(bytes.to_vec()).unwrap();
+        assert!(text.contains("ayaflow_tcp_connections{state=\"new\"} 1"), "{}", text);
+        assert!(text.contains("ayaflow_tcp_connections{state=\"closing\"} 0"), "{}", text);
+    }
+
+    #[tokio::test]
+    async fn test_headroom_on_stats_and_metrics() {
+        let state = test_state();
+        let traffic = state.traffic.clone();
+        let app = router(state, &[], false, &ApiConfig::default());
+        let get = |uri: &'static str| app.clone().oneshot(request_from([10, 0, 0, 1], uri));
+        let body = json_body(get("/api/stats").await.unwrap()).await;
+        assert!(body["headroom"].is_null());
+
+        let mut sampler = HeadroomSampler::new(HeadroomLimits {
+            drain_batch: 256,
+            flush_interval: Duration::from_secs(2),
+            cores: 1,
+        });
+        sampler.sample(&traffic.headroom, &[], Duration::ZERO);
+        traffic.headroom.record_drain(256);
+        sampler.sample(&traffic.headroom, &[], Duration::from_millis(500));
+
+        let body = json_body(get("/api/stats").await.unwrap()).await;
+        assert_eq!(body["headroom"]["ring_batch_peak"], 256);
+        assert_eq!(body["headroom"]["flush_ratio"], 0.25);
+        assert_eq!(body["headroom"]["headroom_ratio"], 0.0);
+        let bytes = axum::body::to_bytes(get("/metrics").await.unwrap().into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let text = String::from_utf8(bytes.to_vec()).unwrap();
+        assert!(text.contains("ayaflow_headroom_ratio 0.0"), "{}", text);
+        let ring = "ayaflow_headroom_utilization{resource=\"ring_buffer\"} 1.0";
+        assert!(text.contains(ring), "{}", text);
+        let flush = "ayaflow_headroom_utilization{resource=\"flush\"} 0.25";
+        assert!(text.contains(flush), "{}", text);
+    }
+
+    #[tokio::test]
+    async fn test_bpf_runtime_on_stats_and_metrics() {
+        use crate::bpf_stats::ProgramRuntime;
+        let state = test_state();
+        let traffic = state.traffic.clone();
+        let app = router(state, &[], false, &ApiConfig::default());
+        let get = |uri: &'static str| app.clone().oneshot(request_from([10, 0, 0, 1], uri));
+
+        // Without statistics both are omitted.
+        let body = json_body(get("/api/stats").await.unwrap()).await;
+        assert!(body["bpf_runtime"].is_null());
+        let bytes = axum::body::to_bytes(get("/metrics").await.unwrap().into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let text = String::from_utf8(bytes.to_vec()).unwrap();
+        assert!(!text.contains("ayaflow_bpf_run_count_total{"), "{}", text);
+
+        traffic.bpf_runtime.update(vec![ProgramRuntime {
+            name: "ayaflow_tc".into(),
+            run_count: 200,
+            runtime_ns: 50_000,
+        }]);
+        let body = json_body(get("/api/stats").await.unwrap()).await;
+        assert_eq!(body["bpf_runtime"]["run_count"], 200);
+        assert_eq!(body["bpf_runtime"]["avg_ns_per_packet"], 250.0);
+        let bytes = axum::body::to_bytes(get("/metrics").await.unwrap().into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let text = String::from_utf8(bytes.to_vec()).unwrap();
+        let runs = "ayaflow_bpf_run_count_total{program=\"ayaflow_tc\"} 200";
+        assert!(text.contains(runs), "{}", text);
+        let runtime = "ayaflow_bpf_runtime_ns_total{program=\"ayaflow_tc\"} 50000";
+        assert!(text.contains(runtime), "{}", text);
+    }
+
+    #[tokio::test]
+    async fn test_service_labels() {
+        let storage = Storage::new(":memory:").unwrap();
+        let alt = PacketMetadata { timestamp: 1, dst_port: 8443, ..sample_packet(100) };
+        storage.flush(&mut vec![sample_packet(100), alt.clone()]).unwrap();
+        let state = Arc::new(AppState {
+            storage: Arc::new(storage),
+            services: Arc::new(ServiceNames::new(&[(8443, "https-alt".to_string())].into())),
+            ..test_support::app_state()
+        });
+        state.traffic.update(&sample_packet(100));
+        state.traffic.update(&alt);
+        let app = router(state, &[], false, &ApiConfig::default());
+        let get = |uri: &'static str| app.clone().oneshot(request_from([10, 0, 0, 1], uri));
+
+        let body = json_body(get("/api/connections?sort=bytes").await.unwrap()).await;
+        let mut services: Vec<&str> = body["connections"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|c| c["service"].as_str().unwrap())
+            .collect();
+        services.sort();
+        assert_eq!(services, ["https", "https-alt"]);
+
+        // Stored rows carry no service; it is attached when served.
+        let body = json_body(get("/api/history").await.unwrap()).await;
+        assert_eq!(body[0]["service"], "https-alt");
+        assert_eq!(body[1]["service"], "https");
+        // The raw ports stay next to the one picked as the service port.
+        assert_eq!((&body[0]["src_port"], &body[0]["dst_port"]), (&40000.into(), &8443.into()));
+        assert_eq!(body[0]["service_port"], 8443);
+
+        let body = json_body(get("/api/top?by=port").await.unwrap()).await;
+        let mut ports: Vec<u64> =
+            body.as_array().unwrap().iter().map(|t| t["port"].as_u64().unwrap()).collect();
+        ports.sort();
+        assert_eq!(ports, [443, 8443]);
+        assert!(body[0].get("subnet").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_device_labels_and_mac_filter() {
+        let storage = Storage::new(":memory:").unwrap();
+        let tv = PacketMetadata {
+            src_mac: Some("aa:bb:cc:dd:ee:ff".into()),
+            dst_mac: Some("b8:27:eb:00:00:01".into()),
+            ..sample_packet(100)
+        };
+        let other = PacketMetadata { timestamp: 1, src_port: 40001, ..sample_packet(60) };
+        storage.flush(&mut vec![tv.clone(), other.clone()]).unwrap();
+        let devices = [("AA:BB:CC:DD:EE:FF".to_string(), "Living room TV".to_string())];
+        let state = Arc::new(AppState {
+            storage: Arc::new(storage),
+            devices: Arc::new(DeviceNames::new(&devices.into())),
+            ..test_support::app_state()
+        });
+        state.traffic.update(&tv);
+        state.traffic.update(&other);
+        let app = router(state, &[], false, &ApiConfig::default());
+        let get = |uri: &'static str| app.clone().oneshot(request_from([10, 0, 0, 1], uri));
+
+        let body = json_body(get("/api/connections?sort=bytes").await.unwrap()).await;
+        let labeled = &body["connections"][0];
+        assert_eq!(labeled["stats"]["src_mac"], "aa:bb:cc:dd:ee:ff");
+        assert_eq!(labeled["src_device"], "Living room TV");
+        assert_eq!(labeled["dst_vendor"], "Raspberry Pi");
+        assert!(labeled.get("dst_device").is_none());
+        assert!(body["connections"][1].get("src_device").is_none());
+
+        // Either side matches, in any accepted spelling.
+        let body = json_body(get("/api/history?mac=B8-27-EB-00-00-01").await.unwrap()).await;
+        assert_eq!(body.as_array().unwrap().len(), 1);
+        assert_eq!(body[0]["src_mac"], "aa:bb:cc:dd:ee:ff");
+        let resp = get("/api/history?mac=aa:bb").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_flow_direction_stats_and_filters() {
+        let local = LocalNetworks::new(vec!["10.0.0.0/24".parse().unwrap()]);
+        let traffic = TrafficState::new().with_local_networks(local);
+        let storage = Storage::new(":memory:").unwrap();
+        let download = PacketMetadata { src_ip: "93.184.216.34".into(), ..sample_packet(1500) };
+        let mut packets = vec![download, sample_packet(100)];
+        for packet in &mut packets {
+            packet.flow_direction = Some(traffic.flow_direction(&packet.src_ip, &packet.dst_ip));
+            traffic.update(packet);
+        }
+        storage.flush(&mut packets).unwrap();
+        let state = Arc::new(AppState {
+            traffic: Arc::new(traffic),
+            storage: Arc::new(storage),
+            ..test_support::app_state()
+        });
+        let app = router(state, &[], false, &ApiConfig::default());
+        let get = |uri: &'static str| app.clone().oneshot(request_from([10, 0, 0, 1], uri));
+
+        let body = json_body(get("/api/stats").await.unwrap()).await;
+        assert_eq!(body["flow_directions"]["inbound"]["bytes"], 1500);
+        assert_eq!(body["flow_directions"]["internal"]["packets"], 1);
+        assert_eq!(body["flow_directions"]["outbound"]["packets"], 0);
+        assert_eq!(body["connections_created_total"], 2);
+        assert_eq!(body["connections_expired_total"], 0);
+        assert_eq!(body["expired_connections_60s"], 0.0);
+
+        let body = json_body(get("/api/connections?direction=inbound").await.unwrap()).await;
+        assert_eq!(body["total"], 1);
+        assert_eq!(body["connections"][0]["stats"]["flow_direction"], "inbound");
+        let body = json_body(get("/api/history?direction=internal").await.unwrap()).await;
+        assert_eq!(body.as_array().unwrap().len(), 1);
+        assert_eq!(body[0]["flow_direction"], "internal");
+        let resp = get("/api/history?direction=ingress").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_cast_stats_and_filters() {
+        let local = LocalNetworks::new(vec!["10.0.0.0/24".parse().unwrap()]);
+        let traffic = TrafficState::new().with_local_networks(local);
+        let storage = Storage::new(":memory:").unwrap();
+        let to = |dst_ip: &str, length| PacketMetadata {
+            dst_ip: dst_ip.into(),
+            dst_port: 5353,
+            ..sample_packet(length)
+        };
+        let mut packets = vec![sample_packet(1000), to("224.0.0.251", 300), to("10.0.0.255", 200)];
+        for packet in &mut packets {
+            packet.cast = Some(traffic.cast(&packet.dst_ip));
+            traffic.update(packet);
+        }
+        storage.flush(&mut packets).unwrap();
+        let state = Arc::new(AppState {
+            traffic: Arc::new(traffic),
+            storage: Arc::new(storage),
+            ..test_support::app_state()
+        });
+        let app = router(state, &[], false, &ApiConfig::default());
+        let get = |uri: &'static str| app.clone().oneshot(request_from([10, 0, 0, 1], uri));
+
+        let body = json_body(get("/api/stats").await.unwrap()).await;
+        assert_eq!(body["casts"]["unicast"]["bytes"], 1000);
+        assert_eq!(body["casts"]["multicast"]["packets"], 1);
+        assert_eq!(body["casts"]["broadcast"]["bytes"], 200);
+
+        let body = json_body(get("/api/connections").await.unwrap()).await;
+        assert_eq!(body["total"], 3);
+        let body = json_body(get("/api/connections?cast=broadcast").await.unwrap()).await;
+        assert_eq!(body["total"], 1);
+        assert_eq!(body["connections"][0]["stats"]["cast"], "broadcast");
+
+        // Top talkers count unicast only unless asked.
+        let body = json_body(get("/api/top?by=dst_ip").await.unwrap()).await;
+        assert_eq!(body.as_array().unwrap().len(), 1);
+        assert_eq!(body[0]["bytes"], 1000);
+        let body = json_body(get("/api/top?by=dst_ip&cast=all").await.unwrap()).await;
+        assert_eq!(body.as_array().unwrap().len(), 3);
+        let body = json_body(get("/api/top?by=dst_ip&cast=multicast").await.unwrap()).await;
+        assert_eq!(body[0]["subnet"], "224.0.0.251/32");
+        assert_eq!(get("/api/top?cast=anycast").await.unwrap().status(), StatusCode::BAD_REQUEST);
+
+        // Stored rows are classed when served.
+        let body = json_body(get("/api/history").await.unwrap()).await;
+        let mut casts: Vec<&str> = body.as_array().unwrap().iter()
+            .map(|row| row["cast"].as_str().unwrap())
+            .collect();
+        casts.sort();
+        assert_eq!(casts, ["broadcast", "multicast", "unicast"]);
+    }
+
+    #[tokio::test]
+    async fn test_top_of_an_instance_ranks_stored_rows() {
+        let storage = Storage::new(":memory:").unwrap();
+        let bucket = |src_ip: &str, length, count| AggregatedBucket {
+            packet_count: count,
+            ..AggregatedBucket::from_packet(&PacketMetadata {
+                src_ip: src_ip.into(),
+                ..sample_packet(length)
+            })
+        };
+        let buckets = [bucket("10.0.0.7", 900, 3), bucket("10.0.0.8", 100, 1)];
+        storage.insert_ingested("edge-1", &buckets).unwrap();
+        storage.insert_ingested("edge-2", &[bucket("10.0.0.9", 5_000, 9)]).unwrap();
+        let state = Arc::new(AppState {
+            storage: Arc::new(storage),
+            ..test_support::app_state()
+        });
+        let app = router(state, &[], false, &ApiConfig::default());
+        let get = |uri: &'static str| app.clone().oneshot(request_from([10, 0, 0, 1], uri));
+
+        let body = json_body(get("/api/top?instance=edge-1&from=0").await.unwrap()).await;
+        assert_eq!(body.as_array().unwrap().len(), 2);
+        assert_eq!(body[0]["subnet"], "10.0.0.7/32");
+        assert_eq!((body[0]["bytes"].as_u64(), body[0]["packets"].as_u64()), (Some(900), Some(3)));
+        let body = json_body(get("/api/top?instance=edge-2&by=port&from=0").await.unwrap()).await;
+        assert_eq!((body[0]["port"].as_u64(), body[0]["bytes"].as_u64()), (Some(443), Some(5_000)));
+        // The live table has none of it, and the default range is the last hour.
+        let body = json_body(get("/api/top").await.unwrap()).await;
+        assert!(body.as_array().unwrap().is_empty());
+        let body = json_body(get("/api/top?instance=edge-1").await.unwrap()).await;
+        assert!(body.as_array().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_connection_joins_live_and_stored() {
+        let traffic = TrafficState::new();
+        let storage = Storage::new(":memory:").unwrap();
+        let reply = PacketMetadata {
+            timestamp: 5,
+            src_ip: "10.0.0.1".into(),
+            src_port: 443,
+            dst_ip: "10.0.0.2".into(),
+            dst_port: 40000,
+            ..sample_packet(60)
+        };
+        let unrelated = PacketMetadata { dst_port: 9999, ..sample_packet(50) };
+        traffic.update(&reply);
+        storage.flush(&mut vec![sample_packet(1500), reply, unrelated]).unwrap();
+        let state = Arc::new(AppState {
+            traffic: Arc::new(traffic),
+            storage: Arc::new(storage),
+            ..test_support::app_state()
+        });
+        let app = router(state, &[], false, &ApiConfig::default());
+        let get = |uri: &'static str| app.clone().oneshot(request_from([10, 0, 0, 1], uri));
+
+        let uri = "/api/connection?src_ip=10.0.0.2&src_port=40000&dst_ip=10.0.0.1&dst_port=443";
+        let body = json_body(get(uri).await.unwrap()).await;
+        assert_eq!(body["live"].as_array().unwrap().len(), 1);
+        assert_eq!(body["live"][0]["connection"], "10.0.0.1:443 -> 10.0.0.2:40000");
+        assert_eq!(body["history"].as_array().unwrap().len(), 2);
+        assert_eq!(body["stored"]["bytes"], 1560);
+        assert_eq!(body["stored"]["first_seen"], 0);
+        assert_eq!(body["stored"]["last_seen"], 5);
+
+        // Either orientation finds the same rows; the limit only trims them.
+        let uri =
+            "/api/connection?src_ip=10.0.0.1&src_port=443&dst_ip=10.0.0.2&dst_port=40000&limit=1";
+        let body = json_body(get(uri).await.unwrap()).await;
+        assert_eq!(body["history"].as_array().unwrap().len(), 1);
+        assert_eq!(body["history"][0]["timestamp"], 5);
+        assert_eq!(body["stored"]["rows"], 2);
+
+        let resp = get("/api/connection?src_ip=10.0.0.1&src_port=443&dst_ip=10.0.0.2").await;
+        assert_eq!(resp.unwrap().status(), StatusCode::BAD_REQUEST);
+
+        // By connection_id, as served by /api/connections and computable
+        // from the 4-tuple in either direction.
+        let body = json_body(get("/api/connections").await.unwrap()).await;
+        assert_eq!(body["connections"][0]["connection_id"], "badb64b01d20c770");
+        let body = json_body(get("/api/connection/badb64b01d20c770?limit=1").await.unwrap()).await;
+        assert_eq!(body["live"][0]["connection"], "10.0.0.1:443 -> 10.0.0.2:40000");
+        assert_eq!(body["stored"]["rows"], 2);
+        let body = json_body(get("/api/connections?connection_id=badb64b01d20c770").await.unwrap())
+            .await;
+        assert_eq!(body["total"], 1);
+        let resp = get("/api/connection/0000000000000000").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp = get("/api/connection/badb64b0").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_connection_id_collision_matches_each_tuple() {
+        // An IPv4 key and its IPv4-mapped twin hash alike, standing in for
+        // a collision.
+        let traffic = TrafficState::new();
+        let storage = Storage::new(":memory:").unwrap();
+        let mapped = PacketMetadata {
+            timestamp: 9,
+            src_ip: "::ffff:10.0.0.2".into(),
+            ..sample_packet(40)
+        };
+        let mut packets = vec![sample_packet(100), mapped];
+        for packet in &packets {
+            traffic.update(packet);
+        }
+        storage.flush(&mut packets).unwrap();
+        let state = Arc::new(AppState {
+            traffic: Arc::new(traffic),
+            storage: Arc::new(storage),
+            ..test_support::app_state()
+        });
+        let app = router(state, &[], false, &ApiConfig::default());
+        let uri = "/api/connection/badb64b01d20c770";
+        let body = json_body(app.oneshot(request_from([10, 0, 0, 1], uri)).await.unwrap()).await;
+        let live = body["live"].as_array().unwrap();
+        let mut live: Vec<&str> = live.iter().map(|e| e["connection"].as_str().unwrap()).collect();
+        live.sort();
+        let tuples = ["10.0.0.2:40000 -> 10.0.0.1:443", "::ffff:10.0.0.2:40000 -> 10.0.0.1:443"];
+        assert_eq!(live, tuples);
+        assert_eq!(body["stored"]["rows"], 2);
+        assert_eq!(body["stored"]["bytes"], 140);
+        assert_eq!(body["history"][0]["src_ip"], "::ffff:10.0.0.2");
+    }
+
+    #[tokio::test]
+    async fn test_categories_and_history_filter() {
+        let traffic = TrafficState::new();
+        let storage = Storage::new(":memory:").unwrap();
+        let ssh = PacketMetadata { src_port: 22, dst_port: 51000, ..sample_packet(200) };
+        let other = PacketMetadata { dst_port: 9999, ..sample_packet(50) };
+        let mut packets = vec![sample_packet(1500), ssh, other];
+        for packet in &packets {
+            traffic.update(packet);
+        }
+        storage.flush(&mut packets).unwrap();
+        let state = Arc::new(AppState {
+            traffic: Arc::new(traffic),
+            storage: Arc::new(storage),
+            ..test_support::app_state()
+        });
+        let app = router(state, &[], false, &ApiConfig::default());
+        let get = |uri: &'static str| app.clone().oneshot(request_from([10, 0, 0, 1], uri));
+
+        let body = json_body(get("/api/categories").await.unwrap()).await;
+        let totals: Vec<(&str, u64)> = body
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|c| (c["name"].as_str().unwrap(), c["bytes"].as_u64().unwrap()))
+            .collect();
+        assert!(totals.contains(&("web", 1500)));
+        assert!(totals.contains(&("ssh", 200)));
+        assert_eq!(totals.last(), Some(&("other", 50)));
+
+        let body = json_body(get("/api/history?category=ssh").await.unwrap()).await;
+        assert_eq!(body.as_array().unwrap().len(), 1);
+        assert_eq!(body[0]["src_port"], 22);
+        let body = json_body(get("/api/history?category=other").await.unwrap()).await;
+        assert_eq!(body.as_array().unwrap().len(), 1);
+        assert_eq!(body[0]["dst_port"], 9999);
+        let resp = get("/api/history?category=games").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let body = get("/metrics").await.unwrap().into_body();
+        let body = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+        let text = String::from_utf8(body.to_vec()).unwrap();
+        assert!(text.contains("ayaflow_category_bytes_total{category=\"web\"} 1500"));
+    }
+
+    fn icmp_packet(src_ip: &str, kind: u8, code: u8) -> PacketMetadata {
+        let message = IcmpMessage { v6: false, kind, code };
+        PacketMetadata {
+            src_ip: src_ip.into(),
+            src_port: 0,
+            dst_port: u16::from_be_bytes([kind, code]),
+            protocol: "ICMP".into(),
+            icmp_type: Some(kind),
+            icmp_code: Some(code),
+            icmp_name: Some(message.name()),
+            ..sample_packet(96)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_icmp_summary_and_history_filter() {
+        let traffic = TrafficState::new();
+        let storage = Storage::new(":memory:").unwrap();
+        let mut packets = vec![
+            sample_packet(1500),
+            icmp_packet("10.0.0.5", 8, 0),
+            icmp_packet("192.0.2.1", 11, 0),
+            icmp_packet("192.0.2.1", 3, 4),
+        ];
+        for packet in &packets {
+            traffic.update(packet);
+        }
+        storage.flush(&mut packets).unwrap();
+        let state = Arc::new(AppState {
+            traffic: Arc::new(traffic),
+            storage: Arc::new(storage),
+            ..test_support::app_state()
+        });
+        let app = router(state, &[], false, &ApiConfig::default());
+        let get = |uri: &'static str| app.clone().oneshot(request_from([10, 0, 0, 1], uri));
+
+        let body = json_body(get("/api/icmp").await.unwrap()).await;
+        assert_eq!(body["types"].as_array().unwrap().len(), 3);
+        assert_eq!(body["error_sources"].as_array().unwrap().len(), 1);
+        assert_eq!(body["error_sources"][0]["ip"], "192.0.2.1");
+        assert_eq!(body["error_sources"][0]["count"], 2);
+        let last = &body["error_sources"][0]["last_message"];
+        assert_eq!(last, "dest-unreachable/fragmentation-needed");
+
+        let body = json_body(get("/api/history?protocol=icmp").await.unwrap()).await;
+        assert_eq!(body.as_array().unwrap().len(), 3);
+        let body = json_body(get("/api/history?protocol=icmp&icmp_type=11").await.unwrap()).await;
+        assert_eq!(body.as_array().unwrap().len(), 1);
+        assert_eq!(body[0]["icmp_type"], 11);
+        assert_eq!(body[0]["icmp_name"], "time-exceeded/ttl-exceeded");
+        let body = json_body(get("/api/history?protocol=tcp").await.unwrap()).await;
+        assert_eq!(body.as_array().unwrap().len(), 1);
+        assert!(body[0].get("icmp_type").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_country_top_and_history_filters() {
+        let placed = |dst_ip: &str, src_port, country: Option<&str>, asn| PacketMetadata {
+            dst_ip: dst_ip.into(),
+            src_port,
+            country: country.map(str::to_string),
+            asn,
+            ..sample_packet(100)
+        };
+        let traffic = TrafficState::new();
+        let storage = Storage::new(":memory:").unwrap();
+        let mut packets = vec![
+            placed("198.51.100.1", 40000, Some("US"), Some(13335)),
+            placed("198.51.100.2", 40001, Some("US"), Some(15169)),
+            placed("203.0.113.1", 40002, Some("CN"), Some(4134)),
+            placed("10.0.0.9", 40003, None, None),
+        ];
+        for packet in &packets {
+            traffic.update(packet);
+        }
+        storage.flush(&mut packets).unwrap();
+        let state = Arc::new(AppState {
+            traffic: Arc::new(traffic),
+            storage: Arc::new(storage),
+            ..test_support::app_state()
+        });
+        let app = router(state, &[], false, &ApiConfig::default());
+        let get = |uri: &'static str| app.clone().oneshot(request_from([10, 0, 0, 1], uri));
+
+        let body = json_body(get("/api/top?by=country").await.unwrap()).await;
+        let groups: Vec<_> = body
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|t| (t["country"].as_str().unwrap(), t["bytes"].as_u64().unwrap()))
+            .collect();
+        assert_eq!(groups[0], ("US", 200));
+        assert_eq!(groups.len(), 3);
+        assert!(groups.contains(&("unknown", 100)));
+        assert!(body[0].get("subnet").is_none());
+
+        let body = json_body(get("/api/connections").await.unwrap()).await;
+        let countries: Vec<_> = body["connections"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|c| c["stats"]["country"].clone())
+            .collect();
+        assert!(countries.contains(&json!("CN")) && countries.contains(&json!(null)));
+
+        let ports = |body: serde_json::Value| {
+            let mut ports: Vec<u64> =
+                body.as_array().unwrap().iter().map(|r| r["src_port"].as_u64().unwrap()).collect();
+            ports.sort();
+            ports
+        };
+        let body = json_body(get("/api/history?country=us").await.unwrap()).await;
+        assert_eq!(ports(body.clone()), [40000, 40001]);
+        assert_eq!(body[0]["country"], "US");
+        let body = json_body(get("/api/history?asn=4134").await.unwrap()).await;
+        assert_eq!(ports(body), [40002]);
+        let body = json_body(get("/api/history?country=unknown").await.unwrap()).await;
+        assert_eq!(ports(body.clone()), [40003]);
+        assert!(body[0].get("country").is_none() && body[0].get("asn").is_none());
+        let body = json_body(get("/api/history?country=US&asn=4134").await.unwrap()).await;
+        assert_eq!(ports(body), Vec::<u64>::new());
+    }
+
+    fn post_reset(uri: &str, token: Option<&str>) -> Request<Body> {
+        let mut req = request_from([10, 0, 0, 1], uri);
+        *req.method_mut() = axum::http::Method::POST;
+        if let Some(token) = token {
+            let value = format!("Bearer {}", token).parse().unwrap();
+            req.headers_mut().insert(header::AUTHORIZATION, value);
+        }
+        req
+    }
+
+    #[tokio::test]
+    async fn test_config_endpoint() {
+        let mut config = Config::default();
+        config.api.admin_token = Some("secret".to_string());
+        let attach = AttachStatus {
+            interface: "eth0".to_string(),
+            hooks: vec!["tc ingress".to_string()],
+            created_qdisc: true,
+        };
+        let state = Arc::new(AppState {
+            config: Arc::new(ConfigResponse::new(&config, None, attach)),
+            ..test_support::app_state()
+        });
+        let app = router(state, &[], false, &config.api);
+
+        let get = |token: Option<&str>| {
+            let mut req = post_reset("/api/config", token);
+            *req.method_mut() = axum::http::Method::GET;
+            app.clone().oneshot(req)
+        };
+        assert_eq!(get(None).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+        let body = json_body(get(Some("secret")).await.unwrap()).await;
+        assert_eq!(body["config"]["api"]["admin_token"], "[redacted]");
+        assert_eq!(body["config"]["port"], 3000);
+        assert_eq!(body["sources"]["port"], "default");
+        assert_eq!(body["attach"]["hooks"][0], "tc ingress");
+        assert!(body["config"].get("sources").is_none());
+        assert_eq!(body["config"]["dns"]["ttl_seconds"], 300);
+    }
+
+    #[tokio::test]
+    async fn test_dns_cache_endpoints() {
+        let limits = ApiConfig {
+            admin_token: Some("secret".to_string()),
+            ..Default::default()
+        };
+        let app = router(test_state(), &[], false, &limits);
+        let resp = app.clone().oneshot(request_from([10, 0, 0, 1], "/api/dns/cache")).await;
+        assert_eq!(resp.unwrap().status(), StatusCode::CONFLICT);
+
+        let state = test_state();
+        let cache = DnsCache::new(Duration::from_secs(300), Duration::from_millis(50))
+            .with_lookup(|ip| Some(format!("host-{}.example", ip)));
+        let cache = Arc::new(cache);
+        for ip in ["192.0.2.1", "192.0.2.2", "192.0.2.3"] {
+            cache.resolve(ip).await;
+        }
+        cache.cached("192.0.2.1");
+        state.diagnostics.set_dns_cache(cache);
+        let app = router(state, &[], false, &limits);
+        let resp = app.clone().oneshot(request_from([10, 0, 0, 1], "/api/dns/cache")).await;
+        let body = json_body(resp.unwrap()).await;
+        assert_eq!((body["entries"].as_u64(), body["hits"].as_u64()), (Some(3), Some(1)));
+        assert_eq!(body["ttl_seconds"], 300);
+
+        let flush = |uri: &str, token| app.clone().oneshot(post_reset(uri, token));
+        let resp = flush("/api/dns/flush", None).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = flush("/api/dns/flush?ip=192.0.2.2", Some("secret")).await.unwrap();
+        assert_eq!(json_body(resp).await["entries_removed"], 1);
+        let resp = flush("/api/dns/flush?ip=nope", Some("secret")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let resp = flush("/api/dns/flush", Some("secret")).await.unwrap();
+        assert_eq!(json_body(resp).await["entries_removed"], 2);
+    }
+
+    #[tokio::test]
+    async fn test_debug_dump() {
+        let state = test_state();
+        for port in 0..30 {
+            let packet = PacketMetadata { src_port: port, ..sample_packet(100 + port as usize) };
+            state.traffic.update(&packet);
+        }
+        let (tx, _rx) = tokio::sync::mpsc::channel::<u8>(4);
+        tx.try_send(1).unwrap();
+        state.diagnostics.watch_queue("storage", &tx);
+        state.storage.update_size_metric(); // As the size timer would.
+        let limits = ApiConfig {
+            admin_token: Some("secret".to_string()),
+            ..Default::default()
+        };
+        let app = router(state, &[], false, &limits);
+        let get = |token: Option<&str>| {
+            let mut req = post_reset("/api/debug/dump", token);
+            *req.method_mut() = axum::http::Method::GET;
+            app.clone().oneshot(req)
+        };
+        assert_eq!(get(None).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+
+        let resp = get(Some("secret")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = json_body(resp).await;
+        assert_eq!(body["traffic"]["active_connections"], 30);
+        // Only the busiest connections, never the whole table.
+        let top = body["traffic"]["top_connections"].as_array().unwrap();
+        assert_eq!(top.len(), crate::diagnostics::TOP_CONNECTIONS);
+        assert_eq!(top[0]["stats"]["bytes_received"], 129);
+        assert_eq!(body["queues"][0]["name"], "storage");
+        assert_eq!(body["queues"][0]["depth"], 1);
+        assert!(body["dns_cache"].is_null());
+        assert!(body["storage"]["db_size_bytes"].as_i64().unwrap() > 0);
+        assert!(body["config"]["sources"].is_object());
+        let schema = DiagnosticDump::schema();
+        let documented = schema["properties"].as_object().unwrap();
+        assert!(body.as_object().unwrap().keys().eq(documented.keys()));
+    }
+
+    #[tokio::test]
+    async fn test_export_snapshot() {
+        let path = std::env::temp_dir().join(format!("ayaflow-export-{}.db", std::process::id()));
+        let path = path.to_string_lossy().into_owned();
+        let sqlite = crate::config::SqliteConfig {
+            snapshot_min_free_mb: 0,
+            ..Default::default()
+        };
+        let storage = Storage::open(&path, &sqlite).unwrap();
+        storage.flush(&mut vec![sample_packet(100), sample_packet(200)]).unwrap();
+        let limits = ApiConfig {
+            admin_token: Some("secret".to_string()),
+            ..Default::default()
+        };
+        let on_disk = AppState {
+            storage: Arc::new(storage),
+            ..test_support::app_state()
+        };
+        let get = |state: Arc<AppState>, token: Option<&str>| {
+            let mut req = post_reset("/api/export/snapshot", token);
+            *req.method_mut() = axum::http::Method::GET;
+            router(state, &[], false, &limits).oneshot(req)
+        };
+        let on_disk = Arc::new(on_disk);
+        let resp = get(on_disk.clone(), None).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let resp = get(on_disk, Some("secret")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "application/vnd.sqlite3");
+        let disposition = resp.headers()[header::CONTENT_DISPOSITION].to_str().unwrap();
+        assert!(disposition.starts_with("attachment; filename=\"ayaflow-"));
+        let length = resp.headers()[header::CONTENT_LENGTH].to_str().unwrap();
+        let length: usize = length.parse().unwrap();
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body.len(), length);
+        assert!(body.starts_with(b"SQLite format 3\0"));
+
+        // An in-memory database has no file to copy.
+        let resp = get(test_state(), Some("secret")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(json_body(resp).await["error"]["code"], "snapshot_unavailable");
+
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path, suffix));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_blocklist_endpoints() {
+        let limits = ApiConfig {
+            admin_token: Some("secret".to_string()),
+            ..Default::default()
+        };
+        let app = router(test_state(), &[], false, &limits);
+        let put = |token: Option<&str>, body: &str| {
+            let mut req = post_reset("/api/blocklist", token);
+            *req.method_mut() = axum::http::Method::PUT;
+            let value = "application/json".parse().unwrap();
+            req.headers_mut().insert(header::CONTENT_TYPE, value);
+            *req.body_mut() = Body::from(body.to_string());
+            app.clone().oneshot(req)
+        };
+        let body = r#"{"entries": ["10.9.8.7", "192.168.1.0/24"]}"#;
+        assert_eq!(put(None, body).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+        let bad = put(Some("secret"), r#"{"entries": ["10.0.0.0/40"]}"#).await.unwrap();
+        assert_eq!(bad.status(), StatusCode::BAD_REQUEST);
+        let bad = put(Some("secret"), r#"{"entries": 3}"#).await.unwrap();
+        assert_eq!(bad.status(), StatusCode::BAD_REQUEST);
+        let resp = put(Some("secret"), body).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // Reading needs no token.
+        let resp = app.clone().oneshot(request_from([10, 0, 0, 1], "/api/blocklist"));
+        let body = json_body(resp.await.unwrap()).await;
+        assert_eq!(body["entries"], serde_json::json!(["10.9.8.7/32", "192.168.1.0/24"]));
+        assert_eq!(body["enforcing"], false);
+        assert_eq!(body["attached"], false);
+    }
+
+    #[tokio::test]
+    async fn test_alert_filters_and_ack() {
+        let storage = Storage::new(":memory:").unwrap();
+        for (subject, severity) in [("10.0.0.7", "warning"), ("10.0.0.8", "critical")] {
+            let alert = Alert {
+                timestamp: 1_000,
+                rule: "ttl_below".into(),
+                severity: severity.into(),
+                subject: subject.into(),
+                message: String::new(),
+            };
+            storage.insert_alert(&alert).unwrap();
+        }
+        let state = Arc::new(AppState {
+            storage: Arc::new(storage),
+            ..test_support::app_state()
+        });
+        let limits = ApiConfig {
+            admin_token: Some("secret".to_string()),
+            ..Default::default()
+        };
+        let app = router(state, &[], false, &limits);
+        let get = |uri: &'static str| app.clone().oneshot(request_from([10, 0, 0, 1], uri));
+
+        let body = json_body(get("/api/alerts?severity=critical").await.unwrap()).await;
+        assert_eq!(body.as_array().unwrap().len(), 1);
+        assert_eq!(body[0]["subject"], "10.0.0.8");
+        let resp = get("/api/alerts?severity=fatal").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let resp = app.clone().oneshot(post_reset("/api/alerts/1/ack", None)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let uri = "/api/alerts/1/ack?by=alice";
+        let resp = app.clone().oneshot(post_reset(uri, Some("secret"))).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = json_body(resp).await;
+        assert_eq!((&body["acked"], &body["acked_by"]), (&true.into(), &"alice".into()));
+        for (uri, status) in [
+            ("/api/alerts/99/ack", StatusCode::NOT_FOUND),
+            ("/api/alerts/one/ack", StatusCode::BAD_REQUEST),
+        ] {
+            let resp = app.clone().oneshot(post_reset(uri, Some("secret"))).await.unwrap();
+            assert_eq!(resp.status(), status, "{}", uri);
+        }
+
+        let body = json_body(get("/api/alerts?acked=false").await.unwrap()).await;
+        assert_eq!(body.as_array().unwrap().len(), 1);
+        assert_eq!(body[0]["id"], 2);
+    }
+
+    #[tokio::test]
+    async fn test_admin_reset() {
+        let resp = router(test_state(), &[], false, &ApiConfig::default())
+            .oneshot(post_reset("/api/admin/reset", Some("secret")))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let state = test_state();
+        state.traffic.update(&sample_packet(1000));
+        let limits = ApiConfig {
+            admin_token: Some("secret".to_string()),
+            ..Default::default()
+        };
+        let app = router(state.clone(), &[], false, &limits);
+        let metric = |text: &str| {
+            text.lines()
+                .find(|l| l.starts_with("ayaflow_bytes_total{interface=\"eth0\"}"))
+                .map(|l| l.rsplit(' ').next().unwrap().to_string())
+        };
+        let scrape = || async {
+            let resp = app.clone().oneshot(request_from([10, 0, 0, 1], "/metrics"));
+            let body = resp.await.unwrap().into_body();
+            let bytes = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+            String::from_utf8(bytes.to_vec()).unwrap()
+        };
+        assert_eq!(metric(&scrape().await).as_deref(), Some("1000"));
+
+        for token in [None, Some("wrong")] {
+            let resp = app.clone().oneshot(post_reset("/api/admin/reset", token));
+            let resp = resp.await.unwrap();
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+            assert_eq!(json_body(resp).await["error"]["code"], "unauthorized");
+        }
+        assert_eq!(state.traffic.total_packets.load(Ordering::Relaxed), 1);
+
+        let uri = "/api/admin/reset?include_db=true";
+        let resp = app.clone().oneshot(post_reset(uri, Some("secret"))).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = json_body(resp).await;
+        assert_eq!(body["packets_cleared"], 1);
+        assert_eq!(body["bytes_cleared"], 1000);
+        assert_eq!(body["connections_cleared"], 1);
+        assert_eq!(body["db_rows_deleted"], 0);
+        assert_eq!(state.traffic.active_connections.load(Ordering::Relaxed), 0);
+
+        // The exported counter keeps rising across the reset.
+        state.traffic.update(&sample_packet(200));
+        assert_eq!(metric(&scrape().await).as_deref(), Some("1200"));
+    }
+
+    #[tokio::test]
+    async fn test_admin_backfill_dns() {
+        let limits = ApiConfig {
+            admin_token: Some("secret".to_string()),
+            ..Default::default()
+        };
+        // Lookups wait for the test, so the run is still going when the
+        // second start arrives.
+        let (release, released) = std::sync::mpsc::channel::<()>();
+        let released = Mutex::new(released);
+        let cache = DnsCache::new(Duration::from_secs(60), Duration::from_secs(10))
+            .with_lookup(move |_| {
+                let _ = released.lock().unwrap().recv();
+                Some("dns.example".into())
+            });
+        let storage = Storage::new(":memory:").unwrap();
+        let public = PacketMetadata { dst_ip: "9.9.9.9".into(), ..sample_packet(100) };
+        storage.flush(&mut vec![public]).unwrap();
+        let state = Arc::new(AppState {
+            storage: Arc::new(storage),
+            backfill: Arc::new(BackfillJob::with_cache(Arc::new(cache))),
+            ..test_support::app_state()
+        });
+        let app = router(state.clone(), &[], false, &limits);
+        let call = |uri: &str, post: bool| {
+            let mut req = post_reset(uri, Some("secret"));
+            if !post {
+                *req.method_mut() = axum::http::Method::GET;
+            }
+            app.clone().oneshot(req)
+        };
+        let resp = call("/api/admin/backfill-dns", false).await.unwrap();
+        assert_eq!(json_body(resp).await["state"], "idle");
+
+        for uri in ["/api/admin/backfill-dns?rate=0", "/api/admin/backfill-dns?since=-1"] {
+            let resp = call(uri, true).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", uri);
+        }
+
+        let resp = call("/api/admin/backfill-dns?skip_private=true", true).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        assert_eq!(json_body(resp).await["state"], "running");
+        let resp = call("/api/admin/backfill-dns", true).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        assert_eq!(json_body(resp).await["error"]["code"], "conflict");
+        drop(release);
+        loop {
+            let body = json_body(call("/api/admin/backfill-dns", false).await.unwrap()).await;
+            if body["state"] != "running" {
+                assert_eq!(body["state"], "finished");
+                assert_eq!(body["hostnames_filled"], 1);
+                let progress = BackfillProgress {
+                    error: Some("locked".into()),
+                    ..state.backfill.progress()
+                };
+                crate::openapi::assert_matches_schema(&progress);
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stream_watch_subscription() {
+        use futures_util::SinkExt;
+        use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+        let state = test_state();
+        state.traffic.update(&sample_packet(1000));
+        state.traffic.update(&PacketMetadata {
+            src_ip: "10.0.0.9".into(),
+            ..sample_packet(64)
+        });
+        let app = router(state, &[], false, &ApiConfig::default());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let service = app.into_make_service_with_connect_info::<SocketAddr>();
+            axum::serve(listener, service).await
+        });
+        let url = format!("ws://{}/api/stream", addr);
+        let (mut ws, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+
+        let frame = next_frame(&mut ws, |f| f.get("total_packets").is_some()).await;
+        assert!(frame.get("watch").is_none());
+
+        ws.send(WsMessage::Text("not json".into())).await.unwrap();
+        let frame = next_frame(&mut ws, |f| f.get("error").is_some()).await;
+        assert_eq!(frame["error"]["code"], "bad_request");
+
+        let watch = r#"{"watch": {"ip": "10.0.0.9", "port": 443}}"#;
+        ws.send(WsMessage::Text(watch.into())).await.unwrap();
+        let frame = next_frame(&mut ws, |f| f.get("watch").is_some()).await;
+        assert_eq!(frame["watch"]["total"], 1);
+        assert_eq!(frame["watch"]["connections"][0]["stats"]["bytes_received"], 64);
+        assert_eq!(frame["total_packets"], 2);
+
+        ws.send(WsMessage::Text(r#"{"watch": null}"#.into())).await.unwrap();
+        next_frame(&mut ws, |f| f.get("total_packets").is_some() && f.get("watch").is_none()).await;
+    }
+
+    type TestSocket = tokio_tungstenite::WebSocketStream<
+        tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+    >;
+
+    /// Read frames until one matches, skipping scheduled pushes.
+    async fn next_frame(
+        ws: &mut TestSocket,
+        pred: impl Fn(&serde_json::Value) -> bool,
+    ) -> serde_json::Value {
+        use futures_util::StreamExt;
+        let recv = async {
+            loop {
+                let msg = ws.next().await.unwrap().unwrap();
+                let frame = serde_json::from_str(msg.to_text().unwrap()).unwrap();
+                if pred(&frame) {
+                    return frame;
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), recv).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_storage_routes_served_under_limits() {
+        let app = router(test_state(), &[], false, &ApiConfig::default());
+        let resp = app
+            .oneshot(request_from([10, 0, 0, 1], "/api/history?limit=10"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_dead_writer_makes_health_503() {
+        let state = test_state();
+        let app = router(state.clone(), &[], false, &ApiConfig::default());
+        let health = || app.clone().oneshot(request_from([10, 0, 0, 1], "/api/health"));
+
+        let (_tx, rx) = tokio::sync::mpsc::channel(1);
+        let stale_after = Some(Duration::from_secs(30));
+        let heartbeat = state.health.register("storage_writer", true, stale_after);
+        let _dns = state.health.register("dns", false, None);
+        let storage = state.storage.clone();
+        let writer = tokio::spawn(crate::storage::supervise_writer(storage, rx, 0, heartbeat));
+
+        let resp = health().await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = json_body(resp).await;
+        assert_eq!(body["status"], "ok");
+        assert_eq!(body["components"][1]["name"], "storage_writer");
+
+        // Killing the writer drops its heartbeat.
+        writer.abort();
+        let _ = writer.await;
+        let resp = health().await.unwrap();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = json_body(resp).await;
+        assert_eq!(body["status"], "down");
+        assert_eq!(body["components"][1]["status"], "down");
+        assert_eq!(body["components"][1]["last_error"], "task exited");
+        assert_eq!(body["components"][0]["status"], "ok");
+    }
+
+    #[tokio::test]
+    async fn test_health_reports_capture_state() {
+        let app = router(test_state(), &[], false, &ApiConfig::default());
+        let resp = app.oneshot(request_from([10, 0, 0, 1], "/api/health")).await.unwrap();
+        assert_eq!(json_body(resp).await["capture"], "enabled");
+
+        // API-only mode registers no components and still reports ok.
+        let state = Arc::new(AppState {
+            capture: CaptureState::Disabled,
+            ..test_support::app_state()
+        });
+        let app = router(state, &[], false, &ApiConfig::default());
+        let resp = app.oneshot(request_from([10, 0, 0, 1], "/api/health")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = json_body(resp).await;
+        assert_eq!(body["capture"], "disabled");
+        assert_eq!(body["status"], "ok");
+        assert_eq!(body["total_packets"], 0);
+    }
+
+    #[tokio::test]
+    async fn test_version_reports_build_and_attachment() {
+        let attach = AttachStatus {
+            interface: "eth0".to_string(),
+            hooks: vec!["tc ingress".to_string(), "tc egress".to_string()],
+            created_qdisc: false,
+        };
+        let state = Arc::new(AppState {
+            version: Arc::new(crate::version::gather(b"abc", attach, Default::default())),
+            ..test_support::app_state()
+        });
+        let app = router(state, &[], false, &ApiConfig::default());
+        let resp = app.oneshot(request_from([10, 0, 0, 1], "/api/version")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = json_body(resp).await;
+        assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(
+            body["ebpf_sha256"],
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        // No ABI marker in these bytes.
+        assert!(body["ebpf_abi_hash"].is_null());
+        assert_eq!(body["attach"]["hooks"], serde_json::json!(["tc ingress", "tc egress"]));
+        assert!(body["interfaces"].is_array());
     }
 }
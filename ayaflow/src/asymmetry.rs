@@ -0,0 +1,284 @@
+//! Flows seen in one direction only, a sign of asymmetric routing.
+//!
+//! Each direction of a flow is its own connection entry.  Once a second
+//! the rate sampler marks entries whose reverse direction is in the table
+//! as `bidirectional`, and the mark stays for the entry's life.  A TCP or
+//! UDP flow to a unicast address that goes unmarked for longer than its
+//! protocol's threshold counts as unidirectional: on an edge that sees all
+//! traffic, its replies went another way.  UDP gets a longer threshold
+//! because syslog, NetFlow export and the like are one-way by design.
+//! Unidirectional flows removed by the cleanup are counted too, as TCP
+//! flows that never saw a reply are the clearest evidence.
+//! `/api/asymmetric` reports the counts and the busiest such flows.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use ayaflow_common::config_check::ConfigProblems;
+use serde::{Deserialize, Serialize};
+use tokio::time::{Duration, Instant};
+
+use crate::locality::Cast;
+use crate::openapi::api_schema;
+use crate::state::{ConnectionEntry, ConnectionStats, TrafficState};
+
+/// Asymmetric routing detection (the `asymmetry:` section of the YAML
+/// config).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AsymmetryConfig {
+    /// Seconds a TCP flow may go without its reverse direction before it
+    /// counts as unidirectional.
+    #[serde(default = "default_tcp_after_seconds")]
+    pub tcp_after_seconds: u64,
+    /// The same for UDP.
+    #[serde(default = "default_udp_after_seconds")]
+    pub udp_after_seconds: u64,
+}
+
+fn default_tcp_after_seconds() -> u64 {
+    10
+}
+
+fn default_udp_after_seconds() -> u64 {
+    120
+}
+
+impl Default for AsymmetryConfig {
+    fn default() -> Self {
+        Self {
+            tcp_after_seconds: default_tcp_after_seconds(),
+            udp_after_seconds: default_udp_after_seconds(),
+        }
+    }
+}
+
+impl AsymmetryConfig {
+    pub fn validate(&self, problems: &mut ConfigProblems) {
+        problems.ensure(
+            self.tcp_after_seconds > 0,
+            "asymmetry.tcp_after_seconds",
+            "must be at least 1",
+        );
+        problems.ensure(
+            self.udp_after_seconds > 0,
+            "asymmetry.udp_after_seconds",
+            "must be at least 1",
+        );
+    }
+}
+
+/// Where a flow stands, if it is one the detection looks at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Class {
+    Bidirectional,
+    Unidirectional,
+    /// Not yet past the threshold.
+    Pending,
+}
+
+/// The thresholds, and the unidirectional flows the cleanup removed.
+pub struct AsymmetryTracker {
+    tcp_after: Duration,
+    udp_after: Duration,
+    expired_tcp: AtomicU64,
+    expired_udp: AtomicU64,
+}
+
+impl Default for AsymmetryTracker {
+    fn default() -> Self {
+        Self::new(&AsymmetryConfig::default())
+    }
+}
+
+impl AsymmetryTracker {
+    pub fn new(config: &AsymmetryConfig) -> Self {
+        Self {
+            tcp_after: Duration::from_secs(config.tcp_after_seconds),
+            udp_after: Duration::from_secs(config.udp_after_seconds),
+            expired_tcp: AtomicU64::new(0),
+            expired_udp: AtomicU64::new(0),
+        }
+    }
+
+    /// The threshold for TCP and UDP flows to unicast addresses; None for
+    /// everything else.
+    fn threshold(&self, stats: &ConnectionStats) -> Option<Duration> {
+        if matches!(stats.cast, Some(Cast::Multicast | Cast::Broadcast)) {
+            return None;
+        }
+        match stats.protocol.as_str() {
+            "TCP" => Some(self.tcp_after),
+            "UDP" => Some(self.udp_after),
+            _ => None,
+        }
+    }
+
+    fn classify(&self, stats: &ConnectionStats, now: Instant) -> Option<Class> {
+        let threshold = self.threshold(stats)?;
+        Some(if stats.bidirectional {
+            Class::Bidirectional
+        } else if now.saturating_duration_since(stats.first_seen) >= threshold {
+            Class::Unidirectional
+        } else {
+            Class::Pending
+        })
+    }
+
+    /// Count an entry the cleanup removed if it was unidirectional.
+    pub fn record_expired(&self, stats: &ConnectionStats, now: Instant) {
+        if self.classify(stats, now) == Some(Class::Unidirectional) {
+            let counter = match stats.protocol.as_str() {
+                "TCP" => &self.expired_tcp,
+                _ => &self.expired_udp,
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Counts over the live table, and the `limit` unidirectional flows
+    /// with the most packets.
+    pub fn report(&self, traffic: &TrafficState, limit: usize) -> AsymmetryReport {
+        let now = Instant::now();
+        let (mut tcp, mut udp) = (DirectionCounts::default(), DirectionCounts::default());
+        let mut unidirectional = Vec::new();
+        for entry in traffic.connections.iter() {
+            let stats = entry.value();
+            let Some(class) = self.classify(stats, now) else {
+                continue;
+            };
+            let counts = if stats.protocol == "TCP" { &mut tcp } else { &mut udp };
+            match class {
+                Class::Bidirectional => counts.bidirectional += 1,
+                Class::Pending => counts.pending += 1,
+                Class::Unidirectional => {
+                    counts.unidirectional += 1;
+                    unidirectional.push(ConnectionEntry::new(*entry.key(), stats.clone()));
+                }
+            }
+        }
+        unidirectional.sort_by_key(|e| std::cmp::Reverse(e.stats.packets_count));
+        unidirectional.truncate(limit);
+        AsymmetryReport {
+            tcp_after_seconds: self.tcp_after.as_secs(),
+            udp_after_seconds: self.udp_after.as_secs(),
+            tcp,
+            udp,
+            expired_unidirectional_tcp: self.expired_tcp.load(Ordering::Relaxed),
+            expired_unidirectional_udp: self.expired_udp.load(Ordering::Relaxed),
+            samples: unidirectional,
+        }
+    }
+}
+
+api_schema! {
+    /// Live flows of one protocol by whether their reverse direction was
+    /// seen.
+    #[derive(Debug, Default, Serialize)]
+    pub struct DirectionCounts {
+        bidirectional: u64,
+        unidirectional: u64,
+        /// One-way so far, but younger than the threshold.
+        pending: u64,
+    }
+}
+
+api_schema! {
+    #[derive(Debug, Serialize)]
+    pub struct AsymmetryReport {
+        tcp_after_seconds: u64,
+        udp_after_seconds: u64,
+        tcp: DirectionCounts,
+        udp: DirectionCounts,
+        /// Unidirectional flows removed by the cleanup since startup.
+        expired_unidirectional_tcp: u64,
+        expired_unidirectional_udp: u64,
+        /// Live unidirectional flows with the most packets.
+        pub samples: Vec<ConnectionEntry>,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::PacketMetadata;
+    use crate::test_support;
+
+    fn packet(src: &str, dst: &str, src_port: u16, dst_port: u16, protocol: &str) -> PacketMetadata {
+        PacketMetadata {
+            src_ip: src.into(),
+            dst_ip: dst.into(),
+            src_port,
+            dst_port,
+            protocol: protocol.into(),
+            length: 100,
+            ..test_support::packet()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_one_way_and_two_way_flows() {
+        let state = TrafficState::new();
+        // A TCP flow with replies, a TCP flow and a syslog stream without,
+        // and one-way multicast.
+        state.update(&packet("10.0.0.5", "93.184.216.34", 51000, 443, "TCP"));
+        state.update(&packet("93.184.216.34", "10.0.0.5", 443, 51000, "TCP"));
+        state.update(&packet("10.0.0.5", "198.51.100.7", 51001, 443, "TCP"));
+        state.update(&packet("10.0.0.5", "198.51.100.7", 51001, 443, "TCP"));
+        state.update(&packet("10.0.0.5", "10.0.0.9", 514, 514, "UDP"));
+        state.update(&packet("10.0.0.5", "224.0.0.251", 5353, 5353, "UDP"));
+        state.sample_rates();
+
+        let json = serde_json::to_value(state.connection_pair(&crate::state::ConnectionKey {
+            src_ip: "10.0.0.5".parse().unwrap(),
+            src_port: 51000,
+            dst_ip: "93.184.216.34".parse().unwrap(),
+            dst_port: 443,
+        }))
+        .unwrap();
+        assert_eq!(json[0]["stats"]["bidirectional"], true);
+        assert_eq!(json[1]["stats"]["bidirectional"], true);
+
+        // Too young to call.
+        let report = state.asymmetry.report(&state, 10);
+        assert_eq!((report.tcp.bidirectional, report.tcp.pending), (2, 1));
+        assert_eq!((report.udp.unidirectional, report.udp.pending), (0, 1));
+
+        // Past the TCP threshold but not the UDP one.
+        tokio::time::advance(Duration::from_secs(10)).await;
+        let report = state.asymmetry.report(&state, 10);
+        assert_eq!((report.tcp.bidirectional, report.tcp.unidirectional), (2, 1));
+        assert_eq!(report.udp.pending, 1);
+        assert_eq!(report.samples.len(), 1);
+        assert_eq!(report.samples[0].connection.dst_ip.to_string(), "198.51.100.7");
+        assert_eq!(report.samples[0].stats.packets_count, 2);
+
+        tokio::time::advance(Duration::from_secs(110)).await;
+        let report = state.asymmetry.report(&state, 10);
+        assert_eq!((report.udp.unidirectional, report.udp.pending), (1, 0));
+        assert_eq!(report.samples.len(), 2);
+        // Multicast is one-way by nature and not counted.
+        assert_eq!(report.udp.bidirectional, 0);
+
+        // Expired one-way flows are counted, the two-way ones are not.
+        state.cleanup_stale_connections(Duration::from_secs(60));
+        let report = state.asymmetry.report(&state, 10);
+        assert_eq!(report.expired_unidirectional_tcp, 1);
+        assert_eq!(report.expired_unidirectional_udp, 1);
+        assert_eq!((report.tcp.bidirectional, report.udp.unidirectional), (0, 0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reply_arriving_late_pairs_both_directions() {
+        let state = TrafficState::new();
+        state.update(&packet("10.0.0.5", "198.51.100.7", 51001, 443, "TCP"));
+        tokio::time::advance(Duration::from_secs(30)).await;
+        state.sample_rates();
+        assert_eq!(state.asymmetry.report(&state, 10).tcp.unidirectional, 1);
+
+        state.update(&packet("198.51.100.7", "10.0.0.5", 443, 51001, "TCP"));
+        state.sample_rates();
+        let report = state.asymmetry.report(&state, 10);
+        assert_eq!((report.tcp.bidirectional, report.tcp.unidirectional), (2, 0));
+        assert!(report.samples.is_empty());
+    }
+}
@@ -0,0 +1,499 @@
+use anyhow::Context;
+use aya::maps::{Array, MapData, MapInfo};
+use aya::programs::{tc, ProgramError, SchedClassifier, TcAttachType, Xdp, XdpFlags};
+use aya::Ebpf;
+use clap::Args;
+use dashmap::DashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::config::{Config, Hook, XdpMode};
+use crate::version::{LoadedMap, LoadedObject, LoadedProgram};
+
+/// Name of the TC classifier, which is also the name of its netlink filter.
+const TC_PROGRAM: &str = "ayaflow";
+
+/// Name of the XDP program.
+const XDP_PROGRAM: &str = "ayaflow_xdp";
+
+/// Lines of a rejected program's verifier log quoted in the error; the
+/// kernel's reason for rejecting it comes last.
+const VERIFIER_LOG_TAIL: usize = 20;
+
+/// The kernel CONFIG array once taken from the object.  The blocklist and
+/// the port prefilter share it, each writing its own index.
+pub type ConfigMap = Arc<Mutex<Array<MapData, u32>>>;
+
+/// What `attach_programs` set up on the interface.
+#[derive(Debug, Default)]
+pub struct Attachment {
+    /// Human-readable description of each attached hook.
+    pub hooks: Vec<String>,
+    /// Whether the clsact qdisc was added by us rather than found in place.
+    pub created_qdisc: bool,
+}
+
+/// Attach the eBPF program(s) selected by `hook` / `xdp_mode` / `direction`.
+///
+/// XDP only sees received packets, so egress capture always goes through the
+/// TC classifier.  When XDP cannot be attached at all, ingress falls back to
+/// TC as well.
+pub fn attach_programs(
+    bpf: &mut Ebpf,
+    iface: &str,
+    config: &Config,
+) -> anyhow::Result<Attachment> {
+    let direction = config.direction;
+    let mut attached = Attachment::default();
+    let mut tc_ingress = direction.ingress() && config.hook == Hook::Tc;
+
+    if config.hook == Hook::Xdp {
+        if direction.ingress() {
+            match attach_xdp(bpf, iface, config.xdp_mode) {
+                Ok(desc) => attached.hooks.push(desc),
+                Err(e) => {
+                    tracing::warn!(
+                        "XDP is not supported on {} ({:#}), falling back to TC ingress",
+                        iface,
+                        e
+                    );
+                    tc_ingress = true;
+                }
+            }
+        } else {
+            tracing::warn!("XDP only observes ingress; direction=egress uses the TC hook alone");
+        }
+        if direction.egress() {
+            tracing::info!("XDP has no egress path, pairing it with the TC egress hook");
+        }
+    }
+
+    if tc_ingress || direction.egress() {
+        let explain = |e| explain_error(iface, config.manage_qdisc, e);
+        if config.manage_qdisc {
+            // If the clsact qdisc already exists (EEXIST), that is fine.
+            match tc::qdisc_add_clsact(iface) {
+                Ok(()) => attached.created_qdisc = true,
+                Err(e) if e.raw_os_error() == Some(17) => {
+                    tracing::debug!("clsact qdisc already exists on {}, reusing", iface);
+                }
+                Err(e) => return Err(explain(e.into())),
+            }
+        }
+        let program: &mut SchedClassifier = bpf.program_mut(TC_PROGRAM).unwrap().try_into()?;
+        program
+            .load()
+            .map_err(|e| explain(load_error(TC_PROGRAM, e)))?;
+        if tc_ingress {
+            program
+                .attach(iface, TcAttachType::Ingress)
+                .map_err(|e| explain(e.into()))?;
+            attached.hooks.push("tc ingress".to_string());
+        }
+        if direction.egress() {
+            program
+                .attach(iface, TcAttachType::Egress)
+                .map_err(|e| explain(e.into()))?;
+            attached.hooks.push("tc egress".to_string());
+        }
+    }
+
+    Ok(attached)
+}
+
+/// Write the verifier log of a rejected `program` to a new file in the
+/// temporary directory and return its path.  The agent runs as root and
+/// the directory is shared, so the name carries the pid, time and a count,
+/// and the file is created, never opened: a file or link planted under
+/// that name fails the write instead of being followed.  Only root can
+/// read it.
+fn save_verifier_log(program: &str, log: &str) -> io::Result<PathBuf> {
+    static SAVED: AtomicU32 = AtomicU32::new(0);
+    let name = format!(
+        "ayaflow-verifier-{}-{}-{}-{}.log",
+        program,
+        std::process::id(),
+        chrono::Utc::now().timestamp_millis(),
+        SAVED.fetch_add(1, Ordering::Relaxed)
+    );
+    let path = std::env::temp_dir().join(name);
+    let mut file = fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(&path)?;
+    file.write_all(log.as_bytes())?;
+    Ok(path)
+}
+
+/// Turn a failed program load into an error that quotes the end of the
+/// verifier log and points at the full log, saved by
+/// [`save_verifier_log`], instead of embedding all of it.
+fn load_error(program: &str, error: ProgramError) -> anyhow::Error {
+    match error {
+        ProgramError::LoadError { io_error, verifier_log } => {
+            rejected(program, io_error, &verifier_log.to_string())
+        }
+        error => error.into(),
+    }
+}
+
+fn rejected(program: &str, io_error: io::Error, log: &str) -> anyhow::Error {
+    let error = anyhow::Error::new(io_error);
+    if log.trim().is_empty() {
+        // Refused before the verifier ran, e.g. for lack of privileges.
+        return error.context(format!("cannot load the {} program", program));
+    }
+    let saved = match save_verifier_log(program, log) {
+        Ok(path) => format!("full verifier log in {}", path.display()),
+        Err(e) => format!("cannot save the verifier log: {}", e),
+    };
+    error.context(format!(
+        "the verifier rejected the {} program ({}); it ends with:\n{}",
+        program,
+        saved,
+        log_tail(log, VERIFIER_LOG_TAIL)
+    ))
+}
+
+/// The last `lines` lines of `log`, without trailing blank lines.
+fn log_tail(log: &str, lines: usize) -> &str {
+    let log = log.trim_end();
+    match log.rmatch_indices('\n').nth(lines.saturating_sub(1)) {
+        Some((newline, _)) => &log[newline + 1..],
+        None => log,
+    }
+}
+
+/// What the kernel reports about the loaded programs and the maps they use.
+/// Programs that were not loaded, such as XDP with `hook: tc`, are left out.
+pub fn loaded_object(bpf: &Ebpf) -> LoadedObject {
+    let mut loaded = LoadedObject::default();
+    let mut map_ids = Vec::new();
+    for (name, program) in bpf.programs() {
+        let Ok(info) = program.info() else {
+            continue;
+        };
+        loaded.programs.push(LoadedProgram {
+            name: name.to_string(),
+            verified_instructions: info.verified_instruction_count(),
+            translated_bytes: info.size_translated(),
+            jited_bytes: info.size_jitted(),
+        });
+        map_ids.extend(info.map_ids().ok().flatten().unwrap_or_default());
+    }
+    map_ids.sort_unstable();
+    map_ids.dedup();
+    loaded.maps = map_ids
+        .into_iter()
+        .filter_map(|id| MapInfo::from_id(id).ok())
+        .map(|info| LoadedMap {
+            name: info.name_as_str().unwrap_or_default().to_string(),
+            key_size: info.key_size(),
+            value_size: info.value_size(),
+            max_entries: info.max_entries(),
+        })
+        .collect();
+    loaded.programs.sort_by(|a, b| a.name.cmp(&b.name));
+    loaded.maps.sort_by(|a, b| a.name.cmp(&b.name));
+    loaded
+}
+
+/// Add an actionable explanation to an attach error when its OS error code
+/// has a well-known cause; other errors are returned unchanged.
+pub fn explain_error(iface: &str, manage_qdisc: bool, error: anyhow::Error) -> anyhow::Error {
+    let errno = error
+        .chain()
+        .find_map(|e| e.downcast_ref::<io::Error>()?.raw_os_error());
+    match errno.and_then(|errno| attach_hint(iface, errno, manage_qdisc)) {
+        Some(hint) => error.context(hint),
+        None => error,
+    }
+}
+
+fn attach_hint(iface: &str, errno: i32, manage_qdisc: bool) -> Option<String> {
+    match errno {
+        // EPERM, EACCES
+        1 | 13 => Some(format!(
+            "permission denied on {}: loading and attaching eBPF needs root or CAP_BPF, \
+             CAP_NET_ADMIN and CAP_PERFMON",
+            iface
+        )),
+        // ENODEV
+        19 => Some(format!("interface {} does not exist (see `ip link`)", iface)),
+        // ENETDOWN
+        100 => Some(format!(
+            "interface {} is down; bring it up with `ip link set dev {} up`",
+            iface, iface
+        )),
+        // ENOENT, EINVAL: netlink attach without a clsact qdisc to attach to.
+        2 | 22 if !manage_qdisc => Some(format!(
+            "no clsact qdisc on {} and manage_qdisc is off; create it with \
+             `tc qdisc add dev {} clsact` or enable manage_qdisc",
+            iface, iface
+        )),
+        _ => None,
+    }
+}
+
+/// Delete the clsact qdisc on `iface` unless filters other than ours are
+/// still attached to it.  Returns whether it was deleted.
+///
+/// aya cannot list filters or delete qdiscs, so this goes through `tc`
+/// from iproute2.  Our own filters must already be detached.
+pub fn remove_clsact_if_unused(iface: &str) -> anyhow::Result<bool> {
+    for direction in ["ingress", "egress"] {
+        let filters = run_tc(&["filter", "show", "dev", iface, direction])?;
+        if !filters.trim().is_empty() {
+            return Ok(false);
+        }
+    }
+    run_tc(&["qdisc", "del", "dev", iface, "clsact"])?;
+    Ok(true)
+}
+
+fn run_tc(args: &[&str]) -> anyhow::Result<String> {
+    let output = Command::new("tc")
+        .args(args)
+        .output()
+        .context("cannot run `tc` (is iproute2 installed?)")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "`tc {}` failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Arguments for `ayaflow detach`.
+#[derive(Args, Debug, Clone)]
+pub struct DetachArgs {
+    /// Interface to remove ayaflow's TC filters from.
+    #[arg(short, long)]
+    pub interface: String,
+
+    /// Also delete the clsact qdisc if no other filters remain on it.
+    #[arg(long)]
+    pub remove_qdisc: bool,
+}
+
+/// Remove TC filters left behind by an agent that did not shut down
+/// cleanly.  XDP programs are attached through links that the kernel
+/// releases when the agent exits, so they need no cleanup.
+pub fn detach(args: &DetachArgs) -> anyhow::Result<()> {
+    let iface = args.interface.as_str();
+    let directions = [(TcAttachType::Ingress, "ingress"), (TcAttachType::Egress, "egress")];
+    for (attach_type, name) in directions {
+        match tc::qdisc_detach_program(iface, attach_type, TC_PROGRAM) {
+            Ok(()) => println!("Detached tc {} filter from {}", name, iface),
+            // NotFound: no filter of ours; EINVAL: no clsact qdisc at all.
+            Err(e) if e.kind() == io::ErrorKind::NotFound || e.raw_os_error() == Some(22) => {
+                println!("No tc {} filter on {}", name, iface);
+            }
+            Err(e) => return Err(explain_error(iface, true, e.into())),
+        }
+    }
+    if args.remove_qdisc {
+        if remove_clsact_if_unused(iface)? {
+            println!("Removed clsact qdisc from {}", iface);
+        } else {
+            println!("Kept clsact qdisc on {}: other filters are still attached", iface);
+        }
+    }
+    Ok(())
+}
+
+/// Load and attach the XDP program, degrading from driver to SKB mode.
+fn attach_xdp(bpf: &mut Ebpf, iface: &str, mode: XdpMode) -> anyhow::Result<String> {
+    let program: &mut Xdp = bpf
+        .program_mut(XDP_PROGRAM)
+        .ok_or_else(|| anyhow::anyhow!("eBPF object has no {} program", XDP_PROGRAM))?
+        .try_into()?;
+    program.load().map_err(|e| load_error(XDP_PROGRAM, e))?;
+
+    if mode == XdpMode::Driver {
+        match program.attach(iface, XdpFlags::DRV_MODE) {
+            Ok(_) => return Ok("xdp ingress (driver)".to_string()),
+            Err(e) => {
+                tracing::warn!(
+                    "XDP driver mode failed on {} ({}), retrying in skb mode",
+                    iface,
+                    e
+                );
+            }
+        }
+    }
+
+    program.attach(iface, XdpFlags::SKB_MODE)?;
+    Ok("xdp ingress (skb)".to_string())
+}
+
+/// Guess whether `iface` delivers bare IP packets (no Ethernet header) from
+/// its ARPHRD type in sysfs.  Unknown interfaces are assumed to be Ethernet.
+pub fn detect_l3_interface(iface: &str) -> bool {
+    fs::read_to_string(format!("/sys/class/net/{}/type", iface))
+        .ok()
+        .and_then(|t| t.trim().parse::<u16>().ok())
+        .is_some_and(link_type_is_l3)
+}
+
+/// ARPHRD link types whose packets start at the IP header.  Loopback (772)
+/// is not one of them: the kernel hands TC/XDP a zeroed Ethernet header.
+fn link_type_is_l3(arphrd: u16) -> bool {
+    matches!(
+        arphrd,
+        512     // ARPHRD_PPP
+        | 519   // ARPHRD_RAWIP
+        | 768   // ARPHRD_TUNNEL (ipip)
+        | 769   // ARPHRD_TUNNEL6
+        | 776   // ARPHRD_SIT
+        | 778   // ARPHRD_IPGRE
+        | 65534 // ARPHRD_NONE (tun, WireGuard)
+    )
+}
+
+/// Interface index to name, read from sysfs.  A miss rescans the directory
+/// so interfaces created after startup are picked up; indexes that are still
+/// unknown are named `if<index>`.
+pub struct InterfaceNames {
+    sysfs: PathBuf,
+    names: DashMap<u32, String>,
+}
+
+impl InterfaceNames {
+    pub fn new() -> Self {
+        Self::from_sysfs("/sys/class/net")
+    }
+
+    fn from_sysfs(dir: impl Into<PathBuf>) -> Self {
+        let names = Self {
+            sysfs: dir.into(),
+            names: DashMap::new(),
+        };
+        names.refresh();
+        names
+    }
+
+    /// Name of the interface with index `ifindex`.  Index 0 (not known,
+    /// e.g. rows written before interfaces were recorded) is the empty string.
+    pub fn name(&self, ifindex: u32) -> String {
+        if ifindex == 0 {
+            return String::new();
+        }
+        if let Some(name) = self.names.get(&ifindex) {
+            return name.clone();
+        }
+        self.refresh();
+        self.names
+            .entry(ifindex)
+            .or_insert_with(|| format!("if{}", ifindex))
+            .clone()
+    }
+
+    fn refresh(&self) {
+        for (index, name) in scan_interfaces(&self.sysfs) {
+            self.names.insert(index, name);
+        }
+    }
+}
+
+/// `(ifindex, name)` for every interface under a sysfs `class/net` directory.
+fn scan_interfaces(dir: &Path) -> Vec<(u32, String)> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let index = fs::read_to_string(entry.path().join("ifindex")).ok()?;
+            let index = index.trim().parse().ok()?;
+            Some((index, entry.file_name().to_string_lossy().into_owned()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_link_type_detection() {
+        assert!(!link_type_is_l3(1)); // ARPHRD_ETHER
+        assert!(!link_type_is_l3(772)); // ARPHRD_LOOPBACK
+        assert!(link_type_is_l3(65534));
+        assert!(link_type_is_l3(512));
+        assert!(!detect_l3_interface("ayaflow-no-such-iface"));
+    }
+
+    #[test]
+    fn test_attach_error_hints() {
+        let hint = |errno, manage| attach_hint("eth0", errno, manage).unwrap_or_default();
+        assert!(hint(1, true).contains("CAP_NET_ADMIN"));
+        assert!(hint(13, true).contains("CAP_NET_ADMIN"));
+        assert!(hint(100, true).contains("ip link set dev eth0 up"));
+        assert!(hint(19, true).contains("does not exist"));
+        assert!(hint(22, false).contains("tc qdisc add dev eth0 clsact"));
+        // With a managed qdisc, EINVAL has no single well-known cause.
+        assert_eq!(attach_hint("eth0", 22, true), None);
+
+        let raw = anyhow::Error::from(io::Error::from_raw_os_error(1)).context("attach failed");
+        let explained = format!("{:#}", explain_error("eth0", true, raw));
+        assert!(explained.starts_with("permission denied on eth0"), "{}", explained);
+        assert!(explained.contains("attach failed"), "{}", explained);
+    }
+
+    #[test]
+    fn test_verifier_log_tail() {
+        let log = "0: (b7) r0 = 0\n1: (95) exit\nR0 !read_ok\nprocessed 2 insns\n\n";
+        assert_eq!(log_tail(log, 2), "R0 !read_ok\nprocessed 2 insns");
+        assert_eq!(log_tail(log, 10), log.trim_end());
+        assert_eq!(log_tail("", 3), "");
+
+        let program = format!("test-{}", std::process::id());
+        let error = rejected(&program, io::Error::from_raw_os_error(13), log);
+        let message = format!("{:#}", error);
+        assert!(message.contains("processed 2 insns"), "{}", message);
+        // The errno stays reachable for `explain_error`.
+        assert!(format!("{:#}", explain_error("eth0", true, error)).contains("CAP_BPF"));
+        let path = message.split("full verifier log in ").nth(1).unwrap();
+        let path = Path::new(path.split(')').next().unwrap());
+        assert_eq!(fs::read_to_string(path).unwrap(), log);
+        assert_eq!(fs::metadata(path).unwrap().permissions().mode() & 0o777, 0o600);
+        // A second rejection never reuses the first one's file.
+        let again = save_verifier_log(&program, log).unwrap();
+        assert_ne!(again, path);
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(again);
+
+        // Refused before verification: no log to save.
+        let message = format!("{:#}", rejected("none", io::Error::from_raw_os_error(1), "\n"));
+        assert!(message.starts_with("cannot load the none program"), "{}", message);
+    }
+
+    #[test]
+    fn test_interface_names_rescan_on_miss() {
+        let dir = std::env::temp_dir().join(format!("ayaflow-test-net-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let add = |name: &str, index: u32| {
+            fs::create_dir_all(dir.join(name)).unwrap();
+            fs::write(dir.join(name).join("ifindex"), format!("{}\n", index)).unwrap();
+        };
+        add("lo", 1);
+        add("eth0", 2);
+
+        let names = InterfaceNames::from_sysfs(&dir);
+        assert_eq!(names.name(2), "eth0");
+        assert_eq!(names.name(0), "");
+
+        // Created after startup: found by the rescan a miss triggers.
+        add("wg0", 7);
+        assert_eq!(names.name(7), "wg0");
+        assert_eq!(names.name(9), "if9");
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,424 @@
+//! Hostnames for packets stored without them: `ayaflow backfill-dns` and
+//! `POST /api/admin/backfill-dns`.
+//!
+//! Rows captured before `resolve_dns` was enabled have no hostnames.  The
+//! job walks the packets table in row order, a batch at a time, resolves the
+//! addresses of the batch that have no `hostnames` entry through a
+//! `DnsCache` and records the names found in one transaction that also
+//! records how far it got, so an interrupted run
+//! resumes after the last committed batch.  Lookups are paced and only a
+//! few run at once; answers the cache already holds are not paced.
+
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Context;
+use clap::Args;
+use tokio::task::JoinSet;
+use tokio::time::{interval, Duration, Interval, MissedTickBehavior};
+
+use crate::config::SqliteConfig;
+use crate::dns::DnsCache;
+use crate::storage::{Storage, StorageBackend, StorageResult, BACKFILL_POSITION_KEY};
+
+pub use ayaflow_common::api::{BackfillProgress, BackfillState};
+
+/// Upper bounds for the pacing options.
+pub const MAX_LOOKUPS_PER_SECOND: u32 = 1000;
+pub const MAX_CONCURRENCY: usize = 64;
+pub const MAX_BATCH_ROWS: usize = 50_000;
+
+/// How long a backfill keeps each answer; a run rarely takes longer, and
+/// an address seen in every batch is then looked up once.
+const CACHE_TTL: Duration = Duration::from_secs(3600);
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// What a backfill run covers and how hard it may press the resolver.
+#[derive(Debug, Clone)]
+pub struct BackfillOptions {
+    /// Only rows captured at or after this time (epoch ms).
+    pub since: Option<i64>,
+    /// Rows read and updated per transaction.
+    pub batch_rows: usize,
+    pub lookups_per_second: u32,
+    /// Lookups in flight at once.
+    pub concurrency: usize,
+    /// Leave private, loopback, link-local and other unroutable addresses
+    /// alone; their PTR queries mostly go unanswered.
+    pub skip_private: bool,
+    /// Start from the first row instead of the saved position.
+    pub restart: bool,
+}
+
+impl Default for BackfillOptions {
+    fn default() -> Self {
+        Self {
+            since: None,
+            batch_rows: 1000,
+            lookups_per_second: 20,
+            concurrency: 4,
+            skip_private: false,
+            restart: false,
+        }
+    }
+}
+
+impl BackfillOptions {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(1..=MAX_BATCH_ROWS).contains(&self.batch_rows) {
+            return Err(format!("batch must be between 1 and {}", MAX_BATCH_ROWS));
+        }
+        if !(1..=MAX_LOOKUPS_PER_SECOND).contains(&self.lookups_per_second) {
+            return Err(format!("rate must be between 1 and {}", MAX_LOOKUPS_PER_SECOND));
+        }
+        if !(1..=MAX_CONCURRENCY).contains(&self.concurrency) {
+            return Err(format!("concurrency must be between 1 and {}", MAX_CONCURRENCY));
+        }
+        Ok(())
+    }
+}
+
+/// Arguments for `ayaflow backfill-dns`.
+#[derive(Args, Debug, Clone)]
+pub struct BackfillArgs {
+    /// SQLite database to update.
+    #[arg(long, default_value = "traffic.db")]
+    pub db: String,
+
+    /// Only packets captured at or after this time: RFC 3339 or epoch ms.
+    #[arg(long, value_parser = crate::cli::parse_time)]
+    pub since: Option<i64>,
+
+    /// Rows updated per transaction.
+    #[arg(long, default_value_t = 1000)]
+    pub batch: usize,
+
+    /// Reverse lookups started per second, at most.
+    #[arg(long, default_value_t = 20)]
+    pub rate: u32,
+
+    /// Reverse lookups in flight at once.
+    #[arg(long, default_value_t = 4)]
+    pub concurrency: usize,
+
+    /// Skip private, loopback, link-local and other unroutable addresses.
+    #[arg(long)]
+    pub skip_private: bool,
+
+    /// Start again from the first row instead of where the last run stopped.
+    #[arg(long)]
+    pub restart: bool,
+}
+
+/// The backfill run started through the API, if any.  One runs at a time.
+#[derive(Default)]
+pub struct BackfillJob {
+    progress: Arc<Mutex<BackfillProgress>>,
+    /// Resolves for every run instead of a fresh cache per run.
+    cache: Option<Arc<DnsCache>>,
+}
+
+impl BackfillJob {
+    /// A job whose runs resolve through `cache`.
+    #[cfg(test)]
+    pub fn with_cache(cache: Arc<DnsCache>) -> Self {
+        Self { cache: Some(cache), ..Default::default() }
+    }
+
+    pub fn progress(&self) -> BackfillProgress {
+        self.progress.lock().unwrap().clone()
+    }
+
+    /// Start a run in the background.  Returns None, starting nothing, while
+    /// one is already running.
+    pub fn start(
+        &self,
+        storage: Arc<dyn StorageBackend>,
+        options: BackfillOptions,
+    ) -> Option<BackfillProgress> {
+        let started = {
+            let mut progress = self.progress.lock().unwrap();
+            if progress.state == BackfillState::Running {
+                return None;
+            }
+            *progress = BackfillProgress { state: BackfillState::Running, ..Default::default() };
+            progress.clone()
+        };
+        let progress = self.progress.clone();
+        let cache = self.cache.clone();
+        tokio::spawn(async move {
+            let cache = cache.unwrap_or_else(|| Arc::new(DnsCache::new(CACHE_TTL, LOOKUP_TIMEOUT)));
+            let report = |p: &BackfillProgress| *progress.lock().unwrap() = p.clone();
+            let result = run(storage, cache, &options, report).await;
+            let mut progress = progress.lock().unwrap();
+            match result {
+                Ok(done) => {
+                    tracing::info!(
+                        "Hostname backfill finished: {} hostnames found for {} rows",
+                        done.hostnames_filled,
+                        done.rows_scanned
+                    );
+                    *progress = done;
+                }
+                Err(e) => {
+                    tracing::warn!("Hostname backfill failed: {}", e);
+                    progress.state = BackfillState::Failed;
+                    progress.error = Some(e.to_string());
+                }
+            }
+        });
+        Some(started)
+    }
+}
+
+/// `ayaflow backfill-dns`: progress on stderr after every batch, the final
+/// figures as JSON on stdout.
+pub async fn run_cli(args: &BackfillArgs) -> anyhow::Result<()> {
+    let options = BackfillOptions {
+        since: args.since,
+        batch_rows: args.batch,
+        lookups_per_second: args.rate,
+        concurrency: args.concurrency,
+        skip_private: args.skip_private,
+        restart: args.restart,
+    };
+    options.validate().map_err(|e| anyhow::anyhow!("--{}", e))?;
+    anyhow::ensure!(Path::new(&args.db).exists(), "no database at {}", args.db);
+    let storage = Storage::open(&args.db, &SqliteConfig::default())
+        .with_context(|| format!("cannot open database {}", args.db))?;
+    let cache = Arc::new(DnsCache::new(CACHE_TTL, LOOKUP_TIMEOUT));
+    let report = |p: &BackfillProgress| {
+        eprintln!(
+            "row {} of {}: {} rows scanned, {} hostnames filled, {} resolved, {} unresolved",
+            p.position,
+            p.last_row,
+            p.rows_scanned,
+            p.hostnames_filled,
+            p.addresses_resolved,
+            p.addresses_unresolved
+        );
+    };
+    let done = run(Arc::new(storage), cache, &options, report).await?;
+    println!("{}", serde_json::to_string_pretty(&done)?);
+    Ok(())
+}
+
+/// Backfill until no rows lacking a hostname are left after the position,
+/// calling `report` after every committed batch.
+pub async fn run(
+    storage: Arc<dyn StorageBackend>,
+    cache: Arc<DnsCache>,
+    options: &BackfillOptions,
+    mut report: impl FnMut(&BackfillProgress),
+) -> StorageResult<BackfillProgress> {
+    let mut progress = BackfillProgress { state: BackfillState::Running, ..Default::default() };
+    if !options.restart {
+        let saved = blocking(&storage, |s| s.load_state(BACKFILL_POSITION_KEY)).await?;
+        progress.position = saved.and_then(|p| p.parse().ok()).unwrap_or(0);
+    }
+    let mut pace = interval(Duration::from_secs(1) / options.lookups_per_second.max(1));
+    pace.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        let (after, since, limit) = (progress.position, options.since, options.batch_rows);
+        let batch = blocking(&storage, move |s| s.unresolved_rows(after, since, limit)).await?;
+        progress.last_row = batch.last_id;
+        if batch.rows == 0 {
+            break;
+        }
+        let names = resolve(&cache, batch.addresses, options, &mut pace, &mut progress).await;
+        let through = batch.through_id;
+        let filled = blocking(&storage, move |s| s.fill_hostnames(through, &names)).await?;
+        progress.position = through;
+        progress.rows_scanned += batch.rows as u64;
+        progress.hostnames_filled += filled as u64;
+        report(&progress);
+    }
+    progress.state = BackfillState::Finished;
+    Ok(progress)
+}
+
+/// Look up `addresses`, returning the `(ip, hostname)` pairs found.
+async fn resolve(
+    cache: &Arc<DnsCache>,
+    addresses: Vec<String>,
+    options: &BackfillOptions,
+    pace: &mut Interval,
+    progress: &mut BackfillProgress,
+) -> Vec<(String, String)> {
+    let mut found = Vec::new();
+    let mut record = |progress: &mut BackfillProgress, ip: String, name: Option<String>| {
+        match name {
+            Some(name) => {
+                progress.addresses_resolved += 1;
+                found.push((ip, name));
+            }
+            None => progress.addresses_unresolved += 1,
+        }
+    };
+    let mut pending = addresses.into_iter();
+    let mut lookups = JoinSet::new();
+    loop {
+        while lookups.len() < options.concurrency {
+            let Some(ip) = pending.next() else { break };
+            let skip = match ip.parse() {
+                Ok(addr) => options.skip_private && is_unroutable(addr),
+                // Rows for non-IP frames carry no address to look up.
+                Err(_) => true,
+            };
+            if skip {
+                progress.addresses_skipped += 1;
+                continue;
+            }
+            if let Some(cached) = cache.peek(&ip) {
+                record(progress, ip, cached);
+                continue;
+            }
+            pace.tick().await;
+            let cache = cache.clone();
+            lookups.spawn(async move {
+                let name = cache.resolve(&ip).await;
+                (ip, name)
+            });
+        }
+        match lookups.join_next().await {
+            Some(Ok((ip, name))) => record(progress, ip, name),
+            Some(Err(_)) => progress.addresses_unresolved += 1,
+            None => break,
+        }
+    }
+    found
+}
+
+/// Addresses no public resolver has a PTR record for.
+fn is_unroutable(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => {
+            v4.is_private()
+                || v4.is_loopback()
+                || v4.is_link_local()
+                || v4.is_unspecified()
+                || v4.is_broadcast()
+                || v4.is_documentation()
+                || v4.is_multicast()
+                // 100.64.0.0/10, carrier-grade NAT.
+                || (v4.octets()[0] == 100 && v4.octets()[1] & 0xc0 == 64)
+        }
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => is_unroutable(v4.into()),
+            None => {
+                v6.is_loopback()
+                    || v6.is_unspecified()
+                    || v6.is_unique_local()
+                    || v6.is_unicast_link_local()
+                    || v6.is_multicast()
+            }
+        },
+    }
+}
+
+/// Run a storage call on the blocking pool, passing on a panic in it.
+async fn blocking<T, F>(storage: &Arc<dyn StorageBackend>, call: F) -> StorageResult<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&dyn StorageBackend) -> StorageResult<T> + Send + 'static,
+{
+    let storage = storage.clone();
+    match tokio::task::spawn_blocking(move || call(storage.as_ref())).await {
+        Ok(result) => result,
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::PacketMetadata;
+    use crate::storage::PacketFilter;
+    use crate::test_support;
+
+    fn packet(src_ip: &str, dst_ip: &str) -> PacketMetadata {
+        PacketMetadata {
+            timestamp: 1000,
+            src_ip: src_ip.into(),
+            dst_ip: dst_ip.into(),
+            dst_port: 53,
+            protocol: "UDP".into(),
+            length: 80,
+            payload_length: 52,
+            direction: "egress".into(),
+            ..test_support::packet()
+        }
+    }
+
+    #[test]
+    fn test_unroutable_addresses() {
+        for ip in ["10.1.2.3", "127.0.0.1", "169.254.1.1", "100.64.0.1", "fd00::1", "fe80::1"] {
+            assert!(is_unroutable(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["8.8.8.8", "100.128.0.1", "2606:4700::1111", "::ffff:1.1.1.1"] {
+            assert!(!is_unroutable(ip.parse().unwrap()), "{}", ip);
+        }
+        assert!(is_unroutable("::ffff:192.168.1.1".parse().unwrap()));
+        assert!(BackfillOptions::default().validate().is_ok());
+        let paced = BackfillOptions { lookups_per_second: 0, ..BackfillOptions::default() };
+        assert!(paced.validate().unwrap_err().starts_with("rate"));
+    }
+
+    #[tokio::test]
+    async fn test_backfill_resumes_and_skips_private() {
+        let storage = Storage::new(":memory:").unwrap();
+        storage
+            .flush(&mut vec![packet("127.0.0.1", "10.0.0.1"), packet("10.0.0.1", "127.0.0.1")])
+            .unwrap();
+        let storage: Arc<dyn StorageBackend> = Arc::new(storage);
+        let cache = DnsCache::new(CACHE_TTL, LOOKUP_TIMEOUT);
+        let cache = Arc::new(cache.with_lookup(|_| Some("localhost".into())));
+        let loopback = cache.resolve("127.0.0.1").await;
+        assert_eq!(loopback.as_deref(), Some("localhost"));
+
+        let options = BackfillOptions { batch_rows: 1, skip_private: true, ..Default::default() };
+        let mut batches = 0;
+        let done = run(storage.clone(), cache.clone(), &options, |_| batches += 1).await.unwrap();
+        assert_eq!(done.state, BackfillState::Finished);
+        assert_eq!((batches, done.rows_scanned, done.position, done.last_row), (2, 2, 2, 2));
+        // Loopback is unroutable too, so nothing is looked up.
+        assert_eq!((done.addresses_skipped, done.addresses_resolved), (4, 0));
+        assert_eq!(done.hostnames_filled, 0);
+
+        // A second run starts after the saved position and finds nothing.
+        let options = BackfillOptions { skip_private: false, ..Default::default() };
+        let again = run(storage.clone(), cache.clone(), &options, |_| {}).await.unwrap();
+        assert_eq!((again.rows_scanned, again.position), (0, 2));
+
+        // From the start, loopback comes from the cache without a lookup.
+        let restart = BackfillOptions { restart: true, ..options };
+        let done = run(storage.clone(), cache, &restart, |_| {}).await.unwrap();
+        assert_eq!((done.rows_scanned, done.addresses_skipped), (2, 0));
+        for row in storage.query_packets(&PacketFilter::default(), 10).unwrap() {
+            let packet = row.packet;
+            let names = [
+                (packet.src_ip, packet.src_hostname),
+                (packet.dst_ip, packet.dst_hostname),
+            ];
+            for (ip, hostname) in names.into_iter().filter(|(ip, _)| ip == "127.0.0.1") {
+                assert_eq!(hostname, loopback, "{}", ip);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_job_runs_one_at_a_time() {
+        let storage: Arc<dyn StorageBackend> = Arc::new(Storage::new(":memory:").unwrap());
+        let job = BackfillJob::default();
+        assert_eq!(job.progress().state, BackfillState::Idle);
+        let started = job.start(storage.clone(), BackfillOptions::default()).unwrap();
+        assert_eq!(started.state, BackfillState::Running);
+        assert!(job.start(storage.clone(), BackfillOptions::default()).is_none());
+        while job.progress().state == BackfillState::Running {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert_eq!(job.progress().state, BackfillState::Finished);
+        assert!(job.start(storage, BackfillOptions::default()).is_some());
+    }
+}
@@ -0,0 +1,382 @@
+//! `ayaflow bench`: synthetic load through the real capture pipeline.
+//!
+//! No eBPF is loaded.  Packets for a fixed set of connections go through
+//! `forward_batch` in ring-buffer-sized batches, so they take the same path
+//! as captured events: the live state, the storage channel, and the
+//! supervised SQLite writer with the configured flush and SQLite settings.
+//! Events are always stored raw; `aggregation_window_seconds` is ignored.
+//! The report is one JSON document on stdout.
+
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Context;
+use ayaflow_common::config_check::ConfigProblems;
+use ayaflow_common::filter::Filters;
+use ayaflow_common::Sampler;
+use clap::Args;
+use prometheus_client::encoding::text::encode;
+use prometheus_client::registry::Registry;
+use serde::Serialize;
+use tokio::sync::mpsc;
+use tokio::time::{interval, sleep, Duration, Instant};
+
+use crate::config::Config;
+use crate::health::HealthRegistry;
+use crate::locality::LocalNetworks;
+use crate::state::{KernelInfo, PacketMetadata, TrafficState};
+use crate::storage::{self, StorageMetrics};
+use crate::{forward_batch, RING_BATCH, STORAGE_QUEUE_CAPACITY};
+
+/// Connections get consecutive source addresses from 10.0.0.0.
+const MAX_CONNECTIONS: u32 = 1 << 24;
+
+/// Arguments for `ayaflow bench`.
+#[derive(Args, Debug, Clone)]
+pub struct BenchArgs {
+    /// Events per second to offer; 0 sends as fast as the pipeline accepts.
+    #[arg(long, default_value_t = 0)]
+    pub rate: u64,
+
+    /// Seconds to generate events for.
+    #[arg(long, default_value_t = 10)]
+    pub duration: u64,
+
+    /// Stop after this many events instead, for repeatable comparisons.
+    #[arg(long)]
+    pub events: Option<u64>,
+
+    /// Distinct connections the events are spread over.
+    #[arg(long, default_value_t = 1000)]
+    pub connections: u32,
+
+    /// Database to write; a temporary one, removed afterwards, by default.
+    #[arg(long)]
+    pub db: Option<PathBuf>,
+
+    /// YAML config to take the storage, sqlite and local_networks sections
+    /// from.
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BenchReport {
+    /// As requested; 0 is unlimited.
+    pub target_rate: u64,
+    pub connections: u32,
+    pub events_sent: u64,
+    /// Time spent generating, and the rate the pipeline took events at.
+    pub generate_seconds: f64,
+    pub events_per_second: f64,
+    /// From the last event sent until the writer accounted for it,
+    /// including the wait for the final flush tick.
+    pub drain_seconds: f64,
+    pub rows_stored: u64,
+    /// Rows that failed to insert or went to the spill file.
+    pub rows_failed: u64,
+    pub rows_per_second: f64,
+    pub live_connections: usize,
+    pub backlog: BacklogReport,
+    pub flushes: FlushReport,
+    /// Absent where `/proc/self/status` cannot be read.
+    pub memory: Option<MemoryReport>,
+}
+
+/// Storage channel depth, sampled after every batch.
+#[derive(Debug, Serialize)]
+pub struct BacklogReport {
+    pub capacity: usize,
+    pub max: usize,
+    pub mean: f64,
+}
+
+/// Writer transactions.  Quantiles are the upper bounds of the histogram
+/// buckets behind `ayaflow_storage_flush_duration_seconds`.
+#[derive(Debug, Default, Serialize)]
+pub struct FlushReport {
+    pub count: u64,
+    pub mean_ms: f64,
+    pub p50_ms: Option<f64>,
+    pub p99_ms: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MemoryReport {
+    pub rss_start_bytes: u64,
+    pub rss_end_bytes: u64,
+    pub rss_growth_bytes: i64,
+    pub rss_peak_bytes: u64,
+}
+
+/// One packet per connection, cloned for each event like a decoded one.
+fn templates(connections: u32) -> Vec<PacketMetadata> {
+    (0..connections)
+        .map(|i| PacketMetadata {
+            timestamp: 0,
+            src_ip: Ipv4Addr::from(0x0a00_0000 + i).to_string(),
+            dst_ip: "192.0.2.10".into(),
+            src_port: 40000 + (i % 20000) as u16,
+            dst_port: 443,
+            protocol: "TCP".into(),
+            length: 1500,
+            payload_length: 1448,
+            direction: "ingress".into(),
+            interface: "bench0".into(),
+            flow_direction: None,
+            cast: None,
+            src_mac: None,
+            dst_mac: None,
+            ttl: Some(64),
+            dscp: Some(0),
+            dscp_class: None,
+            icmp_type: None,
+            icmp_code: None,
+            icmp_name: None,
+            src_hostname: None,
+            dst_hostname: None,
+            domain: None,
+            country: None,
+            asn: None,
+            service: None,
+        })
+        .collect()
+}
+
+pub async fn run(args: &BenchArgs) -> anyhow::Result<()> {
+    let report = bench(args).await?;
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
+
+pub async fn bench(args: &BenchArgs) -> anyhow::Result<BenchReport> {
+    anyhow::ensure!(
+        (1..=MAX_CONNECTIONS).contains(&args.connections),
+        "--connections must be between 1 and {}",
+        MAX_CONNECTIONS
+    );
+    let config = match &args.config {
+        Some(path) => Config::from_file(path)
+            .with_context(|| format!("cannot read config {}", path.display()))?,
+        None => Config::default(),
+    };
+    let mut problems = ConfigProblems::default();
+    config.storage.validate(&mut problems);
+    problems.into_result(args.config.as_deref())?;
+    let (db, temporary) = match &args.db {
+        Some(db) => (db.clone(), false),
+        None => {
+            let name = format!("ayaflow-bench-{}.db", std::process::id());
+            (std::env::temp_dir().join(name), true)
+        }
+    };
+
+    let local_networks = LocalNetworks::from_config(&config.local_networks, "eth0");
+    let storage = storage::open_backend(
+        None,
+        &db.to_string_lossy(),
+        local_networks.networks().to_vec(),
+        config.aggregation_key,
+        &config.sqlite,
+        &config.storage,
+        &config.clickhouse,
+        &config.postgres,
+        None,
+    )?;
+    let traffic = TrafficState::new().with_local_networks(local_networks);
+    let health = Arc::new(HealthRegistry::new());
+    let heartbeat = health.register("storage_writer", true, None);
+    let (tx, rx) = mpsc::channel(STORAGE_QUEUE_CAPACITY);
+    let writer = tokio::spawn(storage::supervise_writer(storage.clone(), rx, 0, heartbeat));
+
+    let rss_start = memory_status();
+    let templates = templates(args.connections);
+    let batch_size = match args.rate {
+        0 => RING_BATCH,
+        rate => RING_BATCH.min(rate as usize),
+    };
+    let kernel = vec![KernelInfo::default(); batch_size];
+    // Behind schedule, ticks fire back to back until the rate is made up.
+    let mut pace = (args.rate > 0)
+        .then(|| interval(Duration::from_secs_f64(batch_size as f64 / args.rate as f64)));
+    let limit = args.events.unwrap_or(u64::MAX);
+    let deadline = Duration::from_secs(args.duration);
+
+    let (mut sent, mut next) = (0u64, 0usize);
+    // Every event reaches the writer, whatever `sample_rate` says.
+    let (mut keep_all, everything) = (Sampler::new(1), Filters::default());
+    let (mut backlog_max, mut backlog_sum, mut samples) = (0, 0, 0u64);
+    let start = Instant::now();
+    while sent < limit && (args.events.is_some() || start.elapsed() < deadline) {
+        if let Some(pace) = &mut pace {
+            pace.tick().await;
+        }
+        let timestamp = chrono::Utc::now().timestamp_millis();
+        let count = batch_size.min((limit - sent) as usize);
+        let batch = (0..count)
+            .map(|_| {
+                next = (next + 1) % templates.len();
+                PacketMetadata { timestamp, ..templates[next].clone() }
+            })
+            .collect();
+        let kernel = &kernel[..count];
+        forward_batch(batch, kernel, &tx, &traffic, &mut keep_all, &everything, None, None, None)
+            .await;
+        sent += count as u64;
+        let depth = tx.max_capacity() - tx.capacity();
+        backlog_max = backlog_max.max(depth);
+        backlog_sum += depth;
+        samples += 1;
+    }
+    let generate = start.elapsed();
+    drop(tx);
+
+    // The writer never returns once the channel closes; wait for its
+    // counters to account for every event instead.
+    let drain_start = Instant::now();
+    let drain_timeout = config.storage.flush_interval() * 3 + Duration::from_secs(10);
+    let metrics = storage.metrics();
+    while stored(metrics) + failed(metrics) < sent && drain_start.elapsed() < drain_timeout {
+        sleep(Duration::from_millis(5)).await;
+    }
+    let drain = drain_start.elapsed();
+    writer.abort();
+
+    let rss_end = memory_status();
+    let report = BenchReport {
+        target_rate: args.rate,
+        connections: args.connections,
+        events_sent: sent,
+        generate_seconds: generate.as_secs_f64(),
+        events_per_second: sent as f64 / generate.as_secs_f64().max(f64::EPSILON),
+        drain_seconds: drain.as_secs_f64(),
+        rows_stored: stored(metrics),
+        rows_failed: failed(metrics),
+        rows_per_second: stored(metrics) as f64 / (generate + drain).as_secs_f64(),
+        live_connections: traffic.connections.len(),
+        backlog: BacklogReport {
+            capacity: STORAGE_QUEUE_CAPACITY,
+            max: backlog_max,
+            mean: backlog_sum as f64 / samples.max(1) as f64,
+        },
+        flushes: flush_report(metrics),
+        memory: rss_start.zip(rss_end).map(|((start, _), (end, peak))| MemoryReport {
+            rss_start_bytes: start,
+            rss_end_bytes: end,
+            rss_growth_bytes: end as i64 - start as i64,
+            rss_peak_bytes: peak,
+        }),
+    };
+
+    drop(storage);
+    if temporary {
+        remove_database(&db);
+    }
+    Ok(report)
+}
+
+fn stored(metrics: &StorageMetrics) -> u64 {
+    metrics.rows_inserted.get()
+}
+
+fn failed(metrics: &StorageMetrics) -> u64 {
+    metrics.insert_failures.get() + metrics.spilled_rows.get() + metrics.spill_dropped_rows.get()
+}
+
+/// Read the flush histogram back through its text exposition.
+fn flush_report(metrics: &StorageMetrics) -> FlushReport {
+    let mut registry = Registry::default();
+    registry.register("flush", "", metrics.flush_duration_seconds.clone());
+    let mut text = String::new();
+    if encode(&mut text, &registry).is_err() {
+        return FlushReport::default();
+    }
+    parse_histogram(&text, "flush")
+}
+
+/// Count, mean and bucket quantiles of histogram `name` in `text`.
+fn parse_histogram(text: &str, name: &str) -> FlushReport {
+    let value = |line: &str| line.rsplit(' ').next().and_then(|v| v.parse::<f64>().ok());
+    let mut buckets = Vec::new();
+    let (mut sum, mut count) = (0.0, 0u64);
+    for line in text.lines() {
+        let Some(rest) = line.strip_prefix(name) else { continue };
+        if let Some(bucket) = rest.strip_prefix("_bucket{le=\"") {
+            let le = bucket.split('"').next().and_then(|le| le.parse::<f64>().ok());
+            if let (Some(le), Some(cumulative)) = (le, value(line)) {
+                buckets.push((le, cumulative as u64));
+            }
+        } else if rest.starts_with("_sum ") {
+            sum = value(line).unwrap_or(0.0);
+        } else if rest.starts_with("_count ") {
+            count = value(line).unwrap_or(0.0) as u64;
+        }
+    }
+    let quantile = |q: f64| {
+        let rank = (q * count as f64).ceil().max(1.0) as u64;
+        buckets.iter().find(|(_, cumulative)| *cumulative >= rank).map(|(le, _)| le * 1000.0)
+    };
+    FlushReport {
+        count,
+        mean_ms: if count == 0 { 0.0 } else { sum * 1000.0 / count as f64 },
+        p50_ms: quantile(0.5).filter(|_| count > 0),
+        p99_ms: quantile(0.99).filter(|_| count > 0),
+    }
+}
+
+/// Resident and peak resident set size in bytes.
+fn memory_status() -> Option<(u64, u64)> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let field = |name: &str| {
+        let line = status.lines().find(|line| line.starts_with(name))?;
+        let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+        Some(kb * 1024)
+    };
+    field("VmRSS:").zip(field("VmHWM:"))
+}
+
+fn remove_database(db: &Path) {
+    for suffix in ["", "-wal", "-shm"] {
+        let mut path = db.as_os_str().to_owned();
+        path.push(suffix);
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_histogram() {
+        let text = "# HELP flush .\n# TYPE flush histogram\n\
+                    flush_sum 0.03\nflush_count 4\n\
+                    flush_bucket{le=\"0.001\"} 1\nflush_bucket{le=\"0.01\"} 3\n\
+                    flush_bucket{le=\"0.1\"} 4\nflush_bucket{le=\"+Inf\"} 4\n";
+        let report = parse_histogram(text, "flush");
+        assert_eq!(report.count, 4);
+        assert!((report.mean_ms - 7.5).abs() < 1e-9);
+        assert_eq!(report.p50_ms, Some(10.0));
+        assert_eq!(report.p99_ms, Some(100.0));
+        assert!(parse_histogram("", "flush").p50_ms.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_bench_stores_every_event() {
+        let args = BenchArgs {
+            rate: 0,
+            duration: 10,
+            events: Some(3000),
+            connections: 50,
+            db: None,
+            config: None,
+        };
+        let report = bench(&args).await.unwrap();
+        assert_eq!(report.events_sent, 3000);
+        assert_eq!(report.rows_stored, 3000);
+        assert_eq!(report.live_connections, 50);
+        assert!(report.flushes.count >= 1);
+        assert!(report.flushes.p99_ms >= report.flushes.p50_ms);
+    }
+}
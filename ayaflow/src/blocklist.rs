@@ -0,0 +1,205 @@
+//! The blocklist the classifier checks every IP packet against.
+//!
+//! Entries live in the kernel's `BLOCKLIST` LPM trie, keyed like event
+//! addresses: IPv4-mapped IPv6, so IPv4 prefixes are 96 bits longer.  A
+//! packet with either address on the list is flagged in its event, and with
+//! enforcement on it is also dropped (TC_ACT_SHOT, or XDP_DROP on the XDP
+//! hook).  Enforcement is fixed at startup and needs both `enforce: true`
+//! and `--enforce-blocklist`; `PUT /api/blocklist` only replaces entries.
+
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+
+use aya::maps::lpm_trie::{Key, LpmTrie};
+use aya::maps::{MapData, PerCpuArray};
+use ayaflow_common::{
+    BLOCKLIST_DROP, BLOCKLIST_FLAG, BLOCKLIST_MAX_ENTRIES, BLOCKLIST_OFF, CONFIG_BLOCKLIST,
+    COUNTER_BLOCKLIST_DROPS,
+};
+use ipnet::IpNet;
+
+use crate::attach::ConfigMap;
+use crate::config::BlocklistConfig;
+use crate::state::TrafficState;
+
+pub use ayaflow_common::api::{BlocklistMatch, BlocklistStatus};
+
+/// An address or CIDR, with host bits cleared.
+pub fn parse_entry(entry: &str) -> Result<IpNet, String> {
+    entry
+        .parse::<IpNet>()
+        .map(|net| net.trunc())
+        .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("invalid address or CIDR {:?}", entry))
+}
+
+/// Parse a whole list, deduplicated and within the kernel map's size.
+pub fn parse_entries<S: AsRef<str>>(entries: &[S]) -> Result<Vec<IpNet>, String> {
+    let mut nets = entries
+        .iter()
+        .map(|entry| parse_entry(entry.as_ref()))
+        .collect::<Result<Vec<_>, _>>()?;
+    nets.sort();
+    nets.dedup();
+    if nets.len() > BLOCKLIST_MAX_ENTRIES as usize {
+        return Err(format!(
+            "at most {} blocklist entries are supported, got {}",
+            BLOCKLIST_MAX_ENTRIES,
+            nets.len()
+        ));
+    }
+    Ok(nets)
+}
+
+/// The key for `net` in an LPM trie the classifier looks event addresses
+/// up in.
+pub fn trie_key(net: &IpNet) -> Key<[u8; 16]> {
+    match net {
+        IpNet::V4(net) => {
+            let addr = net.network().to_ipv6_mapped().octets();
+            Key::new(96 + u32::from(net.prefix_len()), addr)
+        }
+        IpNet::V6(net) => Key::new(u32::from(net.prefix_len()), net.network().octets()),
+    }
+}
+
+/// The kernel maps the entries are loaded into.
+struct KernelMaps {
+    trie: LpmTrie<MapData, [u8; 16], u8>,
+    config: ConfigMap,
+}
+
+impl KernelMaps {
+    /// Move the trie from `old` to `new` entries.  New ones go in before
+    /// stale ones come out, so an address on both lists never slips
+    /// through; a failed insert takes the ones already added back out.
+    fn replace(&mut self, old: &[IpNet], new: &[IpNet], enforcing: bool) -> anyhow::Result<()> {
+        let added: Vec<&IpNet> = new.iter().filter(|net| !old.contains(net)).collect();
+        for (i, net) in added.iter().enumerate() {
+            if let Err(e) = self.trie.insert(&trie_key(net), 1u8, 0) {
+                for net in &added[..i] {
+                    let _ = self.trie.remove(&trie_key(net));
+                }
+                return Err(anyhow::anyhow!("failed to add {} to the blocklist: {}", net, e));
+            }
+        }
+        let mode = match (new.is_empty(), enforcing) {
+            (true, _) => BLOCKLIST_OFF,
+            (false, false) => BLOCKLIST_FLAG,
+            (false, true) => BLOCKLIST_DROP,
+        };
+        self.config.lock().unwrap().set(CONFIG_BLOCKLIST, mode, 0)?;
+        for net in old.iter().filter(|net| !new.contains(net)) {
+            let _ = self.trie.remove(&trie_key(net));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: Vec<IpNet>,
+    kernel: Option<KernelMaps>,
+}
+
+/// The active entries and, once capture starts, the kernel maps they are
+/// loaded into.
+#[derive(Default)]
+pub struct Blocklist {
+    enforcing: bool,
+    inner: Mutex<Inner>,
+}
+
+impl Blocklist {
+    pub fn new(entries: Vec<IpNet>, enforcing: bool) -> Self {
+        Self {
+            enforcing,
+            inner: Mutex::new(Inner { entries, kernel: None }),
+        }
+    }
+
+    /// Entries from a validated config; enforcing only if both switches
+    /// are on.
+    pub fn from_config(config: &BlocklistConfig) -> Self {
+        let entries = parse_entries(&config.entries).unwrap_or_default();
+        Self::new(entries, config.enforcing())
+    }
+
+    pub fn enforcing(&self) -> bool {
+        self.enforcing
+    }
+
+    /// Take over the kernel maps and load the current entries into them.
+    pub fn attach(
+        &self,
+        trie: LpmTrie<MapData, [u8; 16], u8>,
+        config: ConfigMap,
+    ) -> anyhow::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let mut kernel = KernelMaps { trie, config };
+        kernel.replace(&[], &inner.entries, self.enforcing)?;
+        inner.kernel = Some(kernel);
+        Ok(())
+    }
+
+    /// Replace every entry, in the kernel too once attached.
+    pub fn replace(&self, entries: Vec<IpNet>) -> anyhow::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let Inner { entries: old, kernel } = &mut *inner;
+        if let Some(kernel) = kernel {
+            kernel.replace(old, &entries, self.enforcing)?;
+        }
+        *old = entries;
+        Ok(())
+    }
+
+    pub fn status(&self, traffic: &TrafficState) -> BlocklistStatus {
+        let inner = self.inner.lock().unwrap();
+        BlocklistStatus {
+            enforcing: self.enforcing,
+            attached: inner.kernel.is_some(),
+            entries: inner.entries.iter().map(IpNet::to_string).collect(),
+            matched_packets: traffic.blocklisted.packets.load(Ordering::Relaxed),
+            matched_bytes: traffic.blocklisted.bytes.load(Ordering::Relaxed),
+            dropped_packets: traffic.blocklist_drops.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Mirror the kernel's blocklist drop counter into `traffic`.  Called
+/// wherever the other kernel counters are read.
+pub fn sync_drop_counter(counters: &PerCpuArray<MapData, u64>, traffic: &TrafficState) {
+    if let Ok(values) = counters.get(&COUNTER_BLOCKLIST_DROPS, 0) {
+        let drops: u64 = values.iter().sum();
+        traffic.blocklist_drops.store(drops, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_entries() {
+        let nets = parse_entries(&["185.10.20.30", "10.1.2.3/8", "10.0.0.0/8", "2001:db8::1"]);
+        let nets: Vec<String> = nets.unwrap().iter().map(IpNet::to_string).collect();
+        assert_eq!(nets, ["10.0.0.0/8", "185.10.20.30/32", "2001:db8::1/128"]);
+        assert!(parse_entries(&["10.0.0.0/33"]).unwrap_err().contains("10.0.0.0/33"));
+        assert!(parse_entries(&["example.com"]).is_err());
+
+        let key = trie_key(&"192.168.0.0/16".parse().unwrap());
+        assert_eq!(key.prefix_len(), 112);
+        assert_eq!(key.data()[10..14], [0xff, 0xff, 192, 168]);
+    }
+
+    #[test]
+    fn test_replace_without_kernel() {
+        let blocklist = Blocklist::new(parse_entries(&["10.0.0.1"]).unwrap(), false);
+        let traffic = TrafficState::new();
+        blocklist.replace(parse_entries(&["10.0.0.2", "10.0.0.3"]).unwrap()).unwrap();
+        let status = blocklist.status(&traffic);
+        assert_eq!(status.entries, ["10.0.0.2/32", "10.0.0.3/32"]);
+        assert!(!status.attached && !status.enforcing);
+    }
+}
@@ -0,0 +1,202 @@
+//! What the eBPF programs cost, from the kernel's per-program statistics.
+//!
+//! The kernel only counts runs and runtime while statistics are enabled,
+//! through `BPF_ENABLE_STATS` (kernel 5.8) for as long as the returned fd is
+//! held, or the `kernel.bpf_stats_enabled` sysctl on older kernels.  Either
+//! covers every program on the host and adds a little to each run, which is
+//! why `bpf_stats: false` leaves it off.  The sysctl is a host setting that
+//! outlives the process, so it is only set when `bpf_stats: true` is
+//! written out, and only put back if it was 0.  Without support or
+//! permission the failure is logged once and the figures are omitted.
+
+use std::fs;
+use std::os::fd::OwnedFd;
+use std::sync::{Arc, RwLock};
+
+use aya::programs::loaded_programs;
+use aya::sys::{enable_stats, Stats};
+use aya::Ebpf;
+use tokio::task::JoinHandle;
+use tokio::time::Duration;
+
+use crate::state::TrafficState;
+
+pub use ayaflow_common::api::BpfRuntimeSummary;
+
+/// How often the programs' counters are read.
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+const SYSCTL: &str = "/proc/sys/kernel/bpf_stats_enabled";
+
+/// Keeps statistics enabled; dropping it turns them off again unless
+/// something else had them on.
+pub enum StatsGuard {
+    /// Held for `BPF_ENABLE_STATS`; closing it turns them off.
+    Fd { _fd: OwnedFd },
+    /// The sysctl was 0 and has been set; it is reset on drop.
+    Sysctl,
+    /// The sysctl was already on.
+    AlreadyEnabled,
+}
+
+/// Statistics kept on and read while capturing; dropping it stops the
+/// reads, then gives the statistics back.
+pub struct Collector {
+    poller: JoinHandle<()>,
+    _guard: StatsGuard,
+}
+
+impl Drop for Collector {
+    fn drop(&mut self) {
+        self.poller.abort();
+    }
+}
+
+/// Turn statistics on and read the counters of the programs loaded in
+/// `bpf`, or say why they cannot be.  Without `use_sysctl` only
+/// `BPF_ENABLE_STATS` is tried.
+pub fn start(
+    bpf: &Ebpf,
+    traffic: Arc<TrafficState>,
+    use_sysctl: bool,
+) -> Result<Collector, String> {
+    let guard = enable(use_sysctl)?;
+    Ok(Collector {
+        poller: spawn_poller(bpf, traffic),
+        _guard: guard,
+    })
+}
+
+impl Drop for StatsGuard {
+    fn drop(&mut self) {
+        if let StatsGuard::Sysctl = self {
+            if let Err(e) = fs::write(SYSCTL, "0") {
+                tracing::warn!("Could not reset {}: {}", SYSCTL, e);
+            }
+        }
+    }
+}
+
+fn enable(use_sysctl: bool) -> Result<StatsGuard, String> {
+    let syscall_error = match enable_stats(Stats::RunTime) {
+        Ok(fd) => return Ok(StatsGuard::Fd { _fd: fd }),
+        Err(e) => e,
+    };
+    match fs::read_to_string(SYSCTL).map(|value| value.trim().to_string()) {
+        Ok(value) if value != "0" => Ok(StatsGuard::AlreadyEnabled),
+        Ok(_) if !use_sysctl => Err(format!(
+            "{}; set bpf_stats: true to turn on {} instead",
+            syscall_error, SYSCTL
+        )),
+        Ok(_) => match fs::write(SYSCTL, "1") {
+            Ok(()) => Ok(StatsGuard::Sysctl),
+            Err(e) => Err(format!("{}; writing {}: {}", syscall_error, SYSCTL, e)),
+        },
+        Err(e) => Err(format!("{}; reading {}: {}", syscall_error, SYSCTL, e)),
+    }
+}
+
+/// Runs and runtime of one program since it was loaded, counted while
+/// statistics were on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProgramRuntime {
+    pub name: String,
+    pub run_count: u64,
+    pub runtime_ns: u64,
+}
+
+/// The last counters read, empty until the first read or when statistics
+/// are unavailable.
+#[derive(Default)]
+pub struct BpfRuntime {
+    programs: RwLock<Vec<ProgramRuntime>>,
+}
+
+impl BpfRuntime {
+    pub fn update(&self, programs: Vec<ProgramRuntime>) {
+        *self.programs.write().unwrap() = programs;
+    }
+
+    pub fn programs(&self) -> Vec<ProgramRuntime> {
+        self.programs.read().unwrap().clone()
+    }
+
+    /// Totals over every program; None before the first read.
+    pub fn summary(&self) -> Option<BpfRuntimeSummary> {
+        let programs = self.programs.read().unwrap();
+        if programs.is_empty() {
+            return None;
+        }
+        let run_count: u64 = programs.iter().map(|p| p.run_count).sum();
+        let runtime_ns: u64 = programs.iter().map(|p| p.runtime_ns).sum();
+        Some(BpfRuntimeSummary {
+            run_count,
+            runtime_ns,
+            avg_ns_per_packet: match run_count {
+                0 => 0.0,
+                runs => runtime_ns as f64 / runs as f64,
+            },
+        })
+    }
+}
+
+/// Read the counters of the programs loaded in `bpf` every 10 seconds.
+fn spawn_poller(bpf: &Ebpf, traffic: Arc<TrafficState>) -> JoinHandle<()> {
+    let ids: Vec<(u32, String)> = bpf
+        .programs()
+        .filter_map(|(name, program)| Some((program.info().ok()?.id(), name.to_string())))
+        .collect();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+            let mut programs: Vec<ProgramRuntime> = loaded_programs()
+                .filter_map(Result::ok)
+                .filter_map(|info| {
+                    let (_, name) = ids.iter().find(|(id, _)| *id == info.id())?;
+                    Some(ProgramRuntime {
+                        name: name.clone(),
+                        run_count: info.run_count(),
+                        runtime_ns: info.run_time().as_nanos() as u64,
+                    })
+                })
+                .collect();
+            programs.sort_by(|a, b| a.name.cmp(&b.name));
+            traffic.bpf_runtime.update(programs);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary_over_programs() {
+        let runtime = BpfRuntime::default();
+        assert!(runtime.summary().is_none());
+        runtime.update(vec![
+            ProgramRuntime {
+                name: "ayaflow_tc".into(),
+                run_count: 300,
+                runtime_ns: 45_000,
+            },
+            ProgramRuntime {
+                name: "ayaflow_xdp".into(),
+                run_count: 100,
+                runtime_ns: 15_000,
+            },
+        ]);
+        let summary = runtime.summary().unwrap();
+        assert_eq!((summary.run_count, summary.runtime_ns), (400, 60_000));
+        assert_eq!(summary.avg_ns_per_packet, 150.0);
+
+        // Loaded but never run yet.
+        runtime.update(vec![ProgramRuntime {
+            name: "ayaflow_tc".into(),
+            run_count: 0,
+            runtime_ns: 0,
+        }]);
+        assert_eq!(runtime.summary().unwrap().avg_ns_per_packet, 0.0);
+    }
+}
@@ -0,0 +1,315 @@
+//! Approximate counts of distinct source and destination addresses.
+//!
+//! Each wall-clock minute gets a pair of HyperLogLog sketches, 1 KiB each,
+//! which the packet path updates without locking.  The rate sampler closes
+//! the minute once it is over and keeps the last hour of closed minutes;
+//! longer windows are unions of their sketches.  Memory stays around 120 KiB
+//! however many addresses are seen, at a standard error of about 3%.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Mutex;
+
+use serde::Serialize;
+
+use crate::openapi::api_schema;
+
+/// Index bits of each sketch: 2^10 one-byte registers.
+const PRECISION: u32 = 10;
+const REGISTERS: usize = 1 << PRECISION;
+
+const MINUTE_MS: i64 = 60_000;
+
+/// Closed minutes kept, which bounds the longest window.
+const HISTORY_MINUTES: usize = 60;
+
+/// Windows reported by the API and exported on `/metrics`, as
+/// `(label, minutes)`.  Each spans the minute in progress and the closed
+/// minutes before it.
+pub const WINDOWS: [(&str, i64); 3] = [("1m", 1), ("5m", 5), ("60m", 60)];
+
+api_schema! {
+    /// Estimated distinct addresses over one window.
+    #[derive(Debug, Clone, Serialize)]
+    pub struct WindowCardinality {
+        /// "1m", "5m" or "60m".
+        pub window: String,
+        pub src_ips: u64,
+        pub dst_ips: u64,
+    }
+}
+
+api_schema! {
+    /// Estimated distinct addresses in one closed minute.
+    #[derive(Debug, Clone, Serialize)]
+    pub struct MinuteCardinality {
+        /// Start of the minute, milliseconds since the Unix epoch.
+        pub start: i64,
+        pub src_ips: u64,
+        pub dst_ips: u64,
+    }
+}
+
+api_schema! {
+    /// Response of `/api/cardinality`.
+    #[derive(Debug, Clone, Serialize)]
+    pub struct CardinalityReport {
+        pub windows: Vec<WindowCardinality>,
+        /// Closed minutes, newest first.
+        pub recent: Vec<MinuteCardinality>,
+    }
+}
+
+/// HyperLogLog registers of a closed minute, or a union of several.
+#[derive(Debug, Clone)]
+struct Sketch {
+    registers: Vec<u8>,
+}
+
+impl Sketch {
+    fn merge(&mut self, other: &Sketch) {
+        for (mine, theirs) in self.registers.iter_mut().zip(&other.registers) {
+            *mine = (*mine).max(*theirs);
+        }
+    }
+
+    /// The HyperLogLog estimate, with linear counting for small sets.
+    fn estimate(&self) -> u64 {
+        let m = REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self.registers.iter().map(|&r| 2f64.powi(-i32::from(r))).sum();
+        let raw = alpha * m * m / sum;
+        let zeros = self.registers.iter().filter(|&&r| r == 0).count();
+        let estimate = if raw <= 2.5 * m && zeros > 0 {
+            m * (m / zeros as f64).ln()
+        } else {
+            raw
+        };
+        estimate.round() as u64
+    }
+}
+
+/// The minute in progress, updated from the packet path.
+#[derive(Debug)]
+struct AtomicSketch {
+    registers: Vec<AtomicU8>,
+}
+
+impl AtomicSketch {
+    fn new() -> Self {
+        Self {
+            registers: (0..REGISTERS).map(|_| AtomicU8::new(0)).collect(),
+        }
+    }
+
+    fn insert(&self, ip: &IpAddr) {
+        let mut hasher = DefaultHasher::new();
+        ip.hash(&mut hasher);
+        let hash = hasher.finish();
+        let index = (hash >> (64 - PRECISION)) as usize;
+        // The marker bit caps the rank at 64 - PRECISION + 1.
+        let rank = ((hash << PRECISION) | (1 << (PRECISION - 1))).leading_zeros() as u8 + 1;
+        self.registers[index].fetch_max(rank, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> Sketch {
+        Sketch {
+            registers: self.registers.iter().map(|r| r.load(Ordering::Relaxed)).collect(),
+        }
+    }
+
+    /// The registers so far, leaving the sketch empty.
+    fn take(&self) -> Sketch {
+        Sketch {
+            registers: self.registers.iter().map(|r| r.swap(0, Ordering::Relaxed)).collect(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Minute {
+    start: i64,
+    src: Sketch,
+    dst: Sketch,
+}
+
+#[derive(Debug, Default)]
+struct History {
+    /// Start of the minute in progress; None until the first tick.
+    current_start: Option<i64>,
+    closed: VecDeque<Minute>,
+}
+
+/// Distinct source and destination addresses per minute.
+#[derive(Debug)]
+pub struct Cardinality {
+    src: AtomicSketch,
+    dst: AtomicSketch,
+    history: Mutex<History>,
+}
+
+impl Default for Cardinality {
+    fn default() -> Self {
+        Self {
+            src: AtomicSketch::new(),
+            dst: AtomicSketch::new(),
+            history: Mutex::new(History::default()),
+        }
+    }
+}
+
+impl Cardinality {
+    pub fn observe(&self, src_ip: &IpAddr, dst_ip: &IpAddr) {
+        self.src.insert(src_ip);
+        self.dst.insert(dst_ip);
+    }
+
+    /// Close the minute in progress if `now_ms` is past it.  Called about
+    /// once a second; packets seen before the first call count towards the
+    /// minute it starts.
+    pub fn tick(&self, now_ms: i64) {
+        let minute = now_ms - now_ms.rem_euclid(MINUTE_MS);
+        let mut history = self.history.lock().unwrap();
+        match history.current_start {
+            Some(start) if minute > start => {
+                history.closed.push_back(Minute {
+                    start,
+                    src: self.src.take(),
+                    dst: self.dst.take(),
+                });
+                if history.closed.len() > HISTORY_MINUTES {
+                    history.closed.pop_front();
+                }
+            }
+            Some(_) => return,
+            None => {}
+        }
+        history.current_start = Some(minute);
+    }
+
+    /// Forget every address seen, e.g. after an admin reset.
+    pub fn clear(&self) {
+        let mut history = self.history.lock().unwrap();
+        self.src.take();
+        self.dst.take();
+        history.closed.clear();
+    }
+
+    pub fn report(&self) -> CardinalityReport {
+        let history = self.history.lock().unwrap();
+        let current_start = history.current_start.unwrap_or(i64::MIN);
+        let windows = WINDOWS
+            .iter()
+            .map(|&(label, minutes)| {
+                let mut src = self.src.snapshot();
+                let mut dst = self.dst.snapshot();
+                let since = current_start.saturating_sub((minutes - 1) * MINUTE_MS);
+                for minute in history.closed.iter().filter(|m| m.start >= since) {
+                    src.merge(&minute.src);
+                    dst.merge(&minute.dst);
+                }
+                WindowCardinality {
+                    window: label.to_string(),
+                    src_ips: src.estimate(),
+                    dst_ips: dst.estimate(),
+                }
+            })
+            .collect();
+        let recent = history
+            .closed
+            .iter()
+            .rev()
+            .map(|minute| MinuteCardinality {
+                start: minute.start,
+                src_ips: minute.src.estimate(),
+                dst_ips: minute.dst.estimate(),
+            })
+            .collect();
+        CardinalityReport { windows, recent }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn ip(n: u32) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::from(0x0a00_0000 + n))
+    }
+
+    fn assert_close(estimate: u64, actual: u64, tolerance: f64) {
+        let error = (estimate as f64 - actual as f64).abs() / actual as f64;
+        assert!(error <= tolerance, "estimated {} for {}", estimate, actual);
+    }
+
+    #[test]
+    fn test_estimates_distinct_addresses() {
+        let server = ip(0);
+        for n in [10, 100, 1_000, 10_000, 100_000] {
+            let cardinality = Cardinality::default();
+            for i in 0..n {
+                // Every address twice: repeats must not count.
+                cardinality.observe(&ip(i + 1), &server);
+                cardinality.observe(&ip(i + 1), &server);
+            }
+            let window = &cardinality.report().windows[0];
+            assert_close(window.src_ips, n as u64, 0.1);
+            assert_eq!(window.dst_ips, 1);
+        }
+    }
+
+    #[test]
+    fn test_windows_union_recent_minutes() {
+        let cardinality = Cardinality::default();
+        let base = 1_700_000_040_000; // on a minute boundary
+        let server = ip(0);
+        cardinality.tick(base);
+        for i in 0..5_000 {
+            cardinality.observe(&ip(i + 1), &server);
+        }
+        cardinality.tick(base + 59_999);
+        assert!(cardinality.report().recent.is_empty());
+
+        // The next minute overlaps the first by half.
+        cardinality.tick(base + MINUTE_MS);
+        for i in 2_500..7_500 {
+            cardinality.observe(&ip(i + 1), &server);
+        }
+        let report = cardinality.report();
+        assert_eq!(report.recent.len(), 1);
+        assert_eq!(report.recent[0].start, base);
+        assert_close(report.recent[0].src_ips, 5_000, 0.1);
+        assert_eq!(report.windows[0].window, "1m");
+        assert_close(report.windows[0].src_ips, 5_000, 0.1);
+        assert_close(report.windows[1].src_ips, 7_500, 0.1);
+
+        // Minutes older than the window drop out of it.
+        cardinality.tick(base + 5 * MINUTE_MS);
+        let report = cardinality.report();
+        assert_eq!(report.windows[0].src_ips, 0);
+        assert_close(report.windows[1].src_ips, 5_000, 0.1);
+        assert_close(report.windows[2].src_ips, 7_500, 0.1);
+
+        cardinality.clear();
+        let report = cardinality.report();
+        assert!(report.recent.is_empty());
+        assert_eq!(report.windows[2].src_ips, 0);
+    }
+
+    #[test]
+    fn test_history_is_bounded() {
+        let cardinality = Cardinality::default();
+        for minute in 0..200 {
+            cardinality.tick(minute * MINUTE_MS);
+            cardinality.observe(&ip(minute as u32), &ip(0));
+        }
+        let report = cardinality.report();
+        assert_eq!(report.recent.len(), HISTORY_MINUTES);
+        assert_eq!(report.recent[0].start, 198 * MINUTE_MS);
+        assert_close(report.windows[2].src_ips, 60, 0.1);
+    }
+}
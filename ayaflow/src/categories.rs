@@ -0,0 +1,355 @@
+//! Application categories by service port, e.g. 80 and 443 as "web".
+//!
+//! Each category is a named set of ports and port ranges, and a flow falls
+//! in the category of its service port (picked by `service_port_rule`, as
+//! for service names).  Built-in categories cover the common cases; the
+//! `categories:` config map replaces them by name, and ports no category
+//! claims are "other".  The lookup is one byte per port, built at startup.
+
+use std::collections::BTreeMap;
+
+use ayaflow_common::{ServicePortRule, EPHEMERAL_PORT_MIN};
+use serde::Serialize;
+
+use crate::openapi::api_schema;
+use crate::state::TrafficState;
+
+/// The category of every port no other category claims.
+pub const OTHER: &str = "other";
+
+/// Category ids are stored in a byte per port, "other" included.
+const MAX_CATEGORIES: usize = u8::MAX as usize;
+
+const BUILTIN: &[(&str, &[&str])] = &[
+    ("web", &["80", "443", "8000", "8008", "8080", "8443"]),
+    ("mail", &["25", "110", "143", "465", "587", "993", "995"]),
+    ("dns", &["53", "853", "5353"]),
+    ("ssh", &["22"]),
+    ("database", &["1433", "3306", "5432", "6379", "27017"]),
+];
+
+/// Inclusive range of ports.
+pub type PortRange = (u16, u16);
+
+/// A port (`443`) or inclusive range (`8000-8099`).
+fn parse_range(spec: &str) -> Result<PortRange, String> {
+    let port = |s: &str| s.trim().parse::<u16>().ok();
+    let range = match spec.split_once('-') {
+        Some((lo, hi)) => port(lo).zip(port(hi)),
+        None => port(spec).map(|p| (p, p)),
+    };
+    match range {
+        Some((lo, hi)) if lo <= hi => Ok((lo, hi)),
+        _ => Err(format!("invalid port or range {:?}", spec)),
+    }
+}
+
+fn format_range(&(lo, hi): &PortRange) -> String {
+    if lo == hi {
+        lo.to_string()
+    } else {
+        format!("{}-{}", lo, hi)
+    }
+}
+
+/// Named port groups compiled into a per-port lookup.  Ids index `names`;
+/// "other" is always the last.
+#[derive(Debug)]
+pub struct PortCategories {
+    names: Vec<String>,
+    ranges: Vec<Vec<PortRange>>,
+    by_port: Box<[u8]>,
+    rule: ServicePortRule,
+}
+
+impl Default for PortCategories {
+    fn default() -> Self {
+        Self::new(&BTreeMap::new()).expect("built-in categories are valid")
+    }
+}
+
+impl PortCategories {
+    /// The built-in categories with `overrides` applied: a configured name
+    /// replaces the built-in one, and an empty list removes it.  Configured
+    /// categories take their ports from built-in ones, but two configured
+    /// categories may not share a port.
+    pub fn new(overrides: &BTreeMap<String, Vec<String>>) -> Result<Self, String> {
+        let mut configured = Vec::new();
+        for (name, specs) in overrides {
+            if name.is_empty() || name == OTHER {
+                return Err(format!("{:?} cannot be a configured category", name));
+            }
+            let ranges = specs
+                .iter()
+                .map(|spec| parse_range(spec))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| format!("category {}: {}", name, e))?;
+            configured.push((name.clone(), ranges));
+        }
+        let builtin = BUILTIN
+            .iter()
+            .filter(|(name, _)| !overrides.contains_key(*name))
+            .map(|(name, specs)| {
+                let ranges = specs.iter().map(|spec| parse_range(spec).unwrap()).collect();
+                (name.to_string(), ranges)
+            });
+        let mut categories: Vec<(String, Vec<PortRange>)> = builtin.collect();
+        let builtins = categories.len();
+        categories.extend(configured.into_iter().filter(|(_, ranges)| !ranges.is_empty()));
+        if categories.len() >= MAX_CATEGORIES {
+            return Err(format!("at most {} categories are supported", MAX_CATEGORIES - 1));
+        }
+
+        let other = categories.len() as u8;
+        let mut by_port = vec![other; 1 << 16].into_boxed_slice();
+        // Configured categories are written after built-ins so they win.
+        for (id, (name, ranges)) in categories.iter().enumerate() {
+            for &(lo, hi) in ranges {
+                for port in lo..=hi {
+                    let current = by_port[port as usize] as usize;
+                    if id >= builtins && (builtins..id).contains(&current) {
+                        let taken = &categories[current].0;
+                        return Err(format!("port {} is in both {} and {}", port, taken, name));
+                    }
+                    by_port[port as usize] = id as u8;
+                }
+            }
+        }
+
+        let (mut names, mut ranges): (Vec<_>, Vec<_>) = categories.into_iter().unzip();
+        // A port a configured category took is no longer the built-in's.
+        for (id, ranges) in ranges.iter_mut().enumerate().take(builtins) {
+            ranges.retain(|&(lo, hi)| (lo..=hi).any(|port| by_port[port as usize] == id as u8));
+        }
+        names.push(OTHER.to_string());
+        ranges.push(Vec::new());
+        Ok(Self { names, ranges, by_port, rule: ServicePortRule::default() })
+    }
+
+    /// Pick service ports by `rule` rather than the lower port.
+    pub fn with_rule(mut self, rule: ServicePortRule) -> Self {
+        self.rule = rule;
+        self
+    }
+
+    pub fn rule(&self) -> ServicePortRule {
+        self.rule
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    #[cfg(test)]
+    pub fn name(&self, id: usize) -> &str {
+        &self.names[id]
+    }
+
+    pub fn find(&self, name: &str) -> Option<usize> {
+        self.names.iter().position(|n| n == name)
+    }
+
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    /// The category id of a flow, from its service port.  A flow without
+    /// one falls in the category of port 0, as the SQL condition has it.
+    pub fn for_flow(&self, src_port: u16, dst_port: u16) -> usize {
+        let port = self.rule.service_port(src_port, dst_port).unwrap_or(0);
+        self.by_port[port as usize] as usize
+    }
+
+    /// The service ports a history query for category `id` matches.
+    /// "Other" matches every port outside the named categories.
+    pub fn port_match(&self, id: usize) -> PortMatch {
+        let other = self.len() - 1;
+        if id != other {
+            return PortMatch { ranges: self.ranges[id].clone(), negate: false, rule: self.rule };
+        }
+        let mut ranges: Vec<PortRange> = self.ranges[..other].concat();
+        ranges.sort();
+        PortMatch { ranges, negate: true, rule: self.rule }
+    }
+}
+
+/// Service ports a stored packet must fall in, or with `negate` must not,
+/// as in `PortCategories::port_match`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PortMatch {
+    pub ranges: Vec<PortRange>,
+    pub negate: bool,
+    pub rule: ServicePortRule,
+}
+
+impl PortMatch {
+    /// SQL condition on the service port of the `prefix`ed table, in SQL
+    /// SQLite, ClickHouse and PostgreSQL all take.  Ports are integers, so
+    /// formatting them into the statement is safe.
+    pub fn sql(&self, prefix: &str) -> String {
+        self.sql_on(&service_port_sql(self.rule, prefix))
+    }
+
+    fn sql_on(&self, port: &str) -> String {
+        let conditions: Vec<String> = self
+            .ranges
+            .iter()
+            .map(|&(lo, hi)| format!("{} BETWEEN {} AND {}", port, lo, hi))
+            .collect();
+        let any = if conditions.is_empty() {
+            "1 = 0".to_string()
+        } else {
+            conditions.join(" OR ")
+        };
+        if self.negate {
+            format!("NOT ({})", any)
+        } else {
+            format!("({})", any)
+        }
+    }
+}
+
+/// `ServicePortRule::service_port` of the `prefix`ed table's ports as a
+/// SQL expression, 0 for a row without a service port.
+fn service_port_sql(rule: ServicePortRule, prefix: &str) -> String {
+    let (src, dst) = (format!("{}src_port", prefix), format!("{}dst_port", prefix));
+    let lower = format!(
+        "CASE WHEN {src} <> 0 AND ({dst} = 0 OR {src} < {dst}) THEN {src} ELSE {dst} END"
+    );
+    match rule {
+        ServicePortRule::Lower => lower,
+        ServicePortRule::NonEphemeral => {
+            format!("CASE WHEN {l} < {} THEN {l} ELSE 0 END", EPHEMERAL_PORT_MIN, l = lower)
+        }
+    }
+}
+
+api_schema! {
+    /// Traffic in one application category, as served by `/api/categories`.
+    #[derive(Debug, Clone, Serialize)]
+    pub struct CategoryTotals {
+        pub name: String,
+        /// Ports and ranges in the category; empty for "other".
+        pub ports: Vec<String>,
+        pub packets: u64,
+        pub bytes: u64,
+    }
+}
+
+/// Live totals for every category, in id order.
+pub fn totals(traffic: &TrafficState) -> Vec<CategoryTotals> {
+    let categories = traffic.categories();
+    let counters = traffic.category_totals();
+    categories
+        .names
+        .iter()
+        .zip(&categories.ranges)
+        .zip(counters)
+        .map(|((name, ranges), (packets, bytes))| CategoryTotals {
+            name: name.clone(),
+            ports: ranges.iter().map(format_range).collect(),
+            packets,
+            bytes,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn overrides(entries: &[(&str, &[&str])]) -> BTreeMap<String, Vec<String>> {
+        entries
+            .iter()
+            .map(|(name, specs)| (name.to_string(), specs.iter().map(|s| s.to_string()).collect()))
+            .collect()
+    }
+
+    #[test]
+    fn test_builtin_lookup() {
+        let categories = PortCategories::default();
+        let web = categories.find("web").unwrap();
+        assert_eq!(categories.for_flow(51000, 443), web);
+        assert_eq!(categories.for_flow(8080, 60000), web);
+        assert_eq!(categories.name(categories.for_flow(40000, 22)), "ssh");
+        assert_eq!(categories.name(categories.for_flow(40000, 9999)), OTHER);
+        // No transport header.
+        assert_eq!(categories.name(categories.for_flow(0, 0)), OTHER);
+        assert_eq!(categories.names().last().unwrap(), OTHER);
+    }
+
+    #[test]
+    fn test_overrides() {
+        let categories = PortCategories::new(&overrides(&[
+            ("web", &["80", "443"]),
+            ("ssh", &[]),
+            ("games", &["27000-27050", "8080"]),
+        ]))
+        .unwrap();
+        assert!(categories.find("ssh").is_none());
+        assert_eq!(categories.name(categories.for_flow(40000, 22)), OTHER);
+        assert_eq!(categories.name(categories.for_flow(40000, 27015)), "games");
+        assert_eq!(categories.name(categories.for_flow(40000, 8080)), "games");
+        // 27017 moved from the built-in database category.
+        let database = categories.find("database").unwrap();
+        assert!(!categories.port_match(database).ranges.contains(&(27017, 27017)));
+
+        let err = PortCategories::new(&overrides(&[("a", &["10-20"]), ("b", &["15"])]));
+        assert_eq!(err.unwrap_err(), "port 15 is in both a and b");
+        assert!(PortCategories::new(&overrides(&[("a", &["20-10"])])).is_err());
+        assert!(PortCategories::new(&overrides(&[("other", &["1"])])).is_err());
+    }
+
+    #[test]
+    fn test_port_match_sql() {
+        let categories = PortCategories::new(&overrides(&[
+            ("dns", &["53"]),
+            ("web", &["80", "8000-8099"]),
+            ("mail", &[]),
+            ("ssh", &[]),
+            ("database", &[]),
+        ]))
+        .unwrap();
+        let web = categories.port_match(categories.find("web").unwrap());
+        let port = "CASE WHEN p.src_port <> 0 AND (p.dst_port = 0 OR p.src_port < p.dst_port) \
+                    THEN p.src_port ELSE p.dst_port END";
+        assert_eq!(
+            web.sql("p."),
+            format!("({port} BETWEEN 80 AND 80 OR {port} BETWEEN 8000 AND 8099)", port = port)
+        );
+        let other = categories.port_match(categories.find(OTHER).unwrap());
+        assert!(other.negate);
+        assert_eq!(other.ranges, [(53, 53), (80, 80), (8000, 8099)]);
+    }
+
+    #[test]
+    fn test_sql_agrees_with_live_lookup() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        let flows = [(51000, 443), (443, 8443), (8443, 443), (0, 22), (51000, 60001), (0, 0)];
+        for rule in [ServicePortRule::Lower, ServicePortRule::NonEphemeral] {
+            let categories = PortCategories::new(&overrides(&[("p2p", &["51000"])]))
+                .unwrap()
+                .with_rule(rule);
+            for id in 0..categories.len() {
+                let sql = format!(
+                    "SELECT {} FROM (SELECT ?1 AS src_port, ?2 AS dst_port)",
+                    categories.port_match(id).sql("")
+                );
+                for (src, dst) in flows {
+                    let matched: bool =
+                        conn.query_row(&sql, [src, dst], |row| row.get(0)).unwrap();
+                    let expected = categories.for_flow(src, dst) == id;
+                    assert_eq!(matched, expected, "{:?} {}:{} in {}", rule, src, dst, id);
+                }
+            }
+            // Two ephemeral ports: the lower one, or no service port at all.
+            let p2p = match rule {
+                ServicePortRule::Lower => "p2p",
+                ServicePortRule::NonEphemeral => OTHER,
+            };
+            assert_eq!(categories.name(categories.for_flow(51000, 60001)), p2p);
+            assert_eq!(categories.name(categories.for_flow(8443, 443)), "web");
+            assert_eq!(categories.name(categories.for_flow(0, 22)), "ssh");
+        }
+    }
+}
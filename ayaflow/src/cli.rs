@@ -0,0 +1,328 @@
+//! Offline database inspection: `ayaflow query` and `ayaflow top`.
+//!
+//! Both open the database read-only and go through the same `Storage`
+//! queries as the API, so the filters behave identically.
+
+use anyhow::Context;
+use clap::{Args, ValueEnum};
+use serde::Serialize;
+use std::net::IpAddr;
+
+use crate::devices::MacAddr;
+use crate::geoip::CountryMatch;
+use crate::locality::FlowDirection;
+use crate::storage::{HistoryRow, PacketFilter, Storage, RowKind, StoredTalker, TopColumn};
+
+/// How `query` and `top` print their results.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    Table,
+    Json,
+    Csv,
+}
+
+/// Options shared by `query` and `top`.
+#[derive(Args, Debug, Clone)]
+pub struct FilterArgs {
+    /// SQLite database to read.
+    #[arg(long, default_value = "traffic.db")]
+    pub db: String,
+
+    /// Earliest timestamp: RFC 3339 (e.g. 2024-05-01T12:00:00Z) or epoch ms.
+    #[arg(long, value_parser = parse_time)]
+    pub from: Option<i64>,
+
+    /// Latest timestamp: RFC 3339 or epoch ms.
+    #[arg(long, value_parser = parse_time)]
+    pub to: Option<i64>,
+
+    /// Only packets to or from this address.
+    #[arg(long)]
+    pub ip: Option<IpAddr>,
+
+    /// Only packets seen on this interface.
+    #[arg(long)]
+    pub interface: Option<String>,
+
+    /// Only packets to or from this MAC address.
+    #[arg(long)]
+    pub mac: Option<MacAddr>,
+
+    /// Only packets in this direction relative to the local networks:
+    /// inbound, outbound, internal or external.
+    #[arg(long)]
+    pub direction: Option<FlowDirection>,
+
+    /// Only rows stored by this instance.
+    #[arg(long)]
+    pub instance: Option<String>,
+
+    /// Only packets of this protocol, e.g. tcp or icmp.
+    #[arg(long)]
+    pub protocol: Option<String>,
+
+    /// Only ICMP and ICMPv6 packets of this type, e.g. 11 (time exceeded).
+    #[arg(long)]
+    pub icmp_type: Option<u8>,
+
+    /// Only packets whose remote end is in this country, e.g. CN, or
+    /// `unknown` for those GeoIP did not place.
+    #[arg(long)]
+    pub country: Option<CountryMatch>,
+
+    /// Only packets whose remote end is in this autonomous system.
+    #[arg(long)]
+    pub asn: Option<u32>,
+
+    /// Output format.
+    #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+    pub format: OutputFormat,
+}
+
+impl FilterArgs {
+    fn open(&self) -> anyhow::Result<(Storage, PacketFilter)> {
+        if let (Some(from), Some(to)) = (self.from, self.to) {
+            anyhow::ensure!(from <= to, "--from ({}) must not be after --to ({})", from, to);
+        }
+        let storage = Storage::open_read_only(&self.db)
+            .with_context(|| format!("cannot open database {}", self.db))?;
+        let filter = PacketFilter {
+            from: self.from,
+            to: self.to,
+            ip: self.ip.map(|ip| ip.to_string()),
+            interface: self.interface.clone(),
+            mac: self.mac.map(|mac| mac.to_string()),
+            direction: self.direction,
+            category: None,
+            instance: self.instance.clone(),
+            protocol: self.protocol.clone(),
+            icmp_type: self.icmp_type,
+            country: self.country.clone(),
+            asn: self.asn,
+        };
+        Ok((storage, filter))
+    }
+}
+
+/// Arguments for `ayaflow query`.
+#[derive(Args, Debug, Clone)]
+pub struct QueryArgs {
+    #[command(flatten)]
+    pub filter: FilterArgs,
+
+    /// Maximum number of packets to print, newest first.
+    #[arg(long, default_value_t = 100)]
+    pub limit: usize,
+}
+
+/// Arguments for `ayaflow top`.
+#[derive(Args, Debug, Clone)]
+pub struct TopArgs {
+    #[command(flatten)]
+    pub filter: FilterArgs,
+
+    /// Column to group by.
+    #[arg(long, value_enum, default_value_t = TopColumn::DstIp)]
+    pub by: TopColumn,
+
+    /// Maximum number of groups to print.
+    #[arg(long, default_value_t = 20)]
+    pub limit: usize,
+}
+
+/// Parse an RFC 3339 timestamp or a plain number of epoch milliseconds.
+pub fn parse_time(value: &str) -> Result<i64, String> {
+    if let Ok(ms) = value.parse::<i64>() {
+        return Ok(ms);
+    }
+    chrono::DateTime::parse_from_rfc3339(value)
+        .map(|t| t.timestamp_millis())
+        .map_err(|e| format!("expected RFC 3339 or epoch milliseconds: {}", e))
+}
+
+fn format_time(ms: i64) -> String {
+    chrono::DateTime::from_timestamp_millis(ms)
+        .map(|t| t.to_rfc3339_opts(chrono::SecondsFormat::Millis, true))
+        .unwrap_or_else(|| ms.to_string())
+}
+
+pub fn query(args: &QueryArgs) -> anyhow::Result<()> {
+    let (storage, filter) = args.filter.open()?;
+    let rows = storage.query_packets(&filter, args.limit)?;
+    let headers = [
+        "timestamp", "src_ip", "src_port", "dst_ip", "dst_port", "protocol", "length",
+        "kind", "packets", "direction", "interface", "dscp", "domain",
+    ];
+    print!(
+        "{}",
+        render(args.filter.format, &headers, &rows, |row: &HistoryRow| {
+            let p = &row.packet;
+            let kind = match row.kind {
+                RowKind::Raw => "raw",
+                RowKind::Aggregated => "aggregated",
+            };
+            vec![
+                format_time(p.timestamp),
+                p.src_ip.clone(),
+                p.src_port.to_string(),
+                p.dst_ip.clone(),
+                p.dst_port.to_string(),
+                p.protocol.clone(),
+                p.length.to_string(),
+                kind.to_string(),
+                row.packet_count.to_string(),
+                p.direction.clone(),
+                p.interface.clone(),
+                p.dscp_class.clone().unwrap_or_default(),
+                p.domain.clone().unwrap_or_default(),
+            ]
+        })?
+    );
+    Ok(())
+}
+
+pub fn top(args: &TopArgs) -> anyhow::Result<()> {
+    let (storage, filter) = args.filter.open()?;
+    let talkers = storage.query_top(args.by, &filter, args.limit)?;
+    let headers = [args.by.column(), "bytes", "rows"];
+    print!(
+        "{}",
+        render(args.filter.format, &headers, &talkers, |t: &StoredTalker| {
+            vec![t.key.clone(), t.bytes.to_string(), t.rows.to_string()]
+        })?
+    );
+    Ok(())
+}
+
+/// Render rows as an aligned table, JSON array, or CSV.  `cells` must
+/// return one value per header.
+fn render<T: Serialize>(
+    format: OutputFormat,
+    headers: &[&str],
+    rows: &[T],
+    cells: impl Fn(&T) -> Vec<String>,
+) -> anyhow::Result<String> {
+    let mut out = String::new();
+    match format {
+        OutputFormat::Json => {
+            out = serde_json::to_string_pretty(rows)?;
+            out.push('\n');
+        }
+        OutputFormat::Csv => {
+            let header: Vec<String> = headers.iter().map(|h| csv_field(h)).collect();
+            out.push_str(&header.join(","));
+            out.push('\n');
+            for row in rows {
+                let fields: Vec<String> = cells(row).iter().map(|c| csv_field(c)).collect();
+                out.push_str(&fields.join(","));
+                out.push('\n');
+            }
+        }
+        OutputFormat::Table => {
+            let body: Vec<Vec<String>> = rows.iter().map(&cells).collect();
+            let mut widths: Vec<usize> = headers.iter().map(|h| h.len()).collect();
+            for row in &body {
+                for (width, cell) in widths.iter_mut().zip(row) {
+                    *width = (*width).max(cell.chars().count());
+                }
+            }
+            let header: Vec<String> = headers.iter().map(|h| h.to_string()).collect();
+            for row in std::iter::once(&header).chain(&body) {
+                let line: Vec<String> = row
+                    .iter()
+                    .zip(&widths)
+                    .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+                    .collect();
+                out.push_str(line.join("  ").trim_end());
+                out.push('\n');
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Quote a CSV field when it contains a delimiter, quote, or line break.
+pub fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Cli, Command};
+    use clap::Parser;
+
+    #[derive(Serialize)]
+    struct Row {
+        name: &'static str,
+        bytes: u64,
+    }
+
+    fn rows() -> Vec<Row> {
+        vec![
+            Row { name: "example.com", bytes: 1500 },
+            Row { name: "a,\"b\"", bytes: 7 },
+        ]
+    }
+
+    fn cells(row: &Row) -> Vec<String> {
+        vec![row.name.to_string(), row.bytes.to_string()]
+    }
+
+    #[test]
+    fn test_render_formats() {
+        let headers = ["name", "bytes"];
+
+        let table = render(OutputFormat::Table, &headers, &rows(), cells).unwrap();
+        assert_eq!(table, "name         bytes\nexample.com  1500\na,\"b\"        7\n");
+
+        let csv = render(OutputFormat::Csv, &headers, &rows(), cells).unwrap();
+        assert_eq!(csv, "name,bytes\nexample.com,1500\n\"a,\"\"b\"\"\",7\n");
+
+        let json = render(OutputFormat::Json, &headers, &rows(), cells).unwrap();
+        let parsed: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed[0]["bytes"], 1500);
+    }
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(parse_time("1700000000000"), Ok(1_700_000_000_000));
+        assert_eq!(parse_time("2023-11-14T22:13:20Z"), Ok(1_700_000_000_000));
+        assert!(parse_time("yesterday").is_err());
+    }
+
+    #[test]
+    fn test_run_is_the_default_subcommand() {
+        let cli = Cli::try_parse_from(["ayaflow", "-i", "eth0", "--port", "8080"]).unwrap();
+        assert!(cli.command.is_none());
+        assert_eq!(cli.run.interface.as_deref(), Some("eth0"));
+        assert_eq!(cli.run.port, 8080);
+
+        let cli = Cli::try_parse_from(["ayaflow", "run", "-i", "eth1"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Run(ref args))
+            if args.interface.as_deref() == Some("eth1")));
+
+        let cli = Cli::try_parse_from([
+            "ayaflow", "top", "--db", "x.db", "--by", "dst_ip", "--limit", "5", "--format", "csv",
+        ])
+        .unwrap();
+        match cli.command {
+            Some(Command::Top(args)) => {
+                assert_eq!(args.by, TopColumn::DstIp);
+                assert_eq!(args.limit, 5);
+                assert_eq!(args.filter.format, OutputFormat::Csv);
+                assert_eq!(args.filter.db, "x.db");
+            }
+            other => panic!("expected top, got {:?}", other),
+        }
+
+        let cli =
+            Cli::try_parse_from(["ayaflow", "detach", "-i", "eth0", "--remove-qdisc"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Detach(ref args))
+            if args.interface == "eth0" && args.remove_qdisc));
+    }
+}
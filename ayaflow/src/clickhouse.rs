@@ -0,0 +1,1655 @@
+//! ClickHouse storage backend, for captures SQLite cannot keep up with.
+//!
+//! Built with `--features clickhouse` and selected with a
+//! `clickhouse://[user[:password]@]host[:port]/database` db_url.  It speaks
+//! the HTTP interface (port 8123 by default) over plain TCP: rows go in as
+//! JSONEachRow batches and queries come back as JSONCompactEachRow.
+//!
+//! Packets are stored raw in a MergeTree ordered by timestamp, and
+//! retention is a table TTL rather than DELETEs.  The tables SQLite updates
+//! in place (alerts, state, hostnames) are ReplacingMergeTrees read with
+//! FINAL; usage and peer rows are summed at query time.
+//!
+//! An unreachable server does not stop the agent.  The writer holds up to
+//! `buffer_max_rows` rows in memory, retries on every flush interval and
+//! fails its heartbeat meanwhile; reads fail with 503 until it is back.
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use ayaflow_common::{http, AggregationKey};
+use ipnet::IpNet;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::Receiver;
+use tokio::time::{interval, Duration, Instant};
+
+use crate::alerts::{Alert, StoredAlert};
+use crate::config::{ClickHouseConfig, StorageConfig};
+use crate::geoip::CountryMatch;
+use crate::health::Heartbeat;
+use crate::icmp::IcmpMessage;
+use crate::locality::FlowDirection;
+use crate::state::{dscp_class_name, AggregatedBucket, ConnectionKey, PacketMetadata, PeerTotals};
+use crate::storage::{
+    add_usage, blocking, AlertFilter, ExportedPackets, FutureClamp, HistoryRow, HostUsage,
+    HostUsageRow, PacketFilter, PacketRecord, RowKind, Snapshot, StorageBackend, StorageError,
+    StorageEvent, StorageMetrics, StorageResult, StoredConnectionTotals, StoredTalker,
+    StoredTotals, TopColumn, UnresolvedRows, UsageGranularity, WalCheckpoint,
+};
+
+const DEFAULT_PORT: u16 = 8123;
+
+/// Sent with every request: 64-bit integers as JSON numbers, and NULL
+/// rather than column defaults where a LEFT JOIN finds nothing.
+const SETTINGS: [(&str, &str); 2] =
+    [("output_format_json_quote_64bit_integers", "0"), ("join_use_nulls", "1")];
+
+/// Created at startup, or on the first write after the server answers.
+const SCHEMA: [&str; 8] = [
+    "CREATE TABLE IF NOT EXISTS packets (
+        timestamp Int64,
+        src_ip String,
+        dst_ip String,
+        src_port UInt16,
+        dst_port UInt16,
+        protocol LowCardinality(String),
+        length UInt64,
+        direction LowCardinality(String),
+        src_hostname Nullable(String),
+        dst_hostname Nullable(String),
+        domain Nullable(String),
+        ttl Nullable(UInt8),
+        dscp Nullable(UInt8),
+        aggregation LowCardinality(Nullable(String)),
+        interface LowCardinality(String),
+        window_start Nullable(Int64),
+        window_end Nullable(Int64),
+        payload_length UInt64,
+        packet_count UInt64 DEFAULT 1,
+        src_mac Nullable(String),
+        dst_mac Nullable(String),
+        flow_direction LowCardinality(Nullable(String)),
+        instance LowCardinality(Nullable(String)),
+        icmp_type Nullable(UInt8),
+        icmp_code Nullable(UInt8)
+    ) ENGINE = MergeTree
+    PARTITION BY toYYYYMMDD(toDateTime(intDiv(timestamp, 1000)))
+    ORDER BY timestamp",
+    // GeoIP country and ASN of the remote end, added to tables created
+    // before them, with skip indices for the history filters.
+    "ALTER TABLE packets
+        ADD COLUMN IF NOT EXISTS country LowCardinality(Nullable(String)),
+        ADD COLUMN IF NOT EXISTS asn Nullable(UInt32),
+        ADD INDEX IF NOT EXISTS idx_country country TYPE set(0) GRANULARITY 4,
+        ADD INDEX IF NOT EXISTS idx_asn asn TYPE minmax GRANULARITY 4",
+    // Every fold and acknowledgement inserts the whole row again; the
+    // highest version wins.  Alerts, usage and peers carry the instance
+    // that wrote them, like packets; usage and peers are summed over
+    // instances when read.
+    "CREATE TABLE IF NOT EXISTS alerts (
+        id Int64,
+        rule LowCardinality(String),
+        severity LowCardinality(String),
+        subject String,
+        message String,
+        first_seen Int64,
+        last_seen Int64,
+        count UInt64,
+        acked Bool,
+        acked_by Nullable(String),
+        acked_at Nullable(Int64),
+        instance LowCardinality(Nullable(String)),
+        version UInt64
+    ) ENGINE = ReplacingMergeTree(version)
+    ORDER BY id",
+    "CREATE TABLE IF NOT EXISTS state (
+        key String,
+        value String,
+        updated_at UInt64
+    ) ENGINE = ReplacingMergeTree(updated_at)
+    ORDER BY key",
+    "CREATE TABLE IF NOT EXISTS hostnames (
+        ip String,
+        hostname String,
+        resolved_at UInt64
+    ) ENGINE = ReplacingMergeTree(resolved_at)
+    ORDER BY ip",
+    "CREATE TABLE IF NOT EXISTS capture_meta (
+        instance String,
+        since Int64,
+        sample_rate UInt32
+    ) ENGINE = ReplacingMergeTree
+    ORDER BY (instance, since)",
+    "CREATE TABLE IF NOT EXISTS host_usage (
+        local_ip String,
+        hour Int64,
+        direction LowCardinality(String),
+        bytes UInt64,
+        packets UInt64,
+        instance LowCardinality(String)
+    ) ENGINE = SummingMergeTree
+    ORDER BY (local_ip, hour, direction, instance)",
+    "CREATE TABLE IF NOT EXISTS peers (
+        remote_ip String,
+        day Int64,
+        first_seen Int64,
+        last_seen Int64,
+        bytes UInt64,
+        packets UInt64,
+        connections UInt64,
+        instance LowCardinality(String)
+    ) ENGINE = MergeTree
+    ORDER BY (remote_ip, day)",
+];
+
+/// The columns `PacketRow` and `PacketRecord` read, in order, with stored
+/// hostnames falling back to the resolved ones.  Callers append the WHERE
+/// clause.
+const HISTORY_SELECT: &str = "SELECT p.timestamp, p.src_ip, p.dst_ip, p.src_port, p.dst_port,
+            p.protocol, p.length, p.direction,
+            coalesce(p.src_hostname, hs.hostname), coalesce(p.dst_hostname, hd.hostname),
+            p.domain, p.ttl, p.dscp, p.aggregation, p.interface, p.window_start, p.window_end,
+            p.payload_length, p.packet_count, p.src_mac, p.dst_mac, p.flow_direction,
+            p.instance, p.icmp_type, p.icmp_code, p.country, p.asn
+     FROM packets p
+     LEFT JOIN (SELECT ip, hostname FROM hostnames FINAL) hs ON hs.ip = p.src_ip
+     LEFT JOIN (SELECT ip, hostname FROM hostnames FINAL) hd ON hd.ip = p.dst_ip";
+
+/// The columns of `StoredAlert`, in order.
+const ALERT_SELECT: &str = "SELECT id, rule, severity, subject, message, first_seen, last_seen,
+            count, acked, acked_by, acked_at, instance
+     FROM alerts FINAL";
+
+/// Why a request failed.
+#[derive(Debug)]
+pub enum ClickHouseError {
+    /// The server could not be reached, or answered that it cannot serve
+    /// right now.  Worth retrying.
+    Unreachable(String),
+    /// The server refused the query, or its answer could not be read.
+    Rejected(String),
+}
+
+impl ClickHouseError {
+    pub fn is_unreachable(&self) -> bool {
+        matches!(self, ClickHouseError::Unreachable(_))
+    }
+}
+
+impl fmt::Display for ClickHouseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClickHouseError::Unreachable(e) => write!(f, "ClickHouse unreachable: {}", e),
+            ClickHouseError::Rejected(e) => write!(f, "ClickHouse error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ClickHouseError {}
+
+impl From<ClickHouseError> for StorageError {
+    fn from(e: ClickHouseError) -> Self {
+        StorageError::ClickHouse(e)
+    }
+}
+
+type Result<T> = std::result::Result<T, ClickHouseError>;
+
+/// A parsed `clickhouse://` db_url.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClickHouseUrl {
+    /// `host:port`, for connecting.
+    address: String,
+    database: String,
+    user: Option<String>,
+    password: Option<String>,
+}
+
+impl ClickHouseUrl {
+    /// Credentials are taken as written, without percent-decoding.
+    pub fn parse(url: &str) -> anyhow::Result<Self> {
+        let rest = url
+            .strip_prefix("clickhouse://")
+            .ok_or_else(|| anyhow::anyhow!("db_url {:?} must start with clickhouse://", url))?;
+        let (authority, database) = rest.split_once('/').unwrap_or((rest, ""));
+        let database = database.trim_end_matches('/');
+        anyhow::ensure!(!database.is_empty(), "db_url {:?} has no database name", url);
+        let (credentials, host) = match authority.rsplit_once('@') {
+            Some((credentials, host)) => (Some(credentials), host),
+            None => (None, authority),
+        };
+        let (user, password) = match credentials.map(|c| c.split_once(':').unwrap_or((c, ""))) {
+            Some((user, password)) => {
+                (Some(user.to_string()), Some(password).filter(|p| !p.is_empty()))
+            }
+            None => (None, None),
+        };
+        // The port follows the last colon, unless that colon is inside an
+        // IPv6 literal.
+        let (name, address) = match host.rfind(':') {
+            Some(colon) if !host[colon..].contains(']') => {
+                host[colon + 1..]
+                    .parse::<u16>()
+                    .map_err(|_| anyhow::anyhow!("db_url {:?} has an invalid port", url))?;
+                (&host[..colon], host.to_string())
+            }
+            _ => (host, format!("{}:{}", host, DEFAULT_PORT)),
+        };
+        anyhow::ensure!(!name.is_empty(), "db_url {:?} has no host", url);
+        Ok(Self {
+            address,
+            database: database.to_string(),
+            user,
+            password: password.map(str::to_string),
+        })
+    }
+}
+
+/// HTTP requests through `ayaflow_common::http`, one connection each.  The
+/// backend's interface is blocking, so `post` waits for the answer.
+struct Client {
+    url: ClickHouseUrl,
+    timeout: Duration,
+    /// Whether the last request got an answer.
+    reachable: AtomicBool,
+}
+
+impl Client {
+    /// POST `body` with `params` added to the query string, returning the
+    /// response body.
+    fn post(&self, params: &[(&str, &str)], body: &[u8]) -> Result<String> {
+        let result = wait(self.send(params, body));
+        let unreachable = matches!(result, Err(ClickHouseError::Unreachable(_)));
+        self.reachable.store(!unreachable, Ordering::Relaxed);
+        result
+    }
+
+    fn reachable(&self) -> bool {
+        self.reachable.load(Ordering::Relaxed)
+    }
+
+    async fn send(&self, params: &[(&str, &str)], body: &[u8]) -> Result<String> {
+        let address = &self.url.address;
+        let query = form_urlencoded::Serializer::new(String::new())
+            .append_pair("database", &self.url.database)
+            .extend_pairs(SETTINGS)
+            .extend_pairs(params)
+            .finish();
+        let target = format!("/?{}", query);
+        let mut headers = Vec::new();
+        if let Some(user) = &self.url.user {
+            headers.push(("X-ClickHouse-User", user.as_str()));
+        }
+        if let Some(password) = &self.url.password {
+            headers.push(("X-ClickHouse-Key", password.as_str()));
+        }
+        let request = http::Request {
+            method: "POST",
+            target: &target,
+            host: address,
+            headers: &headers,
+            body,
+        };
+        let response = http::send(address, &request, self.timeout)
+            .await
+            .map_err(|e| ClickHouseError::Unreachable(format!("{}: {}", address, e)))?;
+        answer(response)
+    }
+}
+
+/// Wait for `future` on the agent's runtime, off its worker as
+/// `storage::blocking` does, or on a runtime of its own outside one.
+fn wait(future: impl Future<Output = Result<String>>) -> Result<String> {
+    match tokio::runtime::Handle::try_current() {
+        Ok(handle) => blocking(|| handle.block_on(future)),
+        Err(_) => tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| ClickHouseError::Unreachable(format!("no runtime: {}", e)))?
+            .block_on(future),
+    }
+}
+
+/// The body of a response, or the error its status stands for.
+fn answer(response: http::Response) -> Result<String> {
+    let body = String::from_utf8_lossy(&response.body).into_owned();
+    match response.status {
+        200..=299 => Ok(body),
+        502..=504 => Err(ClickHouseError::Unreachable(format!("HTTP {}", response.status))),
+        status => Err(ClickHouseError::Rejected(format!("HTTP {}: {}", status, body.trim()))),
+    }
+}
+
+/// Strings bound to a query's `{name:String}` placeholders.  They travel
+/// as `param_<name>` in the query string, so no value is ever spliced into
+/// the SQL itself.
+#[derive(Debug, Default)]
+struct Params(Vec<(String, String)>);
+
+impl Params {
+    /// Bind `value`, returning its placeholder.
+    fn string(&mut self, value: &str) -> String {
+        let name = format!("p{}", self.0.len());
+        let placeholder = format!("{{{}:String}}", name);
+        self.0.push((format!("param_{}", name), value.to_string()));
+        placeholder
+    }
+
+    fn pairs(&self) -> Vec<(&str, &str)> {
+        self.0.iter().map(|(name, value)| (name.as_str(), value.as_str())).collect()
+    }
+}
+
+/// The `WHERE` condition for `filter` on columns of `prefix`, binding its
+/// strings to `params`.
+fn filter_sql(filter: &PacketFilter, prefix: &str, params: &mut Params) -> String {
+    let p = prefix;
+    let (from, to) = filter.range();
+    let mut sql = format!("{p}timestamp >= {from} AND {p}timestamp <= {to}");
+    if let Some(ip) = &filter.ip {
+        let ip = params.string(ip);
+        sql.push_str(&format!(" AND ({p}src_ip = {ip} OR {p}dst_ip = {ip})"));
+    }
+    if let Some(mac) = &filter.mac {
+        let mac = params.string(mac);
+        sql.push_str(&format!(" AND ({p}src_mac = {mac} OR {p}dst_mac = {mac})"));
+    }
+    let equal = [
+        ("interface", filter.interface.as_deref()),
+        ("flow_direction", filter.direction()),
+        ("instance", filter.instance.as_deref()),
+    ];
+    for (column, value) in equal {
+        if let Some(value) = value {
+            sql.push_str(&format!(" AND {p}{column} = {}", params.string(value)));
+        }
+    }
+    if let Some(protocol) = &filter.protocol {
+        let protocol = params.string(protocol);
+        sql.push_str(&format!(" AND lower({p}protocol) = lower({protocol})"));
+    }
+    if let Some(icmp_type) = filter.icmp_type {
+        sql.push_str(&format!(" AND {p}icmp_type = {icmp_type}"));
+    }
+    if let Some(asn) = filter.asn {
+        sql.push_str(&format!(" AND {p}asn = {asn}"));
+    }
+    match &filter.country {
+        Some(CountryMatch::Code(code)) => {
+            sql.push_str(&format!(" AND {p}country = {}", params.string(code)));
+        }
+        Some(CountryMatch::Unknown) => sql.push_str(&format!(" AND {p}country IS NULL")),
+        None => {}
+    }
+    if let Some(ports) = &filter.category {
+        sql.push_str(&format!(" AND {}", ports.sql(p)));
+    }
+    sql
+}
+
+fn now_ms() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+/// An id for a new alert row: the second it was raised, then 20 random
+/// bits.  Agents sharing the database have no common sequence to count
+/// from, so ids are drawn; they still sort by age and stay below 2^53, so
+/// JSON clients read them exactly.
+fn new_alert_id() -> i64 {
+    (now_ms() / 1000) << 20 | i64::from(rand::random::<u32>() & 0xf_ffff)
+}
+
+/// A `packets` row: written as JSONEachRow, and read back positionally in
+/// `HISTORY_SELECT` order.
+#[derive(Serialize, Deserialize)]
+struct PacketRow<'a> {
+    timestamp: i64,
+    src_ip: Cow<'a, str>,
+    dst_ip: Cow<'a, str>,
+    src_port: u16,
+    dst_port: u16,
+    protocol: Cow<'a, str>,
+    length: u64,
+    direction: Cow<'a, str>,
+    src_hostname: Option<Cow<'a, str>>,
+    dst_hostname: Option<Cow<'a, str>>,
+    domain: Option<Cow<'a, str>>,
+    ttl: Option<u8>,
+    dscp: Option<u8>,
+    aggregation: Option<Cow<'a, str>>,
+    interface: Cow<'a, str>,
+    window_start: Option<i64>,
+    window_end: Option<i64>,
+    payload_length: u64,
+    packet_count: u64,
+    src_mac: Option<Cow<'a, str>>,
+    dst_mac: Option<Cow<'a, str>>,
+    flow_direction: Option<Cow<'a, str>>,
+    instance: Option<Cow<'a, str>>,
+    icmp_type: Option<u8>,
+    icmp_code: Option<u8>,
+    country: Option<Cow<'a, str>>,
+    asn: Option<u32>,
+}
+
+impl<'a> PacketRow<'a> {
+    fn raw(packet: &'a PacketMetadata, instance: Option<&'a str>) -> Self {
+        Self {
+            timestamp: packet.timestamp,
+            src_ip: packet.src_ip.as_str().into(),
+            dst_ip: packet.dst_ip.as_str().into(),
+            src_port: packet.src_port,
+            dst_port: packet.dst_port,
+            protocol: packet.protocol.as_str().into(),
+            length: packet.length as u64,
+            direction: packet.direction.as_str().into(),
+            src_hostname: packet.src_hostname.as_deref().map(Cow::from),
+            dst_hostname: packet.dst_hostname.as_deref().map(Cow::from),
+            domain: packet.domain.as_deref().map(Cow::from),
+            ttl: packet.ttl,
+            dscp: packet.dscp,
+            aggregation: None,
+            interface: packet.interface.as_str().into(),
+            window_start: None,
+            window_end: None,
+            payload_length: packet.payload_length as u64,
+            packet_count: 1,
+            src_mac: packet.src_mac.as_deref().map(Cow::from),
+            dst_mac: packet.dst_mac.as_deref().map(Cow::from),
+            flow_direction: packet.flow_direction.map(|d| d.as_str().into()),
+            instance: instance.map(Cow::from),
+            icmp_type: packet.icmp_type,
+            icmp_code: packet.icmp_code,
+            country: packet.country.as_deref().map(Cow::from),
+            asn: packet.asn,
+        }
+    }
+
+    /// A kernel-swept bucket, which is always per connection.
+    fn bucket(bucket: &'a AggregatedBucket, instance: Option<&'a str>) -> Self {
+        let icmp = IcmpMessage::from_flow(&bucket.protocol, bucket.dst_port);
+        Self {
+            timestamp: bucket.first_timestamp,
+            src_ip: bucket.src_ip.as_str().into(),
+            dst_ip: bucket.dst_ip.as_str().into(),
+            src_port: bucket.src_port,
+            dst_port: bucket.dst_port,
+            protocol: bucket.protocol.as_str().into(),
+            length: bucket.total_bytes,
+            direction: bucket.direction.as_str().into(),
+            src_hostname: bucket.src_hostname.as_deref().map(Cow::from),
+            dst_hostname: bucket.dst_hostname.as_deref().map(Cow::from),
+            domain: bucket.domain.as_deref().map(Cow::from),
+            ttl: None,
+            dscp: None,
+            aggregation: Some(AggregationKey::Connection.as_str().into()),
+            interface: bucket.interface.as_str().into(),
+            window_start: Some(bucket.window_start),
+            window_end: Some(bucket.window_end),
+            payload_length: bucket.payload_bytes,
+            packet_count: bucket.packet_count,
+            src_mac: None,
+            dst_mac: None,
+            flow_direction: bucket.flow_direction.map(|d| d.as_str().into()),
+            instance: instance.map(Cow::from),
+            icmp_type: icmp.map(|m| m.kind),
+            icmp_code: icmp.map(|m| m.code),
+            country: bucket.country.as_deref().map(Cow::from),
+            asn: bucket.asn,
+        }
+    }
+
+    /// Bring stamps too far in the future back to the write time, the
+    /// window along with the timestamp.
+    fn clamp(&mut self, clamp: &mut FutureClamp) {
+        match (self.window_start, self.window_end) {
+            (Some(start), Some(end)) => {
+                let [timestamp, start, end] = clamp.row([self.timestamp, start, end]);
+                (self.timestamp, self.window_start, self.window_end) =
+                    (timestamp, Some(start), Some(end));
+            }
+            _ => [self.timestamp] = clamp.row([self.timestamp]),
+        }
+    }
+
+    fn into_history(self) -> HistoryRow {
+        let icmp = self
+            .icmp_type
+            .zip(self.icmp_code)
+            .map(|(kind, code)| u16::from_be_bytes([kind, code]))
+            .and_then(|port| IcmpMessage::from_flow(&self.protocol, port));
+        let packet = PacketMetadata {
+            timestamp: self.timestamp,
+            src_ip: self.src_ip.into_owned(),
+            dst_ip: self.dst_ip.into_owned(),
+            src_port: self.src_port,
+            dst_port: self.dst_port,
+            protocol: self.protocol.into_owned(),
+            length: self.length as usize,
+            payload_length: self.payload_length as usize,
+            direction: self.direction.into_owned(),
+            flow_direction: self.flow_direction.and_then(|d| d.parse::<FlowDirection>().ok()),
+            cast: None,
+            interface: self.interface.into_owned(),
+            src_mac: self.src_mac.map(Cow::into_owned),
+            dst_mac: self.dst_mac.map(Cow::into_owned),
+            src_hostname: self.src_hostname.map(Cow::into_owned),
+            dst_hostname: self.dst_hostname.map(Cow::into_owned),
+            domain: self.domain.map(Cow::into_owned),
+            country: self.country.map(Cow::into_owned),
+            asn: self.asn,
+            service: None,
+            ttl: self.ttl,
+            dscp: self.dscp,
+            dscp_class: self.dscp.map(dscp_class_name),
+            icmp_type: self.icmp_type,
+            icmp_code: self.icmp_code,
+            icmp_name: icmp.map(|m| m.name()),
+        };
+        HistoryRow {
+            packet,
+            kind: if self.aggregation.is_some() { RowKind::Aggregated } else { RowKind::Raw },
+            packet_count: self.packet_count,
+            instance: self.instance.map(Cow::into_owned),
+            service_port: None,
+        }
+    }
+}
+
+/// Rows waiting for the writer's next insert, as JSONEachRow lines per
+/// table.
+#[derive(Default)]
+struct Pending {
+    tables: BTreeMap<&'static str, VecDeque<String>>,
+    /// Usage totals since the last insert per instance ('' for an unnamed
+    /// agent); one row per key goes in.
+    usage: BTreeMap<String, HostUsage>,
+    /// Folding an alert reads the table, so alerts wait for the server
+    /// like rows do.
+    alerts: VecDeque<Alert>,
+}
+
+impl Pending {
+    fn push(&mut self, table: &'static str, row: &impl Serialize) {
+        let line = serde_json::to_string(row).expect("rows serialize to JSON");
+        self.tables.entry(table).or_default().push_back(line);
+    }
+
+    fn rows(&self) -> usize {
+        let rows: usize = self.tables.values().map(VecDeque::len).sum();
+        let usage: usize = self.usage.values().map(HashMap::len).sum();
+        rows + usage + self.alerts.len()
+    }
+
+    fn packets(&self) -> usize {
+        self.tables.get("packets").map_or(0, VecDeque::len)
+    }
+
+    /// Drop the oldest rows, packets first, until at most `max` are left.
+    /// Usage totals and alerts are kept.  Returns the number dropped.
+    fn truncate(&mut self, max: usize) -> usize {
+        let excess = self.rows().saturating_sub(max);
+        let mut left = excess;
+        let others = self.tables.keys().copied().filter(|table| *table != "packets");
+        for table in std::iter::once("packets").chain(others.collect::<Vec<_>>()) {
+            if let Some(rows) = self.tables.get_mut(table) {
+                let dropped = left.min(rows.len());
+                rows.drain(..dropped);
+                left -= dropped;
+            }
+        }
+        excess - left
+    }
+
+    /// Move the usage totals into `host_usage` rows.
+    fn take_usage(&mut self) {
+        for (instance, usage) in std::mem::take(&mut self.usage) {
+            for ((ip, hour, direction), (bytes, packets)) in usage {
+                let row = serde_json::json!({
+                    "local_ip": ip,
+                    "hour": hour,
+                    "direction": direction,
+                    "bytes": bytes,
+                    "packets": packets,
+                    "instance": instance,
+                });
+                self.push("host_usage", &row);
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ClickHouse {
+    client: Arc<Client>,
+    config: ClickHouseConfig,
+    /// For `max_clock_skew_seconds`.
+    flush: StorageConfig,
+    /// Networks whose hosts get per-hour usage rollups in `host_usage`.
+    local_networks: Vec<IpNet>,
+    /// Written with every stored row; None stores NULL.
+    instance: Option<String>,
+    /// Set once the tables exist; until then every request creates them
+    /// first.
+    schema_ready: Arc<AtomicBool>,
+    /// Retention last applied per table, so a TTL is only altered when it
+    /// changes.
+    ttls: Arc<Mutex<HashMap<&'static str, u64>>>,
+    metrics: Arc<StorageMetrics>,
+}
+
+impl ClickHouse {
+    /// Connect to `url` and create the tables.  An unreachable server is
+    /// not an error, so the agent starts and buffers until it answers; a
+    /// refusal, such as bad credentials or a missing database, is.
+    pub fn open(url: &str, config: &ClickHouseConfig) -> anyhow::Result<Self> {
+        let url = ClickHouseUrl::parse(url)?;
+        let backend = Self {
+            client: Arc::new(Client {
+                url,
+                timeout: Duration::from_millis(config.timeout_ms),
+                reachable: AtomicBool::new(true),
+            }),
+            config: config.clone(),
+            flush: StorageConfig::default(),
+            local_networks: Vec::new(),
+            instance: None,
+            schema_ready: Arc::default(),
+            ttls: Arc::default(),
+            metrics: Arc::default(),
+        };
+        match backend.ensure_schema() {
+            Ok(()) => {}
+            Err(e @ ClickHouseError::Unreachable(_)) => {
+                tracing::warn!("{}; buffering writes until it answers", e)
+            }
+            Err(e) => anyhow::bail!("cannot open ClickHouse database: {}", e),
+        }
+        Ok(backend)
+    }
+
+    /// Maintain `host_usage` rollups for hosts in these networks.
+    pub fn with_local_networks(mut self, networks: Vec<IpNet>) -> Self {
+        self.local_networks = networks;
+        self
+    }
+
+    /// Clamp future stamps as `flush.max_clock_skew_seconds` says.
+    pub fn with_storage_config(mut self, flush: StorageConfig) -> Self {
+        self.flush = flush;
+        self
+    }
+
+    /// Tag every row this handle writes with `instance`.
+    pub fn with_instance(mut self, instance: String) -> Self {
+        self.instance = Some(instance);
+        self
+    }
+
+    fn ensure_schema(&self) -> Result<()> {
+        if self.schema_ready.load(Ordering::Acquire) {
+            return Ok(());
+        }
+        for ddl in SCHEMA {
+            self.client.post(&[], ddl.as_bytes())?;
+        }
+        self.schema_ready.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    fn select<T: DeserializeOwned>(&self, sql: &str, params: &Params) -> Result<Vec<T>> {
+        self.ensure_schema()?;
+        let sql = format!("{} FORMAT JSONCompactEachRow", sql);
+        let body = self.client.post(&params.pairs(), sql.as_bytes())?;
+        parse_rows(&body)
+    }
+
+    fn execute(&self, sql: &str, settings: &[(&str, &str)]) -> Result<()> {
+        self.ensure_schema()?;
+        self.client.post(settings, sql.as_bytes()).map(drop)
+    }
+
+    /// Insert JSONEachRow `rows`, one per line, into `table`.
+    fn insert(&self, table: &str, rows: &str) -> Result<()> {
+        let query = format!("INSERT INTO {} FORMAT JSONEachRow", table);
+        self.execute(rows, &[("query", &query)])
+    }
+
+    /// Add an event to what the next flush inserts, dropping the oldest
+    /// rows beyond `buffer_max_rows`.
+    fn buffer(&self, pending: &mut Pending, event: StorageEvent) {
+        let instance = self.instance.as_deref();
+        let mut clamp = FutureClamp::new(&self.flush);
+        match event {
+            StorageEvent::Packets(packets) => {
+                for packet in &packets {
+                    let mut row = PacketRow::raw(packet, instance);
+                    row.clamp(&mut clamp);
+                    self.push_packet(pending, row);
+                }
+            }
+            StorageEvent::Buckets(buckets) => {
+                for bucket in &buckets {
+                    let mut row = PacketRow::bucket(bucket, instance);
+                    row.clamp(&mut clamp);
+                    self.push_packet(pending, row);
+                }
+            }
+            StorageEvent::Alert(alert) => pending.alerts.push_back(alert),
+            StorageEvent::Hostnames(names) => {
+                let resolved_at = now_ms();
+                for (ip, hostname) in names {
+                    let row = serde_json::json!({
+                        "ip": ip,
+                        "hostname": hostname,
+                        "resolved_at": resolved_at,
+                    });
+                    pending.push("hostnames", &row);
+                }
+            }
+            StorageEvent::Peers(peers) => {
+                for peer in peers {
+                    let row = serde_json::json!({
+                        "remote_ip": peer.ip,
+                        "day": peer.day,
+                        "first_seen": peer.first_seen,
+                        "last_seen": peer.last_seen,
+                        "bytes": peer.bytes,
+                        "packets": peer.packets,
+                        "connections": peer.connections,
+                        "instance": instance.unwrap_or_default(),
+                    });
+                    pending.push("peers", &row);
+                }
+            }
+        }
+        // Counted when buffered: a row dropped later was still clamped.
+        clamp.record(&self.metrics);
+        let dropped = pending.truncate(self.config.buffer_max_rows);
+        if dropped > 0 {
+            self.metrics.spill_dropped_rows.inc_by(dropped as u64);
+        }
+    }
+
+    /// Buffer a `packets` row along with its usage rollup.
+    fn push_packet(&self, pending: &mut Pending, row: PacketRow) {
+        let instance = row.instance.as_deref();
+        let traffic = (row.length, row.packet_count);
+        self.record_usage(pending, instance, &row.src_ip, &row.dst_ip, row.timestamp, traffic);
+        pending.push("packets", &row);
+    }
+
+    fn record_usage(
+        &self,
+        pending: &mut Pending,
+        instance: Option<&str>,
+        src_ip: &str,
+        dst_ip: &str,
+        timestamp: i64,
+        (bytes, packets): (u64, u64),
+    ) {
+        let usage = pending.usage.entry(instance.unwrap_or_default().to_string()).or_default();
+        add_usage(usage, &self.local_networks, src_ip, dst_ip, timestamp, bytes, packets);
+    }
+
+    /// Insert everything pending, at most `flush_max_rows` rows a request.
+    /// Stops at the first request the server is unreachable for, keeping
+    /// the rest.  A batch the server refuses is dropped and counted, and
+    /// the flush carries on; the last refusal is returned.
+    fn flush(&self, pending: &mut Pending) -> Result<()> {
+        let mut refused = None;
+        while let Some(alert) = pending.alerts.front() {
+            match self.fold_alert(alert) {
+                Ok(()) => {}
+                Err(e) if e.is_unreachable() => return Err(e),
+                Err(e) => {
+                    tracing::error!("Failed to insert alert: {}", e);
+                    self.metrics.insert_failures.inc();
+                    refused = Some(e);
+                }
+            }
+            pending.alerts.pop_front();
+        }
+        pending.take_usage();
+        for (table, rows) in &mut pending.tables {
+            while !rows.is_empty() {
+                let started = Instant::now();
+                let batch = rows.len().min(self.config.flush_max_rows);
+                let body: Vec<&str> = rows.range(..batch).map(String::as_str).collect();
+                let inserted = match self.insert(table, &body.join("\n")) {
+                    Ok(()) => batch,
+                    Err(e) if e.is_unreachable() => {
+                        self.metrics.transaction_failures.inc();
+                        return Err(e);
+                    }
+                    Err(e) => {
+                        tracing::error!("ClickHouse refused {} {} rows: {}", batch, table, e);
+                        self.metrics.insert_failures.inc_by(batch as u64);
+                        refused = Some(e);
+                        0
+                    }
+                };
+                if *table == "packets" {
+                    self.metrics.record_flush(batch, inserted as u64, started.elapsed());
+                }
+                rows.drain(..batch);
+            }
+        }
+        refused.map_or(Ok(()), Err)
+    }
+
+    /// `flush` on the blocking pool, reporting the outcome to `heartbeat`.
+    async fn flush_blocking(&self, mut pending: Pending, heartbeat: &Heartbeat) -> Pending {
+        let this = self.clone();
+        let flushed = tokio::task::spawn_blocking(move || {
+            let result = this.flush(&mut pending);
+            (pending, result)
+        });
+        let (pending, result) = match flushed.await {
+            Ok(flushed) => flushed,
+            Err(e) => std::panic::resume_unwind(e.into_panic()),
+        };
+        match &result {
+            Err(e) if e.is_unreachable() => {
+                heartbeat.fail(format!("{} ({} rows buffered)", e, pending.rows()))
+            }
+            _ => heartbeat.report(&result),
+        }
+        pending
+    }
+
+    /// Drain `rx` into ClickHouse until the channel closes.  Packets are
+    /// stored raw: at the rates this backend is for, ClickHouse aggregates
+    /// at query time faster than the writer could ahead of it.
+    async fn run(
+        &self,
+        rx: &mut Receiver<StorageEvent>,
+        aggregation_window_seconds: u64,
+        heartbeat: &Heartbeat,
+    ) {
+        if aggregation_window_seconds > 0 {
+            tracing::warn!("ClickHouse stores packets raw; aggregation_window_seconds is ignored");
+        }
+        let mut pending = Pending::default();
+        let mut ticker = interval(Duration::from_millis(self.config.flush_interval_ms));
+        loop {
+            let flush = tokio::select! {
+                event = rx.recv() => match event {
+                    Some(event) => {
+                        self.buffer(&mut pending, event);
+                        // While the server is down only the ticker retries.
+                        pending.packets() >= self.config.flush_max_rows && self.client.reachable()
+                    }
+                    None => {
+                        self.flush_blocking(pending, heartbeat).await;
+                        return;
+                    }
+                },
+                _ = ticker.tick() => true,
+            };
+            if flush {
+                pending = self.flush_blocking(pending, heartbeat).await;
+            }
+        }
+    }
+
+    /// Fold `alert` into the newest unacknowledged row of its rule and
+    /// subject, or start a new row if there is none.
+    fn fold_alert(&self, alert: &Alert) -> Result<()> {
+        let mut params = Params::default();
+        let instance = match self.instance.as_deref() {
+            Some(instance) => format!("instance = {}", params.string(instance)),
+            None => "instance IS NULL".to_string(),
+        };
+        let sql = format!(
+            "{ALERT_SELECT}
+             WHERE rule = {} AND subject = {} AND {instance} AND NOT acked
+             ORDER BY id DESC LIMIT 1",
+            params.string(&alert.rule),
+            params.string(&alert.subject),
+        );
+        let open = self.select::<StoredAlert>(&sql, &params)?;
+        let row = match open.into_iter().next() {
+            Some(open) => StoredAlert {
+                severity: alert.severity.clone(),
+                message: alert.message.clone(),
+                last_seen: open.last_seen.max(alert.timestamp),
+                count: open.count + 1,
+                ..open
+            },
+            None => StoredAlert {
+                id: new_alert_id(),
+                rule: alert.rule.clone(),
+                severity: alert.severity.clone(),
+                subject: alert.subject.clone(),
+                message: alert.message.clone(),
+                first_seen: alert.timestamp,
+                last_seen: alert.timestamp,
+                count: 1,
+                acked: false,
+                acked_by: None,
+                acked_at: None,
+                instance: self.instance.clone(),
+            },
+        };
+        self.insert_alert(&row)
+    }
+
+    /// Write a new version of an alert row.
+    fn insert_alert(&self, alert: &StoredAlert) -> Result<()> {
+        let mut row = serde_json::to_value(alert).expect("alerts serialize to JSON");
+        row["version"] = now_ms().into();
+        self.insert("alerts", &row.to_string())
+    }
+
+    /// Set `table`'s TTL to `seconds` past `column` (epoch ms), unless that
+    /// is already in effect.  Expired rows go in the background merges,
+    /// without rewriting existing parts now.
+    fn apply_ttl(&self, table: &'static str, column: &str, seconds: u64) -> Result<()> {
+        let mut ttls = self.ttls.lock().unwrap();
+        if ttls.get(table) == Some(&seconds) {
+            return Ok(());
+        }
+        self.execute(
+            &format!(
+                "ALTER TABLE {table}
+                 MODIFY TTL toDateTime(intDiv({column}, 1000)) + INTERVAL {seconds} SECOND"
+            ),
+            &[("materialize_ttl_after_modify", "0")],
+        )?;
+        ttls.insert(table, seconds);
+        Ok(())
+    }
+}
+
+fn parse_rows<T: DeserializeOwned>(body: &str) -> Result<Vec<T>> {
+    body.lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            serde_json::from_str(line)
+                .map_err(|e| ClickHouseError::Rejected(format!("unexpected row {}: {}", line, e)))
+        })
+        .collect()
+}
+
+impl StorageBackend for ClickHouse {
+    fn run_writer<'a>(
+        &'a self,
+        rx: &'a mut Receiver<StorageEvent>,
+        aggregation_window_seconds: u64,
+        heartbeat: &'a Heartbeat,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>> {
+        Box::pin(self.run(rx, aggregation_window_seconds, heartbeat))
+    }
+
+    /// Every request opens its own connection, so there is nothing to
+    /// reopen.
+    fn reopen(&self) -> StorageResult<()> {
+        Ok(())
+    }
+
+    /// Inserted at once rather than through the writer's buffer, so the
+    /// sender learns whether the batch was taken.
+    fn insert_ingested(&self, instance: &str, buckets: &[AggregatedBucket]) -> StorageResult<()> {
+        let mut pending = Pending::default();
+        let mut clamp = FutureClamp::new(&self.flush);
+        for bucket in buckets {
+            let mut row = PacketRow::bucket(bucket, Some(instance));
+            row.clamp(&mut clamp);
+            self.push_packet(&mut pending, row);
+        }
+        self.flush(&mut pending)?;
+        clamp.record(&self.metrics);
+        Ok(())
+    }
+
+    fn query_packets(
+        &self,
+        filter: &PacketFilter,
+        limit: usize,
+    ) -> StorageResult<Vec<HistoryRow>> {
+        let mut params = Params::default();
+        let sql = format!(
+            "{HISTORY_SELECT}
+             WHERE {}
+             ORDER BY p.timestamp DESC LIMIT {limit}",
+            filter_sql(filter, "p.", &mut params)
+        );
+        let rows = self.select::<PacketRow>(&sql, &params)?;
+        Ok(rows.into_iter().map(PacketRow::into_history).collect())
+    }
+
+    fn query_usage(
+        &self,
+        ip: Option<&str>,
+        from: i64,
+        to: i64,
+        granularity: UsageGranularity,
+    ) -> StorageResult<Vec<HostUsageRow>> {
+        let mut params = Params::default();
+        let ip = ip.map(|ip| format!(" AND local_ip = {}", params.string(ip))).unwrap_or_default();
+        let sql = format!(
+            "SELECT local_ip, hour - (hour % {}) AS bucket, direction, sum(bytes), sum(packets)
+             FROM host_usage
+             WHERE hour >= {from} AND hour <= {to}{ip}
+             GROUP BY local_ip, bucket, direction
+             ORDER BY bucket, local_ip, direction",
+            granularity.millis()
+        );
+        let rows = self.select::<(String, i64, String, u64, u64)>(&sql, &params)?;
+        let rows = rows.into_iter().map(|(ip, bucket, direction, bytes, packets)| HostUsageRow {
+            ip,
+            bucket,
+            direction,
+            bytes,
+            packets,
+        });
+        Ok(rows.collect())
+    }
+
+    fn query_top(
+        &self,
+        by: TopColumn,
+        filter: &PacketFilter,
+        limit: usize,
+    ) -> StorageResult<Vec<StoredTalker>> {
+        // The column name comes from a fixed enum, never from user input.
+        let mut params = Params::default();
+        let sql = format!(
+            "SELECT toString({col}), sum(length), sum(packet_count), count()
+             FROM packets
+             WHERE {filter} AND {col} IS NOT NULL
+             GROUP BY toString({col})
+             ORDER BY sum(length) DESC LIMIT {limit}",
+            col = by.column(),
+            filter = filter_sql(filter, "", &mut params)
+        );
+        let rows = self.select::<(String, u64, u64, u64)>(&sql, &params)?;
+        let rows = rows
+            .into_iter()
+            .map(|(key, bytes, packets, rows)| StoredTalker { key, bytes, packets, rows });
+        Ok(rows.collect())
+    }
+
+    fn query_totals(&self, filter: &PacketFilter) -> StorageResult<StoredTotals> {
+        let mut params = Params::default();
+        let sql = format!(
+            "SELECT count(), sum(packet_count), sum(length) FROM packets WHERE {}",
+            filter_sql(filter, "", &mut params)
+        );
+        let rows = self.select::<(u64, u64, u64)>(&sql, &params)?;
+        let (rows, packets, bytes) = rows.first().copied().unwrap_or_default();
+        Ok(StoredTotals { rows, packets, bytes })
+    }
+
+    fn query_peers(
+        &self,
+        ip: Option<&str>,
+        from: i64,
+        to: i64,
+    ) -> StorageResult<Vec<PeerTotals>> {
+        let mut params = Params::default();
+        let ip = ip.map(|ip| format!("WHERE remote_ip = {}", params.string(ip)));
+        let ip = ip.unwrap_or_default();
+        let sql = format!(
+            "SELECT remote_ip, day, min(first_seen), max(last_seen), sum(bytes), sum(packets),
+                    sum(connections)
+             FROM peers {ip}
+             GROUP BY remote_ip, day
+             HAVING max(last_seen) >= {from} AND min(first_seen) <= {to}
+             ORDER BY day, sum(bytes) DESC"
+        );
+        let rows = self.select::<(String, i64, i64, i64, u64, u64, u64)>(&sql, &params)?;
+        let rows = rows.into_iter().map(|row| PeerTotals {
+            ip: row.0,
+            day: row.1,
+            first_seen: row.2,
+            last_seen: row.3,
+            bytes: row.4,
+            packets: row.5,
+            connections: row.6,
+        });
+        Ok(rows.collect())
+    }
+
+    fn query_connection(
+        &self,
+        key: &ConnectionKey,
+        limit: usize,
+    ) -> StorageResult<(Vec<HistoryRow>, StoredConnectionTotals)> {
+        let mut params = Params::default();
+        let src_ip = params.string(&key.src_ip.to_string());
+        let dst_ip = params.string(&key.dst_ip.to_string());
+        let (src_port, dst_port) = (key.src_port, key.dst_port);
+        let tuple = format!(
+            "((p.src_ip = {src_ip} AND p.dst_ip = {dst_ip}
+               AND p.src_port = {src_port} AND p.dst_port = {dst_port})
+              OR (p.src_ip = {dst_ip} AND p.dst_ip = {src_ip}
+               AND p.src_port = {dst_port} AND p.dst_port = {src_port}))"
+        );
+        let sql = format!(
+            "{HISTORY_SELECT}
+             WHERE {tuple}
+             ORDER BY p.timestamp DESC LIMIT {limit}"
+        );
+        let rows = self.select::<PacketRow>(&sql, &params)?;
+        let sql = format!(
+            "SELECT count(), sum(p.packet_count), sum(p.length),
+                    minOrNull(p.timestamp), maxOrNull(p.timestamp)
+             FROM packets p WHERE {tuple}"
+        );
+        let totals = self.select::<(u64, u64, u64, Option<i64>, Option<i64>)>(&sql, &params)?;
+        let totals = totals.first().map_or_else(StoredConnectionTotals::default, |row| {
+            StoredConnectionTotals {
+                rows: row.0,
+                packets: row.1,
+                bytes: row.2,
+                first_seen: row.3,
+                last_seen: row.4,
+            }
+        });
+        Ok((rows.into_iter().map(PacketRow::into_history).collect(), totals))
+    }
+
+    fn query_alerts(
+        &self,
+        filter: &AlertFilter,
+        limit: usize,
+    ) -> StorageResult<Vec<StoredAlert>> {
+        let mut params = Params::default();
+        let mut conditions = vec!["1".to_string()];
+        if let Some(before_id) = filter.before_id {
+            conditions.push(format!("id < {}", before_id));
+        }
+        if let Some(severity) = &filter.severity {
+            conditions.push(format!("severity = {}", params.string(severity)));
+        }
+        if let Some(rule) = &filter.rule {
+            conditions.push(format!("rule = {}", params.string(rule)));
+        }
+        if let Some(since) = filter.since {
+            conditions.push(format!("last_seen >= {}", since));
+        }
+        if let Some(acked) = filter.acked {
+            conditions.push(format!("acked = {}", acked));
+        }
+        if let Some(instance) = &filter.instance {
+            conditions.push(format!("instance = {}", params.string(instance)));
+        }
+        let sql = format!(
+            "{ALERT_SELECT}
+             WHERE {}
+             ORDER BY id DESC LIMIT {limit}",
+            conditions.join(" AND ")
+        );
+        Ok(self.select(&sql, &params)?)
+    }
+
+    fn ack_alert(&self, id: i64, by: &str) -> StorageResult<Option<StoredAlert>> {
+        let sql = format!("{ALERT_SELECT} WHERE id = {id}");
+        let rows = self.select::<StoredAlert>(&sql, &Params::default())?;
+        let Some(mut alert) = rows.into_iter().next() else {
+            return Ok(None);
+        };
+        if !alert.acked {
+            alert.acked = true;
+            alert.acked_by = Some(by.to_string());
+            alert.acked_at = Some(now_ms());
+            self.insert_alert(&alert)?;
+        }
+        Ok(Some(alert))
+    }
+
+    /// Age retention only, as a TTL; `max_rows` is not applied.  Nothing is
+    /// deleted now, so the count is always 0.
+    fn delete_old_alerts(
+        &self,
+        older_than_seconds: u64,
+        _max_rows: usize,
+    ) -> StorageResult<usize> {
+        if older_than_seconds > 0 {
+            self.apply_ttl("alerts", "last_seen", older_than_seconds)?;
+        }
+        Ok(0)
+    }
+
+    /// Applied as TTLs on `packets` and `hostnames`, which ClickHouse
+    /// enforces in its merges, so the count is always 0.
+    fn delete_old_data(&self, older_than_seconds: u64) -> StorageResult<usize> {
+        self.apply_ttl("packets", "timestamp", older_than_seconds)?;
+        self.apply_ttl("hostnames", "resolved_at", older_than_seconds)?;
+        Ok(0)
+    }
+
+    fn clear_packets(&self) -> StorageResult<usize> {
+        let count = self.select::<(u64,)>("SELECT count() FROM packets", &Params::default())?;
+        self.execute("TRUNCATE TABLE packets", &[])?;
+        Ok(count.first().map_or(0, |row| row.0 as usize))
+    }
+
+    /// Queries join the `hostnames` table, so no stored row ever needs
+    /// filling in.
+    fn unresolved_rows(
+        &self,
+        after_id: i64,
+        _since: Option<i64>,
+        _limit: usize,
+    ) -> StorageResult<UnresolvedRows> {
+        Ok(UnresolvedRows { through_id: after_id, last_id: after_id, ..UnresolvedRows::default() })
+    }
+
+    fn fill_hostnames(
+        &self,
+        _through_id: i64,
+        _names: &[(String, String)],
+    ) -> StorageResult<usize> {
+        Ok(0)
+    }
+
+    /// Rows have no id, so positions are timestamps and a batch takes every
+    /// row sharing its last one, which may run it over `limit`.
+    fn export_packets(&self, after: i64, limit: usize) -> StorageResult<ExportedPackets> {
+        let sql = format!(
+            "{HISTORY_SELECT}
+             WHERE p.timestamp > {after}
+             ORDER BY p.timestamp LIMIT {limit} WITH TIES"
+        );
+        let rows = self.select::<PacketRecord>(&sql, &Params::default())?;
+        let through = rows.last().map_or(after, |row| row.timestamp);
+        Ok(ExportedPackets { rows, through })
+    }
+
+    fn export_window(&self, from: i64, to: i64) -> StorageResult<Vec<PacketRecord>> {
+        let sql = format!(
+            "{HISTORY_SELECT}
+             WHERE p.timestamp >= {from} AND p.timestamp < {to}"
+        );
+        Ok(self.select::<PacketRecord>(&sql, &Params::default())?)
+    }
+
+    fn import_packets(&self, records: &[PacketRecord]) -> StorageResult<()> {
+        let mut pending = Pending::default();
+        for record in records {
+            let traffic = (record.length, record.packet_count);
+            let (src, dst) = (&record.src_ip, &record.dst_ip);
+            let instance = record.instance.as_deref();
+            self.record_usage(&mut pending, instance, src, dst, record.timestamp, traffic);
+            pending.push("packets", record);
+        }
+        Ok(self.flush(&mut pending)?)
+    }
+
+    fn save_state(&self, key: &str, value: &str) -> StorageResult<()> {
+        let row = serde_json::json!({ "key": key, "value": value, "updated_at": now_ms() });
+        Ok(self.insert("state", &row.to_string())?)
+    }
+
+    fn load_state(&self, key: &str) -> StorageResult<Option<String>> {
+        let mut params = Params::default();
+        let sql = format!("SELECT value FROM state FINAL WHERE key = {}", params.string(key));
+        let rows = self.select::<(String,)>(&sql, &params)?;
+        Ok(rows.into_iter().next().map(|row| row.0))
+    }
+
+    /// Only a change of rate leaves a row, as with SQLite.
+    fn record_sample_rate(&self, sample_rate: u32, since: i64) -> StorageResult<()> {
+        let instance = self.instance.as_deref().unwrap_or_default();
+        let mut params = Params::default();
+        let sql = format!(
+            "SELECT argMax(sample_rate, since) FROM capture_meta WHERE instance = {}
+             HAVING count() > 0",
+            params.string(instance)
+        );
+        let current = self.select::<(u32,)>(&sql, &params)?;
+        if current.first().map_or(1, |row| row.0) != sample_rate {
+            let row = serde_json::json!({
+                "instance": instance,
+                "since": since,
+                "sample_rate": sample_rate,
+            });
+            self.insert("capture_meta", &row.to_string())?;
+        }
+        Ok(())
+    }
+
+    fn metrics(&self) -> &StorageMetrics {
+        &self.metrics
+    }
+
+    fn checkpoint_wal(&self, _threshold_bytes: u64) -> StorageResult<Option<WalCheckpoint>> {
+        Ok(None)
+    }
+
+    fn update_size_metric(&self) {
+        // A scrape should not wait out the timeout while the server is down.
+        if !self.client.reachable() {
+            return;
+        }
+        let size = self.select::<(i64,)>(
+            "SELECT sum(bytes_on_disk) FROM system.parts
+             WHERE database = currentDatabase() AND active",
+            &Params::default(),
+        );
+        match size {
+            Ok(rows) => {
+                self.metrics.db_size_bytes.set(rows.first().map_or(0, |row| row.0));
+            }
+            Err(e) => tracing::warn!("Failed to read ClickHouse table sizes: {}", e),
+        }
+    }
+
+    fn snapshot(&self) -> StorageResult<Snapshot> {
+        Err(StorageError::Snapshot("not supported by the ClickHouse backend".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::categories::PortMatch;
+    use crate::test_support;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    /// One request the fake server took.
+    #[derive(Debug)]
+    struct Request {
+        /// The `query` parameter, which inserts use.
+        query: String,
+        body: String,
+        /// The values bound to placeholders, `p0` first.
+        bound: Vec<String>,
+    }
+
+    type Requests = Arc<Mutex<Vec<Request>>>;
+
+    /// An HTTP server on a thread that records each request and answers it
+    /// with `answer`'s status and body.
+    fn fake_server(
+        answer: impl Fn(&Request) -> (u16, String) + Send + 'static,
+    ) -> (String, Requests) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("clickhouse://ayaflow:secret@{}/traffic", listener.local_addr().unwrap());
+        let requests = Requests::default();
+        let recorded = requests.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 4096];
+                let (head_end, length) = loop {
+                    let n = stream.read(&mut buf).unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                    if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                        let head = String::from_utf8_lossy(&request[..end]).to_lowercase();
+                        let length = head
+                            .lines()
+                            .find_map(|line| line.strip_prefix("content-length: "))
+                            .map_or(0, |length| length.parse().unwrap());
+                        break (end + 4, length);
+                    }
+                };
+                while request.len() < head_end + length {
+                    let n = stream.read(&mut buf).unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let head = String::from_utf8_lossy(&request[..head_end]).into_owned();
+                let target = head.split_whitespace().nth(1).unwrap();
+                let (_, params) = target.split_once('?').unwrap();
+                let params: Vec<_> = form_urlencoded::parse(params.as_bytes()).collect();
+                let param = |name: &str| params.iter().find(|(k, _)| k == name).map(|(_, v)| v);
+                let bound = (0..)
+                    .map_while(|i| param(&format!("param_p{}", i)).map(|v| v.to_string()))
+                    .collect();
+                assert!(head.contains("X-ClickHouse-Key: secret\r\n"));
+                let request = Request {
+                    query: param("query").map_or_else(String::new, |v| v.to_string()),
+                    body: String::from_utf8_lossy(&request[head_end..]).into_owned(),
+                    bound,
+                };
+                let (status, response) = answer(&request);
+                recorded.lock().unwrap().push(request);
+                let response = format!(
+                    "HTTP/1.1 {} X\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    response.len(),
+                    response
+                );
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+        (url, requests)
+    }
+
+    fn packet(timestamp: i64) -> PacketMetadata {
+        PacketMetadata {
+            timestamp,
+            src_ip: "10.0.0.1".into(),
+            dst_ip: "10.0.0.2".into(),
+            length: 100,
+            payload_length: 60,
+            ttl: Some(64),
+            ..test_support::packet()
+        }
+    }
+
+    #[test]
+    fn test_parse_url() {
+        let url = ClickHouseUrl::parse("clickhouse://ch.lan/traffic").unwrap();
+        assert_eq!(url.address, "ch.lan:8123");
+        assert_eq!((url.database.as_str(), url.user.as_deref()), ("traffic", None));
+        let url = ClickHouseUrl::parse("clickhouse://writer:pw@[::1]:9000/traffic/").unwrap();
+        assert_eq!((url.address.as_str(), url.database.as_str()), ("[::1]:9000", "traffic"));
+        assert_eq!((url.user.as_deref(), url.password.as_deref()), (Some("writer"), Some("pw")));
+        assert!(ClickHouseUrl::parse("clickhouse://ch.lan").is_err());
+        assert!(ClickHouseUrl::parse("clickhouse://ch.lan:http/traffic").is_err());
+        assert!(ClickHouseUrl::parse("clickhouse://:8123/traffic").is_err());
+    }
+
+    #[test]
+    fn test_filter_sql_binds_and_translates() {
+        let filter = PacketFilter {
+            from: Some(10),
+            ip: Some("10.0.0.1' OR '1'='1".into()),
+            protocol: Some("tcp".into()),
+            category: Some(PortMatch {
+                ranges: vec![(443, 443)],
+                negate: false,
+                rule: Default::default(),
+            }),
+            ..PacketFilter::default()
+        };
+        let mut params = Params::default();
+        let sql = filter_sql(&filter, "p.", &mut params);
+        assert!(sql.starts_with(&format!("p.timestamp >= 10 AND p.timestamp <= {}", i64::MAX)));
+        assert!(sql.contains("(p.src_ip = {p0:String} OR p.dst_ip = {p0:String})"));
+        assert!(sql.contains("lower(p.protocol) = lower({p1:String})"));
+        assert!(sql.contains("ELSE p.dst_port END BETWEEN 443 AND 443)"));
+        assert!(!sql.contains("10.0.0.1"));
+        let bound = [("param_p0", "10.0.0.1' OR '1'='1"), ("param_p1", "tcp")];
+        assert_eq!(params.pairs(), bound);
+    }
+
+    #[test]
+    fn test_answers() {
+        let response = |status: u16, body: &str| http::Response { status, body: body.into() };
+        assert_eq!(answer(response(200, "[1]\n[2]\n")).unwrap(), "[1]\n[2]\n");
+        let refused = answer(response(404, "Unknown table\n")).unwrap_err();
+        assert!(matches!(&refused, ClickHouseError::Rejected(e) if e == "HTTP 404: Unknown table"));
+        assert!(answer(response(503, "")).unwrap_err().is_unreachable());
+
+        // A connection cut mid-response is unreachable, like no connection.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 4096];
+            let _ = stream.read(&mut buf);
+            let cut = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n9\r\n[1]";
+            let _ = stream.write_all(cut);
+        });
+        let url = format!("clickhouse://{}/traffic", address);
+        let client = Client {
+            url: ClickHouseUrl::parse(&url).unwrap(),
+            timeout: Duration::from_secs(5),
+            reachable: AtomicBool::new(true),
+        };
+        let cut = client.post(&[], b"SELECT 1").unwrap_err();
+        assert!(cut.is_unreachable(), "{}", cut);
+        assert!(!client.reachable());
+    }
+
+    #[test]
+    fn test_buffers_while_unreachable_then_batches() {
+        let down = Arc::new(AtomicBool::new(true));
+        let is_down = down.clone();
+        let (url, requests) = fake_server(move |_| match is_down.load(Ordering::Relaxed) {
+            true => (503, String::new()),
+            false => (200, String::new()),
+        });
+        let config =
+            ClickHouseConfig { flush_max_rows: 3, buffer_max_rows: 4, ..Default::default() };
+
+        // The agent starts, and reads fail as unavailable.
+        let backend = ClickHouse::open(&url, &config).unwrap().with_instance("a".into());
+        let read = backend.query_packets(&PacketFilter::default(), 10);
+        assert!(matches!(read, Err(StorageError::ClickHouse(e)) if e.is_unreachable()));
+
+        // Writes are held, the oldest dropped past buffer_max_rows.
+        let mut pending = Pending::default();
+        backend.buffer(&mut pending, StorageEvent::Packets((0..3).map(packet).collect()));
+        assert!(backend.flush(&mut pending).unwrap_err().is_unreachable());
+        backend.buffer(&mut pending, StorageEvent::Packets((3..6).map(packet).collect()));
+        assert_eq!(pending.rows(), 4);
+        assert_eq!(backend.metrics.spill_dropped_rows.get(), 2);
+
+        down.store(false, Ordering::Relaxed);
+        requests.lock().unwrap().clear();
+        backend.flush(&mut pending).unwrap();
+        assert_eq!(pending.rows(), 0);
+        assert_eq!(backend.metrics.rows_inserted.get(), 4);
+
+        let requests = requests.lock().unwrap();
+        assert!(requests.iter().any(|request| request.body.contains("ENGINE = MergeTree")
+            && request.body.contains("ORDER BY timestamp")));
+        let inserts: Vec<Vec<i64>> = requests
+            .iter()
+            .filter(|request| request.query == "INSERT INTO packets FORMAT JSONEachRow")
+            .map(|request| {
+                let rows = request.body.lines().map(serde_json::from_str::<PacketRow>);
+                rows.map(|row| row.unwrap().timestamp).collect()
+            })
+            .collect();
+        assert_eq!(inserts, [vec![2, 3, 4], vec![5]]);
+    }
+
+    #[test]
+    fn test_future_stamps_clamped_with_their_window() {
+        let (url, _) = fake_server(|_| (200, String::new()));
+        let backend = ClickHouse::open(&url, &ClickHouseConfig::default()).unwrap();
+        let (before, day) = (now_ms(), 86_400_000);
+        let bucket = AggregatedBucket {
+            window_start: before - 1_000,
+            ..AggregatedBucket::from_packet(&packet(before + day))
+        };
+        let mut pending = Pending::default();
+        let packets = vec![packet(before + day), packet(before + 1_000)];
+        backend.buffer(&mut pending, StorageEvent::Packets(packets));
+        backend.buffer(&mut pending, StorageEvent::Buckets(vec![bucket]));
+        assert_eq!(backend.metrics.clamped_timestamps.get(), 2);
+
+        let rows: Vec<PacketRow> = pending.tables["packets"]
+            .iter()
+            .map(|row| serde_json::from_str(row).unwrap())
+            .collect();
+        let after = now_ms();
+        assert!((before..=after).contains(&rows[0].timestamp));
+        // Within the allowed skew, kept as stamped.
+        assert_eq!(rows[1].timestamp, before + 1_000);
+        // The window keeps its start and ends at the write time.
+        assert!((before..=after).contains(&rows[2].timestamp));
+        assert_eq!(rows[2].window_start, Some(before - 1_000));
+        assert_eq!(rows[2].window_end, Some(rows[2].timestamp));
+    }
+
+    #[test]
+    fn test_history_and_alerts_round_trip() {
+        let row = r#"[1000,"10.0.0.1","10.0.0.2",40000,443,"TCP",100,"egress","laptop",null,
+            null,64,null,"connection","eth0",0,5000,60,7,null,null,"outbound","a",null,null,
+            "JP",64500]"#;
+        let row = row.replace("\n            ", " ");
+        let alert = r#"[4,"ttl_below","warning","10.0.0.1","ttl 1",10,20,2,false,null,null,null]"#;
+        let (url, requests) = fake_server(move |request| match request.body.as_str() {
+            body if body.contains("FROM packets p") => (200, format!("{}\n", row)),
+            body if body.contains("FROM alerts FINAL") && request.bound[1] == "10.0.0.1" => {
+                (200, format!("{}\n", alert))
+            }
+            _ => (200, String::new()),
+        });
+        let backend = ClickHouse::open(&url, &ClickHouseConfig::default()).unwrap();
+
+        let filter = PacketFilter { ip: Some("10.0.0.1".into()), ..PacketFilter::default() };
+        let rows = backend.query_packets(&filter, 5).unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!((rows[0].kind, rows[0].packet_count), (RowKind::Aggregated, 7));
+        assert_eq!(rows[0].packet.src_hostname.as_deref(), Some("laptop"));
+        assert_eq!(rows[0].packet.flow_direction, Some(FlowDirection::Outbound));
+        let placed = (rows[0].packet.country.as_deref(), rows[0].packet.asn);
+        assert_eq!(placed, (Some("JP"), Some(64500)));
+        let requests_made = requests.lock().unwrap();
+        let request = requests_made.last().unwrap();
+        assert!(request.body.contains("(p.src_ip = {p0:String} OR p.dst_ip = {p0:String})"));
+        assert!(request.body.contains("LIMIT 5 FORMAT JSONCompactEachRow"));
+        assert_eq!(request.bound, ["10.0.0.1"]);
+        drop(requests_made);
+
+        let placed = |country: &str| PacketFilter {
+            country: Some(country.parse().unwrap()),
+            asn: Some(64500),
+            ..PacketFilter::default()
+        };
+        backend.query_packets(&placed("jp"), 5).unwrap();
+        let body = requests.lock().unwrap().last().unwrap().body.clone();
+        assert!(body.contains("p.asn = 64500 AND p.country = {p0:String}"), "{}", body);
+        assert_eq!(requests.lock().unwrap().last().unwrap().bound, ["JP"]);
+        backend.query_packets(&placed("unknown"), 5).unwrap();
+        let body = requests.lock().unwrap().last().unwrap().body.clone();
+        assert!(body.contains("p.asn = 64500 AND p.country IS NULL"), "{}", body);
+
+        // A repeat folds into the open row; a new subject gets a new id.
+        let mut pending = Pending::default();
+        for subject in ["10.0.0.1", "10.0.0.9"] {
+            let alert = Alert {
+                timestamp: 30,
+                rule: "ttl_below".into(),
+                severity: "critical".into(),
+                subject: subject.into(),
+                message: "ttl 0".into(),
+            };
+            backend.buffer(&mut pending, StorageEvent::Alert(alert));
+        }
+        requests.lock().unwrap().clear();
+        backend.flush(&mut pending).unwrap();
+
+        let requests = requests.lock().unwrap();
+        // An unnamed agent folds only into rows without an instance.
+        assert!(requests[0].body.contains("AND instance IS NULL AND NOT acked"));
+        assert_eq!(requests[0].bound, ["ttl_below", "10.0.0.1"]);
+        let written: Vec<StoredAlert> = requests
+            .iter()
+            .filter(|request| request.query == "INSERT INTO alerts FORMAT JSONEachRow")
+            .map(|request| serde_json::from_str(&request.body).unwrap())
+            .collect();
+        assert_eq!(written.len(), 2);
+        assert_eq!((written[0].id, written[0].count, written[0].last_seen), (4, 3, 30));
+        assert_eq!((written[0].first_seen, written[0].severity.as_str()), (10, "critical"));
+        assert_eq!(written[1].count, 1);
+        assert!((now_ms() / 1000 - (written[1].id >> 20)).abs() <= 1);
+    }
+}
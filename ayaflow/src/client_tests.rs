@@ -0,0 +1,319 @@
+//! `ayaflow-client` against the real router, served on a loopback port.
+//!
+//! Besides exercising every client method, each response is fetched again
+//! as raw JSON and checked against the endpoint's schema, as is the
+//! client's typed value: a field the agent adds, renames, retypes or drops
+//! shows up here rather than being silently ignored by the client.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use ayaflow_client::{
+    AlertParams, BackfillParams, BackfillState, Client, ConnectionEntry, ConnectionFilter,
+    ConnectionSort, ConnectionsParams, DumpHeader, DumpedConnection, Error, FlowDirection,
+    HistoryParams, RowKind, SortOrder,
+};
+use ayaflow_common::openapi::{conforms, ApiSchema};
+use futures_util::StreamExt;
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+use crate::alerts::Alert;
+use crate::api::{router, AppState};
+use crate::config::{ApiConfig, SqliteConfig};
+use crate::fleet::{FleetMembers, IngestBatch, SensorStats};
+use crate::state::{PacketMetadata, TrafficState};
+use crate::storage::Storage;
+use crate::test_support;
+
+const TOKEN: &str = "secret";
+
+fn packet(src_ip: &str, length: usize) -> PacketMetadata {
+    PacketMetadata {
+        timestamp: 5_000,
+        src_ip: src_ip.into(),
+        length,
+        payload_length: length / 2,
+        flow_direction: Some(crate::locality::FlowDirection::Inbound),
+        src_mac: Some("aa:bb:cc:dd:ee:ff".into()),
+        dst_mac: Some("b8:27:eb:00:00:01".into()),
+        ttl: Some(57),
+        dscp: Some(46),
+        dscp_class: Some("EF".into()),
+        src_hostname: Some("example.net".into()),
+        dst_hostname: Some("gateway.lan".into()),
+        domain: Some("example.net".into()),
+        ..test_support::packet()
+    }
+}
+
+fn icmp_packet() -> PacketMetadata {
+    PacketMetadata {
+        timestamp: 6_000,
+        src_port: 0,
+        dst_port: 11 << 8,
+        protocol: "ICMP".into(),
+        icmp_type: Some(11),
+        icmp_code: Some(0),
+        icmp_name: Some("time-exceeded/ttl-exceeded".into()),
+        ..packet("93.184.216.34", 70)
+    }
+}
+
+/// Serve the router with captured and stored traffic on a loopback port.
+async fn serve(name: &str, limits: ApiConfig) -> (SocketAddr, String) {
+    let file = format!("ayaflow-client-{}-{}.db", name, std::process::id());
+    let path = std::env::temp_dir().join(file);
+    let path = path.to_string_lossy().into_owned();
+    let sqlite = SqliteConfig { snapshot_min_free_mb: 0, ..Default::default() };
+    let storage = Storage::open(&path, &sqlite).unwrap().with_instance("edge-1".into());
+    let mut packets = vec![packet("93.184.216.34", 1500), packet("10.0.0.9", 64), icmp_packet()];
+    storage.flush(&mut packets.clone()).unwrap();
+    let alert = Alert {
+        timestamp: 1_000,
+        rule: "ttl_below".into(),
+        severity: "warning".into(),
+        subject: "93.184.216.34".into(),
+        message: "TTL 3 below 5".into(),
+    };
+    storage.insert_alert(&alert).unwrap();
+
+    let traffic = Arc::new(TrafficState::new());
+    for packet in &mut packets {
+        traffic.update(packet);
+    }
+    let fleet = FleetMembers::default();
+    let batch = IngestBatch {
+        instance: "edge-2".into(),
+        sent_at: 6_500,
+        boot: 1_000,
+        sequence: 1,
+        stats: SensorStats { total_packets: 12, ..Default::default() },
+        buckets: Vec::new(),
+    };
+    fleet.record(&batch, 7_000);
+    let state = Arc::new(AppState {
+        traffic,
+        storage: Arc::new(storage),
+        version: Arc::new(crate::version::gather(
+            b"",
+            Default::default(),
+            Default::default(),
+        )),
+        fleet: Arc::new(fleet),
+        ..test_support::app_state()
+    });
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let app = router(state, &[], false, &limits);
+    tokio::spawn(async move {
+        let service = app.into_make_service_with_connect_info::<SocketAddr>();
+        axum::serve(listener, service).await
+    });
+    (addr, path)
+}
+
+/// Both the raw response and the client's value re-serialized against the
+/// endpoint's documented schema: types, optionality and the set of fields
+/// all have to match what the agent sends.
+async fn assert_no_drift<T: ApiSchema + Serialize>(client: &Client, path: &str, typed: &T) {
+    let schema = T::schema();
+    let raw = client.get_json(path).await.unwrap();
+    if let Err(e) = conforms(&raw, &schema) {
+        panic!("{} does not match its schema at {}", path, e);
+    }
+    if let Err(e) = conforms(&serde_json::to_value(typed).unwrap(), &schema) {
+        panic!("the client's {} does not match its schema at {}", path, e);
+    }
+}
+
+#[tokio::test]
+async fn test_client_read_endpoints() {
+    let (addr, path) = serve("read", ApiConfig::default()).await;
+    let client = Client::new(&format!("http://{}", addr)).unwrap();
+
+    let health = client.health().await.unwrap();
+    assert_eq!(health.active_connections, 3);
+    assert_no_drift(&client, "/api/health", &health).await;
+
+    let version = client.version().await.unwrap();
+    assert_eq!(version.version, env!("CARGO_PKG_VERSION"));
+    assert_no_drift(&client, "/api/version", &version).await;
+
+    let stats = client.stats().await.unwrap();
+    assert_eq!(stats.total_packets, 3);
+    assert_no_drift(&client, "/api/stats", &stats).await;
+
+    let live = client.live().await.unwrap();
+    assert_eq!(live.connections.len(), 3);
+    assert_eq!(live.total_bytes, 1500 + 64 + 70);
+    assert_no_drift(&client, "/api/live", &live).await;
+
+    let params = ConnectionsParams {
+        sort: Some(ConnectionSort::Bytes),
+        order: Some(SortOrder::Asc),
+        limit: Some(1),
+        ..Default::default()
+    };
+    let page = client.connections(&params).await.unwrap();
+    assert_eq!((page.total, page.connections.len()), (3, 1));
+    assert_eq!(page.connections[0].connection, "10.0.0.9:40000 -> 10.0.0.1:443");
+    assert_eq!(page.connections[0].service.as_deref(), Some("https"));
+    assert_no_drift(&client, "/api/connections?sort=bytes&order=asc&limit=1", &page).await;
+    let params = ConnectionsParams {
+        ip: Some("93.184.216.34".parse().unwrap()),
+        port: Some(443),
+        protocol: Some("tcp".into()),
+        interface: Some("eth0".into()),
+        direction: Some(FlowDirection::Inbound),
+        offset: Some(0),
+        ..Default::default()
+    };
+    assert_eq!(client.connections(&params).await.unwrap().total, 1);
+
+    let export = client.export_connections().await.unwrap();
+    assert_eq!((export.header.connections, export.connections.len()), (3, 3));
+    assert_eq!(export.header.total_bytes, 1500 + 64 + 70);
+    let header = serde_json::to_value(&export.header).unwrap();
+    conforms(&header, &DumpHeader::schema()).unwrap();
+    for connection in &export.connections {
+        let connection = serde_json::to_value(connection).unwrap();
+        conforms(&connection, &DumpedConnection::<ConnectionEntry>::schema()).unwrap();
+    }
+
+    let rows = client.history(&HistoryParams::default()).await.unwrap();
+    assert_eq!(rows.len(), 3);
+    assert!(rows.iter().all(|row| row.kind == RowKind::Raw && row.packet_count == 1));
+    assert_no_drift(&client, "/api/history", &rows).await;
+    let every_filter = HistoryParams {
+        limit: Some(10),
+        from: Some(5_000),
+        to: Some(5_000),
+        ip: Some("93.184.216.34".parse().unwrap()),
+        interface: Some("eth0".into()),
+        mac: Some("aa:bb:cc:dd:ee:ff".into()),
+        direction: Some(FlowDirection::Inbound),
+        category: Some("web".into()),
+        instance: Some("edge-1".into()),
+        protocol: Some("tcp".into()),
+        icmp_type: None,
+    };
+    let rows = client.history(&every_filter).await.unwrap();
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0].packet.length, 1500);
+    assert_eq!(rows[0].packet.src_hostname.as_deref(), Some("example.net"));
+    let icmp = HistoryParams { icmp_type: Some(11), ..Default::default() };
+    let rows = client.history(&icmp).await.unwrap();
+    assert_eq!(rows[0].packet.icmp_name.as_deref(), Some("time-exceeded/ttl-exceeded"));
+    assert_no_drift(&client, "/api/history?icmp_type=11", &rows).await;
+    let elsewhere = HistoryParams { instance: Some("edge-2".into()), ..Default::default() };
+    assert!(client.history(&elsewhere).await.unwrap().is_empty());
+
+    let alerts = client.alerts(&AlertParams::default()).await.unwrap();
+    assert_eq!(alerts.len(), 1);
+    assert!(!alerts[0].acked);
+    assert_no_drift(&client, "/api/alerts", &alerts).await;
+    let params = AlertParams { severity: Some("critical".into()), ..Default::default() };
+    assert!(client.alerts(&params).await.unwrap().is_empty());
+
+    let fleet = client.fleet().await.unwrap();
+    assert_eq!(fleet.len(), 1);
+    assert_eq!((fleet[0].last_push, fleet[0].stats.total_packets), (7_000, 12));
+    assert_no_drift(&client, "/api/fleet", &fleet).await;
+
+    let blocklist = client.blocklist().await.unwrap();
+    assert!(blocklist.entries.is_empty());
+    assert_no_drift(&client, "/api/blocklist", &blocklist).await;
+
+    // Rejected parameters and missing admin routes are API errors.
+    let bad = HistoryParams { from: Some(10), to: Some(5), ..Default::default() };
+    let error = client.history(&bad).await.unwrap_err();
+    let bad_request = matches!(&error, Error::Api { code, .. } if code == "bad_request");
+    assert!(bad_request, "{}", error);
+    let error = client.config().await.unwrap_err();
+    assert!(matches!(error, Error::Api { status: Some(404), .. }), "{}", error);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn test_client_admin_endpoints() {
+    let limits = ApiConfig {
+        admin_token: Some(TOKEN.into()),
+        base_path: "/ayaflow".into(),
+        ..Default::default()
+    };
+    let (addr, path) = serve("admin", limits).await;
+    let anonymous = Client::new(&format!("http://{}/ayaflow/", addr)).unwrap();
+    let error = anonymous.reset(false).await.unwrap_err();
+    assert!(matches!(error, Error::Api { status: Some(401), .. }), "{}", error);
+    let client = anonymous.with_token(TOKEN);
+
+    let config = client.config().await.unwrap();
+    assert!(config.get("config").is_some(), "{}", config);
+    assert!(client.debug_dump().await.unwrap().is_object());
+
+    let entries = vec!["10.9.8.7".to_string(), "192.168.1.0/24".to_string()];
+    let blocklist = client.set_blocklist(&entries).await.unwrap();
+    assert_eq!(blocklist.entries, ["10.9.8.7/32", "192.168.1.0/24"]);
+    assert_eq!(client.blocklist().await.unwrap(), blocklist);
+    let error = client.set_blocklist(&["nope".into()]).await.unwrap_err();
+    assert!(matches!(error, Error::Api { status: Some(400), .. }));
+
+    let alert = client.ack_alert(1, Some("on call")).await.unwrap();
+    assert_eq!((alert.acked, alert.acked_by.as_deref()), (true, Some("on call")));
+    let acked = AlertParams { acked: Some(true), ..Default::default() };
+    assert_eq!(client.alerts(&acked).await.unwrap(), [alert]);
+    let error = client.ack_alert(99, None).await.unwrap_err();
+    assert!(matches!(error, Error::Api { status: Some(404), .. }));
+
+    let snapshot = client.export_snapshot().await.unwrap();
+    assert!(snapshot.starts_with(b"SQLite format 3\0"));
+
+    assert_eq!(client.backfill_dns().await.unwrap().state, BackfillState::Idle);
+    let params = BackfillParams { skip_private: true, rate: Some(1000), ..Default::default() };
+    let started = client.start_backfill_dns(&params).await.unwrap();
+    assert_eq!(started.state, BackfillState::Running);
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let progress = loop {
+        let progress = client.backfill_dns().await.unwrap();
+        if progress.state != BackfillState::Running || Instant::now() > deadline {
+            break progress;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    };
+    assert_eq!(progress.state, BackfillState::Finished);
+    assert_no_drift(&client, "/api/admin/backfill-dns", &progress).await;
+
+    let reset = client.reset(true).await.unwrap();
+    assert_eq!((reset.packets_cleared, reset.connections_cleared), (3, 3));
+    assert_eq!(reset.db_rows_deleted, Some(3));
+    assert!(client.history(&HistoryParams::default()).await.unwrap().is_empty());
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn test_client_stream() {
+    let (addr, path) = serve("stream", ApiConfig::default()).await;
+    let client = Client::new(&format!("http://{}", addr)).unwrap();
+    let mut stream = client.stream().await.unwrap();
+
+    let event = stream.next().await.unwrap().unwrap();
+    assert_eq!((event.total_packets, event.active_connections), (3, 3));
+    assert!(event.watch.is_none());
+
+    let filter = ConnectionFilter { ip: Some("10.0.0.9".parse().unwrap()), ..Default::default() };
+    stream.watch(&filter).await.unwrap();
+    let watched = loop {
+        let event = stream.next().await.unwrap().unwrap();
+        if let Some(watch) = event.watch {
+            break watch;
+        }
+    };
+    assert_eq!(watched.total, 1);
+    assert_eq!(watched.connections[0].stats.bytes_received, 64);
+
+    stream.unwatch().await.unwrap();
+    while stream.next().await.unwrap().unwrap().watch.is_some() {}
+    stream.close().await.unwrap();
+    std::fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,98 @@
+//! The wall clock for window boundaries, which must not run backwards.
+//!
+//! Rates, cooldowns and uptime are measured on `Instant`s and never see
+//! the wall clock, and stored timestamps keep whatever it read.  Window
+//! boundaries need it, though: the cardinality minutes, the kernel sweep
+//! windows and the fleet push intervals are aligned to it and stored.
+//! After a step back, as NTP makes when the clock ran ahead, such a window
+//! would end before it began.  `WindowClock` holds its readings at the
+//! latest one until the clock catches up, so every delta is zero or more,
+//! and logs and counts each step.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+#[derive(Debug, Default)]
+struct Reading {
+    latest: Option<i64>,
+    /// The clock is behind `latest`, and the step was already counted.
+    behind: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct WindowClock {
+    reading: Mutex<Reading>,
+    backward_steps: AtomicU64,
+}
+
+impl WindowClock {
+    /// Milliseconds since the Unix epoch, never earlier than a previous
+    /// reading.
+    pub fn now_ms(&self) -> i64 {
+        // Read under the lock, so two callers cannot pass each other and
+        // look like a step.
+        let mut reading = self.reading.lock().unwrap();
+        self.advance(&mut reading, chrono::Utc::now().timestamp_millis())
+    }
+
+    /// `now_ms` for a clock that read `wall_ms`.
+    #[cfg(test)]
+    pub fn observe(&self, wall_ms: i64) -> i64 {
+        self.advance(&mut self.reading.lock().unwrap(), wall_ms)
+    }
+
+    fn advance(&self, reading: &mut Reading, wall_ms: i64) -> i64 {
+        match reading.latest {
+            Some(latest) if wall_ms < latest => {
+                if !reading.behind {
+                    reading.behind = true;
+                    self.backward_steps.fetch_add(1, Ordering::Relaxed);
+                    tracing::warn!(
+                        "System clock stepped back {}ms; window boundaries hold until it \
+                         catches up",
+                        latest - wall_ms
+                    );
+                }
+                latest
+            }
+            _ => {
+                if reading.behind {
+                    tracing::info!("System clock caught up; window boundaries follow it again");
+                    reading.behind = false;
+                }
+                reading.latest = Some(wall_ms);
+                wall_ms
+            }
+        }
+    }
+
+    /// Steps back seen since startup, for `ayaflow_clock_backward_steps_total`.
+    pub fn backward_steps(&self) -> u64 {
+        self.backward_steps.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_step_back_holds_and_counts_once() {
+        let clock = WindowClock::default();
+        assert_eq!(clock.observe(10_000), 10_000);
+        assert_eq!(clock.observe(10_000), 10_000);
+        assert_eq!(clock.observe(12_000), 12_000);
+
+        // Stepped back 5s: readings hold while the clock is behind, and the
+        // step counts once however many readings it spans.
+        assert_eq!(clock.observe(7_000), 12_000);
+        assert_eq!(clock.observe(11_999), 12_000);
+        assert_eq!(clock.backward_steps(), 1);
+        assert_eq!(clock.observe(12_500), 12_500);
+
+        // A forward step is followed, and a later step back counts again.
+        assert_eq!(clock.observe(3_600_000), 3_600_000);
+        assert_eq!(clock.observe(0), 3_600_000);
+        assert_eq!(clock.backward_steps(), 2);
+    }
+}
@@ -0,0 +1,493 @@
+//! Full dumps of the live connection table.
+//!
+//! `GET /api/connections/export` streams every connection rather than a
+//! page of them, as JSON lines or CSV.  With `connection_snapshots.dir` set
+//! the agent also writes the same dump to a timestamped file there every
+//! `interval_seconds` and keeps the newest `keep`, so the table as it was
+//! during an incident can be looked at afterwards.  Both walk the table one
+//! shard at a time: an update waits for one shard's copy at most.
+//!
+//! A dump starts with when it was taken and the agent's counts at that
+//! moment: a `{"snapshot": ...}` line in JSON lines, `#` comment lines
+//! before the column names in CSV.
+
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+
+use ayaflow_common::api::DumpHeader;
+use ayaflow_common::config_check::ConfigProblems;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::time::{interval, Duration, Instant, MissedTickBehavior};
+
+use crate::api::AppState;
+use crate::cli::csv_field;
+use crate::health::Heartbeat;
+use crate::openapi::{string_enum, ApiSchema};
+use crate::state::{ConnectionEntry, ConnectionKey};
+
+pub use ayaflow_common::api::SnapshotInfo;
+
+/// CSV columns, in order.  The address fields come from the connection key,
+/// the rest from its `stats` and labels, and `first_seen`/`last_seen` are
+/// epoch milliseconds.
+const CSV_COLUMNS: &[&str] = &[
+    "src_ip",
+    "src_port",
+    "dst_ip",
+    "dst_port",
+    "protocol",
+    "interface",
+    "bytes_sent",
+    "bytes_received",
+    "packets_count",
+    "payload_bytes",
+    "ttl_min",
+    "ttl_max",
+    "retransmits",
+    "tcp_state",
+    "flow_direction",
+    "cast",
+    "src_mac",
+    "dst_mac",
+    "blocklist",
+    "instant_bps",
+    "jitter_ms",
+    "first_seen",
+    "last_seen",
+    "service",
+    "src_device",
+    "dst_device",
+    "src_vendor",
+    "dst_vendor",
+    "connection_id",
+];
+
+/// Names of snapshot files, before the timestamp.
+const FILE_PREFIX: &str = "connections-";
+
+/// Periodic dumps of the connection table to disk (the
+/// `connection_snapshots:` section of the YAML config).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConnectionSnapshotConfig {
+    /// Directory the dumps are written to, created if missing.  Unset
+    /// writes none.
+    #[serde(default)]
+    pub dir: Option<String>,
+    /// Seconds between dumps.
+    #[serde(default = "default_interval_seconds")]
+    pub interval_seconds: u64,
+    /// Dumps kept in `dir`; older ones are deleted.
+    #[serde(default = "default_keep")]
+    pub keep: usize,
+    #[serde(default)]
+    pub format: ExportFormat,
+}
+
+fn default_interval_seconds() -> u64 {
+    300
+}
+
+fn default_keep() -> usize {
+    24
+}
+
+impl Default for ConnectionSnapshotConfig {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            interval_seconds: default_interval_seconds(),
+            keep: default_keep(),
+            format: ExportFormat::default(),
+        }
+    }
+}
+
+impl ConnectionSnapshotConfig {
+    pub fn validate(&self, problems: &mut ConfigProblems) {
+        problems.ensure(
+            self.dir.as_deref() != Some(""),
+            "connection_snapshots.dir",
+            "must not be empty; leave it unset to write no snapshots",
+        );
+        problems.ensure(
+            self.interval_seconds > 0,
+            "connection_snapshots.interval_seconds",
+            "must be at least 1",
+        );
+        problems.ensure(self.keep > 0, "connection_snapshots.keep", "must be at least 1");
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_seconds)
+    }
+}
+
+/// How a dump is written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Jsonl,
+    Csv,
+}
+
+impl ApiSchema for ExportFormat {
+    fn schema() -> serde_json::Value {
+        string_enum(&["jsonl", "csv"])
+    }
+}
+
+impl ExportFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Jsonl => "application/x-ndjson",
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Jsonl => "jsonl",
+            ExportFormat::Csv => "csv",
+        }
+    }
+}
+
+type DumpedConnection = ayaflow_common::api::DumpedConnection<ConnectionEntry>;
+
+/// A dump in progress, yielding the header and then one chunk per shard.
+pub struct ConnectionDump {
+    state: Arc<AppState>,
+    format: ExportFormat,
+    taken_at: DateTime<Utc>,
+    now: Instant,
+    /// None until the header has been yielded.
+    next_shard: Option<usize>,
+    rows: u64,
+}
+
+impl ConnectionDump {
+    pub fn new(state: Arc<AppState>, format: ExportFormat) -> Self {
+        Self {
+            state,
+            format,
+            taken_at: Utc::now(),
+            now: Instant::now(),
+            next_shard: None,
+            rows: 0,
+        }
+    }
+
+    /// `connections-<UTC timestamp>.<format>`.
+    pub fn file_name(&self) -> String {
+        let at = self.taken_at.format("%Y%m%dT%H%M%SZ");
+        format!("{}{}.{}", FILE_PREFIX, at, self.format.extension())
+    }
+
+    fn header(&self) -> Vec<u8> {
+        let traffic = &self.state.traffic;
+        let header = DumpHeader {
+            taken_at: self.taken_at.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            taken_at_ms: self.taken_at.timestamp_millis(),
+            connections: traffic.connections.len(),
+            total_packets: traffic.total_packets.load(Ordering::Relaxed),
+            total_bytes: traffic.total_bytes.load(Ordering::Relaxed),
+        };
+        match self.format {
+            ExportFormat::Jsonl => {
+                let mut line = serde_json::to_vec(&serde_json::json!({ "snapshot": header }))
+                    .unwrap_or_default();
+                line.push(b'\n');
+                line
+            }
+            ExportFormat::Csv => format!(
+                "# ayaflow connection snapshot taken_at={} taken_at_ms={}\n\
+                 # connections={} total_packets={} total_bytes={}\n{}\n",
+                header.taken_at,
+                header.taken_at_ms,
+                header.connections,
+                header.total_packets,
+                header.total_bytes,
+                CSV_COLUMNS.join(","),
+            )
+            .into_bytes(),
+        }
+    }
+
+    fn rows(&mut self, mut entries: Vec<ConnectionEntry>) -> Vec<u8> {
+        self.state.label_connections(&mut entries);
+        self.rows += entries.len() as u64;
+        let taken_at_ms = self.taken_at.timestamp_millis();
+        let wall_ms =
+            |at: Instant| taken_at_ms - self.now.saturating_duration_since(at).as_millis() as i64;
+        let mut out = Vec::new();
+        for entry in entries {
+            let key = entry.connection;
+            let dumped = DumpedConnection {
+                first_seen: wall_ms(entry.stats.first_seen),
+                last_seen: wall_ms(entry.stats.last_seen),
+                entry,
+            };
+            match self.format {
+                ExportFormat::Jsonl => {
+                    if serde_json::to_writer(&mut out, &dumped).is_ok() {
+                        out.push(b'\n');
+                    }
+                }
+                ExportFormat::Csv => {
+                    out.extend_from_slice(csv_row(&key, &dumped).as_bytes());
+                    out.push(b'\n');
+                }
+            }
+        }
+        out
+    }
+}
+
+impl Iterator for ConnectionDump {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        let Some(shard) = self.next_shard else {
+            self.next_shard = Some(0);
+            return Some(self.header());
+        };
+        // Empty shards are skipped rather than yielded as empty chunks.
+        let mut shard = shard;
+        loop {
+            let entries = self.state.traffic.connection_shard(shard)?;
+            shard += 1;
+            self.next_shard = Some(shard);
+            if !entries.is_empty() {
+                return Some(self.rows(entries));
+            }
+        }
+    }
+}
+
+fn csv_row(key: &ConnectionKey, dumped: &DumpedConnection) -> String {
+    let serde_json::Value::Object(mut fields) = serde_json::to_value(dumped).unwrap_or_default()
+    else {
+        return String::new();
+    };
+    if let Some(serde_json::Value::Object(stats)) = fields.remove("stats") {
+        fields.extend(stats);
+    }
+    fields.insert("src_ip".into(), key.src_ip.to_string().into());
+    fields.insert("src_port".into(), key.src_port.into());
+    fields.insert("dst_ip".into(), key.dst_ip.to_string().into());
+    fields.insert("dst_port".into(), key.dst_port.into());
+    let cells: Vec<String> = CSV_COLUMNS
+        .iter()
+        .map(|column| match fields.get(*column) {
+            None | Some(serde_json::Value::Null) => String::new(),
+            Some(serde_json::Value::String(s)) => csv_field(s),
+            Some(value) => csv_field(&value.to_string()),
+        })
+        .collect();
+    cells.join(",")
+}
+
+/// The newest snapshot written, for `/api/health`.
+#[derive(Default)]
+pub struct SnapshotLog {
+    last: Mutex<Option<SnapshotInfo>>,
+}
+
+impl SnapshotLog {
+    pub fn last(&self) -> Option<SnapshotInfo> {
+        self.last.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    fn record(&self, info: SnapshotInfo) {
+        *self.last.lock().unwrap_or_else(|e| e.into_inner()) = Some(info);
+    }
+}
+
+/// Write a snapshot every `config.interval()` until the process exits.  A
+/// failed one marks the `connection_snapshots` component degraded and is
+/// not retried before the next interval.
+pub async fn run_snapshots(
+    state: Arc<AppState>,
+    config: ConnectionSnapshotConfig,
+    heartbeat: Heartbeat,
+) {
+    let Some(dir) = config.dir.clone().map(PathBuf::from) else {
+        return;
+    };
+    let mut ticker = interval(config.interval());
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // The first tick is immediate, with the table still empty.
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        let (state_ref, dir_ref) = (state.clone(), dir.clone());
+        let (format, keep) = (config.format, config.keep);
+        let written = tokio::task::spawn_blocking(move || {
+            write_snapshot(state_ref, &dir_ref, format, keep)
+        })
+        .await;
+        match written {
+            Ok(Ok(info)) => {
+                tracing::debug!("Wrote {} connections to {}", info.connections, info.path);
+                state.connection_snapshots.record(info);
+                heartbeat.beat();
+            }
+            Ok(Err(e)) => {
+                tracing::warn!("Connection snapshot in {} failed: {}", dir.display(), e);
+                heartbeat.fail(e);
+            }
+            Err(e) => heartbeat.fail(e),
+        }
+    }
+}
+
+/// Dump the table to a new file in `dir`, then delete all but the newest
+/// `keep` snapshots there.  The dump is written under a hidden name and
+/// renamed once complete, so a reader never sees half a file.
+fn write_snapshot(
+    state: Arc<AppState>,
+    dir: &Path,
+    format: ExportFormat,
+    keep: usize,
+) -> std::io::Result<SnapshotInfo> {
+    fs::create_dir_all(dir)?;
+    let mut dump = ConnectionDump::new(state, format);
+    let name = dump.file_name();
+    let (path, partial) = (dir.join(&name), dir.join(format!(".{}.partial", name)));
+    let mut bytes = 0;
+    let written = (|| {
+        let mut file = BufWriter::new(File::create(&partial)?);
+        for chunk in &mut dump {
+            file.write_all(&chunk)?;
+            bytes += chunk.len() as u64;
+        }
+        file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&partial, &path)
+    })();
+    if let Err(e) = written {
+        let _ = fs::remove_file(&partial);
+        return Err(e);
+    }
+    prune(dir, keep)?;
+    Ok(SnapshotInfo {
+        path: path.display().to_string(),
+        taken_at_ms: dump.taken_at.timestamp_millis(),
+        connections: dump.rows,
+        bytes,
+    })
+}
+
+/// Delete the oldest snapshot files in `dir` beyond the newest `keep`.
+/// Their names sort by the time they were taken.
+fn prune(dir: &Path, keep: usize) -> std::io::Result<()> {
+    let mut snapshots: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+            name.starts_with(FILE_PREFIX) && (name.ends_with(".jsonl") || name.ends_with(".csv"))
+        })
+        .collect();
+    snapshots.sort();
+    let excess = snapshots.len().saturating_sub(keep);
+    for path in &snapshots[..excess] {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{PacketMetadata, TrafficState};
+    use crate::test_support;
+
+    fn packet(src_port: u16) -> PacketMetadata {
+        PacketMetadata {
+            src_ip: "10.0.0.1".into(),
+            dst_ip: "10.0.0.2".into(),
+            src_port,
+            length: 100,
+            direction: "egress".into(),
+            ..test_support::packet()
+        }
+    }
+
+    fn state_with(count: u16) -> Arc<AppState> {
+        let traffic = TrafficState::new();
+        for port in 0..count {
+            traffic.update(&packet(40000 + port));
+        }
+        Arc::new(AppState {
+            traffic: Arc::new(traffic),
+            ..test_support::app_state()
+        })
+    }
+
+    #[test]
+    fn test_dump_has_header_and_every_connection() {
+        let state = state_with(300);
+
+        let jsonl: Vec<u8> =
+            ConnectionDump::new(state.clone(), ExportFormat::Jsonl).flatten().collect();
+        let lines: Vec<serde_json::Value> = String::from_utf8(jsonl)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 301);
+        assert_eq!(lines[0]["snapshot"]["connections"], 300);
+        assert_eq!(lines[0]["snapshot"]["total_packets"], 300);
+        let taken_at_ms = lines[0]["snapshot"]["taken_at_ms"].as_i64().unwrap();
+        let row = &lines[1];
+        assert_eq!(row["stats"]["protocol"], "TCP");
+        assert_eq!(row["service"], "https");
+        assert!(row["last_seen"].as_i64().unwrap() <= taken_at_ms);
+
+        let csv = ConnectionDump::new(state, ExportFormat::Csv).flatten().collect();
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert!(lines[0].starts_with("# ayaflow connection snapshot taken_at="));
+        assert!(lines[1].starts_with("# connections=300 total_packets=300 "));
+        assert_eq!(lines[2], CSV_COLUMNS.join(","));
+        assert_eq!(lines.len(), 303);
+        let cells: Vec<&str> = lines[3].split(',').collect();
+        assert_eq!(cells.len(), CSV_COLUMNS.len());
+        assert_eq!(&cells[..5], ["10.0.0.1", cells[1], "10.0.0.2", "443", "TCP"]);
+        assert_eq!(cells[CSV_COLUMNS.iter().position(|c| *c == "service").unwrap()], "https");
+    }
+
+    #[test]
+    fn test_snapshots_rotate() {
+        let dir = std::env::temp_dir().join(format!("ayaflow-snapshots-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let state = state_with(3);
+        // Older snapshots of either format, plus a file that is not one.
+        fs::create_dir_all(&dir).unwrap();
+        for name in ["connections-20200101T000000Z.csv", "connections-20200102T000000Z.jsonl"] {
+            fs::write(dir.join(name), "old").unwrap();
+        }
+        fs::write(dir.join("notes.txt"), "keep me").unwrap();
+
+        let info = write_snapshot(state, &dir, ExportFormat::Jsonl, 2).unwrap();
+        assert_eq!(info.connections, 3);
+        let written = fs::read(&info.path).unwrap();
+        assert_eq!(written.len() as u64, info.bytes);
+
+        let mut names: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names.len(), 3, "{:?}", names);
+        assert_eq!(names[0], "connections-20200102T000000Z.jsonl");
+        assert!(info.path.ends_with(&names[1]));
+        assert_eq!(names[2], "notes.txt");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,162 @@
+//! Suppression of packets observed at two capture points.
+//!
+//! On a router a forwarded packet is classified twice: ingress on the
+//! interface it arrived on and egress on the one it leaves by.  Mirrored
+//! ports and bridges produce the same effect on a single interface.  With
+//! `count_forwarded_once` the second sighting is recognised by its 5-tuple,
+//! protocol, and length and dropped before it reaches the counters.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::state::{ConnectionKey, PacketMetadata};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct PacketKey {
+    connection: ConnectionKey,
+    protocol: String,
+    length: usize,
+}
+
+#[derive(Debug)]
+struct Sighting {
+    at: Instant,
+    interface: String,
+    egress: bool,
+    /// A second sighting has already been matched to this one.
+    paired: bool,
+}
+
+#[derive(Debug, Default)]
+struct Seen {
+    sightings: HashMap<PacketKey, Sighting>,
+    /// Insertion order for expiry; stale entries whose key was re-inserted
+    /// later are skipped when popped.
+    order: VecDeque<(Instant, PacketKey)>,
+}
+
+/// Pairs each packet with at most one copy seen at another capture point,
+/// i.e. a different interface or the opposite direction, within `window`.
+///
+/// Repeats at the same capture point (TCP retransmissions, back-to-back
+/// ACKs) are never collapsed.
+#[derive(Debug)]
+pub struct ForwardDedup {
+    window: Duration,
+    seen: Mutex<Seen>,
+}
+
+impl ForwardDedup {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            seen: Mutex::new(Seen::default()),
+        }
+    }
+
+    /// Whether `packet`, observed at `now`, is the second sighting of a
+    /// packet already counted.
+    pub fn is_duplicate(&self, packet: &PacketMetadata, now: Instant) -> bool {
+        let key = PacketKey {
+            connection: ConnectionKey::from_packet(packet),
+            protocol: packet.protocol.clone(),
+            length: packet.length,
+        };
+        let egress = packet.direction == "egress";
+        let mut seen = self.seen.lock().unwrap();
+        seen.expire(now, self.window);
+
+        if let Some(first) = seen.sightings.get_mut(&key) {
+            let elsewhere = first.interface != packet.interface || first.egress != egress;
+            if elsewhere && !first.paired && now.duration_since(first.at) <= self.window {
+                first.paired = true;
+                return true;
+            }
+        }
+        seen.order.push_back((now, key.clone()));
+        seen.sightings.insert(
+            key,
+            Sighting {
+                at: now,
+                interface: packet.interface.clone(),
+                egress,
+                paired: false,
+            },
+        );
+        false
+    }
+}
+
+impl Seen {
+    fn expire(&mut self, now: Instant, window: Duration) {
+        while let Some((at, _)) = self.order.front() {
+            if now.duration_since(*at) <= window {
+                break;
+            }
+            let (at, key) = self.order.pop_front().unwrap();
+            if self.sightings.get(&key).is_some_and(|s| s.at == at) {
+                self.sightings.remove(&key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    fn packet(interface: &str, direction: &str) -> PacketMetadata {
+        PacketMetadata {
+            src_ip: "10.0.0.5".into(),
+            dst_ip: "93.184.216.34".into(),
+            length: 1500,
+            payload_length: 1448,
+            direction: direction.into(),
+            interface: interface.into(),
+            ttl: Some(64),
+            ..test_support::packet()
+        }
+    }
+
+    #[test]
+    fn test_pairs_sightings_at_different_capture_points() {
+        let dedup = ForwardDedup::new(Duration::from_millis(10));
+        let start = Instant::now();
+        let lan_in = packet("eth1", "ingress");
+        let wan_out = packet("eth0", "egress");
+
+        assert!(!dedup.is_duplicate(&lan_in, start));
+        assert!(dedup.is_duplicate(&wan_out, start + Duration::from_millis(1)));
+        // A third sighting is a new packet, not another copy.
+        assert!(!dedup.is_duplicate(&wan_out, start + Duration::from_millis(2)));
+
+        // Same interface, opposite direction (mirrored traffic).
+        let mirrored = packet("eth0", "ingress");
+        assert!(dedup.is_duplicate(&mirrored, start + Duration::from_millis(3)));
+
+        // Repeats at one capture point are separate packets.
+        let later = start + Duration::from_secs(1);
+        assert!(!dedup.is_duplicate(&lan_in, later));
+        assert!(!dedup.is_duplicate(&lan_in, later));
+
+        // Outside the window the copy is counted again.
+        assert!(!dedup.is_duplicate(&wan_out, later + Duration::from_millis(20)));
+    }
+
+    #[test]
+    fn test_expired_sightings_are_dropped() {
+        let dedup = ForwardDedup::new(Duration::from_millis(10));
+        let start = Instant::now();
+        for port in 0..100 {
+            let mut pkt = packet("eth1", "ingress");
+            pkt.src_port = port;
+            dedup.is_duplicate(&pkt, start);
+        }
+        dedup.is_duplicate(&packet("eth1", "ingress"), start + Duration::from_secs(1));
+        let seen = dedup.seen.lock().unwrap();
+        assert_eq!(seen.sightings.len(), 1);
+        assert_eq!(seen.order.len(), 1);
+    }
+}
@@ -0,0 +1,199 @@
+//! Device names for Ethernet addresses.
+//!
+//! On a flat home or lab network DHCP moves hosts between addresses, but a
+//! host's MAC stays put.  The `devices:` config map names known MACs, and
+//! the first three bytes (the OUI) name the vendor for a small table of
+//! common ones.  Like service names, both are attached when connections are
+//! served, never stored.  Frames to off-link hosts carry the router's MAC,
+//! so only the local side of such a connection names a device.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer};
+
+use crate::openapi::ApiSchema;
+use crate::state::ConnectionEntry;
+
+/// A 48-bit Ethernet address.  Parses `aa:bb:cc:dd:ee:ff` or
+/// `AA-BB-CC-DD-EE-FF` and displays in the lower-case colon form stored in
+/// the database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MacAddr(pub [u8; 6]);
+
+impl MacAddr {
+    /// Locally administered addresses (randomized by phones, or picked by
+    /// hypervisors and container runtimes) belong to no vendor.
+    fn is_local(&self) -> bool {
+        self.0[0] & 0x02 != 0
+    }
+
+    fn oui(&self) -> [u8; 3] {
+        [self.0[0], self.0[1], self.0[2]]
+    }
+}
+
+impl fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", a, b, c, d, e, g)
+    }
+}
+
+impl FromStr for MacAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid MAC address {:?}", s);
+        let separator = if s.contains('-') { '-' } else { ':' };
+        let mut mac = [0u8; 6];
+        let mut parts = s.split(separator);
+        for byte in &mut mac {
+            let part = parts.next().filter(|p| p.len() == 2).ok_or_else(invalid)?;
+            *byte = u8::from_str_radix(part, 16).map_err(|_| invalid())?;
+        }
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+        Ok(Self(mac))
+    }
+}
+
+impl<'de> Deserialize<'de> for MacAddr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl ApiSchema for MacAddr {
+    fn schema() -> serde_json::Value {
+        serde_json::json!({ "type": "string", "format": "mac" })
+    }
+}
+
+/// The display form of a captured address; None for the all-zero address
+/// the classifier reports when a frame had no Ethernet header.
+pub fn format_mac(mac: &[u8; 6]) -> Option<String> {
+    (*mac != [0; 6]).then(|| MacAddr(*mac).to_string())
+}
+
+/// Configured device names and built-in vendors.
+#[derive(Debug, Default)]
+pub struct DeviceNames {
+    names: HashMap<MacAddr, String>,
+}
+
+impl DeviceNames {
+    /// Entries whose key is not a MAC address are logged and skipped.
+    pub fn new(devices: &BTreeMap<String, String>) -> Self {
+        let mut names = HashMap::new();
+        for (mac, name) in devices {
+            match mac.parse() {
+                Ok(mac) => {
+                    names.insert(mac, name.clone());
+                }
+                Err(e) => tracing::warn!("Ignoring devices entry: {}", e),
+            }
+        }
+        Self { names }
+    }
+
+    /// The configured name of `mac`, as formatted by `format_mac`.
+    pub fn name(&self, mac: &str) -> Option<&str> {
+        let mac = mac.parse().ok()?;
+        self.names.get(&mac).map(String::as_str)
+    }
+
+    pub fn label_connections(&self, entries: &mut [ConnectionEntry]) {
+        for entry in entries {
+            let stats = &entry.stats;
+            let label = |mac: &Option<String>| {
+                let mac = mac.as_deref()?;
+                Some((self.name(mac).map(str::to_string), vendor(mac).map(str::to_string)))
+            };
+            (entry.src_device, entry.src_vendor) = label(&stats.src_mac).unwrap_or_default();
+            (entry.dst_device, entry.dst_vendor) = label(&stats.dst_mac).unwrap_or_default();
+        }
+    }
+}
+
+/// The vendor of `mac` from its OUI, for a handful of vendors common on
+/// home and lab networks; not a full IEEE registry.
+pub fn vendor(mac: &str) -> Option<&'static str> {
+    let mac: MacAddr = mac.parse().ok()?;
+    let oui = mac.oui();
+    // Hypervisor defaults are locally administered but well known.
+    match oui {
+        [0x52, 0x54, 0x00] => return Some("QEMU/KVM"),
+        [0x02, 0x42, _] => return Some("Docker"),
+        _ if mac.is_local() => return None,
+        _ => {}
+    }
+    OUI_VENDORS
+        .iter()
+        .find(|(prefix, _)| *prefix == oui)
+        .map(|(_, vendor)| *vendor)
+}
+
+const OUI_VENDORS: &[([u8; 3], &str)] = &[
+    ([0x00, 0x03, 0x93], "Apple"),
+    ([0x00, 0x05, 0x69], "VMware"),
+    ([0x00, 0x0c, 0x29], "VMware"),
+    ([0x00, 0x0e, 0x58], "Sonos"),
+    ([0x00, 0x11, 0x32], "Synology"),
+    ([0x00, 0x15, 0x5d], "Microsoft Hyper-V"),
+    ([0x00, 0x16, 0x3e], "Xen"),
+    ([0x00, 0x17, 0x88], "Philips Hue"),
+    ([0x00, 0x1c, 0x42], "Parallels"),
+    ([0x00, 0x50, 0x56], "VMware"),
+    ([0x08, 0x00, 0x27], "VirtualBox"),
+    ([0x18, 0xb4, 0x30], "Nest"),
+    ([0x24, 0x0a, 0xc4], "Espressif"),
+    ([0x24, 0xa4, 0x3c], "Ubiquiti"),
+    ([0x30, 0xae, 0xa4], "Espressif"),
+    ([0x50, 0xc7, 0xbf], "TP-Link"),
+    ([0xb0, 0xa7, 0x37], "Roku"),
+    ([0xb8, 0x27, 0xeb], "Raspberry Pi"),
+    ([0xd8, 0x3a, 0xdd], "Raspberry Pi"),
+    ([0xdc, 0xa6, 0x32], "Raspberry Pi"),
+    ([0xe4, 0x5f, 0x01], "Raspberry Pi"),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_format() {
+        let mac: MacAddr = "AA-BB-CC-00-11-FF".parse().unwrap();
+        assert_eq!(mac.to_string(), "aa:bb:cc:00:11:ff");
+        assert_eq!("aa:bb:cc:00:11:ff".parse::<MacAddr>(), Ok(mac));
+        for bad in ["", "aa:bb:cc:dd:ee", "aa:bb:cc:dd:ee:ff:00", "aabb.ccdd.eeff", "a:b:c:d:e:f"] {
+            assert!(bad.parse::<MacAddr>().is_err(), "{}", bad);
+        }
+        assert_eq!(format_mac(&[0; 6]), None);
+        assert_eq!(format_mac(&[0xb8, 0x27, 0xeb, 1, 2, 3]).as_deref(), Some("b8:27:eb:01:02:03"));
+    }
+
+    #[test]
+    fn test_names_and_vendors() {
+        let devices = BTreeMap::from([
+            ("AA:BB:CC:DD:EE:FF".to_string(), "Living room TV".to_string()),
+            ("not-a-mac".to_string(), "ignored".to_string()),
+        ]);
+        let names = DeviceNames::new(&devices);
+        assert_eq!(names.names.len(), 1);
+        assert_eq!(names.name("aa:bb:cc:dd:ee:ff"), Some("Living room TV"));
+        assert_eq!(names.name("aa:bb:cc:dd:ee:00"), None);
+
+        assert_eq!(vendor("b8:27:eb:12:34:56"), Some("Raspberry Pi"));
+        assert_eq!(vendor("52:54:00:12:34:56"), Some("QEMU/KVM"));
+        assert_eq!(vendor("02:42:ac:11:00:02"), Some("Docker"));
+        // A randomized address that happens to share a listed OUI's low bits.
+        assert_eq!(vendor("ba:27:eb:12:34:56"), None);
+        assert_eq!(vendor("00:00:5e:00:01:01"), None);
+    }
+}
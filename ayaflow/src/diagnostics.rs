@@ -0,0 +1,224 @@
+//! The diagnostic dump: one JSON document describing a running agent.
+//!
+//! Written to the log on SIGUSR1 and served by `GET /api/debug/dump` (admin
+//! token).  Gathering it only reads counters and takes short locks, so
+//! capture carries on meanwhile.  The live connection table is reduced to
+//! counts and the top few connections, which keeps the dump small however
+//! busy the sensor is.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use serde::Serialize;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+
+use crate::api::{AppState, CaptureState, ConfigResponse};
+use crate::dns::{DnsCache, DnsCacheStats};
+use crate::health::{ComponentHealth, ComponentStatus};
+use crate::openapi::api_schema;
+use crate::state::TrafficSummary;
+use crate::storage::StorageStats;
+
+/// Connections listed in a dump, busiest first.
+pub const TOP_CONNECTIONS: usize = 10;
+
+/// Messages waiting on a channel and its capacity; None once it closed.
+type DepthFn = Box<dyn Fn() -> Option<(usize, usize)> + Send + Sync>;
+
+/// The deepest a watched channel got since the headroom sampler last
+/// looked, recorded by its senders.  The default records for no channel.
+#[derive(Clone, Default)]
+pub struct QueuePeak(Arc<AtomicUsize>);
+
+impl QueuePeak {
+    /// Call after sending on `tx`.
+    pub fn record<T>(&self, tx: &mpsc::Sender<T>) {
+        self.0.fetch_max(tx.max_capacity() - tx.capacity(), Ordering::Relaxed);
+    }
+}
+
+/// Channels and caches registered for the dump by the code that creates
+/// them.
+#[derive(Default)]
+pub struct Diagnostics {
+    queues: Mutex<Vec<(&'static str, DepthFn, QueuePeak)>>,
+    dns: OnceLock<Arc<DnsCache>>,
+}
+
+impl Diagnostics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Report the backlog of the channel `tx` sends on.  Only a weak handle
+    /// is kept, so the receiver still sees the channel close.  Senders
+    /// record on the returned peak, so bursts between two headroom samples
+    /// count.
+    pub fn watch_queue<T: Send + 'static>(
+        &self,
+        name: &'static str,
+        tx: &mpsc::Sender<T>,
+    ) -> QueuePeak {
+        let tx = tx.downgrade();
+        let depth: DepthFn = Box::new(move || {
+            let tx = tx.upgrade()?;
+            Some((tx.max_capacity() - tx.capacity(), tx.max_capacity()))
+        });
+        let peak = QueuePeak::default();
+        self.queues.lock().unwrap().push((name, depth, peak.clone()));
+        peak
+    }
+
+    pub fn set_dns_cache(&self, cache: Arc<DnsCache>) {
+        let _ = self.dns.set(cache);
+    }
+
+    /// Depths of the registered channels that are still open.
+    pub fn queues(&self) -> Vec<QueueDepth> {
+        let queues = self.queues.lock().unwrap();
+        queues
+            .iter()
+            .filter_map(|(name, depth, _)| {
+                let (depth, capacity) = depth()?;
+                Some(QueueDepth {
+                    name: name.to_string(),
+                    depth,
+                    capacity,
+                })
+            })
+            .collect()
+    }
+
+    /// The deepest each open channel got since the last call: the peak its
+    /// senders recorded, or its depth now if that is deeper.
+    pub fn take_queue_peaks(&self) -> Vec<QueueDepth> {
+        let queues = self.queues.lock().unwrap();
+        queues
+            .iter()
+            .filter_map(|(name, depth, peak)| {
+                let (depth, capacity) = depth()?;
+                Some(QueueDepth {
+                    name: name.to_string(),
+                    depth: peak.0.swap(0, Ordering::Relaxed).max(depth),
+                    capacity,
+                })
+            })
+            .collect()
+    }
+
+    /// The reverse DNS cache; None unless `resolve_dns` is on.
+    pub fn dns(&self) -> Option<&DnsCache> {
+        self.dns.get().map(|cache| &**cache)
+    }
+
+    pub fn dns_cache(&self) -> Option<DnsCacheStats> {
+        self.dns.get().map(|cache| cache.stats())
+    }
+}
+
+api_schema! {
+    /// Messages waiting on one internal channel.
+    #[derive(Debug, Clone, Serialize)]
+    pub struct QueueDepth {
+        /// "storage", "dns" or "hooks".
+        pub name: String,
+        pub depth: usize,
+        pub capacity: usize,
+    }
+}
+
+api_schema! {
+    /// Everything about the running agent worth attaching to a bug report.
+    #[derive(Debug, Clone, Serialize)]
+    pub struct DiagnosticDump {
+        pub version: String,
+        /// Milliseconds since the Unix epoch.
+        pub generated_at: i64,
+        pub uptime_seconds: u64,
+        pub capture: CaptureState,
+        pub status: ComponentStatus,
+        /// Heartbeats and last errors of the background tasks.
+        pub components: Vec<ComponentHealth>,
+        pub traffic: TrafficSummary,
+        pub queues: Vec<QueueDepth>,
+        /// Absent unless `resolve_dns` is on.
+        pub dns_cache: Option<DnsCacheStats>,
+        pub storage: StorageStats,
+        /// As served by `/api/config`, secrets redacted.
+        pub config: ConfigResponse,
+    }
+}
+
+/// Gather a dump.  Refreshing the database size briefly takes the storage
+/// reader lock, so call this from a blocking thread.
+pub fn dump(state: &AppState) -> DiagnosticDump {
+    let (status, components) = state.health.report();
+    DiagnosticDump {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        generated_at: chrono::Utc::now().timestamp_millis(),
+        uptime_seconds: state.start_time.elapsed().as_secs(),
+        capture: state.capture,
+        status,
+        components,
+        traffic: state.traffic.summary(TOP_CONNECTIONS),
+        queues: state.diagnostics.queues(),
+        dns_cache: state.diagnostics.dns_cache(),
+        storage: state.storage.stats(),
+        config: state.config.as_ref().clone(),
+    }
+}
+
+/// Log a dump every time the process receives SIGUSR1.
+pub async fn dump_on_sigusr1(state: Arc<AppState>) {
+    let mut signals = match signal(SignalKind::user_defined1()) {
+        Ok(signals) => signals,
+        Err(e) => {
+            tracing::warn!("Cannot listen for SIGUSR1, diagnostic dumps disabled: {}", e);
+            return;
+        }
+    };
+    while signals.recv().await.is_some() {
+        let state = state.clone();
+        let json =
+            tokio::task::spawn_blocking(move || serde_json::to_string(&dump(&state))).await;
+        match json {
+            Ok(Ok(json)) => tracing::info!("Diagnostic dump: {}", json),
+            Ok(Err(e)) => tracing::warn!("Failed to serialize diagnostic dump: {}", e),
+            Err(e) => tracing::warn!("Diagnostic dump failed: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_queue_depths() {
+        let diagnostics = Diagnostics::new();
+        let (tx, mut rx) = mpsc::channel::<u32>(8);
+        let peak = diagnostics.watch_queue("storage", &tx);
+        for i in 0..3 {
+            tx.send(i).await.unwrap();
+            peak.record(&tx);
+        }
+        let queues = diagnostics.queues();
+        assert_eq!(queues.len(), 1);
+        assert_eq!((queues[0].depth, queues[0].capacity), (3, 8));
+
+        // A backlog drained before the sampler looks still counts, once.
+        rx.recv().await.unwrap();
+        rx.recv().await.unwrap();
+        assert_eq!(diagnostics.queues()[0].depth, 1);
+        assert_eq!(diagnostics.take_queue_peaks()[0].depth, 3);
+        assert_eq!(diagnostics.take_queue_peaks()[0].depth, 1);
+
+        // The watch does not keep the channel open.
+        drop(tx);
+        rx.recv().await.unwrap();
+        assert!(rx.recv().await.is_none());
+        assert!(diagnostics.queues().is_empty());
+        assert!(diagnostics.dns_cache().is_none());
+    }
+}
@@ -1,7 +1,80 @@
+use ayaflow_common::config_check::ConfigProblems;
 use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
 use tokio::time::{Duration, Instant};
 
+use crate::diagnostics::QueuePeak;
+use crate::health::Heartbeat;
+use crate::state::PacketMetadata;
+use crate::storage::StorageEvent;
+
+pub use ayaflow_common::api::DnsCacheStats;
+
+/// Addresses waiting for the background resolver, at most.  Misses beyond
+/// this are dropped and queued again the next time they are seen.
+pub const QUEUE_CAPACITY: usize = 4096;
+
+/// Reverse lookups the background resolver runs at once.
+const MAX_CONCURRENT_LOOKUPS: usize = 8;
+
+/// Hostnames handed to the storage writer per message, at most.
+const RESULT_BATCH: usize = 64;
+
+/// Reverse DNS cache tuning (the `dns:` section of the YAML config); only
+/// used with `resolve_dns`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DnsConfig {
+    /// Seconds a hostname is kept before it is looked up again.
+    #[serde(default = "default_ttl_seconds")]
+    pub ttl_seconds: u64,
+    /// Seconds a lookup that found no name is kept.
+    #[serde(default = "default_ttl_seconds")]
+    pub failed_ttl_seconds: u64,
+    /// Longest a single lookup may take, in milliseconds.
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+fn default_ttl_seconds() -> u64 {
+    300
+}
+
+fn default_timeout_ms() -> u64 {
+    2000
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        Self {
+            ttl_seconds: default_ttl_seconds(),
+            failed_ttl_seconds: default_ttl_seconds(),
+            timeout_ms: default_timeout_ms(),
+        }
+    }
+}
+
+impl DnsConfig {
+    pub fn validate(&self, problems: &mut ConfigProblems) {
+        problems.ensure(self.ttl_seconds > 0, "dns.ttl_seconds", "must be at least 1");
+        problems.ensure(
+            self.failed_ttl_seconds > 0,
+            "dns.failed_ttl_seconds",
+            "must be at least 1",
+        );
+        problems.ensure(self.timeout_ms > 0, "dns.timeout_ms", "must be at least 1");
+    }
+}
+
+/// A blocking reverse lookup of one address.
+type Lookup = Arc<dyn Fn(IpAddr) -> Option<String> + Send + Sync>;
+
 /// Cached DNS entry with expiration.
 struct CacheEntry {
     hostname: Option<String>,
@@ -12,10 +85,25 @@ struct CacheEntry {
 ///
 /// Lookups that fail (no PTR record, timeout, etc.) are cached as `None` to
 /// prevent repeated queries for non-resolvable addresses.
+///
+/// The capture path only reads the cache (`fill_cached`); misses go on a
+/// queue that `run_resolver` works through in the background, so a slow
+/// resolver never holds up packet processing.
 pub struct DnsCache {
     cache: DashMap<IpAddr, CacheEntry>,
-    ttl: Duration,
+    /// The TTLs in milliseconds; a config reload changes them for entries
+    /// cached from then on.
+    ttl_ms: AtomicU64,
+    failed_ttl_ms: AtomicU64,
     timeout: Duration,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    /// Beats on every completed lookup; timeouts are reported as failures.
+    heartbeat: Option<Heartbeat>,
+    queue: Option<(mpsc::Sender<IpAddr>, QueuePeak)>,
+    /// Addresses on the queue or being resolved, so each is queued once.
+    pending: DashMap<IpAddr, ()>,
+    lookup: Lookup,
 }
 
 impl DnsCache {
@@ -26,11 +114,101 @@ impl DnsCache {
     pub fn new(ttl: Duration, timeout: Duration) -> Self {
         Self {
             cache: DashMap::new(),
-            ttl,
+            ttl_ms: AtomicU64::new(ttl.as_millis() as u64),
+            failed_ttl_ms: AtomicU64::new(ttl.as_millis() as u64),
             timeout,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            heartbeat: None,
+            queue: None,
+            pending: DashMap::new(),
+            lookup: Arc::new(|ip| dns_lookup::lookup_addr(&ip).ok()),
         }
     }
 
+    /// A cache with the TTLs and timeout of `config`.
+    pub fn from_config(config: &DnsConfig) -> Self {
+        let cache = Self::new(Duration::ZERO, Duration::from_millis(config.timeout_ms));
+        cache.set_ttls(config);
+        cache
+    }
+
+    /// Take the TTLs of `config` for lookups finished from now on; cached
+    /// entries keep the expiry they were given.
+    pub fn set_ttls(&self, config: &DnsConfig) {
+        self.ttl_ms.store(config.ttl_seconds * 1000, Ordering::Relaxed);
+        self.failed_ttl_ms.store(config.failed_ttl_seconds * 1000, Ordering::Relaxed);
+    }
+
+    /// How long a lookup that did or did not find a name is kept.
+    fn ttl(&self, found: bool) -> Duration {
+        let ttl = if found { &self.ttl_ms } else { &self.failed_ttl_ms };
+        Duration::from_millis(ttl.load(Ordering::Relaxed))
+    }
+
+    /// Queue cache misses from `fill_cached` on `queue`, to be drained by
+    /// `run_resolver`, recording its depth on `peak`.
+    pub fn with_queue(mut self, queue: mpsc::Sender<IpAddr>, peak: QueuePeak) -> Self {
+        self.queue = Some((queue, peak));
+        self
+    }
+
+    /// Answer lookups with `lookup` instead of the system resolver.
+    #[cfg(test)]
+    pub fn with_lookup(
+        mut self,
+        lookup: impl Fn(IpAddr) -> Option<String> + Send + Sync + 'static,
+    ) -> Self {
+        self.lookup = Arc::new(lookup);
+        self
+    }
+
+    /// Report lookup health through `heartbeat`.
+    pub fn with_heartbeat(mut self, heartbeat: Heartbeat) -> Self {
+        self.heartbeat = Some(heartbeat);
+        self
+    }
+
+    pub fn stats(&self) -> DnsCacheStats {
+        let now = Instant::now();
+        let mut stats = DnsCacheStats {
+            entries: 0,
+            unresolved: 0,
+            expired: 0,
+            pending: self.pending.len(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            ttl_seconds: self.ttl(true).as_secs(),
+            failed_ttl_seconds: self.ttl(false).as_secs(),
+        };
+        for entry in self.cache.iter() {
+            stats.entries += 1;
+            stats.unresolved += usize::from(entry.hostname.is_none());
+            stats.expired += usize::from(now >= entry.expires_at);
+        }
+        stats
+    }
+
+    /// Drop every entry, so each address is looked up again when next
+    /// seen.  Returns how many were dropped.
+    pub fn flush(&self) -> usize {
+        let entries = self.cache.len();
+        self.cache.clear();
+        entries
+    }
+
+    /// Drop the entry for `ip`, if there is one.
+    pub fn remove(&self, ip: &IpAddr) -> bool {
+        self.cache.remove(ip).is_some()
+    }
+
+    /// The fresh cached answer for `ip`; `Some(None)` is a cached failed
+    /// lookup.
+    fn fresh(&self, ip: &IpAddr) -> Option<Option<String>> {
+        let entry = self.cache.get(ip)?;
+        (Instant::now() < entry.expires_at).then(|| entry.hostname.clone())
+    }
+
     /// Resolve an IPv4 dotted-quad string to a hostname.
     ///
     /// Returns `None` when the address cannot be parsed, cannot be resolved,
@@ -42,22 +220,29 @@ impl DnsCache {
         };
 
         // Fast path: cache hit & still fresh.
-        if let Some(entry) = self.cache.get(&ip) {
-            if Instant::now() < entry.expires_at {
-                return entry.hostname.clone();
-            }
+        if let Some(hostname) = self.fresh(&ip) {
+            return hostname;
         }
 
         // Slow path: perform the reverse lookup (blocking, via spawn_blocking)
         // with a timeout to prevent stalls.
-        let ip_copy = ip;
-        let result = tokio::time::timeout(self.timeout, async move {
-            tokio::task::spawn_blocking(move || dns_lookup::lookup_addr(&ip_copy).ok())
+        let resolver = self.lookup.clone();
+        let lookup = tokio::time::timeout(self.timeout, async move {
+            tokio::task::spawn_blocking(move || resolver(ip))
                 .await
                 .unwrap_or(None)
         })
-        .await
-        .unwrap_or(None);
+        .await;
+        if let Some(ref heartbeat) = self.heartbeat {
+            match lookup {
+                Ok(_) => heartbeat.beat(),
+                Err(_) => heartbeat.fail(format!(
+                    "reverse lookup of {} timed out after {:?}",
+                    ip, self.timeout
+                )),
+            }
+        }
+        let result = lookup.unwrap_or(None);
 
         // If the resolved hostname is just the IP address echoed back, treat
         // it as a failed lookup.
@@ -67,17 +252,107 @@ impl DnsCache {
             ip,
             CacheEntry {
                 hostname: hostname.clone(),
-                expires_at: Instant::now() + self.ttl,
+                expires_at: Instant::now() + self.ttl(hostname.is_some()),
             },
         );
 
         hostname
     }
+
+    /// The fresh cached answer for `ip_str`, if there is one; `Some(None)`
+    /// is a cached failed lookup.  Never queues or resolves.
+    pub fn peek(&self, ip_str: &str) -> Option<Option<String>> {
+        self.fresh(&ip_str.parse().ok()?)
+    }
+
+    /// The cached hostname for `ip_str`, without waiting on DNS.  A miss
+    /// queues the address for the background resolver and returns `None`.
+    /// Only these lookups count as cache hits and misses.
+    pub fn cached(&self, ip_str: &str) -> Option<String> {
+        let ip: IpAddr = ip_str.parse().ok()?;
+        if let Some(hostname) = self.fresh(&ip) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return hostname;
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        self.enqueue(ip);
+        None
+    }
+
+    fn enqueue(&self, ip: IpAddr) {
+        let Some((queue, peak)) = &self.queue else { return };
+        if self.pending.insert(ip, ()).is_some() {
+            return;
+        }
+        match queue.try_send(ip) {
+            Ok(()) => peak.record(queue),
+            Err(_) => {
+                self.pending.remove(&ip);
+            }
+        }
+    }
+
+    /// Fill in both hostnames for a batch of packets from the cache, looking
+    /// each distinct address up once.  Misses are left `None` and queued.
+    pub fn fill_cached(&self, packets: &mut [PacketMetadata]) {
+        let mut cached: HashMap<String, Option<String>> = HashMap::new();
+        for packet in packets {
+            for (ip, hostname) in [
+                (&packet.src_ip, &mut packet.src_hostname),
+                (&packet.dst_ip, &mut packet.dst_hostname),
+            ] {
+                *hostname = cached
+                    .entry(ip.clone())
+                    .or_insert_with(|| self.cached(ip))
+                    .clone();
+            }
+        }
+    }
+
+    /// Resolve queued addresses, a few at a time, until the queue closes.
+    /// Hostnames found are sent to the storage writer, which records them in
+    /// the `hostnames` table so rows stored before the lookup finished still
+    /// show them.
+    pub async fn run_resolver(
+        self: Arc<Self>,
+        mut queue: mpsc::Receiver<IpAddr>,
+        tx: mpsc::Sender<StorageEvent>,
+    ) {
+        let mut lookups = JoinSet::new();
+        let mut found = Vec::new();
+        loop {
+            tokio::select! {
+                next = queue.recv(), if lookups.len() < MAX_CONCURRENT_LOOKUPS => {
+                    let Some(ip) = next else { break };
+                    let cache = self.clone();
+                    lookups.spawn(async move {
+                        let ip_str = ip.to_string();
+                        let hostname = cache.resolve(&ip_str).await;
+                        cache.pending.remove(&ip);
+                        hostname.map(|hostname| (ip_str, hostname))
+                    });
+                }
+                Some(done) = lookups.join_next() => {
+                    if let Ok(Some(resolved)) = done {
+                        found.push(resolved);
+                    }
+                    let idle = lookups.is_empty();
+                    if !found.is_empty() && (idle || found.len() >= RESULT_BATCH) {
+                        let batch = std::mem::take(&mut found);
+                        if tx.send(StorageEvent::Hostnames(batch)).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_support;
 
     #[tokio::test]
     async fn test_cache_stores_result() {
@@ -112,4 +387,118 @@ mod tests {
         // The failed lookup should still be cached.
         assert!(cache.cache.contains_key(&"192.0.2.1".parse::<IpAddr>().unwrap()));
     }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_flush_remove_and_hit_counts() {
+        let config = DnsConfig {
+            failed_ttl_seconds: 10,
+            ..DnsConfig::default()
+        };
+        let cache = DnsCache::from_config(&config);
+        let insert = |ip: &str, hostname: Option<&str>, ttl: u64| {
+            let entry = CacheEntry {
+                hostname: hostname.map(str::to_string),
+                expires_at: Instant::now() + Duration::from_secs(ttl),
+            };
+            cache.cache.insert(ip.parse().unwrap(), entry);
+        };
+        insert("192.0.2.1", Some("old.example"), config.ttl_seconds);
+        insert("192.0.2.2", None, config.failed_ttl_seconds);
+        insert("192.0.2.3", Some("other.example"), config.ttl_seconds);
+
+        assert_eq!(cache.cached("192.0.2.1").as_deref(), Some("old.example"));
+        assert_eq!(cache.cached("192.0.2.2"), None);
+        assert_eq!(cache.cached("192.0.2.9"), None);
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (2, 1));
+        assert_eq!((stats.ttl_seconds, stats.failed_ttl_seconds), (300, 10));
+
+        assert!(cache.remove(&"192.0.2.1".parse().unwrap()));
+        assert!(!cache.remove(&"192.0.2.1".parse().unwrap()));
+        assert_eq!(cache.cached("192.0.2.1"), None);
+        assert_eq!(cache.stats().misses, 2);
+        assert_eq!(cache.peek("192.0.2.3"), Some(Some("other.example".into())));
+        assert_eq!(cache.flush(), 2);
+        assert_eq!(cache.stats().entries, 0);
+        assert_eq!(cache.peek("192.0.2.3"), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_new_ttls_apply_to_new_entries() {
+        let cache = DnsCache::from_config(&DnsConfig::default())
+            .with_lookup(|ip| (ip.to_string() != "192.0.2.2").then(|| "a.example".into()));
+        cache.resolve("192.0.2.1").await;
+        cache.set_ttls(&DnsConfig {
+            ttl_seconds: 30,
+            failed_ttl_seconds: 5,
+            ..DnsConfig::default()
+        });
+        let stats = cache.stats();
+        assert_eq!((stats.ttl_seconds, stats.failed_ttl_seconds), (30, 5));
+        cache.resolve("192.0.2.2").await;
+        cache.resolve("192.0.2.3").await;
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(cache.peek("192.0.2.2"), None);
+        assert_eq!(cache.peek("192.0.2.3"), Some(Some("a.example".into())));
+        tokio::time::advance(Duration::from_secs(60)).await;
+        // The entry from before the change keeps its 300 seconds.
+        assert_eq!(cache.peek("192.0.2.1"), Some(Some("a.example".into())));
+        assert_eq!(cache.peek("192.0.2.3"), None);
+    }
+
+    #[tokio::test]
+    async fn test_fill_cached_queues_misses() {
+        let (queue_tx, queue_rx) = mpsc::channel(QUEUE_CAPACITY);
+        let cache = Arc::new(
+            DnsCache::new(Duration::from_secs(300), Duration::from_secs(2))
+                .with_queue(queue_tx, QueuePeak::default()),
+        );
+        let packet = |src: &str, dst: &str| PacketMetadata {
+            src_ip: src.into(),
+            dst_ip: dst.into(),
+            src_hostname: Some("stale".into()),
+            dst_hostname: Some("stale".into()),
+            ..test_support::packet()
+        };
+        let mut batch = vec![
+            packet("127.0.0.1", "192.0.2.1"),
+            packet("192.0.2.1", "127.0.0.1"),
+            packet("127.0.0.1", "not-an-ip"),
+        ];
+        // Nothing is cached yet: every hostname is cleared and each valid
+        // address is queued once.
+        cache.fill_cached(&mut batch);
+        assert!(batch.iter().all(|p| p.src_hostname.is_none() && p.dst_hostname.is_none()));
+        assert_eq!(cache.pending.len(), 2);
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.pending), (0, 2));
+
+        let (tx, mut rx) = mpsc::channel(16);
+        let resolver = tokio::spawn(cache.clone().run_resolver(queue_rx, tx));
+        while !cache.pending.is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let loopback = cache.resolve("127.0.0.1").await;
+        assert_eq!(cache.cache.len(), 2);
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.pending, stats.expired), (2, 0, 0));
+        assert!(stats.unresolved >= 1);
+        if let Some(ref hostname) = loopback {
+            match rx.recv().await {
+                Some(StorageEvent::Hostnames(found)) => {
+                    assert_eq!(found, vec![("127.0.0.1".to_string(), hostname.clone())]);
+                }
+                _ => panic!("expected resolved hostnames"),
+            }
+        }
+        resolver.abort();
+
+        cache.fill_cached(&mut batch);
+        assert_eq!(batch[0].src_hostname, loopback);
+        assert_eq!(batch[0].dst_hostname, None);
+        assert_eq!(batch[1].src_hostname, None);
+        assert_eq!(batch[1].dst_hostname, loopback);
+        assert_eq!(batch[2].dst_hostname, None);
+    }
 }
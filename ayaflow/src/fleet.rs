@@ -0,0 +1,715 @@
+//! Fleet mode: sensors push traffic summaries to a central agent.
+//!
+//! A sensor with `fleet.central_url` set folds the rows it stores into one
+//! bucket per connection per push interval, and POSTs them with a few live
+//! counters to the central agent's `/api/ingest`.  Batches that cannot be
+//! sent wait for the next interval, at most `max_pending_pushes` of them;
+//! beyond that the oldest are dropped.  The central agent writes what it
+//! receives through its storage backend, tagged with the sender's instance
+//! name, so `?instance=` picks one sensor out of its history and reports.
+//!
+//! Each batch carries the sensor's boot stamp and a sequence number.  A
+//! push whose answer was lost is sent again, and the central agent takes
+//! a key it has already stored as a duplicate rather than storing the rows
+//! twice.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::time::{interval, Duration, Instant};
+
+use crate::diagnostics::QueuePeak;
+use crate::health::Heartbeat;
+use crate::hooks::{self, WebhookUrl};
+use crate::openapi::api_schema;
+use crate::state::{AggregatedBucket, PacketMetadata, TrafficState};
+use crate::storage::StorageEvent;
+
+pub use ayaflow_common::api::{FleetMember, SensorStats};
+
+/// Copies of stored rows waiting for the pusher, at most; more are dropped.
+pub const QUEUE_CAPACITY: usize = 1024;
+
+/// Buckets sent in one request; a busier interval is split.
+pub const MAX_PUSH_BUCKETS: usize = 5_000;
+
+/// Buckets `/api/ingest` takes in one request.
+pub const MAX_INGEST_BUCKETS: usize = 10_000;
+
+/// Largest request body `/api/ingest` reads.
+pub const MAX_INGEST_BYTES: usize = 16 * 1024 * 1024;
+
+/// How long the central agent may take to answer a push.
+const PUSH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Pushing to a central agent (the `fleet:` section of the YAML config).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FleetConfig {
+    /// `http://host[:port][/base_path]` of the central agent.  Unset pushes
+    /// nothing.  The token goes in the clear, so the host must be a
+    /// loopback address: a local TLS tunnel to the central agent.
+    #[serde(default)]
+    pub central_url: Option<String>,
+    /// How often summaries are pushed, and so the window each covers.
+    #[serde(default = "default_push_interval_seconds")]
+    pub push_interval_seconds: u64,
+    /// Bearer token sent with every push: the central agent's
+    /// `api.ingest_token`.
+    #[serde(default)]
+    pub token: Option<String>,
+    /// Batches kept while the central agent cannot be reached.
+    #[serde(default = "default_max_pending_pushes")]
+    pub max_pending_pushes: usize,
+}
+
+fn default_push_interval_seconds() -> u64 {
+    60
+}
+
+fn default_max_pending_pushes() -> usize {
+    60
+}
+
+impl Default for FleetConfig {
+    fn default() -> Self {
+        Self {
+            central_url: None,
+            push_interval_seconds: default_push_interval_seconds(),
+            token: None,
+            max_pending_pushes: default_max_pending_pushes(),
+        }
+    }
+}
+
+fn sensor_stats(traffic: &TrafficState, uptime: Duration) -> SensorStats {
+    let totals = traffic.totals(None);
+    SensorStats {
+        uptime_seconds: uptime.as_secs(),
+        total_packets: totals.packets,
+        total_bytes: totals.bytes,
+        active_connections: traffic.active_connections.load(Ordering::Relaxed),
+        pps_60s: totals.last_minute.pps,
+        bps_60s: totals.last_minute.bps,
+    }
+}
+
+
+api_schema! {
+    /// Body of `POST /api/ingest`: one push interval of a sensor.
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct IngestBatch {
+        /// The sender's instance name, stored with every row.
+        pub instance: String,
+        /// When the sender built the batch, milliseconds since the Unix
+        /// epoch.
+        pub sent_at: i64,
+        /// When the sender's pusher started, milliseconds since the Unix
+        /// epoch; with `sequence`, the batch's idempotency key.
+        #[serde(default)]
+        pub boot: i64,
+        /// Counts the sender's batches from 1 since `boot`; 0 has no key
+        /// and is never taken as a duplicate.
+        #[serde(default)]
+        pub sequence: u64,
+        pub stats: SensorStats,
+        /// Stored traffic per connection over the interval.
+        pub buckets: Vec<AggregatedBucket>,
+    }
+}
+
+/// A batch's idempotency key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchKey {
+    pub instance: String,
+    pub boot: i64,
+    pub sequence: u64,
+}
+
+impl IngestBatch {
+    pub fn key(&self) -> BatchKey {
+        BatchKey {
+            instance: self.instance.clone(),
+            boot: self.boot,
+            sequence: self.sequence,
+        }
+    }
+
+    /// Why the batch is refused, if it is.
+    pub fn validate(&self) -> Result<(), String> {
+        if !(1..=128).contains(&self.instance.len()) || self.instance.contains(char::is_control) {
+            return Err("instance must be 1 to 128 bytes without control characters".into());
+        }
+        if self.buckets.len() > MAX_INGEST_BUCKETS {
+            return Err(format!(
+                "at most {} buckets per batch, got {}",
+                MAX_INGEST_BUCKETS,
+                self.buckets.len()
+            ));
+        }
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            for (field, ip) in [("src_ip", &bucket.src_ip), ("dst_ip", &bucket.dst_ip)] {
+                if ip.parse::<IpAddr>().is_err() {
+                    let shown: String = ip.chars().take(64).collect();
+                    return Err(format!("buckets[{}].{} is not an address: {:?}", i, field, shown));
+                }
+            }
+            if bucket.window_start > bucket.window_end {
+                return Err(format!("buckets[{}] ends before it starts", i));
+            }
+        }
+        Ok(())
+    }
+}
+
+api_schema! {
+    /// What `POST /api/ingest` stored.
+    #[derive(Debug, Clone, Serialize)]
+    pub struct IngestResponse {
+        pub rows: usize,
+        /// The batch was stored before and is not stored again.
+        pub duplicate: bool,
+    }
+}
+
+/// Sensors that pushed to this agent since it started.
+#[derive(Debug, Default)]
+pub struct FleetMembers {
+    members: Mutex<BTreeMap<String, FleetMember>>,
+    /// Per instance, the boot stamp and highest sequence claimed.
+    claimed: Mutex<BTreeMap<String, (i64, u64)>>,
+}
+
+impl FleetMembers {
+    /// Reserve the batch's key before it is stored.  False for a key
+    /// already stored or being stored: a sensor sends its batches in
+    /// order, so any sequence up to the highest of the same boot is one.
+    /// Keys are kept in memory, so a resend across a restart of this
+    /// agent is stored again.
+    pub fn claim(&self, batch: &BatchKey) -> bool {
+        if batch.sequence == 0 {
+            return true;
+        }
+        let mut claimed = self.claimed.lock().unwrap();
+        match claimed.get_mut(&batch.instance) {
+            Some((boot, highest)) if *boot == batch.boot => {
+                if batch.sequence <= *highest {
+                    return false;
+                }
+                *highest = batch.sequence;
+            }
+            // A sensor restart starts over at 1; an older boot's resend
+            // is taken rather than guessed at.
+            Some(entry) if entry.0 < batch.boot => *entry = (batch.boot, batch.sequence),
+            Some(_) => {}
+            None => {
+                claimed.insert(batch.instance.clone(), (batch.boot, batch.sequence));
+            }
+        }
+        true
+    }
+
+    /// Give back the claim of a batch that could not be stored, so its
+    /// resend is taken.
+    pub fn release(&self, batch: &BatchKey) {
+        let mut claimed = self.claimed.lock().unwrap();
+        if let Some((boot, highest)) = claimed.get_mut(&batch.instance) {
+            if *boot == batch.boot && *highest == batch.sequence && batch.sequence > 0 {
+                *highest -= 1;
+            }
+        }
+    }
+
+    /// Note a stored batch, received at `now` (epoch ms).
+    pub fn record(&self, batch: &IngestBatch, now: i64) {
+        let mut members = self.members.lock().unwrap();
+        let member = members.entry(batch.instance.clone()).or_insert_with(|| FleetMember {
+            instance: batch.instance.clone(),
+            last_push: now,
+            batches_received: 0,
+            rows_received: 0,
+            stats: SensorStats::default(),
+        });
+        member.last_push = now;
+        member.batches_received += 1;
+        member.rows_received += batch.buckets.len() as u64;
+        member.stats = batch.stats.clone();
+    }
+
+    /// Every sensor, by instance name.
+    pub fn list(&self) -> Vec<FleetMember> {
+        self.members.lock().unwrap().values().cloned().collect()
+    }
+}
+
+/// How often the tee reports copies it dropped.
+const DROP_REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Pass every event from `rx` on to the storage writer, copying stored
+/// packets and swept buckets to the pusher.  Copies are dropped while the
+/// pusher is behind, so it never holds up the writer; the drops are
+/// logged at most once a minute with their count.
+pub async fn tee(
+    mut rx: Receiver<StorageEvent>,
+    writer: Sender<StorageEvent>,
+    pusher: Sender<StorageEvent>,
+    peak: QueuePeak,
+) {
+    let mut report = DropReport::new(Instant::now());
+    let mut pushing = true;
+    while let Some(event) = rx.recv().await {
+        let copy = match &event {
+            StorageEvent::Packets(packets) => Some(StorageEvent::Packets(packets.clone())),
+            StorageEvent::Buckets(buckets) => Some(StorageEvent::Buckets(buckets.clone())),
+            _ => None,
+        };
+        if let Some(copy) = copy.filter(|_| pushing) {
+            match pusher.try_send(copy) {
+                Ok(()) => peak.record(&pusher),
+                Err(TrySendError::Full(StorageEvent::Packets(rows))) => {
+                    report.count(rows.len(), Instant::now())
+                }
+                Err(TrySendError::Full(StorageEvent::Buckets(rows))) => {
+                    report.count(rows.len(), Instant::now())
+                }
+                Err(TrySendError::Full(_)) => {}
+                Err(TrySendError::Closed(_)) => {
+                    tracing::warn!("Fleet pusher stopped; nothing more is pushed");
+                    pushing = false;
+                }
+            }
+        }
+        if writer.send(event).await.is_err() {
+            tracing::warn!("Storage writer stopped; the fleet tee passes nothing more on");
+            break;
+        }
+    }
+    report.flush();
+}
+
+/// Copies the tee dropped since it last said so.
+#[derive(Debug)]
+struct DropReport {
+    batches: u64,
+    rows: u64,
+    since: Instant,
+}
+
+impl DropReport {
+    fn new(now: Instant) -> Self {
+        Self { batches: 0, rows: 0, since: now }
+    }
+
+    /// Count a dropped batch of `rows`, and log the count once the
+    /// interval is up.
+    fn count(&mut self, rows: usize, now: Instant) {
+        self.batches += 1;
+        self.rows += rows as u64;
+        if now.duration_since(self.since) >= DROP_REPORT_INTERVAL {
+            self.flush();
+            self.since = now;
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.batches > 0 {
+            tracing::warn!(
+                "Fleet pusher is behind; {} batches of {} rows not pushed",
+                self.batches,
+                self.rows
+            );
+        }
+        self.batches = 0;
+        self.rows = 0;
+    }
+}
+
+/// The interval being folded and the batches waiting to be sent.
+#[derive(Debug)]
+pub struct PushQueue {
+    /// When the queue started, sent as each batch's `boot`.
+    boot: i64,
+    /// Last sequence number given to a batch.
+    sequence: u64,
+    window_start: i64,
+    buckets: BTreeMap<String, AggregatedBucket>,
+    /// Kernel-swept buckets, which already cover a window of their own.
+    swept: Vec<AggregatedBucket>,
+    pending: VecDeque<IngestBatch>,
+}
+
+impl PushQueue {
+    pub fn new(now: i64) -> Self {
+        Self {
+            boot: now,
+            sequence: 0,
+            window_start: now,
+            buckets: BTreeMap::new(),
+            swept: Vec::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Fold stored packets and swept buckets into the current interval.
+    pub fn add(&mut self, event: StorageEvent) {
+        match event {
+            StorageEvent::Packets(packets) => packets.iter().for_each(|p| self.fold(p)),
+            StorageEvent::Buckets(buckets) => self.swept.extend(buckets),
+            _ => {}
+        }
+    }
+
+    fn fold(&mut self, packet: &PacketMetadata) {
+        let key = format!(
+            "{}:{} -> {}:{} {}",
+            packet.src_ip, packet.src_port, packet.dst_ip, packet.dst_port, packet.protocol
+        );
+        self.buckets
+            .entry(key)
+            .and_modify(|bucket| bucket.merge(packet))
+            .or_insert_with(|| AggregatedBucket::from_packet(packet));
+    }
+
+    /// End the interval at `now` and queue its batches, at least one so
+    /// the stats go out even when nothing was stored.  Drops the oldest
+    /// batches beyond `max_pending` and returns how many.
+    pub fn close(
+        &mut self,
+        instance: &str,
+        now: i64,
+        stats: SensorStats,
+        max_pending: usize,
+    ) -> usize {
+        let window_start = std::mem::replace(&mut self.window_start, now);
+        let mut buckets: Vec<AggregatedBucket> = std::mem::take(&mut self.buckets)
+            .into_values()
+            .map(|bucket| AggregatedBucket { window_start, window_end: now, ..bucket })
+            .collect();
+        buckets.append(&mut self.swept);
+        loop {
+            let rest = buckets.split_off(buckets.len().min(MAX_PUSH_BUCKETS));
+            self.sequence += 1;
+            self.pending.push_back(IngestBatch {
+                instance: instance.to_string(),
+                sent_at: now,
+                boot: self.boot,
+                sequence: self.sequence,
+                stats: stats.clone(),
+                buckets,
+            });
+            if rest.is_empty() {
+                break;
+            }
+            buckets = rest;
+        }
+        let excess = self.pending.len().saturating_sub(max_pending);
+        self.pending.drain(..excess);
+        excess
+    }
+
+    /// Batches waiting to be sent.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+/// A validated `fleet:` section.
+#[derive(Debug)]
+pub struct Pusher {
+    url: WebhookUrl,
+    authorization: String,
+    interval: Duration,
+    max_pending: usize,
+}
+
+impl Pusher {
+    /// None when no central agent is configured.  Fails on any invalid
+    /// setting, so a typo never silently stops the pushes.
+    pub fn new(config: &FleetConfig) -> anyhow::Result<Option<Self>> {
+        let Some(central) = &config.central_url else {
+            return Ok(None);
+        };
+        let ingest = format!("{}/api/ingest", central.trim_end_matches('/'));
+        let url =
+            WebhookUrl::parse(&ingest).map_err(|e| anyhow::anyhow!("fleet.central_url: {}", e))?;
+        let token = config.token.as_deref().filter(|token| !token.is_empty());
+        let token = token.ok_or_else(|| anyhow::anyhow!("fleet: set token to push"))?;
+        anyhow::ensure!(
+            url.is_loopback(),
+            "fleet.central_url: {:?} would send the token in the clear; pushes need a loopback \
+             address, such as a local TLS tunnel to the central agent",
+            central
+        );
+        anyhow::ensure!(
+            config.push_interval_seconds > 0,
+            "fleet.push_interval_seconds must be at least 1"
+        );
+        anyhow::ensure!(
+            config.max_pending_pushes > 0,
+            "fleet.max_pending_pushes must be at least 1"
+        );
+        Ok(Some(Self {
+            url,
+            authorization: format!("Bearer {}", token),
+            interval: Duration::from_secs(config.push_interval_seconds),
+            max_pending: config.max_pending_pushes,
+        }))
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Send pending batches oldest first until one fails, which stays
+    /// queued for the next interval.  A batch the central agent rejects as
+    /// invalid is dropped, as sending it again would not help.  Returns
+    /// the rows sent.
+    pub async fn send_pending(&self, queue: &mut PushQueue) -> anyhow::Result<usize> {
+        let mut sent = 0;
+        while let Some(batch) = queue.pending.front() {
+            let body = serde_json::to_vec(batch)?;
+            let headers = [
+                ("Content-Type", "application/json"),
+                ("Authorization", self.authorization.as_str()),
+            ];
+            let response = hooks::send(&self.url, &headers, &body, PUSH_TIMEOUT)
+                .await
+                .map_err(|e| anyhow::anyhow!("push: {}", e))?;
+            match response.status {
+                200..=299 => sent += batch.buckets.len(),
+                400 | 413 | 422 => tracing::warn!(
+                    "Central agent rejected a batch of {} rows: HTTP {}: {}",
+                    batch.buckets.len(),
+                    response.status,
+                    String::from_utf8_lossy(&response.body).trim()
+                ),
+                status => anyhow::bail!("central agent answered {}", status),
+            }
+            queue.pending.pop_front();
+        }
+        Ok(sent)
+    }
+
+    /// Fold what the sensor stores from `rx` and push it every interval.
+    /// Runs until `rx` closes.
+    pub async fn run(
+        self,
+        instance: String,
+        mut rx: Receiver<StorageEvent>,
+        traffic: Arc<TrafficState>,
+        heartbeat: Heartbeat,
+    ) {
+        let started = Instant::now();
+        let mut queue = PushQueue::new(traffic.clock.now_ms());
+        let mut ticker = interval(self.interval);
+        // The first tick completes immediately; nothing to push yet.
+        ticker.tick().await;
+        loop {
+            tokio::select! {
+                event = rx.recv() => match event {
+                    Some(event) => queue.add(event),
+                    None => return,
+                },
+                _ = ticker.tick() => {
+                    let now = traffic.clock.now_ms();
+                    let stats = sensor_stats(&traffic, started.elapsed());
+                    let dropped = queue.close(&instance, now, stats, self.max_pending);
+                    if dropped > 0 {
+                        tracing::warn!("Dropped {} unsent fleet batches", dropped);
+                    }
+                    match self.send_pending(&mut queue).await {
+                        Ok(_) => heartbeat.beat(),
+                        Err(e) => {
+                            tracing::warn!(
+                                "Fleet push failed, {} batches waiting: {}",
+                                queue.pending(),
+                                e
+                            );
+                            heartbeat.fail(format!("{} ({} batches waiting)", e, queue.pending()));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    use crate::api::{router, AppState, CaptureState};
+    use crate::config::ApiConfig;
+    use crate::storage::PacketFilter;
+    use crate::test_support;
+
+    const TOKEN: &str = "ingest-secret";
+
+    fn packet(src_ip: &str, length: usize) -> PacketMetadata {
+        PacketMetadata {
+            timestamp: 5_000,
+            src_ip: src_ip.into(),
+            length,
+            payload_length: length / 2,
+            ttl: Some(57),
+            ..test_support::packet()
+        }
+    }
+
+    /// Serve a central agent on `listener`, taking pushes with `TOKEN`.
+    async fn central(listener: tokio::net::TcpListener) -> Arc<AppState> {
+        let state = Arc::new(AppState {
+            capture: CaptureState::Disabled,
+            ..test_support::app_state()
+        });
+        let limits = ApiConfig { ingest_token: Some(TOKEN.into()), ..Default::default() };
+        let app = router(state.clone(), &[], false, &limits);
+        tokio::spawn(async move {
+            let service = app.into_make_service_with_connect_info::<SocketAddr>();
+            axum::serve(listener, service).await
+        });
+        state
+    }
+
+    fn pusher(addr: SocketAddr, max_pending: usize) -> Pusher {
+        let config = FleetConfig {
+            central_url: Some(format!("http://{}/", addr)),
+            token: Some(TOKEN.into()),
+            max_pending_pushes: max_pending,
+            ..Default::default()
+        };
+        Pusher::new(&config).unwrap().unwrap()
+    }
+
+    fn stored(state: &AppState, instance: &str) -> Vec<u64> {
+        let filter = PacketFilter { instance: Some(instance.into()), ..Default::default() };
+        let rows = state.storage.query_packets(&filter, 100).unwrap();
+        rows.iter().map(|row| row.packet_count).collect()
+    }
+
+    #[tokio::test]
+    async fn test_sensors_push_to_central() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = central(listener).await;
+        let pusher = pusher(addr, 10);
+
+        for (instance, packets) in [("edge-1", 3), ("edge-2", 1)] {
+            let mut queue = PushQueue::new(1_000);
+            let batch = (0..packets).map(|_| packet("93.184.216.34", 100)).collect();
+            queue.add(StorageEvent::Packets(batch));
+            queue.add(StorageEvent::Packets(vec![packet("10.0.0.9", 60)]));
+            let stats = SensorStats { total_packets: packets + 1, ..Default::default() };
+            assert_eq!(queue.close(instance, 61_000, stats, 10), 0);
+            assert_eq!(pusher.send_pending(&mut queue).await.unwrap(), 2);
+            assert_eq!(queue.pending(), 0);
+        }
+
+        let mut edge_1 = stored(&state, "edge-1");
+        edge_1.sort();
+        assert_eq!(edge_1, vec![1, 3]);
+        assert_eq!(stored(&state, "edge-2"), vec![1, 1]);
+        let members = state.fleet.list();
+        let names: Vec<&str> = members.iter().map(|m| m.instance.as_str()).collect();
+        assert_eq!(names, vec!["edge-1", "edge-2"]);
+        assert_eq!((members[0].batches_received, members[0].rows_received), (1, 2));
+        assert_eq!(members[0].stats.total_packets, 4);
+    }
+
+    #[tokio::test]
+    async fn test_push_buffers_while_central_is_down() {
+        // Reserve a port with nothing listening on it yet.
+        let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let pusher = pusher(addr, 2);
+        let mut queue = PushQueue::new(0);
+        for i in 1..=3 {
+            let packet = PacketMetadata { timestamp: i * 1_000 - 500, ..packet("10.0.0.9", 60) };
+            queue.add(StorageEvent::Packets(vec![packet]));
+            let dropped = queue.close("edge-1", i * 1_000, SensorStats::default(), 2);
+            assert_eq!(dropped, usize::from(i == 3));
+        }
+        assert!(pusher.send_pending(&mut queue).await.is_err());
+        assert_eq!(queue.pending(), 2);
+
+        let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+        let state = central(listener).await;
+        assert_eq!(pusher.send_pending(&mut queue).await.unwrap(), 2);
+        assert_eq!(queue.pending(), 0);
+        // The oldest interval was dropped.
+        let filter = PacketFilter { instance: Some("edge-1".into()), ..Default::default() };
+        let rows = state.storage.query_packets(&filter, 100).unwrap();
+        let mut timestamps: Vec<i64> = rows.iter().map(|row| row.packet.timestamp).collect();
+        timestamps.sort();
+        assert_eq!(timestamps, vec![1_500, 2_500]);
+    }
+
+    #[tokio::test]
+    async fn test_resent_batch_is_stored_once() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = central(listener).await;
+        let pusher = pusher(addr, 10);
+
+        let mut queue = PushQueue::new(1_000);
+        queue.add(StorageEvent::Packets(vec![packet("10.0.0.9", 60)]));
+        queue.close("edge-1", 61_000, SensorStats::default(), 10);
+        let first = queue.pending[0].clone();
+        assert_eq!((first.boot, first.sequence), (1_000, 1));
+        pusher.send_pending(&mut queue).await.unwrap();
+
+        // Its answer was lost, so it goes again, followed by the next one.
+        queue.pending.push_back(first);
+        queue.add(StorageEvent::Packets(vec![packet("10.0.0.9", 60)]));
+        queue.close("edge-1", 121_000, SensorStats::default(), 10);
+        assert_eq!(queue.pending[1].sequence, 2);
+        pusher.send_pending(&mut queue).await.unwrap();
+        assert_eq!(stored(&state, "edge-1"), vec![1, 1]);
+        assert_eq!(state.fleet.list()[0].batches_received, 2);
+
+        // A restarted sensor numbers from 1 again under a later boot.
+        let mut queue = PushQueue::new(200_000);
+        queue.add(StorageEvent::Packets(vec![packet("10.0.0.9", 60)]));
+        queue.close("edge-1", 261_000, SensorStats::default(), 10);
+        pusher.send_pending(&mut queue).await.unwrap();
+        assert_eq!(stored(&state, "edge-1").len(), 3);
+    }
+
+    #[test]
+    fn test_token_only_to_loopback() {
+        let config = |url: &str| FleetConfig {
+            central_url: Some(url.into()),
+            token: Some(TOKEN.into()),
+            ..Default::default()
+        };
+        let e = Pusher::new(&config("http://central.lan:9100")).unwrap_err();
+        assert!(e.to_string().contains("in the clear"), "{}", e);
+        assert!(Pusher::new(&config("http://localhost:9100/base")).unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_ingest_rejects_bad_token_and_batches() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = central(listener).await;
+
+        let mut queue = PushQueue::new(0);
+        queue.add(StorageEvent::Packets(vec![packet("not-an-ip", 100)]));
+        queue.close("edge-1", 1_000, SensorStats::default(), 10);
+        let body = serde_json::to_vec(&queue.pending[0]).unwrap();
+        let url = WebhookUrl::parse(&format!("http://{}/api/ingest", addr)).unwrap();
+        let headers = [("Content-Type", "application/json"), ("Authorization", "Bearer nope")];
+        let response = hooks::send(&url, &headers, &body, PUSH_TIMEOUT).await.unwrap();
+        assert_eq!(response.status, 401);
+
+        // Refused as invalid, so dropped rather than retried.
+        assert_eq!(pusher(addr, 10).send_pending(&mut queue).await.unwrap(), 0);
+        assert_eq!(queue.pending(), 0);
+        assert!(state.fleet.list().is_empty());
+    }
+}
@@ -0,0 +1,606 @@
+//! Country and autonomous system of the remote end of each flow, looked up
+//! in MaxMind DB files (GeoLite2-Country, GeoLite2-ASN or anything laid out
+//! like them) and stored with each packet.
+//!
+//! The reader covers what those databases use: a binary search tree over
+//! the address bits, 24-, 28- or 32-bit records, and the data section's
+//! strings, numbers, maps and arrays.
+
+use anyhow::{bail, Context, Result};
+use ayaflow_common::api::FlowDirection;
+use ayaflow_common::config_check::ConfigProblems;
+use dashmap::DashMap;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::fmt;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::openapi::ApiSchema;
+use crate::state::PacketMetadata;
+
+/// Precedes the metadata map at the end of every database.
+const METADATA_MARKER: &[u8] = b"\xAB\xCD\xEFMaxMind.com";
+
+/// Zero bytes between the search tree and the data section.
+const DATA_SEPARATOR: usize = 16;
+
+/// Deepest nesting of maps, arrays and pointers decoded.
+const MAX_DEPTH: u32 = 32;
+
+/// Addresses whose location is remembered, at most; the cache starts over
+/// when it fills.
+const CACHE_CAPACITY: usize = 65536;
+
+/// GeoIP databases (the `geoip:` section of the YAML config).  Either may
+/// be left out; packets then carry no country or no ASN.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GeoIpConfig {
+    /// A MaxMind DB with `country.iso_code`, e.g. GeoLite2-Country.mmdb.
+    #[serde(default)]
+    pub country_db: Option<PathBuf>,
+    /// A MaxMind DB with `autonomous_system_number`, e.g. GeoLite2-ASN.mmdb.
+    #[serde(default)]
+    pub asn_db: Option<PathBuf>,
+}
+
+impl GeoIpConfig {
+    pub fn validate(&self, problems: &mut ConfigProblems) {
+        let databases = [("geoip.country_db", &self.country_db), ("geoip.asn_db", &self.asn_db)];
+        for (field, path) in databases {
+            if let Some(path) = path {
+                let message = format!("{} is not a file", path.display());
+                problems.ensure(path.is_file(), field, message);
+            }
+        }
+    }
+}
+
+/// The `country` history filter: a country code in either case, or
+/// `unknown` for packets GeoIP did not place.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum CountryMatch {
+    /// An upper-case ISO 3166 code, as stored.
+    Code(String),
+    Unknown,
+}
+
+impl fmt::Display for CountryMatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CountryMatch::Code(code) => f.write_str(code),
+            CountryMatch::Unknown => f.write_str("unknown"),
+        }
+    }
+}
+
+impl FromStr for CountryMatch {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("unknown") {
+            return Ok(CountryMatch::Unknown);
+        }
+        if s.len() != 2 || !s.bytes().all(|b| b.is_ascii_alphabetic()) {
+            let shown: String = s.chars().take(32).collect();
+            return Err(format!("country must be a two-letter code or unknown, got {:?}", shown));
+        }
+        Ok(CountryMatch::Code(s.to_ascii_uppercase()))
+    }
+}
+
+impl<'de> Deserialize<'de> for CountryMatch {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl ApiSchema for CountryMatch {
+    fn schema() -> serde_json::Value {
+        serde_json::json!({ "type": "string", "pattern": "^([A-Za-z]{2}|unknown)$" })
+    }
+}
+
+/// A decoded data section value.  Lookups only follow maps and read
+/// strings and unsigned integers; anything else is `Other`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Uint(u64),
+    Map(Vec<(String, Value)>),
+    Other,
+}
+
+impl Value {
+    /// The value under `path`, one map key per step.
+    pub fn get(&self, path: &[&str]) -> Option<&Value> {
+        path.iter().try_fold(self, |value, key| match value {
+            Value::Map(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        })
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn as_u64(&self) -> Option<u64> {
+        match self {
+            Value::Uint(n) => Some(*n),
+            _ => None,
+        }
+    }
+}
+
+/// One MaxMind DB file, held in memory.
+pub struct Reader {
+    bytes: Vec<u8>,
+    node_count: usize,
+    record_size: usize,
+    ip_version: u64,
+    /// Where IPv4 addresses start in an IPv6 tree: the node reached
+    /// after 96 zero bits.
+    ipv4_start: usize,
+    database_type: String,
+}
+
+impl Reader {
+    pub fn open(path: &Path) -> Result<Self> {
+        let bytes = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+        Self::from_bytes(bytes).with_context(|| format!("loading {}", path.display()))
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Self> {
+        let Some(marker) =
+            bytes.windows(METADATA_MARKER.len()).rposition(|w| w == METADATA_MARKER)
+        else {
+            bail!("not a MaxMind DB: no metadata marker");
+        };
+        let metadata = &bytes[marker + METADATA_MARKER.len()..];
+        let Some((metadata, _)) = decode(metadata, 0, 0) else {
+            bail!("invalid metadata");
+        };
+        let field = |key| metadata.get(&[key]).and_then(Value::as_u64);
+        let (Some(node_count), Some(record_size), Some(ip_version)) =
+            (field("node_count"), field("record_size"), field("ip_version"))
+        else {
+            bail!("metadata lacks node_count, record_size or ip_version");
+        };
+        if ![24, 28, 32].contains(&record_size) {
+            bail!("unsupported record size {}", record_size);
+        }
+        if ![4, 6].contains(&ip_version) {
+            bail!("unsupported IP version {}", ip_version);
+        }
+        let (node_count, record_size) = (node_count as usize, record_size as usize);
+        let tree_size = node_count * record_size / 4;
+        if tree_size + DATA_SEPARATOR > marker {
+            bail!("search tree of {} nodes overruns the file", node_count);
+        }
+        let database_type = metadata.get(&["database_type"]).and_then(Value::as_str);
+        let mut reader = Self {
+            database_type: database_type.unwrap_or_default().to_string(),
+            bytes,
+            node_count,
+            record_size,
+            ip_version,
+            ipv4_start: 0,
+        };
+        if ip_version == 6 {
+            reader.ipv4_start = (0..96).fold(0, |node, _| reader.next(node, false));
+        }
+        Ok(reader)
+    }
+
+    /// The `database_type` from the metadata, e.g. "GeoLite2-Country".
+    pub fn database_type(&self) -> &str {
+        &self.database_type
+    }
+
+    /// The record for the network containing `ip`, if the database has one.
+    pub fn lookup(&self, ip: IpAddr) -> Option<Value> {
+        let (bits, len, mut node) = match ip {
+            IpAddr::V4(v4) if self.ip_version == 6 => (u32::from(v4).into(), 32, self.ipv4_start),
+            IpAddr::V4(v4) => (u32::from(v4).into(), 32, 0),
+            IpAddr::V6(_) if self.ip_version == 4 => return None,
+            IpAddr::V6(v6) => (u128::from(v6), 128, 0),
+        };
+        for i in (0..len).rev() {
+            node = self.next(node, bits >> i & 1 == 1);
+        }
+        if node <= self.node_count {
+            return None;
+        }
+        let data = &self.bytes[self.node_count * self.record_size / 4 + DATA_SEPARATOR..];
+        let offset = (node - self.node_count).checked_sub(DATA_SEPARATOR)?;
+        decode(data, offset, 0).map(|(value, _)| value)
+    }
+
+    /// The left or right record of `node`; past the last node, `node`
+    /// itself, so a walk that has stopped stays put.
+    fn next(&self, node: usize, right: bool) -> usize {
+        if node >= self.node_count {
+            return node;
+        }
+        let at = node * self.record_size / 4;
+        let b = |i: usize| self.bytes[at + i] as usize;
+        match (self.record_size, right) {
+            (24, false) => b(0) << 16 | b(1) << 8 | b(2),
+            (24, true) => b(3) << 16 | b(4) << 8 | b(5),
+            (28, false) => (b(3) & 0xF0) << 20 | b(0) << 16 | b(1) << 8 | b(2),
+            (28, true) => (b(3) & 0x0F) << 24 | b(4) << 16 | b(5) << 8 | b(6),
+            (_, false) => b(0) << 24 | b(1) << 16 | b(2) << 8 | b(3),
+            (_, true) => b(4) << 24 | b(5) << 16 | b(6) << 8 | b(7),
+        }
+    }
+}
+
+fn be(bytes: &[u8]) -> usize {
+    bytes.iter().fold(0, |n, &b| n << 8 | b as usize)
+}
+
+/// Decode the value at `at` in `data`, returning it and the offset after
+/// it.  Pointers are offsets into `data`.
+fn decode(data: &[u8], mut at: usize, depth: u32) -> Option<(Value, usize)> {
+    if depth > MAX_DEPTH {
+        return None;
+    }
+    let control = *data.get(at)?;
+    at += 1;
+    let mut kind = control >> 5;
+    if kind == 1 {
+        let (ss, vvv) = ((control >> 3 & 3) as usize, (control & 7) as usize);
+        let raw = be(data.get(at..at + ss + 1)?);
+        let target = match ss {
+            0 => vvv << 8 | raw,
+            1 => (vvv << 16 | raw) + 2048,
+            2 => (vvv << 24 | raw) + 526336,
+            _ => raw,
+        };
+        let (value, _) = decode(data, target, depth + 1)?;
+        return Some((value, at + ss + 1));
+    }
+    if kind == 0 {
+        kind = 7 + *data.get(at)?;
+        at += 1;
+    }
+    let mut size = (control & 0x1F) as usize;
+    if size >= 29 {
+        let extra = size - 28;
+        let n = be(data.get(at..at + extra)?);
+        at += extra;
+        size = [29, 285, 65821][extra - 1] + n;
+    }
+    let payload = data.get(at..at + size);
+    match kind {
+        2 => {
+            let s = std::str::from_utf8(payload?).ok()?;
+            Some((Value::String(s.to_string()), at + size))
+        }
+        5 | 6 | 9 if size <= 8 => Some((Value::Uint(be(payload?) as u64), at + size)),
+        3 | 4 | 8 | 10 | 15 => payload.map(|_| (Value::Other, at + size)),
+        7 => {
+            let mut entries = Vec::with_capacity(size.min(64));
+            for _ in 0..size {
+                let (key, next) = decode(data, at, depth + 1)?;
+                let Value::String(key) = key else {
+                    return None;
+                };
+                let (value, next) = decode(data, next, depth + 1)?;
+                entries.push((key, value));
+                at = next;
+            }
+            Some((Value::Map(entries), at))
+        }
+        11 => {
+            for _ in 0..size {
+                (_, at) = decode(data, at, depth + 1)?;
+            }
+            Some((Value::Other, at))
+        }
+        // A boolean's size is its value.
+        14 => Some((Value::Other, at)),
+        _ => None,
+    }
+}
+
+/// Where an address is, as far as the databases know.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Location {
+    pub country: Option<String>,
+    pub asn: Option<u32>,
+}
+
+/// The configured databases and the locations looked up so far.
+pub struct GeoIp {
+    country: Option<Reader>,
+    asn: Option<Reader>,
+    cache: DashMap<IpAddr, Location>,
+}
+
+impl GeoIp {
+    pub fn new(country: Option<Reader>, asn: Option<Reader>) -> Self {
+        Self { country, asn, cache: DashMap::new() }
+    }
+
+    /// The databases `config` names, or None if it names neither.
+    pub fn from_config(config: &GeoIpConfig) -> Result<Option<Self>> {
+        if config.country_db.is_none() && config.asn_db.is_none() {
+            return Ok(None);
+        }
+        let open = |path: &Option<PathBuf>| {
+            let Some(path) = path else {
+                return Ok(None);
+            };
+            let reader = Reader::open(path)?;
+            tracing::info!("GeoIP: {} loaded from {}", reader.database_type(), path.display());
+            Ok::<_, anyhow::Error>(Some(reader))
+        };
+        Ok(Some(Self::new(open(&config.country_db)?, open(&config.asn_db)?)))
+    }
+
+    pub fn locate(&self, ip: IpAddr) -> Location {
+        if let Some(location) = self.cache.get(&ip) {
+            return location.clone();
+        }
+        let country = self.country.as_ref().and_then(|db| db.lookup(ip)).and_then(|record| {
+            let code = record.get(&["country", "iso_code"])?.as_str()?;
+            Some(code.to_ascii_uppercase())
+        });
+        let asn = self.asn.as_ref().and_then(|db| db.lookup(ip)).and_then(|record| {
+            let number = record.get(&["autonomous_system_number"])?.as_u64()?;
+            u32::try_from(number).ok()
+        });
+        let location = Location { country, asn };
+        if self.cache.len() >= CACHE_CAPACITY {
+            self.cache.clear();
+        }
+        self.cache.insert(ip, location.clone());
+        location
+    }
+
+    /// The location of the remote end of a flow: the source of inbound
+    /// traffic, else the destination.
+    pub fn locate_flow(
+        &self,
+        src_ip: &str,
+        dst_ip: &str,
+        direction: Option<FlowDirection>,
+    ) -> Location {
+        let remote = match direction {
+            Some(FlowDirection::Inbound) => src_ip,
+            _ => dst_ip,
+        };
+        remote.parse().map(|ip| self.locate(ip)).unwrap_or_default()
+    }
+
+    /// Set `country` and `asn` on a packet whose flow direction is known.
+    pub fn fill(&self, packet: &mut PacketMetadata) {
+        let location = self.locate_flow(&packet.src_ip, &packet.dst_ip, packet.flow_direction);
+        packet.country = location.country;
+        packet.asn = location.asn;
+    }
+}
+
+/// Writes small databases for tests.
+#[cfg(test)]
+pub mod fixture {
+    use super::*;
+    use ipnet::IpNet;
+
+    /// A data section value to write.
+    pub enum Data {
+        String(&'static str),
+        Uint32(u32),
+        Map(Vec<(&'static str, Data)>),
+    }
+
+    fn control(out: &mut Vec<u8>, kind: u8, size: usize) {
+        if size < 29 {
+            out.push(kind << 5 | size as u8);
+        } else {
+            out.extend([kind << 5 | 29, u8::try_from(size - 29).unwrap()]);
+        }
+    }
+
+    fn encode(out: &mut Vec<u8>, data: &Data) {
+        match data {
+            Data::String(s) => {
+                control(out, 2, s.len());
+                out.extend(s.as_bytes());
+            }
+            Data::Uint32(n) => {
+                control(out, 6, 4);
+                out.extend(n.to_be_bytes());
+            }
+            Data::Map(entries) => {
+                control(out, 7, entries.len());
+                for (key, value) in entries {
+                    encode(out, &Data::String(key));
+                    encode(out, value);
+                }
+            }
+        }
+    }
+
+    /// A country database record.
+    pub fn country(code: &'static str) -> Data {
+        Data::Map(vec![("country", Data::Map(vec![("iso_code", Data::String(code))]))])
+    }
+
+    /// An ASN database record.
+    pub fn asn(number: u32) -> Data {
+        Data::Map(vec![("autonomous_system_number", Data::Uint32(number))])
+    }
+
+    #[derive(Clone, Copy)]
+    enum Record {
+        Empty,
+        Node(usize),
+        Data(usize),
+    }
+
+    /// An IPv6 database mapping each network to its record, with IPv4
+    /// networks at `::a.b.c.d` as MaxMind lays them out.
+    pub fn build(record_size: usize, networks: Vec<(&str, Data)>) -> Vec<u8> {
+        let mut nodes = vec![[Record::Empty; 2]];
+        let mut offsets = Vec::new();
+        let mut section = Vec::new();
+        for (i, (network, record)) in networks.iter().enumerate() {
+            let network: IpNet = network.parse().unwrap();
+            let (bits, len) = match network {
+                IpNet::V4(v4) => (u32::from(v4.addr()) as u128, 96 + v4.prefix_len()),
+                IpNet::V6(v6) => (u128::from(v6.addr()), v6.prefix_len()),
+            };
+            let mut node = 0;
+            for depth in 0..len {
+                let bit = (bits >> (127 - depth) & 1) as usize;
+                if depth + 1 == len {
+                    nodes[node][bit] = Record::Data(i);
+                } else if let Record::Node(next) = nodes[node][bit] {
+                    node = next;
+                } else {
+                    // A longer network inside a shorter one leaves the rest
+                    // of the shorter one where it was.
+                    nodes.push([nodes[node][bit]; 2]);
+                    nodes[node][bit] = Record::Node(nodes.len() - 1);
+                    node = nodes.len() - 1;
+                }
+            }
+            offsets.push(section.len());
+            encode(&mut section, record);
+        }
+
+        let node_count = nodes.len();
+        let value = |record: Record| match record {
+            Record::Empty => node_count,
+            Record::Node(n) => n,
+            Record::Data(i) => node_count + DATA_SEPARATOR + offsets[i],
+        };
+        let mut out = Vec::new();
+        for [left, right] in nodes {
+            let (l, r) = (value(left) as u64, value(right) as u64);
+            match record_size {
+                24 => {
+                    out.extend(&l.to_be_bytes()[5..]);
+                    out.extend(&r.to_be_bytes()[5..]);
+                }
+                28 => {
+                    out.extend(&l.to_be_bytes()[5..]);
+                    out.push(((l >> 24 & 0x0F) << 4 | r >> 24 & 0x0F) as u8);
+                    out.extend(&r.to_be_bytes()[5..]);
+                }
+                _ => {
+                    out.extend(&l.to_be_bytes()[4..]);
+                    out.extend(&r.to_be_bytes()[4..]);
+                }
+            }
+        }
+        out.extend([0; DATA_SEPARATOR]);
+        out.extend(section);
+        out.extend(METADATA_MARKER);
+        encode(
+            &mut out,
+            &Data::Map(vec![
+                ("node_count", Data::Uint32(node_count as u32)),
+                ("record_size", Data::Uint32(record_size as u32)),
+                ("ip_version", Data::Uint32(6)),
+                ("database_type", Data::String("ayaflow-test")),
+            ]),
+        );
+        out
+    }
+
+    /// Locations for 203.0.113.0/24 (JP, AS64500), 198.51.100.0/24 (US,
+    /// AS64501) and 2001:db8::/32 (DE, no ASN).
+    pub fn geoip() -> GeoIp {
+        let country = build(
+            24,
+            vec![
+                ("203.0.113.0/24", country("JP")),
+                ("198.51.100.0/24", country("US")),
+                ("2001:db8::/32", country("DE")),
+            ],
+        );
+        let asn =
+            build(24, vec![("203.0.113.0/24", asn(64500)), ("198.51.100.0/24", asn(64501))]);
+        GeoIp::new(
+            Some(Reader::from_bytes(country).unwrap()),
+            Some(Reader::from_bytes(asn).unwrap()),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fixture::*;
+    use super::*;
+    use crate::test_support;
+
+    #[test]
+    fn test_reader_lookups() {
+        for record_size in [24, 28, 32] {
+            let bytes = build(
+                record_size,
+                vec![
+                    ("203.0.113.0/24", country("JP")),
+                    ("203.0.113.128/25", country("KR")),
+                    ("2001:db8::/32", asn(64500)),
+                ],
+            );
+            let reader = Reader::from_bytes(bytes).unwrap();
+            assert_eq!(reader.database_type(), "ayaflow-test");
+            let code = |ip: &str| {
+                let record = reader.lookup(ip.parse().unwrap())?;
+                record.get(&["country", "iso_code"]).and_then(Value::as_str).map(str::to_string)
+            };
+            assert_eq!(code("203.0.113.5").as_deref(), Some("JP"), "{}", record_size);
+            assert_eq!(code("203.0.113.200").as_deref(), Some("KR"));
+            assert_eq!(code("203.0.114.1"), None);
+            let record = reader.lookup("2001:db8::1".parse().unwrap()).unwrap();
+            assert_eq!(record.get(&["autonomous_system_number"]), Some(&Value::Uint(64500)));
+            assert_eq!(reader.lookup("2001:db9::1".parse().unwrap()), None);
+        }
+
+        assert!(Reader::from_bytes(b"not a database".to_vec()).is_err());
+        let mut truncated = build(24, vec![("203.0.113.0/24", country("JP"))]);
+        truncated.drain(..100);
+        assert!(Reader::from_bytes(truncated).is_err());
+    }
+
+    #[test]
+    fn test_fill_locates_the_remote_end() {
+        let geoip = geoip();
+        let mut outbound = PacketMetadata {
+            src_ip: "10.0.0.2".into(),
+            dst_ip: "203.0.113.9".into(),
+            flow_direction: Some(FlowDirection::Outbound),
+            ..test_support::packet()
+        };
+        geoip.fill(&mut outbound);
+        assert_eq!((outbound.country.as_deref(), outbound.asn), (Some("JP"), Some(64500)));
+
+        let mut inbound = PacketMetadata {
+            src_ip: "2001:db8::7".into(),
+            dst_ip: "fd00::1".into(),
+            flow_direction: Some(FlowDirection::Inbound),
+            ..test_support::packet()
+        };
+        geoip.fill(&mut inbound);
+        assert_eq!((inbound.country.as_deref(), inbound.asn), (Some("DE"), None));
+
+        let mut internal = PacketMetadata {
+            flow_direction: Some(FlowDirection::Internal),
+            ..test_support::packet()
+        };
+        geoip.fill(&mut internal);
+        assert_eq!((internal.country, internal.asn), (None, None));
+        assert_eq!(geoip.cache.len(), 3);
+    }
+}
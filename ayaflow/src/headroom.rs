@@ -0,0 +1,174 @@
+//! How close the sensor runs to its limits, for `headroom` on /api/stats.
+//!
+//! Once a second the rate sampler takes the peaks since its last look: the
+//! largest ring buffer drain, the deepest any channel got as its senders
+//! record it, the longest storage flush, and the CPU time the process
+//! used, from /proc/self/stat.  Over the last minute
+//! `HeadroomReport::estimate` makes each a share of its limit: the drain
+//! batch, the channel's capacity, the flush interval and the cores
+//! available.  The headroom is what the largest share leaves.  These are
+//! utilization figures, not a forecast; a share near 1 is where packets
+//! start to wait or be dropped.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use crate::diagnostics::QueueDepth;
+
+pub use ayaflow_common::api::{HeadroomLimits, HeadroomReport, HeadroomSample};
+
+/// Samples the report covers, one a second.
+const WINDOW: usize = 60;
+
+/// Peaks recorded on the hot paths between two samples, and the last
+/// report.
+#[derive(Default)]
+pub struct Headroom {
+    drain_peak: AtomicUsize,
+    report: RwLock<Option<HeadroomReport>>,
+}
+
+impl Headroom {
+    /// The poller read `events` from the ring buffer in one go.
+    pub fn record_drain(&self, events: usize) {
+        self.drain_peak.fetch_max(events, Ordering::Relaxed);
+    }
+
+    /// None before the second sample, and without capture.
+    pub fn report(&self) -> Option<HeadroomReport> {
+        self.report.read().unwrap().clone()
+    }
+}
+
+/// The fullest of `queues`.
+fn fullest(queues: &[QueueDepth]) -> Option<(String, f64)> {
+    queues
+        .iter()
+        .filter(|q| q.capacity > 0)
+        .map(|q| (q.name.clone(), q.depth as f64 / q.capacity as f64))
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+}
+
+/// Keeps the last minute of samples and publishes the report.
+pub struct HeadroomSampler {
+    limits: HeadroomLimits,
+    samples: VecDeque<HeadroomSample>,
+    /// When the last sample was taken, and the process CPU time then.
+    last: Option<(Instant, Duration)>,
+}
+
+impl HeadroomSampler {
+    pub fn new(limits: HeadroomLimits) -> Self {
+        Self {
+            limits,
+            samples: VecDeque::with_capacity(WINDOW),
+            last: None,
+        }
+    }
+
+    /// Take the peaks since the last call; the first only starts the clock.
+    pub fn sample(&mut self, headroom: &Headroom, queues: &[QueueDepth], flush_peak: Duration) {
+        let now = Instant::now();
+        let cpu_now = process_cpu_time().unwrap_or_default();
+        let drain_peak = headroom.drain_peak.swap(0, Ordering::Relaxed);
+        let Some((then, cpu_then)) = self.last.replace((now, cpu_now)) else {
+            return;
+        };
+        if self.samples.len() == WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(HeadroomSample {
+            elapsed: now - then,
+            drain_peak,
+            queue_peak: fullest(queues),
+            flush_peak,
+            cpu: cpu_now.saturating_sub(cpu_then),
+        });
+        let report = HeadroomReport::estimate(self.samples.make_contiguous(), self.limits);
+        *headroom.report.write().unwrap() = report;
+    }
+}
+
+/// User plus system time of this process so far.
+fn process_cpu_time() -> Option<Duration> {
+    let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+    // SAFETY: sysconf only reads a system constant.
+    let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    if ticks_per_second <= 0 {
+        return None;
+    }
+    let ticks = parse_cpu_ticks(&stat)?;
+    Some(Duration::from_secs_f64(ticks as f64 / ticks_per_second as f64))
+}
+
+/// utime + stime, fields 14 and 15 of /proc/<pid>/stat, in clock ticks.
+/// The command name in field 2 may hold spaces and parentheses, so fields
+/// are counted from the last ')'.
+fn parse_cpu_ticks(stat: &str) -> Option<u64> {
+    let (_, rest) = stat.rsplit_once(')')?;
+    // `rest` starts at field 3, the state.
+    let mut fields = rest.split_whitespace().skip(11);
+    let utime: u64 = fields.next()?.parse().ok()?;
+    let stime: u64 = fields.next()?.parse().ok()?;
+    Some(utime + stime)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: HeadroomLimits = HeadroomLimits {
+        drain_batch: 256,
+        flush_interval: Duration::from_secs(2),
+        cores: 4,
+    };
+
+    #[test]
+    fn test_sampler_keeps_a_minute_and_resets_peaks() {
+        let headroom = Headroom::default();
+        let mut sampler = HeadroomSampler::new(LIMITS);
+        let queues = [
+            QueueDepth {
+                name: "storage".into(),
+                depth: 10,
+                capacity: 100,
+            },
+            QueueDepth {
+                name: "dns".into(),
+                depth: 300,
+                capacity: 1000,
+            },
+        ];
+        sampler.sample(&headroom, &queues, Duration::ZERO);
+        assert!(headroom.report().is_none());
+
+        headroom.record_drain(200);
+        headroom.record_drain(256);
+        headroom.record_drain(3);
+        sampler.sample(&headroom, &queues, Duration::from_millis(50));
+        let report = headroom.report().unwrap();
+        assert_eq!((report.ring_batch_peak, report.ring_batch_ratio), (256, 1.0));
+        assert_eq!(report.queue_peak_name.as_deref(), Some("dns"));
+        assert_eq!(report.headroom_ratio, 0.0);
+
+        for _ in 0..WINDOW {
+            sampler.sample(&headroom, &[], Duration::ZERO);
+        }
+        assert_eq!(sampler.samples.len(), WINDOW);
+        let report = headroom.report().unwrap();
+        assert_eq!(report.ring_batch_peak, 0);
+        assert!(report.queue_peak_name.is_none());
+    }
+
+    #[test]
+    fn test_parse_cpu_ticks() {
+        let stat = "4242 (ayaflow (x) 1) S 1 4242 4242 0 -1 4194560 1200 0 0 0 \
+                    731 204 0 0 20 0 9 0 123456 1000000 2000 rest";
+        assert_eq!(parse_cpu_ticks(stat), Some(935));
+        assert_eq!(parse_cpu_ticks("4242 (ayaflow) S 1 2"), None);
+        assert_eq!(parse_cpu_ticks("no parenthesis"), None);
+        assert!(process_cpu_time().is_some());
+    }
+}
@@ -0,0 +1,202 @@
+use dashmap::DashMap;
+use std::fmt::Display;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub use ayaflow_common::api::{ComponentHealth, ComponentStatus};
+
+struct Component {
+    critical: bool,
+    /// Silence longer than this counts as down.  None for event-driven
+    /// components that may legitimately stay quiet.
+    stale_after: Option<Duration>,
+    last_heartbeat: Instant,
+    failing: bool,
+    last_error: Option<(String, Instant)>,
+    stopped: bool,
+}
+
+impl Component {
+    fn status(&self, now: Instant) -> ComponentStatus {
+        let stale = self
+            .stale_after
+            .is_some_and(|limit| now.duration_since(self.last_heartbeat) > limit);
+        if self.stopped || stale {
+            ComponentStatus::Down
+        } else if self.failing {
+            ComponentStatus::Degraded
+        } else {
+            ComponentStatus::Ok
+        }
+    }
+}
+
+/// Heartbeats and last errors of the agent's background tasks.
+///
+/// Each task registers once and keeps the returned `Heartbeat`; dropping it
+/// (the task returned or panicked) marks the component down.
+#[derive(Default)]
+pub struct HealthRegistry {
+    components: DashMap<&'static str, Component>,
+}
+
+impl HealthRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(
+        self: &Arc<Self>,
+        name: &'static str,
+        critical: bool,
+        stale_after: Option<Duration>,
+    ) -> Heartbeat {
+        self.components.insert(
+            name,
+            Component {
+                critical,
+                stale_after,
+                last_heartbeat: Instant::now(),
+                failing: false,
+                last_error: None,
+                stopped: false,
+            },
+        );
+        Heartbeat {
+            registry: self.clone(),
+            name,
+        }
+    }
+
+    /// Overall status plus per-component details, sorted by name.  The agent
+    /// is down when any critical component is, degraded when anything else
+    /// is not ok.
+    pub fn report(&self) -> (ComponentStatus, Vec<ComponentHealth>) {
+        let now = Instant::now();
+        let mut overall = ComponentStatus::Ok;
+        let mut components: Vec<ComponentHealth> = self
+            .components
+            .iter()
+            .map(|entry| {
+                let component = entry.value();
+                let status = component.status(now);
+                if status == ComponentStatus::Down && component.critical {
+                    overall = ComponentStatus::Down;
+                } else if status != ComponentStatus::Ok && overall == ComponentStatus::Ok {
+                    overall = ComponentStatus::Degraded;
+                }
+                ComponentHealth {
+                    name: entry.key().to_string(),
+                    status,
+                    critical: component.critical,
+                    last_heartbeat_ms_ago: millis_since(now, component.last_heartbeat),
+                    last_error: component.last_error.as_ref().map(|(e, _)| e.clone()),
+                    last_error_ms_ago: component
+                        .last_error
+                        .as_ref()
+                        .map(|(_, at)| millis_since(now, *at)),
+                }
+            })
+            .collect();
+        components.sort_by(|a, b| a.name.cmp(&b.name));
+        (overall, components)
+    }
+
+    fn update(&self, name: &str, f: impl FnOnce(&mut Component)) {
+        if let Some(mut component) = self.components.get_mut(name) {
+            f(&mut component);
+        }
+    }
+}
+
+fn millis_since(now: Instant, then: Instant) -> u64 {
+    now.duration_since(then).as_millis() as u64
+}
+
+/// A registered component's handle for reporting liveness and errors.
+pub struct Heartbeat {
+    registry: Arc<HealthRegistry>,
+    name: &'static str,
+}
+
+impl Heartbeat {
+    /// The component is alive and its last operation succeeded.
+    pub fn beat(&self) {
+        self.registry.update(self.name, |c| {
+            c.last_heartbeat = Instant::now();
+            c.failing = false;
+        });
+    }
+
+    /// The component is alive but its last operation failed.
+    pub fn fail(&self, error: impl Display) {
+        let now = Instant::now();
+        let error = error.to_string();
+        self.registry.update(self.name, |c| {
+            c.last_heartbeat = now;
+            c.failing = true;
+            c.last_error = Some((error, now));
+        });
+    }
+
+    /// `beat` or `fail` depending on an operation's outcome.
+    pub fn report<T, E: Display>(&self, result: &Result<T, E>) {
+        match result {
+            Ok(_) => self.beat(),
+            Err(e) => self.fail(e),
+        }
+    }
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        let reason = if std::thread::panicking() {
+            "task panicked"
+        } else {
+            "task exited"
+        };
+        self.registry.update(self.name, |c| {
+            c.stopped = true;
+            c.last_error = Some((reason.to_string(), Instant::now()));
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_rollup() {
+        let registry = Arc::new(HealthRegistry::new());
+        assert_eq!(registry.report().0, ComponentStatus::Ok);
+
+        let writer = registry.register("storage_writer", true, Some(Duration::from_secs(30)));
+        let dns = registry.register("dns", false, None);
+        writer.beat();
+        assert_eq!(registry.report().0, ComponentStatus::Ok);
+
+        dns.fail("lookup timed out");
+        let (status, components) = registry.report();
+        assert_eq!(status, ComponentStatus::Degraded);
+        assert_eq!(components[0].name, "dns");
+        assert_eq!(components[0].last_error.as_deref(), Some("lookup timed out"));
+
+        // A non-critical component going away only degrades the agent.
+        drop(dns);
+        assert_eq!(registry.report().0, ComponentStatus::Degraded);
+
+        drop(writer);
+        let (status, components) = registry.report();
+        assert_eq!(status, ComponentStatus::Down);
+        assert_eq!(components[1].last_error.as_deref(), Some("task exited"));
+    }
+
+    #[test]
+    fn test_stale_heartbeat_is_down() {
+        let registry = Arc::new(HealthRegistry::new());
+        let _poller = registry.register("packet_poller", true, Some(Duration::ZERO));
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(registry.report().0, ComponentStatus::Down);
+    }
+}
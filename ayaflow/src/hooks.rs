@@ -0,0 +1,591 @@
+//! Actions run when a new connection appears.
+//!
+//! Each rule of the `hooks:` config matches new connection entries by
+//! address, destination port, protocol and flow direction.  A match hands
+//! a JSON description of the connection to a webhook (HTTP POST) or to a
+//! command on stdin.  Matching runs on the packet path and only queues the
+//! firing; `run_hooks` executes it in the background, one at a time, and
+//! records the outcome in the alerts table.
+
+use std::net::IpAddr;
+use std::process::Stdio;
+use std::sync::Mutex;
+
+use ayaflow_common::http;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tokio::time::{timeout, Duration, Instant};
+
+use crate::alerts::Alert;
+use crate::diagnostics::QueuePeak;
+use crate::locality::FlowDirection;
+use crate::state::ConnectionKey;
+use crate::storage::StorageEvent;
+
+/// Firings waiting for `run_hooks`, at most; more are dropped.
+pub const QUEUE_CAPACITY: usize = 256;
+
+/// One entry of the `hooks:` config section.  Unset criteria match
+/// anything; exactly one of `webhook` and `command` must be set.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HookRule {
+    /// Name recorded with each firing, e.g. "ssh-from-outside".
+    pub name: String,
+    /// Address or CIDR matched against either end.
+    #[serde(default)]
+    pub ip: Option<String>,
+    /// Destination port.
+    #[serde(default)]
+    pub port: Option<u16>,
+    /// Case-insensitive protocol name ("TCP", "UDP", ...).
+    #[serde(default)]
+    pub protocol: Option<String>,
+    /// Direction relative to `local_networks`.
+    #[serde(default)]
+    pub direction: Option<FlowDirection>,
+    /// `http://host[:port]/path` to POST the connection to.
+    #[serde(default)]
+    pub webhook: Option<String>,
+    /// Program and arguments to run with the connection on stdin.
+    #[serde(default)]
+    pub command: Option<Vec<String>>,
+    /// Minimum seconds between two firings of this rule.
+    #[serde(default = "default_min_interval_seconds")]
+    pub min_interval_seconds: u64,
+    /// Seconds before a webhook or command is abandoned.
+    #[serde(default = "default_timeout_seconds")]
+    pub timeout_seconds: u64,
+}
+
+fn default_min_interval_seconds() -> u64 {
+    60
+}
+
+fn default_timeout_seconds() -> u64 {
+    10
+}
+
+impl HookRule {
+    /// The error `HookEngine::new` would fail with for this rule.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        CompiledRule::new(self).map(drop)
+    }
+}
+
+/// What a rule does when it fires.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HookAction {
+    Webhook(WebhookUrl),
+    Command(Vec<String>),
+}
+
+/// A parsed `http://` webhook URL.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WebhookUrl {
+    /// `host:port`, for connecting.
+    address: String,
+    /// The `Host` header: the authority as written.
+    host: String,
+    path: String,
+}
+
+impl WebhookUrl {
+    /// Only plain HTTP is supported; wrap `curl` in a `command` for HTTPS.
+    pub fn parse(url: &str) -> anyhow::Result<Self> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| anyhow::anyhow!("webhook {:?} must start with http://", url))?;
+        let (host, path) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], &rest[slash..]),
+            None => (rest, "/"),
+        };
+        anyhow::ensure!(!host.contains('@'), "webhook {:?}: credentials are not supported", url);
+        // The port follows the last colon, unless that colon is inside an
+        // IPv6 literal.
+        let (name, address) = match host.rfind(':') {
+            Some(colon) if !host[colon..].contains(']') => {
+                host[colon + 1..]
+                    .parse::<u16>()
+                    .map_err(|_| anyhow::anyhow!("webhook {:?} has an invalid port", url))?;
+                (&host[..colon], host.to_string())
+            }
+            _ => (host, format!("{}:80", host)),
+        };
+        anyhow::ensure!(!name.is_empty(), "webhook {:?} has no host", url);
+        Ok(Self {
+            address,
+            host: host.to_string(),
+            path: path.to_string(),
+        })
+    }
+
+    /// Whether the host is `localhost` or a loopback address, so nothing
+    /// sent leaves the machine.
+    pub fn is_loopback(&self) -> bool {
+        let name = self.address.rsplit_once(':').map_or("", |(name, _)| name);
+        let name = name.trim_start_matches('[').trim_end_matches(']');
+        name.eq_ignore_ascii_case("localhost")
+            || name.parse::<std::net::IpAddr>().is_ok_and(|ip| ip.is_loopback())
+    }
+}
+
+/// A validated rule and when it last fired.
+#[derive(Debug)]
+struct CompiledRule {
+    name: String,
+    ip: Option<IpNet>,
+    port: Option<u16>,
+    protocol: Option<String>,
+    direction: Option<FlowDirection>,
+    action: HookAction,
+    min_interval: Duration,
+    timeout: Duration,
+    last_fired: Mutex<Option<Instant>>,
+}
+
+impl CompiledRule {
+    fn new(rule: &HookRule) -> anyhow::Result<Self> {
+        anyhow::ensure!(!rule.name.is_empty(), "hook rules need a name");
+        let ip = rule
+            .ip
+            .as_deref()
+            .map(|ip| {
+                ip.parse::<IpNet>()
+                    .or_else(|_| ip.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|_| anyhow::anyhow!("hook {:?}: invalid ip {:?}", rule.name, ip))
+            })
+            .transpose()?;
+        let action = match (&rule.webhook, &rule.command) {
+            (Some(url), None) => HookAction::Webhook(
+                WebhookUrl::parse(url).map_err(|e| anyhow::anyhow!("hook {:?}: {}", rule.name, e))?,
+            ),
+            (None, Some(command)) if !command.is_empty() => HookAction::Command(command.clone()),
+            (None, Some(_)) => anyhow::bail!("hook {:?}: command is empty", rule.name),
+            _ => anyhow::bail!("hook {:?}: set exactly one of webhook and command", rule.name),
+        };
+        anyhow::ensure!(rule.timeout_seconds > 0, "hook {:?}: timeout_seconds is 0", rule.name);
+        Ok(Self {
+            name: rule.name.clone(),
+            ip,
+            port: rule.port,
+            protocol: rule.protocol.clone(),
+            direction: rule.direction,
+            action,
+            min_interval: Duration::from_secs(rule.min_interval_seconds),
+            timeout: Duration::from_secs(rule.timeout_seconds),
+            last_fired: Mutex::new(None),
+        })
+    }
+
+    fn matches(&self, connection: &NewConnection) -> bool {
+        let key = &connection.key;
+        if let Some(net) = self.ip {
+            if !net.contains(&key.src_ip) && !net.contains(&key.dst_ip) {
+                return false;
+            }
+        }
+        if self.port.is_some_and(|port| port != key.dst_port) {
+            return false;
+        }
+        if let Some(ref protocol) = self.protocol {
+            if !connection.protocol.eq_ignore_ascii_case(protocol) {
+                return false;
+            }
+        }
+        if self.direction.is_some_and(|d| d != connection.flow_direction) {
+            return false;
+        }
+        true
+    }
+
+    /// Claim the rule's next firing unless it fired within `min_interval`.
+    fn try_fire(&self, now: Instant) -> bool {
+        let mut last = self.last_fired.lock().unwrap();
+        if last.is_some_and(|at| now.duration_since(at) < self.min_interval) {
+            return false;
+        }
+        *last = Some(now);
+        true
+    }
+}
+
+/// A connection entry just created in the live table.
+#[derive(Debug, Clone, Copy)]
+pub struct NewConnection<'a> {
+    pub key: ConnectionKey,
+    pub protocol: &'a str,
+    pub flow_direction: FlowDirection,
+    pub interface: &'a str,
+}
+
+/// The JSON handed to a webhook or command.
+#[derive(Debug, Clone, Serialize)]
+pub struct HookPayload {
+    pub rule: String,
+    /// Milliseconds since the Unix epoch.
+    pub timestamp: i64,
+    pub src_ip: String,
+    pub src_port: u16,
+    pub dst_ip: String,
+    pub dst_port: u16,
+    pub protocol: String,
+    pub flow_direction: FlowDirection,
+    pub interface: String,
+}
+
+impl HookPayload {
+    fn connection(&self) -> String {
+        format!("{}:{} -> {}:{}", self.src_ip, self.src_port, self.dst_ip, self.dst_port)
+    }
+}
+
+/// A queued firing.
+#[derive(Debug)]
+pub struct HookFiring {
+    pub action: HookAction,
+    pub timeout: Duration,
+    pub payload: HookPayload,
+}
+
+/// Matches new connections against the configured rules.
+pub struct HookEngine {
+    rules: Vec<CompiledRule>,
+    queue: mpsc::Sender<HookFiring>,
+    peak: QueuePeak,
+}
+
+impl HookEngine {
+    /// Fails on the first invalid rule, so a typo never silently disables
+    /// a hook.
+    pub fn new(
+        rules: &[HookRule],
+        queue: mpsc::Sender<HookFiring>,
+        peak: QueuePeak,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            rules: rules.iter().map(CompiledRule::new).collect::<anyhow::Result<_>>()?,
+            queue,
+            peak,
+        })
+    }
+
+    /// Queue a firing for every matching rule that is not rate limited.
+    /// Returns how many were queued.
+    pub fn on_new_connection(&self, connection: &NewConnection) -> usize {
+        let now = Instant::now();
+        let mut queued = 0;
+        for rule in &self.rules {
+            if !rule.matches(connection) || !rule.try_fire(now) {
+                continue;
+            }
+            let key = &connection.key;
+            let firing = HookFiring {
+                action: rule.action.clone(),
+                timeout: rule.timeout,
+                payload: HookPayload {
+                    rule: rule.name.clone(),
+                    timestamp: chrono::Utc::now().timestamp_millis(),
+                    src_ip: key.src_ip.to_string(),
+                    src_port: key.src_port,
+                    dst_ip: key.dst_ip.to_string(),
+                    dst_port: key.dst_port,
+                    protocol: connection.protocol.to_string(),
+                    flow_direction: connection.flow_direction,
+                    interface: connection.interface.to_string(),
+                },
+            };
+            match self.queue.try_send(firing) {
+                Ok(()) => {
+                    self.peak.record(&self.queue);
+                    queued += 1;
+                }
+                Err(e) => tracing::warn!("Dropping firing of hook {:?}: {}", rule.name, e),
+            }
+        }
+        queued
+    }
+}
+
+/// Execute queued firings and record each in the alerts table.
+pub async fn run_hooks(mut rx: mpsc::Receiver<HookFiring>, tx: mpsc::Sender<StorageEvent>) {
+    while let Some(firing) = rx.recv().await {
+        let alert = execute(&firing).await;
+        let _ = tx.send(StorageEvent::Alert(alert)).await;
+    }
+}
+
+async fn execute(firing: &HookFiring) -> Alert {
+    let payload = &firing.payload;
+    let body = serde_json::to_vec(payload).unwrap_or_default();
+    let (kind, result) = match &firing.action {
+        HookAction::Webhook(url) => {
+            ("webhook", post(url, "application/json", &body, firing.timeout).await)
+        }
+        HookAction::Command(argv) => {
+            let result = timeout(firing.timeout, exec(argv, &body)).await;
+            let timed_out = || anyhow::anyhow!("timed out after {:?}", firing.timeout);
+            ("command", result.unwrap_or_else(|_| Err(timed_out())))
+        }
+    };
+    let outcome = result.map_err(|e| e.to_string());
+    if let Err(ref e) = outcome {
+        tracing::warn!("Hook {:?} failed: {}", payload.rule, e);
+    }
+    Alert {
+        timestamp: payload.timestamp,
+        rule: format!("hook:{}", payload.rule),
+        severity: if outcome.is_ok() { "info" } else { "warning" }.to_string(),
+        subject: payload.connection(),
+        message: match outcome {
+            Ok(outcome) => format!("{} {}", kind, outcome),
+            Err(e) => format!("{} failed: {}", kind, e),
+        },
+    }
+}
+
+/// POST `body` and describe the answer.  Non-2xx statuses are errors.
+pub async fn post(
+    url: &WebhookUrl,
+    content_type: &str,
+    body: &[u8],
+    timeout: Duration,
+) -> anyhow::Result<String> {
+    let response = send(url, &[("Content-Type", content_type)], body, timeout).await?;
+    anyhow::ensure!(response.is_success(), "webhook answered {}", response.status);
+    Ok(format!("answered {}", response.status))
+}
+
+/// POST `body` with extra `headers` and return the response, whatever its
+/// status.
+pub async fn send(
+    url: &WebhookUrl,
+    headers: &[(&str, &str)],
+    body: &[u8],
+    timeout: Duration,
+) -> anyhow::Result<http::Response> {
+    let request = http::Request {
+        method: "POST",
+        target: &url.path,
+        host: &url.host,
+        headers,
+        body,
+    };
+    Ok(http::send(&url.address, &request, timeout).await?)
+}
+
+/// Run `argv` with `body` on stdin and return how it exited.  A non-zero
+/// exit is an error.  The child is killed if the caller's timeout fires.
+async fn exec(argv: &[String], body: &[u8]) -> anyhow::Result<String> {
+    let mut child = tokio::process::Command::new(&argv[0])
+        .args(&argv[1..])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // A command that ignores its input may exit before reading it.
+        let _ = stdin.write_all(body).await;
+    }
+    let status = child.wait().await?;
+    anyhow::ensure!(status.success(), "{} exited with {}", argv[0], status);
+    Ok("exited with 0".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    fn rule(yaml: &str) -> HookRule {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn connection(
+        src: &str,
+        dst: &str,
+        dst_port: u16,
+        direction: FlowDirection,
+    ) -> NewConnection<'static> {
+        NewConnection {
+            key: ConnectionKey {
+                src_ip: src.parse().unwrap(),
+                src_port: 50000,
+                dst_ip: dst.parse().unwrap(),
+                dst_port,
+            },
+            protocol: "TCP",
+            flow_direction: direction,
+            interface: "eth0",
+        }
+    }
+
+    fn engine(rules: &[HookRule]) -> (HookEngine, mpsc::Receiver<HookFiring>) {
+        let (tx, rx) = mpsc::channel(16);
+        (HookEngine::new(rules, tx, QueuePeak::default()).unwrap(), rx)
+    }
+
+    #[test]
+    fn test_rule_matching() {
+        let ssh = CompiledRule::new(&rule(
+            "{name: ssh, port: 22, protocol: tcp, direction: inbound, command: [true]}",
+        ))
+        .unwrap();
+        let inbound = FlowDirection::Inbound;
+        assert!(ssh.matches(&connection("203.0.113.5", "192.168.1.10", 22, inbound)));
+        // The reply's entry has 22 as its source port.
+        assert!(!ssh.matches(&connection("192.168.1.10", "203.0.113.5", 50000, inbound)));
+        let internal = FlowDirection::Internal;
+        assert!(!ssh.matches(&connection("192.168.1.5", "192.168.1.10", 22, internal)));
+        let mut udp = connection("203.0.113.5", "192.168.1.10", 22, inbound);
+        udp.protocol = "UDP";
+        assert!(!ssh.matches(&udp));
+
+        // Addresses and CIDRs match either end.
+        let subnet =
+            CompiledRule::new(&rule("{name: lab, ip: 10.1.0.0/16, command: [true]}")).unwrap();
+        let external = FlowDirection::External;
+        assert!(subnet.matches(&connection("10.1.2.3", "8.8.8.8", 53, external)));
+        assert!(subnet.matches(&connection("8.8.8.8", "10.1.2.3", 53, external)));
+        assert!(!subnet.matches(&connection("10.2.0.1", "8.8.8.8", 53, external)));
+        let host = CompiledRule::new(&rule("{name: nas, ip: 'fd00::5', command: [true]}")).unwrap();
+        assert!(host.matches(&connection("fd00::5", "fd00::6", 445, FlowDirection::Internal)));
+        assert!(!host.matches(&connection("fd00::7", "fd00::6", 445, FlowDirection::Internal)));
+
+        // No criteria match everything.
+        let any = CompiledRule::new(&rule("{name: any, webhook: 'http://h/x'}")).unwrap();
+        assert!(any.matches(&connection("1.1.1.1", "2.2.2.2", 1, external)));
+    }
+
+    #[test]
+    fn test_invalid_rules_are_rejected() {
+        for yaml in [
+            "{name: '', command: [true]}",
+            "{name: x}",
+            "{name: x, command: []}",
+            "{name: x, command: [true], webhook: 'http://h/'}",
+            "{name: x, webhook: 'https://h/'}",
+            "{name: x, webhook: 'http://:80/'}",
+            "{name: x, webhook: 'http://h:port/'}",
+            "{name: x, webhook: 'http://user:pw@h/'}",
+            "{name: x, ip: 10.0.0.0/33, command: [true]}",
+            "{name: x, command: [true], timeout_seconds: 0}",
+        ] {
+            assert!(CompiledRule::new(&rule(yaml)).is_err(), "{}", yaml);
+        }
+        assert!(serde_yaml::from_str::<HookRule>("{name: x, prot: tcp}").is_err());
+
+        let url = WebhookUrl::parse("http://[::1]:8080/hook?x=1").unwrap();
+        assert_eq!((url.address.as_str(), url.path.as_str()), ("[::1]:8080", "/hook?x=1"));
+        assert!(url.is_loopback());
+        let url = WebhookUrl::parse("http://alerts.lan").unwrap();
+        assert_eq!((url.address.as_str(), url.host.as_str()), ("alerts.lan:80", "alerts.lan"));
+        assert_eq!(url.path, "/");
+        assert!(!url.is_loopback());
+        for loopback in ["http://localhost:9000/", "http://127.0.0.2"] {
+            assert!(WebhookUrl::parse(loopback).unwrap().is_loopback(), "{}", loopback);
+        }
+        assert!(!WebhookUrl::parse("http://10.0.0.1:8080/").unwrap().is_loopback());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rate_limit_per_rule() {
+        let rules = [
+            rule("{name: ssh, port: 22, command: [true], min_interval_seconds: 30}"),
+            rule("{name: all, command: [true], min_interval_seconds: 0}"),
+        ];
+        let (engine, mut rx) = engine(&rules);
+        let ssh = connection("203.0.113.5", "192.168.1.10", 22, FlowDirection::Inbound);
+        assert_eq!(engine.on_new_connection(&ssh), 2);
+        assert_eq!(engine.on_new_connection(&ssh), 1);
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert_eq!(engine.on_new_connection(&ssh), 2);
+
+        let names: Vec<String> = std::iter::from_fn(|| rx.try_recv().ok())
+            .map(|firing| firing.payload.rule)
+            .collect();
+        assert_eq!(names, ["ssh", "all", "all", "ssh", "all"]);
+    }
+
+    #[tokio::test]
+    async fn test_command_receives_payload_and_is_recorded() {
+        let dir = std::env::temp_dir().join(format!("ayaflow-hook-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let out = dir.join("payload.json");
+        let script = format!("cat > {}", out.display());
+        let rules = [
+            HookRule {
+                command: Some(vec!["sh".into(), "-c".into(), script]),
+                ..rule("{name: record, port: 22, command: [x]}")
+            },
+            rule("{name: fails, port: 22, command: [sh, -c, 'exit 3']}"),
+            rule("{name: hangs, port: 22, command: [sleep, '10'], timeout_seconds: 1}"),
+        ];
+        let (engine, rx) = engine(&rules);
+        let ssh = connection("203.0.113.5", "192.168.1.10", 22, FlowDirection::Inbound);
+        assert_eq!(engine.on_new_connection(&ssh), 3);
+        drop(engine);
+
+        let (tx, mut alerts) = mpsc::channel(16);
+        run_hooks(rx, tx).await;
+        let mut recorded = Vec::new();
+        while let Ok(StorageEvent::Alert(alert)) = alerts.try_recv() {
+            recorded.push((alert.rule, alert.severity, alert.message));
+        }
+        let ok = ("hook:record".into(), "info".into(), "command exited with 0".into());
+        assert_eq!(recorded[0], ok);
+        assert_eq!(recorded[1].1, "warning");
+        assert!(recorded[1].2.contains("exit status: 3"), "{}", recorded[1].2);
+        assert_eq!(recorded[2].2, "command failed: timed out after 1s");
+
+        let payload: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&out).unwrap()).unwrap();
+        assert_eq!(payload["rule"], "record");
+        assert_eq!(payload["src_ip"], "203.0.113.5");
+        assert_eq!(payload["dst_port"], 22);
+        assert_eq!(payload["flow_direction"], "inbound");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_webhook_posts_payload() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/ayaflow", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut chunk = [0; 4096];
+            while !request.ends_with(b"}") {
+                let n = socket.read(&mut chunk).await.unwrap();
+                request.extend_from_slice(&chunk[..n]);
+            }
+            socket.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await.unwrap();
+            String::from_utf8_lossy(&request).to_string()
+        });
+        let firing = HookFiring {
+            action: HookAction::Webhook(WebhookUrl::parse(&url).unwrap()),
+            timeout: Duration::from_secs(5),
+            payload: HookPayload {
+                rule: "ssh".into(),
+                timestamp: 1,
+                src_ip: "203.0.113.5".into(),
+                src_port: 50000,
+                dst_ip: "192.168.1.10".into(),
+                dst_port: 22,
+                protocol: "TCP".into(),
+                flow_direction: FlowDirection::Inbound,
+                interface: "eth0".into(),
+            },
+        };
+        let alert = execute(&firing).await;
+        assert_eq!(alert.message, "webhook answered 204");
+        assert_eq!(alert.subject, "203.0.113.5:50000 -> 192.168.1.10:22");
+        let request = server.await.unwrap();
+        assert!(request.starts_with("POST /ayaflow HTTP/1.1\r\n"), "{}", request);
+        assert!(request.ends_with(r#""flow_direction":"inbound","interface":"eth0"}"#));
+    }
+}
@@ -0,0 +1,339 @@
+//! ICMP and ICMPv6 message names and counters.
+//!
+//! The classifier reports an ICMP packet with source port 0 and
+//! `type << 8 | code` as its destination port, as NetFlow does, so every
+//! flow-shaped path (ring buffer events, kernel buckets, stored rows)
+//! carries the message without extra fields.  Counters are kept per type.
+//! The most recent senders of destination-unreachable, time-exceeded and
+//! packet-too-big messages, usually routers along a path, are remembered
+//! too.  Those surface MTU black holes and routing loops that byte counters
+//! hide.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+
+use serde::Serialize;
+
+use crate::openapi::api_schema;
+use crate::state::TrafficCounters;
+
+/// Senders of path errors remembered; the least recently seen goes first.
+const MAX_ERROR_SOURCES: usize = 64;
+
+/// One ICMP message kind, decoded from a flow's protocol and port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IcmpMessage {
+    pub v6: bool,
+    pub kind: u8,
+    pub code: u8,
+}
+
+impl IcmpMessage {
+    /// The message of a flow `protocol_name` called "ICMP" or "ICMPv6".
+    pub fn from_flow(protocol: &str, dst_port: u16) -> Option<Self> {
+        let v6 = match protocol {
+            "ICMP" => false,
+            "ICMPv6" => true,
+            _ => return None,
+        };
+        let [kind, code] = dst_port.to_be_bytes();
+        Some(Self { v6, kind, code })
+    }
+
+    pub fn protocol(&self) -> &'static str {
+        if self.v6 {
+            "ICMPv6"
+        } else {
+            "ICMP"
+        }
+    }
+
+    /// Type name such as "echo-request", or "type-N" for unassigned ones.
+    pub fn type_name(&self) -> String {
+        let name = match (self.v6, self.kind) {
+            (false, 0) => "echo-reply",
+            (false, 3) | (true, 1) => "dest-unreachable",
+            (false, 4) => "source-quench",
+            (false, 5) | (true, 137) => "redirect",
+            (false, 8) | (true, 128) => "echo-request",
+            (false, 9) | (true, 134) => "router-advertisement",
+            (false, 10) | (true, 133) => "router-solicitation",
+            (false, 11) | (true, 3) => "time-exceeded",
+            (false, 12) | (true, 4) => "parameter-problem",
+            (false, 13) => "timestamp-request",
+            (false, 14) => "timestamp-reply",
+            (true, 2) => "packet-too-big",
+            (true, 129) => "echo-reply",
+            (true, 135) => "neighbor-solicitation",
+            (true, 136) => "neighbor-advertisement",
+            (_, kind) => return format!("type-{}", kind),
+        };
+        name.to_string()
+    }
+
+    /// Type and code, e.g. "dest-unreachable/fragmentation-needed".  Code 0
+    /// of a type without named codes is left out.
+    pub fn name(&self) -> String {
+        let code = match (self.v6, self.kind, self.code) {
+            (false, 3, 0) => "net-unreachable",
+            (false, 3, 1) => "host-unreachable",
+            (false, 3, 2) => "protocol-unreachable",
+            (false, 3, 3) | (true, 1, 4) => "port-unreachable",
+            (false, 3, 4) => "fragmentation-needed",
+            (false, 3, 5) => "source-route-failed",
+            (false, 3, 6) => "net-unknown",
+            (false, 3, 7) => "host-unknown",
+            (false, 3, 9) => "net-prohibited",
+            (false, 3, 10) => "host-prohibited",
+            (false, 3, 13) | (true, 1, 1) => "admin-prohibited",
+            (false, 11, 0) => "ttl-exceeded",
+            (true, 3, 0) => "hop-limit-exceeded",
+            (false, 11, 1) | (true, 3, 1) => "reassembly-exceeded",
+            (true, 1, 0) => "no-route",
+            (true, 1, 2) => "beyond-scope",
+            (true, 1, 3) => "address-unreachable",
+            (true, 1, 5) => "source-policy-failed",
+            (true, 1, 6) => "reject-route",
+            (_, _, 0) => return self.type_name(),
+            (_, _, code) => return format!("{}/code-{}", self.type_name(), code),
+        };
+        format!("{}/{}", self.type_name(), code)
+    }
+
+    pub fn is_unreachable(&self) -> bool {
+        matches!((self.v6, self.kind), (false, 3) | (true, 1))
+    }
+
+    /// Whether a router or host is reporting a problem delivering traffic:
+    /// unreachable, time exceeded or (ICMPv6) packet too big.
+    pub fn is_path_error(&self) -> bool {
+        self.is_unreachable() || matches!((self.v6, self.kind), (false, 11) | (true, 2 | 3))
+    }
+
+    fn index(&self) -> usize {
+        usize::from(self.v6) << 8 | usize::from(self.kind)
+    }
+}
+
+api_schema! {
+    /// Traffic of one ICMP or ICMPv6 type.
+    #[derive(Debug, Clone, Serialize)]
+    pub struct IcmpTypeTotals {
+        /// "ICMP" or "ICMPv6".
+        pub protocol: String,
+        pub icmp_type: u8,
+        /// Type name, e.g. "time-exceeded".
+        pub name: String,
+        pub packets: u64,
+        pub bytes: u64,
+    }
+}
+
+api_schema! {
+    /// A recent sender of unreachable, time-exceeded or packet-too-big
+    /// messages.
+    #[derive(Debug, Clone, Serialize)]
+    pub struct IcmpErrorSource {
+        pub ip: String,
+        /// Its latest message, e.g. "dest-unreachable/fragmentation-needed".
+        pub last_message: String,
+        /// Messages seen from it since it was first remembered.
+        pub count: u64,
+        /// Milliseconds since the Unix epoch.
+        pub first_seen: i64,
+        pub last_seen: i64,
+    }
+}
+
+api_schema! {
+    /// Response of `/api/icmp`.
+    #[derive(Debug, Clone, Serialize)]
+    pub struct IcmpReport {
+        /// Types seen since startup or the last reset, most packets first.
+        pub types: Vec<IcmpTypeTotals>,
+        /// Up to 64 senders of path errors, most recently seen first.
+        pub error_sources: Vec<IcmpErrorSource>,
+    }
+}
+
+#[derive(Debug)]
+struct ErrorSource {
+    message: IcmpMessage,
+    count: u64,
+    first_seen: i64,
+    last_seen: i64,
+}
+
+/// Live ICMP counters, kept in `TrafficState`.
+#[derive(Debug)]
+pub struct IcmpStats {
+    /// Indexed by `v6 << 8 | type`.
+    types: Vec<TrafficCounters>,
+    error_sources: Mutex<HashMap<IpAddr, ErrorSource>>,
+}
+
+impl Default for IcmpStats {
+    fn default() -> Self {
+        Self {
+            types: (0..512).map(|_| TrafficCounters::default()).collect(),
+            error_sources: Mutex::default(),
+        }
+    }
+}
+
+impl IcmpStats {
+    /// Count `packets` of `message` sent by `src`.
+    pub fn observe(&self, message: IcmpMessage, src: IpAddr, packets: u64, bytes: u64) {
+        let counters = &self.types[message.index()];
+        counters.packets.fetch_add(packets, Ordering::Relaxed);
+        counters.bytes.fetch_add(bytes, Ordering::Relaxed);
+        if !message.is_path_error() {
+            return;
+        }
+        let now = chrono::Utc::now().timestamp_millis();
+        let mut sources = self.error_sources.lock().unwrap();
+        if !sources.contains_key(&src) && sources.len() >= MAX_ERROR_SOURCES {
+            let oldest = sources.iter().min_by_key(|(_, source)| source.last_seen);
+            if let Some(oldest) = oldest.map(|(ip, _)| *ip) {
+                sources.remove(&oldest);
+            }
+        }
+        let source = sources.entry(src).or_insert(ErrorSource {
+            message,
+            count: 0,
+            first_seen: now,
+            last_seen: now,
+        });
+        source.message = message;
+        source.count += packets;
+        source.last_seen = now;
+    }
+
+    pub fn clear(&self) {
+        for counters in &self.types {
+            counters.packets.store(0, Ordering::Relaxed);
+            counters.bytes.store(0, Ordering::Relaxed);
+        }
+        self.error_sources.lock().unwrap().clear();
+    }
+
+    pub fn report(&self) -> IcmpReport {
+        let mut types: Vec<IcmpTypeTotals> = self
+            .types
+            .iter()
+            .enumerate()
+            .filter_map(|(index, counters)| {
+                let packets = counters.packets.load(Ordering::Relaxed);
+                if packets == 0 {
+                    return None;
+                }
+                let message = IcmpMessage { v6: index >> 8 == 1, kind: index as u8, code: 0 };
+                Some(IcmpTypeTotals {
+                    protocol: message.protocol().to_string(),
+                    icmp_type: message.kind,
+                    name: message.type_name(),
+                    packets,
+                    bytes: counters.bytes.load(Ordering::Relaxed),
+                })
+            })
+            .collect();
+        types.sort_by_key(|t| std::cmp::Reverse(t.packets));
+        let sources = self.error_sources.lock().unwrap();
+        let mut error_sources: Vec<IcmpErrorSource> = sources
+            .iter()
+            .map(|(ip, source)| IcmpErrorSource {
+                ip: ip.to_string(),
+                last_message: source.message.name(),
+                count: source.count,
+                first_seen: source.first_seen,
+                last_seen: source.last_seen,
+            })
+            .collect();
+        error_sources.sort_by(|a, b| b.last_seen.cmp(&a.last_seen).then(a.ip.cmp(&b.ip)));
+        IcmpReport { types, error_sources }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn message(v6: bool, kind: u8, code: u8) -> IcmpMessage {
+        IcmpMessage { v6, kind, code }
+    }
+
+    #[test]
+    fn test_decode_table() {
+        let cases = [
+            (message(false, 8, 0), "echo-request"),
+            (message(false, 0, 0), "echo-reply"),
+            (message(false, 11, 0), "time-exceeded/ttl-exceeded"),
+            (message(false, 3, 3), "dest-unreachable/port-unreachable"),
+            (message(false, 3, 4), "dest-unreachable/fragmentation-needed"),
+            (message(false, 3, 15), "dest-unreachable/code-15"),
+            (message(false, 8, 1), "echo-request/code-1"),
+            (message(false, 200, 0), "type-200"),
+            (message(true, 128, 0), "echo-request"),
+            (message(true, 1, 0), "dest-unreachable/no-route"),
+            (message(true, 2, 0), "packet-too-big"),
+            (message(true, 3, 0), "time-exceeded/hop-limit-exceeded"),
+            (message(true, 135, 0), "neighbor-solicitation"),
+        ];
+        for (message, name) in cases {
+            assert_eq!(message.name(), name, "{:?}", message);
+        }
+
+        let flow = IcmpMessage::from_flow("ICMP", 3 << 8 | 4).unwrap();
+        assert_eq!(flow, message(false, 3, 4));
+        assert_eq!(IcmpMessage::from_flow("ICMPv6", 2 << 8), Some(message(true, 2, 0)));
+        assert!(IcmpMessage::from_flow("UDP", 3 << 8).is_none());
+
+        assert!(message(false, 3, 1).is_unreachable());
+        assert!(!message(true, 3, 0).is_unreachable());
+        assert!(message(true, 3, 0).is_path_error());
+        assert!(message(true, 2, 0).is_path_error());
+        assert!(!message(false, 8, 0).is_path_error());
+        assert!(!message(false, 2, 0).is_path_error());
+    }
+
+    #[test]
+    fn test_report_aggregates_types_and_error_sources() {
+        let stats = IcmpStats::default();
+        let host: IpAddr = "10.0.0.5".parse().unwrap();
+        let router: IpAddr = "192.0.2.1".parse().unwrap();
+        stats.observe(message(false, 8, 0), host, 3, 252);
+        stats.observe(message(false, 11, 0), router, 1, 96);
+        stats.observe(message(false, 3, 4), router, 2, 1120);
+        // Same type on the other family counts separately.
+        stats.observe(message(true, 3, 0), "2001:db8::1".parse().unwrap(), 1, 120);
+
+        let report = stats.report();
+        let summary: Vec<_> = report
+            .types
+            .iter()
+            .map(|t| (t.protocol.as_str(), t.icmp_type, t.name.as_str(), t.packets))
+            .collect();
+        assert_eq!(summary[0], ("ICMP", 8, "echo-request", 3));
+        assert_eq!(summary[1], ("ICMP", 3, "dest-unreachable", 2));
+        assert_eq!(summary.len(), 4);
+        assert!(summary.contains(&("ICMPv6", 3, "time-exceeded", 1)));
+
+        // Echo is no path error; the router's latest message wins.
+        assert_eq!(report.error_sources.len(), 2);
+        let source = report.error_sources.iter().find(|s| s.ip == "192.0.2.1").unwrap();
+        assert_eq!(source.count, 3);
+        assert_eq!(source.last_message, "dest-unreachable/fragmentation-needed");
+
+        for i in 0..MAX_ERROR_SOURCES as u32 + 10 {
+            stats.observe(message(false, 11, 0), Ipv4Addr::from((1u32 << 24) + i).into(), 1, 96);
+        }
+        assert_eq!(stats.report().error_sources.len(), MAX_ERROR_SOURCES);
+
+        stats.clear();
+        let report = stats.report();
+        assert!(report.types.is_empty() && report.error_sources.is_empty());
+    }
+}
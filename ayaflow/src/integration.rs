@@ -0,0 +1,253 @@
+//! End-to-end test of the capture pipeline on a veth pair.
+//!
+//! Built only with the `integration-test` feature and run by `cargo xtask
+//! integration-test`, which builds the eBPF object first.  The test moves
+//! one end of a veth pair into a fresh network namespace and attaches the
+//! classifier to the other end.  Known TCP and UDP traffic crosses the pair,
+//! and the test checks what reaches the live state through the ring buffer
+//! and what the writer stores in a temporary SQLite database.  Without root
+//! it is skipped.
+
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, TcpListener, TcpStream, UdpSocket};
+use std::os::fd::AsRawFd;
+use std::process::Command;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use anyhow::Context;
+use tokio::sync::mpsc;
+use tokio::time::{sleep, Duration, Instant};
+
+use crate::blocklist::Blocklist;
+use crate::config::Config;
+use crate::diagnostics::Diagnostics;
+use crate::health::HealthRegistry;
+use crate::state::{ConnectionKey, TrafficState};
+use crate::storage::{self, HistoryRow, StorageBackend, StorageEvent};
+use crate::STORAGE_QUEUE_CAPACITY;
+
+const HOST_ADDR: Ipv4Addr = Ipv4Addr::new(10, 203, 0, 1);
+const PEER_ADDR: Ipv4Addr = Ipv4Addr::new(10, 203, 0, 2);
+const TCP_PORT: u16 = 18080;
+const UDP_PORT: u16 = 15353;
+const UDP_DATAGRAMS: usize = 5;
+const UDP_PAYLOAD: usize = 100;
+const TCP_MESSAGE: &[u8] = b"ayaflow integration test";
+
+/// How long captured traffic may take to reach the live state and the
+/// database.
+const SETTLE: Duration = Duration::from_secs(10);
+
+fn ip(args: &[&str]) -> anyhow::Result<()> {
+    let status = Command::new("ip").args(args).status().context("failed to run ip")?;
+    anyhow::ensure!(status.success(), "`ip {}` failed", args.join(" "));
+    Ok(())
+}
+
+/// A namespace holding one end of a veth pair.  Deleting the namespace
+/// destroys the pair, so dropping this leaves nothing behind.
+struct VethPair {
+    namespace: String,
+    host: String,
+}
+
+impl VethPair {
+    fn create() -> anyhow::Result<Self> {
+        let id = std::process::id() % 100_000;
+        let pair = Self {
+            namespace: format!("ayaflow-it-{}", id),
+            host: format!("afit{}h", id),
+        };
+        let peer = format!("afit{}p", id);
+        ip(&["netns", "add", &pair.namespace])?;
+        ip(&["link", "add", &pair.host, "type", "veth", "peer", "name", &peer])?;
+        ip(&["link", "set", &peer, "netns", &pair.namespace])?;
+        ip(&["addr", "add", &format!("{}/30", HOST_ADDR), "dev", &pair.host])?;
+        ip(&["link", "set", &pair.host, "up"])?;
+        let in_ns = |args: &[&str]| {
+            let mut full = vec!["netns", "exec", pair.namespace.as_str(), "ip"];
+            full.extend_from_slice(args);
+            ip(&full)
+        };
+        in_ns(&["addr", "add", &format!("{}/30", PEER_ADDR), "dev", &peer])?;
+        in_ns(&["link", "set", &peer, "up"])?;
+        in_ns(&["link", "set", "lo", "up"])?;
+        Ok(pair)
+    }
+
+    /// Sockets bound inside the namespace.  A socket stays in the namespace
+    /// it was created in, so the caller may use them from any thread.
+    fn bind_peer(&self) -> anyhow::Result<(TcpListener, UdpSocket)> {
+        let path = format!("/run/netns/{}", self.namespace);
+        std::thread::spawn(move || -> anyhow::Result<_> {
+            let ns = std::fs::File::open(&path).with_context(|| format!("cannot open {}", path))?;
+            // SAFETY: `ns` is an open namespace file; only this thread moves.
+            if unsafe { libc::setns(ns.as_raw_fd(), libc::CLONE_NEWNET) } != 0 {
+                return Err(std::io::Error::last_os_error()).context("setns failed");
+            }
+            let tcp = TcpListener::bind((PEER_ADDR, TCP_PORT))?;
+            let udp = UdpSocket::bind((PEER_ADDR, UDP_PORT))?;
+            Ok((tcp, udp))
+        })
+        .join()
+        .map_err(|_| anyhow::anyhow!("namespace thread panicked"))?
+    }
+}
+
+impl Drop for VethPair {
+    fn drop(&mut self) {
+        let _ = ip(&["netns", "del", &self.namespace]);
+    }
+}
+
+/// Echo one TCP message and receive every datagram, returning the client
+/// ports the host used.
+fn exchange(tcp: TcpListener, udp: UdpSocket) -> anyhow::Result<(u16, u16)> {
+    let echo = std::thread::spawn(move || -> std::io::Result<()> {
+        let (mut conn, _) = tcp.accept()?;
+        let mut message = vec![0; TCP_MESSAGE.len()];
+        conn.read_exact(&mut message)?;
+        conn.write_all(&message)
+    });
+    let mut client = TcpStream::connect((PEER_ADDR, TCP_PORT))?;
+    client.set_read_timeout(Some(SETTLE))?;
+    client.write_all(TCP_MESSAGE)?;
+    let mut reply = vec![0; TCP_MESSAGE.len()];
+    client.read_exact(&mut reply)?;
+    anyhow::ensure!(reply == TCP_MESSAGE, "echo did not match");
+    echo.join().map_err(|_| anyhow::anyhow!("echo thread panicked"))??;
+    let tcp_port = client.local_addr()?.port();
+
+    udp.set_read_timeout(Some(SETTLE))?;
+    let sender = UdpSocket::bind((HOST_ADDR, 0))?;
+    let mut buf = [0; UDP_PAYLOAD];
+    for i in 0..UDP_DATAGRAMS {
+        sender.send_to(&[i as u8; UDP_PAYLOAD], (PEER_ADDR, UDP_PORT))?;
+        udp.recv(&mut buf)?;
+    }
+    Ok((tcp_port, sender.local_addr()?.port()))
+}
+
+fn key(client_port: u16, server_port: u16) -> ConnectionKey {
+    ConnectionKey {
+        src_ip: IpAddr::V4(HOST_ADDR),
+        src_port: client_port,
+        dst_ip: IpAddr::V4(PEER_ADDR),
+        dst_port: server_port,
+    }
+}
+
+/// Live packets and payload bytes of a connection, both directions.
+fn live_totals(traffic: &TrafficState, key: &ConnectionKey) -> (u64, u64) {
+    traffic.connection_pair(key).iter().fold((0, 0), |(packets, payload), entry| {
+        (packets + entry.stats.packets_count, payload + entry.stats.payload_bytes)
+    })
+}
+
+fn stored_rows(storage: &dyn StorageBackend, key: &ConnectionKey) -> Vec<HistoryRow> {
+    storage.query_connection(key, 1000).map(|(rows, _)| rows).unwrap_or_default()
+}
+
+/// Poll `check` until it holds or `SETTLE` runs out.
+async fn settle(mut check: impl FnMut() -> bool) -> bool {
+    let deadline = Instant::now() + SETTLE;
+    while !check() {
+        if Instant::now() > deadline {
+            return false;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    true
+}
+
+#[tokio::test]
+async fn test_veth_pipeline() {
+    // SAFETY: geteuid has no preconditions.
+    if unsafe { libc::geteuid() } != 0 {
+        eprintln!("skipping test_veth_pipeline: needs root (run `cargo xtask integration-test`)");
+        return;
+    }
+    let pair = VethPair::create().expect("cannot create the veth pair");
+
+    let db_path = std::env::temp_dir().join(format!("ayaflow-it-{}.db", std::process::id()));
+    let db_path = db_path.to_string_lossy().into_owned();
+    let mut config = Config {
+        interface: Some(pair.host.clone()),
+        skip_preflight: true,
+        db_path: db_path.clone(),
+        ..Config::default()
+    };
+    config.storage.flush_interval_ms = 100;
+    let storage = storage::open_backend(
+        None,
+        &config.db_path,
+        Vec::new(),
+        config.aggregation_key,
+        &config.sqlite,
+        &config.storage,
+        &config.clickhouse,
+        &config.postgres,
+        Some("integration"),
+    )
+    .unwrap();
+    let (tx, rx) = mpsc::channel::<StorageEvent>(STORAGE_QUEUE_CAPACITY);
+    let health = Arc::new(HealthRegistry::new());
+    let heartbeat = health.register("storage_writer", true, None);
+    let writer = tokio::spawn(storage::supervise_writer(storage.clone(), rx, 0, heartbeat));
+    let traffic = Arc::new(TrafficState::new());
+    let capture = crate::start_capture(
+        &config,
+        &tx,
+        &traffic,
+        &health,
+        &Diagnostics::new(),
+        &Blocklist::default(),
+    )
+    .expect("cannot attach to the veth pair");
+
+    let (tcp, udp) = pair.bind_peer().unwrap();
+    let (tcp_port, udp_port) = tokio::task::spawn_blocking(move || exchange(tcp, udp))
+        .await
+        .unwrap()
+        .unwrap();
+    let tcp_key = key(tcp_port, TCP_PORT);
+    let udp_key = key(udp_port, UDP_PORT);
+
+    // Every event decoded; a stale or mislinked object fails here first.
+    let udp_payload = (UDP_DATAGRAMS * UDP_PAYLOAD) as u64;
+    let expected = (UDP_DATAGRAMS as u64, udp_payload);
+    let live = settle(|| live_totals(&traffic, &udp_key) == expected).await;
+    assert!(live, "live UDP totals {:?}", live_totals(&traffic, &udp_key));
+    let echoed = 2 * TCP_MESSAGE.len() as u64;
+    let live = settle(|| live_totals(&traffic, &tcp_key).1 == echoed).await;
+    assert!(live, "live TCP totals {:?}", live_totals(&traffic, &tcp_key));
+    // Handshake, message, echo and at least one acknowledgement.
+    assert!(live_totals(&traffic, &tcp_key).0 >= 5);
+    assert_eq!(traffic.malformed_events.load(Ordering::Relaxed), 0);
+
+    let stored = settle(|| stored_rows(storage.as_ref(), &udp_key).len() == UDP_DATAGRAMS).await;
+    assert!(stored, "stored {} UDP rows", stored_rows(storage.as_ref(), &udp_key).len());
+    for row in stored_rows(storage.as_ref(), &udp_key) {
+        assert_eq!(row.packet.protocol, "UDP");
+        assert_eq!(row.packet.payload_length, UDP_PAYLOAD);
+        assert_eq!(row.packet.interface, pair.host);
+        assert_eq!(row.packet.src_port, udp_port);
+        assert_eq!(row.instance.as_deref(), Some("integration"));
+    }
+    let stored_payload = || {
+        let rows = stored_rows(storage.as_ref(), &tcp_key);
+        rows.iter().map(|row| row.packet.payload_length as u64).sum::<u64>()
+    };
+    assert!(settle(|| stored_payload() == echoed).await, "stored {} TCP bytes", stored_payload());
+    let rows = stored_rows(storage.as_ref(), &tcp_key);
+    assert!(rows.iter().all(|row| row.packet.protocol == "TCP"));
+    assert!(rows.iter().any(|row| row.packet.dst_port == TCP_PORT));
+
+    capture.shutdown();
+    writer.abort();
+    drop(pair);
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = std::fs::remove_file(format!("{}{}", db_path, suffix));
+    }
+}
@@ -0,0 +1,125 @@
+//! The kernel prefilter for `capture_filter.ips`.
+//!
+//! Packets with neither address in an included net are turned away in the
+//! classifier, like those on none of the prefiltered ports.  The includes
+//! go in the `FILTER_IPS` LPM trie, keyed as the blocklist is, so the
+//! kernel matches the same nets as `IpFilter` does.  Excludes, lists too
+//! long for the trie and objects built without it are left to userspace,
+//! which applies the whole capture filter to every event either way.  A
+//! config reload loads the new nets in place.
+
+use aya::maps::lpm_trie::LpmTrie;
+use aya::maps::MapData;
+use aya::Ebpf;
+use ayaflow_common::filter::IpFilter;
+use ayaflow_common::{CONFIG_IP_FILTER, IP_FILTER_MAX_ENTRIES, IP_FILTER_OFF, IP_FILTER_ON};
+use ipnet::IpNet;
+
+use crate::attach::ConfigMap;
+use crate::blocklist::trie_key;
+
+/// The includes of `ips`, deduplicated, or why they do not fit the trie.
+fn kernel_nets(ips: &IpFilter) -> Result<Vec<IpNet>, String> {
+    let mut nets = ips.includes().to_vec();
+    nets.sort();
+    nets.dedup();
+    if nets.len() > IP_FILTER_MAX_ENTRIES as usize {
+        return Err(format!(
+            "{} nets, the kernel trie holds {}",
+            nets.len(),
+            IP_FILTER_MAX_ENTRIES
+        ));
+    }
+    Ok(nets)
+}
+
+/// The prefilter trie, taken from the object so a reload can replace the
+/// nets.
+pub struct IpPrefilter {
+    trie: LpmTrie<MapData, [u8; 16], u8>,
+    config: ConfigMap,
+    /// What the trie holds; empty with the prefilter off.
+    loaded: Vec<IpNet>,
+}
+
+impl IpPrefilter {
+    /// Take the trie from `bpf` and load the includes of `ips`.  None for
+    /// an object built without it, which leaves the nets to userspace.
+    pub fn load(bpf: &mut Ebpf, config: ConfigMap, ips: &IpFilter) -> Option<Self> {
+        match Self::take(bpf, config) {
+            Ok(mut prefilter) => {
+                prefilter.replace(ips);
+                Some(prefilter)
+            }
+            Err(e) => {
+                if !ips.includes().is_empty() {
+                    tracing::info!("{}; capture_filter.ips applies in userspace only", e);
+                }
+                None
+            }
+        }
+    }
+
+    fn take(bpf: &mut Ebpf, config: ConfigMap) -> anyhow::Result<Self> {
+        let trie = bpf
+            .take_map("FILTER_IPS")
+            .ok_or_else(|| anyhow::anyhow!("the eBPF object has no FILTER_IPS map"))?;
+        Ok(Self {
+            trie: LpmTrie::try_from(trie)?,
+            config,
+            loaded: Vec::new(),
+        })
+    }
+
+    /// Load the includes of `ips` in place of what the trie holds; none
+    /// turns the prefilter off.  A failure only costs the prefilter.
+    pub fn replace(&mut self, ips: &IpFilter) {
+        match self.write(ips) {
+            Ok(true) => tracing::info!("Kernel address prefilter: {}", ips),
+            Ok(false) => {}
+            Err(e) => tracing::info!(
+                "Kernel address prefilter off, capture_filter.ips applies in userspace only: {}",
+                e
+            ),
+        }
+    }
+
+    /// Whether the prefilter is on afterwards.
+    fn write(&mut self, ips: &IpFilter) -> anyhow::Result<bool> {
+        // Off while the trie changes, so no address of either list is
+        // turned away meanwhile.
+        self.config.lock().unwrap().set(CONFIG_IP_FILTER, IP_FILTER_OFF, 0)?;
+        for net in self.loaded.drain(..) {
+            let _ = self.trie.remove(&trie_key(&net));
+        }
+        if ips.includes().is_empty() {
+            return Ok(false);
+        }
+        let nets = kernel_nets(ips).map_err(anyhow::Error::msg)?;
+        for net in &nets {
+            // Recorded first, so a failed insert is still removed next time.
+            self.loaded.push(*net);
+            self.trie.insert(&trie_key(net), 1u8, 0)?;
+        }
+        self.config.lock().unwrap().set(CONFIG_IP_FILTER, IP_FILTER_ON, 0)?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kernel_nets_dedup_and_fit_the_trie() {
+        let ips = IpFilter::new(&["10.0.0.0/8", "!10.0.5.0/24", "::ffff:10.0.0.0/104"]).unwrap();
+        assert_eq!(kernel_nets(&ips).unwrap(), ["10.0.0.0/8".parse::<IpNet>().unwrap()]);
+        assert_eq!(trie_key(&kernel_nets(&ips).unwrap()[0]).prefix_len(), 104);
+
+        let many: Vec<String> = (0..=1024u32)
+            .map(|i| format!("10.{}.{}.0/24", i / 256, i % 256))
+            .collect();
+        let ips = IpFilter::new(&many).unwrap();
+        assert!(kernel_nets(&ips).unwrap_err().contains("1025 nets"));
+    }
+}
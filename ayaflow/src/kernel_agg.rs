@@ -0,0 +1,138 @@
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::time::{interval, Duration, Instant};
+
+use aya::maps::{MapData, PerCpuArray, PerCpuHashMap};
+use ayaflow_common::filter::TrafficFilter;
+use ayaflow_common::{FlowCounters, FlowKey, COUNTER_FLOW_OVERFLOW};
+
+use crate::attach::InterfaceNames;
+use crate::diagnostics::QueuePeak;
+use crate::dns::DnsCache;
+use crate::health::Heartbeat;
+use crate::l7::DomainCache;
+use crate::reload::LiveFilters;
+use crate::state::{AggregatedBucket, TrafficState};
+use crate::storage::StorageEvent;
+
+/// Periodically sweep the kernel `FLOWS` per-CPU map, replacing the
+/// per-packet ring buffer poller when `kernel_aggregation` is enabled.
+///
+/// Each sweep sums every key's per-CPU counters, deletes the key, and turns
+/// the totals into an `AggregatedBucket` that is applied to the live state
+/// and handed to the storage writer as-is.  `filters` apply to buckets as
+/// they do to ring buffer events.
+///
+/// Trade-offs versus the per-packet path: there are no per-packet
+/// timestamps (rows carry the sweep window start), the live view only moves
+/// once per window, and packets counted between a key's read and its delete
+/// are lost.  In exchange the classifier never touches the ring buffer.
+#[allow(clippy::too_many_arguments)]
+pub async fn sweep_flow_map(
+    mut flows: PerCpuHashMap<MapData, FlowKey, FlowCounters>,
+    counters: PerCpuArray<MapData, u64>,
+    window: Duration,
+    tx: mpsc::Sender<StorageEvent>,
+    storage_peak: QueuePeak,
+    traffic_state: Arc<TrafficState>,
+    interfaces: Arc<InterfaceNames>,
+    dns_cache: Option<Arc<DnsCache>>,
+    domain_cache: Option<Arc<DomainCache>>,
+    filters: Arc<LiveFilters>,
+    heartbeat: Heartbeat,
+) {
+    let mut ticker = interval(window);
+    // The first tick completes immediately; skip it so the first sweep
+    // covers a full window.
+    ticker.tick().await;
+    let mut previous_sweep = Instant::now();
+
+    loop {
+        ticker.tick().await;
+        // As long as the time that really passed since the last sweep, so
+        // a clock held after a step back still gives full-length windows.
+        // They then overlap the previous ones rather than being empty.
+        let elapsed = previous_sweep.elapsed();
+        previous_sweep = Instant::now();
+        let window_end = traffic_state.clock.now_ms();
+        let window_start = window_end - elapsed.as_millis() as i64;
+
+        // Collect keys first: deleting while iterating restarts the
+        // kernel's key walk.
+        let keys: Vec<FlowKey> = flows.keys().filter_map(Result::ok).collect();
+        let mut buckets = Vec::with_capacity(keys.len());
+        let filters = filters.get();
+
+        for key in keys {
+            let values = match flows.get(&key, 0) {
+                Ok(values) => values,
+                Err(_) => continue,
+            };
+            // Delete right after the read to keep the lost-update window small.
+            let _ = flows.remove(&key);
+
+            let total = values.iter().fold(FlowCounters::default(), |acc, c| FlowCounters {
+                packets: acc.packets + c.packets,
+                bytes: acc.bytes + c.bytes,
+                payload_bytes: acc.payload_bytes + c.payload_bytes,
+            });
+            if total.packets == 0 {
+                continue;
+            }
+
+            let interface = interfaces.name(key.ifindex);
+            let mut bucket =
+                AggregatedBucket::from_flow(&key, &total, window_start, window_end, interface);
+            let passes = |filter: &TrafficFilter| {
+                let (src, dst) = (&bucket.src_ip, &bucket.dst_ip);
+                filter.matches(src, dst, bucket.src_port, bucket.dst_port, &bucket.protocol)
+            };
+            if !passes(&filters.capture) {
+                continue;
+            }
+            let store = passes(&filters.storage);
+            bucket.flow_direction =
+                Some(traffic_state.flow_direction(&bucket.src_ip, &bucket.dst_ip));
+            if let Some(geoip) = traffic_state.geoip() {
+                let (src, dst) = (&bucket.src_ip, &bucket.dst_ip);
+                let location = geoip.locate_flow(src, dst, bucket.flow_direction);
+                (bucket.country, bucket.asn) = (location.country, location.asn);
+            }
+            if let Some(ref cache) = dns_cache {
+                bucket.src_hostname = cache.cached(&bucket.src_ip);
+                bucket.dst_hostname = cache.cached(&bucket.dst_ip);
+            }
+            if let Some(ref cache) = domain_cache {
+                bucket.domain = cache.lookup_destination(&bucket.dst_ip, bucket.dst_port);
+            }
+
+            traffic_state.apply_bucket(&bucket);
+            if store {
+                buckets.push(bucket);
+            }
+        }
+
+        crate::blocklist::sync_drop_counter(&counters, &traffic_state);
+        if let Ok(values) = counters.get(&COUNTER_FLOW_OVERFLOW, 0) {
+            let overflows: u64 = values.iter().sum();
+            let previous = traffic_state
+                .kernel_flow_overflows
+                .swap(overflows, Ordering::Relaxed);
+            if overflows > previous {
+                tracing::warn!(
+                    "Kernel flow map full: {} flows not recorded since last sweep",
+                    overflows - previous
+                );
+            }
+        }
+
+        if !buckets.is_empty() {
+            if tx.send(StorageEvent::Buckets(buckets)).await.is_err() {
+                break;
+            }
+            storage_peak.record(&tx);
+        }
+        heartbeat.beat();
+    }
+}
@@ -254,6 +254,13 @@ impl DomainCache {
         self.get(dst_ip)
     }
 
+    /// Best domain for traffic towards `dst_ip:dst_port`: a TLS SNI match on
+    /// the exact destination first, then any DNS-derived entry for the IP.
+    pub fn lookup_destination(&self, dst_ip: &str, dst_port: u16) -> Option<String> {
+        self.get(&format!("{}:{}", dst_ip, dst_port))
+            .or_else(|| self.get_by_dst_ip(dst_ip))
+    }
+
     /// Periodic cleanup of expired entries.
     pub fn cleanup_expired(&self) {
         let now = Instant::now();
@@ -284,11 +291,9 @@ pub async fn poll_payload_ring_buf(
 ) {
     loop {
         while let Some(item) = ring_buf.next() {
-            if item.len() < core::mem::size_of::<PayloadEvent>() {
+            let Some(event) = PayloadEvent::from_bytes(&item) else {
                 continue;
-            }
-            let event =
-                unsafe { core::ptr::read_unaligned(item.as_ptr() as *const PayloadEvent) };
+            };
 
             traffic_state.deep_inspect_packets.fetch_add(1, Ordering::Relaxed);
 
@@ -0,0 +1,241 @@
+//! Which side of a flow is "us".
+//!
+//! `local_networks` names the CIDRs this agent considers local.  Every
+//! packet and swept bucket is classified against them when it is captured:
+//! inbound (remote to local), outbound (local to remote), internal (both
+//! local) or external (neither, e.g. transit traffic on a router).  When the
+//! list is configured empty it is derived from the capture interface's own
+//! addresses.
+//!
+//! Destinations are also classed as unicast, multicast or broadcast, the
+//! last including the broadcast address of each local IPv4 network, so
+//! discovery chatter can be kept out of top-talker views.
+
+use std::net::IpAddr;
+use std::process::Command;
+
+use ipnet::IpNet;
+use serde::Deserialize;
+
+use crate::openapi::{string_enum, ApiSchema};
+
+pub use ayaflow_common::api::{Cast, FlowDirection};
+
+fn flow_direction(src_local: bool, dst_local: bool) -> FlowDirection {
+    match (src_local, dst_local) {
+        (false, true) => FlowDirection::Inbound,
+        (true, false) => FlowDirection::Outbound,
+        (true, true) => FlowDirection::Internal,
+        (false, false) => FlowDirection::External,
+    }
+}
+
+/// The `cast` query parameter of live views: one class, or all of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CastSelection {
+    All,
+    Unicast,
+    Multicast,
+    Broadcast,
+}
+
+impl CastSelection {
+    /// The class to keep; None keeps every class.
+    pub fn class(self) -> Option<Cast> {
+        match self {
+            CastSelection::All => None,
+            CastSelection::Unicast => Some(Cast::Unicast),
+            CastSelection::Multicast => Some(Cast::Multicast),
+            CastSelection::Broadcast => Some(Cast::Broadcast),
+        }
+    }
+}
+
+impl ApiSchema for CastSelection {
+    fn schema() -> serde_json::Value {
+        string_enum(&["all", "unicast", "multicast", "broadcast"])
+    }
+}
+
+/// The configured or derived local networks.
+#[derive(Debug, Clone, Default)]
+pub struct LocalNetworks {
+    networks: Vec<IpNet>,
+}
+
+impl LocalNetworks {
+    pub fn new(networks: Vec<IpNet>) -> Self {
+        Self { networks }
+    }
+
+    /// Parse the `local_networks` config, logging and skipping invalid
+    /// entries.  An empty list is replaced by the networks of `interface`.
+    pub fn from_config(cidrs: &[String], interface: &str) -> Self {
+        if cidrs.is_empty() {
+            let networks = interface_networks(interface);
+            match &networks {
+                Ok(networks) if !networks.is_empty() => {
+                    let list: Vec<String> = networks.iter().map(IpNet::to_string).collect();
+                    tracing::info!("Local networks from {}: {}", interface, list.join(", "));
+                }
+                Ok(_) => tracing::warn!("{} has no addresses; no traffic is local", interface),
+                Err(e) => tracing::warn!("Failed to read addresses of {}: {}", interface, e),
+            }
+            return Self::new(networks.unwrap_or_default());
+        }
+        let networks = cidrs
+            .iter()
+            .filter_map(|cidr| match cidr.parse::<IpNet>() {
+                Ok(net) => Some(net),
+                Err(e) => {
+                    tracing::warn!("Ignoring invalid local network {:?}: {}", cidr, e);
+                    None
+                }
+            })
+            .collect();
+        Self::new(networks)
+    }
+
+    pub fn networks(&self) -> &[IpNet] {
+        &self.networks
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.networks.iter().any(|net| net.contains(ip))
+    }
+
+    pub fn classify(&self, src_ip: &IpAddr, dst_ip: &IpAddr) -> FlowDirection {
+        flow_direction(self.contains(src_ip), self.contains(dst_ip))
+    }
+
+    /// The cast of traffic to `dst_ip`.  Subnet broadcasts are only known
+    /// for local IPv4 networks with room for one (/30 and wider).
+    pub fn cast(&self, dst_ip: &IpAddr) -> Cast {
+        if dst_ip.is_multicast() {
+            return Cast::Multicast;
+        }
+        let subnet_broadcast = |net: &IpNet| {
+            matches!(net, IpNet::V4(v4) if v4.prefix_len() < 31 && v4.broadcast() == *dst_ip)
+        };
+        match dst_ip {
+            IpAddr::V4(v4) if v4.is_broadcast() => Cast::Broadcast,
+            _ if self.networks.iter().any(subnet_broadcast) => Cast::Broadcast,
+            _ => Cast::Unicast,
+        }
+    }
+
+    /// As `cast`, for an address in its display form.  Unparseable
+    /// addresses are unicast.
+    pub fn cast_str(&self, dst_ip: &str) -> Cast {
+        dst_ip.parse().map_or(Cast::Unicast, |ip| self.cast(&ip))
+    }
+
+    /// As `classify`, for addresses in their display form.  Unparseable
+    /// addresses are not local.
+    pub fn classify_str(&self, src_ip: &str, dst_ip: &str) -> FlowDirection {
+        let local = |ip: &str| ip.parse().is_ok_and(|ip| self.contains(&ip));
+        flow_direction(local(src_ip), local(dst_ip))
+    }
+}
+
+/// The networks `interface`'s addresses belong to, from `ip -o addr`.
+fn interface_networks(interface: &str) -> anyhow::Result<Vec<IpNet>> {
+    let output = Command::new("ip").args(["-o", "addr", "show", "dev", interface]).output()?;
+    anyhow::ensure!(
+        output.status.success(),
+        "ip addr: {}",
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    Ok(parse_ip_addr(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Collect the `inet` / `inet6` prefixes of `ip -o addr` output, with the
+/// host bits cleared.
+fn parse_ip_addr(output: &str) -> Vec<IpNet> {
+    let mut networks = Vec::new();
+    for line in output.lines() {
+        let mut words = line.split_whitespace();
+        while let Some(word) = words.next() {
+            if word != "inet" && word != "inet6" {
+                continue;
+            }
+            if let Some(net) = words.next().and_then(|w| w.parse::<IpNet>().ok()) {
+                let net = net.trunc();
+                if !networks.contains(&net) {
+                    networks.push(net);
+                }
+            }
+        }
+    }
+    networks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classifies_all_four_directions() {
+        let local = LocalNetworks::new(vec!["192.168.1.0/24".parse().unwrap()]);
+        let host: IpAddr = "192.168.1.10".parse().unwrap();
+        let nas: IpAddr = "192.168.1.20".parse().unwrap();
+        let remote: IpAddr = "93.184.216.34".parse().unwrap();
+        let other: IpAddr = "8.8.8.8".parse().unwrap();
+        assert_eq!(local.classify(&remote, &host), FlowDirection::Inbound);
+        assert_eq!(local.classify(&host, &remote), FlowDirection::Outbound);
+        assert_eq!(local.classify(&host, &nas), FlowDirection::Internal);
+        assert_eq!(local.classify(&remote, &other), FlowDirection::External);
+        assert_eq!(local.classify_str("192.168.1.10", "8.8.8.8"), FlowDirection::Outbound);
+        assert_eq!(local.classify_str("not-an-ip", "192.168.1.10"), FlowDirection::Inbound);
+
+        // Nothing is local without networks.
+        let none = LocalNetworks::default();
+        assert_eq!(none.classify(&host, &nas), FlowDirection::External);
+        assert_eq!("internal".parse(), Ok(FlowDirection::Internal));
+        assert!("ingress".parse::<FlowDirection>().is_err());
+    }
+
+    #[test]
+    fn test_cast_boundaries() {
+        let local = LocalNetworks::new(vec![
+            "192.168.1.0/24".parse().unwrap(),
+            "10.1.0.0/30".parse().unwrap(),
+            "10.2.0.0/31".parse().unwrap(),
+            "fd00::/64".parse().unwrap(),
+        ]);
+        let cast = |ip: &str| local.cast_str(ip);
+        for ip in ["224.0.0.0", "224.0.0.251", "239.255.255.250", "239.255.255.255", "ff02::fb"] {
+            assert_eq!(cast(ip), Cast::Multicast, "{}", ip);
+        }
+        for ip in ["223.255.255.255", "240.0.0.0", "192.168.1.254", "192.168.2.255", "fd00::ff"] {
+            assert_eq!(cast(ip), Cast::Unicast, "{}", ip);
+        }
+        for ip in ["255.255.255.255", "192.168.1.255", "10.1.0.3"] {
+            assert_eq!(cast(ip), Cast::Broadcast, "{}", ip);
+        }
+        // A /31 has no broadcast address, and remote networks' are unknown.
+        assert_eq!(cast("10.2.0.1"), Cast::Unicast);
+        assert_eq!(LocalNetworks::default().cast_str("192.168.1.255"), Cast::Unicast);
+        assert_eq!(cast("not-an-ip"), Cast::Unicast);
+        assert_eq!(CastSelection::All.class(), None);
+        assert_eq!(CastSelection::Broadcast.class(), Some(Cast::Broadcast));
+    }
+
+    #[test]
+    fn test_derives_networks_from_interface_addresses() {
+        let output = "\
+1: lo    inet 127.0.0.1/8 scope host lo\\       valid_lft forever preferred_lft forever
+2: eth0    inet 192.168.1.10/24 brd 192.168.1.255 scope global dynamic eth0\\       valid_lft 86077sec
+2: eth0    inet 192.168.1.11/24 scope global secondary eth0\\       valid_lft forever preferred_lft forever
+2: eth0    inet6 2001:db8:1:2:aaaa:bbbb:cccc:dddd/64 scope global dynamic \\       valid_lft 86000sec
+2: eth0    inet6 fe80::1/64 scope link \\       valid_lft forever preferred_lft forever
+";
+        let networks: Vec<String> = parse_ip_addr(output).iter().map(IpNet::to_string).collect();
+        assert_eq!(
+            networks,
+            ["127.0.0.0/8", "192.168.1.0/24", "2001:db8:1:2::/64", "fe80::/64"]
+        );
+        assert!(parse_ip_addr("").is_empty());
+    }
+}
@@ -1,29 +1,101 @@
+// The OpenAPI document in api.rs is one json! literal deeper than the
+// default limit allows.
+#![recursion_limit = "256"]
+
+use anyhow::Context;
 use clap::Parser;
+use futures_util::future::Either;
+use futures_util::FutureExt;
+use std::convert::Infallible;
+use std::future::IntoFuture;
+use std::panic::AssertUnwindSafe;
 use std::path::Path;
-use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
 use tokio::sync::mpsc;
 use tokio::time::{interval, Duration};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-use aya::Ebpf;
-use aya::maps::{Array, RingBuf};
-use aya::programs::{tc, SchedClassifier, TcAttachType};
+use aya::{Ebpf, EbpfLoader, VerifierLogLevel};
+use aya::maps::{lpm_trie::LpmTrie, Array, PerCpuArray, PerCpuHashMap, RingBuf};
 
-use ayaflow_common::PacketEvent;
+use ayaflow_common::filter::{Filters, TrafficFilter};
+use ayaflow_common::{
+    Sampler, CONFIG_CAPTURE_NON_IP, CONFIG_DEEP_INSPECT, CONFIG_ENABLE_IPV6,
+    CONFIG_KERNEL_AGGREGATION, CONFIG_L3_INTERFACE, COUNTER_RING_BUF_DROPS,
+};
 
+mod alerts;
 mod api;
+mod asymmetry;
+mod attach;
+mod backfill;
+mod bench;
+mod blocklist;
+mod bpf_stats;
+mod cardinality;
+mod categories;
+mod cli;
+mod clock;
+#[cfg(test)]
+mod client_tests;
+#[cfg(feature = "clickhouse")]
+mod clickhouse;
 mod config;
+mod connection_export;
+mod dedup;
+mod devices;
+mod diagnostics;
 mod dns;
+mod fleet;
+mod geoip;
+mod headroom;
+mod health;
+mod hooks;
+mod icmp;
+#[cfg(all(test, feature = "integration-test"))]
+mod integration;
+mod ip_filter;
+mod kernel_agg;
 mod l7;
+mod locality;
+mod migrate;
+mod openapi;
+mod port_filter;
+#[cfg(feature = "postgres")]
+mod postgres;
+mod preflight;
+mod query_cache;
+mod rates;
+mod reload;
+mod reports;
+mod services;
+mod spill;
 mod state;
 mod storage;
+#[cfg(test)]
+mod test_support;
+mod unix_socket;
+mod version;
 
-use config::{CliArgs, Config};
-use state::PacketMetadata;
+use config::{Cli, Command, Config, ConfigSource, RunMode};
+use state::{PacketMetadata, StateSnapshot, TrafficState, SNAPSHOT_VERSION};
+use storage::{StorageBackend, StorageEvent};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let cli = CliArgs::parse();
+    let cli = match Cli::parse() {
+        Cli { command: Some(Command::Query(args)), .. } => return cli::query(&args),
+        Cli { command: Some(Command::Top(args)), .. } => return cli::top(&args),
+        Cli { command: Some(Command::Detach(args)), .. } => return attach::detach(&args),
+        Cli { command: Some(Command::Bench(args)), .. } => return bench::run(&args).await,
+        Cli { command: Some(Command::BackfillDns(args)), .. } => {
+            return backfill::run_cli(&args).await
+        }
+        Cli { command: Some(Command::Migrate(args)), .. } => return migrate::run_cli(&args),
+        Cli { command: Some(Command::Run(args)), .. } => args,
+        Cli { command: None, run } => run,
+    };
 
     // Load config from file if provided, otherwise use defaults.
     let mut config = if let Some(ref config_path) = cli.config {
@@ -32,6 +104,20 @@ async fn main() -> anyhow::Result<()> {
         Config::default()
     };
     config.merge_cli(&cli);
+    let config_file = cli.config.as_deref().map(Path::new);
+    config.validate(config_file)?;
+    if cli.check_config {
+        match config_file {
+            Some(file) => println!("{}: OK", file.display()),
+            None => println!("command line: OK"),
+        }
+        return Ok(());
+    }
+    let categories = categories::PortCategories::new(&config.categories)
+        .map_err(|e| anyhow::anyhow!("categories: {}", e))?
+        .with_rule(config.service_port_rule);
+    let report_schedule = reports::ReportSchedule::new(&config.reports)?;
+    let pusher = fleet::Pusher::new(&config.fleet)?;
 
     // Logging.
     if config.quiet {
@@ -48,33 +134,603 @@ async fn main() -> anyhow::Result<()> {
             .init();
     }
 
-    // -- eBPF setup --------------------------------------------------------
-    let mut bpf = Ebpf::load(aya::include_bytes_aligned!(concat!(
-        env!("CARGO_MANIFEST_DIR"),
-        "/../ayaflow-ebpf/target/bpfel-unknown-none/debug/ayaflow"
-    )))?;
+    let capturing = config.mode == RunMode::Capture;
+
+    // -- Channels ----------------------------------------------------------
+    let (tx, rx) = match capturing {
+        true => {
+            let (tx, rx) = mpsc::channel::<StorageEvent>(STORAGE_QUEUE_CAPACITY);
+            (Some(tx), Some(rx))
+        }
+        false => (None, None),
+    };
+
+    let diagnostics = Arc::new(diagnostics::Diagnostics::new());
+    let storage_peak = match &tx {
+        Some(tx) => diagnostics.watch_queue("storage", tx),
+        None => diagnostics::QueuePeak::default(),
+    };
+
+    let blocklist = Arc::new(blocklist::Blocklist::from_config(&config.blocklist));
+    match (config.blocklist.enforce, config.blocklist.enforce_flag) {
+        (true, true) => tracing::warn!("Blocklist enforcement on: matching packets are dropped"),
+        (true, false) => tracing::warn!(
+            "blocklist.enforce is set but --enforce-blocklist was not passed; only flagging"
+        ),
+        (false, true) => tracing::warn!(
+            "--enforce-blocklist needs blocklist.enforce: true in the config; only flagging"
+        ),
+        (false, false) => {}
+    }
+
+    // -- State & Storage ---------------------------------------------------
+    let local_networks = locality::LocalNetworks::from_config(
+        &config.local_networks,
+        config.interface.as_deref().unwrap_or("eth0"),
+    );
+    let mut traffic_state = state::TrafficState::new()
+        .with_local_networks(local_networks.clone())
+        .with_jitter(state::JitterScope::new(config.jitter.udp, &config.jitter.ports))
+        .with_asymmetry(asymmetry::AsymmetryTracker::new(&config.asymmetry))
+        .with_categories(Arc::new(categories));
+    if let (Some(tx), false) = (&tx, config.hooks.is_empty()) {
+        let (hook_tx, hook_rx) = mpsc::channel(hooks::QUEUE_CAPACITY);
+        let peak = diagnostics.watch_queue("hooks", &hook_tx);
+        let engine = hooks::HookEngine::new(&config.hooks, hook_tx, peak)?;
+        tracing::info!("Running {} connection hook(s)", config.hooks.len());
+        tokio::spawn(hooks::run_hooks(hook_rx, tx.clone()));
+        traffic_state = traffic_state.with_hooks(engine);
+    }
+    if let (true, Some(geoip)) = (capturing, geoip::GeoIp::from_config(&config.geoip)?) {
+        traffic_state = traffic_state.with_geoip(geoip);
+    }
+    if config.count_forwarded_once {
+        let window = Duration::from_millis(config.forwarded_dedup_window_ms);
+        tracing::info!("Counting packets seen on two interfaces once ({:?} window)", window);
+        traffic_state = traffic_state.with_forward_dedup(window);
+    }
+    let traffic_state = Arc::new(traffic_state);
+    let health = Arc::new(health::HealthRegistry::new());
+    // Rows are tagged for `?instance=`; an API-only process writes none.
+    let instance = capturing.then(|| config.instance_name());
+    if let Some(instance) = &instance {
+        tracing::info!("Storing rows as instance {}", instance);
+    }
+    let storage = storage::open_backend(
+        config.db_url.as_deref(),
+        &config.db_path,
+        local_networks.networks().to_vec(),
+        config.aggregation_key,
+        &config.sqlite,
+        &config.storage,
+        &config.clickhouse,
+        &config.postgres,
+        instance.as_deref(),
+    )?;
+
+    // -- State Persistence (optional) ---------------------------------------
+    // Without capture the live state stays empty; saving it would overwrite
+    // the snapshot the capturing agent left behind.
+    let persist_state = config.persist_state && capturing;
+    let snapshot_key = state_snapshot_key(instance.as_deref());
+    if persist_state {
+        let timeout = config.connection_timeout;
+        restore_state(&traffic_state, storage.as_ref(), &snapshot_key, timeout);
+
+        let traffic_state_persist = traffic_state.clone();
+        let storage_persist = storage.clone();
+        let key = snapshot_key.clone();
+        let heartbeat =
+            health.register("state_persistence", false, Some(Duration::from_secs(180)));
+        tokio::spawn(async move {
+            let mut persist_interval = interval(Duration::from_secs(60));
+            // The first tick completes immediately; nothing to save yet.
+            persist_interval.tick().await;
+            loop {
+                persist_interval.tick().await;
+                let saved = save_state(&traffic_state_persist, storage_persist.as_ref(), &key);
+                heartbeat.report(&saved);
+            }
+        });
+    }
+
+    // -- Fleet Push Task (optional) ----------------------------------------
+    // The pusher gets a copy of what the writer stores from a tee in front
+    // of the writer.
+    let rx = match (rx, pusher) {
+        (Some(rx), Some(pusher)) => {
+            let (writer_tx, writer_rx) = mpsc::channel(STORAGE_QUEUE_CAPACITY);
+            let (push_tx, push_rx) = mpsc::channel(fleet::QUEUE_CAPACITY);
+            let peak = diagnostics.watch_queue("fleet", &push_tx);
+            let every = pusher.interval();
+            let central = config.fleet.central_url.as_deref().unwrap_or_default();
+            tracing::info!("Pushing summaries to {} every {:?}", central, every);
+            let deadline = (every * 3).max(Duration::from_secs(30));
+            let heartbeat = health.register("fleet_push", false, Some(deadline));
+            tokio::spawn(fleet::tee(rx, writer_tx, push_tx, peak));
+            let instance = config.instance_name();
+            tokio::spawn(pusher.run(instance, push_rx, traffic_state.clone(), heartbeat));
+            Some(writer_rx)
+        }
+        (rx, Some(_)) => {
+            tracing::warn!("API-only mode: not pushing to fleet.central_url");
+            rx
+        }
+        (rx, None) => rx,
+    };
+
+    // The writer beats on every flush tick (the flush interval, or once per
+    // window).
+    let aggregation_window = config.aggregation_window_seconds;
+    let tick = match aggregation_window {
+        0 => config.storage.flush_interval(),
+        window => Duration::from_secs(window),
+    };
+    let writer_deadline = (tick * 3).max(Duration::from_secs(30));
+
+    // -- Secondary Storage Writer (optional) --------------------------------
+    // A second tee in front of the writer feeds a writer of its own, whose
+    // failures degrade health without touching the primary.
+    let (rx, secondary) = match (rx, &config.secondary_db_url) {
+        (Some(rx), Some(url)) => {
+            let backend = storage::open_backend(
+                Some(url),
+                &config.db_path,
+                local_networks.networks().to_vec(),
+                config.aggregation_key,
+                &config.sqlite,
+                &config.storage,
+                &config.clickhouse,
+                &config.postgres,
+                instance.as_deref(),
+            )?;
+            let (writer_tx, writer_rx) = mpsc::channel(STORAGE_QUEUE_CAPACITY);
+            let (secondary_tx, secondary_rx) = mpsc::channel(STORAGE_QUEUE_CAPACITY);
+            let peak = diagnostics.watch_queue("secondary_storage", &secondary_tx);
+            let secondary = Arc::new(migrate::Secondary::new(backend.clone(), secondary_tx, peak));
+            tracing::info!(
+                "Also writing to {}; reads stay on the primary",
+                crate::config::redact_url_password(url)
+            );
+            let heartbeat = health.register("storage_secondary", false, Some(writer_deadline));
+            tokio::spawn(migrate::tee(rx, writer_tx, secondary.clone()));
+            tokio::spawn(storage::supervise_writer(
+                backend,
+                secondary_rx,
+                aggregation_window,
+                heartbeat,
+            ));
+            (Some(writer_rx), Some(secondary))
+        }
+        (rx, Some(_)) => {
+            tracing::warn!("API-only mode: not writing to secondary_db_url");
+            (rx, None)
+        }
+        (rx, None) => (rx, None),
+    };
+
+    // -- Storage Writer Task -----------------------------------------------
+    if let Some(rx) = rx {
+        let storage_clone = storage.clone();
+        if aggregation_window == 0 {
+            tracing::info!(
+                "Storage writer flushes at {} rows or every {}ms",
+                config.storage.flush_max_rows,
+                config.storage.flush_interval_ms
+            );
+        }
+        let heartbeat = health.register("storage_writer", true, Some(writer_deadline));
+        tokio::spawn(storage::supervise_writer(storage_clone, rx, aggregation_window, heartbeat));
+    }
+
+    // -- Rate Sampler Task -------------------------------------------------
+    // It also samples the capacity headroom while capturing, measuring
+    // flushes against the writer's tick.
+    let traffic_state_rates = traffic_state.clone();
+    let mut headroom = capturing.then(|| {
+        let limits = headroom::HeadroomLimits {
+            drain_batch: RING_BATCH,
+            flush_interval: tick,
+            cores: std::thread::available_parallelism().map_or(1, |n| n.get()),
+        };
+        (headroom::HeadroomSampler::new(limits), diagnostics.clone(), storage.clone())
+    });
+    tokio::spawn(async move {
+        let mut sample_interval = interval(Duration::from_secs(1));
+        loop {
+            sample_interval.tick().await;
+            traffic_state_rates.sample_rates();
+            if let Some((sampler, diagnostics, storage)) = &mut headroom {
+                let flush_peak = storage.metrics().take_flush_peak();
+                let queues = diagnostics.take_queue_peaks();
+                sampler.sample(&traffic_state_rates.headroom, &queues, flush_peak);
+            }
+        }
+    });
+
+    // -- Connection Cleanup Task -------------------------------------------
+    let traffic_state_cleanup = traffic_state.clone();
+    let connection_timeout = config.connection_timeout;
+    let cleanup_every = Duration::from_secs(config.cleanup_interval_seconds);
+    // Beats once per sweep, so the deadline follows the interval.
+    let cleanup_deadline = (cleanup_every * 3).max(Duration::from_secs(60));
+    let heartbeat = health.register("connection_cleanup", false, Some(cleanup_deadline));
+    let tx_peers = tx.clone();
+    tokio::spawn(async move {
+        let mut cleanup_interval = interval(cleanup_every);
+        loop {
+            cleanup_interval.tick().await;
+            let peers = traffic_state_cleanup
+                .cleanup_stale_connections_in_chunks(Duration::from_secs(connection_timeout))
+                .await;
+            if let (Some(tx), false) = (&tx_peers, peers.is_empty()) {
+                let _ = tx.send(StorageEvent::Peers(peers)).await;
+            }
+            heartbeat.beat();
+        }
+    });
+
+    // -- Storage Maintenance Task ------------------------------------------
+    let storage_maintenance = storage.clone();
+    let secondary_maintenance = secondary.clone();
+    // An API-only instance usually shares the database with a capturing
+    // agent, which owns retention; only an explicit --data-retention applies.
+    let retention_from_cli =
+        config.source_map().get("data_retention_seconds") == Some(&ConfigSource::Cli);
+    let retention = config.data_retention_seconds.filter(|_| capturing || retention_from_cli);
+    if config.data_retention_seconds.is_some() && retention.is_none() {
+        tracing::info!("API-only mode: data retention disabled (pass --data-retention to enable)");
+    }
+    // Alerts come from the capturing agent too, so it alone prunes them.
+    let alert_retention = capturing
+        .then_some((config.alerts.retention_seconds, config.alerts.max_stored))
+        .filter(|&(age, rows)| age > 0 || rows > 0);
+    let wal_threshold = config.sqlite.wal_checkpoint_threshold_mb * 1024 * 1024;
+    let heartbeat =
+        health.register("storage_maintenance", false, Some(Duration::from_secs(180)));
+    tokio::spawn(async move {
+        let mut maintenance_interval = interval(Duration::from_secs(60));
+        loop {
+            maintenance_interval.tick().await;
+            let retained = retention.map(|seconds| storage_maintenance.delete_old_data(seconds));
+            match &retained {
+                Some(Ok(deleted)) if *deleted > 0 => {
+                    tracing::info!("Data retention: deleted {} old packets", deleted);
+                }
+                Some(Err(e)) => {
+                    tracing::error!("Data retention cleanup failed: {}", e);
+                }
+                _ => {}
+            }
+            // The secondary keeps what the primary keeps, so a cutover loses
+            // nothing the primary still had.
+            if let (Some(seconds), Some(secondary)) = (retention, &secondary_maintenance) {
+                if let Err(e) = secondary.backend().delete_old_data(seconds) {
+                    tracing::warn!("Secondary storage retention cleanup failed: {}", e);
+                }
+            }
+            let pruned = alert_retention
+                .map(|(age, rows)| storage_maintenance.delete_old_alerts(age, rows));
+            match &pruned {
+                Some(Ok(deleted)) if *deleted > 0 => {
+                    tracing::info!("Alert retention: deleted {} old alerts", deleted);
+                }
+                Some(Err(e)) => {
+                    tracing::error!("Alert retention cleanup failed: {}", e);
+                }
+                _ => {}
+            }
+            // Retention deletes just grew the WAL, so check it afterwards.
+            let checkpointed = storage_maintenance.checkpoint_wal(wal_threshold);
+            match &checkpointed {
+                Ok(Some(checkpoint)) if checkpoint.complete => tracing::info!(
+                    "WAL checkpoint: truncated {} bytes ({} frames)",
+                    checkpoint.wal_bytes,
+                    checkpoint.frames_checkpointed
+                ),
+                Ok(Some(checkpoint)) => tracing::warn!(
+                    "WAL checkpoint of {} bytes blocked by readers; retrying next run",
+                    checkpoint.wal_bytes
+                ),
+                Ok(None) => {}
+                Err(e) => tracing::error!("WAL checkpoint failed: {}", e),
+            }
+            match (retained, pruned, checkpointed) {
+                (Some(Err(e)), _, _) | (_, Some(Err(e)), _) | (_, _, Err(e)) => heartbeat.fail(e),
+                _ => heartbeat.beat(),
+            }
+        }
+    });
+
+    // -- Database Size Task ------------------------------------------------
+    // Measuring takes the SQLite reader lock or a ClickHouse round trip, so
+    // it is done here on the blocking pool and scrapes only read the gauges.
+    let storage_size = storage.clone();
+    tokio::spawn(async move {
+        let mut size_interval = interval(SIZE_REFRESH_INTERVAL);
+        loop {
+            size_interval.tick().await;
+            let storage = storage_size.clone();
+            let _ = tokio::task::spawn_blocking(move || storage.update_size_metric()).await;
+        }
+    });
+
+    // -- Daily Report Task (optional) --------------------------------------
+    if let Some(schedule) = report_schedule {
+        tracing::info!("Delivering a daily report at {} UTC", schedule.at());
+        let heartbeat = health.register("reports", false, None);
+        let (storage, traffic) = (storage.clone(), traffic_state.clone());
+        tokio::spawn(reports::run_reports(schedule, storage, traffic, heartbeat));
+    }
+
+    // -- Capture (skipped in API-only mode) -------------------------------
+    if capturing {
+        // Mark where stored data starts being sampled at this rate, so it
+        // can be scaled back up.  The flow sweeper stores every flow.
+        let sample_rate = match config.kernel_aggregation {
+            true => 1,
+            false => Sampler::new(config.sample_rate).rate(),
+        };
+        if sample_rate > 1 {
+            tracing::info!("Storing 1 in {} packets; live stats count all of them", sample_rate);
+        } else if config.sample_rate > 1 {
+            tracing::warn!("sample_rate has no effect with kernel_aggregation");
+        }
+        storage.record_sample_rate(sample_rate, chrono::Utc::now().timestamp_millis())?;
+    }
+    // Capture reads the filters through this, so a reload can swap them.
+    let filters = Arc::new(reload::LiveFilters::new(
+        Filters::new(&config.capture_filter, &config.storage_filter).map_err(anyhow::Error::msg)?,
+    ));
+    if let Some(file) = &cli.config {
+        let (file, cli, running) = (file.into(), cli.clone(), config.clone());
+        let live = reload::Reloadable {
+            filters: filters.clone(),
+            diagnostics: diagnostics.clone(),
+        };
+        tokio::spawn(reload::reload_on_sighup(file, cli, running, live));
+    }
+    let capture = match &tx {
+        Some(tx) => Some(start_capture(
+            &config,
+            tx,
+            &storage_peak,
+            &traffic_state,
+            &health,
+            &diagnostics,
+            &blocklist,
+            &filters,
+        )?),
+        None => {
+            tracing::info!("API-only mode: serving stored data without capturing");
+            None
+        }
+    };
+    drop(tx);
+    let attach = capture.as_ref().map(Capture::status).unwrap_or_default();
+    let loaded = capture.as_ref().map(Capture::loaded).unwrap_or_default();
+    let version = version::gather(ebpf_object(), attach.clone(), loaded);
+    version::log(&version);
+    if config.verbose_bpf_load {
+        version::log_load_details(&version);
+    }
+
+    // -- HTTP API -----------------------------------------------------------
+    let app_state = Arc::new(api::AppState {
+        traffic: traffic_state.clone(),
+        storage: storage.clone(),
+        health: health.clone(),
+        start_time: std::time::Instant::now(),
+        config: Arc::new(api::ConfigResponse::new(&config, cli.config.clone(), attach)),
+        services: Arc::new(
+            services::ServiceNames::new(&config.services).with_rule(config.service_port_rule),
+        ),
+        devices: Arc::new(devices::DeviceNames::new(&config.devices)),
+        capture: match capture {
+            Some(_) => api::CaptureState::Enabled,
+            None => api::CaptureState::Disabled,
+        },
+        diagnostics,
+        blocklist,
+        backfill: Arc::default(),
+        version: Arc::new(version),
+        fleet: Arc::default(),
+        secondary,
+        connection_snapshots: Arc::default(),
+    });
+    tokio::spawn(diagnostics::dump_on_sigusr1(app_state.clone()));
+
+    // -- Connection Snapshot Task (optional) -------------------------------
+    if let Some(dir) = &config.connection_snapshots.dir {
+        let snapshots = config.connection_snapshots.clone();
+        tracing::info!(
+            "Writing connection snapshots to {} every {}s, keeping {}",
+            dir,
+            snapshots.interval_seconds,
+            snapshots.keep
+        );
+        let deadline = snapshots.interval() * 3;
+        let heartbeat = health.register("connection_snapshots", false, Some(deadline));
+        tokio::spawn(connection_export::run_snapshots(app_state.clone(), snapshots, heartbeat));
+    }
+
+    let allowed_ips = config.allowed_ips.clone();
+    let app = api::router(app_state, &allowed_ips, config.serve_ui, &config.api);
+
+    let server = match &config.listen_socket {
+        Some(path) => {
+            let listener = unix_socket::bind(path, config.listen_socket_mode)?;
+            tracing::info!("Server running on unix:{}", path.display());
+            if !allowed_ips.is_empty() {
+                tracing::warn!("allowed_ips is ignored on the Unix socket");
+            }
+            Either::Left(unix_socket::serve(listener, app))
+        }
+        None => {
+            let addr = std::net::SocketAddr::new(config.listen_addr, config.port);
+            let listener = tokio::net::TcpListener::bind(addr)
+                .await
+                .with_context(|| format!("failed to bind API to {}", addr))?;
+            let base_path = config.api.base_path();
+            tracing::info!("Server running on http://{}{}", addr, base_path);
+            if config.serve_ui {
+                tracing::info!("Dashboard available at http://{}{}/", addr, base_path);
+            }
+            Either::Right(
+                axum::serve(
+                    listener,
+                    app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+                )
+                .into_future(),
+            )
+        }
+    };
+
+    // Race the server against a shutdown signal (Ctrl+C / SIGINT).
+    tokio::select! {
+        result = server => {
+            if let Err(e) = result {
+                tracing::error!("Server error: {}", e);
+            }
+        }
+        _ = tokio::signal::ctrl_c() => {
+            tracing::info!("Shutdown signal received, cleaning up...");
+        }
+    }
+
+    // -- Cleanup ---------------------------------------------------------
+    // Give in-flight storage writes a brief window to flush.
+    tokio::time::sleep(Duration::from_millis(250)).await;
+    if persist_state {
+        let _ = save_state(&traffic_state, storage.as_ref(), &snapshot_key);
+    }
+    if let Some(path) = &config.listen_socket {
+        let _ = std::fs::remove_file(path);
+    }
+    match capture {
+        Some(capture) => capture.shutdown(),
+        None => tracing::info!("Shutdown complete"),
+    }
 
+    Ok(())
+}
+
+/// The loaded and attached eBPF programs; dropping `bpf` detaches them.
+struct Capture {
+    bpf: Ebpf,
+    iface: String,
+    attachment: attach::Attachment,
+    /// Keeps the kernel's per-program statistics on and read while
+    /// capturing.
+    bpf_stats: Option<bpf_stats::Collector>,
+}
+
+impl Capture {
+    fn status(&self) -> api::AttachStatus {
+        api::AttachStatus {
+            interface: self.iface.clone(),
+            hooks: self.attachment.hooks.clone(),
+            created_qdisc: self.attachment.created_qdisc,
+        }
+    }
+
+    fn loaded(&self) -> version::LoadedObject {
+        attach::loaded_object(&self.bpf)
+    }
+
+    fn shutdown(self) {
+        let Capture { bpf, iface, attachment, bpf_stats } = self;
+        // Stop reading the programs' counters before they go.
+        drop(bpf_stats);
+        // Drop the eBPF handle.  This detaches the TC classifier / XDP program
+        // from the interface so no orphaned filter is left behind.
+        drop(bpf);
+        // A qdisc we found in place belongs to someone else and is left alone.
+        if attachment.created_qdisc {
+            match attach::remove_clsact_if_unused(&iface) {
+                Ok(true) => tracing::info!("Removed the clsact qdisc added on {}", iface),
+                Ok(false) => {
+                    tracing::info!("Other filters remain on {}, keeping clsact qdisc", iface)
+                }
+                Err(e) => tracing::warn!("Could not remove clsact qdisc from {}: {:#}", iface, e),
+            }
+        }
+        tracing::info!("eBPF programs detached from {}, shutdown complete", iface);
+    }
+}
 
-    // Attach TC classifier to the target interface.
+/// Load and attach the eBPF programs, then spawn everything that consumes
+/// their events: the ring buffer poller or flow sweeper, L7 inspection,
+/// reverse DNS, and alerting.
+#[allow(clippy::too_many_arguments)]
+fn start_capture(
+    config: &Config,
+    tx: &mpsc::Sender<StorageEvent>,
+    storage_peak: &diagnostics::QueuePeak,
+    traffic_state: &Arc<TrafficState>,
+    health: &Arc<health::HealthRegistry>,
+    diagnostics: &diagnostics::Diagnostics,
+    blocklist: &blocklist::Blocklist,
+    filters: &Arc<reload::LiveFilters>,
+) -> anyhow::Result<Capture> {
+    // -- eBPF setup --------------------------------------------------------
     let iface = config
         .interface
         .as_deref()
         .unwrap_or("eth0");
-
-    // If the clsact qdisc already exists (EEXIST), that is fine.
-    if let Err(e) = tc::qdisc_add_clsact(iface) {
-        if e.raw_os_error() != Some(17) {
-            return Err(e.into());
-        }
-        tracing::debug!("clsact qdisc already exists on {}, reusing", iface);
+    if !config.skip_preflight {
+        preflight::run(config, iface)?;
+    }
+    let object = ebpf_object();
+    check_object_abi(object, config.force_ebpf_mismatch)?;
+    // aya only returns the verifier log of a program the kernel rejects.
+    let verifier_log_level = match config.verbose_bpf_load {
+        true => VerifierLogLevel::VERBOSE | VerifierLogLevel::STATS,
+        false => VerifierLogLevel::default(),
+    };
+    // SAFETY: sysconf only reads a system constant.
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u32;
+    let ringbuf_size = config.ringbuf_size_bytes(page_size);
+    if ringbuf_size != config.ringbuf_size_kb * 1024 {
+        tracing::info!(
+            "ringbuf_size_kb {} is below one {}-byte page; using one page",
+            config.ringbuf_size_kb,
+            page_size
+        );
     }
-    let program: &mut SchedClassifier =
-        bpf.program_mut("ayaflow").unwrap().try_into()?;
+    tracing::info!("Packet event ring buffer: {} KiB", ringbuf_size / 1024);
+    traffic_state.ring_buf_size_bytes.store(ringbuf_size.into(), Ordering::Relaxed);
+    let mut bpf = EbpfLoader::new()
+        .verifier_log_level(verifier_log_level)
+        .set_max_entries("EVENTS", ringbuf_size)
+        .load(object)
+        .map_err(|e| attach::explain_error(iface, config.manage_qdisc, e.into()))?;
 
-    program.load()?;
-    program.attach(iface, TcAttachType::Ingress)?;
-    program.attach(iface, TcAttachType::Egress)?;
-    tracing::info!("eBPF TC classifier attached to {} (ingress + egress)", iface);
+    // Attach the TC classifier and/or XDP program to the target interface.
+
+    let attachment = attach::attach_programs(&mut bpf, iface, config)?;
+    tracing::info!("eBPF attached to {} ({})", iface, attachment.hooks.join(", "));
+    // Unlike the pcap binary, the hooks never change the interface's mode.
+    tracing::info!(
+        "Capture sees traffic traversing {} only; promiscuous mode is not used",
+        iface
+    );
+    let bpf_stats = if config.bpf_stats {
+        // Only a setting written out may change the host-wide sysctl.
+        let use_sysctl = config.source_map().get("bpf_stats") != Some(&ConfigSource::Default);
+        match bpf_stats::start(&bpf, traffic_state.clone(), use_sysctl) {
+            Ok(collector) => Some(collector),
+            Err(e) => {
+                tracing::warn!(
+                    "eBPF runtime statistics unavailable, omitting ayaflow_bpf_* metrics: {}",
+                    e
+                );
+                None
+            }
+        }
+    } else {
+        None
+    };
 
     // -- Write runtime flags to eBPF CONFIG map -----------------------------
     {
@@ -83,7 +739,7 @@ async fn main() -> anyhow::Result<()> {
 
         // CONFIG[0]: deep_inspect
         if config.deep_inspect {
-            config_map.set(0, 1u32, 0)?;
+            config_map.set(CONFIG_DEEP_INSPECT, 1u32, 0)?;
             tracing::info!("Deep L7 inspection enabled (DNS + TLS SNI)");
         } else {
             tracing::debug!("Deep L7 inspection disabled");
@@ -91,66 +747,71 @@ async fn main() -> anyhow::Result<()> {
 
         // CONFIG[1]: enable_ipv6
         if config.enable_ipv6 {
-            config_map.set(1, 1u32, 0)?;
+            config_map.set(CONFIG_ENABLE_IPV6, 1u32, 0)?;
             tracing::info!("IPv6 packet capture enabled");
         } else {
             tracing::debug!("IPv6 packet capture disabled (IPv4 only)");
         }
-    }
 
-    // -- Channels ----------------------------------------------------------
-    let (tx, rx) = mpsc::channel::<PacketMetadata>(10000);
-
-    // -- State & Storage ---------------------------------------------------
-    let traffic_state = Arc::new(state::TrafficState::new());
-    let storage = Arc::new(storage::Storage::new(&config.db_path)?);
-
-    // -- Storage Writer Task -----------------------------------------------
-    let storage_clone = storage.clone();
-    let aggregation_window = config.aggregation_window_seconds;
-    tokio::spawn(async move {
-        storage_clone.run_writer(rx, aggregation_window).await;
-    });
+        // CONFIG[2]: kernel_aggregation
+        if config.kernel_aggregation {
+            config_map.set(CONFIG_KERNEL_AGGREGATION, 1u32, 0)?;
+            tracing::info!("Kernel-side flow aggregation enabled (per-packet events off)");
+        }
 
-    // -- Connection Cleanup Task -------------------------------------------
-    let traffic_state_cleanup = traffic_state.clone();
-    let connection_timeout = config.connection_timeout;
-    tokio::spawn(async move {
-        let mut cleanup_interval = interval(Duration::from_secs(10));
-        loop {
-            cleanup_interval.tick().await;
-            traffic_state_cleanup
-                .cleanup_stale_connections(Duration::from_secs(connection_timeout));
+        // CONFIG[3]: l3_interface
+        let l3_interface = config
+            .l3_interface
+            .unwrap_or_else(|| attach::detect_l3_interface(iface));
+        if l3_interface {
+            config_map.set(CONFIG_L3_INTERFACE, 1u32, 0)?;
+            tracing::info!("{} is a layer 3 interface, skipping Ethernet parsing", iface);
         }
-    });
 
-    // -- Data Retention Task -----------------------------------------------
-    if let Some(retention_seconds) = config.data_retention_seconds {
-        let storage_retention = storage.clone();
-        tokio::spawn(async move {
-            let mut retention_interval = interval(Duration::from_secs(60));
-            loop {
-                retention_interval.tick().await;
-                match storage_retention.delete_old_data(retention_seconds) {
-                    Ok(deleted) if deleted > 0 => {
-                        tracing::info!("Data retention: deleted {} old packets", deleted);
-                    }
-                    Err(e) => {
-                        tracing::error!("Data retention cleanup failed: {}", e);
-                    }
-                    _ => {}
-                }
+        // CONFIG[4]: capture_non_ip
+        if config.capture_non_ip {
+            if config.kernel_aggregation {
+                tracing::warn!("capture_non_ip needs per-packet events; \
+                                it is inactive with kernel aggregation");
+            } else if l3_interface {
+                tracing::warn!("capture_non_ip has no effect on layer 3 interfaces");
+            } else {
+                config_map.set(CONFIG_CAPTURE_NON_IP, 1u32, 0)?;
+                tracing::info!("Capturing ARP and other non-IP frames");
             }
-        });
+        }
+    }
+
+    // CONFIG[5] follows the blocklist entries and CONFIG[6] and CONFIG[7]
+    // the prefilters, so all of them keep a handle on the map.
+    let config_map = Arc::new(Mutex::new(Array::try_from(bpf.take_map("CONFIG").unwrap())?));
+    let capture = filters.get().capture.clone();
+    filters.attach_kernel(
+        port_filter::PortPrefilter::load(&mut bpf, config_map.clone(), capture.ports()),
+        ip_filter::IpPrefilter::load(&mut bpf, config_map.clone(), capture.ips()),
+    );
+    let trie = LpmTrie::try_from(bpf.take_map("BLOCKLIST").unwrap())?;
+    blocklist.attach(trie, config_map)?;
+    let entries = config.blocklist.entries.len();
+    if entries > 0 {
+        let action = if blocklist.enforcing() { "dropping" } else { "flagging" };
+        tracing::info!("Blocklist loaded with {} entries, {} matches", entries, action);
     }
 
     // -- DNS Cache (optional reverse lookup) --------------------------------
     let dns_cache = if config.resolve_dns {
         tracing::info!("Reverse DNS resolution enabled");
-        Some(Arc::new(dns::DnsCache::new(
-            Duration::from_secs(300),
-            Duration::from_secs(2),
-        )))
+        let heartbeat = health.register("dns", false, None);
+        let (dns_tx, dns_rx) = mpsc::channel(dns::QUEUE_CAPACITY);
+        let peak = diagnostics.watch_queue("dns", &dns_tx);
+        let cache = Arc::new(
+            dns::DnsCache::from_config(&config.dns)
+                .with_heartbeat(heartbeat)
+                .with_queue(dns_tx, peak),
+        );
+        diagnostics.set_dns_cache(cache.clone());
+        tokio::spawn(cache.clone().run_resolver(dns_rx, tx.clone()));
+        Some(cache)
     } else {
         None
     };
@@ -183,104 +844,848 @@ async fn main() -> anyhow::Result<()> {
         None
     };
 
-    // -- RingBuf Poller (L3/L4 events) --------------------------------------
-    let events_map = bpf.take_map("EVENTS").unwrap();
-    let ring_buf = RingBuf::try_from(events_map)?;
-    let tx_ring = tx.clone();
-    let traffic_state_ring = traffic_state.clone();
+    // -- Alert Engine (optional) ---------------------------------------------
+    let alert_engine = if config.alerts.any_enabled() {
+        let engine = Arc::new(alerts::AlertEngine::new(config.alerts.clone()));
+        if config.kernel_aggregation {
+            tracing::warn!("Per-packet alert rules need the ring buffer path; \
+                            they are inactive with kernel aggregation");
+        }
 
-    tokio::spawn(async move {
-        poll_ring_buf(ring_buf, tx_ring, traffic_state_ring, dns_cache, domain_cache).await;
-    });
-    
-    drop(tx);
+        let engine_cleanup = engine.clone();
+        tokio::spawn(async move {
+            let mut cleanup_interval = interval(Duration::from_secs(60));
+            loop {
+                cleanup_interval.tick().await;
+                engine_cleanup.cleanup();
+            }
+        });
 
-    // -- HTTP API -----------------------------------------------------------
-    let app_state = Arc::new(api::AppState {
-        traffic: traffic_state.clone(),
-        storage: storage.clone(),
-        start_time: std::time::Instant::now(),
-    });
+        if config.alerts.ef_rate_above_bps.is_some() {
+            let engine_ef = engine.clone();
+            let traffic_state_ef = traffic_state.clone();
+            let tx_ef = tx.clone();
+            tokio::spawn(async move {
+                let ef = &traffic_state_ef.qos[alerts::DSCP_EF as usize];
+                let mut check_interval = interval(Duration::from_secs(1));
+                loop {
+                    check_interval.tick().await;
+                    let now = std::time::Instant::now();
+                    if let Some(alert) = engine_ef.check_ef_traffic(now, ef) {
+                        tracing::warn!("Alert [{}] {}", alert.rule, alert.message);
+                        if tx_ef.send(StorageEvent::Alert(alert)).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            });
+        }
 
-    let allowed_ips = config.allowed_ips.clone();
-    let app = api::router(app_state, &allowed_ips);
-
-    let listener =
-        tokio::net::TcpListener::bind(format!("0.0.0.0:{}", config.port)).await?;
-    tracing::info!("Server running on http://0.0.0.0:{}", config.port);
-    let server = axum::serve(
-        listener,
-        app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
-    );
+        if config.alerts.new_connections_above.is_some() {
+            let engine_churn = engine.clone();
+            let traffic_state_churn = traffic_state.clone();
+            let tx_churn = tx.clone();
+            tokio::spawn(async move {
+                let created = &traffic_state_churn.connections_created;
+                let mut check_interval = interval(Duration::from_secs(1));
+                loop {
+                    check_interval.tick().await;
+                    let now = std::time::Instant::now();
+                    let total = created.load(Ordering::Relaxed);
+                    if let Some(alert) = engine_churn.check_connection_churn(now, total) {
+                        tracing::warn!("Alert [{}] {}", alert.rule, alert.message);
+                        if tx_churn.send(StorageEvent::Alert(alert)).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            });
+        }
 
-    // Race the server against a shutdown signal (Ctrl+C / SIGINT).
-    tokio::select! {
-        result = server => {
-            if let Err(e) = result {
-                tracing::error!("Server error: {}", e);
-            }
+        Some(engine)
+    } else {
+        None
+    };
+
+    // -- RingBuf Poller (L3/L4 events) or kernel flow-map sweeper ----------
+    let interfaces = Arc::new(attach::InterfaceNames::new());
+    if !filters.get().capture.is_empty() {
+        tracing::info!("Capturing only {}", config.capture_filter);
+    }
+    if !filters.get().storage.is_empty() {
+        tracing::info!("Storing only {}", config.storage_filter);
+    }
+    let filters = filters.clone();
+    if config.kernel_aggregation {
+        // Without an explicit window, sweep every 10 seconds.
+        let window_secs = match config.aggregation_window_seconds {
+            0 => 10,
+            secs => secs,
+        };
+        let flows = PerCpuHashMap::try_from(bpf.take_map("FLOWS").unwrap())?;
+        let counters = PerCpuArray::try_from(bpf.take_map("COUNTERS").unwrap())?;
+        let tx_sweep = tx.clone();
+        let storage_peak = storage_peak.clone();
+        let traffic_state_sweep = traffic_state.clone();
+        let sweeper_deadline = Duration::from_secs((window_secs * 3).max(30));
+        let heartbeat = health.register("flow_sweeper", true, Some(sweeper_deadline));
+        tracing::info!("Sweeping kernel flow map every {}s", window_secs);
+        tokio::spawn(async move {
+            kernel_agg::sweep_flow_map(
+                flows,
+                counters,
+                Duration::from_secs(window_secs),
+                tx_sweep,
+                storage_peak,
+                traffic_state_sweep,
+                interfaces,
+                dns_cache,
+                domain_cache,
+                filters,
+                heartbeat,
+            )
+            .await;
+        });
+    } else {
+        let events_map = bpf.take_map("EVENTS").unwrap();
+        let ring_buf = RingBuf::try_from(events_map)?;
+        let counters = PerCpuArray::try_from(bpf.take_map("COUNTERS").unwrap())?;
+        tokio::spawn(poll_ring_buf_drops(counters, traffic_state.clone()));
+        let tx_ring = tx.clone();
+        let traffic_state_ring = traffic_state.clone();
+        let heartbeat = health.register("packet_poller", true, Some(Duration::from_secs(30)));
+        let poller = Poller {
+            tx: tx_ring,
+            storage_peak: storage_peak.clone(),
+            traffic_state: traffic_state_ring,
+            interfaces,
+            dns_cache,
+            domain_cache,
+            alert_engine,
+            sample_rate: config.sample_rate,
+            filters,
+            #[cfg(test)]
+            enrich_hook: None,
+        };
+        tokio::spawn(supervise_poller(ring_buf, poller, heartbeat));
+    }
+
+    Ok(Capture {
+        bpf,
+        iface: iface.to_string(),
+        attachment,
+        bpf_stats,
+    })
+}
+
+/// Key of the live-state snapshot in the `state` table, from before
+/// instances kept one each.
+const STATE_SNAPSHOT_KEY: &str = "traffic_state";
+
+/// Each instance saves its own snapshot, so agents sharing a database do
+/// not restore each other's counters.
+fn state_snapshot_key(instance: Option<&str>) -> String {
+    match instance {
+        Some(instance) => format!("{}:{}", STATE_SNAPSHOT_KEY, instance),
+        None => STATE_SNAPSHOT_KEY.to_string(),
+    }
+}
+
+/// Seed `traffic_state` from the last snapshot saved under `key`, or the
+/// pre-instance one.  Missing, corrupt, or outdated snapshots are skipped
+/// so the agent always starts.
+fn restore_state(
+    traffic_state: &TrafficState,
+    storage: &dyn StorageBackend,
+    key: &str,
+    timeout_secs: u64,
+) {
+    let saved = storage.load_state(key).and_then(|json| match json {
+        None if key != STATE_SNAPSHOT_KEY => storage.load_state(STATE_SNAPSHOT_KEY),
+        json => Ok(json),
+    });
+    let json = match saved {
+        Ok(Some(json)) => json,
+        Ok(None) => return,
+        Err(e) => {
+            tracing::warn!("Failed to read state snapshot: {}", e);
+            return;
         }
-        _ = tokio::signal::ctrl_c() => {
-            tracing::info!("Shutdown signal received, cleaning up...");
+    };
+    let snapshot: StateSnapshot = match serde_json::from_str(&json) {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            tracing::warn!("Ignoring corrupt state snapshot: {}", e);
+            return;
         }
+    };
+    if snapshot.version != SNAPSHOT_VERSION {
+        tracing::warn!(
+            "Ignoring state snapshot version {} (expected {})",
+            snapshot.version,
+            SNAPSHOT_VERSION
+        );
+        return;
     }
+    let restored = traffic_state.restore(snapshot, Duration::from_secs(timeout_secs));
+    tracing::info!("Restored state snapshot with {} active connections", restored);
+}
 
-    // -- Cleanup ---------------------------------------------------------
-    // Give in-flight storage writes a brief window to flush.
-    tokio::time::sleep(Duration::from_millis(250)).await;
+fn save_state(
+    traffic_state: &TrafficState,
+    storage: &dyn StorageBackend,
+    key: &str,
+) -> anyhow::Result<()> {
+    let result = serde_json::to_string(&traffic_state.snapshot())
+        .map_err(anyhow::Error::from)
+        .and_then(|json| Ok(storage.save_state(key, &json)?));
+    if let Err(ref e) = result {
+        tracing::warn!("Failed to save state snapshot: {}", e);
+    }
+    result
+}
 
-    // Drop the eBPF handle.  This detaches the TC classifier from the
-    // interface so no orphaned filter is left behind.
-    drop(bpf);
-    tracing::info!("TC filters detached from {}, shutdown complete", iface);
+/// Mirror the kernel's ring buffer and blocklist drop counters into the live
+/// state once a second, warning whenever ring buffer drops grow.
+async fn poll_ring_buf_drops(
+    counters: PerCpuArray<aya::maps::MapData, u64>,
+    traffic_state: Arc<TrafficState>,
+) {
+    let mut poll_interval = interval(Duration::from_secs(1));
+    loop {
+        poll_interval.tick().await;
+        blocklist::sync_drop_counter(&counters, &traffic_state);
+        let Ok(values) = counters.get(&COUNTER_RING_BUF_DROPS, 0) else {
+            continue;
+        };
+        let drops: u64 = values.iter().sum();
+        let previous = traffic_state.ring_buf_drops.swap(drops, Ordering::Relaxed);
+        if drops > previous {
+            tracing::warn!(
+                "Ring buffer full: {} packet events dropped (ringbuf_size_kb is {})",
+                drops - previous,
+                traffic_state.ring_buf_size_bytes.load(Ordering::Relaxed) / 1024
+            );
+        }
+    }
+}
+
+/// The eBPF object built into this binary.
+fn ebpf_object() -> &'static [u8] {
+    aya::include_bytes_aligned!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/../ayaflow-ebpf/target/bpfel-unknown-none/debug/ayaflow"
+    ))
+}
 
+/// Refuse an embedded eBPF object built against another event layout, which
+/// happens when userspace is rebuilt without the classifier.
+fn check_object_abi(object: &[u8], force: bool) -> anyhow::Result<()> {
+    let found = ayaflow_common::embedded_abi_hash(object);
+    if found == Some(ayaflow_common::ABI_HASH) {
+        return Ok(());
+    }
+    let message = format!(
+        "the embedded eBPF object was built for layout {} but this binary expects {:08x}; \
+         rebuild both with `cargo xtask build`",
+        found.map_or("unknown".to_string(), |hash| format!("{:08x}", hash)),
+        ayaflow_common::ABI_HASH
+    );
+    anyhow::ensure!(force, "{} (or pass --force to load it anyway)", message);
+    tracing::warn!("Loading anyway because of --force: {}", message);
     Ok(())
 }
 
-/// Continuously poll the eBPF RingBuf for PacketEvent entries, convert them
-/// to PacketMetadata, update the live TrafficState, and forward to the storage writer channel.
-async fn poll_ring_buf(
-    mut ring_buf: RingBuf<aya::maps::MapData>,
-    tx: mpsc::Sender<PacketMetadata>,
+/// Most ring buffer events forwarded to the storage writer in one message.
+const RING_BATCH: usize = 256;
+
+/// Messages the storage channel holds before senders wait.
+const STORAGE_QUEUE_CAPACITY: usize = 10000;
+
+/// How often the database and WAL size gauges are measured.
+const SIZE_REFRESH_INTERVAL: Duration = Duration::from_secs(15);
+
+/// First and longest wait before restarting a panicked poller.
+const POLLER_BACKOFF_MIN: Duration = Duration::from_secs(1);
+const POLLER_BACKOFF_MAX: Duration = Duration::from_secs(30);
+
+/// Where the poller reads raw events: the EVENTS ring buffer, or a fake in
+/// tests.
+trait EventSource: Send {
+    /// Pass the next waiting event to `f`; false when there is none.
+    fn next_with(&mut self, f: &mut dyn FnMut(&[u8])) -> bool;
+}
+
+impl EventSource for RingBuf<aya::maps::MapData> {
+    fn next_with(&mut self, f: &mut dyn FnMut(&[u8])) -> bool {
+        match self.next() {
+            Some(item) => {
+                f(&item);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Everything the poller hands events on to.
+struct Poller {
+    tx: mpsc::Sender<StorageEvent>,
+    storage_peak: diagnostics::QueuePeak,
     traffic_state: Arc<state::TrafficState>,
+    interfaces: Arc<attach::InterfaceNames>,
     dns_cache: Option<Arc<dns::DnsCache>>,
     domain_cache: Option<Arc<l7::DomainCache>>,
+    alert_engine: Option<Arc<alerts::AlertEngine>>,
+    /// Store 1 in this many events; 0 or 1 stores them all.
+    sample_rate: u32,
+    filters: Arc<reload::LiveFilters>,
+    /// Called with each drained batch before it is enriched, so tests can
+    /// fail there.
+    #[cfg(test)]
+    enrich_hook: Option<fn(&mut [PacketMetadata])>,
+}
+
+/// Poll `source` forever, restarting with backoff whenever the poller
+/// panics, say in enrichment.  The supervisor keeps the source, so the
+/// EVENTS map never has to be taken again; events the kernel writes
+/// meanwhile wait in the ring buffer, and only the batch in hand is lost.
+async fn supervise_poller(
+    mut source: impl EventSource,
+    poller: Poller,
+    heartbeat: health::Heartbeat,
 ) {
+    let mut backoff = POLLER_BACKOFF_MIN;
     loop {
-        while let Some(item) = ring_buf.next() {
-            if item.len() < core::mem::size_of::<PacketEvent>() {
-                continue;
+        let started = std::time::Instant::now();
+        let run = poller.run(&mut source, &heartbeat);
+        let Err(panic) = AssertUnwindSafe(run).catch_unwind().await;
+        let cause = panic
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown cause".into());
+        if started.elapsed() > POLLER_BACKOFF_MAX {
+            backoff = POLLER_BACKOFF_MIN;
+        }
+        tracing::error!("Packet poller panicked ({}), restarting in {:?}", cause, backoff);
+        heartbeat.fail(format!("poller panicked ({}), restarting in {:?}", cause, backoff));
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(POLLER_BACKOFF_MAX);
+    }
+}
+
+impl Poller {
+    /// Continuously poll `source` for PacketEvent entries, convert them to
+    /// PacketMetadata, update the live TrafficState, and forward to the
+    /// storage writer channel.
+    async fn run(&self, source: &mut dyn EventSource, heartbeat: &health::Heartbeat) -> Infallible {
+        let mut sampler = Sampler::new(self.sample_rate);
+        loop {
+            let mut batch = Vec::with_capacity(RING_BATCH);
+            let mut kernel = Vec::with_capacity(RING_BATCH);
+            let mut decode = |bytes: &[u8]| {
+                let name = |ifindex| self.interfaces.name(ifindex);
+                match state::packet_from_bytes(bytes, name) {
+                    Ok((meta, info)) => {
+                        batch.push(meta);
+                        kernel.push(info);
+                    }
+                    Err(e) => {
+                        // One bad event usually means they all are; say so once.
+                        let malformed = &self.traffic_state.malformed_events;
+                        if malformed.fetch_add(1, Ordering::Relaxed) == 0 {
+                            tracing::error!(
+                                "Skipping ring buffer events that do not match this build \
+                                 ({}); is the eBPF object stale?",
+                                e
+                            );
+                        }
+                    }
+                }
+            };
+            let mut read = 0;
+            while read < RING_BATCH && source.next_with(&mut decode) {
+                read += 1;
+            }
+            self.traffic_state.headroom.record_drain(read);
+            #[cfg(test)]
+            if let Some(hook) = self.enrich_hook {
+                hook(&mut batch);
             }
-            let event =
-                unsafe { core::ptr::read_unaligned(item.as_ptr() as *const PacketEvent) };
-            let mut meta = PacketMetadata::from_ebpf(&event);
-
-            // Enrich with reverse DNS if enabled.
-            if let Some(ref cache) = dns_cache {
-                meta.src_hostname = cache.resolve(&meta.src_ip).await;
-                meta.dst_hostname = cache.resolve(&meta.dst_ip).await;
+
+            let drained = read < RING_BATCH;
+            forward_batch(
+                batch,
+                &kernel,
+                &self.tx,
+                &self.traffic_state,
+                &mut sampler,
+                &self.filters.get(),
+                self.dns_cache.as_deref(),
+                self.domain_cache.as_deref(),
+                self.alert_engine.as_deref(),
+            )
+            .await;
+            self.storage_peak.record(&self.tx);
+
+            heartbeat.beat();
+            // Yield briefly to avoid busy-spinning when the ring buffer is
+            // empty; a full batch means more events are likely waiting.
+            if drained {
+                tokio::time::sleep(Duration::from_millis(1)).await;
             }
+        }
+    }
+}
+
+/// Enrich a batch of ring buffer events, fold the ones `filters.capture`
+/// passes into the live state, and hand those `filters.storage` passes and
+/// `sampler` keeps to the storage writer as one message.
+#[allow(clippy::too_many_arguments)]
+async fn forward_batch(
+    mut batch: Vec<PacketMetadata>,
+    kernel: &[state::KernelInfo],
+    tx: &mpsc::Sender<StorageEvent>,
+    traffic_state: &TrafficState,
+    sampler: &mut Sampler,
+    filters: &Filters,
+    dns_cache: Option<&dns::DnsCache>,
+    domain_cache: Option<&l7::DomainCache>,
+    alert_engine: Option<&alerts::AlertEngine>,
+) {
+    if batch.is_empty() {
+        return;
+    }
+
+    // Enrich with cached reverse DNS if enabled; misses are resolved in the
+    // background and backfilled by the storage writer.
+    if let Some(cache) = dns_cache {
+        cache.fill_cached(&mut batch);
+    }
+
+    let passes = |filter: &TrafficFilter, meta: &PacketMetadata| {
+        filter.matches(&meta.src_ip, &meta.dst_ip, meta.src_port, meta.dst_port, &meta.protocol)
+    };
+    let mut stored = Vec::with_capacity(batch.len());
+    for (meta, kernel) in batch.iter_mut().zip(kernel) {
+        if !passes(&filters.capture, meta) {
+            stored.push(false);
+            continue;
+        }
+        meta.flow_direction = Some(traffic_state.flow_direction(&meta.src_ip, &meta.dst_ip));
+        meta.cast = Some(traffic_state.cast(&meta.dst_ip));
+        if let Some(geoip) = traffic_state.geoip() {
+            geoip.fill(meta);
+        }
+        // Enrich with domain from L7 deep inspection if enabled.
+        if let Some(cache) = domain_cache {
+            meta.domain = cache.lookup_destination(&meta.dst_ip, meta.dst_port);
+        }
+
+        let is_new = traffic_state.update_from_kernel(meta, *kernel);
+        stored.push(is_new && passes(&filters.storage, meta) && sampler.keep());
+        if !is_new {
+            continue;
+        }
+        for alert in alert_engine.map(|e| e.check_packet(meta)).unwrap_or_default() {
+            tracing::warn!("Alert [{}] {}", alert.rule, alert.message);
+            let _ = tx.send(StorageEvent::Alert(alert)).await;
+        }
+    }
+    // Duplicate sightings are not stored either, so stored totals agree
+    // with the live ones (divided by the sample rate) where no filter
+    // applies.
+    if stored.contains(&false) {
+        let mut stored = stored.into_iter();
+        batch.retain(|_| stored.next().unwrap_or(true));
+    }
+    if batch.is_empty() {
+        return;
+    }
+    let _ = tx.send(StorageEvent::Packets(batch)).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use ayaflow_common::filter::FilterSpec;
+    use std::time::Instant;
 
-            // Enrich with domain from L7 deep inspection if enabled.
-            if let Some(ref cache) = domain_cache {
-                // Try TLS SNI match first (most specific: dst_ip:dst_port).
-                let sni_key = format!("{}:{}", meta.dst_ip, meta.dst_port);
-                if let Some(domain) = cache.get(&sni_key) {
-                    meta.domain = Some(domain);
-                } else {
-                    // Try DNS-based match (by domain name is less direct, so
-                    // we try the dst_ip match for any cached DNS resolution).
-                    if let domain @ Some(_) = cache.get_by_dst_ip(&meta.dst_ip) {
-                        meta.domain = domain;
+    /// Events/sec through `forward_batch` into a writer that discards
+    /// everything, sending one event per message versus `RING_BATCH`.
+    ///
+    /// `cargo test -p ayaflow --release -- --ignored --nocapture bench_`
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn bench_forward_batch_throughput() {
+        const EVENTS: usize = 1_000_000;
+        let packet = PacketMetadata {
+            src_ip: "10.0.0.1".into(),
+            dst_ip: "192.168.1.1".into(),
+            length: 1500,
+            payload_length: 1448,
+            ttl: Some(64),
+            ..test_support::packet()
+        };
+
+        for batch_size in [1, RING_BATCH] {
+            let (tx, mut rx) = mpsc::channel(10000);
+            let sink = tokio::spawn(async move {
+                let mut received = 0;
+                while let Some(event) = rx.recv().await {
+                    if let StorageEvent::Packets(packets) = event {
+                        received += packets.len();
                     }
                 }
+                received
+            });
+            let traffic_state = TrafficState::new();
+            let kernel = vec![state::KernelInfo::default(); batch_size];
+
+            let start = Instant::now();
+            for _ in 0..EVENTS / batch_size {
+                let batch = vec![packet.clone(); batch_size];
+                let (all, none) = (&mut Sampler::new(1), &Filters::default());
+                forward_batch(batch, &kernel, &tx, &traffic_state, all, none, None, None, None)
+                    .await;
             }
+            drop(tx);
+            let received = sink.await.unwrap();
+            let elapsed = start.elapsed().as_secs_f64();
+
+            assert_eq!(received, EVENTS / batch_size * batch_size);
+            println!(
+                "batch size {:>3}: {:>10.0} events/sec",
+                batch_size,
+                received as f64 / elapsed
+            );
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_supervisor_restarts_panicked_poller() {
+        /// Groups of undecodable events, each group ending a drain.
+        struct Undecodable(Vec<usize>);
+        impl EventSource for Undecodable {
+            fn next_with(&mut self, f: &mut dyn FnMut(&[u8])) -> bool {
+                match self.0.first_mut() {
+                    Some(0) => {
+                        self.0.remove(0);
+                        false
+                    }
+                    Some(left) => {
+                        *left -= 1;
+                        f(&[0; 4]);
+                        true
+                    }
+                    None => false,
+                }
+            }
+        }
+        /// Enrichment that breaks on the first batch only.
+        fn fail_once(_: &mut [PacketMetadata]) {
+            use std::sync::atomic::AtomicBool;
+            static FAILED: AtomicBool = AtomicBool::new(false);
+            if !FAILED.swap(true, Ordering::Relaxed) {
+                panic!("enrichment failed");
+            }
+        }
+
+        let (tx, _rx) = mpsc::channel(16);
+        let traffic_state = Arc::new(TrafficState::new());
+        let poller = Poller {
+            tx,
+            storage_peak: Default::default(),
+            traffic_state: traffic_state.clone(),
+            interfaces: Arc::new(attach::InterfaceNames::new()),
+            dns_cache: None,
+            domain_cache: None,
+            alert_engine: None,
+            sample_rate: 1,
+            filters: Arc::default(),
+            enrich_hook: Some(fail_once),
+        };
+        let registry = Arc::new(health::HealthRegistry::new());
+        let heartbeat = registry.register("packet_poller", true, None);
+        let source = Undecodable(vec![3, 2]);
+        tokio::spawn(supervise_poller(source, poller, heartbeat));
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let error = registry.report().1[0].last_error.clone().unwrap();
+        assert!(error.contains("poller panicked (enrichment failed)"), "{}", error);
+        assert_eq!(registry.report().0, health::ComponentStatus::Degraded);
+        assert_eq!(traffic_state.malformed_events.load(Ordering::Relaxed), 3);
+
+        // After the backoff the same source is polled again.
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(traffic_state.malformed_events.load(Ordering::Relaxed), 5);
+        assert_eq!(registry.report().0, health::ComponentStatus::Ok);
+    }
+
+    #[tokio::test]
+    async fn test_sampling_thins_storage_only() {
+        let packet = |src_port| PacketMetadata {
+            src_ip: "10.0.0.1".into(),
+            dst_ip: "192.168.1.1".into(),
+            src_port,
+            length: 100,
+            payload_length: 48,
+            direction: "egress".into(),
+            ttl: Some(64),
+            ..test_support::packet()
+        };
+        let (tx, mut rx) = mpsc::channel(16);
+        let traffic_state = TrafficState::new();
+        let (sampler, none) = (&mut Sampler::new(3), &Filters::default());
+        let kernel = vec![state::KernelInfo::default(); 4];
+        for ports in [40000..40004, 40004..40008] {
+            let batch = ports.map(packet).collect();
+            forward_batch(batch, &kernel, &tx, &traffic_state, sampler, none, None, None, None)
+                .await;
+        }
+        drop(tx);
+
+        assert_eq!(traffic_state.total_packets.load(Ordering::Relaxed), 8);
+        let mut stored = Vec::new();
+        while let Some(StorageEvent::Packets(packets)) = rx.recv().await {
+            stored.extend(packets.iter().map(|p| p.src_port));
+        }
+        // The 3rd and 6th packets across batches.
+        assert_eq!(stored, [40002, 40005]);
+    }
+
+    #[tokio::test]
+    async fn test_storage_filter_keeps_the_live_view() {
+        let packet = |src_ip: &str, protocol: &str| PacketMetadata {
+            timestamp: chrono::Utc::now().timestamp_millis(),
+            src_ip: src_ip.into(),
+            dst_ip: "192.168.1.1".into(),
+            protocol: protocol.into(),
+            length: 100,
+            payload_length: 48,
+            direction: "egress".into(),
+            ttl: Some(64),
+            ..test_support::packet()
+        };
+        let spec = |yaml: &str| serde_yaml::from_str::<FilterSpec>(yaml).unwrap();
+        let filters = Filters::new(
+            &spec("protocols: [tcp]"),
+            &spec("ips: [10.1.0.0/16]\nports: [400-500]"),
+        )
+        .unwrap();
+        let (tx, mut rx) = mpsc::channel(16);
+        let traffic_state = TrafficState::new();
+        let batch = vec![
+            packet("10.1.0.5", "TCP"),
+            packet("10.9.0.5", "TCP"),
+            packet("10.1.0.6", "UDP"),
+        ];
+        let kernel = vec![state::KernelInfo::default(); batch.len()];
+        let all = &mut Sampler::new(1);
+        forward_batch(batch, &kernel, &tx, &traffic_state, all, &filters, None, None, None).await;
+        drop(tx);
+
+        // The UDP packet is not captured at all; both TCP ones are live.
+        assert_eq!(traffic_state.total_packets.load(Ordering::Relaxed), 2);
+        assert_eq!(traffic_state.connections.len(), 2);
 
-            traffic_state.update(&meta);
-            let _ = tx.send(meta).await;
+        let storage = storage::Storage::new(":memory:").unwrap();
+        while let Some(StorageEvent::Packets(mut packets)) = rx.recv().await {
+            storage.flush(&mut packets).unwrap();
         }
+        let rows = storage.query_packets(&storage::PacketFilter::default(), 100).unwrap();
+        let sources: Vec<&str> = rows.iter().map(|row| row.packet.src_ip.as_str()).collect();
+        assert_eq!(sources, ["10.1.0.5"]);
+    }
+
+    /// GeoIP places each packet's remote end: live connections keep the
+    /// country they were created with, and stored rows are found by
+    /// country, ASN or the lack of a country.
+    #[tokio::test]
+    async fn test_geoip_reaches_live_top_and_history() {
+        let packet = |src_ip: &str, dst_ip: &str, src_port, length| PacketMetadata {
+            timestamp: chrono::Utc::now().timestamp_millis(),
+            src_ip: src_ip.into(),
+            dst_ip: dst_ip.into(),
+            src_port,
+            length,
+            ..test_support::packet()
+        };
+        let local = locality::LocalNetworks::new(vec!["10.0.0.0/8".parse().unwrap()]);
+        let traffic_state =
+            TrafficState::new().with_local_networks(local).with_geoip(geoip::fixture::geoip());
+        let batch = vec![
+            packet("10.0.0.2", "203.0.113.9", 40000, 100),
+            packet("10.0.0.2", "203.0.113.10", 40001, 200),
+            packet("198.51.100.7", "10.0.0.1", 5000, 400),
+            packet("10.0.0.2", "192.0.2.1", 40002, 50),
+        ];
+        let kernel = vec![state::KernelInfo::default(); batch.len()];
+        let (tx, mut rx) = mpsc::channel(16);
+        let (all, none) = (&mut Sampler::new(1), &Filters::default());
+        forward_batch(batch, &kernel, &tx, &traffic_state, all, none, None, None, None).await;
+        drop(tx);
+
+        let top = traffic_state.top_talkers(
+            state::TopBy::Country,
+            state::SubnetPrefixes::host(),
+            None,
+            10,
+        );
+        let groups: Vec<_> =
+            top.iter().map(|t| (t.country.as_deref().unwrap(), t.bytes, t.hosts)).collect();
+        assert_eq!(groups, [("US", 400, 1), ("JP", 300, 2), ("unknown", 50, 1)]);
 
-        // Yield briefly to avoid busy-spinning when the ring buffer is empty.
-        tokio::time::sleep(Duration::from_millis(1)).await;
+        let storage = storage::Storage::new(":memory:").unwrap();
+        while let Some(StorageEvent::Packets(mut packets)) = rx.recv().await {
+            storage.flush(&mut packets).unwrap();
+        }
+        let query = |country: Option<&str>, asn| {
+            let filter = storage::PacketFilter {
+                country: country.map(|c| c.parse().unwrap()),
+                asn,
+                ..Default::default()
+            };
+            let rows = storage.query_packets(&filter, 100).unwrap();
+            let mut found: Vec<_> = rows
+                .iter()
+                .map(|row| (row.packet.src_port, row.packet.country.clone(), row.packet.asn))
+                .collect();
+            found.sort();
+            found
+        };
+        let jp = |port| (port, Some("JP".to_string()), Some(64500));
+        assert_eq!(query(Some("jp"), None), [jp(40000), jp(40001)]);
+        assert_eq!(query(None, Some(64501)), [(5000, Some("US".to_string()), Some(64501))]);
+        assert_eq!(query(Some("unknown"), None), [(40002, None, None)]);
+        assert_eq!(query(Some("CN"), None), []);
+    }
+
+    /// Reverse DNS never holds up a batch: misses go to storage without
+    /// hostnames and onto the resolver queue, and the resolver's answer
+    /// reaches the rows already stored through the `hostnames` table.
+    #[tokio::test]
+    async fn test_cold_dns_is_queued_and_backfilled() {
+        let packet = |dst_ip: &str| PacketMetadata {
+            src_ip: "10.0.0.1".into(),
+            dst_ip: dst_ip.into(),
+            length: 1500,
+            payload_length: 1448,
+            direction: "egress".into(),
+            ttl: Some(64),
+            ..test_support::packet()
+        };
+        // Nothing drains the queue, so a batch waiting on DNS would hang.
+        let (dns_tx, mut dns_rx) = mpsc::channel(dns::QUEUE_CAPACITY);
+        let cache = dns::DnsCache::new(Duration::from_secs(300), Duration::from_secs(2))
+            .with_queue(dns_tx, Default::default());
+        let (tx, mut rx) = mpsc::channel(16);
+        let traffic_state = TrafficState::new();
+        let batch = vec![packet("192.0.2.1"), packet("192.0.2.2"), packet("192.0.2.1")];
+        let kernel = vec![state::KernelInfo::default(); batch.len()];
+        let (all, none) = (&mut Sampler::new(1), &Filters::default());
+        let forwarding =
+            forward_batch(batch, &kernel, &tx, &traffic_state, all, none, Some(&cache), None, None);
+        tokio::time::timeout(Duration::from_secs(1), forwarding)
+            .await
+            .expect("forward_batch waited on DNS");
+
+        // Each distinct address is queued once.
+        let mut queued = Vec::new();
+        while let Ok(ip) = dns_rx.try_recv() {
+            queued.push(ip.to_string());
+        }
+        queued.sort();
+        assert_eq!(queued, ["10.0.0.1", "192.0.2.1", "192.0.2.2"]);
+        let Some(StorageEvent::Packets(packets)) = rx.recv().await else {
+            panic!("no packets forwarded");
+        };
+        assert_eq!(packets.len(), 3);
+        assert!(packets.iter().all(|p| p.src_hostname.is_none() && p.dst_hostname.is_none()));
+
+        let storage: Arc<dyn StorageBackend> = Arc::new(storage::Storage::new(":memory:").unwrap());
+        let (writer_tx, writer_rx) = mpsc::channel(16);
+        let heartbeat = Arc::new(health::HealthRegistry::new()).register("writer", true, None);
+        tokio::spawn(storage::supervise_writer(storage.clone(), writer_rx, 0, heartbeat));
+        writer_tx.send(StorageEvent::Packets(packets)).await.unwrap();
+        // What run_resolver sends once the lookup finishes.
+        let found = vec![("192.0.2.1".to_string(), "one.example".to_string())];
+        writer_tx.send(StorageEvent::Hostnames(found)).await.unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let rows = loop {
+            let rows = storage.query_history(10).unwrap();
+            if rows.len() == 3 && rows.iter().any(|row| row.packet.dst_hostname.is_some()) {
+                break rows;
+            }
+            assert!(Instant::now() < deadline, "rows never backfilled: {:?}", rows);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        };
+        let hostname = |dst: &str| {
+            let row = rows.iter().find(|row| row.packet.dst_ip == dst).unwrap();
+            row.packet.dst_hostname.clone()
+        };
+        assert_eq!(hostname("192.0.2.1").as_deref(), Some("one.example"));
+        assert_eq!(hostname("192.0.2.2"), None);
+    }
+
+    #[test]
+    fn test_check_object_abi() {
+        let mut object = b"\x7fELF".to_vec();
+        assert!(check_object_abi(&object, false).is_err());
+        assert!(check_object_abi(&object, true).is_ok());
+        object.extend_from_slice(&ayaflow_common::ABI_MARKER);
+        assert!(check_object_abi(&object, false).is_ok());
+    }
+
+    /// Worst-case batch latency with reverse DNS on and nothing cached: every
+    /// packet carries a new address.  Run with
+    /// `cargo test --release -- --ignored --nocapture bench_forward_batch_cold_dns`.
+    #[tokio::test]
+    #[ignore]
+    async fn bench_forward_batch_cold_dns() {
+        const BATCHES: usize = 1_000;
+        let (tx, mut rx) = mpsc::channel(10000);
+        let sink = tokio::spawn(async move { while rx.recv().await.is_some() {} });
+        let (dns_tx, _dns_rx) = mpsc::channel(dns::QUEUE_CAPACITY);
+        let cache = dns::DnsCache::new(Duration::from_secs(300), Duration::from_secs(2))
+            .with_queue(dns_tx, Default::default());
+        let traffic_state = TrafficState::new();
+        let kernel = vec![state::KernelInfo::default(); RING_BATCH];
+
+        let mut worst = Duration::ZERO;
+        let start = Instant::now();
+        for i in 0..BATCHES {
+            let batch = (0..RING_BATCH)
+                .map(|j| {
+                    let n = (i * RING_BATCH + j) as u32;
+                    PacketMetadata {
+                        src_ip: "10.0.0.1".into(),
+                        dst_ip: std::net::Ipv4Addr::from(0x0b00_0000 + n).to_string(),
+                        length: 1500,
+                        payload_length: 1448,
+                        direction: "egress".into(),
+                        ttl: Some(64),
+                        ..test_support::packet()
+                    }
+                })
+                .collect();
+            let batch_start = Instant::now();
+            let (all, none) = (&mut Sampler::new(1), &Filters::default());
+            let cache = Some(&cache);
+            forward_batch(batch, &kernel, &tx, &traffic_state, all, none, cache, None, None).await;
+            worst = worst.max(batch_start.elapsed());
+        }
+        drop(tx);
+        sink.await.unwrap();
+        println!(
+            "{} cold batches of {}: {:?} mean, {:?} worst",
+            BATCHES,
+            RING_BATCH,
+            start.elapsed() / BATCHES as u32,
+            worst
+        );
     }
 }
@@ -0,0 +1,461 @@
+//! Moving stored traffic to another backend: `secondary_db_url` writes new
+//! traffic to both, and `ayaflow migrate` copies what was stored before.
+//!
+//! A cutover runs the agent with the new backend as `secondary_db_url`,
+//! copies the old history across with `ayaflow migrate`, then swaps the
+//! URLs.  Reads always hit the primary.  The secondary writer gets a copy
+//! of every writer event through a tee; copies it is too far behind to take
+//! are dropped and counted rather than holding up the primary.
+//!
+//! The secondary records when dual writes began.  The copy walks the
+//! source's packets from before that time in batches, and saves its
+//! position in the destination's `state` table after each one, so an
+//! interrupted run resumes after the last finished batch; a batch cut
+//! short may be copied again.  The dual-write period is then checked a
+//! minute at a time: rows the secondary lacks, because the tee dropped them
+//! or its writes failed, are copied.  Rows are matched on everything but
+//! their hostnames, so a window checked twice gains nothing twice.  The
+//! last few minutes are left for a later run, as the secondary may still be
+//! writing them.  Usage rollups are rebuilt from the copied rows; alerts
+//! and peer totals are not copied.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use anyhow::Context;
+use clap::Args;
+use serde::Serialize;
+use tokio::sync::mpsc::{Receiver, Sender};
+
+use crate::config::Config;
+use crate::diagnostics::QueuePeak;
+use crate::locality::LocalNetworks;
+use crate::state::AggregatedBucket;
+use crate::storage::{
+    self, DbLocation, PacketFilter, PacketRecord, StorageBackend, StorageEvent, StorageResult,
+};
+
+pub use ayaflow_common::api::SecondaryStatus;
+
+/// `state` key, in the secondary, holding the time dual writes began.
+pub const DUAL_WRITE_SINCE_KEY: &str = "dual_write_since";
+
+/// `state` key, in the destination, holding how far the copy from one
+/// source got.
+const POSITION_KEY: &str = "migrate_position";
+
+pub const MAX_BATCH_ROWS: usize = 50_000;
+
+/// Length of the dual-write windows checked one at a time, in milliseconds.
+const WINDOW_MS: i64 = 60_000;
+
+/// Arguments for `ayaflow migrate`.
+#[derive(Args, Debug, Clone)]
+pub struct MigrateArgs {
+    /// Backend to copy from, e.g. sqlite://traffic.db.
+    #[arg(long)]
+    pub from: String,
+
+    /// Backend to copy into, e.g. clickhouse://host/traffic.
+    #[arg(long)]
+    pub to: String,
+
+    /// Config file for the local networks the usage rollups are kept for,
+    /// and the ClickHouse options.
+    #[arg(short, long)]
+    pub config: Option<String>,
+
+    /// Rows copied per batch.
+    #[arg(long, default_value_t = 5000)]
+    pub batch: usize,
+
+    /// Start again from the first row instead of where the last run stopped.
+    #[arg(long)]
+    pub restart: bool,
+
+    /// Leave the last this many seconds of dual writes for a later run, as
+    /// the secondary may not have written them yet.
+    #[arg(long, default_value_t = 300)]
+    pub settle_seconds: u64,
+}
+
+/// How far a copy got.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct MigrateProgress {
+    /// Source position after the last finished batch.
+    pub position: i64,
+    pub rows_read: u64,
+    pub rows_copied: u64,
+    /// Rows from the dual-write period, left to the window check.
+    pub rows_skipped: u64,
+    /// Rows from the dual-write period the secondary lacked, copied by the
+    /// window check.
+    pub rows_backfilled: u64,
+    /// How far the dual-write period is checked, epoch ms.
+    pub checked_through: Option<i64>,
+    /// Rows in the source when the copy started.
+    pub source_rows: u64,
+    /// The dual-write start, epoch ms; None when the destination was never
+    /// a secondary, in which case everything is copied.
+    pub before: Option<i64>,
+}
+
+pub fn run_cli(args: &MigrateArgs) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        (1..=MAX_BATCH_ROWS).contains(&args.batch),
+        "--batch must be between 1 and {}",
+        MAX_BATCH_ROWS
+    );
+    anyhow::ensure!(args.from != args.to, "--from and --to are the same backend");
+    let config = match &args.config {
+        Some(path) => Config::from_file(Path::new(path))?,
+        None => Config::default(),
+    };
+    if let DbLocation::Sqlite(path) = storage::parse_db_url(&args.from)? {
+        anyhow::ensure!(Path::new(&path).exists(), "no database at {}", path);
+    }
+    let open = |url: &str| {
+        let interface = config.interface.as_deref().unwrap_or("eth0");
+        storage::open_backend(
+            Some(url),
+            &config.db_path,
+            LocalNetworks::from_config(&config.local_networks, interface).networks().to_vec(),
+            config.aggregation_key,
+            &config.sqlite,
+            &config.storage,
+            &config.clickhouse,
+            &config.postgres,
+            None,
+        )
+        .with_context(|| format!("cannot open {}", crate::config::redact_url_password(url)))
+    };
+    let (source, destination) = (open(&args.from)?, open(&args.to)?);
+    let key = format!("{} {}", POSITION_KEY, crate::config::redact_url_password(&args.from));
+    let report = |p: &MigrateProgress| {
+        eprintln!(
+            "position {}: {} of {} rows read, {} copied, {} from dual writes; {} backfilled",
+            p.position, p.rows_read, p.source_rows, p.rows_copied, p.rows_skipped,
+            p.rows_backfilled
+        );
+    };
+    let done = migrate(source.as_ref(), destination.as_ref(), &key, args, report)?;
+    println!("{}", serde_json::to_string_pretty(&done)?);
+    Ok(())
+}
+
+/// Copy `source`'s packets into `destination` until none are left after
+/// the saved position, then check the settled dual-write windows, calling
+/// `report` after every batch and window.
+pub fn migrate(
+    source: &dyn StorageBackend,
+    destination: &dyn StorageBackend,
+    key: &str,
+    args: &MigrateArgs,
+    mut report: impl FnMut(&MigrateProgress),
+) -> StorageResult<MigrateProgress> {
+    let mut progress = MigrateProgress {
+        source_rows: source.query_totals(&PacketFilter::default())?.rows,
+        before: destination.load_state(DUAL_WRITE_SINCE_KEY)?.and_then(|t| t.parse().ok()),
+        ..MigrateProgress::default()
+    };
+    let saved = |key: &str| -> StorageResult<Option<i64>> {
+        let value = destination.load_state(key)?;
+        Ok(value.and_then(|p| p.parse().ok()).filter(|_| !args.restart))
+    };
+    progress.position = saved(key)?.unwrap_or(0);
+    loop {
+        let batch = source.export_packets(progress.position, args.batch)?;
+        if batch.rows.is_empty() {
+            break;
+        }
+        let read = batch.rows.len() as u64;
+        let rows: Vec<_> = batch
+            .rows
+            .into_iter()
+            .filter(|row| progress.before.is_none_or(|before| row.timestamp < before))
+            .collect();
+        if !rows.is_empty() {
+            destination.import_packets(&rows)?;
+        }
+        destination.save_state(key, &batch.through.to_string())?;
+        progress.position = batch.through;
+        progress.rows_read += read;
+        progress.rows_copied += rows.len() as u64;
+        progress.rows_skipped += read - rows.len() as u64;
+        report(&progress);
+    }
+
+    let Some(before) = progress.before else {
+        return Ok(progress);
+    };
+    let window_key = format!("{} checked", key);
+    let settled = chrono::Utc::now().timestamp_millis() - args.settle_seconds as i64 * 1000;
+    let mut from = saved(&window_key)?.unwrap_or(before);
+    while from < settled {
+        let to = (from + WINDOW_MS).min(settled);
+        progress.rows_backfilled += backfill_window(source, destination, from, to)?;
+        destination.save_state(&window_key, &to.to_string())?;
+        progress.checked_through = Some(to);
+        report(&progress);
+        from = to;
+    }
+    Ok(progress)
+}
+
+/// Copy the rows `source` holds from `from` until `to` that `destination`
+/// lacks, and return how many.
+fn backfill_window(
+    source: &dyn StorageBackend,
+    destination: &dyn StorageBackend,
+    from: i64,
+    to: i64,
+) -> StorageResult<u64> {
+    let mut held = HashMap::<String, usize>::new();
+    for row in destination.export_window(from, to)? {
+        *held.entry(row_key(&row)).or_default() += 1;
+    }
+    let missing: Vec<_> = source
+        .export_window(from, to)?
+        .into_iter()
+        .filter(|row| match held.get_mut(&row_key(row)) {
+            Some(count) if *count > 0 => {
+                *count -= 1;
+                false
+            }
+            _ => true,
+        })
+        .collect();
+    if !missing.is_empty() {
+        destination.import_packets(&missing)?;
+    }
+    Ok(missing.len() as u64)
+}
+
+/// What a row is matched on: everything but the hostnames, which each
+/// backend fills in from its own, later, lookups.
+fn row_key(row: &PacketRecord) -> String {
+    let row = PacketRecord { src_hostname: None, dst_hostname: None, ..row.clone() };
+    serde_json::to_string(&row).unwrap_or_default()
+}
+
+/// The backend written alongside the primary during a migration.
+pub struct Secondary {
+    backend: Arc<dyn StorageBackend>,
+    tx: Sender<StorageEvent>,
+    peak: QueuePeak,
+    dropped: AtomicU64,
+}
+
+impl Secondary {
+    /// Record when dual writes began, unless an earlier run already did.
+    pub fn new(
+        backend: Arc<dyn StorageBackend>,
+        tx: Sender<StorageEvent>,
+        peak: QueuePeak,
+    ) -> Self {
+        let started = backend.load_state(DUAL_WRITE_SINCE_KEY).and_then(|since| match since {
+            Some(_) => Ok(()),
+            None => {
+                let now = chrono::Utc::now().timestamp_millis();
+                backend.save_state(DUAL_WRITE_SINCE_KEY, &now.to_string())
+            }
+        });
+        if let Err(e) = started {
+            tracing::warn!("Cannot record the dual-write start in the secondary: {}", e);
+        }
+        Self { backend, tx, peak, dropped: AtomicU64::new(0) }
+    }
+
+    pub fn backend(&self) -> &Arc<dyn StorageBackend> {
+        &self.backend
+    }
+
+    /// Write buckets pushed to `/api/ingest` to the secondary too.  The
+    /// primary already took them, so a failure is only logged.
+    pub fn insert_ingested(&self, instance: &str, buckets: &[AggregatedBucket]) {
+        if let Err(e) = self.backend.insert_ingested(instance, buckets) {
+            tracing::warn!("Secondary storage did not take an ingested batch: {}", e);
+        }
+    }
+
+    pub fn status(&self, primary: &dyn StorageBackend) -> SecondaryStatus {
+        let written = |backend: &dyn StorageBackend| {
+            let metrics = backend.metrics();
+            metrics.rows_inserted.get() + metrics.replayed_rows.get()
+        };
+        let metrics = self.backend.metrics();
+        SecondaryStatus {
+            lag_rows: written(primary).saturating_sub(written(self.backend.as_ref())),
+            queued_events: self.tx.max_capacity() - self.tx.capacity(),
+            dropped_events: self.dropped.load(Ordering::Relaxed),
+            write_failures: metrics.transaction_failures.get() + metrics.insert_failures.get(),
+        }
+    }
+}
+
+/// Pass every event from `rx` on to the primary writer, copying it to the
+/// secondary's unless that one is full.  A copy is only made for a slot the
+/// secondary has room in; what it misses, `ayaflow migrate` backfills.
+pub async fn tee(
+    mut rx: Receiver<StorageEvent>,
+    writer: Sender<StorageEvent>,
+    secondary: Arc<Secondary>,
+) {
+    while let Some(event) = rx.recv().await {
+        match secondary.tx.try_reserve() {
+            Ok(slot) => {
+                slot.send(event.clone());
+                secondary.peak.record(&secondary.tx);
+            }
+            Err(_) => {
+                let dropped = secondary.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                if dropped.is_power_of_two() {
+                    tracing::warn!("Secondary storage is behind; {} events not written", dropped);
+                }
+            }
+        }
+        if writer.send(event).await.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SqliteConfig;
+    use crate::health::HealthRegistry;
+    use crate::state::PacketMetadata;
+    use crate::storage::Storage;
+    use crate::test_support;
+    use std::path::PathBuf;
+    use tokio::sync::mpsc;
+
+    /// Databases `a` and `b` in a fresh directory for one test, which the
+    /// test removes.
+    fn databases(test: &str) -> (PathBuf, Storage, Storage) {
+        let name = format!("ayaflow-migrate-{}-{}", test, std::process::id());
+        let dir = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let open = |name: &str| {
+            let path = dir.join(name);
+            Storage::open(path.to_str().unwrap(), &SqliteConfig::default()).unwrap()
+        };
+        let (a, b) = (open("a.db"), open("b.db"));
+        (dir, a, b)
+    }
+
+    fn packet(timestamp: i64) -> PacketMetadata {
+        PacketMetadata {
+            timestamp,
+            src_ip: "10.0.0.1".into(),
+            dst_ip: "1.1.1.1".into(),
+            length: 100,
+            payload_length: 60,
+            direction: "egress".into(),
+            src_mac: Some("02:00:00:00:00:01".into()),
+            ttl: Some(64),
+            dscp: Some(46),
+            dst_hostname: Some("one.one.one.one".into()),
+            ..test_support::packet()
+        }
+    }
+
+    fn args(batch: usize) -> MigrateArgs {
+        MigrateArgs {
+            from: "sqlite://a.db".into(),
+            to: "sqlite://b.db".into(),
+            config: None,
+            batch,
+            restart: false,
+            settle_seconds: 0,
+        }
+    }
+
+    #[test]
+    fn test_migrate_between_files_resumes() {
+        let (dir, source, destination) = databases("resume");
+        source.flush(&mut (1..=5).map(|t| packet(t * 1000)).collect::<Vec<_>>()).unwrap();
+
+        let mut batches = 0;
+        let first = migrate(&source, &destination, "key", &args(2), |_| batches += 1);
+        assert_eq!(first.unwrap().rows_copied, 5);
+        assert_eq!(batches, 3);
+        let rows = |storage: &Storage| {
+            let rows = storage.query_packets(&PacketFilter::default(), 10).unwrap();
+            serde_json::to_value(rows).unwrap()
+        };
+        assert_eq!(rows(&destination), rows(&source));
+        let copied = destination.query_packets(&PacketFilter::default(), 1).unwrap();
+        assert_eq!(copied[0].packet.ttl, Some(64));
+        assert_eq!(copied[0].packet.dst_hostname.as_deref(), Some("one.one.one.one"));
+        assert_eq!(destination.query_totals(&PacketFilter::default()).unwrap().bytes, 500);
+
+        // New source rows are picked up from the saved position.
+        source.flush(&mut vec![packet(6000)]).unwrap();
+        let resumed = migrate(&source, &destination, "key", &args(2), |_| {}).unwrap();
+        assert_eq!((resumed.rows_read, resumed.rows_copied), (1, 1));
+        assert_eq!(destination.query_totals(&PacketFilter::default()).unwrap().rows, 6);
+        let again = migrate(&source, &destination, "key", &args(2), |_| {}).unwrap();
+        assert_eq!(again.rows_read, 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_dual_write_and_copy_before_it() {
+        let (dir, primary, backend) = databases("dual");
+        let (primary, backend) = (Arc::new(primary), Arc::new(backend));
+        let since = chrono::Utc::now().timestamp_millis() - 90_000;
+        backend.save_state(DUAL_WRITE_SINCE_KEY, &since.to_string()).unwrap();
+
+        let (tx, rx) = mpsc::channel(16);
+        let (writer_tx, writer_rx) = mpsc::channel(16);
+        let (secondary_tx, secondary_rx) = mpsc::channel(1);
+        let secondary =
+            Arc::new(Secondary::new(backend.clone(), secondary_tx, QueuePeak::default()));
+        let health = Arc::new(HealthRegistry::new());
+        for (storage, rx) in [
+            (primary.clone() as Arc<dyn StorageBackend>, writer_rx),
+            (backend.clone() as Arc<dyn StorageBackend>, secondary_rx),
+        ] {
+            let heartbeat = health.register("writer", true, None);
+            tokio::spawn(storage::supervise_writer(storage, rx, 0, heartbeat));
+        }
+        let tee = tokio::spawn(tee(rx, writer_tx, secondary.clone()));
+        tx.send(StorageEvent::Packets(vec![packet(since + 1)])).await.unwrap();
+        drop(tx);
+        tee.await.unwrap();
+        for _ in 0..100 {
+            if secondary.status(primary.as_ref()).lag_rows == 0 && primary.stats().rows_inserted > 0
+            {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        let status = secondary.status(primary.as_ref());
+        assert_eq!((status.lag_rows, status.dropped_events, status.write_failures), (0, 0, 0));
+        assert_eq!(backend.query_totals(&PacketFilter::default()).unwrap().rows, 1);
+
+        // The copy takes a row from before dual writes, and of the two
+        // since, only the one the secondary missed.
+        primary.flush(&mut vec![packet(1000), packet(since + 70_000)]).unwrap();
+        let done = migrate(primary.as_ref(), backend.as_ref(), "key", &args(10), |_| {}).unwrap();
+        assert_eq!((done.rows_copied, done.rows_skipped, done.rows_backfilled), (1, 2, 1));
+        assert_eq!(done.before, Some(since));
+        assert!(done.checked_through.unwrap() > since + 70_000);
+        let rows = backend.query_packets(&PacketFilter::default(), 10).unwrap();
+        let mut timestamps: Vec<i64> = rows.iter().map(|row| row.packet.timestamp).collect();
+        timestamps.sort();
+        assert_eq!(timestamps, [1000, since + 1, since + 70_000]);
+
+        // Checking the windows again finds nothing missing.
+        backend.save_state("key checked", &since.to_string()).unwrap();
+        let again = migrate(primary.as_ref(), backend.as_ref(), "key", &args(10), |_| {}).unwrap();
+        assert_eq!((again.rows_read, again.rows_backfilled), (0, 0));
+        assert_eq!(backend.query_totals(&PacketFilter::default()).unwrap().rows, 3);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
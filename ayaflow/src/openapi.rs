@@ -0,0 +1,30 @@
+//! OpenAPI 3 schema generation, from `ayaflow_common::openapi` so the
+//! response types shared with `ayaflow-client` document themselves the same
+//! way.
+//!
+//! Types served by the API implement `ApiSchema`, normally through the
+//! `api_schema!` macro, which wraps the struct definition itself so the
+//! documented fields cannot drift from the serialized ones.  Types with
+//! custom serde representations implement the trait by hand and are checked
+//! against a serialized sample in the tests.
+
+pub(crate) use ayaflow_common::api_schema;
+pub use ayaflow_common::openapi::{object_schema, query_parameters, string_enum, ApiSchema};
+
+/// Assert that a serialized value has exactly the properties its schema
+/// documents, each of the documented type.  Used to check hand-written
+/// `ApiSchema` impls.
+#[cfg(test)]
+pub fn assert_matches_schema<T: ApiSchema + serde::Serialize>(value: &T) {
+    let serialized = serde_json::to_value(value).unwrap();
+    let schema = T::schema();
+    let mut actual: Vec<&String> = serialized.as_object().unwrap().keys().collect();
+    let mut documented: Vec<&String> =
+        schema["properties"].as_object().unwrap().keys().collect();
+    actual.sort();
+    documented.sort();
+    assert_eq!(actual, documented);
+    if let Err(e) = ayaflow_common::openapi::conforms(&serialized, &schema) {
+        panic!("{}", e);
+    }
+}
@@ -0,0 +1,157 @@
+//! The kernel prefilter for `capture_filter.ports`.
+//!
+//! TCP and UDP packets on none of the ports are turned away in the
+//! classifier, before they take a ring buffer slot or a flow map entry.
+//! Single ports go in the `FILTER_PORTS` hash map and ranges in the
+//! `FILTER_PORT_RANGES` array, which the classifier walks in turn.  Other
+//! protocols, lists too long for the maps and objects built without them
+//! are left to userspace, which applies the whole capture filter to every
+//! event either way.  A config reload loads the new ports in place.
+
+use aya::maps::{Array, HashMap, MapData};
+use aya::Ebpf;
+use ayaflow_common::filter::PortSet;
+use ayaflow_common::{
+    CONFIG_PORT_FILTER, PORT_FILTER_MAX_PORTS, PORT_FILTER_MAX_RANGES, PORT_FILTER_OFF,
+};
+
+use crate::attach::ConfigMap;
+
+/// A port set laid out for the kernel maps.
+#[derive(Debug, PartialEq, Eq)]
+struct KernelPorts {
+    singles: Vec<u16>,
+    /// `lo << 16 | hi`.
+    ranges: Vec<u32>,
+}
+
+/// Split `ports` over the maps, or say why they do not fit.
+fn split(ports: &PortSet) -> Result<KernelPorts, String> {
+    let (singles, ranges): (Vec<_>, Vec<_>) = ports.ranges().iter().partition(|(lo, hi)| lo == hi);
+    if singles.len() > PORT_FILTER_MAX_PORTS as usize {
+        return Err(format!(
+            "{} single ports, the kernel map holds {}",
+            singles.len(),
+            PORT_FILTER_MAX_PORTS
+        ));
+    }
+    if ranges.len() > PORT_FILTER_MAX_RANGES as usize {
+        return Err(format!(
+            "{} ranges, the kernel map holds {}",
+            ranges.len(),
+            PORT_FILTER_MAX_RANGES
+        ));
+    }
+    Ok(KernelPorts {
+        singles: singles.into_iter().map(|&(port, _)| port).collect(),
+        ranges: ranges
+            .into_iter()
+            .map(|&(lo, hi)| u32::from(lo) << 16 | u32::from(hi))
+            .collect(),
+    })
+}
+
+/// The prefilter maps, taken from the object so a reload can replace the
+/// ports.
+pub struct PortPrefilter {
+    singles: HashMap<MapData, u16, u8>,
+    ranges: Array<MapData, u32>,
+    config: ConfigMap,
+    /// What the maps hold; None with the prefilter off.
+    loaded: Option<KernelPorts>,
+}
+
+impl PortPrefilter {
+    /// Take the maps from `bpf` and load `ports`.  None for an object built
+    /// without them, which leaves the ports to userspace.
+    pub fn load(bpf: &mut Ebpf, config: ConfigMap, ports: &PortSet) -> Option<Self> {
+        match Self::take(bpf, config) {
+            Ok(mut prefilter) => {
+                prefilter.replace(ports);
+                Some(prefilter)
+            }
+            Err(e) => {
+                if !ports.is_empty() {
+                    tracing::info!("{}; capture_filter.ports applies in userspace only", e);
+                }
+                None
+            }
+        }
+    }
+
+    fn take(bpf: &mut Ebpf, config: ConfigMap) -> anyhow::Result<Self> {
+        let missing = |name| anyhow::anyhow!("the eBPF object has no {} map", name);
+        let singles = bpf.take_map("FILTER_PORTS").ok_or_else(|| missing("FILTER_PORTS"))?;
+        let ranges = bpf
+            .take_map("FILTER_PORT_RANGES")
+            .ok_or_else(|| missing("FILTER_PORT_RANGES"))?;
+        Ok(Self {
+            singles: HashMap::try_from(singles)?,
+            ranges: Array::try_from(ranges)?,
+            config,
+            loaded: None,
+        })
+    }
+
+    /// Load `ports` in place of what the maps hold; an empty set turns the
+    /// prefilter off.  A failure only costs the prefilter.
+    pub fn replace(&mut self, ports: &PortSet) {
+        match self.write(ports) {
+            Ok(true) => tracing::info!("Kernel port prefilter: {}", ports),
+            Ok(false) => {}
+            Err(e) => tracing::info!(
+                "Kernel port prefilter off, capture_filter.ports applies in userspace only: {}",
+                e
+            ),
+        }
+    }
+
+    /// Whether the prefilter is on afterwards.
+    fn write(&mut self, ports: &PortSet) -> anyhow::Result<bool> {
+        // Off while the maps change, so no port of either set is turned
+        // away meanwhile.
+        self.config.lock().unwrap().set(CONFIG_PORT_FILTER, PORT_FILTER_OFF, 0)?;
+        if let Some(old) = self.loaded.take() {
+            for port in &old.singles {
+                let _ = self.singles.remove(port);
+            }
+        }
+        if ports.is_empty() {
+            return Ok(false);
+        }
+        let kernel = split(ports).map_err(anyhow::Error::msg)?;
+        for port in &kernel.singles {
+            self.singles.insert(port, 1u8, 0)?;
+        }
+        for (i, range) in kernel.ranges.iter().enumerate() {
+            self.ranges.set(i as u32, range, 0)?;
+        }
+        // Set last, so the classifier only looks once the maps are complete.
+        let mode = kernel.ranges.len() as u32 + 1;
+        self.loaded = Some(kernel);
+        self.config.lock().unwrap().set(CONFIG_PORT_FILTER, mode, 0)?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_singles_and_ranges() {
+        let ports: PortSet = "443,80,30000-32767,8080-8081".parse().unwrap();
+        assert_eq!(
+            split(&ports).unwrap(),
+            KernelPorts {
+                singles: vec![80, 443],
+                ranges: vec![8080 << 16 | 8081, 30000 << 16 | 32767],
+            }
+        );
+
+        let many_singles = PortSet::from_ranges((0..=2048).step_by(2).map(|p| (p, p)));
+        assert!(split(&many_singles).unwrap_err().contains("1025 single ports"));
+        let many_ranges = PortSet::from_ranges((0..17u16).map(|i| (i * 10, i * 10 + 1)));
+        assert!(split(&many_ranges).unwrap_err().contains("17 ranges"));
+    }
+}
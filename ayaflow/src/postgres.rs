@@ -0,0 +1,1548 @@
+//! PostgreSQL storage backend, for a database several agents can share.
+//!
+//! Built with `--features postgres` and selected with a
+//! `postgres://[user[:password]@]host[:port]/database` db_url (or
+//! `postgresql://`, or any other form tokio-postgres parses).  Connections
+//! come from a pool of `pool_size`, shared by the writer and API queries,
+//! and go over plain TCP.
+//!
+//! The tables mirror SQLite's.  Packets are stored raw and go in through
+//! binary COPY, `flush_max_rows` at a time; rollups and alerts are updated
+//! in place, and retention DELETEs as it does with SQLite.
+//!
+//! An unreachable server does not stop the agent.  The writer holds up to
+//! `buffer_max_rows` rows in memory, retries on every flush interval and
+//! fails its heartbeat meanwhile; reads fail with 503 until it is back.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::error::Error as _;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use ayaflow_common::AggregationKey;
+use deadpool_postgres::{Manager, Object, Pool, PoolError, Runtime};
+use ipnet::IpNet;
+use tokio::sync::mpsc::Receiver;
+use tokio::time::{interval, Duration, Instant};
+use tokio_postgres::binary_copy::BinaryCopyInWriter;
+use tokio_postgres::types::{ToSql, Type};
+use tokio_postgres::{NoTls, Row};
+
+use crate::alerts::{Alert, StoredAlert};
+use crate::config::{PostgresConfig, StorageConfig};
+use crate::geoip::CountryMatch;
+use crate::health::Heartbeat;
+use crate::icmp::IcmpMessage;
+use crate::locality::FlowDirection;
+use crate::state::{dscp_class_name, AggregatedBucket, ConnectionKey, PacketMetadata, PeerTotals};
+use crate::storage::{
+    add_usage, blocking, AlertFilter, ExportedPackets, FutureClamp, HistoryRow, HostUsage,
+    HostUsageRow, PacketFilter, PacketRecord, RowKind, Snapshot, StorageBackend, StorageError,
+    StorageEvent, StorageMetrics, StorageResult, StoredConnectionTotals, StoredTalker,
+    StoredTotals, TopColumn, UnresolvedRows, UsageGranularity, WalCheckpoint,
+};
+
+/// Created at startup, or on the first request after the server answers.
+/// Integers are BIGINT wherever SQLite's would not fit a smaller type.
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS packets (
+        id BIGSERIAL PRIMARY KEY,
+        timestamp BIGINT NOT NULL,
+        src_ip TEXT NOT NULL,
+        dst_ip TEXT NOT NULL,
+        src_port INTEGER NOT NULL,
+        dst_port INTEGER NOT NULL,
+        protocol TEXT NOT NULL,
+        length BIGINT NOT NULL,
+        direction TEXT NOT NULL,
+        src_hostname TEXT,
+        dst_hostname TEXT,
+        domain TEXT,
+        ttl SMALLINT,
+        dscp SMALLINT,
+        aggregation TEXT,
+        interface TEXT NOT NULL,
+        window_start BIGINT,
+        window_end BIGINT,
+        payload_length BIGINT NOT NULL,
+        packet_count BIGINT NOT NULL DEFAULT 1,
+        src_mac TEXT,
+        dst_mac TEXT,
+        flow_direction TEXT,
+        instance TEXT,
+        icmp_type SMALLINT,
+        icmp_code SMALLINT,
+        country TEXT,
+        asn BIGINT
+    );
+    CREATE INDEX IF NOT EXISTS idx_timestamp ON packets (timestamp);
+    CREATE INDEX IF NOT EXISTS idx_tuple ON packets (src_ip, dst_ip, src_port, dst_port);
+    CREATE INDEX IF NOT EXISTS idx_country ON packets (country, timestamp);
+    CREATE INDEX IF NOT EXISTS idx_asn ON packets (asn, timestamp);
+
+    CREATE TABLE IF NOT EXISTS alerts (
+        id BIGSERIAL PRIMARY KEY,
+        rule TEXT NOT NULL,
+        severity TEXT NOT NULL,
+        subject TEXT NOT NULL,
+        message TEXT NOT NULL,
+        first_seen BIGINT NOT NULL,
+        last_seen BIGINT NOT NULL,
+        count BIGINT NOT NULL DEFAULT 1,
+        acked BOOLEAN NOT NULL DEFAULT FALSE,
+        acked_by TEXT,
+        acked_at BIGINT,
+        instance TEXT
+    );
+    CREATE INDEX IF NOT EXISTS idx_alerts_subject ON alerts (rule, subject);
+    CREATE INDEX IF NOT EXISTS idx_alerts_last_seen ON alerts (last_seen);
+
+    CREATE TABLE IF NOT EXISTS state (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL,
+        updated_at BIGINT NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS hostnames (
+        ip TEXT PRIMARY KEY,
+        hostname TEXT NOT NULL,
+        resolved_at BIGINT NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS capture_meta (
+        instance TEXT NOT NULL,
+        since BIGINT NOT NULL,
+        sample_rate BIGINT NOT NULL,
+        PRIMARY KEY (instance, since)
+    );
+
+    CREATE TABLE IF NOT EXISTS host_usage (
+        local_ip TEXT NOT NULL,
+        hour BIGINT NOT NULL,
+        direction TEXT NOT NULL,
+        bytes BIGINT NOT NULL,
+        packets BIGINT NOT NULL,
+        instance TEXT NOT NULL DEFAULT '',
+        PRIMARY KEY (local_ip, hour, direction, instance)
+    );
+
+    CREATE TABLE IF NOT EXISTS peers (
+        remote_ip TEXT NOT NULL,
+        day BIGINT NOT NULL,
+        first_seen BIGINT NOT NULL,
+        last_seen BIGINT NOT NULL,
+        bytes BIGINT NOT NULL,
+        packets BIGINT NOT NULL,
+        connections BIGINT NOT NULL,
+        instance TEXT NOT NULL DEFAULT '',
+        PRIMARY KEY (remote_ip, day, instance)
+    );";
+
+/// The columns of `PacketRecord`, in order, as COPY writes them.
+const COPY_PACKETS: &str = "COPY packets (timestamp, src_ip, dst_ip, src_port, dst_port,
+        protocol, length, direction, src_hostname, dst_hostname, domain, ttl, dscp,
+        aggregation, interface, window_start, window_end, payload_length, packet_count,
+        src_mac, dst_mac, flow_direction, instance, icmp_type, icmp_code, country, asn)
+    FROM STDIN (FORMAT binary)";
+
+/// The types of the `COPY_PACKETS` columns.
+const PACKET_TYPES: [Type; 27] = [
+    Type::INT8,
+    Type::TEXT,
+    Type::TEXT,
+    Type::INT4,
+    Type::INT4,
+    Type::TEXT,
+    Type::INT8,
+    Type::TEXT,
+    Type::TEXT,
+    Type::TEXT,
+    Type::TEXT,
+    Type::INT2,
+    Type::INT2,
+    Type::TEXT,
+    Type::TEXT,
+    Type::INT8,
+    Type::INT8,
+    Type::INT8,
+    Type::INT8,
+    Type::TEXT,
+    Type::TEXT,
+    Type::TEXT,
+    Type::TEXT,
+    Type::INT2,
+    Type::INT2,
+    Type::TEXT,
+    Type::INT8,
+];
+
+/// The columns `packet_record` reads, in order, with resolved hostnames
+/// taking the place of stored ones, then the row id.  Callers append the
+/// WHERE clause.
+const HISTORY_SELECT: &str = "SELECT p.timestamp, p.src_ip, p.dst_ip, p.src_port, p.dst_port,
+            p.protocol, p.length, p.direction,
+            COALESCE(hs.hostname, p.src_hostname), COALESCE(hd.hostname, p.dst_hostname),
+            p.domain, p.ttl, p.dscp, p.aggregation, p.interface, p.window_start, p.window_end,
+            p.payload_length, p.packet_count, p.src_mac, p.dst_mac, p.flow_direction,
+            p.instance, p.icmp_type, p.icmp_code, p.country, p.asn, p.id
+     FROM packets p
+     LEFT JOIN hostnames hs ON hs.ip = p.src_ip
+     LEFT JOIN hostnames hd ON hd.ip = p.dst_ip";
+
+/// The columns of `StoredAlert`, in order.
+const ALERT_SELECT: &str = "SELECT id, rule, severity, subject, message, first_seen, last_seen,
+            count, acked, acked_by, acked_at, instance
+     FROM alerts";
+
+/// SQLSTATE classes of a server that cannot serve right now: connection
+/// exceptions, insufficient resources (such as too many connections) and
+/// operator intervention (shutting down, starting up).
+const UNAVAILABLE_STATES: [&str; 3] = ["08", "53", "57P"];
+
+/// Why a request failed.
+#[derive(Debug)]
+pub enum PostgresError {
+    /// The server could not be reached, or answered that it cannot serve
+    /// right now.  Worth retrying.
+    Unreachable(String),
+    /// The server refused the statement, or its answer could not be read.
+    Rejected(String),
+}
+
+impl PostgresError {
+    pub fn is_unreachable(&self) -> bool {
+        matches!(self, PostgresError::Unreachable(_))
+    }
+}
+
+impl fmt::Display for PostgresError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PostgresError::Unreachable(e) => write!(f, "PostgreSQL unreachable: {}", e),
+            PostgresError::Rejected(e) => write!(f, "PostgreSQL error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for PostgresError {}
+
+impl From<tokio_postgres::Error> for PostgresError {
+    fn from(e: tokio_postgres::Error) -> Self {
+        let unreachable = match e.code() {
+            Some(state) => UNAVAILABLE_STATES.iter().any(|class| state.code().starts_with(class)),
+            None => e.is_closed() || e.source().is_some_and(|source| source.is::<std::io::Error>()),
+        };
+        let message = match e.as_db_error() {
+            Some(db) => db.to_string(),
+            None => e.to_string(),
+        };
+        if unreachable {
+            PostgresError::Unreachable(message)
+        } else {
+            PostgresError::Rejected(message)
+        }
+    }
+}
+
+impl From<PostgresError> for StorageError {
+    fn from(e: PostgresError) -> Self {
+        StorageError::Postgres(e)
+    }
+}
+
+/// A connection the pool could not hand out.  Only a failed connect says
+/// more than that the server is not answering.
+fn pool_error(e: PoolError) -> PostgresError {
+    match e {
+        PoolError::Backend(e) => e.into(),
+        e => PostgresError::Unreachable(e.to_string()),
+    }
+}
+
+type Result<T> = std::result::Result<T, PostgresError>;
+
+/// Values bound to a statement's `$n` placeholders, so no value is ever
+/// spliced into the SQL itself.
+#[derive(Default)]
+struct Params(Vec<Box<dyn ToSql + Sync + Send>>);
+
+impl Params {
+    /// Bind `value`, returning its placeholder.
+    fn bind(&mut self, value: impl ToSql + Sync + Send + 'static) -> String {
+        self.0.push(Box::new(value));
+        format!("${}", self.0.len())
+    }
+
+    fn refs(&self) -> Vec<&(dyn ToSql + Sync)> {
+        self.0.iter().map(|value| &**value as &(dyn ToSql + Sync)).collect()
+    }
+}
+
+/// The `WHERE` condition for `filter` on columns of `prefix`, binding its
+/// values to `params`.
+fn filter_sql(filter: &PacketFilter, prefix: &str, params: &mut Params) -> String {
+    let p = prefix;
+    let mut conditions = Vec::new();
+    if let Some(from) = filter.from {
+        conditions.push(format!("{p}timestamp >= {}", params.bind(from)));
+    }
+    if let Some(to) = filter.to {
+        conditions.push(format!("{p}timestamp <= {}", params.bind(to)));
+    }
+    if let Some(ip) = &filter.ip {
+        let ip = params.bind(ip.clone());
+        conditions.push(format!("({p}src_ip = {ip} OR {p}dst_ip = {ip})"));
+    }
+    if let Some(mac) = &filter.mac {
+        let mac = params.bind(mac.clone());
+        conditions.push(format!("({p}src_mac = {mac} OR {p}dst_mac = {mac})"));
+    }
+    let equal = [
+        ("interface", filter.interface.as_deref()),
+        ("flow_direction", filter.direction()),
+        ("instance", filter.instance.as_deref()),
+    ];
+    for (column, value) in equal {
+        if let Some(value) = value {
+            conditions.push(format!("{p}{column} = {}", params.bind(value.to_string())));
+        }
+    }
+    if let Some(protocol) = &filter.protocol {
+        let protocol = params.bind(protocol.to_ascii_lowercase());
+        conditions.push(format!("lower({p}protocol) = {protocol}"));
+    }
+    if let Some(icmp_type) = filter.icmp_type {
+        conditions.push(format!("{p}icmp_type = {}", params.bind(i16::from(icmp_type))));
+    }
+    if let Some(asn) = filter.asn {
+        conditions.push(format!("{p}asn = {}", params.bind(i64::from(asn))));
+    }
+    match &filter.country {
+        Some(CountryMatch::Code(code)) => {
+            conditions.push(format!("{p}country = {}", params.bind(code.clone())));
+        }
+        Some(CountryMatch::Unknown) => conditions.push(format!("{p}country IS NULL")),
+        None => {}
+    }
+    if let Some(ports) = &filter.category {
+        conditions.push(ports.sql(p));
+    }
+    if conditions.is_empty() {
+        "TRUE".to_string()
+    } else {
+        conditions.join(" AND ")
+    }
+}
+
+fn now_ms() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+/// A `packets` row for a captured packet.
+fn raw_record(packet: &PacketMetadata, instance: Option<&str>) -> PacketRecord {
+    PacketRecord {
+        timestamp: packet.timestamp,
+        src_ip: packet.src_ip.clone(),
+        dst_ip: packet.dst_ip.clone(),
+        src_port: packet.src_port,
+        dst_port: packet.dst_port,
+        protocol: packet.protocol.clone(),
+        length: packet.length as u64,
+        direction: packet.direction.clone(),
+        src_hostname: packet.src_hostname.clone(),
+        dst_hostname: packet.dst_hostname.clone(),
+        domain: packet.domain.clone(),
+        ttl: packet.ttl,
+        dscp: packet.dscp,
+        aggregation: None,
+        interface: packet.interface.clone(),
+        window_start: None,
+        window_end: None,
+        payload_length: packet.payload_length as u64,
+        packet_count: 1,
+        src_mac: packet.src_mac.clone(),
+        dst_mac: packet.dst_mac.clone(),
+        flow_direction: packet.flow_direction.map(|d| d.as_str().to_string()),
+        instance: instance.map(str::to_string),
+        icmp_type: packet.icmp_type,
+        icmp_code: packet.icmp_code,
+        country: packet.country.clone(),
+        asn: packet.asn,
+    }
+}
+
+/// A `packets` row for a kernel-swept bucket, which is always per
+/// connection.
+fn bucket_record(bucket: &AggregatedBucket, instance: Option<&str>) -> PacketRecord {
+    let icmp = IcmpMessage::from_flow(&bucket.protocol, bucket.dst_port);
+    PacketRecord {
+        timestamp: bucket.first_timestamp,
+        src_ip: bucket.src_ip.clone(),
+        dst_ip: bucket.dst_ip.clone(),
+        src_port: bucket.src_port,
+        dst_port: bucket.dst_port,
+        protocol: bucket.protocol.clone(),
+        length: bucket.total_bytes,
+        direction: bucket.direction.clone(),
+        src_hostname: bucket.src_hostname.clone(),
+        dst_hostname: bucket.dst_hostname.clone(),
+        domain: bucket.domain.clone(),
+        ttl: None,
+        dscp: None,
+        aggregation: Some(AggregationKey::Connection.as_str().to_string()),
+        interface: bucket.interface.clone(),
+        window_start: Some(bucket.window_start),
+        window_end: Some(bucket.window_end),
+        payload_length: bucket.payload_bytes,
+        packet_count: bucket.packet_count,
+        src_mac: None,
+        dst_mac: None,
+        flow_direction: bucket.flow_direction.map(|d| d.as_str().to_string()),
+        instance: instance.map(str::to_string),
+        icmp_type: icmp.map(|m| m.kind),
+        icmp_code: icmp.map(|m| m.code),
+        country: bucket.country.clone(),
+        asn: bucket.asn,
+    }
+}
+
+/// Bring stamps too far in the future back to the write time, the window
+/// along with the timestamp.
+fn clamp_record(record: &mut PacketRecord, clamp: &mut FutureClamp) {
+    match (record.window_start, record.window_end) {
+        (Some(start), Some(end)) => {
+            let [timestamp, start, end] = clamp.row([record.timestamp, start, end]);
+            (record.timestamp, record.window_start, record.window_end) =
+                (timestamp, Some(start), Some(end));
+        }
+        _ => [record.timestamp] = clamp.row([record.timestamp]),
+    }
+}
+
+fn history_row(record: PacketRecord) -> HistoryRow {
+    let icmp = record
+        .icmp_type
+        .zip(record.icmp_code)
+        .map(|(kind, code)| u16::from_be_bytes([kind, code]))
+        .and_then(|port| IcmpMessage::from_flow(&record.protocol, port));
+    let packet = PacketMetadata {
+        timestamp: record.timestamp,
+        src_ip: record.src_ip,
+        dst_ip: record.dst_ip,
+        src_port: record.src_port,
+        dst_port: record.dst_port,
+        protocol: record.protocol,
+        length: record.length as usize,
+        payload_length: record.payload_length as usize,
+        direction: record.direction,
+        flow_direction: record.flow_direction.and_then(|d| d.parse::<FlowDirection>().ok()),
+        cast: None,
+        interface: record.interface,
+        src_mac: record.src_mac,
+        dst_mac: record.dst_mac,
+        src_hostname: record.src_hostname,
+        dst_hostname: record.dst_hostname,
+        domain: record.domain,
+        country: record.country,
+        asn: record.asn,
+        service: None,
+        ttl: record.ttl,
+        dscp: record.dscp,
+        dscp_class: record.dscp.map(dscp_class_name),
+        icmp_type: record.icmp_type,
+        icmp_code: record.icmp_code,
+        icmp_name: icmp.map(|m| m.name()),
+    };
+    HistoryRow {
+        packet,
+        kind: if record.aggregation.is_some() { RowKind::Aggregated } else { RowKind::Raw },
+        packet_count: record.packet_count,
+        instance: record.instance,
+        service_port: None,
+    }
+}
+
+/// A `HISTORY_SELECT` row and its id.
+fn packet_record(row: &Row) -> std::result::Result<(i64, PacketRecord), tokio_postgres::Error> {
+    let port = |i: usize| row.try_get::<_, i32>(i).map(|port| port as u16);
+    let count = |i: usize| row.try_get::<_, i64>(i).map(|count| count as u64);
+    let byte = |i: usize| row.try_get::<_, Option<i16>>(i).map(|v| v.map(|v| v as u8));
+    let record = PacketRecord {
+        timestamp: row.try_get(0)?,
+        src_ip: row.try_get(1)?,
+        dst_ip: row.try_get(2)?,
+        src_port: port(3)?,
+        dst_port: port(4)?,
+        protocol: row.try_get(5)?,
+        length: count(6)?,
+        direction: row.try_get(7)?,
+        src_hostname: row.try_get(8)?,
+        dst_hostname: row.try_get(9)?,
+        domain: row.try_get(10)?,
+        ttl: byte(11)?,
+        dscp: byte(12)?,
+        aggregation: row.try_get(13)?,
+        interface: row.try_get(14)?,
+        window_start: row.try_get(15)?,
+        window_end: row.try_get(16)?,
+        payload_length: count(17)?,
+        packet_count: count(18)?,
+        src_mac: row.try_get(19)?,
+        dst_mac: row.try_get(20)?,
+        flow_direction: row.try_get(21)?,
+        instance: row.try_get(22)?,
+        icmp_type: byte(23)?,
+        icmp_code: byte(24)?,
+        country: row.try_get(25)?,
+        asn: row.try_get::<_, Option<i64>>(26)?.map(|asn| asn as u32),
+    };
+    Ok((row.try_get(27)?, record))
+}
+
+fn stored_alert(row: &Row) -> std::result::Result<StoredAlert, tokio_postgres::Error> {
+    Ok(StoredAlert {
+        id: row.try_get(0)?,
+        rule: row.try_get(1)?,
+        severity: row.try_get(2)?,
+        subject: row.try_get(3)?,
+        message: row.try_get(4)?,
+        first_seen: row.try_get(5)?,
+        last_seen: row.try_get(6)?,
+        count: row.try_get::<_, i64>(7)? as u64,
+        acked: row.try_get(8)?,
+        acked_by: row.try_get(9)?,
+        acked_at: row.try_get(10)?,
+        instance: row.try_get(11)?,
+    })
+}
+
+/// Rows waiting for the writer's next flush.
+#[derive(Default)]
+struct Pending {
+    packets: VecDeque<PacketRecord>,
+    /// Address, name and when it was resolved.
+    hostnames: VecDeque<(String, String, i64)>,
+    peers: VecDeque<PeerTotals>,
+    /// Usage totals since the last flush per instance ('' for an unnamed
+    /// agent); one upsert per key goes in.
+    usage: BTreeMap<String, HostUsage>,
+    /// Folding an alert reads the table, so alerts wait for the server
+    /// like rows do.
+    alerts: VecDeque<Alert>,
+}
+
+impl Pending {
+    fn rows(&self) -> usize {
+        self.packets.len() + self.rollups() + self.alerts.len()
+    }
+
+    /// Rows the rollup transaction writes.
+    fn rollups(&self) -> usize {
+        let usage: usize = self.usage.values().map(HashMap::len).sum();
+        self.hostnames.len() + self.peers.len() + usage
+    }
+
+    /// Drop the oldest rows, packets first, until at most `max` are left.
+    /// Usage totals and alerts are kept.  Returns the number dropped.
+    fn truncate(&mut self, max: usize) -> usize {
+        let excess = self.rows().saturating_sub(max);
+        let mut left = excess;
+        let dropped = left.min(self.packets.len());
+        self.packets.drain(..dropped);
+        left -= dropped;
+        let dropped = left.min(self.hostnames.len());
+        self.hostnames.drain(..dropped);
+        left -= dropped;
+        let dropped = left.min(self.peers.len());
+        self.peers.drain(..dropped);
+        left -= dropped;
+        excess - left
+    }
+
+    fn clear_rollups(&mut self) {
+        self.hostnames.clear();
+        self.peers.clear();
+        self.usage.clear();
+    }
+}
+
+#[derive(Clone)]
+pub struct Postgres {
+    pool: Pool,
+    /// The runtime requests and pooled connections run on: the agent's, or
+    /// `_runtime` when the backend was opened outside one.
+    handle: tokio::runtime::Handle,
+    _runtime: Option<Arc<tokio::runtime::Runtime>>,
+    config: PostgresConfig,
+    /// For `max_clock_skew_seconds`.
+    flush: StorageConfig,
+    /// Networks whose hosts get per-hour usage rollups in `host_usage`.
+    local_networks: Vec<IpNet>,
+    /// Written with every stored row; None stores NULL.
+    instance: Option<String>,
+    /// Set once the tables exist; until then every request creates them
+    /// first.
+    schema_ready: Arc<AtomicBool>,
+    /// Whether the last request got an answer.
+    reachable: Arc<AtomicBool>,
+    metrics: Arc<StorageMetrics>,
+}
+
+impl Postgres {
+    /// Set up the pool for `url` and create the tables.  An unreachable
+    /// server is not an error, so the agent starts and buffers until it
+    /// answers; a refusal, such as bad credentials or a missing database,
+    /// is.
+    pub fn open(url: &str, config: &PostgresConfig) -> anyhow::Result<Self> {
+        let redacted = crate::config::redact_url_password(url);
+        let mut pg = url
+            .parse::<tokio_postgres::Config>()
+            .map_err(|e| anyhow::anyhow!("invalid db_url {:?}: {}", redacted, e))?;
+        let timeout = Duration::from_millis(config.timeout_ms);
+        if pg.get_connect_timeout().is_none() {
+            pg.connect_timeout(timeout);
+        }
+        let (handle, runtime) = match tokio::runtime::Handle::try_current() {
+            Ok(handle) => (handle, None),
+            Err(_) => {
+                // Pooled connections are tasks, which only a multi-threaded
+                // runtime runs between calls.
+                let runtime = tokio::runtime::Builder::new_multi_thread()
+                    .worker_threads(1)
+                    .enable_all()
+                    .build()?;
+                (runtime.handle().clone(), Some(Arc::new(runtime)))
+            }
+        };
+        let pool = Pool::builder(Manager::new(pg, NoTls))
+            .max_size(config.pool_size)
+            .runtime(Runtime::Tokio1)
+            .wait_timeout(Some(timeout))
+            .create_timeout(Some(timeout))
+            .recycle_timeout(Some(timeout))
+            .build()
+            .map_err(|e| anyhow::anyhow!("cannot set up PostgreSQL pool: {}", e))?;
+        let backend = Self {
+            pool,
+            handle,
+            _runtime: runtime,
+            config: config.clone(),
+            flush: StorageConfig::default(),
+            local_networks: Vec::new(),
+            instance: None,
+            schema_ready: Arc::default(),
+            reachable: Arc::new(AtomicBool::new(true)),
+            metrics: Arc::default(),
+        };
+        match backend.request(async { Ok(()) }) {
+            Ok(()) => {}
+            Err(e @ PostgresError::Unreachable(_)) => {
+                tracing::warn!("{}; buffering writes until it answers", e)
+            }
+            Err(e) => anyhow::bail!("cannot open PostgreSQL database {}: {}", redacted, e),
+        }
+        Ok(backend)
+    }
+
+    /// Maintain `host_usage` rollups for hosts in these networks.
+    pub fn with_local_networks(mut self, networks: Vec<IpNet>) -> Self {
+        self.local_networks = networks;
+        self
+    }
+
+    /// Clamp future stamps as `flush.max_clock_skew_seconds` says.
+    pub fn with_storage_config(mut self, flush: StorageConfig) -> Self {
+        self.flush = flush;
+        self
+    }
+
+    /// Tag every row this handle writes with `instance`.
+    pub fn with_instance(mut self, instance: String) -> Self {
+        self.instance = Some(instance);
+        self
+    }
+
+    fn reachable(&self) -> bool {
+        self.reachable.load(Ordering::Relaxed)
+    }
+
+    /// Run `work` once the tables exist, giving up after `timeout_ms`.  The
+    /// backend's interface is blocking, so this waits for the answer, off
+    /// the runtime worker as `storage::blocking` does.
+    fn request<T>(&self, work: impl Future<Output = Result<T>>) -> Result<T> {
+        let timeout = Duration::from_millis(self.config.timeout_ms);
+        let attempt = async {
+            self.ensure_schema().await?;
+            work.await
+        };
+        let result = blocking(|| {
+            self.handle.block_on(async {
+                tokio::time::timeout(timeout, attempt).await.unwrap_or_else(|_| {
+                    Err(PostgresError::Unreachable(format!("timed out after {:?}", timeout)))
+                })
+            })
+        });
+        let unreachable = matches!(result, Err(PostgresError::Unreachable(_)));
+        self.reachable.store(!unreachable, Ordering::Relaxed);
+        result
+    }
+
+    async fn client(&self) -> Result<Object> {
+        self.pool.get().await.map_err(pool_error)
+    }
+
+    async fn ensure_schema(&self) -> Result<()> {
+        if self.schema_ready.load(Ordering::Acquire) {
+            return Ok(());
+        }
+        self.client().await?.batch_execute(SCHEMA).await?;
+        self.schema_ready.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    fn select<T>(
+        &self,
+        sql: &str,
+        params: &Params,
+        read: impl Fn(&Row) -> std::result::Result<T, tokio_postgres::Error>,
+    ) -> Result<Vec<T>> {
+        self.request(async {
+            let rows = self.client().await?.query(sql, &params.refs()).await?;
+            rows.iter().map(|row| read(row).map_err(PostgresError::from)).collect()
+        })
+    }
+
+    /// Run a statement, returning the rows it affected.
+    fn execute(&self, sql: &str, params: &Params) -> Result<u64> {
+        self.request(async { Ok(self.client().await?.execute(sql, &params.refs()).await?) })
+    }
+
+    /// Add an event to what the next flush writes, dropping the oldest
+    /// rows beyond `buffer_max_rows`.
+    fn buffer(&self, pending: &mut Pending, event: StorageEvent) {
+        let instance = self.instance.as_deref();
+        let mut clamp = FutureClamp::new(&self.flush);
+        match event {
+            StorageEvent::Packets(packets) => {
+                for packet in &packets {
+                    let mut record = raw_record(packet, instance);
+                    clamp_record(&mut record, &mut clamp);
+                    self.push_packet(pending, record);
+                }
+            }
+            StorageEvent::Buckets(buckets) => {
+                for bucket in &buckets {
+                    let mut record = bucket_record(bucket, instance);
+                    clamp_record(&mut record, &mut clamp);
+                    self.push_packet(pending, record);
+                }
+            }
+            StorageEvent::Alert(alert) => pending.alerts.push_back(alert),
+            StorageEvent::Hostnames(names) => {
+                let resolved_at = now_ms();
+                let names = names.into_iter().map(|(ip, hostname)| (ip, hostname, resolved_at));
+                pending.hostnames.extend(names);
+            }
+            StorageEvent::Peers(peers) => pending.peers.extend(peers),
+        }
+        // Counted when buffered: a row dropped later was still clamped.
+        clamp.record(&self.metrics);
+        let dropped = pending.truncate(self.config.buffer_max_rows);
+        if dropped > 0 {
+            self.metrics.spill_dropped_rows.inc_by(dropped as u64);
+        }
+    }
+
+    /// Buffer a `packets` row along with its usage rollup.
+    fn push_packet(&self, pending: &mut Pending, record: PacketRecord) {
+        let instance = record.instance.as_deref().unwrap_or_default();
+        let usage = pending.usage.entry(instance.to_string()).or_default();
+        let (src, dst) = (&record.src_ip, &record.dst_ip);
+        let (bytes, packets) = (record.length, record.packet_count);
+        add_usage(usage, &self.local_networks, src, dst, record.timestamp, bytes, packets);
+        pending.packets.push_back(record);
+    }
+
+    /// Write everything pending: alerts one at a time, the rollups in one
+    /// transaction, then packets in COPY batches of `flush_max_rows`.
+    /// Stops at the first request the server is unreachable for, keeping
+    /// the rest.  Rows the server refuses are dropped and counted, and the
+    /// flush carries on; the last refusal is returned.
+    fn flush(&self, pending: &mut Pending) -> Result<()> {
+        let mut refused = None;
+        while let Some(alert) = pending.alerts.front() {
+            match self.request(self.fold_alert(alert)) {
+                Ok(()) => {}
+                Err(e) if e.is_unreachable() => return Err(e),
+                Err(e) => {
+                    tracing::error!("Failed to insert alert: {}", e);
+                    self.metrics.insert_failures.inc();
+                    refused = Some(e);
+                }
+            }
+            pending.alerts.pop_front();
+        }
+        let rollups = pending.rollups();
+        if rollups > 0 {
+            match self.request(self.upsert_rollups(pending)) {
+                Ok(()) => {}
+                Err(e) if e.is_unreachable() => {
+                    self.metrics.transaction_failures.inc();
+                    return Err(e);
+                }
+                Err(e) => {
+                    tracing::error!("PostgreSQL refused {} rollup rows: {}", rollups, e);
+                    self.metrics.insert_failures.inc_by(rollups as u64);
+                    refused = Some(e);
+                }
+            }
+            pending.clear_rollups();
+        }
+        while !pending.packets.is_empty() {
+            let started = Instant::now();
+            let batch = pending.packets.len().min(self.config.flush_max_rows);
+            let inserted = match self.request(self.copy_packets(pending.packets.range(..batch))) {
+                Ok(_) => batch,
+                Err(e) if e.is_unreachable() => {
+                    self.metrics.transaction_failures.inc();
+                    return Err(e);
+                }
+                Err(e) => {
+                    tracing::error!("PostgreSQL refused {} packets rows: {}", batch, e);
+                    self.metrics.insert_failures.inc_by(batch as u64);
+                    refused = Some(e);
+                    0
+                }
+            };
+            self.metrics.record_flush(batch, inserted as u64, started.elapsed());
+            pending.packets.drain(..batch);
+        }
+        refused.map_or(Ok(()), Err)
+    }
+
+    /// `flush` on the blocking pool, reporting the outcome to `heartbeat`.
+    async fn flush_blocking(&self, mut pending: Pending, heartbeat: &Heartbeat) -> Pending {
+        let this = self.clone();
+        let flushed = tokio::task::spawn_blocking(move || {
+            let result = this.flush(&mut pending);
+            (pending, result)
+        });
+        let (pending, result) = match flushed.await {
+            Ok(flushed) => flushed,
+            Err(e) => std::panic::resume_unwind(e.into_panic()),
+        };
+        match &result {
+            Err(e) if e.is_unreachable() => {
+                heartbeat.fail(format!("{} ({} rows buffered)", e, pending.rows()))
+            }
+            _ => heartbeat.report(&result),
+        }
+        pending
+    }
+
+    /// Drain `rx` into PostgreSQL until the channel closes.  Packets are
+    /// stored raw, as with ClickHouse.
+    async fn run(
+        &self,
+        rx: &mut Receiver<StorageEvent>,
+        aggregation_window_seconds: u64,
+        heartbeat: &Heartbeat,
+    ) {
+        if aggregation_window_seconds > 0 {
+            tracing::warn!("PostgreSQL stores packets raw; aggregation_window_seconds is ignored");
+        }
+        let mut pending = Pending::default();
+        let mut ticker = interval(Duration::from_millis(self.config.flush_interval_ms));
+        loop {
+            let flush = tokio::select! {
+                event = rx.recv() => match event {
+                    Some(event) => {
+                        self.buffer(&mut pending, event);
+                        // While the server is down only the ticker retries.
+                        pending.packets.len() >= self.config.flush_max_rows && self.reachable()
+                    }
+                    None => {
+                        self.flush_blocking(pending, heartbeat).await;
+                        return;
+                    }
+                },
+                _ = ticker.tick() => true,
+            };
+            if flush {
+                pending = self.flush_blocking(pending, heartbeat).await;
+            }
+        }
+    }
+
+    /// Fold `alert` into the newest unacknowledged row of its rule and
+    /// subject, or start a new row if there is none.
+    async fn fold_alert(&self, alert: &Alert) -> Result<()> {
+        let client = self.client().await?;
+        let instance = self.instance.as_deref();
+        let params: [&(dyn ToSql + Sync); 6] = [
+            &alert.timestamp,
+            &alert.rule,
+            &alert.severity,
+            &alert.subject,
+            &alert.message,
+            &instance,
+        ];
+        let folded = client
+            .execute(
+                "UPDATE alerts SET last_seen = GREATEST(last_seen, $1), count = count + 1,
+                     severity = $3, message = $5
+                 WHERE id = (SELECT max(id) FROM alerts
+                             WHERE rule = $2 AND subject = $4
+                               AND instance IS NOT DISTINCT FROM $6 AND NOT acked)",
+                &params,
+            )
+            .await?;
+        if folded == 0 {
+            client
+                .execute(
+                    "INSERT INTO alerts
+                         (first_seen, last_seen, rule, severity, subject, message, instance)
+                     VALUES ($1, $1, $2, $3, $4, $5, $6)",
+                    &params,
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Fold the pending usage totals, peers and hostnames into their rows,
+    /// all or nothing.
+    async fn upsert_rollups(&self, pending: &Pending) -> Result<()> {
+        let mut object = self.client().await?;
+        let client: &mut tokio_postgres::Client = &mut object;
+        let tx = client.transaction().await?;
+        let usage = tx
+            .prepare(
+                "INSERT INTO host_usage (local_ip, hour, direction, bytes, packets, instance)
+                 VALUES ($1, $2, $3, $4, $5, $6)
+                 ON CONFLICT (local_ip, hour, direction, instance) DO UPDATE SET
+                     bytes = host_usage.bytes + excluded.bytes,
+                     packets = host_usage.packets + excluded.packets",
+            )
+            .await?;
+        for (instance, totals) in &pending.usage {
+            for ((ip, hour, direction), (bytes, packets)) in totals {
+                let (bytes, packets) = (*bytes as i64, *packets as i64);
+                tx.execute(&usage, &[ip, hour, direction, &bytes, &packets, instance]).await?;
+            }
+        }
+        let peers = tx
+            .prepare(
+                "INSERT INTO peers
+                     (remote_ip, day, first_seen, last_seen, bytes, packets, connections, instance)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+                 ON CONFLICT (remote_ip, day, instance) DO UPDATE SET
+                     first_seen = LEAST(peers.first_seen, excluded.first_seen),
+                     last_seen = GREATEST(peers.last_seen, excluded.last_seen),
+                     bytes = peers.bytes + excluded.bytes,
+                     packets = peers.packets + excluded.packets,
+                     connections = peers.connections + excluded.connections",
+            )
+            .await?;
+        let instance = self.instance.as_deref().unwrap_or_default();
+        for peer in &pending.peers {
+            let row: [&(dyn ToSql + Sync); 8] = [
+                &peer.ip,
+                &peer.day,
+                &peer.first_seen,
+                &peer.last_seen,
+                &(peer.bytes as i64),
+                &(peer.packets as i64),
+                &(peer.connections as i64),
+                &instance,
+            ];
+            tx.execute(&peers, &row).await?;
+        }
+        let hostnames = tx
+            .prepare(
+                "INSERT INTO hostnames (ip, hostname, resolved_at) VALUES ($1, $2, $3)
+                 ON CONFLICT (ip) DO UPDATE SET hostname = excluded.hostname,
+                     resolved_at = excluded.resolved_at",
+            )
+            .await?;
+        for (ip, hostname, resolved_at) in &pending.hostnames {
+            tx.execute(&hostnames, &[ip, hostname, resolved_at]).await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Write `records` with one binary COPY.
+    async fn copy_packets(&self, records: impl Iterator<Item = &PacketRecord>) -> Result<u64> {
+        let client = self.client().await?;
+        let sink = client.copy_in(COPY_PACKETS).await?;
+        let mut writer = std::pin::pin!(BinaryCopyInWriter::new(sink, &PACKET_TYPES));
+        for record in records {
+            let row: [&(dyn ToSql + Sync); 27] = [
+                &record.timestamp,
+                &record.src_ip,
+                &record.dst_ip,
+                &i32::from(record.src_port),
+                &i32::from(record.dst_port),
+                &record.protocol,
+                &(record.length as i64),
+                &record.direction,
+                &record.src_hostname,
+                &record.dst_hostname,
+                &record.domain,
+                &record.ttl.map(i16::from),
+                &record.dscp.map(i16::from),
+                &record.aggregation,
+                &record.interface,
+                &record.window_start,
+                &record.window_end,
+                &(record.payload_length as i64),
+                &(record.packet_count as i64),
+                &record.src_mac,
+                &record.dst_mac,
+                &record.flow_direction,
+                &record.instance,
+                &record.icmp_type.map(i16::from),
+                &record.icmp_code.map(i16::from),
+                &record.country,
+                &record.asn.map(i64::from),
+            ];
+            writer.as_mut().write(&row).await?;
+        }
+        Ok(writer.finish().await?)
+    }
+
+    /// `HISTORY_SELECT` rows matching `condition`, which is appended as it
+    /// is.
+    fn select_packets(&self, condition: &str, params: &Params) -> Result<Vec<(i64, PacketRecord)>> {
+        let sql = format!("{HISTORY_SELECT} {condition}");
+        self.select(&sql, params, packet_record)
+    }
+}
+
+impl StorageBackend for Postgres {
+    fn run_writer<'a>(
+        &'a self,
+        rx: &'a mut Receiver<StorageEvent>,
+        aggregation_window_seconds: u64,
+        heartbeat: &'a Heartbeat,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>> {
+        Box::pin(self.run(rx, aggregation_window_seconds, heartbeat))
+    }
+
+    /// The pool replaces broken connections itself, so there is nothing to
+    /// reopen.
+    fn reopen(&self) -> StorageResult<()> {
+        Ok(())
+    }
+
+    /// Written at once rather than through the writer's buffer, so the
+    /// sender learns whether the batch was taken.
+    fn insert_ingested(&self, instance: &str, buckets: &[AggregatedBucket]) -> StorageResult<()> {
+        let mut pending = Pending::default();
+        let mut clamp = FutureClamp::new(&self.flush);
+        for bucket in buckets {
+            let mut record = bucket_record(bucket, Some(instance));
+            clamp_record(&mut record, &mut clamp);
+            self.push_packet(&mut pending, record);
+        }
+        self.flush(&mut pending)?;
+        clamp.record(&self.metrics);
+        Ok(())
+    }
+
+    fn query_packets(
+        &self,
+        filter: &PacketFilter,
+        limit: usize,
+    ) -> StorageResult<Vec<HistoryRow>> {
+        let mut params = Params::default();
+        let condition = format!(
+            "WHERE {}
+             ORDER BY p.timestamp DESC, p.id DESC LIMIT {limit}",
+            filter_sql(filter, "p.", &mut params)
+        );
+        let rows = self.select_packets(&condition, &params)?;
+        Ok(rows.into_iter().map(|(_, record)| history_row(record)).collect())
+    }
+
+    fn query_usage(
+        &self,
+        ip: Option<&str>,
+        from: i64,
+        to: i64,
+        granularity: UsageGranularity,
+    ) -> StorageResult<Vec<HostUsageRow>> {
+        let mut params = Params::default();
+        let (from, to) = (params.bind(from), params.bind(to));
+        let ip = ip.map(|ip| format!(" AND local_ip = {}", params.bind(ip.to_string())));
+        let sql = format!(
+            "SELECT local_ip, hour - (hour % {}) AS bucket, direction,
+                    SUM(bytes)::BIGINT, SUM(packets)::BIGINT
+             FROM host_usage
+             WHERE hour >= {from} AND hour <= {to}{}
+             GROUP BY local_ip, bucket, direction
+             ORDER BY bucket, local_ip, direction",
+            granularity.millis(),
+            ip.unwrap_or_default()
+        );
+        Ok(self.select(&sql, &params, |row| {
+            Ok(HostUsageRow {
+                ip: row.try_get(0)?,
+                bucket: row.try_get(1)?,
+                direction: row.try_get(2)?,
+                bytes: row.try_get::<_, i64>(3)? as u64,
+                packets: row.try_get::<_, i64>(4)? as u64,
+            })
+        })?)
+    }
+
+    fn query_top(
+        &self,
+        by: TopColumn,
+        filter: &PacketFilter,
+        limit: usize,
+    ) -> StorageResult<Vec<StoredTalker>> {
+        // The column name comes from a fixed enum, never from user input.
+        let mut params = Params::default();
+        let sql = format!(
+            "SELECT {col}::TEXT, SUM(length)::BIGINT, SUM(packet_count)::BIGINT, COUNT(*)
+             FROM packets
+             WHERE {filter} AND {col} IS NOT NULL
+             GROUP BY 1
+             ORDER BY 2 DESC LIMIT {limit}",
+            col = by.column(),
+            filter = filter_sql(filter, "", &mut params)
+        );
+        Ok(self.select(&sql, &params, |row| {
+            Ok(StoredTalker {
+                key: row.try_get(0)?,
+                bytes: row.try_get::<_, i64>(1)? as u64,
+                packets: row.try_get::<_, i64>(2)? as u64,
+                rows: row.try_get::<_, i64>(3)? as u64,
+            })
+        })?)
+    }
+
+    fn query_totals(&self, filter: &PacketFilter) -> StorageResult<StoredTotals> {
+        let mut params = Params::default();
+        let sql = format!(
+            "SELECT COUNT(*), COALESCE(SUM(packet_count), 0)::BIGINT,
+                    COALESCE(SUM(length), 0)::BIGINT
+             FROM packets WHERE {}",
+            filter_sql(filter, "", &mut params)
+        );
+        let rows = self.select(&sql, &params, |row| {
+            Ok(StoredTotals {
+                rows: row.try_get::<_, i64>(0)? as u64,
+                packets: row.try_get::<_, i64>(1)? as u64,
+                bytes: row.try_get::<_, i64>(2)? as u64,
+            })
+        })?;
+        Ok(rows.into_iter().next().unwrap_or_default())
+    }
+
+    fn query_peers(
+        &self,
+        ip: Option<&str>,
+        from: i64,
+        to: i64,
+    ) -> StorageResult<Vec<PeerTotals>> {
+        let mut params = Params::default();
+        let ip = ip.map(|ip| format!("WHERE remote_ip = {}", params.bind(ip.to_string())));
+        let (from, to) = (params.bind(from), params.bind(to));
+        let sql = format!(
+            "SELECT remote_ip, day, MIN(first_seen), MAX(last_seen), SUM(bytes)::BIGINT,
+                    SUM(packets)::BIGINT, SUM(connections)::BIGINT
+             FROM peers {}
+             GROUP BY remote_ip, day
+             HAVING MAX(last_seen) >= {from} AND MIN(first_seen) <= {to}
+             ORDER BY day, SUM(bytes) DESC",
+            ip.unwrap_or_default()
+        );
+        Ok(self.select(&sql, &params, |row| {
+            Ok(PeerTotals {
+                ip: row.try_get(0)?,
+                day: row.try_get(1)?,
+                first_seen: row.try_get(2)?,
+                last_seen: row.try_get(3)?,
+                bytes: row.try_get::<_, i64>(4)? as u64,
+                packets: row.try_get::<_, i64>(5)? as u64,
+                connections: row.try_get::<_, i64>(6)? as u64,
+            })
+        })?)
+    }
+
+    fn query_connection(
+        &self,
+        key: &ConnectionKey,
+        limit: usize,
+    ) -> StorageResult<(Vec<HistoryRow>, StoredConnectionTotals)> {
+        let mut params = Params::default();
+        let src_ip = params.bind(key.src_ip.to_string());
+        let dst_ip = params.bind(key.dst_ip.to_string());
+        let (src_port, dst_port) = (key.src_port, key.dst_port);
+        let tuple = format!(
+            "((p.src_ip = {src_ip} AND p.dst_ip = {dst_ip}
+               AND p.src_port = {src_port} AND p.dst_port = {dst_port})
+              OR (p.src_ip = {dst_ip} AND p.dst_ip = {src_ip}
+               AND p.src_port = {dst_port} AND p.dst_port = {src_port}))"
+        );
+        let condition = format!(
+            "WHERE {tuple}
+             ORDER BY p.timestamp DESC, p.id DESC LIMIT {limit}"
+        );
+        let rows = self.select_packets(&condition, &params)?;
+        let sql = format!(
+            "SELECT COUNT(*), COALESCE(SUM(p.packet_count), 0)::BIGINT,
+                    COALESCE(SUM(p.length), 0)::BIGINT, MIN(p.timestamp), MAX(p.timestamp)
+             FROM packets p WHERE {tuple}"
+        );
+        let totals = self.select(&sql, &params, |row| {
+            Ok(StoredConnectionTotals {
+                rows: row.try_get::<_, i64>(0)? as u64,
+                packets: row.try_get::<_, i64>(1)? as u64,
+                bytes: row.try_get::<_, i64>(2)? as u64,
+                first_seen: row.try_get(3)?,
+                last_seen: row.try_get(4)?,
+            })
+        })?;
+        let rows = rows.into_iter().map(|(_, record)| history_row(record)).collect();
+        Ok((rows, totals.into_iter().next().unwrap_or_default()))
+    }
+
+    fn query_alerts(
+        &self,
+        filter: &AlertFilter,
+        limit: usize,
+    ) -> StorageResult<Vec<StoredAlert>> {
+        let mut params = Params::default();
+        let mut conditions = vec!["TRUE".to_string()];
+        if let Some(before_id) = filter.before_id {
+            conditions.push(format!("id < {}", params.bind(before_id)));
+        }
+        if let Some(severity) = &filter.severity {
+            conditions.push(format!("severity = {}", params.bind(severity.clone())));
+        }
+        if let Some(rule) = &filter.rule {
+            conditions.push(format!("rule = {}", params.bind(rule.clone())));
+        }
+        if let Some(since) = filter.since {
+            conditions.push(format!("last_seen >= {}", params.bind(since)));
+        }
+        if let Some(acked) = filter.acked {
+            conditions.push(format!("acked = {}", params.bind(acked)));
+        }
+        if let Some(instance) = &filter.instance {
+            conditions.push(format!("instance = {}", params.bind(instance.clone())));
+        }
+        let sql = format!(
+            "{ALERT_SELECT}
+             WHERE {}
+             ORDER BY id DESC LIMIT {limit}",
+            conditions.join(" AND ")
+        );
+        Ok(self.select(&sql, &params, stored_alert)?)
+    }
+
+    fn ack_alert(&self, id: i64, by: &str) -> StorageResult<Option<StoredAlert>> {
+        let mut params = Params::default();
+        let sql = format!(
+            "UPDATE alerts SET acked = TRUE, acked_by = {}, acked_at = {}
+             WHERE id = {} AND NOT acked",
+            params.bind(by.to_string()),
+            params.bind(now_ms()),
+            params.bind(id)
+        );
+        self.execute(&sql, &params)?;
+        let mut params = Params::default();
+        let sql = format!("{ALERT_SELECT} WHERE id = {}", params.bind(id));
+        Ok(self.select(&sql, &params, stored_alert)?.into_iter().next())
+    }
+
+    fn delete_old_alerts(&self, older_than_seconds: u64, max_rows: usize) -> StorageResult<usize> {
+        let mut deleted = 0;
+        if older_than_seconds > 0 {
+            let mut params = Params::default();
+            let cutoff_ms = now_ms() - (older_than_seconds as i64 * 1000);
+            let sql = format!("DELETE FROM alerts WHERE last_seen < {}", params.bind(cutoff_ms));
+            deleted += self.execute(&sql, &params)?;
+        }
+        if max_rows > 0 {
+            let sql = format!(
+                "DELETE FROM alerts WHERE id IN
+                     (SELECT id FROM alerts ORDER BY last_seen DESC, id DESC OFFSET {max_rows})"
+            );
+            deleted += self.execute(&sql, &Params::default())?;
+        }
+        Ok(deleted as usize)
+    }
+
+    fn delete_old_data(&self, older_than_seconds: u64) -> StorageResult<usize> {
+        let now = now_ms();
+        let cutoff_ms = now - (older_than_seconds as i64 * 1000);
+        // Rows from an instance with a wrong clock would never age out.
+        let future_ms = now.saturating_add(self.flush.max_clock_skew_ms());
+        let mut params = Params::default();
+        let sql = format!(
+            "DELETE FROM packets WHERE timestamp < {} OR timestamp > {}",
+            params.bind(cutoff_ms),
+            params.bind(future_ms)
+        );
+        let deleted = self.execute(&sql, &params)?;
+        // As with SQLite, a name this old only labels rows deleted above.
+        let mut params = Params::default();
+        let sql = format!("DELETE FROM hostnames WHERE resolved_at < {}", params.bind(cutoff_ms));
+        self.execute(&sql, &params)?;
+        self.metrics.retention_deleted.inc_by(deleted);
+        Ok(deleted as usize)
+    }
+
+    fn clear_packets(&self) -> StorageResult<usize> {
+        Ok(self.execute("DELETE FROM packets", &Params::default())? as usize)
+    }
+
+    /// Queries join the `hostnames` table, so no stored row ever needs
+    /// filling in.
+    fn unresolved_rows(
+        &self,
+        after_id: i64,
+        _since: Option<i64>,
+        _limit: usize,
+    ) -> StorageResult<UnresolvedRows> {
+        Ok(UnresolvedRows { through_id: after_id, last_id: after_id, ..UnresolvedRows::default() })
+    }
+
+    fn fill_hostnames(
+        &self,
+        _through_id: i64,
+        _names: &[(String, String)],
+    ) -> StorageResult<usize> {
+        Ok(0)
+    }
+
+    /// Positions are row ids, as with SQLite.
+    fn export_packets(&self, after: i64, limit: usize) -> StorageResult<ExportedPackets> {
+        let mut params = Params::default();
+        let condition = format!("WHERE p.id > {} ORDER BY p.id LIMIT {limit}", params.bind(after));
+        let rows = self.select_packets(&condition, &params)?;
+        let through = rows.last().map_or(after, |(id, _)| *id);
+        Ok(ExportedPackets { rows: rows.into_iter().map(|(_, record)| record).collect(), through })
+    }
+
+    fn export_window(&self, from: i64, to: i64) -> StorageResult<Vec<PacketRecord>> {
+        let mut params = Params::default();
+        let condition = format!(
+            "WHERE p.timestamp >= {} AND p.timestamp < {} ORDER BY p.id",
+            params.bind(from),
+            params.bind(to)
+        );
+        let rows = self.select_packets(&condition, &params)?;
+        Ok(rows.into_iter().map(|(_, record)| record).collect())
+    }
+
+    fn import_packets(&self, records: &[PacketRecord]) -> StorageResult<()> {
+        let mut pending = Pending::default();
+        for record in records {
+            self.push_packet(&mut pending, record.clone());
+        }
+        Ok(self.flush(&mut pending)?)
+    }
+
+    fn save_state(&self, key: &str, value: &str) -> StorageResult<()> {
+        let mut params = Params::default();
+        let sql = format!(
+            "INSERT INTO state (key, value, updated_at) VALUES ({}, {}, {})
+             ON CONFLICT (key) DO UPDATE SET value = excluded.value,
+                 updated_at = excluded.updated_at",
+            params.bind(key.to_string()),
+            params.bind(value.to_string()),
+            params.bind(now_ms())
+        );
+        self.execute(&sql, &params)?;
+        Ok(())
+    }
+
+    fn load_state(&self, key: &str) -> StorageResult<Option<String>> {
+        let mut params = Params::default();
+        let sql = format!("SELECT value FROM state WHERE key = {}", params.bind(key.to_string()));
+        Ok(self.select(&sql, &params, |row| row.try_get(0))?.into_iter().next())
+    }
+
+    /// Only a change of rate leaves a row, as with SQLite.
+    fn record_sample_rate(&self, sample_rate: u32, since: i64) -> StorageResult<()> {
+        let instance = self.instance.clone().unwrap_or_default();
+        let mut params = Params::default();
+        let sql = format!(
+            "SELECT sample_rate FROM capture_meta WHERE instance = {}
+             ORDER BY since DESC LIMIT 1",
+            params.bind(instance.clone())
+        );
+        let current = self.select(&sql, &params, |row| row.try_get::<_, i64>(0))?;
+        if current.first().map_or(1, |rate| *rate) != i64::from(sample_rate) {
+            let mut params = Params::default();
+            let sql = format!(
+                "INSERT INTO capture_meta (instance, since, sample_rate) VALUES ({}, {}, {})
+                 ON CONFLICT (instance, since) DO UPDATE SET sample_rate = excluded.sample_rate",
+                params.bind(instance),
+                params.bind(since),
+                params.bind(i64::from(sample_rate))
+            );
+            self.execute(&sql, &params)?;
+        }
+        Ok(())
+    }
+
+    fn metrics(&self) -> &StorageMetrics {
+        &self.metrics
+    }
+
+    fn checkpoint_wal(&self, _threshold_bytes: u64) -> StorageResult<Option<WalCheckpoint>> {
+        Ok(None)
+    }
+
+    fn update_size_metric(&self) {
+        // A scrape should not wait out the timeout while the server is down.
+        if !self.reachable() {
+            return;
+        }
+        let size = self.select(
+            "SELECT pg_database_size(current_database())",
+            &Params::default(),
+            |row| row.try_get::<_, i64>(0),
+        );
+        match size {
+            Ok(rows) => self.metrics.db_size_bytes.set(rows.first().copied().unwrap_or(0)),
+            Err(e) => tracing::warn!("Failed to read PostgreSQL database size: {}", e),
+        }
+    }
+
+    fn snapshot(&self) -> StorageResult<Snapshot> {
+        Err(StorageError::Snapshot("not supported by the PostgreSQL backend".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::categories::PortMatch;
+    use crate::test_support;
+
+    /// A db_url nothing listens on.
+    fn closed_url() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("postgres://ayaflow:secret@{}/traffic", listener.local_addr().unwrap())
+    }
+
+    fn packet(timestamp: i64) -> PacketMetadata {
+        PacketMetadata {
+            timestamp,
+            src_ip: "10.0.0.1".into(),
+            dst_ip: "10.0.0.2".into(),
+            length: 100,
+            payload_length: 60,
+            ttl: Some(64),
+            ..test_support::packet()
+        }
+    }
+
+    #[test]
+    fn test_filter_sql_binds_and_translates() {
+        let filter = PacketFilter {
+            from: Some(10),
+            ip: Some("10.0.0.1' OR '1'='1".into()),
+            protocol: Some("TCP".into()),
+            country: Some("unknown".parse().unwrap()),
+            asn: Some(64500),
+            category: Some(PortMatch {
+                ranges: vec![(443, 443)],
+                negate: false,
+                rule: Default::default(),
+            }),
+            ..PacketFilter::default()
+        };
+        let mut params = Params::default();
+        let sql = filter_sql(&filter, "p.", &mut params);
+        assert!(sql.starts_with("p.timestamp >= $1 AND (p.src_ip = $2 OR p.dst_ip = $2)"));
+        assert!(sql.contains("lower(p.protocol) = $3 AND p.asn = $4 AND p.country IS NULL"));
+        assert!(sql.contains("ELSE p.dst_port END BETWEEN 443 AND 443)"));
+        assert!(!sql.contains("10.0.0.1"));
+        assert_eq!(params.refs().len(), 4);
+        assert_eq!(filter_sql(&PacketFilter::default(), "", &mut Params::default()), "TRUE");
+    }
+
+    #[test]
+    fn test_buffers_while_unreachable() {
+        let config = PostgresConfig {
+            flush_max_rows: 3,
+            buffer_max_rows: 4,
+            timeout_ms: 1000,
+            ..Default::default()
+        };
+
+        // The agent starts, and reads fail as unavailable.
+        let backend = Postgres::open(&closed_url(), &config).unwrap().with_instance("a".into());
+        let read = backend.query_packets(&PacketFilter::default(), 10);
+        assert!(matches!(read, Err(StorageError::Postgres(e)) if e.is_unreachable()));
+        assert!(!backend.reachable());
+
+        // Writes are held, the oldest dropped past buffer_max_rows.
+        let mut pending = Pending::default();
+        backend.buffer(&mut pending, StorageEvent::Packets((0..3).map(packet).collect()));
+        assert!(backend.flush(&mut pending).unwrap_err().is_unreachable());
+        backend.buffer(&mut pending, StorageEvent::Packets((3..6).map(packet).collect()));
+        assert_eq!(pending.rows(), 4);
+        assert_eq!(backend.metrics.spill_dropped_rows.get(), 2);
+        let kept: Vec<i64> = pending.packets.iter().map(|record| record.timestamp).collect();
+        assert_eq!(kept, [2, 3, 4, 5]);
+        assert_eq!(pending.packets[0].instance.as_deref(), Some("a"));
+        assert!(backend.flush(&mut pending).unwrap_err().is_unreachable());
+        assert_eq!(pending.rows(), 4);
+        assert_eq!(backend.metrics.transaction_failures.get(), 2);
+
+        assert!(Postgres::open("postgres://db:port/x", &config).is_err());
+    }
+
+    #[test]
+    fn test_future_stamps_clamped_with_their_window() {
+        let backend = Postgres::open(&closed_url(), &PostgresConfig::default()).unwrap();
+        let (before, day) = (now_ms(), 86_400_000);
+        let bucket = AggregatedBucket {
+            window_start: before - 1_000,
+            ..AggregatedBucket::from_packet(&packet(before + day))
+        };
+        let mut pending = Pending::default();
+        let packets = vec![packet(before + day), packet(before + 1_000)];
+        backend.buffer(&mut pending, StorageEvent::Packets(packets));
+        backend.buffer(&mut pending, StorageEvent::Buckets(vec![bucket]));
+        assert_eq!(backend.metrics.clamped_timestamps.get(), 2);
+
+        let rows = &pending.packets;
+        let after = now_ms();
+        assert!((before..=after).contains(&rows[0].timestamp));
+        // Within the allowed skew, kept as stamped.
+        assert_eq!(rows[1].timestamp, before + 1_000);
+        // The window keeps its start and ends at the write time.
+        assert!((before..=after).contains(&rows[2].timestamp));
+        assert_eq!(rows[2].window_start, Some(before - 1_000));
+        assert_eq!(rows[2].window_end, Some(rows[2].timestamp));
+    }
+
+    #[test]
+    fn test_history_rows_from_records() {
+        let bucket = AggregatedBucket::from_packet(&packet(1000));
+        let mut record = bucket_record(&bucket, Some("a"));
+        record.country = Some("JP".into());
+        record.asn = Some(64500);
+        let row = history_row(record);
+        assert_eq!((row.kind, row.packet_count), (RowKind::Aggregated, 1));
+        assert_eq!(row.instance.as_deref(), Some("a"));
+        assert_eq!((row.packet.country.as_deref(), row.packet.asn), (Some("JP"), Some(64500)));
+
+        let row = history_row(raw_record(&packet(1000), None));
+        assert_eq!((row.kind, row.packet.ttl, row.packet.length), (RowKind::Raw, Some(64), 100));
+    }
+}
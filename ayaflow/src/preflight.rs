@@ -0,0 +1,217 @@
+//! Startup checks run before any eBPF object is loaded.
+//!
+//! aya reports missing privileges or kernel features as bare errno values
+//! from deep inside program loading.  These checks look for the common
+//! causes up front and report each with a one-line fix.  Checks whose
+//! inputs cannot be read (no procfs, no kernel config) are skipped rather
+//! than failed; `--skip-preflight` bypasses them all.
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::config::{Config, Hook};
+
+// Capability bits from linux/capability.h.
+const CAP_NET_ADMIN: u32 = 12;
+const CAP_SYS_ADMIN: u32 = 21;
+const CAP_BPF: u32 = 39;
+
+/// BPF ring buffers, which carry every event to userspace, arrived in 5.8.
+const MIN_KERNEL: (u32, u32) = (5, 8);
+
+/// Kernel options the TC hook needs: the BPF classifier and the clsact
+/// qdisc (built as part of the ingress qdisc).
+const TC_KERNEL_OPTIONS: [&str; 2] = ["CONFIG_NET_CLS_BPF", "CONFIG_NET_SCH_INGRESS"];
+
+/// A failed check: what is wrong and how to fix it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Failure {
+    pub problem: String,
+    pub fix: String,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}; {}", self.problem, self.fix)
+    }
+}
+
+/// Run every check for capturing on `iface`, logging each failure.
+pub fn run(config: &Config, iface: &str) -> anyhow::Result<()> {
+    let failures = checks(config, iface);
+    for failure in &failures {
+        tracing::error!("Preflight: {}", failure);
+    }
+    if !failures.is_empty() {
+        anyhow::bail!(
+            "{} preflight check(s) failed; fix them or pass --skip-preflight",
+            failures.len()
+        );
+    }
+    Ok(())
+}
+
+fn checks(config: &Config, iface: &str) -> Vec<Failure> {
+    let mut failures = Vec::new();
+    match fs::read_to_string("/proc/self/status").ok().and_then(|s| effective_caps(&s)) {
+        Some(caps) => failures.extend(check_capabilities(caps)),
+        None => tracing::debug!("Preflight: cannot read capabilities, skipping"),
+    }
+
+    let release = fs::read_to_string("/proc/sys/kernel/osrelease").unwrap_or_default();
+    let release = release.trim();
+    if let Some(failure) = parse_release(release).and_then(check_kernel) {
+        failures.push(failure);
+    }
+    // XDP falls back to TC and egress always uses it, so TC is nearly always
+    // needed; only ingress-only XDP can do without.
+    let uses_tc = config.hook == Hook::Tc || config.direction.egress();
+    if uses_tc {
+        match fs::read_to_string(format!("/boot/config-{}", release)) {
+            Ok(kernel_config) => failures.extend(check_tc_support(&kernel_config)),
+            Err(_) => tracing::debug!("Preflight: no kernel config for {}, skipping", release),
+        }
+    }
+
+    failures.extend(check_interface(Path::new("/sys/class/net"), iface));
+    failures
+}
+
+/// The effective capability set from `/proc/<pid>/status`.  Effective root
+/// normally holds every capability, so this covers running as root too.
+fn effective_caps(status: &str) -> Option<u64> {
+    let hex = status.lines().find_map(|line| line.strip_prefix("CapEff:"))?;
+    u64::from_str_radix(hex.trim(), 16).ok()
+}
+
+fn check_capabilities(caps: u64) -> Vec<Failure> {
+    let has = |cap: u32| caps & (1 << cap) != 0;
+    let mut failures = Vec::new();
+    // Kernels before 5.8 have no CAP_BPF and require CAP_SYS_ADMIN instead.
+    if !has(CAP_BPF) && !has(CAP_SYS_ADMIN) {
+        failures.push(Failure {
+            problem: "missing CAP_BPF, needed to load eBPF programs".to_string(),
+            fix: "run as root or `sudo setcap cap_bpf,cap_net_admin,cap_perfmon+ep $(which ayaflow)`"
+                .to_string(),
+        });
+    }
+    if !has(CAP_NET_ADMIN) {
+        failures.push(Failure {
+            problem: "missing CAP_NET_ADMIN, needed to attach to an interface".to_string(),
+            fix: "run as root or grant it with `setcap cap_net_admin+ep` (or `cap_add: NET_ADMIN`)"
+                .to_string(),
+        });
+    }
+    failures
+}
+
+/// `(major, minor)` from a kernel release such as `6.1.0-18-amd64`.
+fn parse_release(release: &str) -> Option<(u32, u32)> {
+    let mut parts = release.split(|c: char| !c.is_ascii_digit());
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+fn check_kernel(version: (u32, u32)) -> Option<Failure> {
+    (version < MIN_KERNEL).then(|| Failure {
+        problem: format!(
+            "kernel {}.{} has no BPF ring buffer support (needs {}.{}+)",
+            version.0, version.1, MIN_KERNEL.0, MIN_KERNEL.1
+        ),
+        fix: "upgrade the kernel".to_string(),
+    })
+}
+
+/// Options from `TC_KERNEL_OPTIONS` that a kernel config (`/boot/config-*`)
+/// neither builds in nor builds as a module.
+fn check_tc_support(kernel_config: &str) -> Vec<Failure> {
+    TC_KERNEL_OPTIONS
+        .iter()
+        .filter(|option| {
+            !kernel_config.lines().any(|line| {
+                line.strip_prefix(**option)
+                    .is_some_and(|value| value == "=y" || value == "=m")
+            })
+        })
+        .map(|option| Failure {
+            problem: format!("kernel built without {}, needed by the TC hook", option),
+            fix: "use a kernel with it enabled, or `--hook xdp --direction ingress`".to_string(),
+        })
+        .collect()
+}
+
+/// `iface` must exist under the sysfs `class/net` directory and not be down.
+/// Interfaces without carrier detection report `unknown` and pass.
+fn check_interface(sysfs: &Path, iface: &str) -> Option<Failure> {
+    let dir = sysfs.join(iface);
+    if !dir.exists() {
+        return Some(Failure {
+            problem: format!("interface {} does not exist", iface),
+            fix: "pass an existing one with --interface (see `ip link`)".to_string(),
+        });
+    }
+    let state = fs::read_to_string(dir.join("operstate")).unwrap_or_default();
+    (state.trim() == "down").then(|| Failure {
+        problem: format!("interface {} is down", iface),
+        fix: format!("bring it up with `ip link set dev {} up`", iface),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capability_checks() {
+        let status = "Name:\tayaflow\nCapInh:\t0000000000000000\nCapEff:\t000001ffffffffff\n";
+        let root = effective_caps(status).unwrap();
+        assert!(check_capabilities(root).is_empty());
+        assert_eq!(effective_caps("Name:\tayaflow\n"), None);
+
+        let failures = check_capabilities(0);
+        assert_eq!(failures.len(), 2);
+        assert!(failures[0].to_string().contains("CAP_BPF"));
+        assert!(failures[1].to_string().contains("CAP_NET_ADMIN"));
+
+        // CAP_SYS_ADMIN stands in for CAP_BPF on older kernels.
+        let legacy = (1 << CAP_SYS_ADMIN) | (1 << CAP_NET_ADMIN);
+        assert!(check_capabilities(legacy).is_empty());
+    }
+
+    #[test]
+    fn test_kernel_checks() {
+        assert_eq!(parse_release("6.1.0-18-amd64"), Some((6, 1)));
+        assert_eq!(parse_release("5.15.0"), Some((5, 15)));
+        assert_eq!(parse_release(""), None);
+        assert!(check_kernel((5, 8)).is_none());
+        assert!(check_kernel((5, 15)).is_none());
+        assert!(check_kernel((4, 19)).unwrap().problem.contains("4.19"));
+
+        let config = "CONFIG_NET_CLS_BPF=m\n# CONFIG_NET_SCH_INGRESS is not set\n";
+        let failures = check_tc_support(config);
+        assert_eq!(failures.len(), 1);
+        assert!(failures[0].problem.contains("CONFIG_NET_SCH_INGRESS"));
+        assert!(check_tc_support("CONFIG_NET_CLS_BPF=y\nCONFIG_NET_SCH_INGRESS=m\n").is_empty());
+    }
+
+    #[test]
+    fn test_interface_check() {
+        let dir = std::env::temp_dir().join(format!("ayaflow-preflight-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        for (name, state) in [("eth0", "up"), ("eth1", "down"), ("wg0", "unknown")] {
+            fs::create_dir_all(dir.join(name)).unwrap();
+            fs::write(dir.join(name).join("operstate"), format!("{}\n", state)).unwrap();
+        }
+
+        assert_eq!(check_interface(&dir, "eth0"), None);
+        assert_eq!(check_interface(&dir, "wg0"), None);
+        let down = check_interface(&dir, "eth1").unwrap();
+        assert!(down.fix.contains("ip link set dev eth1 up"));
+        let missing = check_interface(&dir, "eth9").unwrap();
+        assert!(missing.problem.contains("does not exist"));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
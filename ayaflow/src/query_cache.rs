@@ -0,0 +1,321 @@
+//! Results of recent history queries.
+//!
+//! Dashboards poll the same `/api/history` query every few seconds, while
+//! the writer commits new rows at most once per flush interval.  Results
+//! are kept per normalized filter and limit until a write that could change
+//! them bumps the generation, or until they are older than the TTL, so
+//! repeat polls never touch SQLite.  Entry count and estimated row memory
+//! are both capped; the least recently used entry goes first.
+
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use tokio::time::{Duration, Instant};
+
+use crate::categories::PortMatch;
+use crate::config::StorageConfig;
+use crate::geoip::CountryMatch;
+use crate::locality::FlowDirection;
+use crate::storage::{HistoryRow, PacketFilter};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct QueryKey {
+    from: i64,
+    to: i64,
+    ip: Option<String>,
+    interface: Option<String>,
+    mac: Option<String>,
+    direction: Option<FlowDirection>,
+    category: Option<PortMatch>,
+    instance: Option<String>,
+    protocol: Option<String>,
+    icmp_type: Option<u8>,
+    country: Option<CountryMatch>,
+    asn: Option<u32>,
+    limit: usize,
+}
+
+impl QueryKey {
+    /// Open bounds key the same as the extreme timestamps they stand for.
+    fn new(filter: &PacketFilter, limit: usize) -> Self {
+        Self {
+            from: filter.from.unwrap_or(i64::MIN),
+            to: filter.to.unwrap_or(i64::MAX),
+            ip: filter.ip.clone(),
+            interface: filter.interface.clone(),
+            mac: filter.mac.clone(),
+            direction: filter.direction,
+            category: filter.category.clone(),
+            instance: filter.instance.clone(),
+            protocol: filter.protocol.as_ref().map(|p| p.to_ascii_uppercase()),
+            icmp_type: filter.icmp_type,
+            country: filter.country.clone(),
+            asn: filter.asn,
+            limit,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    rows: Vec<HistoryRow>,
+    bytes: usize,
+    stored_at: Instant,
+    last_used: u64,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    /// The generation every entry was read at; a newer one empties the map.
+    generation: u64,
+    map: HashMap<QueryKey, Entry>,
+    bytes: usize,
+    /// Bumped on every lookup and insert, for least-recently-used eviction.
+    clock: u64,
+}
+
+impl Entries {
+    fn sync(&mut self, generation: u64) {
+        if self.generation != generation {
+            self.generation = generation;
+            self.map.clear();
+            self.bytes = 0;
+        }
+    }
+
+    fn remove(&mut self, key: &QueryKey) {
+        if let Some(entry) = self.map.remove(key) {
+            self.bytes -= entry.bytes;
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct QueryCache {
+    max_entries: usize,
+    max_bytes: usize,
+    ttl: Duration,
+    /// Bumped after every committed write to the tables history reads.
+    generation: AtomicU64,
+    entries: Mutex<Entries>,
+}
+
+impl QueryCache {
+    pub fn new(config: &StorageConfig) -> Self {
+        Self {
+            max_entries: config.query_cache_entries,
+            max_bytes: config.query_cache_max_bytes,
+            ttl: Duration::from_millis(config.query_cache_ttl_ms),
+            generation: AtomicU64::new(0),
+            entries: Mutex::default(),
+        }
+    }
+
+    /// A cache that stores nothing, for handles whose database another
+    /// process writes to.
+    pub fn disabled() -> Self {
+        Self::new(&StorageConfig {
+            query_cache_entries: 0,
+            ..StorageConfig::default()
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.max_entries > 0 && self.max_bytes > 0
+    }
+
+    /// The generation to pass to `insert` for a query about to run.  Read
+    /// it before querying, so a write committed meanwhile discards the
+    /// result instead of caching it.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// Forget every entry.  Called after each committed write.
+    pub fn invalidate(&self) {
+        self.generation.fetch_add(1, Ordering::AcqRel);
+    }
+
+    pub fn get(&self, filter: &PacketFilter, limit: usize) -> Option<Vec<HistoryRow>> {
+        let key = QueryKey::new(filter, limit);
+        let mut entries = self.entries.lock().unwrap();
+        entries.sync(self.generation());
+        entries.clock += 1;
+        let clock = entries.clock;
+        if entries.map.get(&key)?.stored_at.elapsed() > self.ttl {
+            entries.remove(&key);
+            return None;
+        }
+        let entry = entries.map.get_mut(&key)?;
+        entry.last_used = clock;
+        Some(entry.rows.clone())
+    }
+
+    /// Keep `rows`, read at `generation`, unless a write has landed since
+    /// or they alone exceed the memory cap.
+    pub fn insert(
+        &self,
+        filter: &PacketFilter,
+        limit: usize,
+        generation: u64,
+        rows: &[HistoryRow],
+    ) {
+        let bytes = rows.iter().map(row_bytes).sum::<usize>();
+        if !self.enabled() || bytes > self.max_bytes {
+            return;
+        }
+        let key = QueryKey::new(filter, limit);
+        let mut entries = self.entries.lock().unwrap();
+        entries.sync(self.generation());
+        if entries.generation != generation {
+            return;
+        }
+        entries.remove(&key);
+        while entries.map.len() >= self.max_entries || entries.bytes + bytes > self.max_bytes {
+            let oldest = entries.map.iter().min_by_key(|(_, entry)| entry.last_used);
+            let Some(oldest) = oldest.map(|(key, _)| key.clone()) else {
+                break;
+            };
+            entries.remove(&oldest);
+        }
+        entries.clock += 1;
+        let entry = Entry {
+            rows: rows.to_vec(),
+            bytes,
+            stored_at: Instant::now(),
+            last_used: entries.clock,
+        };
+        entries.bytes += bytes;
+        entries.map.insert(key, entry);
+    }
+
+    /// Estimated memory held by cached rows.
+    pub fn bytes(&self) -> usize {
+        self.entries.lock().unwrap().bytes
+    }
+}
+
+/// A row's size plus its heap strings.
+fn row_bytes(row: &HistoryRow) -> usize {
+    let packet = &row.packet;
+    let strings = [
+        Some(&packet.src_ip),
+        Some(&packet.dst_ip),
+        Some(&packet.protocol),
+        Some(&packet.direction),
+        Some(&packet.interface),
+        packet.src_mac.as_ref(),
+        packet.dst_mac.as_ref(),
+        packet.src_hostname.as_ref(),
+        packet.dst_hostname.as_ref(),
+        packet.domain.as_ref(),
+        row.instance.as_ref(),
+    ];
+    size_of::<HistoryRow>() + strings.into_iter().flatten().map(String::len).sum::<usize>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::RowKind;
+    use crate::state::PacketMetadata;
+    use crate::test_support;
+
+    fn rows(count: usize) -> Vec<HistoryRow> {
+        let packet = PacketMetadata {
+            timestamp: 1000,
+            src_ip: "10.0.0.5".into(),
+            dst_ip: "93.184.216.34".into(),
+            length: 1500,
+            payload_length: 1448,
+            ttl: Some(64),
+            ..test_support::packet()
+        };
+        let row = HistoryRow {
+            packet,
+            kind: RowKind::Raw,
+            packet_count: 1,
+            instance: None,
+            service_port: None,
+        };
+        vec![row; count]
+    }
+
+    fn cache(entries: usize, max_bytes: usize) -> QueryCache {
+        QueryCache::new(&StorageConfig {
+            query_cache_entries: entries,
+            query_cache_max_bytes: max_bytes,
+            ..StorageConfig::default()
+        })
+    }
+
+    #[test]
+    fn test_keys_and_invalidation() {
+        let cache = cache(8, 1 << 20);
+        let all = PacketFilter::default();
+        let eth0 = PacketFilter { interface: Some("eth0".into()), ..PacketFilter::default() };
+        assert!(cache.get(&all, 100).is_none());
+
+        cache.insert(&all, 100, cache.generation(), &rows(3));
+        cache.insert(&eth0, 100, cache.generation(), &rows(1));
+        assert_eq!(cache.get(&all, 100).map(|r| r.len()), Some(3));
+        assert_eq!(cache.get(&eth0, 100).map(|r| r.len()), Some(1));
+        assert!(cache.get(&all, 10).is_none());
+        // An open bound is the same query as its extreme.
+        let explicit = PacketFilter { from: Some(i64::MIN), ..PacketFilter::default() };
+        assert!(cache.get(&explicit, 100).is_some());
+
+        cache.invalidate();
+        assert!(cache.get(&all, 100).is_none());
+        assert_eq!(cache.bytes(), 0);
+
+        // A result read before a write is not kept.
+        let generation = cache.generation();
+        cache.invalidate();
+        cache.insert(&all, 100, generation, &rows(3));
+        assert!(cache.get(&all, 100).is_none());
+    }
+
+    #[test]
+    fn test_entry_and_memory_caps() {
+        let row = row_bytes(&rows(1)[0]);
+        let cache = cache(2, row * 5);
+        let filters: Vec<PacketFilter> = ["a", "b", "c"]
+            .map(|i| PacketFilter { interface: Some(i.into()), ..PacketFilter::default() })
+            .into();
+        cache.insert(&filters[0], 10, 0, &rows(1));
+        cache.insert(&filters[1], 10, 0, &rows(1));
+        // Using "a" makes "b" the one evicted for "c".
+        cache.get(&filters[0], 10).unwrap();
+        cache.insert(&filters[2], 10, 0, &rows(1));
+        assert!(cache.get(&filters[0], 10).is_some());
+        assert!(cache.get(&filters[1], 10).is_none());
+        assert_eq!(cache.bytes(), row * 2);
+
+        // Four rows fit beside the recently read "a" once "c" is gone; six
+        // never fit.
+        cache.insert(&filters[1], 10, 0, &rows(4));
+        assert_eq!(cache.bytes(), row * 5);
+        assert!(cache.get(&filters[2], 10).is_none());
+        assert!(cache.get(&filters[0], 10).is_some());
+        cache.insert(&PacketFilter::default(), 10, 0, &rows(6));
+        assert!(cache.get(&PacketFilter::default(), 10).is_none());
+        assert_eq!(cache.bytes(), row * 5);
+
+        assert!(!QueryCache::disabled().enabled());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_entries_expire() {
+        let cache = cache(8, 1 << 20);
+        let all = PacketFilter::default();
+        cache.insert(&all, 100, 0, &rows(1));
+        tokio::time::advance(cache.ttl - Duration::from_millis(1)).await;
+        assert!(cache.get(&all, 100).is_some());
+        tokio::time::advance(Duration::from_millis(2)).await;
+        assert!(cache.get(&all, 100).is_none());
+        assert_eq!(cache.bytes(), 0);
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Longest window a rate can be asked for.
+const MAX_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    at: Instant,
+    packets: u64,
+    bytes: u64,
+}
+
+/// Packets and bytes per second over one window.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Rate {
+    pub pps: f64,
+    pub bps: f64,
+}
+
+/// Sliding-window traffic rates.
+///
+/// A timer task records the lifetime totals about once a second; rates are
+/// the difference between the newest sample and the newest one at least a
+/// window older, over the time actually between them.  Ticks that land a
+/// little late or early so stretch the span slightly instead of leaving no
+/// sample to diff against.  The packet hot path only bumps the atomic totals
+/// and never touches this history.
+#[derive(Debug, Default)]
+pub struct RateSampler {
+    history: Mutex<VecDeque<Sample>>,
+}
+
+impl RateSampler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record the lifetime totals observed at `at`.
+    pub fn record(&self, at: Instant, packets: u64, bytes: u64) {
+        let mut history = self.history.lock().unwrap();
+        history.push_back(Sample { at, packets, bytes });
+        // Keep the newest sample at least MAX_WINDOW old as the base for it.
+        while history
+            .get(1)
+            .is_some_and(|next| at.duration_since(next.at) >= MAX_WINDOW)
+        {
+            history.pop_front();
+        }
+    }
+
+    /// Forget all samples, e.g. after the totals were reset.
+    pub fn clear(&self) {
+        self.history.lock().unwrap().clear();
+    }
+
+    /// Average rate over the last `window`.  Until the history covers the
+    /// whole window the rate is taken over whatever it does cover.
+    pub fn rate(&self, window: Duration) -> Rate {
+        let history = self.history.lock().unwrap();
+        let Some(newest) = history.back() else {
+            return Rate::default();
+        };
+        let base = history
+            .iter()
+            .rev()
+            .find(|sample| newest.at.duration_since(sample.at) >= window)
+            .unwrap_or(&history[0]);
+        let elapsed = newest.at.duration_since(base.at).as_secs_f64();
+        if elapsed <= 0.0 {
+            return Rate::default();
+        }
+        Rate {
+            pps: newest.packets.saturating_sub(base.packets) as f64 / elapsed,
+            bps: newest.bytes.saturating_sub(base.bytes) as f64 / elapsed,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_windows_track_recent_traffic() {
+        let sampler = RateSampler::new();
+        let start = Instant::now();
+        assert_eq!(sampler.rate(Duration::from_secs(1)), Rate::default());
+
+        // 60s of 10 packets / 1000 bytes per second, then a 1s spike.
+        for i in 0..=60u64 {
+            sampler.record(start + Duration::from_secs(i), i * 10, i * 1000);
+        }
+        sampler.record(start + Duration::from_secs(61), 600 + 610, 60_000 + 61_000);
+
+        let one = sampler.rate(Duration::from_secs(1));
+        assert_eq!(one.pps, 610.0);
+        assert_eq!(one.bps, 61_000.0);
+
+        let minute = sampler.rate(Duration::from_secs(60));
+        assert_eq!(minute.pps, (1210.0 - 10.0) / 60.0);
+        assert_eq!(minute.bps, (121_000.0 - 1000.0) / 60.0);
+    }
+
+    #[test]
+    fn test_history_is_bounded() {
+        let sampler = RateSampler::new();
+        let start = Instant::now();
+        for i in 0..600u64 {
+            sampler.record(start + Duration::from_secs(i), i, i);
+        }
+        assert!(sampler.history.lock().unwrap().len() <= 61);
+
+        // A short history still yields a rate over the span it covers.
+        let fresh = RateSampler::new();
+        fresh.record(start, 0, 0);
+        fresh.record(start + Duration::from_secs(2), 20, 200);
+        assert_eq!(fresh.rate(MAX_WINDOW).pps, 10.0);
+    }
+
+    #[test]
+    fn test_rates_survive_tick_jitter() {
+        // A steady 100 packets/s sampled on a tick that runs late and early.
+        let sampler = RateSampler::new();
+        let start = Instant::now();
+        let mut t = Duration::ZERO;
+        for i in 0..200u32 {
+            sampler.record(start + t, (t.as_secs_f64() * 100.0) as u64, 0);
+            t += Duration::from_millis(if i % 2 == 0 { 1150 } else { 900 });
+        }
+        for window in [Duration::from_secs(1), Duration::from_secs(60)] {
+            let pps = sampler.rate(window).pps;
+            assert!((pps - 100.0).abs() < 1.0, "{:?}: {}", window, pps);
+        }
+    }
+}
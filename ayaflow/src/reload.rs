@@ -0,0 +1,200 @@
+//! Rereading the config file on SIGHUP.
+//!
+//! The file goes through the same merge with the command line and the same
+//! validation as at startup; a file that fails either is logged and the
+//! running settings stay.  `capture_filter` and `storage_filter` take
+//! effect in place, in the kernel prefilters too, and so do the reverse
+//! DNS TTLs, for names looked up from then on.  Any other setting that
+//! differs from the running one is logged as needing a restart.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+use ayaflow_common::filter::Filters;
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::config::{CliArgs, Config};
+use crate::diagnostics::Diagnostics;
+use crate::ip_filter::IpPrefilter;
+use crate::port_filter::PortPrefilter;
+
+/// The top-level settings a reload applies whole.
+const RELOADABLE: &[&str] = &["capture_filter", "storage_filter"];
+
+/// What a reload changes in place.
+pub struct Reloadable {
+    pub filters: Arc<LiveFilters>,
+    /// Holds the reverse DNS cache once capture has made one.
+    pub diagnostics: Arc<Diagnostics>,
+}
+
+/// The filters in force, swapped whole on a reload.  Readers take the
+/// current set once per batch.
+#[derive(Default)]
+pub struct LiveFilters {
+    filters: RwLock<Arc<Filters>>,
+    /// The kernel prefilters, once capture has loaded them.
+    ports: Mutex<Option<PortPrefilter>>,
+    ips: Mutex<Option<IpPrefilter>>,
+}
+
+impl LiveFilters {
+    pub fn new(filters: Filters) -> Self {
+        Self {
+            filters: RwLock::new(Arc::new(filters)),
+            ports: Mutex::new(None),
+            ips: Mutex::new(None),
+        }
+    }
+
+    pub fn get(&self) -> Arc<Filters> {
+        self.filters.read().unwrap().clone()
+    }
+
+    /// Hand over the prefilters, loaded with the current ports and nets.
+    pub fn attach_kernel(&self, ports: Option<PortPrefilter>, ips: Option<IpPrefilter>) {
+        *self.ports.lock().unwrap() = ports;
+        *self.ips.lock().unwrap() = ips;
+    }
+
+    /// Put `filters` in force, in the kernel first so the userspace filter
+    /// never sees fewer packets than it passes.
+    pub fn replace(&self, filters: Filters) {
+        if let Some(prefilter) = self.ports.lock().unwrap().as_mut() {
+            prefilter.replace(filters.capture.ports());
+        }
+        if let Some(prefilter) = self.ips.lock().unwrap().as_mut() {
+            prefilter.replace(filters.capture.ips());
+        }
+        *self.filters.write().unwrap() = Arc::new(filters);
+    }
+}
+
+/// Read `file` as startup did: merged with `cli`, then validated.
+fn load(file: &Path, cli: &CliArgs) -> anyhow::Result<Config> {
+    let mut config = Config::from_file(file)?;
+    config.merge_cli(cli);
+    config.validate(Some(file))?;
+    Ok(config)
+}
+
+/// The top-level settings in `new` that differ from `running` and only
+/// take effect on a restart.
+fn needs_restart(running: &Config, new: &Config) -> Vec<String> {
+    let mut new = new.clone();
+    new.dns.ttl_seconds = running.dns.ttl_seconds;
+    new.dns.failed_ttl_seconds = running.dns.failed_ttl_seconds;
+    let (Ok(serde_json::Value::Object(running)), Ok(serde_json::Value::Object(new))) =
+        (serde_json::to_value(running), serde_json::to_value(new))
+    else {
+        return Vec::new();
+    };
+    new.iter()
+        .filter(|(key, value)| {
+            !RELOADABLE.contains(&key.as_str()) && running.get(*key) != Some(value)
+        })
+        .map(|(key, _)| key.clone())
+        .collect()
+}
+
+/// Reread `file` and apply what it changes to `live`.  `running` is the
+/// config the process started with.
+fn reload(file: &Path, cli: &CliArgs, running: &Config, live: &Reloadable) -> anyhow::Result<()> {
+    let config = load(file, cli)?;
+    // Validation has already built these once.
+    let new = Filters::new(&config.capture_filter, &config.storage_filter)
+        .map_err(anyhow::Error::msg)?;
+    live.filters.replace(new);
+    if let Some(cache) = live.diagnostics.dns() {
+        cache.set_ttls(&config.dns);
+    }
+    tracing::info!(
+        "Reloaded {}: capturing {}, storing {}, DNS TTLs {}s and {}s for failed lookups",
+        file.display(),
+        config.capture_filter,
+        config.storage_filter,
+        config.dns.ttl_seconds,
+        config.dns.failed_ttl_seconds
+    );
+    let restart = needs_restart(running, &config);
+    if !restart.is_empty() {
+        tracing::warn!("Changed settings need a restart to apply: {}", restart.join(", "));
+    }
+    Ok(())
+}
+
+/// Reload `file` every time the process receives SIGHUP.
+pub async fn reload_on_sighup(
+    file: PathBuf,
+    cli: CliArgs,
+    running: Config,
+    live: Reloadable,
+) {
+    let mut signals = match signal(SignalKind::hangup()) {
+        Ok(signals) => signals,
+        Err(e) => {
+            tracing::warn!("Cannot listen for SIGHUP, config reloads disabled: {}", e);
+            return;
+        }
+    };
+    while signals.recv().await.is_some() {
+        if let Err(e) = reload(&file, &cli, &running, &live) {
+            tracing::warn!("Config reload failed, keeping the running settings:\n{:#}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    use crate::config::Cli;
+    use crate::dns::DnsCache;
+
+    fn write_config(path: &Path, yaml: &str) {
+        std::fs::write(path, yaml).unwrap();
+    }
+
+    #[test]
+    fn test_reload_swaps_filters_and_keeps_them_on_error() {
+        let dir = std::env::temp_dir().join(format!("ayaflow-reload-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("ayaflow.yaml");
+        let db = dir.join("traffic.db");
+        let db = db.to_str().unwrap();
+        let cli = Cli::parse_from(["ayaflow", "--skip-preflight", "--db-path", db]).run;
+        write_config(&file, "capture_filter:\n  ports: [\"443\"]\n");
+        let running = load(&file, &cli).unwrap();
+        let started = Filters::new(&running.capture_filter, &running.storage_filter).unwrap();
+        let live = Reloadable {
+            filters: Arc::new(LiveFilters::new(started)),
+            diagnostics: Arc::default(),
+        };
+        let filters = &live.filters;
+        live.diagnostics.set_dns_cache(Arc::new(DnsCache::from_config(&running.dns)));
+        assert!(filters.get().capture.matches("10.0.0.2", "10.0.0.1", 40000, 443, "TCP"));
+
+        let yaml = "capture_filter:\n  ports: [\"53\"]\nport: 9090\ndns:\n  ttl_seconds: 60\n";
+        write_config(&file, yaml);
+        reload(&file, &cli, &running, &live).unwrap();
+        let current = filters.get();
+        assert!(!current.capture.matches("10.0.0.2", "10.0.0.1", 40000, 443, "TCP"));
+        assert!(current.capture.matches("10.0.0.2", "10.0.0.1", 40000, 53, "UDP"));
+        assert_eq!(live.diagnostics.dns_cache().unwrap().ttl_seconds, 60);
+        assert_eq!(needs_restart(&running, &load(&file, &cli).unwrap()), ["port"]);
+        write_config(&file, "dns:\n  timeout_ms: 500\n");
+        assert_eq!(needs_restart(&running, &load(&file, &cli).unwrap()), ["dns"]);
+
+        // A bad entry, or a file that no longer parses, changes nothing.
+        write_config(&file, "capture_filter:\n  ports: [\"9000-80\"]\n");
+        let e = reload(&file, &cli, &running, &live).unwrap_err();
+        assert!(e.to_string().contains("capture_filter.ports"), "{}", e);
+        write_config(&file, "capture_filter: [\n");
+        assert!(reload(&file, &cli, &running, &live).is_err());
+        assert!(Arc::ptr_eq(&filters.get(), &current));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,516 @@
+//! Traffic summaries over a period.
+//!
+//! A report totals what was stored in the period and lists the top talkers
+//! and destinations by stored bytes, the alerts raised and the live
+//! connection count when it was built.  `/api/report` builds one on demand.
+//! With `reports.daily_at` set, the agent also builds one covering the last
+//! 24h every day at that time (UTC).  It posts the report to
+//! `reports.webhook`, writes it to `reports.output_path`, or both.  A failed
+//! delivery is retried with backoff and marks the `reports` component
+//! degraded.
+
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use chrono::{DateTime, NaiveTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::time::{sleep, Duration};
+
+use crate::health::Heartbeat;
+use crate::hooks::{self, WebhookUrl};
+use crate::openapi::{api_schema, string_enum, ApiSchema};
+use crate::state::TrafficState;
+use crate::storage::{
+    AlertFilter, PacketFilter, StorageBackend, StorageResult, StoredTalker, StoredTotals,
+    TopColumn,
+};
+
+/// What a report covers unless asked otherwise, and what the daily one
+/// always covers.
+pub const DEFAULT_PERIOD: Duration = Duration::from_secs(24 * 3600);
+
+/// Longest period `/api/report` accepts.
+const MAX_PERIOD: Duration = Duration::from_secs(366 * 24 * 3600);
+
+/// How long a webhook may take to answer.
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Pause before the first retry of a failed delivery; later pauses double
+/// up to the maximum.
+const RETRY_BACKOFF_MIN: Duration = Duration::from_secs(30);
+const RETRY_BACKOFF_MAX: Duration = Duration::from_secs(30 * 60);
+
+/// Scheduled reports (the `reports:` section of the YAML config).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReportsConfig {
+    /// Build the daily report at this time of day, `HH:MM` in UTC.  Unset
+    /// schedules none.
+    #[serde(default)]
+    pub daily_at: Option<String>,
+    /// `http://host[:port]/path` to POST the daily report to.
+    #[serde(default)]
+    pub webhook: Option<String>,
+    /// File to write the daily report to, replacing the previous one.  A
+    /// `{date}` in the path becomes the day the report ends, `YYYY-MM-DD`.
+    #[serde(default)]
+    pub output_path: Option<String>,
+    /// How delivered reports are rendered.
+    #[serde(default)]
+    pub format: ReportFormat,
+    /// Entries in each top list.
+    #[serde(default = "default_top")]
+    pub top: usize,
+    /// Further attempts after a failed delivery.
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+}
+
+fn default_top() -> usize {
+    10
+}
+
+fn default_retries() -> u32 {
+    5
+}
+
+impl Default for ReportsConfig {
+    fn default() -> Self {
+        Self {
+            daily_at: None,
+            webhook: None,
+            output_path: None,
+            format: ReportFormat::default(),
+            top: default_top(),
+            retries: default_retries(),
+        }
+    }
+}
+
+/// How a report is rendered.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportFormat {
+    #[default]
+    Json,
+    Markdown,
+}
+
+impl ApiSchema for ReportFormat {
+    fn schema() -> serde_json::Value {
+        string_enum(&["json", "markdown"])
+    }
+}
+
+impl ReportFormat {
+    /// The content type and body of `report` in this format.
+    pub fn render(self, report: &Report) -> (&'static str, Vec<u8>) {
+        match self {
+            ReportFormat::Json => {
+                ("application/json", serde_json::to_vec(report).unwrap_or_default())
+            }
+            ReportFormat::Markdown => ("text/markdown; charset=utf-8", markdown(report).into()),
+        }
+    }
+}
+
+/// A time of day, in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DailyAt(NaiveTime);
+
+impl DailyAt {
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        NaiveTime::parse_from_str(spec.trim(), "%H:%M")
+            .map(Self)
+            .map_err(|_| format!("expected HH:MM, got {:?}", spec))
+    }
+
+    /// The first occurrence strictly after `now`.
+    pub fn next_after(self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let today = now.date_naive().and_time(self.0).and_utc();
+        if today > now {
+            today
+        } else {
+            today + chrono::Duration::days(1)
+        }
+    }
+}
+
+/// A period like `24h`: a whole number of seconds (`s`), minutes (`m`),
+/// hours (`h`) or days (`d`), up to a year.
+pub fn parse_period(spec: &str) -> Result<Duration, String> {
+    let invalid = || format!("period must be like 90m, 24h or 7d, got {:?}", spec);
+    let unit = match spec.chars().last() {
+        Some('s') => 1,
+        Some('m') => 60,
+        Some('h') => 3600,
+        Some('d') => 24 * 3600,
+        _ => return Err(invalid()),
+    };
+    let count: u64 = spec[..spec.len() - 1].parse().map_err(|_| invalid())?;
+    let period = Duration::from_secs(count.saturating_mul(unit));
+    if period.is_zero() || period > MAX_PERIOD {
+        return Err(format!("period must be between 1s and 366d, got {:?}", spec));
+    }
+    Ok(period)
+}
+
+api_schema! {
+    /// A traffic summary, as served by `/api/report` and delivered daily.
+    #[derive(Debug, Clone, Serialize)]
+    pub struct Report {
+        /// The period covered, milliseconds since the Unix epoch.
+        pub from: i64,
+        pub to: i64,
+        /// Everything stored in the period.
+        pub stored: StoredTotals,
+        /// Stored bytes by source address, most first.
+        pub top_talkers: Vec<StoredTalker>,
+        /// Stored bytes by destination address, most first.
+        pub top_destinations: Vec<StoredTalker>,
+        /// Stored alerts that fired in the period, repeats folded, and how
+        /// many of them are unacknowledged.
+        pub alerts: u64,
+        pub unacked_alerts: u64,
+        /// Live connections when the report was built.
+        pub active_connections: usize,
+    }
+}
+
+/// The report for the `period` ending at `to` (epoch milliseconds), over
+/// the rows one `instance` stored or all of them.  Storage queries block,
+/// so async callers run this on the blocking pool.
+pub fn build(
+    storage: &dyn StorageBackend,
+    traffic: &TrafficState,
+    to: i64,
+    period: Duration,
+    top: usize,
+    instance: Option<String>,
+) -> StorageResult<Report> {
+    let from = to.saturating_sub(period.as_millis() as i64);
+    let filter = PacketFilter {
+        from: Some(from),
+        to: Some(to),
+        instance,
+        ..PacketFilter::default()
+    };
+    // Retention caps the alerts table, so listing every row is bounded.
+    let alerts = AlertFilter {
+        since: Some(from),
+        instance: filter.instance.clone(),
+        ..AlertFilter::default()
+    };
+    let alerts = storage.query_alerts(&alerts, i64::MAX as usize)?;
+    Ok(Report {
+        from,
+        to,
+        stored: storage.query_totals(&filter)?,
+        top_talkers: storage.query_top(TopColumn::SrcIp, &filter, top)?,
+        top_destinations: storage.query_top(TopColumn::DstIp, &filter, top)?,
+        alerts: alerts.len() as u64,
+        unacked_alerts: alerts.iter().filter(|alert| !alert.acked).count() as u64,
+        active_connections: traffic.active_connections.load(Ordering::Relaxed),
+    })
+}
+
+fn format_time(ms: i64) -> String {
+    DateTime::from_timestamp_millis(ms)
+        .map(|t| t.format("%Y-%m-%d %H:%M UTC").to_string())
+        .unwrap_or_else(|| ms.to_string())
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "kB", "MB", "GB", "TB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1000.0 && unit < UNITS.len() - 1 {
+        value /= 1000.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} B", bytes),
+        _ => format!("{:.1} {}", value, UNITS[unit]),
+    }
+}
+
+/// A short Markdown rendering for chat webhooks and mail.
+fn markdown(report: &Report) -> String {
+    let mut out = format!(
+        "# ayaFlow report\n\n{} to {}\n\n\
+         - Stored traffic: {} in {} packets\n\
+         - Alerts: {} ({} unacknowledged)\n\
+         - Active connections: {}\n",
+        format_time(report.from),
+        format_time(report.to),
+        format_bytes(report.stored.bytes),
+        report.stored.packets,
+        report.alerts,
+        report.unacked_alerts,
+        report.active_connections
+    );
+    for (title, talkers) in [
+        ("Top talkers", &report.top_talkers),
+        ("Top destinations", &report.top_destinations),
+    ] {
+        out.push_str(&format!("\n## {}\n\n", title));
+        if talkers.is_empty() {
+            out.push_str("Nothing stored.\n");
+            continue;
+        }
+        out.push_str("| Address | Bytes |\n|---|---:|\n");
+        for talker in talkers {
+            out.push_str(&format!("| {} | {} |\n", talker.key, format_bytes(talker.bytes)));
+        }
+    }
+    out
+}
+
+/// A validated `reports:` section.
+#[derive(Debug)]
+pub struct ReportSchedule {
+    at: DailyAt,
+    webhook: Option<WebhookUrl>,
+    output_path: Option<String>,
+    format: ReportFormat,
+    top: usize,
+    retries: u32,
+}
+
+impl ReportSchedule {
+    /// None when no daily report is configured.  Fails on any invalid
+    /// setting, so a typo never silently stops the reports.
+    pub fn new(config: &ReportsConfig) -> anyhow::Result<Option<Self>> {
+        let Some(at) = &config.daily_at else {
+            anyhow::ensure!(
+                config.webhook.is_none() && config.output_path.is_none(),
+                "reports: set daily_at to schedule deliveries"
+            );
+            return Ok(None);
+        };
+        let at = DailyAt::parse(at).map_err(|e| anyhow::anyhow!("reports.daily_at: {}", e))?;
+        anyhow::ensure!(
+            config.webhook.is_some() || config.output_path.is_some(),
+            "reports: set webhook or output_path to deliver the daily report"
+        );
+        anyhow::ensure!(
+            (1..=100).contains(&config.top),
+            "reports.top must be between 1 and 100"
+        );
+        Ok(Some(Self {
+            at,
+            webhook: config.webhook.as_deref().map(WebhookUrl::parse).transpose()?,
+            output_path: config.output_path.clone(),
+            format: config.format,
+            top: config.top,
+            retries: config.retries,
+        }))
+    }
+
+    /// The time of day, `HH:MM`, the report is built at.
+    pub fn at(&self) -> String {
+        self.at.0.format("%H:%M").to_string()
+    }
+
+    /// Post or write `report` to every configured destination.
+    async fn deliver(&self, report: &Report) -> anyhow::Result<()> {
+        let (content_type, body) = self.format.render(report);
+        if let Some(url) = &self.webhook {
+            hooks::post(url, content_type, &body, DELIVERY_TIMEOUT)
+                .await
+                .map_err(|e| anyhow::anyhow!("webhook: {}", e))?;
+        }
+        if let Some(path) = &self.output_path {
+            let date = DateTime::from_timestamp_millis(report.to).unwrap_or_default();
+            let path = path.replace("{date}", &date.format("%Y-%m-%d").to_string());
+            // Written aside and renamed, so readers never see half a report.
+            let partial = format!("{}.partial", path);
+            tokio::fs::write(&partial, &body).await?;
+            tokio::fs::rename(&partial, &path).await?;
+        }
+        Ok(())
+    }
+
+    /// Deliver `report`, retrying failures with backoff.  Returns whether
+    /// it was delivered.
+    async fn deliver_with_retries(&self, report: &Report, heartbeat: &Heartbeat) -> bool {
+        let mut backoff = RETRY_BACKOFF_MIN;
+        for attempt in 0..=self.retries {
+            match self.deliver(report).await {
+                Ok(()) => {
+                    heartbeat.beat();
+                    return true;
+                }
+                Err(e) if attempt < self.retries => {
+                    tracing::warn!("Report delivery failed, retrying in {:?}: {}", backoff, e);
+                    heartbeat.fail(format!("delivery failed, retrying: {}", e));
+                    sleep(backoff).await;
+                    backoff = (backoff * 2).min(RETRY_BACKOFF_MAX);
+                }
+                Err(e) => {
+                    tracing::error!("Report delivery failed, giving up: {}", e);
+                    heartbeat.fail(format!("delivery failed: {}", e));
+                }
+            }
+        }
+        false
+    }
+}
+
+/// Build and deliver the daily report at every scheduled time.
+pub async fn run_reports(
+    schedule: ReportSchedule,
+    storage: Arc<dyn StorageBackend>,
+    traffic: Arc<TrafficState>,
+    heartbeat: Heartbeat,
+) {
+    loop {
+        let now = Utc::now();
+        let next = schedule.at.next_after(now);
+        sleep((next - now).to_std().unwrap_or_default()).await;
+        // The report ends at the scheduled time, however late it runs.
+        let to = next.timestamp_millis();
+        let (storage, traffic) = (storage.clone(), traffic.clone());
+        let top = schedule.top;
+        let built = tokio::task::spawn_blocking(move || {
+            build(storage.as_ref(), &traffic, to, DEFAULT_PERIOD, top, None)
+        })
+        .await;
+        match built {
+            Ok(Ok(report)) => {
+                if schedule.deliver_with_retries(&report, &heartbeat).await {
+                    tracing::info!("Delivered the report for {}", format_time(to));
+                }
+            }
+            Ok(Err(e)) => {
+                tracing::error!("Cannot build the daily report: {}", e);
+                heartbeat.fail(format!("cannot build the report: {}", e));
+            }
+            Err(e) => heartbeat.fail(format!("report task failed: {}", e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alerts::Alert;
+    use crate::health::HealthRegistry;
+    use crate::state::PacketMetadata;
+    use crate::storage::Storage;
+    use crate::test_support;
+
+    fn packet(src_ip: &str, dst_ip: &str, timestamp: i64, length: usize) -> PacketMetadata {
+        PacketMetadata {
+            timestamp,
+            src_ip: src_ip.into(),
+            dst_ip: dst_ip.into(),
+            length,
+            payload_length: length,
+            ..test_support::packet()
+        }
+    }
+
+    fn config(yaml: &str) -> ReportsConfig {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn test_schedule_and_period() {
+        let at = DailyAt::parse("07:30").unwrap();
+        let before = "2026-10-14T06:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        let after = "2026-10-14T07:30:00Z".parse::<DateTime<Utc>>().unwrap();
+        assert_eq!(at.next_after(before).to_rfc3339(), "2026-10-14T07:30:00+00:00");
+        assert_eq!(at.next_after(after).to_rfc3339(), "2026-10-15T07:30:00+00:00");
+        assert!(DailyAt::parse("24:00").is_err());
+
+        assert_eq!(parse_period("24h"), Ok(DEFAULT_PERIOD));
+        assert_eq!(parse_period("90m"), Ok(Duration::from_secs(5400)));
+        for invalid in ["", "h", "24", "0h", "-1h", "367d", "1.5h"] {
+            assert!(parse_period(invalid).is_err(), "{:?}", invalid);
+        }
+
+        assert!(ReportSchedule::new(&ReportsConfig::default()).unwrap().is_none());
+        for invalid in [
+            "{webhook: 'http://h/'}",
+            "{daily_at: '07:00'}",
+            "{daily_at: '7am', output_path: /tmp/r.json}",
+            "{daily_at: '07:00', webhook: 'https://h/'}",
+            "{daily_at: '07:00', output_path: /tmp/r.json, top: 0}",
+        ] {
+            assert!(ReportSchedule::new(&config(invalid)).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_build_and_render() {
+        let storage = Storage::new(":memory:").unwrap();
+        storage
+            .flush(&mut vec![
+                packet("10.0.0.1", "8.8.8.8", 10_000, 1500),
+                packet("10.0.0.1", "1.1.1.1", 20_000, 500),
+                packet("10.0.0.2", "8.8.8.8", 30_000, 100),
+                // Before the period.
+                packet("10.0.0.3", "9.9.9.9", 1_000, 9000),
+            ])
+            .unwrap();
+        storage
+            .insert_alert(&Alert {
+                timestamp: 15_000,
+                rule: "ttl_below".into(),
+                severity: "warning".into(),
+                subject: "10.0.0.1".into(),
+                message: "TTL 2".into(),
+            })
+            .unwrap();
+        let traffic = TrafficState::new();
+
+        let report = build(&storage, &traffic, 40_000, Duration::from_secs(35), 1, None).unwrap();
+        assert_eq!((report.from, report.to), (5_000, 40_000));
+        assert_eq!(report.stored, StoredTotals { rows: 3, packets: 3, bytes: 2100 });
+        assert_eq!(report.top_talkers.len(), 1);
+        let talker = &report.top_talkers[0];
+        assert_eq!((talker.key.as_str(), talker.bytes), ("10.0.0.1", 2000));
+        assert_eq!(report.top_destinations[0].key, "8.8.8.8");
+        assert_eq!((report.alerts, report.unacked_alerts), (1, 1));
+        crate::openapi::assert_matches_schema(&report);
+
+        let (content_type, body) = ReportFormat::Markdown.render(&report);
+        assert!(content_type.starts_with("text/markdown"));
+        let text = String::from_utf8(body).unwrap();
+        assert!(text.contains("1970-01-01 00:00 UTC to 1970-01-01 00:00 UTC"), "{}", text);
+        assert!(text.contains("- Stored traffic: 2.1 kB in 3 packets\n"), "{}", text);
+        assert!(text.contains("| 10.0.0.1 | 2.0 kB |\n"), "{}", text);
+        assert!(text.contains("| 8.8.8.8 | 1.6 kB |\n"), "{}", text);
+
+        let empty = build(&storage, &traffic, 500, Duration::from_secs(1), 10, None).unwrap();
+        let (_, body) = ReportFormat::Markdown.render(&empty);
+        assert!(String::from_utf8(body).unwrap().contains("Nothing stored."));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_delivery_retries_then_reports_health() {
+        let dir = std::env::temp_dir().join(format!("ayaflow-report-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join("report-{date}.json");
+        let yaml = format!("{{daily_at: '07:00', output_path: '{}', retries: 2}}", path.display());
+        let schedule = ReportSchedule::new(&config(&yaml)).unwrap().unwrap();
+        let storage = Storage::new(":memory:").unwrap();
+        let report = build(&storage, &TrafficState::new(), 0, DEFAULT_PERIOD, 10, None).unwrap();
+        let registry = Arc::new(HealthRegistry::new());
+        let heartbeat = registry.register("reports", false, None);
+
+        // The directory does not exist, so every attempt fails.
+        assert!(!schedule.deliver_with_retries(&report, &heartbeat).await);
+        let (_, components) = registry.report();
+        assert!(components[0].last_error.as_deref().unwrap().starts_with("delivery failed:"));
+
+        std::fs::create_dir_all(&dir).unwrap();
+        assert!(schedule.deliver_with_retries(&report, &heartbeat).await);
+        assert_eq!(registry.report().0, crate::health::ComponentStatus::Ok);
+        let written = std::fs::read(dir.join("report-1970-01-01.json")).unwrap();
+        let written: serde_json::Value = serde_json::from_slice(&written).unwrap();
+        assert_eq!(written["to"], 0);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
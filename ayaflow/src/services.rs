@@ -0,0 +1,196 @@
+//! Service names for well-known ports, e.g. 443/TCP as "https".
+//!
+//! Names are attached when connections and history rows are served, never
+//! stored, so changing the `services:` overrides relabels old rows too.
+//! Built-in names follow the IANA service name registry, except `dns` for
+//! port 53 (registered as `domain`).
+
+use std::collections::{BTreeMap, HashMap};
+
+use ayaflow_common::ServicePortRule;
+
+use crate::state::{ConnectionEntry, PacketMetadata};
+
+/// Built-in and configured port names.
+#[derive(Debug, Default)]
+pub struct ServiceNames {
+    /// From the `services:` config map; apply to both TCP and UDP and win
+    /// over built-ins.
+    overrides: HashMap<u16, String>,
+    rule: ServicePortRule,
+}
+
+impl ServiceNames {
+    pub fn new(overrides: &BTreeMap<u16, String>) -> Self {
+        Self {
+            overrides: overrides.iter().map(|(port, name)| (*port, name.clone())).collect(),
+            rule: ServicePortRule::default(),
+        }
+    }
+
+    /// Pick service ports by `rule` rather than the lower port.
+    pub fn with_rule(mut self, rule: ServicePortRule) -> Self {
+        self.rule = rule;
+        self
+    }
+
+    /// The name of `port` for `protocol` ("TCP" or "UDP"); other protocols
+    /// have no ports to name.
+    pub fn name(&self, port: u16, protocol: &str) -> Option<&str> {
+        let builtin = match protocol {
+            "TCP" => tcp_service(port),
+            "UDP" => udp_service(port),
+            _ => return None,
+        };
+        self.overrides.get(&port).map(String::as_str).or(builtin)
+    }
+
+    /// A TCP or UDP flow's service port under the configured rule.  Other
+    /// protocols carry something else in their ports, or nothing.
+    pub fn service_port(&self, src_port: u16, dst_port: u16, protocol: &str) -> Option<u16> {
+        match protocol {
+            "TCP" | "UDP" => self.rule.service_port(src_port, dst_port),
+            _ => None,
+        }
+    }
+
+    /// The name of a flow's service port.
+    pub fn for_flow(&self, src_port: u16, dst_port: u16, protocol: &str) -> Option<String> {
+        let port = self.service_port(src_port, dst_port, protocol)?;
+        self.name(port, protocol).map(str::to_string)
+    }
+
+    pub fn label_connections(&self, entries: &mut [ConnectionEntry]) {
+        for entry in entries {
+            let key = &entry.connection;
+            entry.service = self.for_flow(key.src_port, key.dst_port, &entry.stats.protocol);
+        }
+    }
+
+    pub fn label_packets<'a>(&self, packets: impl IntoIterator<Item = &'a mut PacketMetadata>) {
+        for packet in packets {
+            packet.service = self.for_flow(packet.src_port, packet.dst_port, &packet.protocol);
+        }
+    }
+}
+
+fn tcp_service(port: u16) -> Option<&'static str> {
+    Some(match port {
+        20 => "ftp-data",
+        21 => "ftp",
+        22 => "ssh",
+        23 => "telnet",
+        25 => "smtp",
+        53 => "dns",
+        80 => "http",
+        88 => "kerberos",
+        110 => "pop3",
+        111 => "sunrpc",
+        119 => "nntp",
+        135 => "epmap",
+        139 => "netbios-ssn",
+        143 => "imap",
+        179 => "bgp",
+        389 => "ldap",
+        443 => "https",
+        445 => "microsoft-ds",
+        465 => "submissions",
+        587 => "submission",
+        636 => "ldaps",
+        873 => "rsync",
+        993 => "imaps",
+        995 => "pop3s",
+        1433 => "ms-sql-s",
+        1883 => "mqtt",
+        2049 => "nfs",
+        2379 => "etcd-client",
+        2380 => "etcd-server",
+        3306 => "mysql",
+        3389 => "ms-wbt-server",
+        3478 => "stun",
+        5432 => "postgresql",
+        5672 => "amqp",
+        5900 => "rfb",
+        6379 => "redis",
+        8080 => "http-alt",
+        8443 => "pcsync-https",
+        8883 => "secure-mqtt",
+        27017 => "mongodb",
+        _ => return None,
+    })
+}
+
+fn udp_service(port: u16) -> Option<&'static str> {
+    Some(match port {
+        53 => "dns",
+        67 => "bootps",
+        68 => "bootpc",
+        69 => "tftp",
+        88 => "kerberos",
+        123 => "ntp",
+        137 => "netbios-ns",
+        138 => "netbios-dgm",
+        161 => "snmp",
+        162 => "snmptrap",
+        389 => "ldap",
+        // HTTP/3 over QUIC.
+        443 => "https",
+        500 => "isakmp",
+        514 => "syslog",
+        546 => "dhcpv6-client",
+        547 => "dhcpv6-server",
+        1194 => "openvpn",
+        1900 => "ssdp",
+        2049 => "nfs",
+        3478 => "stun",
+        4500 => "ipsec-nat-t",
+        4789 => "vxlan",
+        5353 => "mdns",
+        5355 => "llmnr",
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin_names() {
+        let names = ServiceNames::default();
+        assert_eq!(names.name(443, "TCP"), Some("https"));
+        assert_eq!(names.name(5353, "UDP"), Some("mdns"));
+        assert_eq!(names.name(5353, "TCP"), None);
+        assert_eq!(names.name(22, "IP(47)"), None);
+        // A client's ephemeral port loses to the service port either way round.
+        assert_eq!(names.for_flow(51000, 443, "TCP").as_deref(), Some("https"));
+        assert_eq!(names.for_flow(53, 40000, "UDP").as_deref(), Some("dns"));
+        assert_eq!(names.for_flow(51000, 51001, "TCP"), None);
+        // Non-IP frames carry their ethertype as the destination port.
+        assert_eq!(names.for_flow(0, 0x0806, "ARP"), None);
+        assert_eq!(names.service_port(0, 0x0806, "ARP"), None);
+        // Both registered: the lower port names the flow either way round.
+        assert_eq!(names.for_flow(8443, 443, "TCP").as_deref(), Some("https"));
+        assert_eq!(names.service_port(51000, 60001, "UDP"), Some(51000));
+        let names = ServiceNames::default().with_rule(ServicePortRule::NonEphemeral);
+        assert_eq!(names.service_port(51000, 60001, "UDP"), None);
+        assert_eq!(names.service_port(443, 8443, "TCP"), Some(443));
+    }
+
+    #[test]
+    fn test_overrides_win_over_builtins() {
+        let overrides = BTreeMap::from([
+            (8443, "https-alt".to_string()),
+            (9000, "minio".to_string()),
+            (53, "resolver".to_string()),
+        ]);
+        let names = ServiceNames::new(&overrides);
+        assert_eq!(names.name(8443, "TCP"), Some("https-alt"));
+        assert_eq!(names.name(53, "UDP"), Some("resolver"));
+        // Overrides cover both transports, including ports with no built-in.
+        assert_eq!(names.name(9000, "UDP"), Some("minio"));
+        assert_eq!(names.name(443, "TCP"), Some("https"));
+        assert_eq!(names.name(9000, "ICMP"), None);
+        assert_eq!(names.for_flow(8443, 50000, "TCP").as_deref(), Some("https-alt"));
+    }
+}
@@ -0,0 +1,142 @@
+//! Bounded on-disk overflow for rows the database keeps refusing.
+//!
+//! Once the storage writer's flushes fail several times in a row (disk
+//! full, read-only or locked database), it appends each batch here as JSON
+//! lines instead of holding it in memory, and replays the file after the
+//! next successful write.  Records beyond `max_bytes` are dropped.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+
+use ayaflow_common::AggregationKey;
+use serde::{Deserialize, Serialize};
+
+use crate::state::{AggregatedBucket, PacketMetadata};
+
+/// One spilled row: a raw packet, or an aggregated bucket with the
+/// granularity it was keyed at.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SpillRecord {
+    Packet(PacketMetadata),
+    Bucket {
+        key: AggregationKey,
+        bucket: AggregatedBucket,
+    },
+}
+
+#[derive(Debug)]
+pub struct SpillFile {
+    path: PathBuf,
+    max_bytes: u64,
+    /// Current file size; the file may survive a restart.
+    bytes: u64,
+}
+
+impl SpillFile {
+    pub fn new(path: impl Into<PathBuf>, max_bytes: u64) -> Self {
+        let path = path.into();
+        let bytes = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+        Self { path, max_bytes, bytes }
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Append records while they fit under `max_bytes`.  Returns how many
+    /// were written; the rest are dropped.
+    pub fn append(&mut self, records: impl IntoIterator<Item = SpillRecord>) -> io::Result<usize> {
+        let mut out = Vec::new();
+        let mut written = 0;
+        for record in records {
+            let mut line = serde_json::to_vec(&record)?;
+            line.push(b'\n');
+            if self.bytes + (out.len() + line.len()) as u64 > self.max_bytes {
+                break;
+            }
+            out.extend(line);
+            written += 1;
+        }
+        if !out.is_empty() {
+            let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+            file.write_all(&out)?;
+            self.bytes += out.len() as u64;
+        }
+        Ok(written)
+    }
+
+    /// Read back every record and empty the file.  Lines that no longer
+    /// parse are skipped.
+    pub fn drain(&mut self) -> io::Result<Vec<SpillRecord>> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                self.bytes = 0;
+                return Ok(Vec::new());
+            }
+            Err(e) => return Err(e),
+        };
+        let mut records = Vec::new();
+        for line in BufReader::new(file).lines() {
+            match serde_json::from_str(&line?) {
+                Ok(record) => records.push(record),
+                Err(e) => tracing::warn!("Skipping unreadable spill record: {}", e),
+            }
+        }
+        fs::remove_file(&self.path)?;
+        self.bytes = 0;
+        Ok(records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    fn packet(timestamp: i64) -> SpillRecord {
+        SpillRecord::Packet(PacketMetadata {
+            timestamp,
+            src_ip: "10.0.0.1".into(),
+            dst_ip: "8.8.8.8".into(),
+            dst_port: 53,
+            protocol: "UDP".into(),
+            length: 80,
+            payload_length: 52,
+            direction: "egress".into(),
+            ttl: Some(64),
+            domain: Some("example.com".into()),
+            ..test_support::packet()
+        })
+    }
+
+    #[test]
+    fn test_spill_is_bounded_and_drains() {
+        let path = std::env::temp_dir().join(format!("ayaflow-spill-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let line = serde_json::to_vec(&packet(0)).unwrap().len() as u64 + 1;
+        let mut spill = SpillFile::new(&path, line * 3);
+
+        assert_eq!(spill.append((0..2).map(packet)).unwrap(), 2);
+        assert_eq!(spill.append((2..5).map(packet)).unwrap(), 1);
+        assert_eq!(spill.bytes(), line * 3);
+
+        // A restarted writer picks up where the file left off.
+        let mut spill = SpillFile::new(&path, line * 3);
+        assert_eq!(spill.bytes(), line * 3);
+        let records = spill.drain().unwrap();
+        let timestamps: Vec<i64> = records
+            .iter()
+            .map(|r| match r {
+                SpillRecord::Packet(p) => p.timestamp,
+                SpillRecord::Bucket { bucket, .. } => bucket.first_timestamp,
+            })
+            .collect();
+        assert_eq!(timestamps, vec![0, 1, 2]);
+        assert_eq!(spill.bytes(), 0);
+        assert!(!path.exists());
+        assert!(spill.drain().unwrap().is_empty());
+    }
+}
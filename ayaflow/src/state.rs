@@ -1,32 +1,42 @@
 use dashmap::DashMap;
-use serde::Serialize;
-use std::net::{Ipv4Addr, Ipv6Addr};
+use ipnet::IpNet;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
+use prometheus_client::registry::Registry;
+use serde::ser::SerializeStruct;
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::Notify;
 use tokio::time::Instant;
 
-use ayaflow_common::PacketEvent;
+use ayaflow_common::{
+    EventError, FlowCounters, FlowKey, PacketEvent, ServiceSide, ETHERTYPE_ARP, ETHERTYPE_IPV4,
+    ETHERTYPE_IPV6, TCP_ACK, TCP_FIN, TCP_RST, TCP_SYN,
+};
 
-#[derive(Debug, Clone, Serialize)]
-pub struct PacketMetadata {
-    pub timestamp: i64,
-    pub src_ip: String,
-    pub dst_ip: String,
-    pub src_port: u16,
-    pub dst_port: u16,
-    pub protocol: String,
-    pub length: usize,
-    /// Packet direction: "ingress" or "egress".
-    pub direction: String,
-    /// Reverse-DNS hostname for source IP (None when DNS resolution is disabled).
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub src_hostname: Option<String>,
-    /// Reverse-DNS hostname for destination IP (None when DNS resolution is disabled).
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub dst_hostname: Option<String>,
-    /// Domain name from DNS query or TLS SNI (None when deep_inspect is disabled).
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub domain: Option<String>,
-}
+use crate::asymmetry::AsymmetryTracker;
+use crate::blocklist::BlocklistMatch;
+use crate::bpf_stats::BpfRuntime;
+use crate::cardinality::Cardinality;
+use crate::clock::WindowClock;
+use crate::headroom::Headroom;
+use crate::icmp::{IcmpMessage, IcmpStats};
+use crate::categories::PortCategories;
+use crate::dedup::ForwardDedup;
+use crate::hooks::{HookEngine, NewConnection};
+use crate::geoip::GeoIp;
+use crate::devices::format_mac;
+use crate::locality::{Cast, FlowDirection, LocalNetworks};
+use crate::openapi::{api_schema, string_enum, ApiSchema};
+use crate::rates::{Rate, RateSampler};
+
+pub use ayaflow_common::api::{
+    CastTotals, ConnectionId, ConnectionSort, DirectionTotals, FlowDirectionTotals, PacketMetadata,
+    SortOrder, TcpState, TcpStateCounts,
+};
 
 /// Convert a 16-byte address + addr_type into a human-readable IP string.
 fn addr_to_string(raw: &[u8; 16], addr_type: u8) -> String {
@@ -38,82 +48,1137 @@ fn addr_to_string(raw: &[u8; 16], addr_type: u8) -> String {
     }
 }
 
-impl PacketMetadata {
-    /// Convert a kernel-side PacketEvent into a userspace PacketMetadata.
-    ///
-    /// IP addresses are converted from the 16-byte wire format (IPv4-mapped
-    /// or raw IPv6) to canonical string representations.
-    /// The timestamp is assigned here in userspace.
-    pub fn from_ebpf(event: &PacketEvent) -> Self {
-        let src_ip = addr_to_string(&event.src_addr, event.addr_type);
-        let dst_ip = addr_to_string(&event.dst_addr, event.addr_type);
-        let protocol = match event.protocol {
-            6 => "TCP".to_string(),
-            17 => "UDP".to_string(),
-            other => format!("IP({})", other),
-        };
-        let direction = if event.direction == 0 {
-            "ingress".to_string()
-        } else {
-            "egress".to_string()
+/// Map an IP protocol number to the name used throughout the API.
+fn protocol_name(protocol: u8) -> String {
+    match protocol {
+        6 => "TCP".to_string(),
+        17 => "UDP".to_string(),
+        1 => "ICMP".to_string(),
+        58 => "ICMPv6".to_string(),
+        other => format!("IP({})", other),
+    }
+}
+
+/// Whether `name` is one `protocol_name` or `ether_type_name` produces,
+/// ignoring case.
+pub fn is_protocol_name(name: &str) -> bool {
+    let upper = name.to_ascii_uppercase();
+    if matches!(upper.as_str(), "TCP" | "UDP" | "ICMP" | "ICMPV6" | "ARP") {
+        return true;
+    }
+    if let Some(number) = upper.strip_prefix("IP(").and_then(|n| n.strip_suffix(')')) {
+        return number.parse::<u8>().is_ok_and(|n| n.to_string() == number);
+    }
+    upper
+        .strip_prefix("ETH(0X")
+        .and_then(|n| n.strip_suffix(')'))
+        .is_some_and(|hex| hex.len() == 4 && u16::from_str_radix(hex, 16).is_ok())
+}
+
+/// Name a non-IP frame's protocol: "ARP", or "ETH(0x88cc)" style.
+fn ether_type_name(ether_type: u16) -> String {
+    match ether_type {
+        ETHERTYPE_ARP => "ARP".to_string(),
+        other => format!("ETH(0x{:04x})", other),
+    }
+}
+
+/// Name a DSCP code point: "CS0"-"CS7", "AF11"-"AF43", "EF", "VA" (voice
+/// admit) and "LE" (lower effort); anything else as "DSCP<n>".
+pub fn dscp_class_name(dscp: u8) -> String {
+    match dscp {
+        46 => "EF".to_string(),
+        44 => "VA".to_string(),
+        1 => "LE".to_string(),
+        d if d % 8 == 0 && d < 64 => format!("CS{}", d / 8),
+        d if (10..=38).contains(&d) && matches!(d % 8, 2 | 4 | 6) => {
+            format!("AF{}{}", d / 8, (d % 8) / 2)
+        }
+        d => format!("DSCP{}", d),
+    }
+}
+
+/// Map the eBPF direction tag to "ingress" / "egress".
+fn direction_name(direction: u8) -> String {
+    if direction == 0 {
+        "ingress".to_string()
+    } else {
+        "egress".to_string()
+    }
+}
+
+/// Decode a ring buffer item and convert it, naming the interface with
+/// `interface(ifindex)`.  Also returns what the kernel reported beyond
+/// the metadata.
+pub fn packet_from_bytes(
+    bytes: &[u8],
+    interface: impl FnOnce(u32) -> String,
+) -> Result<(PacketMetadata, KernelInfo), EventError> {
+    let event = PacketEvent::parse(bytes)?;
+    let meta = packet_from_ebpf(&event, interface(event.ifindex));
+    Ok((meta, KernelInfo::from_ebpf(&event)))
+}
+
+/// Convert a kernel-side PacketEvent into a userspace PacketMetadata.
+///
+/// IP addresses are converted from the 16-byte wire format (IPv4-mapped
+/// or raw IPv6) to canonical string representations.
+/// The timestamp is assigned here in userspace.
+///
+/// Non-IP frames (`capture_non_ip`) have no addresses or ports: they run
+/// from 0.0.0.0 to 0.0.0.0 with the ethertype as `dst_port`, so each
+/// ethertype is tracked as a connection of its own.
+pub fn packet_from_ebpf(event: &PacketEvent, interface: String) -> PacketMetadata {
+    if !is_ip(event) {
+        return packet_from_non_ip(event, interface);
+    }
+    let src_ip = addr_to_string(&event.src_addr, event.addr_type);
+    let dst_ip = addr_to_string(&event.dst_addr, event.addr_type);
+    let protocol = protocol_name(event.protocol);
+    let direction = direction_name(event.direction);
+    let dscp = event.tos >> 2;
+    let icmp = IcmpMessage::from_flow(&protocol, event.dst_port);
+    PacketMetadata {
+        timestamp: chrono::Utc::now().timestamp_millis(),
+        src_ip,
+        dst_ip,
+        src_port: event.src_port,
+        dst_port: event.dst_port,
+        protocol,
+        length: event.pkt_len as usize,
+        payload_length: payload_length(event),
+        direction,
+        interface,
+        flow_direction: None,
+        cast: None,
+        src_mac: format_mac(&event.src_mac),
+        dst_mac: format_mac(&event.dst_mac),
+        ttl: Some(event.ttl),
+        dscp: Some(dscp),
+        dscp_class: Some(dscp_class_name(dscp)),
+        icmp_type: icmp.map(|m| m.kind),
+        icmp_code: icmp.map(|m| m.code),
+        icmp_name: icmp.map(|m| m.name()),
+        src_hostname: None,
+        dst_hostname: None,
+        domain: None,
+        country: None,
+        asn: None,
+        service: None,
+    }
+}
+
+fn packet_from_non_ip(event: &PacketEvent, interface: String) -> PacketMetadata {
+    let unspecified = Ipv4Addr::UNSPECIFIED.to_string();
+    PacketMetadata {
+        timestamp: chrono::Utc::now().timestamp_millis(),
+        src_ip: unspecified.clone(),
+        dst_ip: unspecified,
+        src_port: 0,
+        dst_port: event.ether_type,
+        protocol: ether_type_name(event.ether_type),
+        length: event.pkt_len as usize,
+        payload_length: 0,
+        direction: direction_name(event.direction),
+        interface,
+        flow_direction: None,
+        cast: None,
+        src_mac: format_mac(&event.src_mac),
+        dst_mac: format_mac(&event.dst_mac),
+        ttl: None,
+        dscp: None,
+        dscp_class: None,
+        icmp_type: None,
+        icmp_code: None,
+        icmp_name: None,
+        src_hostname: None,
+        dst_hostname: None,
+        domain: None,
+        country: None,
+        asn: None,
+        service: None,
+    }
+}
+
+fn is_ip(event: &PacketEvent) -> bool {
+    matches!(event.ether_type, ETHERTYPE_IPV4 | ETHERTYPE_IPV6)
+}
+
+/// Transport payload carried by a kernel event.  A header that claims to be
+/// longer than the transport length it sits in counts as no payload.
+fn payload_length(event: &PacketEvent) -> usize {
+    event.l4_len.saturating_sub(u16::from(event.l4_header_len)) as usize
+}
+
+/// Typed key for the live connection table.
+///
+/// Displays as `"src_ip:src_port -> dst_ip:dst_port"`, the same string the
+/// table used to be keyed by, so API consumers see no change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ConnectionKey {
+    pub src_ip: IpAddr,
+    pub src_port: u16,
+    pub dst_ip: IpAddr,
+    pub dst_port: u16,
+}
+
+impl ConnectionKey {
+    /// Build the key for a packet.  Unparseable addresses map to the
+    /// unspecified address rather than dropping the packet.
+    pub fn from_packet(packet: &PacketMetadata) -> Self {
+        let unspecified = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+        Self {
+            src_ip: packet.src_ip.parse().unwrap_or(unspecified),
+            src_port: packet.src_port,
+            dst_ip: packet.dst_ip.parse().unwrap_or(unspecified),
+            dst_port: packet.dst_port,
+        }
+    }
+
+    /// The key of the opposite direction.
+    pub fn reversed(&self) -> Self {
+        Self {
+            src_ip: self.dst_ip,
+            src_port: self.dst_port,
+            dst_ip: self.src_ip,
+            dst_port: self.src_port,
+        }
+    }
+
+    /// Each endpoint as its address in 16-byte IPv6 form, IPv4 mapped as
+    /// `::ffff:a.b.c.d`, followed by its port in big-endian.
+    fn endpoints(&self) -> ([u8; 18], [u8; 18]) {
+        let endpoint = |ip: IpAddr, port: u16| {
+            let v6 = match ip {
+                IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+                IpAddr::V6(v6) => v6,
+            };
+            let mut bytes = [0; 18];
+            bytes[..16].copy_from_slice(&v6.octets());
+            bytes[16..].copy_from_slice(&port.to_be_bytes());
+            bytes
         };
+        (
+            endpoint(self.src_ip, self.src_port),
+            endpoint(self.dst_ip, self.dst_port),
+        )
+    }
+
+    /// This key or its reverse, whichever has the lower source endpoint.
+    pub fn canonical(&self) -> Self {
+        let (src, dst) = self.endpoints();
+        if src <= dst {
+            *self
+        } else {
+            self.reversed()
+        }
+    }
+
+    /// The [`ConnectionId`] of both directions.
+    pub fn id(&self) -> ConnectionId {
+        let (lower, higher) = self.canonical().endpoints();
+        let hash = lower.iter().chain(&higher).fold(FNV_OFFSET_BASIS, |hash, byte| {
+            (hash ^ u64::from(*byte)).wrapping_mul(FNV_PRIME)
+        });
+        ConnectionId(hash)
+    }
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+impl fmt::Display for ConnectionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{} -> {}:{}",
+            self.src_ip, self.src_port, self.dst_ip, self.dst_port
+        )
+    }
+}
+
+/// TCP sequence information and flags for one segment, used for
+/// retransmit detection and state tracking.
+#[derive(Debug, Clone, Copy)]
+pub struct TcpSegment {
+    pub seq: u32,
+    pub payload_len: u16,
+    pub flags: u8,
+}
+
+impl TcpSegment {
+    /// The segment carried by a kernel event, if it is TCP.
+    pub fn from_ebpf(event: &PacketEvent) -> Option<Self> {
+        (event.protocol == 6).then_some(Self {
+            seq: event.tcp_seq,
+            payload_len: payload_length(event) as u16,
+            flags: event.tcp_flags,
+        })
+    }
+}
+
+/// What the classifier reports about a packet beyond its metadata.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct KernelInfo {
+    pub segment: Option<TcpSegment>,
+    /// Kernel monotonic time the packet was seen at.
+    pub ktime_ns: Option<u64>,
+    /// Set when an address is on the blocklist.
+    pub blocklist: Option<BlocklistMatch>,
+}
+
+impl KernelInfo {
+    pub fn from_ebpf(event: &PacketEvent) -> Self {
         Self {
-            timestamp: chrono::Utc::now().timestamp_millis(),
-            src_ip,
-            dst_ip,
-            src_port: event.src_port,
-            dst_port: event.dst_port,
-            protocol,
-            length: event.pkt_len as usize,
-            direction,
-            src_hostname: None,
-            dst_hostname: None,
-            domain: None,
+            segment: TcpSegment::from_ebpf(event),
+            ktime_ns: (event.ktime_ns != 0).then_some(event.ktime_ns),
+            blocklist: BlocklistMatch::from_ebpf(event.blocklist),
+        }
+    }
+}
+
+/// Which connections get inter-arrival and jitter tracking: every UDP flow
+/// (voice and video ride on RTP over UDP) unless disabled, plus any flow
+/// with an end on one of `ports`.
+#[derive(Debug, Clone)]
+pub struct JitterScope {
+    udp: bool,
+    ports: HashSet<u16>,
+}
+
+impl Default for JitterScope {
+    fn default() -> Self {
+        Self::new(true, &[])
+    }
+}
+
+impl JitterScope {
+    pub fn new(udp: bool, ports: &[u16]) -> Self {
+        Self {
+            udp,
+            ports: ports.iter().copied().collect(),
         }
     }
+
+    fn tracks(&self, protocol: &str, key: &ConnectionKey) -> bool {
+        (self.udp && protocol == "UDP")
+            || self.ports.contains(&key.src_port)
+            || self.ports.contains(&key.dst_port)
+    }
+}
+
+/// The state after a segment carrying `flags`.
+fn next_tcp_state(state: Option<TcpState>, flags: u8) -> TcpState {
+    let has = |flag: u8| flags & flag != 0;
+    match state {
+        _ if has(TCP_RST) => TcpState::Closed,
+        // A bare SYN starts over even on a closed flow (port reuse).
+        _ if has(TCP_SYN) && !has(TCP_ACK) => TcpState::New,
+        None | Some(TcpState::Closed) if has(TCP_SYN) => TcpState::New,
+        // A retransmitted SYN+ACK changes nothing.
+        Some(state) if has(TCP_SYN) => state,
+        // Final ACKs and retransmitted FINs after the close.
+        Some(TcpState::Closed) => TcpState::Closed,
+        _ if has(TCP_FIN) => TcpState::Closing,
+        None | Some(TcpState::New) => TcpState::Established,
+        Some(state) => state,
+    }
 }
 
-#[derive(Debug, Serialize, Clone)]
+/// How long a closed connection stays in the live table so its last ACKs
+/// land on it instead of opening a new entry.
+pub const CLOSED_LINGER: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// `a <= b` in TCP sequence space (RFC 1982 serial number arithmetic).
+fn seq_at_or_below(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) <= 0
+}
+
+#[derive(Debug, Clone)]
 pub struct ConnectionStats {
+    pub protocol: String,
     pub bytes_sent: u64,
     pub bytes_received: u64,
     pub packets_count: u64,
-    #[serde(skip)]
+    /// Transport payload bytes in both directions.
+    pub payload_bytes: u64,
+    /// Lowest TTL / hop limit seen on this connection.
+    pub ttl_min: Option<u8>,
+    /// Highest TTL / hop limit seen on this connection.
+    pub ttl_max: Option<u8>,
+    /// TCP data segments whose sequence number did not advance.
+    pub retransmits: u32,
+    /// Interface the most recent packet was seen on.
+    pub interface: String,
+    /// Direction relative to `local_networks`.
+    pub flow_direction: Option<FlowDirection>,
+    /// Class of the destination address.
+    pub cast: Option<Cast>,
+    /// GeoIP country of the remote end, looked up when the entry was
+    /// created.
+    pub country: Option<String>,
+    /// Ethernet addresses of the most recent packet; None until a packet
+    /// with them arrives (L3 interfaces, kernel-aggregated flows).
+    pub src_mac: Option<String>,
+    pub dst_mac: Option<String>,
+    /// Highest sequence number of a data-carrying segment (TCP only).
+    tcp_max_seq: Option<u32>,
+    /// None for other protocols and kernel-aggregated flows, which carry
+    /// no flags.
+    pub tcp_state: Option<TcpState>,
+    /// What the classifier last did with a blocklisted packet of this
+    /// connection; None if it never matched.
+    pub blocklist: Option<BlocklistMatch>,
+    /// Set by the rate sampler once the reverse direction was in the table,
+    /// and kept when it expires.
+    pub bidirectional: bool,
+    /// Bytes per second over the last rate sample; written only by
+    /// `sample_rates`, so an idle connection drops to zero at the next one.
+    pub instant_bps: u64,
+    /// `total_bytes` at the last rate sample.
+    sampled_bytes: u64,
+    /// Kernel time of the latest packet, for inter-arrival tracking.
+    last_arrival_ns: Option<u64>,
+    /// Gaps folded into `mean_interarrival_ns`.
+    interarrival_gaps: u64,
+    /// Running mean of the gaps between packets.
+    mean_interarrival_ns: f64,
+    /// Smoothed absolute deviation of each gap from the mean before it, as
+    /// RFC 3550 interarrival jitter without sender timestamps.
+    jitter_ns: f64,
+    /// When the first packet was counted; not serialized.
+    pub first_seen: Instant,
+    /// Serialized as `last_seen_ms_ago`, milliseconds since the last packet.
     pub last_seen: Instant,
 }
 
 impl Default for ConnectionStats {
     fn default() -> Self {
         Self {
+            protocol: String::new(),
             bytes_sent: 0,
             bytes_received: 0,
             packets_count: 0,
+            payload_bytes: 0,
+            ttl_min: None,
+            ttl_max: None,
+            retransmits: 0,
+            interface: String::new(),
+            flow_direction: None,
+            cast: None,
+            country: None,
+            src_mac: None,
+            dst_mac: None,
+            tcp_max_seq: None,
+            tcp_state: None,
+            blocklist: None,
+            bidirectional: false,
+            instant_bps: 0,
+            sampled_bytes: 0,
+            last_arrival_ns: None,
+            interarrival_gaps: 0,
+            mean_interarrival_ns: 0.0,
+            jitter_ns: 0.0,
+            first_seen: Instant::now(),
             last_seen: Instant::now(),
         }
     }
 }
 
-/// Holds accumulated stats for a single connection within an aggregation time window.
-#[derive(Debug, Clone)]
-pub struct AggregatedBucket {
-    pub first_timestamp: i64,
-    pub src_ip: String,
-    pub dst_ip: String,
-    pub src_port: u16,
-    pub dst_port: u16,
-    pub protocol: String,
-    pub packet_count: u64,
+impl ConnectionStats {
+    pub fn total_bytes(&self) -> u64 {
+        self.bytes_sent + self.bytes_received
+    }
+
+    /// The end of `key` that is not us: the source of inbound flows, the
+    /// destination of everything else.
+    pub fn remote_ip(&self, key: &ConnectionKey) -> IpAddr {
+        match self.flow_direction {
+            Some(FlowDirection::Inbound) => key.src_ip,
+            _ => key.dst_ip,
+        }
+    }
+
+    /// Fraction of packets that were retransmissions.
+    pub fn retransmit_ratio(&self) -> f64 {
+        if self.packets_count == 0 {
+            0.0
+        } else {
+            f64::from(self.retransmits) / self.packets_count as f64
+        }
+    }
+
+    /// Mean gap between packets, once two have been timed.
+    pub fn interarrival_mean_ms(&self) -> Option<f64> {
+        (self.interarrival_gaps > 0).then(|| self.mean_interarrival_ns / 1e6)
+    }
+
+    /// Interarrival jitter, once there is a mean to deviate from.
+    pub fn jitter_ms(&self) -> Option<f64> {
+        (self.interarrival_gaps > 1).then(|| self.jitter_ns / 1e6)
+    }
+
+    /// Fold in a packet seen at kernel time `ktime_ns`.  Events from
+    /// different CPUs can reach the ring buffer slightly out of order; one
+    /// older than the latest is skipped rather than counted as a gap.
+    fn observe_arrival(&mut self, ktime_ns: u64) {
+        match self.last_arrival_ns {
+            Some(last) if ktime_ns < last => return,
+            Some(last) => {
+                let gap = (ktime_ns - last) as f64;
+                if self.interarrival_gaps > 0 {
+                    let deviation = (gap - self.mean_interarrival_ns).abs();
+                    self.jitter_ns += (deviation - self.jitter_ns) / 16.0;
+                }
+                self.interarrival_gaps += 1;
+                self.mean_interarrival_ns +=
+                    (gap - self.mean_interarrival_ns) / self.interarrival_gaps as f64;
+            }
+            None => {}
+        }
+        self.last_arrival_ns = Some(ktime_ns);
+    }
+
+    fn observe_ttl(&mut self, ttl: u8) {
+        self.ttl_min = Some(self.ttl_min.map_or(ttl, |min| min.min(ttl)));
+        self.ttl_max = Some(self.ttl_max.map_or(ttl, |max| max.max(ttl)));
+    }
+
+    /// Track the highest data sequence number; a data segment at or below
+    /// it is counted as a retransmission.  Returns true in that case.
+    fn observe_tcp_segment(&mut self, segment: TcpSegment) -> bool {
+        if segment.payload_len == 0 {
+            return false;
+        }
+        match self.tcp_max_seq {
+            Some(max) if seq_at_or_below(segment.seq, max) => {
+                self.retransmits += 1;
+                true
+            }
+            _ => {
+                self.tcp_max_seq = Some(segment.seq);
+                false
+            }
+        }
+    }
+}
+
+impl Serialize for ConnectionStats {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut st = serializer.serialize_struct("ConnectionStats", 21)?;
+        st.serialize_field("protocol", &self.protocol)?;
+        st.serialize_field("bytes_sent", &self.bytes_sent)?;
+        st.serialize_field("bytes_received", &self.bytes_received)?;
+        st.serialize_field("packets_count", &self.packets_count)?;
+        st.serialize_field("payload_bytes", &self.payload_bytes)?;
+        st.serialize_field("ttl_min", &self.ttl_min)?;
+        st.serialize_field("ttl_max", &self.ttl_max)?;
+        st.serialize_field("retransmits", &self.retransmits)?;
+        st.serialize_field("retransmit_ratio", &self.retransmit_ratio())?;
+        st.serialize_field("interface", &self.interface)?;
+        st.serialize_field("flow_direction", &self.flow_direction)?;
+        st.serialize_field("cast", &self.cast)?;
+        st.serialize_field("country", &self.country)?;
+        st.serialize_field("src_mac", &self.src_mac)?;
+        st.serialize_field("dst_mac", &self.dst_mac)?;
+        st.serialize_field("tcp_state", &self.tcp_state)?;
+        st.serialize_field("blocklist", &self.blocklist)?;
+        st.serialize_field("bidirectional", &self.bidirectional)?;
+        st.serialize_field("instant_bps", &self.instant_bps)?;
+        st.serialize_field("interarrival_mean_ms", &self.interarrival_mean_ms())?;
+        st.serialize_field("jitter_ms", &self.jitter_ms())?;
+        st.serialize_field(
+            "last_seen_ms_ago",
+            &(self.last_seen.elapsed().as_millis() as u64),
+        )?;
+        st.end()
+    }
+}
+
+/// Served as `ayaflow_common::api::ConnectionStats`.
+impl ApiSchema for ConnectionStats {
+    fn schema() -> serde_json::Value {
+        ayaflow_common::api::ConnectionStats::schema()
+    }
+}
+
+// ── Connection Queries ────────────────────────────────────────────────────────
+
+/// Filters applied to the connection table before sorting and paging.
+/// Also the body of a `/api/stream` watch subscription.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConnectionFilter {
+    /// Matches either side of the connection.
+    pub ip: Option<IpAddr>,
+    /// Matches either the source or destination port.
+    pub port: Option<u16>,
+    /// Case-insensitive protocol name ("TCP", "UDP", ...).
+    pub protocol: Option<String>,
+    /// Interface the connection was last seen on.
+    pub interface: Option<String>,
+    /// Direction relative to `local_networks`.
+    pub direction: Option<FlowDirection>,
+    /// Destination class; every class when absent.
+    pub cast: Option<Cast>,
+    /// Both directions of one connection.  On a hash collision this
+    /// matches more than one; narrow with `ip` and `port`.
+    pub connection_id: Option<ConnectionId>,
+}
+
+impl ConnectionFilter {
+    pub fn matches(&self, key: &ConnectionKey, stats: &ConnectionStats) -> bool {
+        if let Some(ip) = self.ip {
+            if key.src_ip != ip && key.dst_ip != ip {
+                return false;
+            }
+        }
+        if let Some(port) = self.port {
+            if key.src_port != port && key.dst_port != port {
+                return false;
+            }
+        }
+        if let Some(ref proto) = self.protocol {
+            if !stats.protocol.eq_ignore_ascii_case(proto) {
+                return false;
+            }
+        }
+        if let Some(ref interface) = self.interface {
+            if stats.interface != *interface {
+                return false;
+            }
+        }
+        if let Some(direction) = self.direction {
+            if stats.flow_direction != Some(direction) {
+                return false;
+            }
+        }
+        if let Some(cast) = self.cast {
+            if stats.cast != Some(cast) {
+                return false;
+            }
+        }
+        if let Some(id) = self.connection_id {
+            if key.id() != id {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// A single row of a connection query result.
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionEntry {
+    #[serde(serialize_with = "serialize_display")]
+    pub connection: ConnectionKey,
+    pub connection_id: ConnectionId,
+    pub stats: ConnectionStats,
+    /// Service name of the lower port; filled in by the API.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub service: Option<String>,
+    /// Names from the `devices:` map for the connection's MACs, and the
+    /// vendors their OUIs belong to; filled in by the API.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub src_device: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dst_device: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub src_vendor: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dst_vendor: Option<String>,
+}
+
+impl ConnectionEntry {
+    /// An entry with no names attached yet.
+    pub fn new(connection: ConnectionKey, stats: ConnectionStats) -> Self {
+        Self {
+            connection,
+            connection_id: connection.id(),
+            stats,
+            service: None,
+            src_device: None,
+            dst_device: None,
+            src_vendor: None,
+            dst_vendor: None,
+        }
+    }
+}
+
+/// Served as `ayaflow_common::api::ConnectionEntry`.
+impl ApiSchema for ConnectionEntry {
+    fn schema() -> serde_json::Value {
+        ayaflow_common::api::ConnectionEntry::schema()
+    }
+}
+
+fn serialize_display<T: fmt::Display, S: Serializer>(
+    value: &T,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(value)
+}
+
+fn serialize_display_opt<T: fmt::Display, S: Serializer>(
+    value: &Option<T>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(value) => serializer.collect_str(value),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// A page of the agent's live entries.
+pub type ConnectionPage = ayaflow_common::api::ConnectionPage<ConnectionEntry>;
+
+// ── Top Talkers ───────────────────────────────────────────────────────────────
+
+/// Grouping column for top-talker queries.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TopBy {
+    #[default]
+    SrcIp,
+    DstIp,
+    SrcSubnet,
+    DstSubnet,
+    /// The service port of TCP and UDP connections.
+    Port,
+    /// The GeoIP country of the remote end.
+    Country,
+}
+
+impl ApiSchema for TopBy {
+    fn schema() -> serde_json::Value {
+        string_enum(&["src_ip", "dst_ip", "src_subnet", "dst_subnet", "port", "country"])
+    }
+}
+
+impl TopBy {
+    fn is_source(self) -> bool {
+        matches!(self, TopBy::SrcIp | TopBy::SrcSubnet)
+    }
+
+    fn is_subnet(self) -> bool {
+        matches!(self, TopBy::SrcSubnet | TopBy::DstSubnet)
+    }
+}
+
+/// Prefix lengths used to mask addresses for `*_subnet` groupings.
+#[derive(Debug, Clone, Copy)]
+pub struct SubnetPrefixes {
+    v4: u8,
+    v6: u8,
+}
+
+impl SubnetPrefixes {
+    /// Returns `None` when either length is out of range for its family.
+    pub fn new(v4: u8, v6: u8) -> Option<Self> {
+        (v4 <= 32 && v6 <= 128).then_some(Self { v4, v6 })
+    }
+
+    /// Full-length prefixes, i.e. one group per address.
+    pub fn host() -> Self {
+        Self { v4: 32, v6: 128 }
+    }
+
+    fn mask(&self, ip: IpAddr) -> IpNet {
+        let len = match ip {
+            IpAddr::V4(_) => self.v4,
+            IpAddr::V6(_) => self.v6,
+        };
+        // Lengths are validated in `new`, so this cannot fail.
+        IpNet::new(ip, len).map(|net| net.trunc()).unwrap_or_else(|_| IpNet::from(ip))
+    }
+}
+
+api_schema! {
+    /// Totals for one address, subnet or service port in a top-talker
+    /// query.
+    #[derive(Debug, Clone, Serialize)]
+    pub struct TopTalker {
+        /// CIDR notation; per-IP groupings use a full-length prefix.
+        /// Absent for `by=port`.
+        #[serde(skip_serializing_if = "Option::is_none", serialize_with = "serialize_display_opt")]
+        pub subnet: Option<IpNet>,
+        /// Only for `by=port`.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub port: Option<u16>,
+        /// Only for `by=country`; "unknown" for remote ends GeoIP did not
+        /// place.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub country: Option<String>,
+        pub bytes: u64,
+        pub packets: u64,
+        pub connections: usize,
+        /// Distinct addresses on the grouped side seen within the subnet,
+        /// servers on the port, or remote ends in the country.
+        pub hosts: usize,
+    }
+}
+
+// ── QoS ───────────────────────────────────────────────────────────────────────
+
+/// Number of distinct DSCP code points (six bits).
+const DSCP_VALUES: usize = 64;
+
+/// Lifetime packet/byte counters for one DSCP code point or flow direction.
+#[derive(Debug, Default)]
+pub struct TrafficCounters {
+    pub packets: AtomicU64,
+    pub bytes: AtomicU64,
+}
+
+api_schema! {
+    /// Traffic observed with one DSCP marking.
+    #[derive(Debug, Clone, Serialize)]
+    pub struct QosClass {
+        pub dscp: u8,
+        /// Class name, e.g. "EF" or "AF41".
+        pub class: String,
+        pub packets: u64,
+        pub bytes: u64,
+    }
+}
+
+// ── Flow directions ───────────────────────────────────────────────────────────
+
+api_schema! {
+    /// Live-state counters and the busiest connections, for the
+    /// diagnostic dump.
+    #[derive(Debug, Clone, Serialize)]
+    pub struct TrafficSummary {
+        pub active_connections: usize,
+        pub total_packets: u64,
+        pub total_bytes: u64,
+        /// Interfaces with traffic.
+        pub interfaces: usize,
+        pub tcp_states: TcpStateCounts,
+        pub tcp_retransmits: u64,
+        pub forwarded_duplicates: u64,
+        pub kernel_flow_overflows: u64,
+        pub ring_buf_drops: u64,
+        /// The connections with the most bytes.
+        pub top_connections: Vec<ConnectionEntry>,
+    }
+}
+
+// ── Interfaces ────────────────────────────────────────────────────────────────
+
+/// Lifetime totals and recent rates, overall or for one interface.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Totals {
+    pub packets: u64,
+    pub bytes: u64,
+    pub payload_bytes: u64,
+    pub last_second: Rate,
+    pub last_minute: Rate,
+}
+
+/// From `TrafficState::churn`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ConnectionChurn {
+    pub created: u64,
+    pub expired: u64,
+    /// New connections per second over the last 1s and 60s.
+    pub created_1s: f64,
+    pub created_60s: f64,
+    /// Expired connections per second over the last 60s.
+    pub expired_60s: f64,
+}
+
+/// What `TrafficState::reset` cleared.
+#[derive(Debug, Clone, Copy)]
+pub struct ResetCounts {
+    pub packets: u64,
+    pub bytes: u64,
+    pub connections: usize,
+}
+
+/// Lifetime counters and recent rates for one interface.
+#[derive(Debug, Default)]
+pub struct InterfaceStats {
+    pub packets: AtomicU64,
+    pub bytes: AtomicU64,
+    pub payload_bytes: AtomicU64,
+    pub rates: RateSampler,
+}
+
+impl InterfaceStats {
+    fn sample_rates(&self) {
+        self.rates.record(
+            std::time::Instant::now(),
+            self.packets.load(Ordering::Relaxed),
+            self.bytes.load(Ordering::Relaxed),
+        );
+    }
+}
+
+// ── Snapshots ─────────────────────────────────────────────────────────────────
+
+/// Bumped whenever `StateSnapshot` changes shape; older snapshots are ignored.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// Upper bound on connections written to a snapshot (most recent first).
+const SNAPSHOT_MAX_CONNECTIONS: usize = 10_000;
+
+/// Persisted form of `TrafficState`, used to survive agent restarts.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StateSnapshot {
+    pub version: u32,
+    /// Milliseconds since the Unix epoch when the snapshot was taken.
+    pub saved_at: i64,
+    pub total_packets: u64,
     pub total_bytes: u64,
-    pub direction: String,
-    pub src_hostname: Option<String>,
-    pub dst_hostname: Option<String>,
-    pub domain: Option<String>,
+    /// Absent in snapshots written before payload tracking.
+    #[serde(default)]
+    pub total_payload_bytes: u64,
+    pub deep_inspect_packets: u64,
+    pub domains_resolved: u64,
+    /// Absent in snapshots written before retransmit tracking.
+    #[serde(default)]
+    pub tcp_retransmits: u64,
+    pub connections: Vec<SnapshotConnection>,
+}
+
+/// A connection in a snapshot.  `Instant` cannot be persisted, so the
+/// last-seen time is stored as idle milliseconds at `saved_at`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SnapshotConnection {
+    pub key: ConnectionKey,
+    pub protocol: String,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub packets_count: u64,
+    #[serde(default)]
+    pub payload_bytes: u64,
+    pub ttl_min: Option<u8>,
+    pub ttl_max: Option<u8>,
+    #[serde(default)]
+    pub retransmits: u32,
+    #[serde(default)]
+    pub interface: String,
+    #[serde(default)]
+    pub tcp_state: Option<TcpState>,
+    pub idle_ms: u64,
+}
+
+const DAY_MS: i64 = 86_400_000;
+
+api_schema! {
+    /// Traffic with one remote address on one UTC day, folded in from
+    /// connections as they are cleaned up.
+    #[derive(Debug, Clone, PartialEq, Serialize)]
+    pub struct PeerTotals {
+        pub ip: String,
+        /// Day start, milliseconds since the Unix epoch.
+        pub day: i64,
+        /// First and last packet, milliseconds since the Unix epoch.
+        pub first_seen: i64,
+        pub last_seen: i64,
+        pub bytes: u64,
+        pub packets: u64,
+        /// Connections (one per direction) folded into the row.
+        pub connections: u64,
+    }
+}
+
+/// Stale entries removed per cleanup step.
+pub const CLEANUP_CHUNK: usize = 4096;
+
+/// Connection cleanup instrumentation, exported as
+/// `ayaflow_connection_cleanup_*`.
+#[derive(Debug)]
+pub struct CleanupMetrics {
+    /// Wall time of a whole pass, yields included.
+    pub duration_seconds: Histogram,
+    /// Longest single step of a pass: one shard scan or one chunk of
+    /// removals.  This is how long the packet path can wait on a shard.
+    pub pause_seconds: Histogram,
+    /// Entries removed per pass.
+    pub removed: Histogram,
+}
+
+impl Default for CleanupMetrics {
+    fn default() -> Self {
+        Self {
+            // 0.1ms .. ~3s
+            duration_seconds: Histogram::new(exponential_buckets(0.0001, 2.0, 15)),
+            // 10us .. ~0.3s
+            pause_seconds: Histogram::new(exponential_buckets(0.00001, 2.0, 15)),
+            // 1 .. ~1M entries
+            removed: Histogram::new(exponential_buckets(1.0, 4.0, 11)),
+        }
+    }
+}
+
+impl CleanupMetrics {
+    pub fn register(&self, registry: &mut Registry) {
+        registry.register(
+            "ayaflow_connection_cleanup_duration_seconds",
+            "Time taken by one stale-connection cleanup pass",
+            self.duration_seconds.clone(),
+        );
+        registry.register(
+            "ayaflow_connection_cleanup_pause_seconds",
+            "Longest step of a cleanup pass holding connection table shards",
+            self.pause_seconds.clone(),
+        );
+        registry.register(
+            "ayaflow_connection_cleanup_removed",
+            "Connections removed per cleanup pass",
+            self.removed.clone(),
+        );
+    }
+}
+
+/// One pass of the stale-connection cleanup.  Each step either scans one
+/// shard of the connection table for stale keys, or removes up to
+/// `CLEANUP_CHUNK` of the keys found; no step holds more than one shard.
+struct CleanupPass {
+    timeout: tokio::time::Duration,
+    now: Instant,
+    now_ms: i64,
+    started: std::time::Instant,
+    longest_step: std::time::Duration,
+    next_shard: usize,
+    pending: Vec<ConnectionKey>,
+    peers: HashMap<(IpAddr, i64), PeerTotals>,
+    removed: usize,
+}
+
+impl CleanupPass {
+    fn new(timeout: tokio::time::Duration) -> Self {
+        Self {
+            timeout,
+            now: Instant::now(),
+            now_ms: chrono::Utc::now().timestamp_millis(),
+            started: std::time::Instant::now(),
+            longest_step: std::time::Duration::ZERO,
+            next_shard: 0,
+            pending: Vec::new(),
+            peers: HashMap::new(),
+            removed: 0,
+        }
+    }
+
+    /// Idle past the timeout, or closed and quiet for `CLOSED_LINGER`.
+    fn is_stale(&self, stats: &ConnectionStats) -> bool {
+        let idle = self.now.saturating_duration_since(stats.last_seen);
+        idle > self.timeout || (stats.tcp_state == Some(TcpState::Closed) && idle > CLOSED_LINGER)
+    }
+
+    /// Run one step; false once every shard has been scanned and emptied.
+    fn step(&mut self, state: &TrafficState) -> bool {
+        let step_started = std::time::Instant::now();
+        if self.pending.is_empty() {
+            let Some(shard) = state.connections.shards().get(self.next_shard) else {
+                return false;
+            };
+            self.next_shard += 1;
+            let stale: Vec<ConnectionKey> = shard
+                .read()
+                .iter()
+                .filter(|(_, stats)| self.is_stale(stats.get()))
+                .map(|(key, _)| *key)
+                .collect();
+            self.pending = stale;
+        } else {
+            let chunk = self.pending.split_off(self.pending.len().saturating_sub(CLEANUP_CHUNK));
+            let mut removed = 0;
+            for key in chunk {
+                // A packet may have arrived since the scan.
+                if let Some((key, stats)) =
+                    state.connections.remove_if(&key, |_, stats| self.is_stale(stats))
+                {
+                    self.fold(&key, &stats);
+                    state.asymmetry.record_expired(&stats, self.now);
+                    removed += 1;
+                }
+            }
+            state.active_connections.fetch_sub(removed, Ordering::Relaxed);
+            state.connections_expired.fetch_add(removed as u64, Ordering::Relaxed);
+            if removed > 0 {
+                state.bump_generation();
+            }
+            self.removed += removed;
+        }
+        self.longest_step = self.longest_step.max(step_started.elapsed());
+        true
+    }
+
+    fn fold(&mut self, key: &ConnectionKey, stats: &ConnectionStats) {
+        let wall_ms =
+            |at: Instant| self.now_ms - self.now.saturating_duration_since(at).as_millis() as i64;
+        let (first_seen, last_seen) = (wall_ms(stats.first_seen), wall_ms(stats.last_seen));
+        let day = last_seen - last_seen.rem_euclid(DAY_MS);
+        let ip = stats.remote_ip(key);
+        let peer = self.peers.entry((ip, day)).or_insert_with(|| PeerTotals {
+            ip: ip.to_string(),
+            day,
+            first_seen,
+            last_seen,
+            bytes: 0,
+            packets: 0,
+            connections: 0,
+        });
+        peer.first_seen = peer.first_seen.min(first_seen);
+        peer.last_seen = peer.last_seen.max(last_seen);
+        peer.bytes += stats.total_bytes();
+        peer.packets += stats.packets_count;
+        peer.connections += 1;
+    }
+
+    fn finish(self, metrics: &CleanupMetrics) -> Vec<PeerTotals> {
+        metrics.duration_seconds.observe(self.started.elapsed().as_secs_f64());
+        metrics.pause_seconds.observe(self.longest_step.as_secs_f64());
+        metrics.removed.observe(self.removed as f64);
+        self.peers.into_values().collect()
+    }
+}
+
+api_schema! {
+    /// Holds accumulated stats for a single connection within an aggregation time window.
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct AggregatedBucket {
+        pub first_timestamp: i64,
+        /// Bounds of the aggregation window, in epoch ms (end exclusive).
+        pub window_start: i64,
+        pub window_end: i64,
+        pub src_ip: String,
+        pub dst_ip: String,
+        pub src_port: u16,
+        pub dst_port: u16,
+        pub protocol: String,
+        pub packet_count: u64,
+        pub total_bytes: u64,
+        pub payload_bytes: u64,
+        pub direction: String,
+        /// Absent in buckets spilled before flow directions were recorded.
+        #[serde(default)]
+        pub flow_direction: Option<FlowDirection>,
+        pub interface: String,
+        pub src_hostname: Option<String>,
+        pub dst_hostname: Option<String>,
+        pub domain: Option<String>,
+        /// GeoIP country and ASN of the remote end, as on `PacketMetadata`;
+        /// absent in buckets spilled before GeoIP.
+        #[serde(default)]
+        pub country: Option<String>,
+        #[serde(default)]
+        pub asn: Option<u32>,
+    }
 }
 
 impl AggregatedBucket {
+    /// A bucket holding one packet, with a zero-length window at its
+    /// timestamp until the caller assigns the real one.
     pub fn from_packet(packet: &PacketMetadata) -> Self {
         Self {
             first_timestamp: packet.timestamp,
+            window_start: packet.timestamp,
+            window_end: packet.timestamp,
             src_ip: packet.src_ip.clone(),
             dst_ip: packet.dst_ip.clone(),
             src_port: packet.src_port,
@@ -121,110 +1186,1075 @@ impl AggregatedBucket {
             protocol: packet.protocol.clone(),
             packet_count: 1,
             total_bytes: packet.length as u64,
+            payload_bytes: packet.payload_length as u64,
             direction: packet.direction.clone(),
+            flow_direction: packet.flow_direction,
+            interface: packet.interface.clone(),
             src_hostname: packet.src_hostname.clone(),
             dst_hostname: packet.dst_hostname.clone(),
             domain: packet.domain.clone(),
+            country: packet.country.clone(),
+            asn: packet.asn,
         }
     }
 
+    /// Build a bucket from a swept kernel flow-map entry.
+    ///
+    /// Kernel aggregation keeps no per-packet timestamps, so the caller
+    /// passes the sweep window (whose start doubles as `first_timestamp`)
+    /// along with the name of the key's interface.
+    pub fn from_flow(
+        key: &FlowKey,
+        counters: &FlowCounters,
+        window_start: i64,
+        window_end: i64,
+        interface: String,
+    ) -> Self {
+        Self {
+            first_timestamp: window_start,
+            window_start,
+            window_end,
+            src_ip: addr_to_string(&key.src_addr, key.addr_type),
+            dst_ip: addr_to_string(&key.dst_addr, key.addr_type),
+            src_port: key.src_port,
+            dst_port: key.dst_port,
+            protocol: protocol_name(key.protocol),
+            packet_count: counters.packets,
+            total_bytes: counters.bytes,
+            payload_bytes: counters.payload_bytes,
+            direction: direction_name(key.direction),
+            flow_direction: None,
+            interface,
+            src_hostname: None,
+            dst_hostname: None,
+            domain: None,
+            country: None,
+            asn: None,
+        }
+    }
+
+    /// Add `packet` to the bucket.  A hostname missing so far is taken from
+    /// it, since a lookup that finishes mid-window names later packets only;
+    /// once set, a hostname is kept.
     pub fn merge(&mut self, packet: &PacketMetadata) {
         self.packet_count += 1;
         self.total_bytes += packet.length as u64;
+        self.payload_bytes += packet.payload_length as u64;
+        if self.src_hostname.is_none() {
+            self.src_hostname.clone_from(&packet.src_hostname);
+        }
+        if self.dst_hostname.is_none() {
+            self.dst_hostname.clone_from(&packet.dst_hostname);
+        }
     }
 }
 
+fn category_counters(categories: &PortCategories) -> Vec<TrafficCounters> {
+    (0..categories.len()).map(|_| TrafficCounters::default()).collect()
+}
+
 pub struct TrafficState {
-    pub connections: DashMap<String, ConnectionStats>,
+    pub connections: DashMap<ConnectionKey, ConnectionStats>,
     pub total_packets: AtomicU64,
     pub total_bytes: AtomicU64,
+    /// Transport payload bytes, a subset of `total_bytes`.
+    pub total_payload_bytes: AtomicU64,
     pub active_connections: AtomicUsize,
+    /// Connection entries inserted, and removed by the stale-connection
+    /// cleanup.  Entries restored from a snapshot count
+    /// in neither.
+    pub connections_created: AtomicU64,
+    pub connections_expired: AtomicU64,
+    pub cleanup_metrics: CleanupMetrics,
+    /// Recent samples of the two, for churn rates.  Recorded as packets
+    /// (created) and bytes (expired).
+    churn_rates: RateSampler,
     /// Total L7 payload events received from eBPF (only when deep_inspect is on).
     pub deep_inspect_packets: AtomicU64,
     /// Total domains successfully resolved from DNS/TLS SNI.
     pub domains_resolved: AtomicU64,
+    /// Kernel flow-map inserts dropped because the map was full (only with
+    /// kernel aggregation).  Mirrors the summed per-CPU kernel counter.
+    pub kernel_flow_overflows: AtomicU64,
+    /// Packet events the kernel dropped because the ring buffer was full
+    /// (only without kernel aggregation).  Mirrors a kernel counter.
+    pub ring_buf_drops: AtomicU64,
+    /// Bytes in the kernel's packet event ring buffer as loaded; 0 until
+    /// the eBPF programs are.
+    pub ring_buf_size_bytes: AtomicU64,
+    /// Ring buffer items skipped because their header did not match this
+    /// build's `PacketEvent`, e.g. from a stale eBPF object.
+    pub malformed_events: AtomicU64,
+    /// Kernel runs and runtime of the eBPF programs, when `bpf_stats` is
+    /// available.
+    pub bpf_runtime: BpfRuntime,
+    /// Ring buffer drain peaks and the last capacity headroom report.
+    pub headroom: Headroom,
+    /// Packets with a blocklisted address, dropped or not (per-packet
+    /// events only).
+    pub blocklisted: TrafficCounters,
+    /// Packets the kernel dropped for the blocklist.  Mirrors a kernel
+    /// counter.
+    pub blocklist_drops: AtomicU64,
+    /// TCP retransmissions detected across all connections.
+    pub tcp_retransmits: AtomicU64,
+    /// Second sightings of forwarded or mirrored packets left uncounted
+    /// (only with `count_forwarded_once`).
+    pub forwarded_duplicates: AtomicU64,
+    /// Set when `count_forwarded_once` is on.
+    forward_dedup: Option<ForwardDedup>,
+    /// Recent samples of the packet/byte totals for windowed rates.
+    pub rates: RateSampler,
+    /// When connection rates were last sampled.
+    connections_sampled_at: std::sync::Mutex<Option<Instant>>,
+    /// Per-DSCP counters, indexed by code point.  Aggregated buckets carry
+    /// no DSCP and are not counted here.
+    pub qos: [TrafficCounters; DSCP_VALUES],
+    /// Totals per interface name.  Traffic with an unknown interface is only
+    /// in the global totals.
+    pub interfaces: DashMap<String, InterfaceStats>,
+    /// Distinct source and destination addresses per minute.
+    pub cardinality: Cardinality,
+    /// Wall clock for the cardinality minutes, kernel sweeps and fleet
+    /// pushes; read every second by the sampler, so it notices a step soon.
+    pub clock: WindowClock,
+    /// ICMP totals per type and recent senders of path errors.
+    pub icmp: IcmpStats,
+    /// Flows seen in one direction only.
+    pub asymmetry: AsymmetryTracker,
+    /// What counts as local for `flow_direction`.
+    local_networks: LocalNetworks,
+    /// Country and ASN of each flow's remote end, when configured.
+    geoip: Option<GeoIp>,
+    /// Rules run on every new connection entry.
+    hooks: Option<HookEngine>,
+    /// Connections whose packet timing is tracked.
+    jitter: JitterScope,
+    /// Totals per flow direction, indexed as `FlowDirection::ALL`.
+    pub flow_directions: [TrafficCounters; 4],
+    /// Totals per destination class, indexed by `Cast`.
+    pub casts: [TrafficCounters; 3],
+    /// Application categories by service port.
+    categories: Arc<PortCategories>,
+    /// Totals per category, indexed by category id.
+    category_counters: Vec<TrafficCounters>,
+    /// Number of times `reset` has run, so exporters can tell a reset from
+    /// counters that merely have not moved.
+    pub resets: AtomicU64,
+    /// Bumped whenever the live connection table changes; `/api/live`
+    /// serves it as its ETag.
+    generation: AtomicU64,
+    /// Requests in `wait_for_change`.  Changes only notify while there are
+    /// any, so an unwatched table costs a counter bump per packet.
+    waiters: AtomicUsize,
+    changed: Notify,
 }
 
 impl TrafficState {
     pub fn new() -> Self {
+        let categories = Arc::new(PortCategories::default());
         Self {
             connections: DashMap::new(),
             total_packets: AtomicU64::new(0),
             total_bytes: AtomicU64::new(0),
+            total_payload_bytes: AtomicU64::new(0),
             active_connections: AtomicUsize::new(0),
+            connections_created: AtomicU64::new(0),
+            connections_expired: AtomicU64::new(0),
+            cleanup_metrics: CleanupMetrics::default(),
+            churn_rates: RateSampler::new(),
             deep_inspect_packets: AtomicU64::new(0),
             domains_resolved: AtomicU64::new(0),
+            kernel_flow_overflows: AtomicU64::new(0),
+            ring_buf_drops: AtomicU64::new(0),
+            ring_buf_size_bytes: AtomicU64::new(0),
+            malformed_events: AtomicU64::new(0),
+            bpf_runtime: BpfRuntime::default(),
+            headroom: Headroom::default(),
+            blocklisted: TrafficCounters::default(),
+            blocklist_drops: AtomicU64::new(0),
+            tcp_retransmits: AtomicU64::new(0),
+            forwarded_duplicates: AtomicU64::new(0),
+            forward_dedup: None,
+            rates: RateSampler::new(),
+            connections_sampled_at: std::sync::Mutex::new(None),
+            qos: std::array::from_fn(|_| TrafficCounters::default()),
+            interfaces: DashMap::new(),
+            cardinality: Cardinality::default(),
+            clock: WindowClock::default(),
+            icmp: IcmpStats::default(),
+            asymmetry: AsymmetryTracker::default(),
+            local_networks: LocalNetworks::default(),
+            geoip: None,
+            hooks: None,
+            jitter: JitterScope::default(),
+            flow_directions: std::array::from_fn(|_| TrafficCounters::default()),
+            casts: std::array::from_fn(|_| TrafficCounters::default()),
+            category_counters: category_counters(&categories),
+            categories,
+            resets: AtomicU64::new(0),
+            generation: AtomicU64::new(0),
+            waiters: AtomicUsize::new(0),
+            changed: Notify::new(),
+        }
+    }
+
+    /// Changes when anything `/api/live` serves may have changed.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    fn bump_generation(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        if self.waiters.load(Ordering::SeqCst) > 0 {
+            self.changed.notify_waiters();
+        }
+    }
+
+    /// Wait until the generation moves on from `seen`, at most `timeout`.
+    /// Returns the generation then.
+    pub async fn wait_for_change(&self, seen: u64, timeout: tokio::time::Duration) -> u64 {
+        struct Waiting<'a>(&'a AtomicUsize);
+        impl Drop for Waiting<'_> {
+            fn drop(&mut self) {
+                self.0.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+
+        self.waiters.fetch_add(1, Ordering::SeqCst);
+        let _waiting = Waiting(&self.waiters);
+        let _ = tokio::time::timeout(timeout, async {
+            loop {
+                // Registered before the check, so a bump after it wakes us.
+                let notified = self.changed.notified();
+                tokio::pin!(notified);
+                notified.as_mut().enable();
+                if self.generation() != seen {
+                    return;
+                }
+                notified.await;
+            }
+        })
+        .await;
+        self.generation()
+    }
+
+    /// Count a packet seen at two capture points within `window` (forwarded,
+    /// or mirrored back) only once, at the first.
+    pub fn with_forward_dedup(mut self, window: std::time::Duration) -> Self {
+        self.forward_dedup = Some(ForwardDedup::new(window));
+        self
+    }
+
+    pub fn with_local_networks(mut self, local_networks: LocalNetworks) -> Self {
+        self.local_networks = local_networks;
+        self
+    }
+
+    pub fn with_geoip(mut self, geoip: GeoIp) -> Self {
+        self.geoip = Some(geoip);
+        self
+    }
+
+    pub fn with_hooks(mut self, hooks: HookEngine) -> Self {
+        self.hooks = Some(hooks);
+        self
+    }
+
+    pub fn with_jitter(mut self, jitter: JitterScope) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    pub fn with_asymmetry(mut self, asymmetry: AsymmetryTracker) -> Self {
+        self.asymmetry = asymmetry;
+        self
+    }
+
+    pub fn with_categories(mut self, categories: Arc<PortCategories>) -> Self {
+        self.category_counters = category_counters(&categories);
+        self.categories = categories;
+        self
+    }
+
+    pub fn categories(&self) -> &PortCategories {
+        &self.categories
+    }
+
+    /// The direction of traffic from `src_ip` to `dst_ip` relative to the
+    /// local networks.
+    pub fn flow_direction(&self, src_ip: &str, dst_ip: &str) -> FlowDirection {
+        self.local_networks.classify_str(src_ip, dst_ip)
+    }
+
+    /// Whether traffic to `dst_ip` is unicast, multicast or broadcast.
+    pub fn cast(&self, dst_ip: &str) -> Cast {
+        self.local_networks.cast_str(dst_ip)
+    }
+
+    pub fn geoip(&self) -> Option<&GeoIp> {
+        self.geoip.as_ref()
+    }
+
+    /// Feed the current totals to the rate sampler.  Called once a second by
+    /// the sampler task.
+    pub fn sample_rates(&self) {
+        self.rates.record(
+            std::time::Instant::now(),
+            self.total_packets.load(Ordering::Relaxed),
+            self.total_bytes.load(Ordering::Relaxed),
+        );
+        for entry in self.interfaces.iter() {
+            entry.sample_rates();
+        }
+        self.churn_rates.record(
+            std::time::Instant::now(),
+            self.connections_created.load(Ordering::Relaxed),
+            self.connections_expired.load(Ordering::Relaxed),
+        );
+        self.sample_connection_rates();
+        self.cardinality.tick(self.clock.now_ms());
+    }
+
+    /// Set each connection's `instant_bps` from the bytes it moved since the
+    /// previous sample.  The first sample only records a baseline.  Also
+    /// marks connections whose reverse direction showed up as
+    /// `bidirectional`, looking the reverse up once no shard is held.
+    fn sample_connection_rates(&self) {
+        let now = Instant::now();
+        let elapsed = self
+            .connections_sampled_at
+            .lock()
+            .unwrap()
+            .replace(now)
+            .map(|at| now.duration_since(at).as_secs_f64());
+        let mut changed = false;
+        let mut unpaired = Vec::new();
+        for mut entry in self.connections.iter_mut() {
+            if !entry.bidirectional {
+                unpaired.push(*entry.key());
+            }
+            let stats = entry.value_mut();
+            let total = stats.total_bytes();
+            let delta = total.saturating_sub(stats.sampled_bytes);
+            stats.sampled_bytes = total;
+            let instant_bps = match elapsed {
+                Some(secs) if secs > 0.0 => (delta as f64 / secs) as u64,
+                _ => 0,
+            };
+            changed |= stats.instant_bps != instant_bps;
+            stats.instant_bps = instant_bps;
+        }
+        for key in unpaired {
+            if self.connections.contains_key(&key.reversed()) {
+                if let Some(mut stats) = self.connections.get_mut(&key) {
+                    stats.bidirectional = true;
+                    changed = true;
+                }
+            }
+        }
+        if changed {
+            self.bump_generation();
+        }
+    }
+
+    #[cfg(test)]
+    pub fn update(&self, packet: &PacketMetadata) -> bool {
+        self.update_with_segment(packet, None)
+    }
+
+    /// As `update_from_kernel`, without a kernel timestamp.
+    #[cfg(test)]
+    pub fn update_with_segment(
+        &self,
+        packet: &PacketMetadata,
+        segment: Option<TcpSegment>,
+    ) -> bool {
+        self.update_from_kernel(packet, KernelInfo { segment, ..KernelInfo::default() })
+    }
+
+    /// Record a packet, running retransmit detection on its TCP segment and
+    /// jitter tracking on its kernel timestamp when the classifier reported
+    /// them.  Returns false, recording nothing, when the packet is a
+    /// duplicate sighting under `count_forwarded_once`.
+    pub fn update_from_kernel(&self, packet: &PacketMetadata, kernel: KernelInfo) -> bool {
+        if let Some(dedup) = &self.forward_dedup {
+            if dedup.is_duplicate(packet, std::time::Instant::now()) {
+                self.forwarded_duplicates.fetch_add(1, Ordering::Relaxed);
+                return false;
+            }
+        }
+        let key = ConnectionKey::from_packet(packet);
+        let is_egress = packet.direction == "egress";
+        let flow_direction = packet
+            .flow_direction
+            .unwrap_or_else(|| self.local_networks.classify(&key.src_ip, &key.dst_ip));
+        let cast = packet.cast.unwrap_or_else(|| self.local_networks.cast(&key.dst_ip));
+        self.record(
+            key,
+            &packet.protocol,
+            is_egress,
+            flow_direction,
+            cast,
+            &packet.interface,
+            1,
+            packet.length as u64,
+            packet.payload_length as u64,
+            packet.ttl,
+            kernel,
+            packet.src_mac.as_deref().zip(packet.dst_mac.as_deref()),
+            packet.country.as_deref(),
+        );
+        if let Some(counters) = packet.dscp.and_then(|dscp| self.qos.get(dscp as usize)) {
+            counters.packets.fetch_add(1, Ordering::Relaxed);
+            counters.bytes.fetch_add(packet.length as u64, Ordering::Relaxed);
+        }
+        true
+    }
+
+    /// Fold a pre-aggregated bucket (kernel aggregation sweep) into the live
+    /// state, exactly as if its packets had arrived one by one.
+    pub fn apply_bucket(&self, bucket: &AggregatedBucket) {
+        let key = ConnectionKey {
+            src_ip: bucket.src_ip.parse().unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            src_port: bucket.src_port,
+            dst_ip: bucket.dst_ip.parse().unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            dst_port: bucket.dst_port,
+        };
+        let is_egress = bucket.direction == "egress";
+        let flow_direction = bucket
+            .flow_direction
+            .unwrap_or_else(|| self.local_networks.classify(&key.src_ip, &key.dst_ip));
+        self.record(
+            key,
+            &bucket.protocol,
+            is_egress,
+            flow_direction,
+            self.local_networks.cast(&key.dst_ip),
+            &bucket.interface,
+            bucket.packet_count,
+            bucket.total_bytes,
+            bucket.payload_bytes,
+            None,
+            KernelInfo::default(),
+            None,
+            bucket.country.as_deref(),
+        );
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn record(
+        &self,
+        key: ConnectionKey,
+        protocol: &str,
+        is_egress: bool,
+        flow_direction: FlowDirection,
+        cast: Cast,
+        interface: &str,
+        packets: u64,
+        bytes: u64,
+        payload_bytes: u64,
+        ttl: Option<u8>,
+        kernel: KernelInfo,
+        macs: Option<(&str, &str)>,
+        country: Option<&str>,
+    ) {
+        let segment = kernel.segment;
+        let mut is_new = false;
+        let mut stats = self.connections.entry(key).or_insert_with(|| {
+            is_new = true;
+            self.active_connections.fetch_add(1, Ordering::Relaxed);
+            self.connections_created.fetch_add(1, Ordering::Relaxed);
+            ConnectionStats {
+                protocol: protocol.to_string(),
+                country: country.map(str::to_string),
+                ..Default::default()
+            }
+        });
+        stats.packets_count += packets;
+        stats.payload_bytes += payload_bytes;
+        if is_egress {
+            stats.bytes_sent += bytes;
+        } else {
+            stats.bytes_received += bytes;
+        }
+        if let Some(ttl) = ttl {
+            stats.observe_ttl(ttl);
+        }
+        if let Some(segment) = segment {
+            if stats.observe_tcp_segment(segment) {
+                self.tcp_retransmits.fetch_add(1, Ordering::Relaxed);
+            }
+            stats.tcp_state = Some(next_tcp_state(stats.tcp_state, segment.flags));
+        }
+        if let Some(ktime_ns) = kernel.ktime_ns.filter(|_| self.jitter.tracks(protocol, &key)) {
+            stats.observe_arrival(ktime_ns);
+        }
+        if let Some(verdict) = kernel.blocklist {
+            stats.blocklist = Some(verdict);
+            self.blocklisted.packets.fetch_add(packets, Ordering::Relaxed);
+            self.blocklisted.bytes.fetch_add(bytes, Ordering::Relaxed);
+        }
+        if stats.interface != interface {
+            stats.interface = interface.to_string();
+        }
+        stats.flow_direction = Some(flow_direction);
+        stats.cast = Some(cast);
+        if let Some((src_mac, dst_mac)) = macs {
+            if stats.src_mac.as_deref() != Some(src_mac) {
+                stats.src_mac = Some(src_mac.to_string());
+            }
+            if stats.dst_mac.as_deref() != Some(dst_mac) {
+                stats.dst_mac = Some(dst_mac.to_string());
+            }
+        }
+        stats.last_seen = Instant::now();
+        let tcp_state = stats.tcp_state;
+        drop(stats);
+        if let Some(hooks) = self.hooks.as_ref().filter(|_| is_new) {
+            hooks.on_new_connection(&NewConnection {
+                key,
+                protocol,
+                flow_direction,
+                interface,
+            });
+        }
+        if let (Some(state), Some(segment)) = (tcp_state, segment) {
+            if segment.flags & (TCP_FIN | TCP_RST) != 0 {
+                self.close_pair(key, state);
+            }
+        }
+        self.cardinality.observe(&key.src_ip, &key.dst_ip);
+        if let Some(message) = IcmpMessage::from_flow(protocol, key.dst_port) {
+            self.icmp.observe(message, key.src_ip, packets, bytes);
+        }
+
+        self.total_packets.fetch_add(packets, Ordering::Relaxed);
+        self.total_bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.total_payload_bytes
+            .fetch_add(payload_bytes, Ordering::Relaxed);
+        let direction = &self.flow_directions[flow_direction as usize];
+        for counters in [direction, &self.casts[cast as usize]] {
+            counters.packets.fetch_add(packets, Ordering::Relaxed);
+            counters.bytes.fetch_add(bytes, Ordering::Relaxed);
+        }
+        let category = self.categories.for_flow(key.src_port, key.dst_port);
+        let counters = &self.category_counters[category];
+        counters.packets.fetch_add(packets, Ordering::Relaxed);
+        counters.bytes.fetch_add(bytes, Ordering::Relaxed);
+        if !interface.is_empty() {
+            let counters = match self.interfaces.get(interface) {
+                Some(counters) => counters,
+                None => self.interfaces.entry(interface.to_string()).or_default().downgrade(),
+            };
+            counters.packets.fetch_add(packets, Ordering::Relaxed);
+            counters.bytes.fetch_add(bytes, Ordering::Relaxed);
+            counters
+                .payload_bytes
+                .fetch_add(payload_bytes, Ordering::Relaxed);
+        }
+        self.bump_generation();
+    }
+
+    /// Zero the lifetime counters, per-interface and per-DSCP totals, and
+    /// drop every live connection, as if the agent had just started.
+    /// Kernel flow-map overflows and ring buffer drops mirror kernel counters
+    /// and are kept.
+    pub fn reset(&self) -> ResetCounts {
+        let packets = self.total_packets.swap(0, Ordering::Relaxed);
+        let bytes = self.total_bytes.swap(0, Ordering::Relaxed);
+        self.total_payload_bytes.store(0, Ordering::Relaxed);
+        self.deep_inspect_packets.store(0, Ordering::Relaxed);
+        self.domains_resolved.store(0, Ordering::Relaxed);
+        self.tcp_retransmits.store(0, Ordering::Relaxed);
+        self.forwarded_duplicates.store(0, Ordering::Relaxed);
+        self.connections_created.store(0, Ordering::Relaxed);
+        self.connections_expired.store(0, Ordering::Relaxed);
+        let counters = self.qos.iter().chain(&self.flow_directions).chain(&self.casts);
+        let counters = counters.chain(&self.category_counters);
+        for counters in counters.chain([&self.blocklisted]) {
+            counters.packets.store(0, Ordering::Relaxed);
+            counters.bytes.store(0, Ordering::Relaxed);
+        }
+        self.interfaces.clear();
+        self.rates.clear();
+        self.churn_rates.clear();
+        self.cardinality.clear();
+        self.icmp.clear();
+
+        // Count what is actually removed so connections inserted concurrently
+        // keep `active_connections` consistent.
+        let mut connections = 0;
+        self.connections.retain(|_, _| {
+            connections += 1;
+            false
+        });
+        self.active_connections
+            .fetch_sub(connections, Ordering::Relaxed);
+        self.resets.fetch_add(1, Ordering::Relaxed);
+        self.bump_generation();
+
+        ResetCounts {
+            packets,
+            bytes,
+            connections,
+        }
+    }
+
+    /// After a FIN or RST on `key`, close both directions once the other
+    /// one has finished too.  A RST closes the other direction outright.
+    fn close_pair(&self, key: ConnectionKey, state: TcpState) {
+        let reverse = key.reversed();
+        let Some(mut other) = self.connections.get_mut(&reverse) else {
+            return;
+        };
+        let closed = matches!(
+            (state, other.tcp_state),
+            (TcpState::Closed, Some(_))
+                | (TcpState::Closing, Some(TcpState::Closing | TcpState::Closed))
+        );
+        if !closed {
+            return;
+        }
+        other.tcp_state = Some(TcpState::Closed);
+        drop(other);
+        if let Some(mut stats) = self.connections.get_mut(&key) {
+            stats.tcp_state = Some(TcpState::Closed);
+        }
+    }
+
+    /// As `cleanup_stale_connections_in_chunks`, in one go.
+    #[cfg(test)]
+    pub fn cleanup_stale_connections(&self, timeout: tokio::time::Duration) -> Vec<PeerTotals> {
+        let mut pass = CleanupPass::new(timeout);
+        while pass.step(self) {}
+        pass.finish(&self.cleanup_metrics)
+    }
+
+    /// Drop connections idle for `timeout`, and closed TCP connections once
+    /// they have been quiet for `CLOSED_LINGER`.  Returns their totals per
+    /// remote address and day, for the `peers` table.  Yields after
+    /// scanning each shard and after each `CLEANUP_CHUNK` removals, so the
+    /// packet path never waits long on one shard.
+    pub async fn cleanup_stale_connections_in_chunks(
+        &self,
+        timeout: tokio::time::Duration,
+    ) -> Vec<PeerTotals> {
+        let mut pass = CleanupPass::new(timeout);
+        while pass.step(self) {
+            tokio::task::yield_now().await;
+        }
+        pass.finish(&self.cleanup_metrics)
+    }
+
+    /// Totals across all traffic, or for one interface (zero if nothing
+    /// has been seen on it).
+    pub fn totals(&self, interface: Option<&str>) -> Totals {
+        let one = std::time::Duration::from_secs(1);
+        let sixty = std::time::Duration::from_secs(60);
+        match interface {
+            None => Totals {
+                packets: self.total_packets.load(Ordering::Relaxed),
+                bytes: self.total_bytes.load(Ordering::Relaxed),
+                payload_bytes: self.total_payload_bytes.load(Ordering::Relaxed),
+                last_second: self.rates.rate(one),
+                last_minute: self.rates.rate(sixty),
+            },
+            Some(name) => self.interfaces.get(name).map_or_else(Totals::default, |stats| Totals {
+                packets: stats.packets.load(Ordering::Relaxed),
+                bytes: stats.bytes.load(Ordering::Relaxed),
+                payload_bytes: stats.payload_bytes.load(Ordering::Relaxed),
+                last_second: stats.rates.rate(one),
+                last_minute: stats.rates.rate(sixty),
+            }),
+        }
+    }
+
+    /// Connections created and expired, in total and per second.
+    pub fn churn(&self) -> ConnectionChurn {
+        let one = self.churn_rates.rate(std::time::Duration::from_secs(1));
+        let sixty = self.churn_rates.rate(std::time::Duration::from_secs(60));
+        ConnectionChurn {
+            created: self.connections_created.load(Ordering::Relaxed),
+            expired: self.connections_expired.load(Ordering::Relaxed),
+            created_1s: one.pps,
+            created_60s: sixty.pps,
+            expired_60s: sixty.bps,
+        }
+    }
+
+    /// Live TCP connections per state, optionally on one interface.
+    pub fn tcp_state_counts(&self, interface: Option<&str>) -> TcpStateCounts {
+        let mut counts = TcpStateCounts::default();
+        for entry in self.connections.iter() {
+            let stats = entry.value();
+            if interface.is_some_and(|name| stats.interface != name) {
+                continue;
+            }
+            match stats.tcp_state {
+                Some(TcpState::New) => counts.new += 1,
+                Some(TcpState::Established) => counts.established += 1,
+                Some(TcpState::Closing) => counts.closing += 1,
+                Some(TcpState::Closed) => counts.closed += 1,
+                None => {}
+            }
+        }
+        counts
+    }
+
+    /// Number of live connections matching `filter`.
+    pub fn count_connections(&self, filter: &ConnectionFilter) -> usize {
+        self.connections
+            .iter()
+            .filter(|entry| filter.matches(entry.key(), entry.value()))
+            .count()
+    }
+
+    /// Filter, sort, and page the live connection table.
+    ///
+    /// Operates on cloned typed stats so the DashMap shards are only held
+    /// while copying, not while sorting.
+    pub fn query_connections(
+        &self,
+        filter: &ConnectionFilter,
+        sort: ConnectionSort,
+        order: SortOrder,
+        offset: usize,
+        limit: usize,
+    ) -> ConnectionPage {
+        let mut entries: Vec<ConnectionEntry> = self
+            .connections
+            .iter()
+            .filter(|entry| filter.matches(entry.key(), entry.value()))
+            .map(|entry| ConnectionEntry::new(*entry.key(), entry.value().clone()))
+            .collect();
+
+        // Ascending comparators; reversed below for descending order.
+        match sort {
+            ConnectionSort::Bytes => entries.sort_by_key(|e| e.stats.total_bytes()),
+            ConnectionSort::Packets => entries.sort_by_key(|e| e.stats.packets_count),
+            ConnectionSort::LastSeen => entries.sort_by_key(|e| e.stats.last_seen),
+            ConnectionSort::Rate => entries.sort_by_key(|e| e.stats.instant_bps),
+        }
+        if let SortOrder::Desc = order {
+            entries.reverse();
+        }
+
+        let total = entries.len();
+        let connections = entries.into_iter().skip(offset).take(limit).collect();
+        ConnectionPage { total, connections }
+    }
+
+    /// Copies of the entries in one shard of the connection table, taken
+    /// under that shard's read lock alone so updates to the others carry
+    /// on; None past the last shard.
+    pub fn connection_shard(&self, shard: usize) -> Option<Vec<ConnectionEntry>> {
+        let shard = self.connections.shards().get(shard)?;
+        let entries = shard
+            .read()
+            .iter()
+            .map(|(key, stats)| ConnectionEntry::new(*key, stats.get().clone()))
+            .collect();
+        Some(entries)
+    }
+
+    /// The canonical keys of live connections with this id.  More than one
+    /// only on a hash collision, each covering both directions.
+    pub fn connections_with_id(&self, id: ConnectionId) -> Vec<ConnectionKey> {
+        let mut keys: Vec<ConnectionKey> = Vec::new();
+        for entry in self.connections.iter() {
+            let key = entry.key().canonical();
+            if key.id() == id && !keys.contains(&key) {
+                keys.push(key);
+            }
         }
+        keys
     }
 
-    pub fn update(&self, packet: &PacketMetadata) {
-        let key = format!(
-            "{}:{} -> {}:{}",
-            packet.src_ip, packet.src_port, packet.dst_ip, packet.dst_port
-        );
-
-        let is_egress = packet.direction == "egress";
+    /// The live entries for `key` and its reverse direction, in that order,
+    /// skipping either one not in the table.
+    pub fn connection_pair(&self, key: &ConnectionKey) -> Vec<ConnectionEntry> {
+        [*key, key.reversed()]
+            .into_iter()
+            .filter_map(|key| {
+                let stats = self.connections.get(&key)?.clone();
+                Some(ConnectionEntry::new(key, stats))
+            })
+            .collect()
+    }
 
-        self.connections
-            .entry(key)
-            .and_modify(|stats| {
-                stats.packets_count += 1;
-                if is_egress {
-                    stats.bytes_sent += packet.length as u64;
-                } else {
-                    stats.bytes_received += packet.length as u64;
+    /// Capture totals and the most recently active connections.
+    pub fn snapshot(&self) -> StateSnapshot {
+        let mut connections: Vec<SnapshotConnection> = self
+            .connections
+            .iter()
+            .map(|entry| {
+                let stats = entry.value();
+                SnapshotConnection {
+                    key: *entry.key(),
+                    protocol: stats.protocol.clone(),
+                    bytes_sent: stats.bytes_sent,
+                    bytes_received: stats.bytes_received,
+                    packets_count: stats.packets_count,
+                    payload_bytes: stats.payload_bytes,
+                    ttl_min: stats.ttl_min,
+                    ttl_max: stats.ttl_max,
+                    retransmits: stats.retransmits,
+                    interface: stats.interface.clone(),
+                    tcp_state: stats.tcp_state,
+                    idle_ms: stats.last_seen.elapsed().as_millis() as u64,
                 }
-                stats.last_seen = Instant::now();
             })
-            .or_insert_with(|| {
-                self.active_connections.fetch_add(1, Ordering::Relaxed);
-                let mut cs = ConnectionStats {
-                    packets_count: 1,
-                    ..Default::default()
-                };
-                if is_egress {
-                    cs.bytes_sent = packet.length as u64;
-                } else {
-                    cs.bytes_received = packet.length as u64;
-                }
-                cs
-            });
+            .collect();
+        connections.sort_by_key(|c| c.idle_ms);
+        connections.truncate(SNAPSHOT_MAX_CONNECTIONS);
+
+        StateSnapshot {
+            version: SNAPSHOT_VERSION,
+            saved_at: chrono::Utc::now().timestamp_millis(),
+            total_packets: self.total_packets.load(Ordering::Relaxed),
+            total_bytes: self.total_bytes.load(Ordering::Relaxed),
+            total_payload_bytes: self.total_payload_bytes.load(Ordering::Relaxed),
+            deep_inspect_packets: self.deep_inspect_packets.load(Ordering::Relaxed),
+            domains_resolved: self.domains_resolved.load(Ordering::Relaxed),
+            tcp_retransmits: self.tcp_retransmits.load(Ordering::Relaxed),
+            connections,
+        }
+    }
 
-        self.total_packets.fetch_add(1, Ordering::Relaxed);
-        self.total_bytes
-            .fetch_add(packet.length as u64, Ordering::Relaxed);
+    /// Counters and the `top` connections by bytes; the connection table
+    /// itself is never copied out whole.
+    pub fn summary(&self, top: usize) -> TrafficSummary {
+        let page = self.query_connections(
+            &ConnectionFilter::default(),
+            ConnectionSort::Bytes,
+            SortOrder::Desc,
+            0,
+            top,
+        );
+        TrafficSummary {
+            active_connections: self.active_connections.load(Ordering::Relaxed),
+            total_packets: self.total_packets.load(Ordering::Relaxed),
+            total_bytes: self.total_bytes.load(Ordering::Relaxed),
+            interfaces: self.interfaces.len(),
+            tcp_states: self.tcp_state_counts(None),
+            tcp_retransmits: self.tcp_retransmits.load(Ordering::Relaxed),
+            forwarded_duplicates: self.forwarded_duplicates.load(Ordering::Relaxed),
+            kernel_flow_overflows: self.kernel_flow_overflows.load(Ordering::Relaxed),
+            ring_buf_drops: self.ring_buf_drops.load(Ordering::Relaxed),
+            top_connections: page.connections,
+        }
     }
 
-    pub fn cleanup_stale_connections(&self, timeout: tokio::time::Duration) {
+    /// Restore totals from a snapshot and re-seed connections that would
+    /// not yet have been cleaned up as stale.  Returns the number of
+    /// connections restored.  Meant to be called before capture starts.
+    pub fn restore(&self, snapshot: StateSnapshot, timeout: tokio::time::Duration) -> usize {
+        self.total_packets
+            .store(snapshot.total_packets, Ordering::Relaxed);
+        self.total_bytes.store(snapshot.total_bytes, Ordering::Relaxed);
+        self.total_payload_bytes
+            .store(snapshot.total_payload_bytes, Ordering::Relaxed);
+        self.deep_inspect_packets
+            .store(snapshot.deep_inspect_packets, Ordering::Relaxed);
+        self.domains_resolved
+            .store(snapshot.domains_resolved, Ordering::Relaxed);
+        self.tcp_retransmits
+            .store(snapshot.tcp_retransmits, Ordering::Relaxed);
+
+        let downtime_ms =
+            (chrono::Utc::now().timestamp_millis() - snapshot.saved_at).max(0) as u64;
         let now = Instant::now();
-        let mut to_remove = Vec::new();
+        let mut restored = 0;
+        for conn in snapshot.connections {
+            let idle = tokio::time::Duration::from_millis(conn.idle_ms + downtime_ms);
+            if idle >= timeout {
+                continue;
+            }
+            let Some(last_seen) = now.checked_sub(idle) else {
+                continue;
+            };
+            let flow_direction = self.local_networks.classify(&conn.key.src_ip, &conn.key.dst_ip);
+            let remote = match flow_direction {
+                FlowDirection::Inbound => conn.key.src_ip,
+                _ => conn.key.dst_ip,
+            };
+            let country = self.geoip.as_ref().and_then(|geoip| geoip.locate(remote).country);
+            let stats = ConnectionStats {
+                protocol: conn.protocol,
+                bytes_sent: conn.bytes_sent,
+                bytes_received: conn.bytes_received,
+                packets_count: conn.packets_count,
+                payload_bytes: conn.payload_bytes,
+                ttl_min: conn.ttl_min,
+                ttl_max: conn.ttl_max,
+                retransmits: conn.retransmits,
+                interface: conn.interface,
+                flow_direction: Some(flow_direction),
+                cast: Some(self.local_networks.cast(&conn.key.dst_ip)),
+                country,
+                tcp_state: conn.tcp_state,
+                // Restored bytes were not moved since the last sample.
+                sampled_bytes: conn.bytes_sent + conn.bytes_received,
+                // The snapshot keeps no start time.
+                first_seen: last_seen,
+                last_seen,
+                ..Default::default()
+            };
+            if self.connections.insert(conn.key, stats).is_none() {
+                restored += 1;
+            }
+        }
+        self.active_connections.fetch_add(restored, Ordering::Relaxed);
+        self.bump_generation();
+        restored
+    }
+
+    /// Group live connections to `cast` destinations (all when None) by
+    /// source or destination address, optionally masked to a subnet, by
+    /// service port or by remote country, and return the groups with the
+    /// most bytes.
+    pub fn top_talkers(
+        &self,
+        by: TopBy,
+        prefixes: SubnetPrefixes,
+        cast: Option<Cast>,
+        limit: usize,
+    ) -> Vec<TopTalker> {
+        let prefixes = if by.is_subnet() { prefixes } else { SubnetPrefixes::host() };
+        let rule = self.categories.rule();
+        // Keyed by (subnet, port, country), one of which is set.
+        type Group = (Option<IpNet>, Option<u16>, Option<String>);
+        let mut groups: HashMap<Group, (TopTalker, HashSet<IpAddr>)> = HashMap::new();
 
         for entry in self.connections.iter() {
-            if now.duration_since(entry.value().last_seen) > timeout {
-                to_remove.push(entry.key().clone());
+            if cast.is_some_and(|cast| entry.value().cast != Some(cast)) {
+                continue;
             }
+            let key = entry.key();
+            let (group, ip) = match by {
+                // Connections between clients have no service port under
+                // `non_ephemeral`, and other protocols no ports at all.
+                TopBy::Port => {
+                    if !matches!(entry.value().protocol.as_str(), "TCP" | "UDP") {
+                        continue;
+                    }
+                    match rule.service_side(key.src_port, key.dst_port) {
+                        Some(ServiceSide::Src) => ((None, Some(key.src_port), None), key.src_ip),
+                        Some(ServiceSide::Dst) => ((None, Some(key.dst_port), None), key.dst_ip),
+                        None => continue,
+                    }
+                }
+                TopBy::Country => {
+                    let country = entry.value().country.as_deref().unwrap_or("unknown");
+                    ((None, None, Some(country.to_string())), entry.value().remote_ip(key))
+                }
+                _ => {
+                    let ip = if by.is_source() { key.src_ip } else { key.dst_ip };
+                    ((Some(prefixes.mask(ip)), None, None), ip)
+                }
+            };
+            let (talker, hosts) = groups.entry(group.clone()).or_insert_with(|| {
+                let talker = TopTalker {
+                    subnet: group.0,
+                    port: group.1,
+                    country: group.2,
+                    bytes: 0,
+                    packets: 0,
+                    connections: 0,
+                    hosts: 0,
+                };
+                (talker, HashSet::new())
+            });
+            talker.bytes += entry.value().total_bytes();
+            talker.packets += entry.value().packets_count;
+            talker.connections += 1;
+            hosts.insert(ip);
         }
 
-        let removed_count = to_remove.len();
-        for key in to_remove {
-            self.connections.remove(&key);
+        let mut talkers: Vec<TopTalker> = groups
+            .into_values()
+            .map(|(mut talker, hosts)| {
+                talker.hosts = hosts.len();
+                talker
+            })
+            .collect();
+        talkers.sort_by_key(|t| std::cmp::Reverse(t.bytes));
+        talkers.truncate(limit);
+        talkers
+    }
+
+    /// Every DSCP class seen so far, most bytes first.
+    pub fn flow_direction_totals(&self) -> FlowDirectionTotals {
+        let totals = |direction: FlowDirection| {
+            let counters = &self.flow_directions[direction as usize];
+            DirectionTotals {
+                packets: counters.packets.load(Ordering::Relaxed),
+                bytes: counters.bytes.load(Ordering::Relaxed),
+            }
+        };
+        FlowDirectionTotals {
+            inbound: totals(FlowDirection::Inbound),
+            outbound: totals(FlowDirection::Outbound),
+            internal: totals(FlowDirection::Internal),
+            external: totals(FlowDirection::External),
         }
+    }
 
-        if removed_count > 0 {
-            self.active_connections
-                .fetch_sub(removed_count, Ordering::Relaxed);
+    pub fn cast_totals(&self) -> CastTotals {
+        let totals = |cast: Cast| {
+            let counters = &self.casts[cast as usize];
+            DirectionTotals {
+                packets: counters.packets.load(Ordering::Relaxed),
+                bytes: counters.bytes.load(Ordering::Relaxed),
+            }
+        };
+        CastTotals {
+            unicast: totals(Cast::Unicast),
+            multicast: totals(Cast::Multicast),
+            broadcast: totals(Cast::Broadcast),
         }
     }
+
+    /// Packets and bytes per category, indexed by category id.
+    pub fn category_totals(&self) -> Vec<(u64, u64)> {
+        self.category_counters
+            .iter()
+            .map(|c| (c.packets.load(Ordering::Relaxed), c.bytes.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    pub fn qos_breakdown(&self) -> Vec<QosClass> {
+        let mut classes: Vec<QosClass> = self
+            .qos
+            .iter()
+            .enumerate()
+            .filter_map(|(dscp, counters)| {
+                let packets = counters.packets.load(Ordering::Relaxed);
+                (packets > 0).then(|| QosClass {
+                    dscp: dscp as u8,
+                    class: dscp_class_name(dscp as u8),
+                    packets,
+                    bytes: counters.bytes.load(Ordering::Relaxed),
+                })
+            })
+            .collect();
+        classes.sort_by_key(|c| std::cmp::Reverse(c.bytes));
+        classes
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use ayaflow_common::ipv4_mapped;
+    use crate::test_support;
+    use ayaflow_common::{ipv4_mapped, ServicePortRule, EVENT_SIZE, EVENT_VERSION};
 
     #[test]
     fn test_from_ebpf_tcp() {
         let event = PacketEvent {
+            version: EVENT_VERSION,
+            size: EVENT_SIZE,
             src_addr: ipv4_mapped(u32::from_be_bytes([10, 0, 0, 1])),
             dst_addr: ipv4_mapped(u32::from_be_bytes([192, 168, 1, 100])),
             src_port: 12345,
@@ -232,23 +2262,96 @@ mod tests {
             protocol: 6,
             direction: 0,
             addr_type: 4,
-            _pad: [0; 1],
+            ttl: 64,
             pkt_len: 1500,
+            tcp_seq: 0,
+            l4_len: 1480,
+            tos: 0xb8, // EF, not ECN-capable
+            l4_header_len: 32, // timestamps option
+            ifindex: 2,
+            ether_type: ETHERTYPE_IPV4,
+            tcp_flags: 0,
+            blocklist: 0,
+            src_mac: [0xb8, 0x27, 0xeb, 0x01, 0x02, 0x03],
+            dst_mac: [0; 6],
+            _pad: [0; 4],
+            ktime_ns: 0,
         };
-        let meta = PacketMetadata::from_ebpf(&event);
+        let meta = packet_from_ebpf(&event, "eth0".into());
 
         assert_eq!(meta.src_ip, "10.0.0.1");
+        assert_eq!(meta.src_mac.as_deref(), Some("b8:27:eb:01:02:03"));
+        // L3 interfaces report no Ethernet header.
+        assert_eq!(meta.dst_mac, None);
         assert_eq!(meta.dst_ip, "192.168.1.100");
         assert_eq!(meta.src_port, 12345);
         assert_eq!(meta.dst_port, 443);
         assert_eq!(meta.protocol, "TCP");
         assert_eq!(meta.length, 1500);
+        assert_eq!(meta.payload_length, 1448);
         assert_eq!(meta.direction, "ingress");
+        assert_eq!(meta.ttl, Some(64));
+        assert_eq!(meta.dscp, Some(46));
+        assert_eq!(meta.dscp_class.as_deref(), Some("EF"));
+    }
+
+    #[test]
+    fn test_try_from_bytes() {
+        let event = PacketEvent {
+            version: EVENT_VERSION,
+            size: EVENT_SIZE,
+            src_addr: ipv4_mapped(u32::from_be_bytes([10, 0, 0, 1])),
+            dst_addr: ipv4_mapped(u32::from_be_bytes([1, 1, 1, 1])),
+            src_port: 40000,
+            dst_port: 853,
+            protocol: 6,
+            direction: 1,
+            addr_type: 4,
+            ttl: 64,
+            pkt_len: 60,
+            tcp_seq: 1000,
+            l4_len: 40,
+            tos: 0,
+            l4_header_len: 40,
+            ifindex: 3,
+            ether_type: ETHERTYPE_IPV4,
+            tcp_flags: TCP_SYN,
+            blocklist: 0,
+            src_mac: [0; 6],
+            dst_mac: [0; 6],
+            _pad: [0; 4],
+            ktime_ns: 42,
+        };
+        let name = |ifindex: u32| format!("if{}", ifindex);
+
+        // Round trip through the bytes the classifier writes.
+        let bytes = event.as_bytes();
+        assert_eq!(bytes.len(), EVENT_SIZE as usize);
+        let (meta, info) = packet_from_bytes(bytes, name).unwrap();
+        assert_eq!((meta.src_ip.as_str(), meta.dst_port), ("10.0.0.1", 853));
+        assert_eq!((meta.direction.as_str(), meta.interface.as_str()), ("egress", "if3"));
+        assert_eq!(info.segment.map(|s| (s.seq, s.flags)), Some((1000, TCP_SYN)));
+        assert_eq!(info.ktime_ns, Some(42));
+        assert!(PacketEvent::from_bytes(bytes).is_some());
+
+        // Unaligned, short and oversized buffers.
+        let mut shifted = vec![0u8; 1];
+        shifted.extend_from_slice(bytes);
+        assert!(packet_from_bytes(&shifted[1..], name).is_ok());
+        let short = packet_from_bytes(&bytes[..EVENT_SIZE as usize - 8], name);
+        assert!(matches!(short, Err(EventError::Size { .. })));
+        shifted.extend_from_slice(&[0; 8]);
+        let long = packet_from_bytes(&shifted[1..], name);
+        assert!(matches!(long, Err(EventError::Size { .. })));
+        assert!(PacketEvent::from_bytes(&shifted[1..]).is_none());
+        assert!(PacketEvent::from_bytes(&[]).is_none());
     }
 
     #[test]
     fn test_from_ebpf_udp() {
         let event = PacketEvent {
+            version: EVENT_VERSION,
+            size: EVENT_SIZE,
             src_addr: ipv4_mapped(u32::from_be_bytes([172, 16, 0, 1])),
             dst_addr: ipv4_mapped(u32::from_be_bytes([8, 8, 8, 8])),
             src_port: 53000,
@@ -256,16 +2359,30 @@ mod tests {
             protocol: 17,
             direction: 1,
             addr_type: 4,
-            _pad: [0; 1],
+            ttl: 1,
             pkt_len: 64,
+            tcp_seq: 0,
+            l4_len: 44,
+            tos: 0,
+            l4_header_len: 8,
+            ifindex: 2,
+            ether_type: ETHERTYPE_IPV4,
+            tcp_flags: 0,
+            blocklist: 0,
+            src_mac: [0; 6],
+            dst_mac: [0; 6],
+            _pad: [0; 4],
+            ktime_ns: 0,
         };
-        let meta = PacketMetadata::from_ebpf(&event);
+        let meta = packet_from_ebpf(&event, "eth0".into());
 
         assert_eq!(meta.src_ip, "172.16.0.1");
         assert_eq!(meta.dst_ip, "8.8.8.8");
         assert_eq!(meta.protocol, "UDP");
         assert_eq!(meta.length, 64);
+        assert_eq!(meta.payload_length, 36);
         assert_eq!(meta.direction, "egress");
+        assert_eq!(meta.ttl, Some(1));
     }
 
     #[test]
@@ -274,6 +2391,8 @@ mod tests {
         let src: [u8; 16] = [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
         let dst: [u8; 16] = [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2];
         let event = PacketEvent {
+            version: EVENT_VERSION,
+            size: EVENT_SIZE,
             src_addr: src,
             dst_addr: dst,
             src_port: 8080,
@@ -281,33 +2400,141 @@ mod tests {
             protocol: 6,
             direction: 0,
             addr_type: 6,
-            _pad: [0; 1],
+            ttl: 255,
             pkt_len: 500,
+            tcp_seq: 0,
+            l4_len: 460,
+            tos: 0,
+            l4_header_len: 20,
+            ifindex: 2,
+            ether_type: ETHERTYPE_IPV6,
+            tcp_flags: 0,
+            blocklist: 0,
+            src_mac: [0; 6],
+            dst_mac: [0; 6],
+            _pad: [0; 4],
+            ktime_ns: 0,
         };
-        let meta = PacketMetadata::from_ebpf(&event);
+        let meta = packet_from_ebpf(&event, "eth0".into());
 
         assert_eq!(meta.src_ip, "2001:db8::1");
         assert_eq!(meta.dst_ip, "2001:db8::2");
         assert_eq!(meta.protocol, "TCP");
         assert_eq!(meta.length, 500);
+        assert_eq!(meta.payload_length, 440);
         assert_eq!(meta.direction, "ingress");
+        assert_eq!(meta.ttl, Some(255));
+    }
+
+    #[test]
+    fn test_non_ip_frames() {
+        let frame = |ether_type| PacketEvent {
+            version: EVENT_VERSION,
+            size: EVENT_SIZE,
+            src_addr: [0; 16],
+            dst_addr: [0; 16],
+            src_port: 0,
+            dst_port: 0,
+            protocol: 0,
+            direction: 0,
+            addr_type: 0,
+            ttl: 0,
+            pkt_len: 60,
+            tcp_seq: 0,
+            l4_len: 0,
+            tos: 0,
+            l4_header_len: 0,
+            ifindex: 2,
+            ether_type,
+            tcp_flags: 0,
+            blocklist: 0,
+            src_mac: [0; 6],
+            dst_mac: [0; 6],
+            _pad: [0; 4],
+            ktime_ns: 0,
+        };
+        let arp = packet_from_ebpf(&frame(ETHERTYPE_ARP), "eth0".into());
+        assert_eq!(arp.protocol, "ARP");
+        assert_eq!(arp.src_ip, "0.0.0.0");
+        assert_eq!(arp.length, 60);
+        assert_eq!(arp.ttl, None);
+        assert_eq!(arp.dscp, None);
+        let lldp = packet_from_ebpf(&frame(0x88cc), "eth0".into());
+        assert_eq!(lldp.protocol, "ETH(0x88cc)");
+
+        // Each ethertype is its own connection, filterable by protocol.
+        let state = TrafficState::new();
+        for _ in 0..3 {
+            state.update(&arp);
+        }
+        state.update(&lldp);
+        state.update(&packet("10.0.0.9", 443, "TCP", 1500));
+        assert_eq!(state.active_connections.load(Ordering::Relaxed), 3);
+        let filter = ConnectionFilter {
+            protocol: Some("arp".into()),
+            ..Default::default()
+        };
+        let page =
+            state.query_connections(&filter, ConnectionSort::Packets, SortOrder::Desc, 0, 10);
+        assert_eq!(page.total, 1);
+        assert_eq!(page.connections[0].stats.packets_count, 3);
+        assert_eq!(page.connections[0].stats.bytes_received, 180);
+    }
+
+    #[test]
+    fn test_payload_length_edge_cases() {
+        let event = |protocol, l4_len, l4_header_len| PacketEvent {
+            version: EVENT_VERSION,
+            size: EVENT_SIZE,
+            src_addr: ipv4_mapped(u32::from_be_bytes([10, 0, 0, 1])),
+            dst_addr: ipv4_mapped(u32::from_be_bytes([10, 0, 0, 2])),
+            src_port: 40000,
+            dst_port: 443,
+            protocol,
+            direction: 0,
+            addr_type: 4,
+            ttl: 64,
+            pkt_len: 60,
+            tcp_seq: 1,
+            l4_len,
+            tos: 0,
+            l4_header_len,
+            ifindex: 2,
+            ether_type: ETHERTYPE_IPV4,
+            tcp_flags: 0,
+            blocklist: 0,
+            src_mac: [0; 6],
+            dst_mac: [0; 6],
+            _pad: [0; 4],
+            ktime_ns: 0,
+        };
+        let payload = |e: &PacketEvent| packet_from_ebpf(e, "eth0".into()).payload_length;
+
+        // A pure ACK is all header.
+        let ack = event(6, 20, 20);
+        assert_eq!(payload(&ack), 0);
+        assert_eq!(TcpSegment::from_ebpf(&ack).unwrap().payload_len, 0);
+        // A data offset pointing past the segment (truncated or malformed).
+        assert_eq!(payload(&event(6, 24, 60)), 0);
+        // UDP length fields below the 8-byte header are bogus.
+        assert_eq!(payload(&event(17, 4, 8)), 0);
+        assert_eq!(payload(&event(17, 8, 8)), 0);
+        assert_eq!(payload(&event(17, 520, 8)), 512);
+        // Protocols without a parsed transport header carry no payload count.
+        assert_eq!(payload(&event(1, 0, 0)), 0);
     }
 
     #[test]
     fn test_traffic_state_update() {
         let state = TrafficState::new();
         let packet = PacketMetadata {
-            timestamp: 0,
             src_ip: "127.0.0.1".into(),
             dst_ip: "127.0.0.1".into(),
             src_port: 80,
             dst_port: 1234,
-            protocol: "TCP".into(),
             length: 100,
-            direction: "ingress".into(),
-            src_hostname: None,
-            dst_hostname: None,
-            domain: None,
+            payload_length: 48,
+            ..test_support::packet()
         };
 
         state.update(&packet);
@@ -318,6 +2545,769 @@ mod tests {
         state.update(&packet);
         assert_eq!(state.total_packets.load(Ordering::Relaxed), 2);
         assert_eq!(state.total_bytes.load(Ordering::Relaxed), 200);
+        assert_eq!(state.total_payload_bytes.load(Ordering::Relaxed), 96);
+        let stats = state.connections.iter().next().unwrap().value().clone();
+        assert_eq!(stats.payload_bytes, 96);
+        assert_eq!(state.active_connections.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_connection_ttl_min_max() {
+        let state = TrafficState::new();
+        for ttl in [64, 3, 128] {
+            let mut p = packet("192.168.1.9", 443, "TCP", 60);
+            p.ttl = Some(ttl);
+            state.update(&p);
+        }
+        // Rows without a TTL (e.g. aggregated) must not disturb the range.
+        state.update(&packet("192.168.1.9", 443, "TCP", 60));
+
+        let stats = state.connections.iter().next().unwrap().value().clone();
+        assert_eq!(stats.ttl_min, Some(3));
+        assert_eq!(stats.ttl_max, Some(128));
+    }
+
+    #[test]
+    fn test_dscp_names_and_qos_breakdown() {
+        assert_eq!(dscp_class_name(0), "CS0");
+        assert_eq!(dscp_class_name(46), "EF");
+        assert_eq!(dscp_class_name(34), "AF41");
+        assert_eq!(dscp_class_name(14), "AF13");
+        assert_eq!(dscp_class_name(48), "CS6");
+        assert_eq!(dscp_class_name(13), "DSCP13");
+
+        let state = TrafficState::new();
+        let mut voice = packet("10.0.0.5", 5060, "UDP", 200);
+        voice.dscp = Some(46);
+        state.update(&voice);
+        state.update(&voice);
+        let mut best_effort = packet("10.0.0.6", 443, "TCP", 1500);
+        best_effort.dscp = Some(0);
+        state.update(&best_effort);
+        state.update(&packet("10.0.0.7", 443, "TCP", 900)); // no DSCP recorded
+
+        let qos = state.qos_breakdown();
+        assert_eq!(qos.len(), 2);
+        assert_eq!((qos[0].class.as_str(), qos[0].packets, qos[0].bytes), ("CS0", 1, 1500));
+        assert_eq!((qos[1].class.as_str(), qos[1].packets, qos[1].bytes), ("EF", 2, 400));
+    }
+
+    #[test]
+    fn test_bucket_merge_keeps_first_hostnames() {
+        let unnamed = packet("192.168.1.5", 443, "TCP", 100);
+        let named = PacketMetadata {
+            src_hostname: Some("laptop.lan".into()),
+            dst_hostname: Some("gateway.lan".into()),
+            ..packet("192.168.1.5", 443, "TCP", 200)
+        };
+        let renamed = PacketMetadata {
+            src_hostname: Some("renamed.lan".into()),
+            ..packet("192.168.1.5", 443, "TCP", 300)
+        };
+
+        let mut bucket = AggregatedBucket::from_packet(&unnamed);
+        assert_eq!((bucket.src_hostname.as_deref(), bucket.dst_hostname.as_deref()), (None, None));
+        bucket.merge(&named);
+        bucket.merge(&renamed);
+        bucket.merge(&unnamed);
+        assert_eq!((bucket.packet_count, bucket.total_bytes), (4, 700));
+        assert_eq!(bucket.src_hostname.as_deref(), Some("laptop.lan"));
+        assert_eq!(bucket.dst_hostname.as_deref(), Some("gateway.lan"));
+
+        let bucket = AggregatedBucket::from_packet(&named);
+        assert_eq!(bucket.src_hostname.as_deref(), Some("laptop.lan"));
+    }
+
+    #[test]
+    fn test_apply_bucket_from_flow() {
+        let key = FlowKey {
+            src_addr: ipv4_mapped(u32::from_be_bytes([10, 0, 0, 2])),
+            dst_addr: ipv4_mapped(u32::from_be_bytes([1, 1, 1, 1])),
+            src_port: 5000,
+            dst_port: 53,
+            protocol: 17,
+            direction: 1,
+            addr_type: 4,
+            _pad: [0; 1],
+            ifindex: 3,
+        };
+        let counters = FlowCounters {
+            packets: 7,
+            bytes: 700,
+            payload_bytes: 364,
+        };
+        let bucket = AggregatedBucket::from_flow(&key, &counters, 1234, 11234, "wg0".into());
+        assert_eq!(bucket.first_timestamp, 1234);
+        assert_eq!(bucket.window_end, 11234);
+        assert_eq!(bucket.src_ip, "10.0.0.2");
+        assert_eq!(bucket.protocol, "UDP");
+        assert_eq!(bucket.direction, "egress");
+
+        let state = TrafficState::new();
+        state.apply_bucket(&bucket);
+        state.apply_bucket(&bucket);
+        assert_eq!(state.total_packets.load(Ordering::Relaxed), 14);
+        assert_eq!(state.total_bytes.load(Ordering::Relaxed), 1400);
+        assert_eq!(state.total_payload_bytes.load(Ordering::Relaxed), 728);
+        let wg0 = state.interfaces.get("wg0").unwrap();
+        assert_eq!(wg0.packets.load(Ordering::Relaxed), 14);
+        assert_eq!(state.active_connections.load(Ordering::Relaxed), 1);
+        let stats = state.connections.iter().next().unwrap().value().clone();
+        assert_eq!(stats.bytes_sent, 1400);
+        assert_eq!(stats.packets_count, 14);
+    }
+
+    fn packet(src_ip: &str, dst_port: u16, protocol: &str, length: usize) -> PacketMetadata {
+        PacketMetadata {
+            src_ip: src_ip.into(),
+            dst_port,
+            protocol: protocol.into(),
+            length,
+            ..test_support::packet()
+        }
+    }
+
+    #[test]
+    fn test_connection_id() {
+        let key = |src: &str, src_port, dst: &str, dst_port| ConnectionKey {
+            src_ip: src.parse().unwrap(),
+            src_port,
+            dst_ip: dst.parse().unwrap(),
+            dst_port,
+        };
+        // Fixed values, so external systems can rely on them.
+        let https = key("10.0.0.2", 40000, "10.0.0.1", 443);
+        assert_eq!(https.id().to_string(), "badb64b01d20c770");
+        assert_eq!(https.reversed().id(), https.id());
+        assert_eq!(https.canonical(), https.reversed());
+        let v6 = key("2001:db8::1", 443, "10.0.0.2", 40000);
+        assert_eq!(v6.id().to_string(), "b3ca25737ce1caaa");
+        assert_eq!("B3CA25737CE1CAAA".parse::<ConnectionId>(), Ok(v6.id()));
+        for bad in ["b3ca25737ce1caa", "+3ca25737ce1caaa", "b3ca25737ce1caaz"] {
+            assert!(bad.parse::<ConnectionId>().is_err(), "{}", bad);
+        }
+
+        // An IPv4 key and its IPv4-mapped IPv6 twin hash alike, standing in
+        // for a collision: lookups see two connections and filters match
+        // both until narrowed by address.
+        let state = TrafficState::new();
+        for src_ip in ["192.168.1.3", "::ffff:192.168.1.3"] {
+            state.update(&packet(src_ip, 53, "UDP", 100));
+        }
+        state.update(&PacketMetadata {
+            src_ip: "10.0.0.1".into(),
+            dst_ip: "192.168.1.3".into(),
+            src_port: 53,
+            dst_port: 40000,
+            ..packet("10.0.0.1", 53, "UDP", 100)
+        });
+        let id = key("192.168.1.3", 40000, "10.0.0.1", 53).id();
+        assert_eq!(state.connections_with_id(id).len(), 2);
+        let filter = ConnectionFilter {
+            connection_id: Some(id),
+            ..Default::default()
+        };
+        let page = state.query_connections(&filter, ConnectionSort::Bytes, SortOrder::Desc, 0, 10);
+        assert_eq!(page.total, 3);
+        let narrowed = ConnectionFilter {
+            ip: Some("::ffff:192.168.1.3".parse().unwrap()),
+            ..filter
+        };
+        assert_eq!(state.count_connections(&narrowed), 1);
+    }
+
+    #[test]
+    fn test_query_connections_filter_sort_page() {
+        let state = TrafficState::new();
+        state.update(&packet("192.168.1.1", 443, "TCP", 100));
+        state.update(&packet("192.168.1.2", 443, "TCP", 300));
+        state.update(&packet("192.168.1.3", 53, "UDP", 200));
+        state.update(&packet("192.168.1.3", 53, "UDP", 200));
+
+        let all = state.query_connections(
+            &ConnectionFilter::default(),
+            ConnectionSort::Bytes,
+            SortOrder::Desc,
+            0,
+            10,
+        );
+        assert_eq!(all.total, 3);
+        let bytes: Vec<u64> = all.connections.iter().map(|e| e.stats.total_bytes()).collect();
+        assert_eq!(bytes, vec![400, 300, 100]);
+
+        let tcp = ConnectionFilter {
+            protocol: Some("tcp".into()),
+            ..Default::default()
+        };
+        let page = state.query_connections(&tcp, ConnectionSort::Bytes, SortOrder::Asc, 1, 10);
+        assert_eq!(page.total, 2);
+        assert_eq!(page.connections.len(), 1);
+        assert_eq!(page.connections[0].stats.total_bytes(), 300);
+
+        let by_ip = ConnectionFilter {
+            ip: Some("192.168.1.3".parse().unwrap()),
+            port: Some(53),
+            ..Default::default()
+        };
+        let page = state.query_connections(&by_ip, ConnectionSort::Packets, SortOrder::Desc, 0, 10);
+        assert_eq!(page.total, 1);
+        assert_eq!(page.connections[0].stats.packets_count, 2);
+        assert_eq!(
+            page.connections[0].connection.to_string(),
+            "192.168.1.3:40000 -> 10.0.0.1:53"
+        );
+    }
+
+    #[test]
+    fn test_flow_direction_totals_and_filter() {
+        let local = LocalNetworks::new(vec!["10.0.0.0/24".parse().unwrap()]);
+        let state = TrafficState::new().with_local_networks(local);
+        // `packet` sends to 10.0.0.1, which is local.
+        state.update(&packet("93.184.216.34", 443, "TCP", 1000));
+        state.update(&packet("10.0.0.7", 445, "TCP", 300));
+        let upload = PacketMetadata {
+            src_ip: "10.0.0.1".into(),
+            dst_ip: "93.184.216.34".into(),
+            ..packet("10.0.0.1", 443, "TCP", 200)
+        };
+        state.update(&upload);
+        let transit = PacketMetadata {
+            dst_ip: "8.8.8.8".into(),
+            ..packet("172.16.0.1", 53, "UDP", 80)
+        };
+        state.update(&transit);
+        // A direction set at capture wins over classifying again.
+        let tagged = PacketMetadata {
+            flow_direction: Some(FlowDirection::Inbound),
+            ..transit.clone()
+        };
+        state.update(&tagged);
+
+        let totals = state.flow_direction_totals();
+        assert_eq!(totals.inbound, DirectionTotals { packets: 2, bytes: 1080 });
+        assert_eq!(totals.outbound, DirectionTotals { packets: 1, bytes: 200 });
+        assert_eq!(totals.internal, DirectionTotals { packets: 1, bytes: 300 });
+        assert_eq!(totals.external, DirectionTotals { packets: 1, bytes: 80 });
+
+        let outbound = ConnectionFilter {
+            direction: Some(FlowDirection::Outbound),
+            ..Default::default()
+        };
+        let page =
+            state.query_connections(&outbound, ConnectionSort::Bytes, SortOrder::Desc, 0, 10);
+        assert_eq!(page.total, 1);
+        assert_eq!(page.connections[0].connection.src_ip.to_string(), "10.0.0.1");
+        let json = serde_json::to_value(&page.connections[0].stats).unwrap();
+        assert_eq!(json["flow_direction"], "outbound");
+
+        state.reset();
+        assert_eq!(state.flow_direction_totals(), FlowDirectionTotals::default());
+    }
+
+    #[test]
+    fn test_top_talkers_by_subnet() {
+        let state = TrafficState::new();
+        state.update(&packet("10.1.2.3", 443, "TCP", 100));
+        state.update(&packet("10.1.2.4", 443, "TCP", 200));
+        state.update(&packet("10.1.2.4", 80, "TCP", 50));
+        state.update(&packet("10.1.9.1", 443, "TCP", 1000));
+        state.update(&packet("fd00::1", 443, "TCP", 10));
+
+        let prefixes = SubnetPrefixes::new(24, 64).unwrap();
+        let top = state.top_talkers(TopBy::SrcSubnet, prefixes, None, 10);
+        let summary: Vec<(String, u64, usize, usize)> = top
+            .iter()
+            .map(|t| (t.subnet.unwrap().to_string(), t.bytes, t.connections, t.hosts))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("10.1.9.0/24".to_string(), 1000, 1, 1),
+                ("10.1.2.0/24".to_string(), 350, 3, 2),
+                ("fd00::/64".to_string(), 10, 1, 1),
+            ]
+        );
+
+        // Per-IP grouping ignores the prefixes.
+        let top = state.top_talkers(TopBy::SrcIp, prefixes, None, 1);
+        assert_eq!(top[0].subnet.unwrap().to_string(), "10.1.9.1/32");
+
+        assert!(SubnetPrefixes::new(33, 64).is_none());
+        assert!(SubnetPrefixes::new(24, 129).is_none());
+    }
+
+    #[test]
+    fn test_top_talkers_by_port() {
+        let rules = [ServicePortRule::Lower, ServicePortRule::NonEphemeral];
+        for rule in rules {
+            let categories = PortCategories::default().with_rule(rule);
+            let state = TrafficState::new().with_categories(Arc::new(categories));
+            // A request and its reply both count for 443, not also for the
+            // client's port.
+            state.update(&packet("10.1.2.3", 443, "TCP", 100));
+            let reply = PacketMetadata {
+                src_ip: "10.0.0.1".into(),
+                dst_ip: "10.1.2.3".into(),
+                src_port: 443,
+                dst_port: 40000,
+                ..packet("10.0.0.1", 0, "TCP", 900)
+            };
+            state.update(&reply);
+            // From 443 to 8443 the lower port is the service.
+            state.update(&PacketMetadata { src_port: 443, ..packet("10.1.2.4", 8443, "TCP", 50) });
+            // Between two ephemeral ports.
+            state.update(&packet("10.1.2.5", 50000, "UDP", 400));
+            state.update(&packet("10.1.2.6", 0x0800, "ICMP", 64));
+
+            let top = state.top_talkers(TopBy::Port, SubnetPrefixes::host(), None, 10);
+            let summary: Vec<(Option<u16>, u64, usize, usize)> =
+                top.iter().map(|t| (t.port, t.bytes, t.connections, t.hosts)).collect();
+            let mut expected = vec![(Some(443), 1050, 3, 2)];
+            if rule == ServicePortRule::Lower {
+                expected.push((Some(40000), 400, 1, 1));
+            }
+            assert_eq!(summary, expected, "{:?}", rule);
+            let json = serde_json::to_value(&top[0]).unwrap();
+            assert!(json.get("subnet").is_none());
+            assert_eq!(json["port"], 443);
+        }
+    }
+
+    #[test]
+    fn test_snapshot_restore_round_trip() {
+        let state = TrafficState::new();
+        state.update(&packet("10.0.0.2", 443, "TCP", 100));
+        state.update(&packet("10.0.0.3", 53, "UDP", 60));
+
+        let mut snapshot = state.snapshot();
+        assert_eq!(snapshot.version, SNAPSHOT_VERSION);
+        // Make one connection look older than the timeout.
+        snapshot
+            .connections
+            .iter_mut()
+            .find(|c| c.protocol == "UDP")
+            .unwrap()
+            .idle_ms = 120_000;
+        let json = serde_json::to_string(&snapshot).unwrap();
+
+        let restored_state = TrafficState::new();
+        let snapshot: StateSnapshot = serde_json::from_str(&json).unwrap();
+        let restored = restored_state.restore(snapshot, tokio::time::Duration::from_secs(60));
+
+        assert_eq!(restored, 1);
+        assert_eq!(restored_state.total_packets.load(Ordering::Relaxed), 2);
+        assert_eq!(restored_state.total_bytes.load(Ordering::Relaxed), 160);
+        assert_eq!(restored_state.active_connections.load(Ordering::Relaxed), 1);
+        let entry = restored_state.connections.iter().next().unwrap();
+        assert_eq!(entry.value().protocol, "TCP");
+        assert_eq!(entry.value().bytes_received, 100);
+    }
+
+    #[test]
+    fn test_hand_written_schemas_match_serialization() {
+        use crate::openapi::assert_matches_schema;
+
+        let state = TrafficState::new();
+        let mut with_ttl = packet("10.0.0.2", 443, "TCP", 100);
+        with_ttl.ttl = Some(64);
+        state.update(&with_ttl);
+        let page = state.query_connections(
+            &ConnectionFilter::default(),
+            ConnectionSort::Packets,
+            SortOrder::Desc,
+            0,
+            1,
+        );
+        let labeled = ConnectionEntry {
+            service: Some("https".to_string()),
+            src_device: Some("Laptop".to_string()),
+            dst_device: Some("Living room TV".to_string()),
+            src_vendor: Some("Apple".to_string()),
+            dst_vendor: Some("Sonos".to_string()),
+            ..page.connections[0].clone()
+        };
+        assert_matches_schema(&labeled);
+        assert_matches_schema(&page.connections[0].stats);
+
+        // Every documented enum value must deserialize.
+        fn check_enum<T: ApiSchema + serde::de::DeserializeOwned>() {
+            for value in T::schema()["enum"].as_array().unwrap() {
+                serde_json::from_value::<T>(value.clone()).unwrap();
+            }
+        }
+        check_enum::<ConnectionSort>();
+        check_enum::<SortOrder>();
+        check_enum::<TopBy>();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_connection_rates_decay_and_sort() {
+        let state = TrafficState::new();
+        let rates = |state: &TrafficState| -> Vec<(IpAddr, u64)> {
+            state
+                .query_connections(
+                    &ConnectionFilter::default(),
+                    ConnectionSort::Rate,
+                    SortOrder::Desc,
+                    0,
+                    10,
+                )
+                .connections
+                .iter()
+                .map(|e| (e.connection.src_ip, e.stats.instant_bps))
+                .collect()
+        };
+        let bulk: IpAddr = "10.0.0.2".parse().unwrap();
+        let chatty: IpAddr = "10.0.0.3".parse().unwrap();
+
+        state.sample_rates();
+        for _ in 0..10 {
+            state.update(&packet("10.0.0.2", 443, "TCP", 1000));
+        }
+        state.update(&packet("10.0.0.3", 443, "TCP", 100));
+        tokio::time::advance(tokio::time::Duration::from_secs(1)).await;
+        state.sample_rates();
+        assert_eq!(rates(&state), vec![(bulk, 10_000), (chatty, 100)]);
+
+        // The bulk flow goes idle and decays to zero; the smaller one, still
+        // active, now sorts first despite fewer lifetime bytes.
+        state.update(&packet("10.0.0.3", 443, "TCP", 500));
+        tokio::time::advance(tokio::time::Duration::from_secs(2)).await;
+        state.sample_rates();
+        assert_eq!(rates(&state), vec![(chatty, 250), (bulk, 0)]);
+    }
+
+    #[test]
+    fn test_interarrival_jitter() {
+        let timed = |gaps_ms: &[u64]| {
+            let mut stats = ConnectionStats::default();
+            let mut ktime = 1_000_000_000;
+            stats.observe_arrival(ktime);
+            for gap in gaps_ms {
+                ktime += gap * 1_000_000;
+                stats.observe_arrival(ktime);
+            }
+            stats
+        };
+        let close = |a: Option<f64>, b: f64| (a.unwrap() - b).abs() < 1e-9;
+
+        // A steady 20ms stream (G.711 at 50 packets/s) has no jitter.
+        let steady = timed(&[20; 50]);
+        assert!(close(steady.interarrival_mean_ms(), 20.0));
+        assert!(close(steady.jitter_ms(), 0.0));
+
+        // One late packet moves jitter by 1/16 of its deviation.
+        let late = timed(&[20, 20, 30]);
+        assert!(close(late.jitter_ms(), 10.0 / 16.0));
+        assert!(close(late.interarrival_mean_ms(), 70.0 / 3.0));
+
+        // Alternating 10/30ms gaps settle at 10ms from the 20ms mean.
+        let bursty = timed(&[10, 30].repeat(200));
+        assert!((bursty.jitter_ms().unwrap() - 10.0).abs() < 0.1);
+        assert!(close(bursty.interarrival_mean_ms(), 20.0));
+
+        // Too few packets for either, and reordered events are skipped.
+        assert_eq!(timed(&[]).interarrival_mean_ms(), None);
+        assert_eq!(timed(&[20]).jitter_ms(), None);
+        let mut reordered = timed(&[20, 20]);
+        reordered.observe_arrival(1_000_000_000);
+        assert!(close(reordered.jitter_ms(), 0.0));
+        assert_eq!(reordered.interarrival_gaps, 2);
+    }
+
+    #[test]
+    fn test_jitter_tracked_for_udp_and_configured_ports() {
+        let state = TrafficState::new().with_jitter(JitterScope::new(true, &[5060]));
+        let rtp = packet("10.0.0.2", 16384, "UDP", 200);
+        let sip = packet("10.0.0.2", 5060, "TCP", 600);
+        let web = packet("10.0.0.2", 443, "TCP", 1500);
+        for i in 0..3 {
+            for pkt in [&rtp, &sip, &web] {
+                let kernel = KernelInfo { ktime_ns: Some(i * 20_000_000), ..KernelInfo::default() };
+                state.update_from_kernel(pkt, kernel);
+            }
+        }
+        let stats = |pkt| state.connections.get(&ConnectionKey::from_packet(pkt)).unwrap().clone();
+        assert_eq!(stats(&rtp).jitter_ms(), Some(0.0));
+        assert_eq!(stats(&sip).interarrival_mean_ms(), Some(20.0));
+        assert_eq!(stats(&web).interarrival_mean_ms(), None);
+        let json = serde_json::to_value(stats(&web)).unwrap();
+        assert!(json["jitter_ms"].is_null());
+
+        let no_udp = TrafficState::new().with_jitter(JitterScope::new(false, &[]));
+        for i in 0..3 {
+            let info = KernelInfo { ktime_ns: Some(i), ..KernelInfo::default() };
+            no_udp.update_from_kernel(&rtp, info);
+        }
+        let key = ConnectionKey::from_packet(&rtp);
+        assert_eq!(no_udp.connections.get(&key).unwrap().interarrival_mean_ms(), None);
+    }
+
+    #[test]
+    fn test_tcp_retransmit_counting() {
+        let state = TrafficState::new();
+        let pkt = packet("10.0.0.2", 443, "TCP", 1500);
+        let seg = |seq, payload_len| Some(TcpSegment { seq, payload_len, flags: TCP_ACK });
+
+        state.update_with_segment(&pkt, seg(1000, 1460));
+        state.update_with_segment(&pkt, seg(2460, 1460));
+        // Pure ACKs never count, even with an old sequence number.
+        state.update_with_segment(&pkt, seg(1000, 0));
+        // Same and older data segments are retransmissions.
+        state.update_with_segment(&pkt, seg(2460, 1460));
+        state.update_with_segment(&pkt, seg(1000, 1460));
+        state.update_with_segment(&pkt, seg(3920, 1460));
+
+        let key = ConnectionKey::from_packet(&pkt);
+        let stats = state.connections.get(&key).unwrap().clone();
+        assert_eq!(stats.retransmits, 2);
+        assert_eq!(stats.packets_count, 6);
+        assert!((stats.retransmit_ratio() - 2.0 / 6.0).abs() < 1e-9);
+        assert_eq!(state.tcp_retransmits.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_tcp_retransmit_sequence_wraparound() {
+        let state = TrafficState::new();
+        let pkt = packet("10.0.0.3", 443, "TCP", 100);
+        let seg = |seq, payload_len| Some(TcpSegment { seq, payload_len, flags: TCP_ACK });
+
+        state.update_with_segment(&pkt, seg(u32::MAX - 100, 100));
+        // Wrapped past zero: still new data.
+        state.update_with_segment(&pkt, seg(50, 100));
+        // Pre-wrap sequence is now old.
+        state.update_with_segment(&pkt, seg(u32::MAX - 100, 100));
+
+        let key = ConnectionKey::from_packet(&pkt);
+        assert_eq!(state.connections.get(&key).unwrap().retransmits, 1);
+    }
+
+    #[test]
+    fn test_tcp_state_transitions() {
+        use TcpState::*;
+        const SYN_ACK: u8 = TCP_SYN | TCP_ACK;
+        const FIN_ACK: u8 = TCP_FIN | TCP_ACK;
+        let cases: &[(&str, &[u8], TcpState)] = &[
+            ("client handshake", &[TCP_SYN, TCP_ACK], Established),
+            ("server handshake", &[SYN_ACK, TCP_ACK], Established),
+            ("SYN retransmitted", &[TCP_SYN, TCP_SYN], New),
+            ("SYN+ACK retransmitted", &[SYN_ACK, TCP_ACK, SYN_ACK], Established),
+            ("joined mid-stream", &[TCP_ACK], Established),
+            ("joined at the FIN", &[FIN_ACK], Closing),
+            ("half close", &[TCP_SYN, TCP_ACK, FIN_ACK, TCP_ACK], Closing),
+            ("refused", &[TCP_SYN, TCP_RST], Closed),
+            ("reset mid-stream", &[TCP_ACK, TCP_RST | TCP_ACK], Closed),
+            ("ACKs after a reset", &[TCP_RST, TCP_ACK, FIN_ACK], Closed),
+            ("port reused after a reset", &[TCP_ACK, TCP_RST, TCP_SYN], New),
+            ("new SYN on an open flow", &[TCP_ACK, TCP_SYN], New),
+        ];
+        for (name, flags, expected) in cases {
+            let state = flags.iter().fold(None, |state, &f| Some(next_tcp_state(state, f)));
+            assert_eq!(state, Some(*expected), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_tcp_close_pairs_directions() {
+        let state = TrafficState::new();
+        let out = packet("10.0.0.2", 443, "TCP", 60);
+        let back = PacketMetadata {
+            src_ip: out.dst_ip.clone(),
+            dst_ip: out.src_ip.clone(),
+            src_port: out.dst_port,
+            dst_port: out.src_port,
+            ..out.clone()
+        };
+        let send = |packet: &PacketMetadata, flags| {
+            state.update_with_segment(packet, Some(TcpSegment { seq: 0, payload_len: 0, flags }));
+        };
+        let tcp_state = |packet: &PacketMetadata| {
+            state.connections.get(&ConnectionKey::from_packet(packet)).unwrap().tcp_state
+        };
+
+        send(&out, TCP_SYN);
+        send(&back, TCP_SYN | TCP_ACK);
+        assert_eq!(state.tcp_state_counts(None).new, 2);
+        send(&out, TCP_ACK);
+        send(&back, TCP_ACK);
+        assert_eq!(state.tcp_state_counts(None).established, 2);
+
+        send(&out, TCP_FIN | TCP_ACK);
+        assert_eq!(tcp_state(&out), Some(TcpState::Closing));
+        assert_eq!(tcp_state(&back), Some(TcpState::Established));
+        send(&back, TCP_FIN | TCP_ACK);
+        let counts = state.tcp_state_counts(None);
+        assert_eq!(counts, TcpStateCounts { closed: 2, ..Default::default() });
+        assert_eq!(state.tcp_state_counts(Some("eth9")), TcpStateCounts::default());
+
+        // A RST closes both directions at once; UDP has no state.
+        let other = packet("10.0.0.3", 443, "TCP", 60);
+        send(&other, TCP_ACK);
+        let reply = PacketMetadata {
+            src_ip: other.dst_ip.clone(),
+            dst_ip: other.src_ip.clone(),
+            src_port: other.dst_port,
+            dst_port: other.src_port,
+            ..other.clone()
+        };
+        send(&reply, TCP_ACK);
+        send(&reply, TCP_RST);
+        assert_eq!(tcp_state(&other), Some(TcpState::Closed));
+        state.update(&packet("10.0.0.4", 53, "UDP", 80));
+        assert_eq!(state.tcp_state_counts(None).closed, 4);
+        let json = serde_json::to_value(&*state.connections.iter().next().unwrap()).unwrap();
+        assert!(json.get("tcp_state").is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_closed_connections_leave_promptly() {
+        let state = TrafficState::new();
+        let closed = packet("10.0.0.2", 443, "TCP", 60);
+        let open = packet("10.0.0.3", 443, "TCP", 60);
+        let segment = |flags| Some(TcpSegment { seq: 0, payload_len: 0, flags });
+        state.update_with_segment(&closed, segment(TCP_RST));
+        state.update_with_segment(&open, segment(TCP_ACK));
+
+        let timeout = tokio::time::Duration::from_secs(300);
+        state.cleanup_stale_connections(timeout);
+        assert_eq!(state.connections.len(), 2);
+        tokio::time::advance(CLOSED_LINGER + tokio::time::Duration::from_secs(1)).await;
+        state.cleanup_stale_connections(timeout);
+        assert!(!state.connections.contains_key(&ConnectionKey::from_packet(&closed)));
+        assert_eq!(state.connections.len(), 1);
+        assert_eq!(state.active_connections.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cleanup_in_chunks_yields() {
+        let state = TrafficState::new();
+        let stale = 5 * CLEANUP_CHUNK;
+        for port in 0..stale {
+            let port = 1024 + port as u16;
+            state.update(&PacketMetadata { src_port: port, ..packet("10.0.0.2", 53, "UDP", 80) });
+        }
+        let timeout = tokio::time::Duration::from_secs(60);
+        tokio::time::advance(timeout + tokio::time::Duration::from_secs(1)).await;
+        for port in 0..100 {
+            state.update(&PacketMetadata { src_port: port, ..packet("10.0.0.3", 53, "UDP", 80) });
+        }
+
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let ticker = tokio::spawn({
+            let ticks = ticks.clone();
+            async move {
+                loop {
+                    ticks.fetch_add(1, Ordering::Relaxed);
+                    tokio::task::yield_now().await;
+                }
+            }
+        });
+        let peers = state.cleanup_stale_connections_in_chunks(timeout).await;
+        ticker.abort();
+
+        // Every shard scan and every chunk of removals gave way.
+        let steps = state.connections.shards().len() + stale / CLEANUP_CHUNK;
+        assert!(ticks.load(Ordering::Relaxed) >= steps, "{} ticks", ticks.load(Ordering::Relaxed));
+        assert_eq!(peers.iter().map(|p| p.connections).sum::<u64>(), stale as u64);
+        assert_eq!(state.connections.len(), 100);
+        assert_eq!(state.active_connections.load(Ordering::Relaxed), 100);
+        assert_eq!(state.connections_expired.load(Ordering::Relaxed), stale as u64);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_connection_churn_counters() {
+        let state = TrafficState::new();
+        state.update(&packet("10.0.0.2", 443, "TCP", 60));
+        state.update(&packet("10.0.0.2", 443, "TCP", 60));
+        state.update(&packet("10.0.0.3", 53, "UDP", 80));
+        let churn = state.churn();
+        assert_eq!((churn.created, churn.expired), (2, 0));
+
+        let timeout = tokio::time::Duration::from_secs(60);
+        tokio::time::advance(timeout + tokio::time::Duration::from_secs(1)).await;
+        state.update(&packet("10.0.0.4", 443, "TCP", 60));
+        state.cleanup_stale_connections(timeout);
+        let churn = state.churn();
+        assert_eq!((churn.created, churn.expired), (3, 2));
         assert_eq!(state.active_connections.load(Ordering::Relaxed), 1);
+
+        // Two expiries 10s apart in the sampler's history: 0.2/s.
+        let at = std::time::Instant::now();
+        state.churn_rates.record(at, 1, 0);
+        state.churn_rates.record(at + std::time::Duration::from_secs(10), 3, 2);
+        assert_eq!(state.churn().expired_60s, 0.2);
+
+        state.reset();
+        assert_eq!(state.churn(), ConnectionChurn::default());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cleanup_folds_connections_into_peers() {
+        let local = LocalNetworks::new(vec!["10.0.0.0/24".parse().unwrap()]);
+        let state = TrafficState::new().with_local_networks(local);
+        // Download from the peer on two ports, and the reply direction.
+        state.update(&packet("93.184.216.34", 443, "TCP", 1000));
+        state.update(&packet("93.184.216.34", 443, "TCP", 500));
+        state.update(&packet("93.184.216.34", 80, "TCP", 200));
+        let upload = PacketMetadata {
+            src_ip: "10.0.0.1".into(),
+            dst_ip: "93.184.216.34".into(),
+            ..packet("10.0.0.1", 443, "TCP", 60)
+        };
+        state.update(&upload);
+
+        let timeout = tokio::time::Duration::from_secs(60);
+        assert!(state.cleanup_stale_connections(timeout).is_empty());
+        tokio::time::advance(timeout * 2).await;
+        let peers = state.cleanup_stale_connections(timeout);
+        assert!(state.connections.is_empty());
+        assert_eq!(peers.len(), 1);
+        let peer = &peers[0];
+        assert_eq!(peer.ip, "93.184.216.34");
+        assert_eq!((peer.bytes, peer.packets, peer.connections), (1760, 4, 3));
+        assert!(peer.first_seen <= peer.last_seen);
+        assert_eq!(peer.day % DAY_MS, 0);
+        assert!(peer.day <= peer.last_seen && peer.last_seen < peer.day + DAY_MS);
+    }
+
+    #[test]
+    fn test_forwarded_packets_count_once() {
+        // A router forwarding LAN -> WAN sees each packet ingress on eth1
+        // and egress on eth0.
+        let route = |state: &TrafficState| {
+            for port in [443, 443, 80] {
+                let mut lan_in = packet("192.168.1.10", port, "TCP", 1000);
+                lan_in.interface = "eth1".into();
+                let mut wan_out = lan_in.clone();
+                wan_out.interface = "eth0".into();
+                wan_out.direction = "egress".into();
+                state.update(&lan_in);
+                state.update(&wan_out);
+            }
+        };
+
+        let counted_twice = TrafficState::new();
+        route(&counted_twice);
+        assert_eq!(counted_twice.total_packets.load(Ordering::Relaxed), 6);
+        assert_eq!(counted_twice.total_bytes.load(Ordering::Relaxed), 6000);
+
+        let once = TrafficState::new().with_forward_dedup(std::time::Duration::from_secs(1));
+        route(&once);
+        assert_eq!(once.total_packets.load(Ordering::Relaxed), 3);
+        assert_eq!(once.total_bytes.load(Ordering::Relaxed), 3000);
+        assert_eq!(once.forwarded_duplicates.load(Ordering::Relaxed), 3);
+        // Credited to the interface and direction of the first sighting.
+        assert_eq!(once.totals(Some("eth1")).packets, 3);
+        assert_eq!(once.totals(Some("eth0")).packets, 0);
+        let sent: u64 = once.connections.iter().map(|c| c.bytes_sent).sum();
+        let received: u64 = once.connections.iter().map(|c| c.bytes_received).sum();
+        assert_eq!((sent, received), (0, 3000));
+
+        // Back-to-back packets on one interface are not mistaken for copies.
+        let mut local = packet("10.0.0.2", 22, "TCP", 60);
+        local.direction = "egress".into();
+        assert!(once.update(&local));
+        assert!(once.update(&local));
+        assert_eq!(once.total_packets.load(Ordering::Relaxed), 5);
     }
 }
@@ -1,22 +1,632 @@
-use crate::state::{AggregatedBucket, PacketMetadata};
-use chrono;
-use rusqlite::{params, Connection, Result};
-use std::collections::HashMap;
-use std::sync::Arc;
+use crate::alerts::{Alert, StoredAlert};
+use crate::categories::PortMatch;
+use crate::config::{ClickHouseConfig, PostgresConfig, SqliteConfig, StorageConfig};
+use crate::geoip::CountryMatch;
+use crate::health::Heartbeat;
+use crate::icmp::IcmpMessage;
+use crate::locality::FlowDirection;
+use crate::openapi::{api_schema, string_enum, ApiSchema};
+use crate::query_cache::QueryCache;
+use crate::spill::{SpillFile, SpillRecord};
+use crate::state::{dscp_class_name, AggregatedBucket, ConnectionKey, PacketMetadata, PeerTotals};
+use ayaflow_common::AggregationKey;
+use futures_util::FutureExt;
+use ipnet::IpNet;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
+use prometheus_client::registry::Registry;
+use rusqlite::types::Value;
+use rusqlite::{
+    params, params_from_iter, Connection, OpenFlags, OptionalExtension, ParamsFromIter, Result,
+    Transaction, TransactionBehavior,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::future::Future;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicI64, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, PoisonError};
+use tokio::runtime::RuntimeFlavor;
 use tokio::sync::mpsc::Receiver;
-use tokio::time::{interval, Duration};
+use tokio::time::{interval, interval_at, Duration, Instant, MissedTickBehavior};
+
+pub use ayaflow_common::api::{HistoryRow, RowKind};
+
+/// Messages accepted by the storage writer task.
+#[derive(Clone)]
+pub enum StorageEvent {
+    /// A batch of captured packets, stored raw or folded into the current
+    /// aggregation window depending on the writer mode.
+    Packets(Vec<PacketMetadata>),
+    /// Already-aggregated flow summaries (kernel aggregation sweeps), written
+    /// as-is regardless of the writer mode.
+    Buckets(Vec<AggregatedBucket>),
+    /// A raised alert, written immediately.
+    Alert(Alert),
+    /// Reverse-DNS results as `(ip, hostname)`, resolved after the packets
+    /// that carried the address were queued.
+    Hostnames(Vec<(String, String)>),
+    /// Totals of connections dropped from the live table, folded into
+    /// `peers`.
+    Peers(Vec<PeerTotals>),
+}
 
 #[derive(Clone)]
 pub struct Storage {
+    /// The writer task, retention, and every other write go through this.
     conn: Arc<std::sync::Mutex<Connection>>,
+    /// Read-only connection for queries, so they never wait on the writer's
+    /// transactions.  The same connection as `conn` for in-memory and
+    /// read-only databases.
+    reader: Arc<std::sync::Mutex<Connection>>,
+    /// The database's `-wal` file; None in memory and for read-only handles.
+    wal_path: Option<PathBuf>,
+    /// Path and settings to open fresh connections with in `reopen`.
+    reopen_with: Option<(String, SqliteConfig)>,
+    /// Overflow for batches while writes keep failing; None when disabled.
+    spill: Option<Arc<std::sync::Mutex<SpillFile>>>,
+    /// Writes that failed since the last one that succeeded.
+    consecutive_failures: Arc<AtomicU32>,
+    /// Networks whose hosts get per-hour usage rollups in `host_usage`.
+    local_networks: Vec<IpNet>,
+    /// Granularity of rows written by the aggregated writer.
+    aggregation_key: AggregationKey,
+    /// When the raw writer commits its buffer.
+    flush: StorageConfig,
+    /// Recent `query_packets` results, invalidated by every write.
+    query_cache: Arc<QueryCache>,
+    /// Held while a snapshot is written, so only one runs at a time.
+    snapshot_lock: Arc<std::sync::Mutex<()>>,
+    /// Written with every stored row; None stores NULL.
+    instance: Option<String>,
+    /// The reader's `PRAGMA data_version` when last checked.  It moves when
+    /// any other connection commits, this process's writer or another
+    /// instance's, and then the query cache is dropped.
+    data_version: Arc<AtomicI64>,
+    metrics: Arc<StorageMetrics>,
+}
+
+/// Writer and retention instrumentation, exported as `ayaflow_storage_*`.
+#[derive(Debug)]
+pub struct StorageMetrics {
+    /// Packet rows (raw or aggregated) committed.
+    pub rows_inserted: Counter,
+    pub flush_duration_seconds: Histogram,
+    /// Longest flush, in microseconds, since the headroom sampler last took
+    /// it.
+    flush_peak_us: AtomicU64,
+    /// Rows per flush, including rows that failed to insert.
+    pub flush_batch_size: Histogram,
+    /// Transactions that failed to start, write, or commit; their rows are
+    /// retried or spilled.
+    pub transaction_failures: Counter,
+    /// Rows that failed to insert on their own, inside a committed
+    /// transaction.  They are not retried.
+    pub insert_failures: Counter,
+    /// Rows written to the spill file, and rows dropped because it was full.
+    pub spilled_rows: Counter,
+    pub spill_dropped_rows: Counter,
+    /// Spilled rows written to the database after it recovered.
+    pub replayed_rows: Counter,
+    pub spill_size_bytes: Gauge,
+    /// Times the writer task died and was restarted.
+    pub writer_restarts: Counter,
+    pub retention_deleted: Counter,
+    /// Main database file size, refreshed on every scrape.
+    pub db_size_bytes: Gauge,
+    /// Write-ahead log size, refreshed on every scrape.
+    pub wal_size_bytes: Gauge,
+    /// Forced checkpoints that truncated the WAL.
+    pub wal_checkpoints: Counter,
+    /// Forced checkpoints that could not finish because a reader held an
+    /// old snapshot, or that failed.
+    pub wal_checkpoints_incomplete: Counter,
+    /// History queries answered from the query cache, and ones that ran.
+    pub query_cache_hits: Counter,
+    pub query_cache_misses: Counter,
+    /// Estimated memory held by cached query results.
+    pub query_cache_bytes: Gauge,
+    /// Write transactions retried after SQLITE_BUSY, e.g. while another
+    /// instance held the write lock.
+    pub busy_retries: Counter,
+    /// Rows stamped further in the future than `max_clock_skew_seconds`
+    /// and stored at the write time instead.
+    pub clamped_timestamps: Counter,
+}
+
+impl Default for StorageMetrics {
+    fn default() -> Self {
+        Self {
+            rows_inserted: Counter::default(),
+            // 0.5ms .. ~8s
+            flush_duration_seconds: Histogram::new(exponential_buckets(0.0005, 2.0, 15)),
+            flush_peak_us: AtomicU64::new(0),
+            // 1 .. 16384 rows
+            flush_batch_size: Histogram::new(exponential_buckets(1.0, 2.0, 15)),
+            transaction_failures: Counter::default(),
+            insert_failures: Counter::default(),
+            spilled_rows: Counter::default(),
+            spill_dropped_rows: Counter::default(),
+            replayed_rows: Counter::default(),
+            spill_size_bytes: Gauge::default(),
+            writer_restarts: Counter::default(),
+            retention_deleted: Counter::default(),
+            db_size_bytes: Gauge::default(),
+            wal_size_bytes: Gauge::default(),
+            wal_checkpoints: Counter::default(),
+            wal_checkpoints_incomplete: Counter::default(),
+            query_cache_hits: Counter::default(),
+            query_cache_misses: Counter::default(),
+            query_cache_bytes: Gauge::default(),
+            busy_retries: Counter::default(),
+            clamped_timestamps: Counter::default(),
+        }
+    }
+}
+
+impl StorageMetrics {
+    pub fn register(&self, registry: &mut Registry) {
+        registry.register(
+            "ayaflow_storage_rows_inserted",
+            "Packet rows written to the database",
+            self.rows_inserted.clone(),
+        );
+        registry.register(
+            "ayaflow_storage_flush_duration_seconds",
+            "Time taken by one storage flush transaction",
+            self.flush_duration_seconds.clone(),
+        );
+        registry.register(
+            "ayaflow_storage_flush_batch_size",
+            "Rows written per storage flush",
+            self.flush_batch_size.clone(),
+        );
+        registry.register(
+            "ayaflow_storage_transaction_failures",
+            "Storage transactions that failed to start or commit",
+            self.transaction_failures.clone(),
+        );
+        registry.register(
+            "ayaflow_storage_insert_failures",
+            "Rows that failed to insert and were skipped",
+            self.insert_failures.clone(),
+        );
+        registry.register(
+            "ayaflow_storage_spilled_rows",
+            "Rows written to the spill file while the database refused writes",
+            self.spilled_rows.clone(),
+        );
+        registry.register(
+            "ayaflow_storage_spill_dropped_rows",
+            "Rows dropped because the spill file was full",
+            self.spill_dropped_rows.clone(),
+        );
+        registry.register(
+            "ayaflow_storage_replayed_rows",
+            "Spilled rows written to the database after it recovered",
+            self.replayed_rows.clone(),
+        );
+        registry.register(
+            "ayaflow_storage_spill_size_bytes",
+            "Size of the spill file",
+            self.spill_size_bytes.clone(),
+        );
+        registry.register(
+            "ayaflow_storage_writer_restarts",
+            "Times the storage writer task died and was restarted",
+            self.writer_restarts.clone(),
+        );
+        registry.register(
+            "ayaflow_storage_retention_deleted_rows",
+            "Packet rows deleted by data retention",
+            self.retention_deleted.clone(),
+        );
+        registry.register(
+            "ayaflow_storage_db_size_bytes",
+            "Size of the main database file, excluding the WAL",
+            self.db_size_bytes.clone(),
+        );
+        registry.register(
+            "ayaflow_storage_wal_size_bytes",
+            "Size of the SQLite write-ahead log file",
+            self.wal_size_bytes.clone(),
+        );
+        registry.register(
+            "ayaflow_storage_wal_checkpoints",
+            "Forced WAL checkpoints that truncated the log",
+            self.wal_checkpoints.clone(),
+        );
+        registry.register(
+            "ayaflow_storage_wal_checkpoints_incomplete",
+            "Forced WAL checkpoints blocked by readers or failed",
+            self.wal_checkpoints_incomplete.clone(),
+        );
+        registry.register(
+            "ayaflow_storage_query_cache_hits",
+            "History queries answered from the query cache",
+            self.query_cache_hits.clone(),
+        );
+        registry.register(
+            "ayaflow_storage_query_cache_misses",
+            "History queries that ran against the database",
+            self.query_cache_misses.clone(),
+        );
+        registry.register(
+            "ayaflow_storage_query_cache_bytes",
+            "Estimated memory held by cached history query results",
+            self.query_cache_bytes.clone(),
+        );
+        registry.register(
+            "ayaflow_storage_busy_retries",
+            "Write transactions retried because the database was locked",
+            self.busy_retries.clone(),
+        );
+        registry.register(
+            "ayaflow_storage_clamped_timestamps",
+            "Rows stamped too far in the future and stored at the write time",
+            self.clamped_timestamps.clone(),
+        );
+    }
+
+    pub(crate) fn record_flush(&self, batch: usize, inserted: u64, elapsed: Duration) {
+        self.rows_inserted.inc_by(inserted);
+        self.flush_batch_size.observe(batch as f64);
+        self.flush_duration_seconds.observe(elapsed.as_secs_f64());
+        self.flush_peak_us.fetch_max(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// The longest flush since the last call.
+    pub fn take_flush_peak(&self) -> Duration {
+        Duration::from_micros(self.flush_peak_us.swap(0, Ordering::Relaxed))
+    }
+
+    pub fn stats(&self) -> StorageStats {
+        StorageStats {
+            db_size_bytes: self.db_size_bytes.get(),
+            wal_size_bytes: self.wal_size_bytes.get(),
+            rows_inserted: self.rows_inserted.get(),
+            transaction_failures: self.transaction_failures.get(),
+            insert_failures: self.insert_failures.get(),
+            spilled_rows: self.spilled_rows.get(),
+            spill_dropped_rows: self.spill_dropped_rows.get(),
+            spill_size_bytes: self.spill_size_bytes.get(),
+            writer_restarts: self.writer_restarts.get(),
+            query_cache_bytes: self.query_cache_bytes.get(),
+        }
+    }
+}
+
+api_schema! {
+    /// Writer and database figures, for the diagnostic dump.
+    #[derive(Debug, Clone, Serialize)]
+    pub struct StorageStats {
+        pub db_size_bytes: i64,
+        pub wal_size_bytes: i64,
+        pub rows_inserted: u64,
+        pub transaction_failures: u64,
+        pub insert_failures: u64,
+        pub spilled_rows: u64,
+        pub spill_dropped_rows: u64,
+        pub spill_size_bytes: i64,
+        pub writer_restarts: u64,
+        pub query_cache_bytes: i64,
+    }
+}
+
+const HOUR_MS: i64 = 3_600_000;
+
+/// Consecutive failed writes after which buffered rows go to the spill
+/// file instead of staying in memory.
+const SPILL_AFTER_FAILURES: u32 = 3;
+
+/// Delay before the first writer restart, doubling up to the maximum.  A
+/// writer that ran longer than the maximum starts over at the minimum.
+const RESTART_BACKOFF_MIN: Duration = Duration::from_secs(1);
+const RESTART_BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+/// Snapshot files start with this, so ones left behind can be found.
+const SNAPSHOT_PREFIX: &str = ".ayaflow-snapshot-";
+
+/// A snapshot file untouched this long belongs to an export that died.
+/// `VACUUM INTO` keeps writing, so one in progress is never this old.
+const SNAPSHOT_STALE_AFTER: Duration = Duration::from_secs(3600);
+
+/// Bytes and packets per (local host, hour start, direction) for one flush.
+pub(crate) type HostUsage = HashMap<(String, i64, &'static str), (u64, u64)>;
+
+/// Hostname per address among one flush's rows.
+type PacketHostnames<'a> = BTreeMap<&'a str, &'a str>;
+
+/// `state` key set once the hostnames stored on packet rows have been
+/// copied into the `hostnames` table.
+const HOSTNAMES_MIGRATED_KEY: &str = "hostnames_migrated";
+
+/// Bucket size for usage queries.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UsageGranularity {
+    #[default]
+    Hour,
+    Day,
+}
+
+impl UsageGranularity {
+    pub(crate) fn millis(self) -> i64 {
+        match self {
+            UsageGranularity::Hour => HOUR_MS,
+            UsageGranularity::Day => 24 * HOUR_MS,
+        }
+    }
+}
+
+impl ApiSchema for UsageGranularity {
+    fn schema() -> serde_json::Value {
+        string_enum(&["hour", "day"])
+    }
+}
+
+api_schema! {
+    /// Traffic totals for one local host in one time bucket.
+    #[derive(Debug, Clone, Serialize)]
+    pub struct HostUsageRow {
+        pub ip: String,
+        /// Bucket start, milliseconds since the Unix epoch (UTC).
+        pub bucket: i64,
+        /// "rx" (received by the local host) or "tx" (sent by it).
+        pub direction: String,
+        pub bytes: u64,
+        pub packets: u64,
+    }
+}
+
+api_schema! {
+    /// Stored totals for one connection, both directions together.
+    #[derive(Debug, Clone, Default, Serialize)]
+    pub struct StoredConnectionTotals {
+        pub rows: u64,
+        /// Packets the rows stand for, counting aggregated ones in full.
+        pub packets: u64,
+        pub bytes: u64,
+        /// Oldest and newest row timestamps; absent without rows.
+        pub first_seen: Option<i64>,
+        pub last_seen: Option<i64>,
+    }
+}
+
+impl StoredConnectionTotals {
+    /// Fold in another connection's totals.
+    pub fn add(&mut self, other: &Self) {
+        self.rows += other.rows;
+        self.packets += other.packets;
+        self.bytes += other.bytes;
+        self.first_seen = self.first_seen.into_iter().chain(other.first_seen).min();
+        self.last_seen = self.last_seen.into_iter().chain(other.last_seen).max();
+    }
+}
+
+/// Filters and page position for `/api/alerts`.
+#[derive(Debug, Clone, Default)]
+pub struct AlertFilter {
+    /// Only rows with a lower id, i.e. the page after the one ending there.
+    pub before_id: Option<i64>,
+    pub severity: Option<String>,
+    pub rule: Option<String>,
+    /// Only rows that fired at or after this time, milliseconds since the
+    /// Unix epoch.
+    pub since: Option<i64>,
+    pub acked: Option<bool>,
+    /// Only rows raised by this instance.
+    pub instance: Option<String>,
+}
+
+/// Filters shared by the history API and the offline `query` / `top`
+/// subcommands.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PacketFilter {
+    /// Earliest timestamp, milliseconds since the Unix epoch.
+    pub from: Option<i64>,
+    /// Latest timestamp, milliseconds since the Unix epoch.
+    pub to: Option<i64>,
+    /// Match packets with this source or destination IP.
+    pub ip: Option<String>,
+    /// Match packets seen on this interface.
+    pub interface: Option<String>,
+    /// Match packets with this source or destination MAC, in the stored
+    /// lower-case colon form.
+    pub mac: Option<String>,
+    /// Match packets in this direction relative to `local_networks`.
+    pub direction: Option<FlowDirection>,
+    /// Match packets whose service port is in a category.
+    pub category: Option<PortMatch>,
+    /// Match rows written by this instance.
+    pub instance: Option<String>,
+    /// Match packets of this protocol, ignoring case ("TCP", "icmp").
+    pub protocol: Option<String>,
+    /// Match ICMP and ICMPv6 packets of this type.
+    pub icmp_type: Option<u8>,
+    /// Match packets whose remote end GeoIP placed in this country, or
+    /// did not place.
+    pub country: Option<CountryMatch>,
+    /// Match packets whose remote end is in this autonomous system.
+    pub asn: Option<u32>,
+}
+
+impl PacketFilter {
+    #[cfg(feature = "clickhouse")]
+    pub(crate) fn range(&self) -> (i64, i64) {
+        (self.from.unwrap_or(i64::MIN), self.to.unwrap_or(i64::MAX))
+    }
+
+    pub(crate) fn direction(&self) -> Option<&'static str> {
+        self.direction.map(FlowDirection::as_str)
+    }
+}
+
+/// Packet column to group by in `query_top`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+#[value(rename_all = "snake_case")]
+pub enum TopColumn {
+    SrcIp,
+    DstIp,
+    DstPort,
+    Protocol,
+    Domain,
+    Interface,
+    /// GeoIP country of the remote end; rows GeoIP did not place are left
+    /// out.
+    Country,
+}
+
+impl TopColumn {
+    pub fn column(self) -> &'static str {
+        match self {
+            TopColumn::SrcIp => "src_ip",
+            TopColumn::DstIp => "dst_ip",
+            TopColumn::DstPort => "dst_port",
+            TopColumn::Protocol => "protocol",
+            TopColumn::Domain => "domain",
+            TopColumn::Interface => "interface",
+            TopColumn::Country => "country",
+        }
+    }
+}
+
+api_schema! {
+    /// One group from `query_top`.  With aggregation enabled a stored row is
+    /// a whole window, so `rows` counts stored rows and `packets` the
+    /// packets they cover.
+    #[derive(Debug, Clone, Serialize)]
+    pub struct StoredTalker {
+        pub key: String,
+        pub bytes: u64,
+        pub packets: u64,
+        pub rows: u64,
+    }
+}
+
+api_schema! {
+    /// Stored traffic matching a filter, from `query_totals`.
+    #[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+    pub struct StoredTotals {
+        pub rows: u64,
+        /// Packets the rows stand for, counting aggregated ones in full.
+        pub packets: u64,
+        pub bytes: u64,
+    }
+}
+
+/// `state` key holding the last packet row `fill_hostnames` committed, where
+/// the next hostname backfill resumes.
+pub const BACKFILL_POSITION_KEY: &str = "dns_backfill_position";
+
+/// Packet rows missing a hostname, from `unresolved_rows`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UnresolvedRows {
+    /// Rows read; 0 when none are left after the start row.
+    pub rows: usize,
+    /// The last row read, so the batch covers rows after the start row up
+    /// to and including this one.
+    pub through_id: i64,
+    /// The highest row in the table.
+    pub last_id: i64,
+    /// Distinct addresses those rows lack a hostname for.
+    pub addresses: Vec<String>,
+}
+
+/// One `packets` row with every column, as `ayaflow migrate` copies it
+/// between backends.  Fields follow the ClickHouse column order; columns
+/// SQLite leaves NULL there read as the values queries show for them.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PacketRecord {
+    pub timestamp: i64,
+    pub src_ip: String,
+    pub dst_ip: String,
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub protocol: String,
+    pub length: u64,
+    pub direction: String,
+    pub src_hostname: Option<String>,
+    pub dst_hostname: Option<String>,
+    pub domain: Option<String>,
+    pub ttl: Option<u8>,
+    pub dscp: Option<u8>,
+    /// Granularity an aggregated row was keyed at; None for raw packets.
+    pub aggregation: Option<String>,
+    pub interface: String,
+    pub window_start: Option<i64>,
+    pub window_end: Option<i64>,
+    pub payload_length: u64,
+    pub packet_count: u64,
+    pub src_mac: Option<String>,
+    pub dst_mac: Option<String>,
+    pub flow_direction: Option<String>,
+    pub instance: Option<String>,
+    pub icmp_type: Option<u8>,
+    pub icmp_code: Option<u8>,
+    /// Absent in exports from before GeoIP.
+    #[serde(default)]
+    pub country: Option<String>,
+    #[serde(default)]
+    pub asn: Option<u32>,
+}
+
+/// A batch of rows from `export_packets`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExportedPackets {
+    pub rows: Vec<PacketRecord>,
+    /// Where the next batch starts; the start position when `rows` is
+    /// empty.
+    pub through: i64,
 }
 
 impl Storage {
+    /// Open an existing database without creating or migrating anything, for
+    /// offline inspection while the daemon may be writing to it.
+    pub fn open_read_only(db_path: &str) -> Result<Self> {
+        let conn = Connection::open_with_flags(
+            db_path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+        let conn = Arc::new(std::sync::Mutex::new(conn));
+        Ok(Self {
+            reader: conn.clone(),
+            conn,
+            wal_path: None,
+            reopen_with: None,
+            spill: None,
+            consecutive_failures: Arc::default(),
+            local_networks: Vec::new(),
+            aggregation_key: AggregationKey::default(),
+            flush: StorageConfig::default(),
+            // The daemon may write without this handle seeing it.
+            query_cache: Arc::new(QueryCache::disabled()),
+            snapshot_lock: Arc::default(),
+            instance: None,
+            data_version: Arc::default(),
+            metrics: Arc::default(),
+        })
+    }
+
+    #[cfg(test)]
     pub fn new(db_path: &str) -> Result<Self> {
-        let conn = Connection::open(db_path)?;
+        Self::open(db_path, &SqliteConfig::default())
+    }
+
+    /// Open or create the database with the writer/reader connection pair.
+    pub fn open(db_path: &str, sqlite: &SqliteConfig) -> Result<Self> {
+        let busy_timeout = Duration::from_millis(sqlite.busy_timeout_ms);
+        let mut conn = Connection::open(db_path)?;
+        conn.busy_timeout(busy_timeout)?;
 
         let _: String = conn.query_row("PRAGMA journal_mode=WAL;", [], |row| row.get(0))?;
         conn.execute_batch("PRAGMA synchronous=NORMAL;")?;
+        conn.pragma_update(None, "wal_autocheckpoint", sqlite.wal_autocheckpoint_pages)?;
 
         conn.execute(
             "CREATE TABLE IF NOT EXISTS packets (
@@ -31,113 +641,524 @@ impl Storage {
                 direction TEXT,
                 src_hostname TEXT,
                 dst_hostname TEXT,
-                domain TEXT
+                domain TEXT,
+                ttl INTEGER,
+                dscp INTEGER
             )",
             [],
         )?;
 
-        // Migrate existing databases: add hostname columns if missing.
-        // ALTER TABLE ... ADD COLUMN is a no-op when the column already exists
-        // in SQLite >= 3.35, but older versions error. We ignore errors here.
-        let _ = conn.execute("ALTER TABLE packets ADD COLUMN src_hostname TEXT", []);
-        let _ = conn.execute("ALTER TABLE packets ADD COLUMN dst_hostname TEXT", []);
-        let _ = conn.execute("ALTER TABLE packets ADD COLUMN domain TEXT", []);
-        let _ = conn.execute("ALTER TABLE packets ADD COLUMN direction TEXT", []);
+        // Migrate existing databases: add columns introduced after the
+        // original schema.  Existing rows get NULL for the new columns.
+        add_column_if_missing(&conn, "packets", "src_hostname", "TEXT")?;
+        add_column_if_missing(&conn, "packets", "dst_hostname", "TEXT")?;
+        add_column_if_missing(&conn, "packets", "domain", "TEXT")?;
+        add_column_if_missing(&conn, "packets", "direction", "TEXT")?;
+        add_column_if_missing(&conn, "packets", "ttl", "INTEGER")?;
+        add_column_if_missing(&conn, "packets", "dscp", "INTEGER")?;
+        // Granularity an aggregated row was keyed at; NULL for raw packets.
+        add_column_if_missing(&conn, "packets", "aggregation", "TEXT")?;
+        add_column_if_missing(&conn, "packets", "interface", "TEXT")?;
+        // Aggregation window of an aggregated row, epoch ms; NULL for raw packets.
+        add_column_if_missing(&conn, "packets", "window_start", "INTEGER")?;
+        add_column_if_missing(&conn, "packets", "window_end", "INTEGER")?;
+        // Transport payload bytes; NULL for rows stored before payload tracking.
+        add_column_if_missing(&conn, "packets", "payload_length", "INTEGER")?;
+        // Packets a row stands for.  Existing rows, including aggregated ones
+        // written before the column existed, are backfilled with 1.
+        add_column_if_missing(&conn, "packets", "packet_count", "INTEGER NOT NULL DEFAULT 1")?;
+        // Ethernet addresses; NULL on L3 interfaces and for aggregated rows.
+        add_column_if_missing(&conn, "packets", "src_mac", "TEXT")?;
+        add_column_if_missing(&conn, "packets", "dst_mac", "TEXT")?;
+        add_column_if_missing(&conn, "packets", "flow_direction", "TEXT")?;
+        // The agent that wrote the row; NULL for rows from before instances.
+        add_column_if_missing(&conn, "packets", "instance", "TEXT")?;
+        // ICMP and ICMPv6 type and code; NULL for other protocols, host-pair
+        // aggregated rows and rows from before ICMP capture.
+        add_column_if_missing(&conn, "packets", "icmp_type", "INTEGER")?;
+        add_column_if_missing(&conn, "packets", "icmp_code", "INTEGER")?;
+        // GeoIP country code and ASN of the remote end; NULL without the
+        // databases, for unlisted addresses and rows from before GeoIP.
+        add_column_if_missing(&conn, "packets", "country", "TEXT")?;
+        add_column_if_missing(&conn, "packets", "asn", "INTEGER")?;
 
         conn.execute(
             "CREATE INDEX IF NOT EXISTS idx_timestamp ON packets(timestamp)",
             [],
         )?;
+        // Per-connection lookups match both orientations of the 4-tuple.
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_tuple ON packets(src_ip, dst_ip, src_port, dst_port)",
+            [],
+        )?;
+        // `country` and `asn` history filters, newest first within each.
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_country ON packets(country, timestamp)",
+            [],
+        )?;
+        conn.execute("CREATE INDEX IF NOT EXISTS idx_asn ON packets(asn, timestamp)", [])?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS state (
+                key TEXT PRIMARY KEY,
+                value TEXT NOT NULL,
+                updated_at INTEGER NOT NULL
+            )",
+            [],
+        )?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS alerts (
+                id INTEGER PRIMARY KEY,
+                timestamp INTEGER NOT NULL,
+                rule TEXT NOT NULL,
+                severity TEXT NOT NULL,
+                subject TEXT NOT NULL,
+                message TEXT NOT NULL
+            )",
+            [],
+        )?;
+        // `timestamp` is the first firing.  Repeats fold into the open row
+        // of their rule and subject; rows from before the fold count once.
+        add_column_if_missing(&conn, "alerts", "last_seen", "INTEGER")?;
+        add_column_if_missing(&conn, "alerts", "count", "INTEGER NOT NULL DEFAULT 1")?;
+        add_column_if_missing(&conn, "alerts", "acked", "INTEGER NOT NULL DEFAULT 0")?;
+        add_column_if_missing(&conn, "alerts", "acked_by", "TEXT")?;
+        add_column_if_missing(&conn, "alerts", "acked_at", "INTEGER")?;
+        // The agent that raised it; NULL for rows from before instances.
+        add_column_if_missing(&conn, "alerts", "instance", "TEXT")?;
+        conn.execute("UPDATE alerts SET last_seen = timestamp WHERE last_seen IS NULL", [])?;
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_alerts_subject ON alerts(rule, subject)",
+            [],
+        )?;
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_alerts_last_seen ON alerts(last_seen)",
+            [],
+        )?;
+
+        // Hostnames per address, joined into queries.  Packet rows from
+        // before the table held every name keep theirs in src_hostname and
+        // dst_hostname, which are still read but no longer written.
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS hostnames (
+                ip TEXT PRIMARY KEY,
+                hostname TEXT NOT NULL,
+                resolved_at INTEGER NOT NULL
+            )",
+            [],
+        )?;
+        migrate_packet_hostnames(&mut conn)?;
+
+        // Storage sample rate each instance ran at from `since` (epoch ms)
+        // until its next row; '' for an unnamed agent.  Packets before an
+        // instance's first row were stored unsampled.
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS capture_meta (
+                instance TEXT NOT NULL,
+                since INTEGER NOT NULL,
+                sample_rate INTEGER NOT NULL,
+                PRIMARY KEY (instance, since)
+            )",
+            [],
+        )?;
+
+        // Rollups are kept per instance ('' for an unnamed agent), so agents
+        // sharing the database never fold into each other's rows; queries
+        // sum over them.
+        let host_usage = "CREATE TABLE IF NOT EXISTS host_usage (
+                local_ip TEXT NOT NULL,
+                hour INTEGER NOT NULL,
+                direction TEXT NOT NULL,
+                bytes INTEGER NOT NULL,
+                packets INTEGER NOT NULL,
+                instance TEXT NOT NULL DEFAULT '',
+                PRIMARY KEY (local_ip, hour, direction, instance)
+            )";
+        conn.execute(host_usage, [])?;
+        key_by_instance(&mut conn, "host_usage", host_usage)?;
+
+        // Every remote address ever talked to, per day.  Outlives packet
+        // retention, so it stays the record of a peer whose packets were
+        // sampled, aggregated or deleted away.
+        let peers = "CREATE TABLE IF NOT EXISTS peers (
+                remote_ip TEXT NOT NULL,
+                day INTEGER NOT NULL,
+                first_seen INTEGER NOT NULL,
+                last_seen INTEGER NOT NULL,
+                bytes INTEGER NOT NULL,
+                packets INTEGER NOT NULL,
+                connections INTEGER NOT NULL,
+                instance TEXT NOT NULL DEFAULT '',
+                PRIMARY KEY (remote_ip, day, instance)
+            )";
+        conn.execute(peers, [])?;
+        key_by_instance(&mut conn, "peers", peers)?;
 
+        let conn = Arc::new(std::sync::Mutex::new(conn));
+        // A second connection to ":memory:" would open a separate, empty
+        // database.
+        let in_memory = db_path.is_empty() || db_path == ":memory:";
+        let (reader, wal_path) = if in_memory {
+            (conn.clone(), None)
+        } else {
+            let reader = Connection::open_with_flags(
+                db_path,
+                OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+            )?;
+            reader.busy_timeout(busy_timeout)?;
+            let wal_path = PathBuf::from(format!("{}-wal", db_path));
+            (Arc::new(std::sync::Mutex::new(reader)), Some(wal_path))
+        };
+        if !in_memory {
+            remove_stale_snapshots(&snapshot_dir(db_path, sqlite));
+        }
+        let spill = (!in_memory && sqlite.spill_max_mb > 0).then(|| {
+            let path = sqlite
+                .spill_path
+                .clone()
+                .unwrap_or_else(|| format!("{}.spill.jsonl", db_path));
+            let spill = SpillFile::new(path, sqlite.spill_max_mb * 1024 * 1024);
+            Arc::new(std::sync::Mutex::new(spill))
+        });
         Ok(Self {
-            conn: Arc::new(std::sync::Mutex::new(conn)),
+            conn,
+            reader,
+            wal_path,
+            reopen_with: (!in_memory).then(|| (db_path.to_string(), sqlite.clone())),
+            spill,
+            consecutive_failures: Arc::default(),
+            local_networks: Vec::new(),
+            aggregation_key: AggregationKey::default(),
+            flush: StorageConfig::default(),
+            query_cache: Arc::new(QueryCache::new(&StorageConfig::default())),
+            snapshot_lock: Arc::default(),
+            instance: None,
+            data_version: Arc::default(),
+            metrics: Arc::default(),
         })
     }
 
-    pub async fn run_writer(&self, rx: Receiver<PacketMetadata>, aggregation_window_seconds: u64) {
+    /// Maintain `host_usage` rollups for hosts in these networks.
+    pub fn with_local_networks(mut self, networks: Vec<IpNet>) -> Self {
+        self.local_networks = networks;
+        self
+    }
+
+    /// Key aggregated rows at this granularity (see `AggregationKey`).
+    pub fn with_aggregation_key(mut self, key: AggregationKey) -> Self {
+        self.aggregation_key = key;
+        self
+    }
+
+    /// Flush raw packets at these thresholds and size the query cache (see
+    /// `StorageConfig`).
+    pub fn with_storage_config(mut self, flush: StorageConfig) -> Self {
+        self.query_cache = Arc::new(QueryCache::new(&flush));
+        self.flush = flush;
+        self
+    }
+
+    /// Tag stored rows with this instance name.
+    pub fn with_instance(mut self, instance: String) -> Self {
+        self.instance = Some(instance);
+        self
+    }
+
+    /// Drop cached query results after a write.
+    fn invalidate_queries(&self) {
+        self.query_cache.invalidate();
+        self.metrics.query_cache_bytes.set(0);
+    }
+
+    /// Replace both connections with fresh ones, so a connection that was
+    /// left mid-transaction or poisoned by a panicking writer is not reused.
+    /// In-memory databases keep theirs, since a new one would be empty.
+    pub fn reopen(&self) -> Result<()> {
+        if let Some((path, sqlite)) = &self.reopen_with {
+            let fresh = Storage::open(path, sqlite)?;
+            for (old, new) in [(&self.conn, &fresh.conn), (&self.reader, &fresh.reader)] {
+                let mut old_conn = old.lock().unwrap_or_else(PoisonError::into_inner);
+                std::mem::swap(&mut *old_conn, &mut *new.lock().unwrap());
+            }
+        }
+        self.conn.clear_poison();
+        self.reader.clear_poison();
+        self.invalidate_queries();
+        Ok(())
+    }
+
+    /// Drain `rx` into the database until the channel closes.  `heartbeat`
+    /// beats on every flush tick and records the last failed write.
+    pub async fn run_writer(
+        &self,
+        rx: &mut Receiver<StorageEvent>,
+        aggregation_window_seconds: u64,
+        heartbeat: &Heartbeat,
+    ) {
         if aggregation_window_seconds == 0 {
-            self.run_writer_raw(rx).await;
+            self.run_writer_raw(rx, heartbeat).await;
         } else {
-            self.run_writer_aggregated(rx, aggregation_window_seconds)
+            let window = Duration::from_secs(aggregation_window_seconds);
+            self.run_writer_aggregated(rx, window, heartbeat, WallClock::System)
                 .await;
         }
     }
 
-    async fn run_writer_raw(&self, mut rx: Receiver<PacketMetadata>) {
+    async fn run_writer_raw(&self, rx: &mut Receiver<StorageEvent>, heartbeat: &Heartbeat) {
         let mut buffer = Vec::new();
-        let mut ticker = interval(Duration::from_secs(2));
+        let mut ticker = interval(self.flush.flush_interval());
 
         loop {
             tokio::select! {
-                Some(packet) = rx.recv() => {
-                    buffer.push(packet);
-                    if buffer.len() >= 1000 {
-                         self.flush(&mut buffer);
+                Some(event) = rx.recv() => match event {
+                    StorageEvent::Packets(packets) => {
+                        buffer.extend(packets);
+                        if buffer.len() >= self.flush.flush_max_rows {
+                            self.report_write(heartbeat, &self.write_packets(&mut buffer));
+                        }
                     }
-                }
+                    StorageEvent::Buckets(buckets) => {
+                        let result = self.write_swept(buckets);
+                        self.report_write(heartbeat, &result);
+                    }
+                    StorageEvent::Alert(alert) => {
+                        heartbeat.report(&self.insert_alert(&alert));
+                    }
+                    StorageEvent::Hostnames(names) => {
+                        heartbeat.report(&self.upsert_hostnames(&names));
+                    }
+                    StorageEvent::Peers(peers) => {
+                        heartbeat.report(&self.upsert_peers(&peers));
+                    }
+                },
                 _ = ticker.tick() => {
-                    if !buffer.is_empty() {
-                        self.flush(&mut buffer);
+                    if buffer.is_empty() {
+                        heartbeat.beat();
+                    } else {
+                        self.report_write(heartbeat, &self.write_packets(&mut buffer));
                     }
                 }
             }
         }
     }
 
+    /// Aggregate packets into wall-clock aligned windows and write each
+    /// window's buckets shortly after it closes.  Windows are written once:
+    /// a packet stamped in one already written, because it arrived late or
+    /// the clock stepped back, goes into the earliest window still open,
+    /// and a flush never goes back to an earlier time than the last.
     async fn run_writer_aggregated(
         &self,
-        mut rx: Receiver<PacketMetadata>,
-        window_secs: u64,
+        rx: &mut Receiver<StorageEvent>,
+        window: Duration,
+        heartbeat: &Heartbeat,
+        clock: WallClock,
     ) {
-        let mut buckets: HashMap<String, AggregatedBucket> = HashMap::new();
-        let mut ticker = interval(Duration::from_secs(window_secs));
+        let window_ms = window.as_millis() as i64;
+        let mut buckets = Buckets::new();
+        // Aligned to the wall clock once, then every window on the monotonic
+        // clock, so a clock that holds after a step back cannot hold up the
+        // writes.
+        let mut ticker = interval_at(next_flush(clock, window_ms), window);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut open_start = window_bounds(clock.now_ms(), window_ms).0;
 
         loop {
             tokio::select! {
-                Some(packet) = rx.recv() => {
-                    let key = format!(
-                        "{}:{} -> {}:{}",
-                        packet.src_ip, packet.src_port, packet.dst_ip, packet.dst_port
-                    );
-                    buckets
-                        .entry(key)
-                        .and_modify(|b| b.merge(&packet))
-                        .or_insert_with(|| AggregatedBucket::from_packet(&packet));
-                }
-                _ = ticker.tick() => {
-                    if !buckets.is_empty() {
-                        self.flush_aggregated(&mut buckets);
+                Some(event) = rx.recv() => match event {
+                    StorageEvent::Packets(packets) => {
+                        for packet in &packets {
+                            self.aggregate(&mut buckets, packet, window_ms, open_start);
+                        }
+                    }
+                    StorageEvent::Buckets(swept) => {
+                        let result = self.write_swept(swept);
+                        self.report_write(heartbeat, &result);
+                    }
+                    StorageEvent::Alert(alert) => {
+                        heartbeat.report(&self.insert_alert(&alert));
                     }
+                    StorageEvent::Hostnames(names) => {
+                        heartbeat.report(&self.upsert_hostnames(&names));
+                    }
+                    StorageEvent::Peers(peers) => {
+                        heartbeat.report(&self.upsert_peers(&peers));
+                    }
+                },
+                _ = ticker.tick() => {
+                    let result = self.flush_aggregated(&mut buckets, open_start);
+                    self.report_write(heartbeat, &result);
+                    // The next window, or the same one again while the
+                    // clock is behind it, as after a step back.
+                    let now = clock.now_ms().max(open_start);
+                    open_start = window_bounds(now, window_ms).0;
                 }
             }
         }
     }
 
-    fn flush(&self, buffer: &mut Vec<PacketMetadata>) {
-        let mut conn = self.conn.lock().unwrap();
-        let tx = match conn.transaction() {
-            Ok(tx) => tx,
-            Err(e) => {
-                eprintln!("Failed to start transaction: {}", e);
-                return;
-            }
+    /// `beat`, or `fail` with how much is waiting in the spill file.
+    fn report_write(&self, heartbeat: &Heartbeat, result: &Result<()>) {
+        let spilled = self.spill_bytes();
+        match result {
+            Err(e) if spilled > 0 => heartbeat.fail(format!("{} ({} bytes spilled)", e, spilled)),
+            _ => heartbeat.report(result),
+        }
+    }
+
+    /// Count a write's outcome, replaying the spill file after a success.
+    /// Returns the number of writes that have failed in a row.
+    fn record_write(&self, result: &Result<()>) -> u32 {
+        if result.is_ok() {
+            self.consecutive_failures.store(0, Ordering::Relaxed);
+            self.replay_spill();
+            return 0;
+        }
+        self.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// `flush`, moving the buffer to the spill file instead of letting it
+    /// grow once writes have failed `SPILL_AFTER_FAILURES` times in a row.
+    fn write_packets(&self, buffer: &mut Vec<PacketMetadata>) -> Result<()> {
+        let result = self.flush(buffer);
+        if self.record_write(&result) >= SPILL_AFTER_FAILURES && self.spill.is_some() {
+            self.spill_rows(buffer.drain(..).map(SpillRecord::Packet).collect());
+        }
+        result
+    }
+
+    /// Write kernel-swept buckets, which are always per connection.  They
+    /// are buffered nowhere else, so a failed batch is spilled at once.
+    fn write_swept(&self, swept: Vec<AggregatedBucket>) -> Result<()> {
+        let key = AggregationKey::Connection;
+        let result = self.insert_buckets(&swept, key);
+        if self.record_write(&result) > 0 {
+            let records = swept.into_iter().map(|bucket| SpillRecord::Bucket { key, bucket });
+            self.spill_rows(records.collect());
+        }
+        result
+    }
+
+    /// Append rows to the spill file, dropping any that do not fit.
+    fn spill_rows(&self, records: Vec<SpillRecord>) {
+        let Some(spill) = &self.spill else {
+            return;
         };
+        let total = records.len();
+        let mut spill = spill.lock().unwrap();
+        let written = spill.append(records).unwrap_or_else(|e| {
+            tracing::error!("Failed to write spill file: {}", e);
+            0
+        });
+        self.metrics.spilled_rows.inc_by(written as u64);
+        if written < total {
+            tracing::warn!("Spill file full, dropped {} rows", total - written);
+            self.metrics.spill_dropped_rows.inc_by((total - written) as u64);
+        }
+        self.metrics.spill_size_bytes.set(spill.bytes() as i64);
+    }
 
-        {
-            let mut stmt = match tx.prepare(
-                "INSERT INTO packets (timestamp, src_ip, dst_ip, src_port, dst_port, protocol, length, direction, src_hostname, dst_hostname, domain)
-                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
-            ) {
-                Ok(stmt) => stmt,
+    fn spill_bytes(&self) -> u64 {
+        self.spill.as_ref().map_or(0, |spill| spill.lock().unwrap().bytes())
+    }
+
+    /// Write spilled rows back once the database accepts writes again.
+    /// Batches that fail again return to the spill file.
+    fn replay_spill(&self) {
+        let Some(spill) = &self.spill else {
+            return;
+        };
+        let records = {
+            let mut spill = spill.lock().unwrap();
+            if spill.bytes() == 0 {
+                return;
+            }
+            match spill.drain() {
+                Ok(records) => records,
                 Err(e) => {
-                    eprintln!("Failed to prepare statement: {}", e);
+                    tracing::error!("Failed to read spill file: {}", e);
                     return;
                 }
-            };
+            }
+        };
+        self.metrics.spill_size_bytes.set(0);
+        let total = records.len();
+        let mut packets = Vec::new();
+        let mut buckets = Vec::new();
+        for record in records {
+            match record {
+                SpillRecord::Packet(packet) => packets.push(packet),
+                SpillRecord::Bucket { key, bucket } => buckets.push((key, bucket)),
+            }
+        }
+
+        let mut failed = Vec::new();
+        if !packets.is_empty() && self.flush(&mut packets).is_err() {
+            failed.extend(packets.into_iter().map(SpillRecord::Packet));
+        }
+        // One transaction per granularity the buckets were keyed at.
+        while let Some(&(key, _)) = buckets.first() {
+            let (group, rest): (Vec<_>, Vec<_>) =
+                buckets.into_iter().partition(|(k, _)| *k == key);
+            buckets = rest;
+            if self.insert_buckets(group.iter().map(|(_, b)| b), key).is_err() {
+                let records = group.into_iter();
+                failed.extend(records.map(|(key, bucket)| SpillRecord::Bucket { key, bucket }));
+            }
+        }
+        let replayed = total - failed.len();
+        if replayed > 0 {
+            tracing::info!("Replayed {} spilled rows", replayed);
+            self.metrics.replayed_rows.inc_by(replayed as u64);
+        }
+        if !failed.is_empty() {
+            self.spill_rows(failed);
+        }
+    }
+
+    /// Write buffered packets in one transaction and clear the buffer on
+    /// commit.  Rows that fail to insert on their own are logged, counted,
+    /// and skipped.  Errors that mean the database takes no writes at all
+    /// (read-only, full, locked) roll the batch back and leave `buffer` as
+    /// it was; a locked database is retried first.
+    pub(crate) fn flush(&self, buffer: &mut Vec<PacketMetadata>) -> Result<()> {
+        self.retry_busy(|| self.flush_once(buffer))
+    }
+
+    fn flush_once(&self, buffer: &mut Vec<PacketMetadata>) -> Result<()> {
+        let started = Instant::now();
+        let mut usage = HostUsage::new();
+        let mut names = PacketHostnames::new();
+        let mut inserted = 0;
+        let mut clamp = FutureClamp::new(&self.flush);
+        let mut conn = self.conn.lock().unwrap();
+        let tx = write_transaction(&mut conn).inspect_err(|e| {
+            tracing::error!("Failed to start transaction: {}", e);
+            self.metrics.transaction_failures.inc();
+        })?;
+
+        {
+            let mut stmt = tx
+                .prepare(
+                    "INSERT INTO packets (timestamp, src_ip, dst_ip, src_port, dst_port, protocol, length, direction, domain, ttl, dscp, interface, payload_length, src_mac, dst_mac, flow_direction, instance, icmp_type, icmp_code, country, asn)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21)",
+                )
+                .inspect_err(|e| tracing::error!("Failed to prepare statement: {}", e))?;
 
             for packet in buffer.iter() {
-                if let Err(e) = stmt.execute(params![
-                    packet.timestamp,
+                let [timestamp] = clamp.row([packet.timestamp]);
+                self.record_usage(
+                    &mut usage,
+                    &packet.src_ip,
+                    &packet.dst_ip,
+                    timestamp,
+                    packet.length as u64,
+                    1,
+                );
+                add_hostnames(&mut names, packet);
+                match stmt.execute(params![
+                    timestamp,
                     packet.src_ip,
                     packet.dst_ip,
                     packet.src_port,
@@ -145,47 +1166,198 @@ impl Storage {
                     packet.protocol,
                     packet.length,
                     packet.direction,
-                    packet.src_hostname,
-                    packet.dst_hostname,
-                    packet.domain
+                    packet.domain,
+                    packet.ttl,
+                    packet.dscp,
+                    packet.interface,
+                    packet.payload_length,
+                    packet.src_mac,
+                    packet.dst_mac,
+                    packet.flow_direction.map(FlowDirection::as_str),
+                    self.instance,
+                    packet.icmp_type,
+                    packet.icmp_code,
+                    packet.country,
+                    packet.asn
                 ]) {
-                    eprintln!("Failed to insert packet: {}", e);
+                    Ok(_) => inserted += 1,
+                    Err(e) => self.insert_failed(e, "packet")?,
                 }
             }
         }
+        upsert_host_usage(&tx, usage, self.instance.as_deref());
+        upsert_packet_hostnames(&tx, names);
 
-        if let Err(e) = tx.commit() {
-            eprintln!("Failed to commit transaction: {}", e);
-        } else {
-            buffer.clear();
+        tx.commit().inspect_err(|e| {
+            tracing::error!("Failed to commit transaction: {}", e);
+            self.metrics.transaction_failures.inc();
+        })?;
+        self.invalidate_queries();
+        self.metrics
+            .record_flush(buffer.len(), inserted, started.elapsed());
+        clamp.record(&self.metrics);
+        buffer.clear();
+        Ok(())
+    }
+
+    /// Run a write transaction, again after a pause when it fails with
+    /// SQLITE_BUSY, up to `BUSY_ATTEMPTS` times.  `busy_timeout_ms` already
+    /// waits on the lock; this covers a writer that holds it longer, such
+    /// as another instance's retention pass.  Both waits block, so they run
+    /// through `blocking`.
+    fn retry_busy<T>(&self, mut write: impl FnMut() -> Result<T>) -> Result<T> {
+        let mut attempt = 1;
+        blocking(|| loop {
+            match write() {
+                Err(e) if is_busy(&e) && attempt < BUSY_ATTEMPTS => {
+                    tracing::debug!("Database busy (attempt {}), retrying: {}", attempt, e);
+                    self.metrics.busy_retries.inc();
+                    std::thread::sleep(BUSY_BACKOFF * attempt);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        })
+    }
+
+    /// Skip a row that failed to insert, or return the error to abort the
+    /// transaction when it means no row would succeed.
+    fn insert_failed(&self, e: rusqlite::Error, row: &str) -> Result<()> {
+        if is_unwritable(&e) {
+            tracing::error!("Database refused write: {}", e);
+            self.metrics.transaction_failures.inc();
+            return Err(e);
+        }
+        tracing::warn!("Failed to insert {}: {}", row, e);
+        self.metrics.insert_failures.inc();
+        Ok(())
+    }
+
+    /// Fold a packet into the bucket for its `aggregation_key` in the window
+    /// its timestamp falls in, or the one starting at `open_start` if that
+    /// is later.  Dropped ports are stored as 0.
+    fn aggregate(
+        &self,
+        buckets: &mut Buckets,
+        packet: &PacketMetadata,
+        window_ms: i64,
+        open_start: i64,
+    ) {
+        let (window_start, window_end) =
+            window_bounds(packet.timestamp.max(open_start), window_ms);
+        let (src_port, dst_port) = self
+            .aggregation_key
+            .key_ports(packet.src_port, packet.dst_port);
+        let key = format!(
+            "{}:{} -> {}:{} {}",
+            packet.src_ip, src_port, packet.dst_ip, dst_port, packet.protocol
+        );
+        buckets
+            .entry((window_start, key))
+            .and_modify(|b| b.merge(packet))
+            .or_insert_with(|| AggregatedBucket {
+                src_port,
+                dst_port,
+                window_start,
+                window_end,
+                ..AggregatedBucket::from_packet(packet)
+            });
+    }
+
+    /// Write the buckets of the window starting at `open_start` and every
+    /// earlier one.  Buckets of later windows, filled by packets that
+    /// arrived after the boundary, stay for the next flush, as do written
+    /// ones whose write failed until they are spilled.
+    fn flush_aggregated(&self, buckets: &mut Buckets, open_start: i64) -> Result<()> {
+        let due = |b: &AggregatedBucket| b.window_start <= open_start;
+        if !buckets.values().any(due) {
+            return Ok(());
+        }
+        let closed = buckets.values().filter(|b| due(b));
+        let result = self.insert_buckets(closed, self.aggregation_key);
+        let failures = self.record_write(&result);
+        if result.is_ok() {
+            buckets.retain(|_, b| !due(b));
+        } else if failures >= SPILL_AFTER_FAILURES && self.spill.is_some() {
+            let (closed, open): (Buckets, Buckets) =
+                std::mem::take(buckets).into_iter().partition(|(_, b)| due(b));
+            *buckets = open;
+            let key = self.aggregation_key;
+            let records = closed.into_values().map(|bucket| SpillRecord::Bucket { key, bucket });
+            self.spill_rows(records.collect());
         }
+        result
+    }
+
+    /// Insert aggregated buckets in one transaction, tagged with the
+    /// granularity they were keyed at.  Errors as `flush`.
+    fn insert_buckets<'a>(
+        &self,
+        buckets: impl IntoIterator<Item = &'a AggregatedBucket> + Clone,
+        granularity: AggregationKey,
+    ) -> Result<()> {
+        let instance = self.instance.as_deref();
+        self.retry_busy(|| self.insert_buckets_once(buckets.clone(), granularity, instance))
+    }
+
+    /// Insert per-connection buckets pushed by another agent, tagged with
+    /// its `instance` instead of this one's.  Errors as `flush`, and
+    /// nothing is spilled: the sender keeps the batch until it is taken.
+    pub fn insert_ingested(&self, instance: &str, buckets: &[AggregatedBucket]) -> Result<()> {
+        let key = AggregationKey::Connection;
+        self.retry_busy(|| self.insert_buckets_once(buckets, key, Some(instance)))
     }
 
-    fn flush_aggregated(&self, buckets: &mut HashMap<String, AggregatedBucket>) {
+    fn insert_buckets_once<'a>(
+        &self,
+        buckets: impl IntoIterator<Item = &'a AggregatedBucket>,
+        granularity: AggregationKey,
+        instance: Option<&str>,
+    ) -> Result<()> {
+        let started = Instant::now();
+        let mut usage = HostUsage::new();
+        let mut names = PacketHostnames::new();
+        let (mut batch, mut inserted) = (0, 0);
+        let mut clamp = FutureClamp::new(&self.flush);
         let mut conn = self.conn.lock().unwrap();
-        let tx = match conn.transaction() {
-            Ok(tx) => tx,
-            Err(e) => {
-                eprintln!("Failed to start transaction: {}", e);
-                return;
-            }
-        };
+        let tx = write_transaction(&mut conn).inspect_err(|e| {
+            tracing::error!("Failed to start transaction: {}", e);
+            self.metrics.transaction_failures.inc();
+        })?;
 
         {
-            let mut stmt = match tx.prepare(
-                "INSERT INTO packets (timestamp, src_ip, dst_ip, src_port, dst_port, protocol, length, direction, src_hostname, dst_hostname, domain)
-                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
-            ) {
-                Ok(stmt) => stmt,
-                Err(e) => {
-                    eprintln!("Failed to prepare statement: {}", e);
-                    return;
-                }
-            };
+            let mut stmt = tx
+                .prepare(
+                    "INSERT INTO packets (timestamp, src_ip, dst_ip, src_port, dst_port, protocol, length, direction, domain, aggregation, interface, window_start, window_end, payload_length, packet_count, flow_direction, instance, icmp_type, icmp_code, country, asn)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21)",
+                )
+                .inspect_err(|e| tracing::error!("Failed to prepare statement: {}", e))?;
 
-            for bucket in buckets.values() {
-                if let Err(e) = stmt.execute(params![
-                    bucket.first_timestamp,
+            for bucket in buckets {
+                batch += 1;
+                let [timestamp, window_start, window_end] =
+                    clamp.row([bucket.first_timestamp, bucket.window_start, bucket.window_end]);
+                self.record_usage(
+                    &mut usage,
+                    &bucket.src_ip,
+                    &bucket.dst_ip,
+                    timestamp,
+                    bucket.total_bytes,
+                    bucket.packet_count,
+                );
+                for (ip, hostname) in [
+                    (&bucket.src_ip, &bucket.src_hostname),
+                    (&bucket.dst_ip, &bucket.dst_hostname),
+                ] {
+                    if let Some(hostname) = hostname {
+                        names.insert(ip, hostname);
+                    }
+                }
+                // Host-pair keys drop the port that carries type and code.
+                let icmp = IcmpMessage::from_flow(&bucket.protocol, bucket.dst_port)
+                    .filter(|_| granularity == AggregationKey::Connection);
+                match stmt.execute(params![
+                    timestamp,
                     bucket.src_ip,
                     bucket.dst_ip,
                     bucket.src_port,
@@ -193,57 +1365,3380 @@ impl Storage {
                     bucket.protocol,
                     bucket.total_bytes as i64,
                     bucket.direction,
-                    bucket.src_hostname,
-                    bucket.dst_hostname,
-                    bucket.domain
+                    bucket.domain,
+                    granularity.as_str(),
+                    bucket.interface,
+                    window_start,
+                    window_end,
+                    bucket.payload_bytes as i64,
+                    bucket.packet_count as i64,
+                    bucket.flow_direction.map(FlowDirection::as_str),
+                    instance,
+                    icmp.map(|m| m.kind),
+                    icmp.map(|m| m.code),
+                    bucket.country,
+                    bucket.asn
                 ]) {
-                    eprintln!("Failed to insert aggregated row: {}", e);
+                    Ok(_) => inserted += 1,
+                    Err(e) => self.insert_failed(e, "aggregated row")?,
                 }
             }
         }
+        upsert_host_usage(&tx, usage, instance);
+        upsert_packet_hostnames(&tx, names);
 
-        if let Err(e) = tx.commit() {
-            eprintln!("Failed to commit transaction: {}", e);
-        } else {
-            buckets.clear();
-        }
+        tx.commit().inspect_err(|e| {
+            tracing::error!("Failed to commit transaction: {}", e);
+            self.metrics.transaction_failures.inc();
+        })?;
+        self.invalidate_queries();
+        self.metrics.record_flush(batch, inserted, started.elapsed());
+        clamp.record(&self.metrics);
+        Ok(())
     }
 
-    pub fn query_history(&self, limit: usize) -> Result<Vec<PacketMetadata>> {
-        let conn = self.conn.lock().unwrap();
-        let mut stmt = conn.prepare(
-            "SELECT timestamp, src_ip, dst_ip, src_port, dst_port, protocol, length, direction, src_hostname, dst_hostname, domain
-             FROM packets ORDER BY timestamp DESC LIMIT ?1",
-        )?;
-
-        let rows = stmt.query_map([limit], |row| {
-            Ok(PacketMetadata {
-                timestamp: row.get(0)?,
-                src_ip: row.get(1)?,
-                dst_ip: row.get(2)?,
-                src_port: row.get(3)?,
-                dst_port: row.get(4)?,
-                protocol: row.get(5)?,
-                length: row.get(6)?,
-                direction: row.get::<_, Option<String>>(7)?.unwrap_or_else(|| "ingress".to_string()),
-                src_hostname: row.get(8)?,
-                dst_hostname: row.get(9)?,
-                domain: row.get(10)?,
+    /// Attribute traffic to whichever endpoints are local hosts.  Traffic
+    /// between two local hosts counts as "tx" for one and "rx" for the other.
+    fn record_usage(
+        &self,
+        usage: &mut HostUsage,
+        src_ip: &str,
+        dst_ip: &str,
+        timestamp: i64,
+        bytes: u64,
+        packets: u64,
+    ) {
+        add_usage(usage, &self.local_networks, src_ip, dst_ip, timestamp, bytes, packets);
+    }
+
+    /// Usage rollups for buckets starting within `[from, to]`, optionally
+    /// for a single host.
+    pub fn query_usage(
+        &self,
+        ip: Option<&str>,
+        from: i64,
+        to: i64,
+        granularity: UsageGranularity,
+    ) -> Result<Vec<HostUsageRow>> {
+        let mut query = QueryBuilder::new("");
+        let bucket = query.bind(granularity.millis());
+        query
+            .eq("local_ip", ip.map(str::to_string))
+            .cmp("hour", ">=", Some(from))
+            .cmp("hour", "<=", Some(to));
+        let conn = self.reader.lock().unwrap();
+        let mut stmt = conn.prepare(&query.sql(
+            &format!(
+                "SELECT local_ip, hour - (hour % {bucket}) AS bucket, direction,
+                        SUM(bytes), SUM(packets)
+                 FROM host_usage"
+            ),
+            "GROUP BY local_ip, bucket, direction ORDER BY bucket, local_ip, direction",
+        ))?;
+        let rows = stmt.query_map(query.params(), |row| {
+            Ok(HostUsageRow {
+                ip: row.get(0)?,
+                bucket: row.get(1)?,
+                direction: row.get(2)?,
+                bytes: row.get::<_, i64>(3)? as u64,
+                packets: row.get::<_, i64>(4)? as u64,
             })
         })?;
+        rows.collect()
+    }
 
-        let mut result = Vec::new();
-        for row in rows {
-            result.push(row?);
+    /// Most recent stored packets matching `filter`, newest first.  Served
+    /// from the query cache when nothing was written since the same query.
+    pub fn query_packets(&self, filter: &PacketFilter, limit: usize) -> Result<Vec<HistoryRow>> {
+        if !self.query_cache.enabled() {
+            return self.select_packets(filter, limit);
         }
-        Ok(result)
+        self.check_data_version()?;
+        if let Some(rows) = self.query_cache.get(filter, limit) {
+            self.metrics.query_cache_hits.inc();
+            return Ok(rows);
+        }
+        self.metrics.query_cache_misses.inc();
+        let generation = self.query_cache.generation();
+        let rows = self.select_packets(filter, limit)?;
+        self.query_cache.insert(filter, limit, generation, &rows);
+        self.metrics.query_cache_bytes.set(self.query_cache.bytes() as i64);
+        Ok(rows)
     }
 
-    pub fn delete_old_data(&self, older_than_seconds: u64) -> Result<usize> {
-        let cutoff_ms =
-            chrono::Utc::now().timestamp_millis() - (older_than_seconds as i64 * 1000);
-        let conn = self.conn.lock().unwrap();
-        let deleted = conn.execute("DELETE FROM packets WHERE timestamp < ?1", params![cutoff_ms])?;
+    /// Drop the query cache if another connection committed since the last
+    /// check.  This handle's own writes invalidate it directly, but those of
+    /// another instance on the same database are only seen here.
+    fn check_data_version(&self) -> Result<()> {
+        let version: i64 =
+            self.reader.lock().unwrap().query_row("PRAGMA data_version", [], |row| row.get(0))?;
+        if self.data_version.swap(version, Ordering::AcqRel) != version {
+            self.invalidate_queries();
+        }
+        Ok(())
+    }
+
+    fn select_packets(&self, filter: &PacketFilter, limit: usize) -> Result<Vec<HistoryRow>> {
+        let mut query = QueryBuilder::new("p.");
+        query.packet_filter(filter).limit(limit);
+        let conn = self.reader.lock().unwrap();
+        let mut stmt = conn.prepare(&query.sql(HISTORY_SELECT, "ORDER BY p.timestamp DESC"))?;
+        let rows = stmt.query_map(query.params(), history_row)?;
+        rows.collect()
+    }
+
+    /// The `limit` newest stored rows of one connection, in either
+    /// orientation of its 4-tuple, and totals over all of its rows.
+    /// Host-pair aggregated rows have no ports and never match.
+    pub fn query_connection(
+        &self,
+        key: &ConnectionKey,
+        limit: usize,
+    ) -> Result<(Vec<HistoryRow>, StoredConnectionTotals)> {
+        const TUPLE: &str =
+            "((p.src_ip = ?1 AND p.dst_ip = ?2 AND p.src_port = ?3 AND p.dst_port = ?4)
+              OR (p.src_ip = ?2 AND p.dst_ip = ?1 AND p.src_port = ?4 AND p.dst_port = ?3))";
+        let (src_ip, dst_ip) = (key.src_ip.to_string(), key.dst_ip.to_string());
+        let tuple = params![src_ip, dst_ip, key.src_port, key.dst_port];
+        let conn = self.reader.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "{HISTORY_SELECT}
+             WHERE {TUPLE}
+             ORDER BY p.timestamp DESC LIMIT {limit}"
+        ))?;
+        let rows = stmt.query_map(tuple, history_row)?.collect::<Result<Vec<_>>>()?;
+        let totals = conn.query_row(
+            &format!(
+                "SELECT COUNT(*), COALESCE(SUM(p.packet_count), 0), COALESCE(SUM(p.length), 0),
+                        MIN(p.timestamp), MAX(p.timestamp)
+                 FROM packets p WHERE {TUPLE}"
+            ),
+            tuple,
+            |row| {
+                Ok(StoredConnectionTotals {
+                    rows: row.get::<_, i64>(0)? as u64,
+                    packets: row.get::<_, i64>(1)? as u64,
+                    bytes: row.get::<_, i64>(2)? as u64,
+                    first_seen: row.get(3)?,
+                    last_seen: row.get(4)?,
+                })
+            },
+        )?;
+        Ok((rows, totals))
+    }
+
+    /// Total stored traffic grouped by one packet column, most bytes first.
+    pub fn query_top(
+        &self,
+        by: TopColumn,
+        filter: &PacketFilter,
+        limit: usize,
+    ) -> Result<Vec<StoredTalker>> {
+        let mut query = QueryBuilder::new("");
+        query.packet_filter(filter).not_null(by.column()).limit(limit);
+        let conn = self.reader.lock().unwrap();
+        let mut stmt = conn.prepare(&query.sql(
+            &format!(
+                "SELECT CAST({} AS TEXT) AS grp, SUM(length), SUM(packet_count), COUNT(*)
+                 FROM packets",
+                by.column()
+            ),
+            "GROUP BY grp ORDER BY SUM(length) DESC",
+        ))?;
+        let rows = stmt.query_map(query.params(), |row| {
+            Ok(StoredTalker {
+                key: row.get(0)?,
+                bytes: row.get::<_, i64>(1)? as u64,
+                packets: row.get::<_, i64>(2)? as u64,
+                rows: row.get::<_, i64>(3)? as u64,
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// Rows, packets and bytes stored matching `filter`.
+    pub fn query_totals(&self, filter: &PacketFilter) -> Result<StoredTotals> {
+        let mut query = QueryBuilder::new("");
+        query.packet_filter(filter);
+        let conn = self.reader.lock().unwrap();
+        conn.query_row(
+            &query.sql(
+                "SELECT COUNT(*), COALESCE(SUM(packet_count), 0), COALESCE(SUM(length), 0)
+                 FROM packets",
+                "",
+            ),
+            query.params(),
+            |row| {
+                Ok(StoredTotals {
+                    rows: row.get::<_, i64>(0)? as u64,
+                    packets: row.get::<_, i64>(1)? as u64,
+                    bytes: row.get::<_, i64>(2)? as u64,
+                })
+            },
+        )
+    }
+
+    /// Delete every stored packet in one transaction.  Returns the number
+    /// of rows removed.
+    pub fn clear_packets(&self) -> Result<usize> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = write_transaction(&mut conn)?;
+        let deleted = tx.execute("DELETE FROM packets", [])?;
+        tx.commit()?;
+        self.invalidate_queries();
         Ok(deleted)
     }
+
+    /// Store a value in the `state` key-value table, replacing any previous one.
+    pub fn save_state(&self, key: &str, value: &str) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT OR REPLACE INTO state (key, value, updated_at) VALUES (?1, ?2, ?3)",
+            params![key, value, chrono::Utc::now().timestamp_millis()],
+        )?;
+        Ok(())
+    }
+
+    /// Record that this instance stores 1 in `sample_rate` packets from
+    /// `since` on.  Nothing is written when that rate is already in effect,
+    /// so only changes leave a boundary.
+    pub fn record_sample_rate(&self, sample_rate: u32, since: i64) -> Result<()> {
+        let instance = self.instance.as_deref().unwrap_or_default();
+        let conn = self.conn.lock().unwrap();
+        let current: Option<u32> = conn
+            .query_row(
+                "SELECT sample_rate FROM capture_meta WHERE instance = ?1
+                 ORDER BY since DESC LIMIT 1",
+                [instance],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if current.unwrap_or(1) != sample_rate {
+            conn.execute(
+                "INSERT OR REPLACE INTO capture_meta (instance, since, sample_rate)
+                 VALUES (?1, ?2, ?3)",
+                params![instance, since, sample_rate],
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn load_state(&self, key: &str) -> Result<Option<String>> {
+        let conn = self.reader.lock().unwrap();
+        conn.query_row("SELECT value FROM state WHERE key = ?1", [key], |row| row.get(0))
+            .optional()
+    }
+
+    /// Fold `alert` into this instance's unacknowledged row for its rule
+    /// and subject, or start a new row if there is none.
+    pub(crate) fn insert_alert(&self, alert: &Alert) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        let params = params![
+            alert.timestamp,
+            alert.rule,
+            alert.severity,
+            alert.subject,
+            alert.message,
+            self.instance
+        ];
+        let folded = conn.execute(
+            "UPDATE alerts
+             SET last_seen = max(last_seen, ?1), count = count + 1, severity = ?3, message = ?5
+             WHERE id = (SELECT max(id) FROM alerts
+                         WHERE rule = ?2 AND subject = ?4 AND instance IS ?6 AND acked = 0)",
+            params,
+        );
+        let result = match folded {
+            Ok(0) => conn.execute(
+                "INSERT INTO alerts (timestamp, last_seen, rule, severity, subject, message, instance)
+                 VALUES (?1, ?1, ?2, ?3, ?4, ?5, ?6)",
+                params,
+            ),
+            other => other,
+        };
+        result.inspect_err(|e| tracing::error!("Failed to insert alert: {}", e))?;
+        Ok(())
+    }
+
+    /// Record background reverse-DNS results, replacing older ones.
+    fn upsert_hostnames(&self, names: &[(String, String)]) -> Result<()> {
+        let now = chrono::Utc::now().timestamp_millis();
+        let mut conn = self.conn.lock().unwrap();
+        let tx = write_transaction(&mut conn)?;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT INTO hostnames (ip, hostname, resolved_at) VALUES (?1, ?2, ?3)
+                 ON CONFLICT(ip) DO UPDATE SET hostname = excluded.hostname,
+                     resolved_at = excluded.resolved_at",
+            )?;
+            for (ip, hostname) in names {
+                stmt.execute(params![ip, hostname, now])?;
+            }
+        }
+        tx.commit()?;
+        // Stored rows show these names from now on.
+        self.invalidate_queries();
+        Ok(())
+    }
+
+    /// Up to `limit` packet rows after `after_id`, oldest first, that lack a
+    /// source or destination hostname and were captured at or after `since`.
+    /// A row lacks one when neither the row nor `hostnames` has it.
+    pub fn unresolved_rows(
+        &self,
+        after_id: i64,
+        since: Option<i64>,
+        limit: usize,
+    ) -> Result<UnresolvedRows> {
+        let conn = self.reader.lock().unwrap();
+        let mut stmt = conn.prepare_cached(
+            "SELECT id, src_ip, dst_ip, src_missing, dst_missing FROM (
+                 SELECT p.id, p.src_ip, p.dst_ip,
+                     p.src_hostname IS NULL AND hs.ip IS NULL AS src_missing,
+                     p.dst_hostname IS NULL AND hd.ip IS NULL AS dst_missing
+                 FROM packets p
+                 LEFT JOIN hostnames hs ON hs.ip = p.src_ip
+                 LEFT JOIN hostnames hd ON hd.ip = p.dst_ip
+                 WHERE p.id > ?1 AND p.timestamp >= ?2
+             )
+             WHERE src_missing OR dst_missing
+             ORDER BY id LIMIT ?3",
+        )?;
+        let mut batch = UnresolvedRows { through_id: after_id, ..UnresolvedRows::default() };
+        let mut addresses = BTreeSet::new();
+        let mut rows = stmt.query(params![after_id, since.unwrap_or(i64::MIN), limit as i64])?;
+        while let Some(row) = rows.next()? {
+            batch.rows += 1;
+            batch.through_id = row.get(0)?;
+            for (ip, missing) in [(1, 3), (2, 4)] {
+                if row.get::<_, bool>(missing)? {
+                    addresses.insert(row.get::<_, String>(ip)?);
+                }
+            }
+        }
+        batch.addresses = addresses.into_iter().collect();
+        batch.last_id = conn.query_row("SELECT coalesce(max(id), 0) FROM packets", [], |row| {
+            row.get(0)
+        })?;
+        Ok(batch)
+    }
+
+    /// Record `names` (`(ip, hostname)`) resolved for the rows up to
+    /// `through_id`, saving `through_id` as the backfill position in the
+    /// same transaction.  Returns the number of addresses given a hostname
+    /// they lacked.
+    pub fn fill_hostnames(&self, through_id: i64, names: &[(String, String)]) -> Result<usize> {
+        let now = chrono::Utc::now().timestamp_millis();
+        let mut conn = self.conn.lock().unwrap();
+        let filled = self.retry_busy(|| {
+            let tx = write_transaction(&mut conn)?;
+            let mut filled = 0;
+            {
+                // The agent may have resolved an address meanwhile.
+                let mut stmt = tx.prepare(
+                    "INSERT OR IGNORE INTO hostnames (ip, hostname, resolved_at)
+                     VALUES (?1, ?2, ?3)",
+                )?;
+                for (ip, hostname) in names {
+                    filled += stmt.execute(params![ip, hostname, now])?;
+                }
+            }
+            tx.execute(
+                "INSERT OR REPLACE INTO state (key, value, updated_at) VALUES (?1, ?2, ?3)",
+                params![BACKFILL_POSITION_KEY, through_id.to_string(), now],
+            )?;
+            tx.commit()?;
+            Ok(filled)
+        })?;
+        drop(conn);
+        if filled > 0 {
+            self.invalidate_queries();
+        }
+        Ok(filled)
+    }
+
+    /// Up to `limit` packet rows after row `after_id`, in row order, with
+    /// hostnames resolved since they were stored filled in.
+    pub fn export_packets(&self, after_id: i64, limit: usize) -> Result<ExportedPackets> {
+        let mut query = QueryBuilder::new("p.");
+        query.cmp("id", ">", Some(after_id)).limit(limit);
+        let mut batch = ExportedPackets { through: after_id, ..ExportedPackets::default() };
+        self.export_rows(&query, |id, row| {
+            batch.through = id;
+            batch.rows.push(row);
+        })?;
+        Ok(batch)
+    }
+
+    /// Every packet row stored with `from <= timestamp < to`, read as
+    /// `export_packets` reads them.
+    pub fn export_window(&self, from: i64, to: i64) -> Result<Vec<PacketRecord>> {
+        let mut query = QueryBuilder::new("p.");
+        query.cmp("timestamp", ">=", Some(from)).cmp("timestamp", "<", Some(to));
+        let mut rows = Vec::new();
+        self.export_rows(&query, |_, row| rows.push(row))?;
+        Ok(rows)
+    }
+
+    /// The rows `query` selects in row order, with their ids.
+    fn export_rows(
+        &self,
+        query: &QueryBuilder,
+        mut each: impl FnMut(i64, PacketRecord),
+    ) -> Result<()> {
+        let conn = self.reader.lock().unwrap();
+        let mut stmt = conn.prepare_cached(&query.sql(
+            "SELECT p.id, p.timestamp, p.src_ip, p.dst_ip, COALESCE(p.src_port, 0),
+                 COALESCE(p.dst_port, 0), COALESCE(p.protocol, ''), COALESCE(p.length, 0),
+                 COALESCE(p.direction, 'ingress'),
+                 COALESCE(hs.hostname, p.src_hostname), COALESCE(hd.hostname, p.dst_hostname),
+                 p.domain, p.ttl, p.dscp, p.aggregation, COALESCE(p.interface, ''),
+                 p.window_start, p.window_end, COALESCE(p.payload_length, 0), p.packet_count,
+                 p.src_mac, p.dst_mac, p.flow_direction, p.instance, p.icmp_type, p.icmp_code,
+                 p.country, p.asn
+             FROM packets p
+             LEFT JOIN hostnames hs ON hs.ip = p.src_ip
+             LEFT JOIN hostnames hd ON hd.ip = p.dst_ip",
+            "ORDER BY p.id",
+        ))?;
+        let mut rows = stmt.query(query.params())?;
+        while let Some(row) = rows.next()? {
+            each(row.get(0)?, PacketRecord {
+                timestamp: row.get(1)?,
+                src_ip: row.get(2)?,
+                dst_ip: row.get(3)?,
+                src_port: row.get(4)?,
+                dst_port: row.get(5)?,
+                protocol: row.get(6)?,
+                length: row.get::<_, i64>(7)? as u64,
+                direction: row.get(8)?,
+                src_hostname: row.get(9)?,
+                dst_hostname: row.get(10)?,
+                domain: row.get(11)?,
+                ttl: row.get(12)?,
+                dscp: row.get(13)?,
+                aggregation: row.get(14)?,
+                interface: row.get(15)?,
+                window_start: row.get(16)?,
+                window_end: row.get(17)?,
+                payload_length: row.get::<_, i64>(18)? as u64,
+                packet_count: row.get::<_, i64>(19)? as u64,
+                src_mac: row.get(20)?,
+                dst_mac: row.get(21)?,
+                flow_direction: row.get(22)?,
+                instance: row.get(23)?,
+                icmp_type: row.get(24)?,
+                icmp_code: row.get(25)?,
+                country: row.get(26)?,
+                asn: row.get(27)?,
+            });
+        }
+        Ok(())
+    }
+
+    /// Insert rows exported from another database as they are, in one
+    /// transaction, adding them to the usage rollups and their hostnames
+    /// to `hostnames`.
+    pub fn import_packets(&self, records: &[PacketRecord]) -> Result<()> {
+        let mut usage = BTreeMap::<Option<&str>, HostUsage>::new();
+        let mut names = PacketHostnames::new();
+        for record in records {
+            let (src, dst) = (&record.src_ip, &record.dst_ip);
+            let (bytes, packets) = (record.length, record.packet_count);
+            let usage = usage.entry(record.instance.as_deref()).or_default();
+            self.record_usage(usage, src, dst, record.timestamp, bytes, packets);
+            for (ip, hostname) in [(src, &record.src_hostname), (dst, &record.dst_hostname)] {
+                if let Some(hostname) = hostname {
+                    names.insert(ip, hostname);
+                }
+            }
+        }
+        let mut conn = self.conn.lock().unwrap();
+        self.retry_busy(|| {
+            let tx = write_transaction(&mut conn)?;
+            {
+                let mut stmt = tx.prepare(
+                    "INSERT INTO packets (timestamp, src_ip, dst_ip, src_port, dst_port, protocol, length, direction, src_hostname, dst_hostname, domain, ttl, dscp, aggregation, interface, window_start, window_end, payload_length, packet_count, src_mac, dst_mac, flow_direction, instance, icmp_type, icmp_code, country, asn)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22, ?23, ?24, ?25, ?26, ?27)",
+                )?;
+                for r in records {
+                    stmt.execute(params![
+                        r.timestamp,
+                        r.src_ip,
+                        r.dst_ip,
+                        r.src_port,
+                        r.dst_port,
+                        r.protocol,
+                        r.length as i64,
+                        r.direction,
+                        r.src_hostname,
+                        r.dst_hostname,
+                        r.domain,
+                        r.ttl,
+                        r.dscp,
+                        r.aggregation,
+                        r.interface,
+                        r.window_start,
+                        r.window_end,
+                        r.payload_length as i64,
+                        r.packet_count as i64,
+                        r.src_mac,
+                        r.dst_mac,
+                        r.flow_direction,
+                        r.instance,
+                        r.icmp_type,
+                        r.icmp_code,
+                        r.country,
+                        r.asn
+                    ])?;
+                }
+            }
+            for (&instance, usage) in &usage {
+                upsert_host_usage(&tx, usage.clone(), instance);
+            }
+            upsert_packet_hostnames(&tx, names.clone());
+            tx.commit()
+        })?;
+        drop(conn);
+        self.invalidate_queries();
+        Ok(())
+    }
+
+    /// Add expired connections' totals to their `peers` rows.
+    fn upsert_peers(&self, peers: &[PeerTotals]) -> Result<()> {
+        let instance = self.instance.as_deref().unwrap_or_default();
+        let mut conn = self.conn.lock().unwrap();
+        let tx = write_transaction(&mut conn)?;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT INTO peers
+                     (remote_ip, day, first_seen, last_seen, bytes, packets, connections, instance)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+                 ON CONFLICT (remote_ip, day, instance) DO UPDATE SET
+                     first_seen = MIN(first_seen, excluded.first_seen),
+                     last_seen = MAX(last_seen, excluded.last_seen),
+                     bytes = bytes + excluded.bytes,
+                     packets = packets + excluded.packets,
+                     connections = connections + excluded.connections",
+            )?;
+            for peer in peers {
+                stmt.execute(params![
+                    peer.ip,
+                    peer.day,
+                    peer.first_seen,
+                    peer.last_seen,
+                    peer.bytes as i64,
+                    peer.packets as i64,
+                    peer.connections as i64,
+                    instance
+                ])?;
+            }
+        }
+        tx.commit()
+    }
+
+    /// `peers` rows active within `[from, to]`, optionally for one address,
+    /// oldest day first, summed over instances.
+    pub fn query_peers(&self, ip: Option<&str>, from: i64, to: i64) -> Result<Vec<PeerTotals>> {
+        let mut query = QueryBuilder::new("");
+        query
+            .eq("remote_ip", ip.map(str::to_string))
+            .cmp("last_seen", ">=", Some(from))
+            .cmp("first_seen", "<=", Some(to));
+        let conn = self.reader.lock().unwrap();
+        let mut stmt = conn.prepare(&query.sql(
+            "SELECT remote_ip, day, first_seen, last_seen, bytes, packets, connections
+             FROM (SELECT remote_ip, day, MIN(first_seen) AS first_seen,
+                       MAX(last_seen) AS last_seen, SUM(bytes) AS bytes,
+                       SUM(packets) AS packets, SUM(connections) AS connections
+                   FROM peers GROUP BY remote_ip, day)",
+            "ORDER BY day, bytes DESC",
+        ))?;
+        let rows = stmt.query_map(query.params(), |row| {
+            Ok(PeerTotals {
+                ip: row.get(0)?,
+                day: row.get(1)?,
+                first_seen: row.get(2)?,
+                last_seen: row.get(3)?,
+                bytes: row.get::<_, i64>(4)? as u64,
+                packets: row.get::<_, i64>(5)? as u64,
+                connections: row.get::<_, i64>(6)? as u64,
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// Alerts matching `filter`, newest row first.  Rows are ordered by id,
+    /// so a repeat folded into an old row does not move it between pages.
+    pub fn query_alerts(&self, filter: &AlertFilter, limit: usize) -> Result<Vec<StoredAlert>> {
+        let mut query = QueryBuilder::new("");
+        query
+            .cmp("id", "<", filter.before_id)
+            .eq("severity", filter.severity.clone())
+            .eq("rule", filter.rule.clone())
+            .cmp("last_seen", ">=", filter.since)
+            .eq("acked", filter.acked)
+            .eq("instance", filter.instance.clone())
+            .limit(limit);
+        let conn = self.reader.lock().unwrap();
+        let mut stmt = conn.prepare(&query.sql(ALERT_SELECT, "ORDER BY id DESC"))?;
+        let rows = stmt.query_map(query.params(), stored_alert)?;
+        rows.collect()
+    }
+
+    /// Mark alert `id` handled.  Acknowledging it again keeps the first
+    /// acknowledgement.  None when there is no such alert.
+    pub fn ack_alert(&self, id: i64, by: &str) -> Result<Option<StoredAlert>> {
+        let now = chrono::Utc::now().timestamp_millis();
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE alerts SET acked = 1, acked_by = ?2, acked_at = ?3 WHERE id = ?1 AND acked = 0",
+            params![id, by, now],
+        )?;
+        conn.query_row(&format!("{ALERT_SELECT} WHERE id = ?1"), [id], stored_alert)
+            .optional()
+    }
+
+    /// Delete alerts last seen more than `older_than_seconds` ago (0 keeps
+    /// any age), then all but the `max_rows` most recently seen (0 keeps
+    /// any number).  Returns the number deleted.
+    pub fn delete_old_alerts(&self, older_than_seconds: u64, max_rows: usize) -> Result<usize> {
+        let conn = self.conn.lock().unwrap();
+        let mut deleted = 0;
+        if older_than_seconds > 0 {
+            let cutoff_ms =
+                chrono::Utc::now().timestamp_millis() - (older_than_seconds as i64 * 1000);
+            deleted += conn.execute("DELETE FROM alerts WHERE last_seen < ?1", [cutoff_ms])?;
+        }
+        if max_rows > 0 {
+            let mut query = QueryBuilder::new("");
+            query.offset(max_rows);
+            let kept = query.sql("SELECT id FROM alerts", "ORDER BY last_seen DESC, id DESC");
+            let sql = format!("DELETE FROM alerts WHERE id IN ({kept})");
+            deleted += conn.execute(&sql, query.params())?;
+        }
+        Ok(deleted)
+    }
+
+    pub fn delete_old_data(&self, older_than_seconds: u64) -> Result<usize> {
+        let now = chrono::Utc::now().timestamp_millis();
+        let cutoff_ms = now - (older_than_seconds as i64 * 1000);
+        // Rows from before future stamps were clamped, or from another
+        // instance with a wrong clock, would never age out.
+        let future_ms = now.saturating_add(self.flush.max_clock_skew_ms());
+        let conn = self.conn.lock().unwrap();
+        let deleted = conn.execute(
+            "DELETE FROM packets WHERE timestamp < ?1 OR timestamp > ?2",
+            params![cutoff_ms, future_ms],
+        )?;
+        // A name is resolved after the rows lacking it and refreshed by each
+        // flush of rows enriched with it, so one this old only labels rows
+        // deleted above.
+        conn.execute("DELETE FROM hostnames WHERE resolved_at < ?1", params![cutoff_ms])?;
+        self.invalidate_queries();
+        self.metrics.retention_deleted.inc_by(deleted as u64);
+        Ok(deleted)
+    }
+
+    fn wal_size(&self) -> Option<u64> {
+        std::fs::metadata(self.wal_path.as_ref()?).ok().map(|m| m.len())
+    }
+
+    /// Checkpoint and truncate the WAL once it has grown past
+    /// `threshold_bytes`.  Returns None when no checkpoint was needed.
+    pub fn checkpoint_wal(&self, threshold_bytes: u64) -> Result<Option<WalCheckpoint>> {
+        let Some(size) = self.wal_size().filter(|size| *size > threshold_bytes) else {
+            return Ok(None);
+        };
+        let conn = self.conn.lock().unwrap();
+        let result = conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(2)?))
+        });
+        drop(conn);
+        let (busy, frames) = result.inspect_err(|_| {
+            self.metrics.wal_checkpoints_incomplete.inc();
+        })?;
+        let complete = busy == 0;
+        if complete {
+            self.metrics.wal_checkpoints.inc();
+        } else {
+            self.metrics.wal_checkpoints_incomplete.inc();
+        }
+        Ok(Some(WalCheckpoint {
+            wal_bytes: size,
+            frames_checkpointed: frames.max(0) as u64,
+            complete,
+        }))
+    }
+
+    /// Copy the database with `VACUUM INTO` and return the copy open but
+    /// already unlinked, so it is gone once the reader closes it however
+    /// the download ends.  The copy runs on a connection of its own, in one
+    /// read transaction, so it is consistent and queries keep their reader.
+    pub fn snapshot(&self) -> StorageResult<Snapshot> {
+        let Some((db_path, sqlite)) = &self.reopen_with else {
+            let reason = "only databases on disk opened by the daemon can be exported";
+            return Err(StorageError::Snapshot(reason.into()));
+        };
+        let Ok(_guard) = self.snapshot_lock.try_lock() else {
+            return Err(StorageError::Snapshot("another snapshot is in progress".into()));
+        };
+        let dir = snapshot_dir(db_path, sqlite);
+        remove_stale_snapshots(&dir);
+
+        let conn = Connection::open_with_flags(
+            db_path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+        conn.busy_timeout(Duration::from_millis(sqlite.busy_timeout_ms))?;
+        // Free pages are not copied.
+        let needed: i64 = conn.query_row(
+            "SELECT (page_count - freelist_count) * page_size
+             FROM pragma_page_count(), pragma_freelist_count(), pragma_page_size()",
+            [],
+            |row| row.get(0),
+        )?;
+        let needed = needed.max(0) as u64;
+        let reserve = sqlite.snapshot_min_free_mb.saturating_mul(1024 * 1024);
+        let available = available_bytes(&dir).map_err(|e| {
+            StorageError::Snapshot(format!("cannot check free space in {}: {}", dir.display(), e))
+        })?;
+        if available < needed.saturating_add(reserve) {
+            return Err(StorageError::Snapshot(format!(
+                "{} has {} MiB free, a {} MiB snapshot would leave less than {} MiB",
+                dir.display(),
+                available >> 20,
+                needed.div_ceil(1 << 20),
+                sqlite.snapshot_min_free_mb
+            )));
+        }
+
+        let name = format!(
+            "{}{}-{}.db",
+            SNAPSHOT_PREFIX,
+            std::process::id(),
+            chrono::Utc::now().timestamp_millis()
+        );
+        let path = dir.join(name);
+        let Some(dest) = path.to_str() else {
+            return Err(StorageError::Snapshot(format!("{} is not UTF-8", path.display())));
+        };
+        let copied = conn.execute("VACUUM INTO ?1", [dest]);
+        drop(conn);
+        let opened = copied.map_err(StorageError::from).and_then(|_| {
+            let file = std::fs::File::open(&path)?;
+            let bytes = file.metadata()?.len();
+            Ok(Snapshot { file, bytes })
+        });
+        if let Err(e) = std::fs::remove_file(&path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                tracing::warn!("Failed to remove snapshot {}: {}", path.display(), e);
+            }
+        }
+        opened
+    }
+}
+
+/// A copy of the database from `Storage::snapshot`.  The file has no name
+/// left on disk.
+#[derive(Debug)]
+pub struct Snapshot {
+    pub file: std::fs::File,
+    pub bytes: u64,
+}
+
+fn snapshot_dir(db_path: &str, sqlite: &SqliteConfig) -> PathBuf {
+    match &sqlite.snapshot_dir {
+        Some(dir) => PathBuf::from(dir),
+        None => match Path::new(db_path).parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => PathBuf::from("."),
+        },
+    }
+}
+
+/// Delete snapshot files in `dir` left behind by exports that died before
+/// unlinking them.
+fn remove_stale_snapshots(dir: &Path) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        if !entry.file_name().to_string_lossy().starts_with(SNAPSHOT_PREFIX) {
+            continue;
+        }
+        let age = entry.metadata().and_then(|m| m.modified()).ok().and_then(|t| t.elapsed().ok());
+        if age.is_some_and(|age| age >= SNAPSHOT_STALE_AFTER) {
+            match std::fs::remove_file(entry.path()) {
+                Ok(()) => tracing::info!("Removed stale snapshot {}", entry.path().display()),
+                Err(e) => tracing::warn!("Failed to remove {}: {}", entry.path().display(), e),
+            }
+        }
+    }
+}
+
+/// Bytes unprivileged processes may still write on the filesystem holding
+/// `dir`.
+// Field widths differ between targets.
+#[allow(clippy::unnecessary_cast)]
+fn available_bytes(dir: &Path) -> std::io::Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(dir.as_os_str().as_bytes())?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `path` is NUL-terminated and statvfs fills `stat` on success.
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    // SAFETY: initialized by the successful call above.
+    let stat = unsafe { stat.assume_init() };
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Outcome of a forced WAL checkpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WalCheckpoint {
+    /// WAL size that triggered the checkpoint.
+    pub wal_bytes: u64,
+    pub frames_checkpointed: u64,
+    /// False when readers kept part of the log from being copied back; the
+    /// next maintenance run tries again.
+    pub complete: bool,
+}
+
+// ── Backends ──────────────────────────────────────────────────────────────────
+
+/// Error from a storage backend.
+#[derive(Debug)]
+pub enum StorageError {
+    Sqlite(rusqlite::Error),
+    /// A snapshot was refused or could not be written.
+    Snapshot(String),
+    #[cfg(feature = "clickhouse")]
+    ClickHouse(crate::clickhouse::ClickHouseError),
+    #[cfg(feature = "postgres")]
+    Postgres(crate::postgres::PostgresError),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Sqlite(e) => e.fmt(f),
+            StorageError::Snapshot(reason) => write!(f, "snapshot failed: {}", reason),
+            #[cfg(feature = "clickhouse")]
+            StorageError::ClickHouse(e) => e.fmt(f),
+            #[cfg(feature = "postgres")]
+            StorageError::Postgres(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StorageError::Sqlite(e) => Some(e),
+            StorageError::Snapshot(_) => None,
+            #[cfg(feature = "clickhouse")]
+            StorageError::ClickHouse(e) => Some(e),
+            #[cfg(feature = "postgres")]
+            StorageError::Postgres(e) => Some(e),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for StorageError {
+    fn from(e: rusqlite::Error) -> Self {
+        StorageError::Sqlite(e)
+    }
+}
+
+impl From<std::io::Error> for StorageError {
+    fn from(e: std::io::Error) -> Self {
+        StorageError::Snapshot(e.to_string())
+    }
+}
+
+pub type StorageResult<T> = std::result::Result<T, StorageError>;
+
+/// Everything the agent and the API need from a database.
+///
+/// Queries are synchronous; the API runs them on the blocking pool.
+pub trait StorageBackend: Send + Sync {
+    /// Drain `rx` into the database until the channel closes, beating
+    /// `heartbeat` on every flush.  `aggregation_window_seconds == 0` stores
+    /// packets raw.
+    fn run_writer<'a>(
+        &'a self,
+        rx: &'a mut Receiver<StorageEvent>,
+        aggregation_window_seconds: u64,
+        heartbeat: &'a Heartbeat,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>>;
+
+    /// Drop and reopen the database connections before a writer restart.
+    fn reopen(&self) -> StorageResult<()>;
+
+    /// Write per-connection buckets another agent pushed to `/api/ingest`,
+    /// tagged with its instance name, in one transaction.
+    fn insert_ingested(&self, instance: &str, buckets: &[AggregatedBucket]) -> StorageResult<()>;
+
+    /// Most recent stored packets matching `filter`, newest first.
+    fn query_packets(
+        &self,
+        filter: &PacketFilter,
+        limit: usize,
+    ) -> StorageResult<Vec<HistoryRow>>;
+
+    /// Most recent stored packets, newest first.
+    fn query_history(&self, limit: usize) -> StorageResult<Vec<HistoryRow>> {
+        self.query_packets(&PacketFilter::default(), limit)
+    }
+
+    /// Usage rollups for buckets starting within `[from, to]`.
+    fn query_usage(
+        &self,
+        ip: Option<&str>,
+        from: i64,
+        to: i64,
+        granularity: UsageGranularity,
+    ) -> StorageResult<Vec<HostUsageRow>>;
+
+    /// Stored traffic matching `filter` grouped by one column, most bytes
+    /// first.
+    fn query_top(
+        &self,
+        by: TopColumn,
+        filter: &PacketFilter,
+        limit: usize,
+    ) -> StorageResult<Vec<StoredTalker>>;
+
+    /// Stored traffic matching `filter`, in total.
+    fn query_totals(&self, filter: &PacketFilter) -> StorageResult<StoredTotals>;
+
+    /// Per-day peer totals active within `[from, to]`.
+    fn query_peers(
+        &self,
+        ip: Option<&str>,
+        from: i64,
+        to: i64,
+    ) -> StorageResult<Vec<PeerTotals>>;
+
+    /// Newest stored rows and totals for one connection, either direction.
+    fn query_connection(
+        &self,
+        key: &ConnectionKey,
+        limit: usize,
+    ) -> StorageResult<(Vec<HistoryRow>, StoredConnectionTotals)>;
+
+    /// Alerts matching `filter`, newest row first.
+    fn query_alerts(
+        &self,
+        filter: &AlertFilter,
+        limit: usize,
+    ) -> StorageResult<Vec<StoredAlert>>;
+
+    /// Acknowledge alert `id`, returning it; None when it does not exist.
+    fn ack_alert(&self, id: i64, by: &str) -> StorageResult<Option<StoredAlert>>;
+
+    /// Apply alert retention by age and count, returning the count deleted.
+    fn delete_old_alerts(
+        &self,
+        older_than_seconds: u64,
+        max_rows: usize,
+    ) -> StorageResult<usize>;
+
+    /// Delete packets older than the retention period, returning the count.
+    fn delete_old_data(&self, older_than_seconds: u64) -> StorageResult<usize>;
+
+    /// Delete every stored packet, returning the count.
+    fn clear_packets(&self) -> StorageResult<usize>;
+
+    /// Packet rows after `after_id` lacking a hostname, for the backfill.
+    fn unresolved_rows(
+        &self,
+        after_id: i64,
+        since: Option<i64>,
+        limit: usize,
+    ) -> StorageResult<UnresolvedRows>;
+
+    /// Record the hostnames resolved for a batch from `unresolved_rows` and
+    /// mark it done, returning the number of addresses that lacked one.
+    fn fill_hostnames(&self, through_id: i64, names: &[(String, String)])
+        -> StorageResult<usize>;
+
+    /// About `limit` packet rows after position `after`, oldest first, for
+    /// copying to another backend.  Positions are the backend's own and
+    /// only mean something to it: start from 0 and pass `through` back.
+    fn export_packets(&self, after: i64, limit: usize) -> StorageResult<ExportedPackets>;
+
+    /// Every packet row with `from <= timestamp < to`, as `export_packets`
+    /// reads them, for checking one backend against another.
+    fn export_window(&self, from: i64, to: i64) -> StorageResult<Vec<PacketRecord>>;
+
+    /// Write rows from another backend's `export_packets` unchanged.
+    fn import_packets(&self, records: &[PacketRecord]) -> StorageResult<()>;
+
+    fn save_state(&self, key: &str, value: &str) -> StorageResult<()>;
+
+    fn load_state(&self, key: &str) -> StorageResult<Option<String>>;
+
+    /// Record the storage sample rate in effect from `since` (epoch ms),
+    /// so stored figures can be scaled back up.
+    fn record_sample_rate(&self, sample_rate: u32, since: i64) -> StorageResult<()>;
+
+    fn metrics(&self) -> &StorageMetrics;
+
+    /// Checkpoint the write-ahead log if it exceeds `threshold_bytes`.
+    /// Backends without one return None.
+    fn checkpoint_wal(&self, threshold_bytes: u64) -> StorageResult<Option<WalCheckpoint>>;
+
+    /// Refresh `db_size_bytes` and `wal_size_bytes`.  Blocking; called
+    /// from a timer on the blocking pool, never from a request.
+    fn update_size_metric(&self);
+
+    /// A consistent copy of the whole database, for export.
+    fn snapshot(&self) -> StorageResult<Snapshot>;
+
+    /// The writer counters and the last measured database size.
+    fn stats(&self) -> StorageStats {
+        self.metrics().stats()
+    }
+}
+
+impl StorageBackend for Storage {
+    fn run_writer<'a>(
+        &'a self,
+        rx: &'a mut Receiver<StorageEvent>,
+        aggregation_window_seconds: u64,
+        heartbeat: &'a Heartbeat,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>> {
+        Box::pin(Storage::run_writer(self, rx, aggregation_window_seconds, heartbeat))
+    }
+
+    fn reopen(&self) -> StorageResult<()> {
+        Ok(Storage::reopen(self)?)
+    }
+
+    fn insert_ingested(&self, instance: &str, buckets: &[AggregatedBucket]) -> StorageResult<()> {
+        Ok(Storage::insert_ingested(self, instance, buckets)?)
+    }
+
+    fn query_packets(
+        &self,
+        filter: &PacketFilter,
+        limit: usize,
+    ) -> StorageResult<Vec<HistoryRow>> {
+        Ok(Storage::query_packets(self, filter, limit)?)
+    }
+
+    fn query_usage(
+        &self,
+        ip: Option<&str>,
+        from: i64,
+        to: i64,
+        granularity: UsageGranularity,
+    ) -> StorageResult<Vec<HostUsageRow>> {
+        Ok(Storage::query_usage(self, ip, from, to, granularity)?)
+    }
+
+    fn query_top(
+        &self,
+        by: TopColumn,
+        filter: &PacketFilter,
+        limit: usize,
+    ) -> StorageResult<Vec<StoredTalker>> {
+        Ok(Storage::query_top(self, by, filter, limit)?)
+    }
+
+    fn query_totals(&self, filter: &PacketFilter) -> StorageResult<StoredTotals> {
+        Ok(Storage::query_totals(self, filter)?)
+    }
+
+    fn query_peers(
+        &self,
+        ip: Option<&str>,
+        from: i64,
+        to: i64,
+    ) -> StorageResult<Vec<PeerTotals>> {
+        Ok(Storage::query_peers(self, ip, from, to)?)
+    }
+
+    fn query_connection(
+        &self,
+        key: &ConnectionKey,
+        limit: usize,
+    ) -> StorageResult<(Vec<HistoryRow>, StoredConnectionTotals)> {
+        Ok(Storage::query_connection(self, key, limit)?)
+    }
+
+    fn query_alerts(
+        &self,
+        filter: &AlertFilter,
+        limit: usize,
+    ) -> StorageResult<Vec<StoredAlert>> {
+        Ok(Storage::query_alerts(self, filter, limit)?)
+    }
+
+    fn ack_alert(&self, id: i64, by: &str) -> StorageResult<Option<StoredAlert>> {
+        Ok(Storage::ack_alert(self, id, by)?)
+    }
+
+    fn delete_old_alerts(
+        &self,
+        older_than_seconds: u64,
+        max_rows: usize,
+    ) -> StorageResult<usize> {
+        Ok(Storage::delete_old_alerts(self, older_than_seconds, max_rows)?)
+    }
+
+    fn delete_old_data(&self, older_than_seconds: u64) -> StorageResult<usize> {
+        Ok(Storage::delete_old_data(self, older_than_seconds)?)
+    }
+
+    fn clear_packets(&self) -> StorageResult<usize> {
+        Ok(Storage::clear_packets(self)?)
+    }
+
+    fn unresolved_rows(
+        &self,
+        after_id: i64,
+        since: Option<i64>,
+        limit: usize,
+    ) -> StorageResult<UnresolvedRows> {
+        Ok(Storage::unresolved_rows(self, after_id, since, limit)?)
+    }
+
+    fn fill_hostnames(&self, through_id: i64, names: &[(String, String)]) -> StorageResult<usize> {
+        Ok(Storage::fill_hostnames(self, through_id, names)?)
+    }
+
+    fn export_packets(&self, after: i64, limit: usize) -> StorageResult<ExportedPackets> {
+        Ok(Storage::export_packets(self, after, limit)?)
+    }
+
+    fn export_window(&self, from: i64, to: i64) -> StorageResult<Vec<PacketRecord>> {
+        Ok(Storage::export_window(self, from, to)?)
+    }
+
+    fn import_packets(&self, records: &[PacketRecord]) -> StorageResult<()> {
+        Ok(Storage::import_packets(self, records)?)
+    }
+
+    fn save_state(&self, key: &str, value: &str) -> StorageResult<()> {
+        Ok(Storage::save_state(self, key, value)?)
+    }
+
+    fn load_state(&self, key: &str) -> StorageResult<Option<String>> {
+        Ok(Storage::load_state(self, key)?)
+    }
+
+    fn record_sample_rate(&self, sample_rate: u32, since: i64) -> StorageResult<()> {
+        Ok(Storage::record_sample_rate(self, sample_rate, since)?)
+    }
+
+    fn metrics(&self) -> &StorageMetrics {
+        &self.metrics
+    }
+
+    fn checkpoint_wal(&self, threshold_bytes: u64) -> StorageResult<Option<WalCheckpoint>> {
+        Ok(Storage::checkpoint_wal(self, threshold_bytes)?)
+    }
+
+    fn snapshot(&self) -> StorageResult<Snapshot> {
+        Storage::snapshot(self)
+    }
+
+    fn update_size_metric(&self) {
+        let conn = self.reader.lock().unwrap();
+        let size: Result<i64> = conn.query_row(
+            "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()",
+            [],
+            |row| row.get(0),
+        );
+        drop(conn);
+        match size {
+            Ok(size) => {
+                self.metrics.db_size_bytes.set(size);
+            }
+            Err(e) => tracing::warn!("Failed to read database size: {}", e),
+        }
+        self.metrics.wal_size_bytes.set(self.wal_size().unwrap_or(0) as i64);
+    }
+}
+
+/// Run the writer until the channel closes, restarting it with backoff
+/// whenever it panics.  Each restart reopens the database first.  Events
+/// queued meanwhile wait in `rx`; only the batch the writer held when it
+/// died is lost.
+pub async fn supervise_writer(
+    storage: Arc<dyn StorageBackend>,
+    mut rx: Receiver<StorageEvent>,
+    aggregation_window_seconds: u64,
+    heartbeat: Heartbeat,
+) {
+    let mut backoff = RESTART_BACKOFF_MIN;
+    loop {
+        let started = Instant::now();
+        let writer = storage.run_writer(&mut rx, aggregation_window_seconds, &heartbeat);
+        if AssertUnwindSafe(writer).catch_unwind().await.is_ok() {
+            return;
+        }
+        storage.metrics().writer_restarts.inc();
+        if started.elapsed() > RESTART_BACKOFF_MAX {
+            backoff = RESTART_BACKOFF_MIN;
+        }
+        loop {
+            tracing::error!("Storage writer panicked, restarting in {:?}", backoff);
+            heartbeat.fail(format!("writer panicked, restarting in {:?}", backoff));
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(RESTART_BACKOFF_MAX);
+            match storage.reopen() {
+                Ok(()) => break,
+                Err(e) => tracing::error!("Failed to reopen database: {}", e),
+            }
+        }
+    }
+}
+
+/// `<db>.<instance>.spill.jsonl`, so instances sharing a database never
+/// replay each other's rows.  A spill file left at the old shared path,
+/// `<db>.spill.jsonl`, is moved there by the first instance to start.
+fn instance_spill_path(db_path: &str, instance: &str) -> String {
+    let safe: String = instance
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || "-_.".contains(c) { c } else { '_' })
+        .collect();
+    let path = format!("{}.{}.spill.jsonl", db_path, safe);
+    let legacy = format!("{}.spill.jsonl", db_path);
+    if Path::new(&legacy).exists() && !Path::new(&path).exists() {
+        if let Err(e) = std::fs::rename(&legacy, &path) {
+            tracing::warn!("Cannot move spill file {} to {}: {}", legacy, path, e);
+        }
+    }
+    path
+}
+
+/// Where `db_url` points.
+#[derive(Debug, PartialEq, Eq)]
+pub enum DbLocation {
+    Sqlite(String),
+    /// The whole URL; only parsed further when the backend is built in.
+    ClickHouse(String),
+    /// Likewise the whole URL.
+    Postgres(String),
+}
+
+/// `sqlite://<path>` (or `sqlite:<path>`), `clickhouse://...` and
+/// `postgres://...` (or `postgresql://...`) URLs.
+pub fn parse_db_url(url: &str) -> anyhow::Result<DbLocation> {
+    if let Some(path) = url.strip_prefix("sqlite://").or_else(|| url.strip_prefix("sqlite:")) {
+        anyhow::ensure!(!path.is_empty(), "db_url {:?} has no database path", url);
+        return Ok(DbLocation::Sqlite(path.to_string()));
+    }
+    if url.starts_with("clickhouse://") {
+        return Ok(DbLocation::ClickHouse(url.to_string()));
+    }
+    if url.starts_with("postgres://") || url.starts_with("postgresql://") {
+        return Ok(DbLocation::Postgres(url.to_string()));
+    }
+    anyhow::bail!(
+        "unsupported db_url {:?}: expected sqlite://, clickhouse:// or postgres://",
+        url
+    )
+}
+
+/// Open the backend `db_url` selects, or the SQLite file at `db_path`
+/// when no URL is configured.
+#[allow(clippy::too_many_arguments)]
+#[cfg_attr(
+    not(all(feature = "clickhouse", feature = "postgres")),
+    allow(unused_variables)
+)]
+pub fn open_backend(
+    db_url: Option<&str>,
+    db_path: &str,
+    local_networks: Vec<IpNet>,
+    aggregation_key: AggregationKey,
+    sqlite: &SqliteConfig,
+    flush: &StorageConfig,
+    clickhouse: &ClickHouseConfig,
+    postgres: &PostgresConfig,
+    instance: Option<&str>,
+) -> anyhow::Result<Arc<dyn StorageBackend>> {
+    let location = match db_url {
+        Some(url) => parse_db_url(url)?,
+        None => DbLocation::Sqlite(db_path.to_string()),
+    };
+    match location {
+        DbLocation::Sqlite(path) => {
+            let mut sqlite = sqlite.clone();
+            if let (None, Some(instance)) = (&sqlite.spill_path, instance) {
+                sqlite.spill_path = Some(instance_spill_path(&path, instance));
+            }
+            let mut storage = Storage::open(&path, &sqlite)
+                .map_err(|e| anyhow::anyhow!("cannot open database {}: {}", path, e))?
+                .with_local_networks(local_networks)
+                .with_aggregation_key(aggregation_key)
+                .with_storage_config(flush.clone());
+            if let Some(instance) = instance {
+                storage = storage.with_instance(instance.to_string());
+            }
+            Ok(Arc::new(storage))
+        }
+        #[cfg(feature = "clickhouse")]
+        DbLocation::ClickHouse(url) => {
+            let mut backend = crate::clickhouse::ClickHouse::open(&url, clickhouse)?
+                .with_local_networks(local_networks)
+                .with_storage_config(flush.clone());
+            if let Some(instance) = instance {
+                backend = backend.with_instance(instance.to_string());
+            }
+            Ok(Arc::new(backend))
+        }
+        #[cfg(not(feature = "clickhouse"))]
+        DbLocation::ClickHouse(_) => {
+            anyhow::bail!("ClickHouse storage is not built in; rebuild with --features clickhouse")
+        }
+        #[cfg(feature = "postgres")]
+        DbLocation::Postgres(url) => {
+            let mut backend = crate::postgres::Postgres::open(&url, postgres)?
+                .with_local_networks(local_networks)
+                .with_storage_config(flush.clone());
+            if let Some(instance) = instance {
+                backend = backend.with_instance(instance.to_string());
+            }
+            Ok(Arc::new(backend))
+        }
+        #[cfg(not(feature = "postgres"))]
+        DbLocation::Postgres(_) => {
+            anyhow::bail!("PostgreSQL storage is not built in; rebuild with --features postgres")
+        }
+    }
+}
+
+/// Brings rows stamped further ahead than `max_clock_skew_seconds`, as
+/// after the clock jumped, back to the write time.  Such rows would
+/// otherwise outlive retention and sort above every real one.  Both
+/// backends run every row of a write through one.
+pub(crate) struct FutureClamp {
+    now: i64,
+    max_skew_seconds: u64,
+    max_skew_ms: i64,
+    clamped: u64,
+}
+
+impl FutureClamp {
+    pub(crate) fn new(flush: &StorageConfig) -> Self {
+        Self {
+            now: chrono::Utc::now().timestamp_millis(),
+            max_skew_seconds: flush.max_clock_skew_seconds,
+            max_skew_ms: flush.max_clock_skew_ms(),
+            clamped: 0,
+        }
+    }
+
+    /// One row's stamps, such as a bucket's timestamp and window.  When the
+    /// latest is too far ahead, every one past the write time becomes the
+    /// write time, so a window still ends no earlier than it starts and
+    /// the row counts once.
+    pub(crate) fn row<const N: usize>(&mut self, stamps: [i64; N]) -> [i64; N] {
+        let latest = stamps.iter().copied().max().unwrap_or(i64::MIN);
+        if latest.saturating_sub(self.now) <= self.max_skew_ms {
+            return stamps;
+        }
+        self.clamped += 1;
+        stamps.map(|stamp| stamp.min(self.now))
+    }
+
+    /// Count the rows clamped once the write committed, warning the first
+    /// time only so a clock stuck in the future does not flood the log.
+    pub(crate) fn record(self, metrics: &StorageMetrics) {
+        if self.clamped == 0 {
+            return;
+        }
+        if metrics.clamped_timestamps.get() == 0 {
+            tracing::warn!(
+                "Stored {} rows stamped more than {}s in the future at the write time; \
+                 check the clock (later ones are only counted)",
+                self.clamped,
+                self.max_skew_seconds
+            );
+        }
+        metrics.clamped_timestamps.inc_by(self.clamped);
+    }
+}
+
+/// Attempts `retry_busy` makes, and the pause before the second; later
+/// pauses grow linearly.
+const BUSY_ATTEMPTS: u32 = 4;
+const BUSY_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Run `f`, which may block on the database lock, without stalling the
+/// runtime worker it is called on: on the multi-threaded runtime the
+/// worker's other tasks move to another thread first.  On the blocking pool
+/// or a current-thread runtime `f` just runs.
+pub(crate) fn blocking<T>(f: impl FnOnce() -> T) -> T {
+    match tokio::runtime::Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(f)
+        }
+        _ => f(),
+    }
+}
+
+/// Begin a transaction that takes the write lock at once.  A deferred one
+/// that reads first fails with SQLITE_BUSY, without waiting, if another
+/// connection commits before it writes.
+fn write_transaction(conn: &mut Connection) -> Result<Transaction<'_>> {
+    conn.transaction_with_behavior(TransactionBehavior::Immediate)
+}
+
+fn is_busy(e: &rusqlite::Error) -> bool {
+    use rusqlite::ErrorCode::*;
+    matches!(e.sqlite_error_code(), Some(DatabaseBusy | DatabaseLocked))
+}
+
+/// Whether an insert failed because the database takes no writes at all,
+/// rather than because of the row.
+fn is_unwritable(e: &rusqlite::Error) -> bool {
+    use rusqlite::ErrorCode::*;
+    matches!(
+        e.sqlite_error_code(),
+        Some(
+            ReadOnly | DiskFull | SystemIoFailure | DatabaseBusy | DatabaseLocked | CannotOpen
+                | DatabaseCorrupt | NotADatabase | PermissionDenied
+        )
+    )
+}
+
+/// Add a row's traffic to the hour of `timestamp` for whichever endpoints
+/// are in `local_networks`.
+pub(crate) fn add_usage(
+    usage: &mut HostUsage,
+    local_networks: &[IpNet],
+    src_ip: &str,
+    dst_ip: &str,
+    timestamp: i64,
+    bytes: u64,
+    packets: u64,
+) {
+    if local_networks.is_empty() {
+        return;
+    }
+    let hour = timestamp - timestamp.rem_euclid(HOUR_MS);
+    for (ip, direction) in [(src_ip, "tx"), (dst_ip, "rx")] {
+        let is_local = ip
+            .parse::<IpAddr>()
+            .is_ok_and(|ip| local_networks.iter().any(|net| net.contains(&ip)));
+        if is_local {
+            let entry = usage.entry((ip.to_string(), hour, direction)).or_default();
+            entry.0 += bytes;
+            entry.1 += packets;
+        }
+    }
+}
+
+/// Note the hostnames a packet was enriched with for `upsert_packet_hostnames`.
+fn add_hostnames<'a>(names: &mut PacketHostnames<'a>, packet: &'a PacketMetadata) {
+    for (ip, hostname) in [
+        (&packet.src_ip, &packet.src_hostname),
+        (&packet.dst_ip, &packet.dst_hostname),
+    ] {
+        if let Some(hostname) = hostname {
+            names.insert(ip, hostname);
+        }
+    }
+}
+
+/// Record the hostnames one flush's rows were enriched with.  They are no
+/// longer stored on the rows themselves; refreshing `resolved_at` keeps
+/// retention from deleting a name that rows still show.
+fn upsert_packet_hostnames(tx: &Transaction, names: PacketHostnames) {
+    if names.is_empty() {
+        return;
+    }
+    let mut stmt = match tx.prepare(
+        "INSERT OR REPLACE INTO hostnames (ip, hostname, resolved_at) VALUES (?1, ?2, ?3)",
+    ) {
+        Ok(stmt) => stmt,
+        Err(e) => {
+            tracing::error!("Failed to prepare hostname statement: {}", e);
+            return;
+        }
+    };
+    let now = chrono::Utc::now().timestamp_millis();
+    for (ip, hostname) in names {
+        if let Err(e) = stmt.execute(params![ip, hostname, now]) {
+            tracing::error!("Failed to record hostname: {}", e);
+        }
+    }
+}
+
+/// Copy the latest hostname stored on packet rows for each address into
+/// `hostnames`, once.  Names already there are newer and kept.
+fn migrate_packet_hostnames(conn: &mut Connection) -> Result<()> {
+    let migrated: Option<String> = conn
+        .query_row("SELECT value FROM state WHERE key = ?1", [HOSTNAMES_MIGRATED_KEY], |row| {
+            row.get(0)
+        })
+        .optional()?;
+    if migrated.is_some() {
+        return Ok(());
+    }
+    let now = chrono::Utc::now().timestamp_millis();
+    let tx = conn.transaction()?;
+    // SQLite takes a bare column from the row holding the max().
+    let copied = tx.execute(
+        "INSERT OR IGNORE INTO hostnames (ip, hostname, resolved_at)
+         SELECT ip, hostname, max(timestamp) FROM (
+             SELECT src_ip AS ip, src_hostname AS hostname, timestamp FROM packets
+             WHERE src_hostname IS NOT NULL
+             UNION ALL
+             SELECT dst_ip, dst_hostname, timestamp FROM packets
+             WHERE dst_hostname IS NOT NULL
+         )
+         GROUP BY ip",
+        [],
+    )?;
+    tx.execute(
+        "INSERT OR REPLACE INTO state (key, value, updated_at) VALUES (?1, ?2, ?3)",
+        params![HOSTNAMES_MIGRATED_KEY, now.to_string(), now],
+    )?;
+    tx.commit()?;
+    if copied > 0 {
+        tracing::info!("Moved {} hostnames from packet rows into the hostnames table", copied);
+    }
+    Ok(())
+}
+
+/// Fold one flush's usage totals into `instance`'s `host_usage` rows, one
+/// statement per key.
+fn upsert_host_usage(tx: &Transaction, usage: HostUsage, instance: Option<&str>) {
+    if usage.is_empty() {
+        return;
+    }
+    let instance = instance.unwrap_or_default();
+    let mut stmt = match tx.prepare(
+        "INSERT INTO host_usage (local_ip, hour, direction, bytes, packets, instance)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+         ON CONFLICT (local_ip, hour, direction, instance) DO UPDATE SET
+             bytes = bytes + excluded.bytes,
+             packets = packets + excluded.packets",
+    ) {
+        Ok(stmt) => stmt,
+        Err(e) => {
+            tracing::error!("Failed to prepare usage statement: {}", e);
+            return;
+        }
+    };
+    for ((ip, hour, direction), (bytes, packets)) in usage {
+        let row = params![ip, hour, direction, bytes as i64, packets as i64, instance];
+        if let Err(e) = stmt.execute(row) {
+            tracing::error!("Failed to update host usage: {}", e);
+        }
+    }
+}
+
+/// A `WHERE` clause built one predicate at a time, with every value bound
+/// as a `?N` parameter.  Column names and operators are `&'static str`, so
+/// SQL text only ever comes from the source; filter values from a request
+/// can only arrive as parameters.  Predicates given None are left out.
+#[derive(Debug, Default)]
+struct QueryBuilder {
+    /// Table alias of the columns, e.g. "p.".
+    prefix: &'static str,
+    predicates: Vec<String>,
+    params: Vec<Value>,
+    /// Placeholders of the bound `LIMIT` and `OFFSET`.
+    limit: Option<String>,
+    offset: Option<String>,
+}
+
+impl QueryBuilder {
+    fn new(prefix: &'static str) -> Self {
+        Self { prefix, ..Self::default() }
+    }
+
+    /// Bind `value` and return its placeholder, for a statement that needs
+    /// a parameter outside the `WHERE` clause.
+    fn bind(&mut self, value: impl Into<Value>) -> String {
+        self.params.push(value.into());
+        format!("?{}", self.params.len())
+    }
+
+    /// `column op value`, e.g. `cmp("timestamp", ">=", from)`.
+    fn cmp<T: Into<Value>>(
+        &mut self,
+        column: &'static str,
+        op: &'static str,
+        value: Option<T>,
+    ) -> &mut Self {
+        if let Some(value) = value {
+            let value = self.bind(value);
+            self.predicates.push(format!("{}{} {} {}", self.prefix, column, op, value));
+        }
+        self
+    }
+
+    fn eq<T: Into<Value>>(&mut self, column: &'static str, value: Option<T>) -> &mut Self {
+        self.cmp(column, "=", value)
+    }
+
+    /// `column = value`, ignoring ASCII case.
+    fn eq_nocase<T: Into<Value>>(&mut self, column: &'static str, value: Option<T>) -> &mut Self {
+        if let Some(value) = value {
+            let value = self.bind(value);
+            self.predicates.push(format!("{}{} = {} COLLATE NOCASE", self.prefix, column, value));
+        }
+        self
+    }
+
+    /// Either column equal to `value`, such as the source or destination
+    /// address.
+    fn either_eq<T: Into<Value>>(
+        &mut self,
+        [a, b]: [&'static str; 2],
+        value: Option<T>,
+    ) -> &mut Self {
+        if let Some(value) = value {
+            let (p, value) = (self.prefix, self.bind(value));
+            self.predicates.push(format!("({p}{a} = {value} OR {p}{b} = {value})"));
+        }
+        self
+    }
+
+    fn not_null(&mut self, column: &'static str) -> &mut Self {
+        self.predicates.push(format!("{}{} IS NOT NULL", self.prefix, column));
+        self
+    }
+
+    fn null(&mut self, column: &'static str) -> &mut Self {
+        self.predicates.push(format!("{}{} IS NULL", self.prefix, column));
+        self
+    }
+
+    /// A condition holding no values, like `PortMatch::sql` built from
+    /// integers only.
+    fn condition(&mut self, sql: String) -> &mut Self {
+        self.predicates.push(sql);
+        self
+    }
+
+    fn limit(&mut self, limit: usize) -> &mut Self {
+        self.limit = Some(self.bind(i64::try_from(limit).unwrap_or(i64::MAX)));
+        self
+    }
+
+    /// Skip the first `offset` rows.  SQLite only takes `OFFSET` after a
+    /// `LIMIT`, so without one the limit is -1, none.
+    fn offset(&mut self, offset: usize) -> &mut Self {
+        self.offset = Some(self.bind(i64::try_from(offset).unwrap_or(i64::MAX)));
+        self
+    }
+
+    /// Everything `filter` asks for.
+    fn packet_filter(&mut self, filter: &PacketFilter) -> &mut Self {
+        self.cmp("timestamp", ">=", filter.from)
+            .cmp("timestamp", "<=", filter.to)
+            .either_eq(["src_ip", "dst_ip"], filter.ip.clone())
+            .eq("interface", filter.interface.clone())
+            .either_eq(["src_mac", "dst_mac"], filter.mac.clone())
+            .eq("flow_direction", filter.direction().map(str::to_string))
+            .eq("instance", filter.instance.clone())
+            .eq_nocase("protocol", filter.protocol.clone())
+            .eq("icmp_type", filter.icmp_type)
+            .eq("asn", filter.asn);
+        match &filter.country {
+            Some(CountryMatch::Code(code)) => self.eq("country", Some(code.clone())),
+            Some(CountryMatch::Unknown) => self.null("country"),
+            None => self,
+        };
+        if let Some(ports) = &filter.category {
+            self.condition(ports.sql(self.prefix));
+        }
+        self
+    }
+
+    /// `head`, then the `WHERE` clause if there is one, `tail` (`GROUP BY`,
+    /// `ORDER BY`), the `LIMIT` and the `OFFSET`.
+    fn sql(&self, head: &str, tail: &str) -> String {
+        let mut sql = head.to_string();
+        if !self.predicates.is_empty() {
+            sql.push_str(" WHERE ");
+            sql.push_str(&self.predicates.join(" AND "));
+        }
+        if !tail.is_empty() {
+            sql.push(' ');
+            sql.push_str(tail);
+        }
+        if self.limit.is_some() || self.offset.is_some() {
+            sql.push_str(" LIMIT ");
+            sql.push_str(self.limit.as_deref().unwrap_or("-1"));
+        }
+        if let Some(offset) = &self.offset {
+            sql.push_str(" OFFSET ");
+            sql.push_str(offset);
+        }
+        sql
+    }
+
+    fn params(&self) -> ParamsFromIter<std::slice::Iter<'_, Value>> {
+        params_from_iter(self.params.iter())
+    }
+}
+
+/// The columns `history_row` reads, with hostnames from the `hostnames`
+/// table falling back to those stored on rows from before it was the only
+/// place they are written.  Callers append the WHERE clause.
+const HISTORY_SELECT: &str = "SELECT p.timestamp, p.src_ip, p.dst_ip, p.src_port, p.dst_port,
+            p.protocol, p.length, p.direction,
+            COALESCE(hs.hostname, p.src_hostname), COALESCE(hd.hostname, p.dst_hostname),
+            p.domain, p.ttl, p.dscp, p.interface, p.payload_length,
+            p.aggregation IS NOT NULL, p.packet_count, p.src_mac, p.dst_mac, p.flow_direction,
+            p.instance, p.icmp_type, p.icmp_code, p.country, p.asn
+     FROM packets p
+     LEFT JOIN hostnames hs ON hs.ip = p.src_ip
+     LEFT JOIN hostnames hd ON hd.ip = p.dst_ip";
+
+const ALERT_SELECT: &str = "SELECT id, rule, severity, subject, message, timestamp,
+            COALESCE(last_seen, timestamp), count, acked, acked_by, acked_at, instance
+     FROM alerts";
+
+fn stored_alert(row: &rusqlite::Row) -> Result<StoredAlert> {
+    Ok(StoredAlert {
+        id: row.get(0)?,
+        rule: row.get(1)?,
+        severity: row.get(2)?,
+        subject: row.get(3)?,
+        message: row.get(4)?,
+        first_seen: row.get(5)?,
+        last_seen: row.get(6)?,
+        count: row.get::<_, i64>(7)? as u64,
+        acked: row.get(8)?,
+        acked_by: row.get(9)?,
+        acked_at: row.get(10)?,
+        instance: row.get(11)?,
+    })
+}
+
+fn history_row(row: &rusqlite::Row) -> Result<HistoryRow> {
+    let dscp: Option<u8> = row.get(12)?;
+    let protocol: String = row.get(5)?;
+    let (icmp_type, icmp_code): (Option<u8>, Option<u8>) = (row.get(21)?, row.get(22)?);
+    let icmp = icmp_type
+        .zip(icmp_code)
+        .map(|(kind, code)| u16::from_be_bytes([kind, code]))
+        .and_then(|port| IcmpMessage::from_flow(&protocol, port));
+    let packet = PacketMetadata {
+        timestamp: row.get(0)?,
+        src_ip: row.get(1)?,
+        dst_ip: row.get(2)?,
+        src_port: row.get(3)?,
+        dst_port: row.get(4)?,
+        protocol,
+        length: row.get(6)?,
+        payload_length: row.get::<_, Option<usize>>(14)?.unwrap_or(0),
+        direction: row.get::<_, Option<String>>(7)?.unwrap_or_else(|| "ingress".to_string()),
+        flow_direction: row.get::<_, Option<String>>(19)?.and_then(|d| d.parse().ok()),
+        cast: None,
+        interface: row.get::<_, Option<String>>(13)?.unwrap_or_default(),
+        src_mac: row.get(17)?,
+        dst_mac: row.get(18)?,
+        src_hostname: row.get(8)?,
+        dst_hostname: row.get(9)?,
+        domain: row.get(10)?,
+        country: row.get(23)?,
+        asn: row.get(24)?,
+        service: None,
+        ttl: row.get(11)?,
+        dscp,
+        dscp_class: dscp.map(dscp_class_name),
+        icmp_type,
+        icmp_code,
+        icmp_name: icmp.map(|m| m.name()),
+    };
+    let aggregated: bool = row.get(15)?;
+    Ok(HistoryRow {
+        packet,
+        kind: if aggregated { RowKind::Aggregated } else { RowKind::Raw },
+        packet_count: row.get(16)?,
+        instance: row.get(20)?,
+        service_port: None,
+    })
+}
+
+/// `ALTER TABLE ... ADD COLUMN` unless `table` already has `column`.
+///
+/// Checking `PRAGMA table_info` first keeps the migration idempotent without
+/// swallowing unrelated errors (locked or read-only databases).
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str, decl: &str) -> Result<()> {
+    if !has_column(conn, table, column)? {
+        conn.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, decl),
+            [],
+        )?;
+    }
+    Ok(())
+}
+
+fn has_column(conn: &Connection, table: &str, column: &str) -> Result<bool> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let exists = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .filter_map(|name| name.ok())
+        .any(|name| name == column);
+    Ok(exists)
+}
+
+/// Rebuild a rollup table from before instances with `create`, which adds
+/// `instance` as the last column and to the primary key.  The old rows
+/// become the unnamed agent's.  SQLite cannot change a primary key in
+/// place; the check repeats under the write lock, so of two agents opening
+/// the database at once only one rebuilds.
+fn key_by_instance(conn: &mut Connection, table: &str, create: &str) -> Result<()> {
+    if has_column(conn, table, "instance")? {
+        return Ok(());
+    }
+    let tx = write_transaction(conn)?;
+    if has_column(&tx, table, "instance")? {
+        return Ok(());
+    }
+    tx.execute(&format!("ALTER TABLE {table} RENAME TO {table}_old"), [])?;
+    tx.execute(create, [])?;
+    let copied = tx.execute(&format!("INSERT INTO {table} SELECT *, '' FROM {table}_old"), [])?;
+    tx.execute(&format!("DROP TABLE {table}_old"), [])?;
+    tx.commit()?;
+    tracing::info!("Keyed {} by instance ({} rows kept)", table, copied);
+    Ok(())
+}
+
+// ── Aggregation Windows ───────────────────────────────────────────────────────
+
+/// Aggregated buckets keyed by window start and aggregation key.
+type Buckets = HashMap<(i64, String), AggregatedBucket>;
+
+/// How long after a window closes its buckets are written, so packets
+/// stamped just before the boundary but still queued make it in.
+const WINDOW_FLUSH_GRACE: Duration = Duration::from_millis(500);
+
+/// Start and (exclusive) end of the `window_ms` window containing
+/// `timestamp`.  Windows are aligned to multiples of their length since the
+/// epoch, so 60s windows start on the minute.
+fn window_bounds(timestamp: i64, window_ms: i64) -> (i64, i64) {
+    let start = timestamp - timestamp.rem_euclid(window_ms);
+    (start, start + window_ms)
+}
+
+/// When to flush next: the end of the current window plus the grace period.
+fn next_flush(clock: WallClock, window_ms: i64) -> Instant {
+    let now = clock.now_ms();
+    let (_, end) = window_bounds(now, window_ms);
+    Instant::now() + Duration::from_millis((end - now) as u64) + WINDOW_FLUSH_GRACE
+}
+
+/// Wall-clock source for window alignment.  Tests start it at a fixed time
+/// and let it advance with tokio's pausable clock, or hold it still as
+/// after a step back.
+#[derive(Debug, Clone, Copy)]
+enum WallClock {
+    System,
+    #[cfg(test)]
+    Mock { epoch_ms: i64, started: Instant },
+    #[cfg(test)]
+    Held(i64),
+}
+
+impl WallClock {
+    fn now_ms(self) -> i64 {
+        match self {
+            WallClock::System => chrono::Utc::now().timestamp_millis(),
+            #[cfg(test)]
+            WallClock::Mock { epoch_ms, started } => {
+                epoch_ms + started.elapsed().as_millis() as i64
+            }
+            #[cfg(test)]
+            WallClock::Held(epoch_ms) => epoch_ms,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    fn temp_db(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!(
+            "ayaflow-test-{}-{}.db",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn test_migration_adds_ttl_to_legacy_schema() {
+        let path = temp_db("migration");
+        {
+            let conn = Connection::open(&path).unwrap();
+            conn.execute_batch(
+                "CREATE TABLE packets (
+                    id INTEGER PRIMARY KEY,
+                    timestamp INTEGER NOT NULL,
+                    src_ip TEXT NOT NULL,
+                    dst_ip TEXT NOT NULL,
+                    src_port INTEGER,
+                    dst_port INTEGER,
+                    protocol TEXT,
+                    length INTEGER
+                );
+                INSERT INTO packets (timestamp, src_ip, dst_ip, src_port, dst_port, protocol, length)
+                VALUES (1, '10.0.0.1', '10.0.0.2', 1, 2, 'TCP', 60);",
+            )
+            .unwrap();
+        }
+
+        // Opening twice proves the migration is idempotent.
+        drop(Storage::new(&path).unwrap());
+        let storage = Storage::new(&path).unwrap();
+
+        let rows = storage.query_history(10).unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].packet.ttl, None);
+        assert_eq!(rows[0].packet.dscp, None);
+        assert_eq!(rows[0].packet.direction, "ingress");
+        assert_eq!(rows[0].packet.payload_length, 0);
+        assert_eq!((rows[0].kind, rows[0].packet_count), (RowKind::Raw, 1));
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    fn alert(subject: &str, timestamp: i64) -> Alert {
+        Alert {
+            timestamp,
+            rule: "ttl_below".into(),
+            severity: "warning".into(),
+            subject: subject.into(),
+            message: format!("TTL at {}", timestamp),
+        }
+    }
+
+    #[test]
+    fn test_alert_repeats_fold_until_acked() {
+        let storage = Storage::new(":memory:").unwrap();
+        let all = AlertFilter::default();
+        storage.insert_alert(&alert("10.0.0.7", 1_000)).unwrap();
+        storage.insert_alert(&alert("10.0.0.8", 1_500)).unwrap();
+        let repeat = Alert { severity: "critical".into(), ..alert("10.0.0.7", 2_000) };
+        storage.insert_alert(&repeat).unwrap();
+
+        let rows = storage.query_alerts(&all, 10).unwrap();
+        assert_eq!(rows.len(), 2);
+        let folded = &rows[1];
+        assert_eq!((folded.subject.as_str(), folded.count), ("10.0.0.7", 2));
+        assert_eq!((folded.first_seen, folded.last_seen), (1_000, 2_000));
+        assert_eq!(folded.severity, "critical");
+        assert_eq!(folded.message, "TTL at 2000");
+
+        let acked = storage.ack_alert(folded.id, "alice").unwrap().unwrap();
+        assert!(acked.acked);
+        assert_eq!(acked.acked_by.as_deref(), Some("alice"));
+        let again = storage.ack_alert(folded.id, "bob").unwrap().unwrap();
+        assert_eq!((again.acked_by, again.acked_at), (acked.acked_by, acked.acked_at));
+        assert!(storage.ack_alert(99, "alice").unwrap().is_none());
+
+        // The next firing after the acknowledgement opens a new row.
+        storage.insert_alert(&alert("10.0.0.7", 3_000)).unwrap();
+        let rows = storage.query_alerts(&all, 10).unwrap();
+        assert_eq!(rows.len(), 3);
+        assert_eq!((rows[0].subject.as_str(), rows[0].count), ("10.0.0.7", 1));
+        assert!(!rows[0].acked);
+        let open = AlertFilter { acked: Some(false), ..AlertFilter::default() };
+        assert_eq!(storage.query_alerts(&open, 10).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_alert_pages_and_retention() {
+        let storage = Storage::new(":memory:").unwrap();
+        for i in 1..=5 {
+            storage.insert_alert(&alert(&format!("10.0.0.{}", i), i * 1_000)).unwrap();
+        }
+        let page = |before_id| {
+            let filter = AlertFilter { before_id, ..AlertFilter::default() };
+            let rows = storage.query_alerts(&filter, 2).unwrap();
+            rows.iter().map(|a| a.id).collect::<Vec<_>>()
+        };
+        assert_eq!(page(None), [5, 4]);
+        assert_eq!(page(Some(4)), [3, 2]);
+        assert_eq!(page(Some(2)), [1]);
+
+        // A repeat folded into the oldest row leaves it on the last page.
+        storage.insert_alert(&alert("10.0.0.1", 6_000)).unwrap();
+        assert_eq!(page(Some(2)), [1]);
+        let since = AlertFilter { since: Some(4_000), ..AlertFilter::default() };
+        let rows = storage.query_alerts(&since, 10).unwrap();
+        assert_eq!(rows.iter().map(|a| a.id).collect::<Vec<_>>(), [5, 4, 1]);
+        let other = AlertFilter { rule: Some("hook:x".into()), ..AlertFilter::default() };
+        assert!(storage.query_alerts(&other, 10).unwrap().is_empty());
+
+        // The cap keeps the most recently seen rows.
+        assert_eq!(storage.delete_old_alerts(0, 2).unwrap(), 3);
+        assert_eq!(page(None), [5, 1]);
+        assert_eq!(storage.delete_old_alerts(0, 0).unwrap(), 0);
+        // These fired in 1970, long past any retention age.
+        assert_eq!(storage.delete_old_alerts(3600, 0).unwrap(), 2);
+    }
+
+    fn packet(src_ip: &str, dst_ip: &str, timestamp: i64, length: usize) -> PacketMetadata {
+        PacketMetadata {
+            timestamp,
+            src_ip: src_ip.into(),
+            dst_ip: dst_ip.into(),
+            length,
+            ..test_support::packet()
+        }
+    }
+
+    #[test]
+    fn test_parse_db_url() {
+        assert_eq!(
+            parse_db_url("sqlite:///var/lib/ayaflow/traffic.db").unwrap(),
+            DbLocation::Sqlite("/var/lib/ayaflow/traffic.db".into())
+        );
+        assert_eq!(
+            parse_db_url("sqlite:traffic.db").unwrap(),
+            DbLocation::Sqlite("traffic.db".into())
+        );
+        assert_eq!(
+            parse_db_url("postgres://u@db/ayaflow").unwrap(),
+            DbLocation::Postgres("postgres://u@db/ayaflow".into())
+        );
+        assert_eq!(
+            parse_db_url("postgresql://db").unwrap(),
+            DbLocation::Postgres("postgresql://db".into())
+        );
+        assert_eq!(
+            parse_db_url("clickhouse://ch:8123/ayaflow").unwrap(),
+            DbLocation::ClickHouse("clickhouse://ch:8123/ayaflow".into())
+        );
+        assert!(parse_db_url("sqlite://").is_err());
+        assert!(parse_db_url("mysql://db").is_err());
+
+        let (key, sqlite) = (AggregationKey::default(), SqliteConfig::default());
+        let (flush, clickhouse) = (StorageConfig::default(), ClickHouseConfig::default());
+        let postgres = PostgresConfig::default();
+        let open = |url| {
+            open_backend(
+                Some(url),
+                "x.db",
+                Vec::new(),
+                key,
+                &sqlite,
+                &flush,
+                &clickhouse,
+                &postgres,
+                None,
+            )
+        };
+        let backend = open("sqlite://:memory:").unwrap();
+        assert!(backend.query_packets(&PacketFilter::default(), 10).unwrap().is_empty());
+        #[cfg(not(feature = "postgres"))]
+        assert!(open("postgres://db/x").is_err());
+        #[cfg(not(feature = "clickhouse"))]
+        assert!(open("clickhouse://db/x").is_err());
+    }
+
+    #[test]
+    fn test_sample_rate_changes_recorded_per_instance() {
+        let path = temp_db("sample-rate");
+        let sqlite = SqliteConfig::default();
+        let edge = Storage::open(&path, &sqlite).unwrap().with_instance("edge-1".into());
+        let core = Storage::open(&path, &sqlite).unwrap().with_instance("core-1".into());
+        edge.record_sample_rate(1, 0).unwrap();
+        edge.record_sample_rate(10, 1_000).unwrap();
+        // Restarting at the same rate leaves no new boundary.
+        edge.record_sample_rate(10, 1_500).unwrap();
+        core.record_sample_rate(10, 1_600).unwrap();
+        edge.record_sample_rate(4, 2_000).unwrap();
+
+        let rows: Vec<(String, i64, u32)> = {
+            let conn = edge.conn.lock().unwrap();
+            let mut stmt = conn
+                .prepare("SELECT instance, since, sample_rate FROM capture_meta ORDER BY since")
+                .unwrap();
+            let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)));
+            rows.unwrap().collect::<Result<_>>().unwrap()
+        };
+        let row = |instance: &str, since, rate| (instance.to_string(), since, rate);
+        assert_eq!(
+            rows,
+            [row("edge-1", 1_000, 10), row("core-1", 1_600, 10), row("edge-1", 2_000, 4)]
+        );
+        drop((edge, core));
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path, suffix));
+        }
+    }
+
+    #[test]
+    fn test_storage_metrics_move_on_flush() {
+        let storage = Storage::new(":memory:").unwrap();
+        let metrics = storage.metrics();
+        storage
+            .flush(&mut vec![
+                packet("10.0.0.1", "10.0.0.2", 1_000, 60),
+                packet("10.0.0.1", "10.0.0.2", 2_000, 60),
+            ])
+            .unwrap();
+        let bucket = AggregatedBucket::from_packet(&packet("10.0.0.1", "10.0.0.3", 3_000, 90));
+        storage
+            .insert_buckets([&bucket], AggregationKey::Connection)
+            .unwrap();
+        assert_eq!(metrics.rows_inserted.get(), 3);
+        assert_eq!(metrics.transaction_failures.get(), 0);
+
+        // Everything above is far older than a 1s retention.
+        assert_eq!(storage.delete_old_data(1).unwrap(), 3);
+        assert_eq!(metrics.retention_deleted.get(), 3);
+
+        storage.update_size_metric();
+        assert!(metrics.db_size_bytes.get() > 0);
+
+        let mut registry = Registry::default();
+        metrics.register(&mut registry);
+        let mut text = String::new();
+        prometheus_client::encoding::text::encode(&mut text, &registry).unwrap();
+        assert!(text.contains("ayaflow_storage_rows_inserted_total 3"), "{}", text);
+        assert!(text.contains("ayaflow_storage_flush_batch_size_count 2"), "{}", text);
+        assert!(text.contains("ayaflow_storage_flush_batch_size_sum 3.0"), "{}", text);
+        assert!(text.contains("ayaflow_storage_flush_duration_seconds_count 2"), "{}", text);
+
+        // The headroom sampler takes the longest flush and starts over.
+        assert!(!metrics.take_flush_peak().is_zero());
+        assert!(metrics.take_flush_peak().is_zero());
+    }
+
+    #[test]
+    fn test_future_timestamps_clamped_and_expired() {
+        let storage = Storage::new(":memory:").unwrap();
+        let metrics = storage.metrics();
+        let now = chrono::Utc::now().timestamp_millis();
+        let (day, minute) = (86_400_000, 60_000);
+        storage
+            .flush(&mut vec![
+                packet("10.0.0.1", "10.0.0.2", now + 2 * day, 60),
+                packet("10.0.0.1", "10.0.0.2", now + 10 * minute, 60),
+            ])
+            .unwrap();
+        let bucket = AggregatedBucket::from_packet(&packet("10.0.0.1", "10.0.0.3", now + day, 90));
+        storage.insert_buckets([&bucket], AggregationKey::Connection).unwrap();
+        assert_eq!(metrics.clamped_timestamps.get(), 2);
+        let window: (i64, i64) = storage
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT window_start, window_end FROM packets WHERE window_start IS NOT NULL",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert!(window.0 <= window.1 && window.1 < now + minute, "{:?}", window);
+
+        let mut stamps: Vec<i64> = storage
+            .query_history(10)
+            .unwrap()
+            .iter()
+            .map(|row| row.packet.timestamp)
+            .collect();
+        stamps.sort();
+        assert!(stamps[0] >= now && stamps[1] < now + minute, "{:?}", stamps);
+        // Within the allowed skew, kept as stamped.
+        assert_eq!(stamps[2], now + 10 * minute);
+
+        // A row stored before clamping, a year ahead, is expired by
+        // retention however long that is; rows within the skew are not.
+        storage
+            .conn
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT INTO packets (timestamp, src_ip, dst_ip, src_port, dst_port, protocol, length, direction)
+                 VALUES (?1, '10.0.0.9', '10.0.0.2', 1, 2, 'TCP', 60, 'ingress')",
+                params![now + 365 * day],
+            )
+            .unwrap();
+        assert_eq!(storage.delete_old_data(3_600).unwrap(), 1);
+        assert_eq!(storage.query_history(10).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_two_instances_share_a_database() {
+        let path = temp_db("instances");
+        let open = |name: &str| {
+            let storage = Storage::open(&path, &SqliteConfig::default()).unwrap();
+            storage.with_instance(name.to_string())
+        };
+        let (a, b) = (open("host-eth0"), open("host-eth1"));
+        // Warm a's query cache; only b writes next.
+        assert!(a.query_history(10).unwrap().is_empty());
+
+        std::thread::scope(|scope| {
+            for storage in [&a, &b] {
+                scope.spawn(move || {
+                    for batch in 0..50 {
+                        let mut rows: Vec<_> = (0..20)
+                            .map(|i| packet("10.0.0.1", "10.0.0.2", batch * 100 + i, 60))
+                            .collect();
+                        storage.flush(&mut rows).unwrap();
+                        assert!(rows.is_empty());
+                    }
+                });
+            }
+        });
+        let only = |instance: &str| PacketFilter {
+            instance: Some(instance.to_string()),
+            ..PacketFilter::default()
+        };
+        for (storage, name) in [(&a, "host-eth0"), (&b, "host-eth1")] {
+            let rows = storage.query_packets(&only(name), 10_000).unwrap();
+            assert_eq!(rows.len(), 1000);
+            assert!(rows.iter().all(|row| row.instance.as_deref() == Some(name)));
+        }
+        assert_eq!(a.query_packets(&PacketFilter::default(), 10_000).unwrap().len(), 2000);
+        assert!(a.query_packets(&only("other"), 10).unwrap().is_empty());
+
+        // b's writes reach a's cache through the data version alone.
+        a.query_history(10).unwrap();
+        b.flush(&mut vec![packet("10.0.0.1", "10.0.0.2", 9_000, 60)]).unwrap();
+        assert_eq!(a.query_history(10).unwrap()[0].packet.timestamp, 9_000);
+
+        // Retention from either instance deletes by age, whoever wrote the
+        // row, and running it twice changes nothing.
+        assert_eq!(a.delete_old_data(0).unwrap(), 2001);
+        assert_eq!(b.delete_old_data(0).unwrap(), 0);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path, suffix));
+        }
+    }
+
+    #[test]
+    fn test_alerts_and_rollups_kept_per_instance() {
+        let path = temp_db("instance-rollups");
+        let open = |name: &str| {
+            Storage::open(&path, &SqliteConfig::default())
+                .unwrap()
+                .with_instance(name.to_string())
+                .with_local_networks(vec!["192.168.1.0/24".parse().unwrap()])
+        };
+        let (a, b) = (open("host-eth0"), open("host-eth1"));
+        let peer = PeerTotals {
+            ip: "8.8.8.8".into(),
+            day: 0,
+            first_seen: 1_000,
+            last_seen: 2_000,
+            bytes: 100,
+            packets: 2,
+            connections: 1,
+        };
+        for storage in [&a, &b] {
+            storage.insert_alert(&alert("10.0.0.7", 1_000)).unwrap();
+            storage.flush(&mut vec![packet("8.8.8.8", "192.168.1.20", 1_000, 500)]).unwrap();
+            storage.upsert_peers(std::slice::from_ref(&peer)).unwrap();
+        }
+
+        // The same firing on two instances opens a row on each.
+        let alerts = a.query_alerts(&AlertFilter::default(), 10).unwrap();
+        let mut raised: Vec<_> = alerts.iter().map(|a| (a.instance.as_deref(), a.count)).collect();
+        raised.sort();
+        assert_eq!(raised, [(Some("host-eth0"), 1), (Some("host-eth1"), 1)]);
+        let only_b = AlertFilter { instance: Some("host-eth1".into()), ..AlertFilter::default() };
+        let alerts = a.query_alerts(&only_b, 10).unwrap();
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].instance.as_deref(), Some("host-eth1"));
+
+        // Rollups keep a row per instance and are read summed.
+        let conn = Connection::open(&path).unwrap();
+        let count = |table: &str| -> i64 {
+            let sql = format!("SELECT COUNT(DISTINCT instance) FROM {}", table);
+            conn.query_row(&sql, [], |row| row.get(0)).unwrap()
+        };
+        assert_eq!((count("host_usage"), count("peers")), (2, 2));
+        let usage = a.query_usage(None, 0, i64::MAX, UsageGranularity::Day).unwrap();
+        assert_eq!(usage.len(), 1);
+        assert_eq!((usage[0].bytes, usage[0].packets), (1000, 2));
+        let peers = b.query_peers(None, 0, i64::MAX).unwrap();
+        assert_eq!(peers.len(), 1);
+        assert_eq!((peers[0].bytes, peers[0].connections), (200, 2));
+        assert_eq!((peers[0].first_seen, peers[0].last_seen), (1_000, 2_000));
+        drop(conn);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path, suffix));
+        }
+    }
+
+    #[test]
+    fn test_rollups_from_before_instances_are_kept() {
+        let path = temp_db("rollup-migration");
+        {
+            let conn = Connection::open(&path).unwrap();
+            conn.execute_batch(
+                "CREATE TABLE host_usage (
+                    local_ip TEXT NOT NULL,
+                    hour INTEGER NOT NULL,
+                    direction TEXT NOT NULL,
+                    bytes INTEGER NOT NULL,
+                    packets INTEGER NOT NULL,
+                    PRIMARY KEY (local_ip, hour, direction)
+                );
+                INSERT INTO host_usage VALUES ('192.168.1.20', 0, 'rx', 700, 7);
+                CREATE TABLE peers (
+                    remote_ip TEXT NOT NULL,
+                    day INTEGER NOT NULL,
+                    first_seen INTEGER NOT NULL,
+                    last_seen INTEGER NOT NULL,
+                    bytes INTEGER NOT NULL,
+                    packets INTEGER NOT NULL,
+                    connections INTEGER NOT NULL,
+                    PRIMARY KEY (remote_ip, day)
+                );
+                INSERT INTO peers VALUES ('8.8.8.8', 0, 1000, 2000, 300, 3, 1);",
+            )
+            .unwrap();
+        }
+
+        // Opening twice proves the rebuild runs once.
+        drop(Storage::new(&path).unwrap());
+        let storage = Storage::new(&path).unwrap().with_instance("host-eth0".into());
+        storage.upsert_peers(&[PeerTotals {
+            ip: "8.8.8.8".into(),
+            day: 0,
+            first_seen: 500,
+            last_seen: 1_500,
+            bytes: 100,
+            packets: 1,
+            connections: 1,
+        }])
+        .unwrap();
+        let usage = storage.query_usage(None, 0, i64::MAX, UsageGranularity::Hour).unwrap();
+        assert_eq!((usage.len(), usage[0].bytes, usage[0].packets), (1, 700, 7));
+        let peers = storage.query_peers(None, 0, i64::MAX).unwrap();
+        assert_eq!(peers.len(), 1);
+        assert_eq!((peers[0].bytes, peers[0].connections), (400, 2));
+        assert_eq!((peers[0].first_seen, peers[0].last_seen), (500, 2_000));
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path, suffix));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_busy_retries_leave_the_runtime_running() {
+        let path = temp_db("busy-runtime");
+        // No busy timeout: every attempt finds the lock held and backs off.
+        let sqlite = SqliteConfig { busy_timeout_ms: 0, ..SqliteConfig::default() };
+        let storage = Arc::new(Storage::open(&path, &sqlite).unwrap());
+        let locker = Connection::open(&path).unwrap();
+        locker.execute_batch("BEGIN IMMEDIATE").unwrap();
+
+        let writer = tokio::spawn({
+            let storage = storage.clone();
+            async move { storage.flush(&mut vec![packet("10.0.0.1", "10.0.0.2", 1_000, 60)]) }
+        });
+        // Spawned onto the same single worker, this only runs on time if
+        // the writer's waits gave the worker up.
+        let started = std::time::Instant::now();
+        let other = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            started.elapsed()
+        });
+        let waited = other.await.unwrap();
+        locker.execute_batch("COMMIT").unwrap();
+        writer.await.unwrap().unwrap();
+        assert!(waited < Duration::from_millis(150), "{:?}", waited);
+        assert!(storage.metrics().busy_retries.get() > 0);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path, suffix));
+        }
+    }
+
+    #[test]
+    fn test_query_cache_invalidated_by_writes() {
+        let storage = Storage::new(":memory:").unwrap();
+        let metrics = storage.metrics();
+        storage.flush(&mut vec![packet("10.0.0.1", "10.0.0.2", 1_000, 60)]).unwrap();
+        let hits = || (metrics.query_cache_hits.get(), metrics.query_cache_misses.get());
+
+        assert_eq!(storage.query_history(10).unwrap().len(), 1);
+        assert_eq!(storage.query_history(10).unwrap().len(), 1);
+        assert_eq!(hits(), (1, 1));
+        assert!(metrics.query_cache_bytes.get() > 0);
+
+        // Every kind of write is seen by the next query.
+        storage.flush(&mut vec![packet("10.0.0.1", "10.0.0.2", 2_000, 60)]).unwrap();
+        assert_eq!(metrics.query_cache_bytes.get(), 0);
+        assert_eq!(storage.query_history(10).unwrap().len(), 2);
+        let bucket = AggregatedBucket::from_packet(&packet("10.0.0.1", "10.0.0.3", 3_000, 90));
+        storage.insert_buckets([&bucket], AggregationKey::Connection).unwrap();
+        assert_eq!(storage.query_history(10).unwrap().len(), 3);
+        storage.upsert_hostnames(&[("10.0.0.2".into(), "nas.lan".into())]).unwrap();
+        let rows = storage.query_history(10).unwrap();
+        assert_eq!(rows[1].packet.dst_hostname.as_deref(), Some("nas.lan"));
+        storage.clear_packets().unwrap();
+        assert!(storage.query_history(10).unwrap().is_empty());
+        assert_eq!(hits(), (1, 5));
+        assert!(storage.query_history(10).unwrap().is_empty());
+        assert_eq!(hits(), (2, 5));
+
+        let disabled = StorageConfig { query_cache_entries: 0, ..StorageConfig::default() };
+        let storage = Storage::new(":memory:").unwrap().with_storage_config(disabled);
+        storage.query_history(10).unwrap();
+        storage.query_history(10).unwrap();
+        assert_eq!(storage.metrics.query_cache_misses.get(), 0);
+    }
+
+    #[test]
+    fn test_host_usage_rollup() {
+        let storage = Storage::new(":memory:")
+            .unwrap()
+            .with_local_networks(vec!["192.168.1.0/24".parse().unwrap()]);
+
+        // Two flushes in the same hour accumulate into one row per direction.
+        storage
+            .flush(&mut vec![
+                packet("8.8.8.8", "192.168.1.20", 1_000, 1000),
+                packet("192.168.1.20", "8.8.8.8", 2_000, 100),
+            ])
+            .unwrap();
+        storage
+            .flush(&mut vec![packet("1.1.1.1", "192.168.1.20", 3_000, 500)])
+            .unwrap();
+        // Next hour, and traffic without a local endpoint.
+        storage
+            .flush(&mut vec![
+                packet("8.8.8.8", "192.168.1.20", HOUR_MS + 1, 50),
+                packet("8.8.8.8", "1.1.1.1", 4_000, 9999),
+            ])
+            .unwrap();
+
+        let hourly = storage
+            .query_usage(Some("192.168.1.20"), 0, i64::MAX, UsageGranularity::Hour)
+            .unwrap();
+        let summary: Vec<(i64, &str, u64, u64)> = hourly
+            .iter()
+            .map(|r| (r.bucket, r.direction.as_str(), r.bytes, r.packets))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![(0, "rx", 1500, 2), (0, "tx", 100, 1), (HOUR_MS, "rx", 50, 1)]
+        );
+
+        let daily = storage
+            .query_usage(None, 0, i64::MAX, UsageGranularity::Day)
+            .unwrap();
+        assert_eq!(daily.len(), 2);
+        assert_eq!(daily[0].bytes, 1550);
+    }
+
+    #[tokio::test]
+    async fn test_peer_totals_survive_cleanup() {
+        use crate::locality::LocalNetworks;
+        use crate::state::TrafficState;
+
+        let local = LocalNetworks::new(vec!["192.168.1.0/24".parse().unwrap()]);
+        let traffic = TrafficState::new().with_local_networks(local);
+        traffic.update(&packet("185.10.20.30", "192.168.1.20", 1_000, 1400));
+        traffic.update(&packet("192.168.1.20", "185.10.20.30", 1_000, 100));
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        let peers = traffic.cleanup_stale_connections(Duration::from_millis(1));
+        assert_eq!(traffic.summary(1).active_connections, 0);
+        assert_eq!(peers.len(), 1);
+
+        let storage = Arc::new(Storage::new(":memory:").unwrap());
+        let registry = Arc::new(crate::health::HealthRegistry::new());
+        let heartbeat = registry.register("storage_writer", true, None);
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        let backend: Arc<dyn StorageBackend> = storage.clone();
+        tokio::spawn(supervise_writer(backend, rx, 0, heartbeat));
+        // A later connection the same day adds to the row.
+        let again = PeerTotals { bytes: 500, packets: 1, connections: 1, ..peers[0].clone() };
+        tx.send(StorageEvent::Peers(peers.clone())).await.unwrap();
+        tx.send(StorageEvent::Peers(vec![again])).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let rows = storage.query_peers(Some("185.10.20.30"), 0, i64::MAX).unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!((rows[0].bytes, rows[0].packets, rows[0].connections), (2000, 3, 3));
+        assert_eq!(rows[0].day, peers[0].day);
+        assert!(storage.query_peers(Some("8.8.8.8"), 0, i64::MAX).unwrap().is_empty());
+        assert!(storage.query_peers(None, 0, rows[0].first_seen - 1).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_packet_filter_and_top() {
+        let path = temp_db("filter");
+        let storage = Storage::new(&path).unwrap();
+        storage
+            .flush(&mut vec![
+                packet("10.0.0.1", "8.8.8.8", 1_000, 100),
+                packet("10.0.0.2", "8.8.8.8", 2_000, 300),
+                packet("10.0.0.1", "1.1.1.1", 3_000, 50),
+            ])
+            .unwrap();
+
+        // The read-only handle sees the same rows through the same filters.
+        let reader = Storage::open_read_only(&path).unwrap();
+        let filter = PacketFilter {
+            from: Some(1_500),
+            to: None,
+            ip: Some("10.0.0.1".to_string()),
+            interface: Some("eth0".to_string()),
+            mac: None,
+            direction: None,
+            category: None,
+            instance: None,
+            protocol: Some("tcp".to_string()),
+            icmp_type: None,
+            country: None,
+            asn: None,
+        };
+        let rows = reader.query_packets(&filter, 10).unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].packet.timestamp, 3_000);
+
+        let top = reader
+            .query_top(TopColumn::DstIp, &PacketFilter::default(), 10)
+            .unwrap();
+        let summary: Vec<(&str, u64, u64)> =
+            top.iter().map(|t| (t.key.as_str(), t.bytes, t.rows)).collect();
+        assert_eq!(summary, vec![("8.8.8.8", 400, 2), ("1.1.1.1", 50, 1)]);
+        let totals = reader.query_totals(&filter).unwrap();
+        assert_eq!(totals, StoredTotals { rows: 1, packets: 1, bytes: 50 });
+        assert!(reader.save_state("k", "v").is_err());
+        assert!(reader
+            .flush(&mut vec![packet("10.0.0.3", "8.8.8.8", 4_000, 10)])
+            .is_err());
+
+        let wlan = PacketMetadata {
+            interface: "wlan0".into(),
+            ..packet("10.0.0.1", "8.8.8.8", 5_000, 70)
+        };
+        storage.flush(&mut vec![wlan]).unwrap();
+        let rows = reader.query_packets(&filter, 10).unwrap();
+        assert_eq!(rows.len(), 1);
+        let filter = PacketFilter {
+            interface: Some("wlan0".to_string()),
+            ..PacketFilter::default()
+        };
+        let rows = reader.query_packets(&filter, 10).unwrap();
+        assert_eq!((rows.len(), rows[0].packet.interface.as_str()), (1, "wlan0"));
+        let top = reader.query_top(TopColumn::Interface, &PacketFilter::default(), 10).unwrap();
+        assert_eq!(top[0].key, "eth0");
+        assert_eq!(top[1].key, "wlan0");
+
+        let tv = PacketMetadata {
+            flow_direction: Some(FlowDirection::Outbound),
+            src_mac: Some("aa:bb:cc:dd:ee:ff".into()),
+            dst_mac: Some("00:11:22:33:44:55".into()),
+            ..packet("10.0.0.9", "8.8.8.8", 6_000, 20)
+        };
+        storage.flush(&mut vec![tv]).unwrap();
+        for mac in ["aa:bb:cc:dd:ee:ff", "00:11:22:33:44:55"] {
+            let filter = PacketFilter {
+                mac: Some(mac.to_string()),
+                ..PacketFilter::default()
+            };
+            let rows = reader.query_packets(&filter, 10).unwrap();
+            assert_eq!(rows.len(), 1);
+            assert_eq!(rows[0].packet.src_mac.as_deref(), Some("aa:bb:cc:dd:ee:ff"));
+        }
+        let outbound = PacketFilter {
+            direction: Some(FlowDirection::Outbound),
+            ..PacketFilter::default()
+        };
+        let rows = reader.query_packets(&outbound, 10).unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].packet.flow_direction, Some(FlowDirection::Outbound));
+        let top = reader.query_top(TopColumn::SrcIp, &outbound, 10).unwrap();
+        assert_eq!((top.len(), top[0].key.as_str()), (1, "10.0.0.9"));
+
+        assert_eq!(storage.clear_packets().unwrap(), 5);
+        assert!(reader.query_history(10).unwrap().is_empty());
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_query_builder_predicates_limit_and_offset() {
+        use rusqlite::types::Value::{Integer, Text};
+
+        // No predicates, no WHERE; no limit, no LIMIT.
+        let query = QueryBuilder::new("p.");
+        assert_eq!(query.sql("SELECT * FROM packets p", "ORDER BY p.id"), {
+            "SELECT * FROM packets p ORDER BY p.id"
+        });
+        assert!(query.params.is_empty());
+        let mut query = QueryBuilder::new("");
+        query.packet_filter(&PacketFilter::default()).eq("rule", None::<String>);
+        assert_eq!(query.sql("SELECT * FROM packets", ""), "SELECT * FROM packets");
+
+        let mut query = QueryBuilder::new("p.");
+        let filter = PacketFilter {
+            from: Some(1_000),
+            ip: Some("10.0.0.1".to_string()),
+            protocol: Some("tcp".to_string()),
+            ..PacketFilter::default()
+        };
+        query.packet_filter(&filter).limit(10);
+        assert_eq!(
+            query.sql("SELECT *", "ORDER BY p.timestamp DESC"),
+            "SELECT * WHERE p.timestamp >= ?1 AND (p.src_ip = ?2 OR p.dst_ip = ?2) \
+             AND p.protocol = ?3 COLLATE NOCASE ORDER BY p.timestamp DESC LIMIT ?4"
+        );
+        assert_eq!(
+            query.params,
+            vec![Integer(1_000), Text("10.0.0.1".into()), Text("tcp".into()), Integer(10)]
+        );
+
+        // A parameter bound ahead of the predicates keeps its number, and
+        // LIMIT follows whatever comes before it.
+        let mut query = QueryBuilder::new("");
+        let bucket = query.bind(3_600_000);
+        query.limit(0).eq("local_ip", Some("10.0.0.1".to_string()));
+        assert_eq!(bucket, "?1");
+        assert_eq!(query.sql("SELECT ?1", ""), "SELECT ?1 WHERE local_ip = ?3 LIMIT ?2");
+        let mut query = QueryBuilder::new("");
+        query.limit(usize::MAX);
+        assert_eq!(query.params, vec![Integer(i64::MAX)]);
+
+        // OFFSET always follows LIMIT, which is -1 when only skipping.
+        let mut query = QueryBuilder::new("");
+        query.offset(20).eq("rule", Some("scan".to_string())).limit(10);
+        assert_eq!(
+            query.sql("SELECT id FROM alerts", "ORDER BY id"),
+            "SELECT id FROM alerts WHERE rule = ?2 ORDER BY id LIMIT ?3 OFFSET ?1"
+        );
+        assert_eq!(query.params, vec![Integer(20), Text("scan".into()), Integer(10)]);
+        let mut query = QueryBuilder::new("");
+        query.offset(5);
+        assert_eq!(query.sql("SELECT id", ""), "SELECT id LIMIT -1 OFFSET ?1");
+        // Pages of a real query.
+        let conn = Connection::open_in_memory().unwrap();
+        let page = |limit: Option<usize>, offset: usize| {
+            let mut query = QueryBuilder::new("");
+            query.cmp("value", ">", Some(1)).offset(offset);
+            if let Some(limit) = limit {
+                query.limit(limit);
+            }
+            let numbers = "SELECT value FROM (SELECT 1 AS value UNION ALL SELECT 2 \
+                           UNION ALL SELECT 3 UNION ALL SELECT 4 UNION ALL SELECT 5)";
+            let mut stmt = conn.prepare(&query.sql(numbers, "ORDER BY value")).unwrap();
+            let rows = stmt.query_map(query.params(), |row| row.get::<_, i64>(0)).unwrap();
+            rows.collect::<Result<Vec<_>>>().unwrap()
+        };
+        assert_eq!(page(Some(2), 0), [2, 3]);
+        assert_eq!(page(Some(2), 2), [4, 5]);
+        assert_eq!(page(Some(2), 4), Vec::<i64>::new());
+        assert_eq!(page(None, 1), [3, 4, 5]);
+
+        // Every field at once; the category's ports take no parameters.
+        let filter = PacketFilter {
+            from: Some(1),
+            to: Some(2),
+            ip: Some("10.0.0.1".into()),
+            interface: Some("eth0".into()),
+            mac: Some("aa:bb:cc:dd:ee:ff".into()),
+            direction: Some(FlowDirection::Inbound),
+            category: Some(PortMatch {
+                ranges: vec![(443, 443)],
+                negate: false,
+                rule: Default::default(),
+            }),
+            instance: Some("edge".into()),
+            protocol: Some("udp".into()),
+            icmp_type: Some(3),
+            country: Some(CountryMatch::Code("CN".into())),
+            asn: Some(13335),
+        };
+        let mut query = QueryBuilder::new("p.");
+        query.packet_filter(&filter);
+        let sql = query.sql("SELECT *", "");
+        assert!(sql.contains(
+            "p.protocol = ?8 COLLATE NOCASE AND p.icmp_type = ?9 AND p.asn = ?10 \
+             AND p.country = ?11 AND (CASE"
+        ));
+        assert!(sql.ends_with("BETWEEN 443 AND 443)"), "{}", sql);
+        assert_eq!(query.params.len(), 11);
+        assert_eq!(query.params[5], Text("inbound".into()));
+        assert_eq!(query.params[8], Integer(3));
+        assert_eq!(query.params[10], Text("CN".into()));
+
+        let unknown = PacketFilter { country: Some(CountryMatch::Unknown), ..Default::default() };
+        let mut query = QueryBuilder::new("p.");
+        query.packet_filter(&unknown);
+        assert_eq!(query.sql("SELECT *", ""), "SELECT * WHERE p.country IS NULL");
+        assert!(query.params.is_empty());
+    }
+
+    #[test]
+    fn test_hostile_filter_values_are_only_parameters() {
+        const HOSTILE: &[&str] = &[
+            "' OR '1'='1",
+            "x'); DROP TABLE packets; --",
+            "\" OR \"\"=\"",
+            "10.0.0.1' UNION SELECT sql FROM sqlite_master --",
+            "%' OR 1=1 --",
+            "?1",
+            "NULL",
+            "\\'",
+            "eth0\0; DELETE FROM packets",
+            "é' /* */ OR 0x1 = 1",
+        ];
+        let filter = |value: &str| PacketFilter {
+            ip: Some(value.to_string()),
+            interface: Some(value.to_string()),
+            mac: Some(value.to_string()),
+            instance: Some(value.to_string()),
+            protocol: Some(value.to_string()),
+            ..PacketFilter::default()
+        };
+        let benign = {
+            let mut query = QueryBuilder::new("p.");
+            query.packet_filter(&filter("x")).limit(10);
+            query.sql(HISTORY_SELECT, "ORDER BY p.timestamp DESC")
+        };
+
+        let path = temp_db("hostile");
+        let storage = Storage::new(&path).unwrap();
+        let mut stored = vec![packet("10.0.0.1", "8.8.8.8", 1_000, 100)];
+        for (i, value) in HOSTILE.iter().enumerate() {
+            stored.push(PacketMetadata {
+                interface: value.to_string(),
+                ..packet("10.0.0.2", "8.8.8.8", 2_000 + i as i64, 10)
+            });
+        }
+        storage.flush(&mut stored).unwrap();
+
+        for value in HOSTILE {
+            // The statement is the same whatever the value, which only
+            // appears among the parameters.
+            let mut query = QueryBuilder::new("p.");
+            query.packet_filter(&filter(value)).limit(10);
+            assert_eq!(query.sql(HISTORY_SELECT, "ORDER BY p.timestamp DESC"), benign);
+            assert_eq!(query.params[0], Value::Text(value.to_string()));
+
+            assert!(storage.query_packets(&filter(value), 10).unwrap().is_empty());
+            let totals = storage.query_totals(&filter(value)).unwrap();
+            assert_eq!(totals.rows, 0, "{}", value);
+            assert!(storage.query_top(TopColumn::SrcIp, &filter(value), 10).unwrap().is_empty());
+            assert!(storage.query_peers(Some(value), 0, i64::MAX).unwrap().is_empty());
+            let usage = storage.query_usage(Some(value), 0, i64::MAX, UsageGranularity::Hour);
+            assert!(usage.unwrap().is_empty());
+            let alerts = AlertFilter {
+                severity: Some(value.to_string()),
+                rule: Some(value.to_string()),
+                ..AlertFilter::default()
+            };
+            assert!(storage.query_alerts(&alerts, 10).unwrap().is_empty());
+
+            // Matched as a plain string it finds the one row stored with it.
+            let exact = PacketFilter {
+                interface: Some(value.to_string()),
+                ..PacketFilter::default()
+            };
+            let rows = storage.query_packets(&exact, 10).unwrap();
+            assert_eq!(rows.len(), 1, "{}", value);
+            assert_eq!(rows[0].packet.interface, *value);
+        }
+        assert_eq!(storage.query_history(100).unwrap().len(), HOSTILE.len() + 1);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_hostnames_backfill_stored_packets() {
+        let path = temp_db("hostnames");
+        let storage = Storage::new(&path).unwrap();
+        let mut resolved_early = packet("10.0.0.1", "1.1.1.1", 2_000, 100);
+        resolved_early.dst_hostname = Some("one.one.one.one".into());
+        storage
+            .flush(&mut vec![packet("10.0.0.1", "8.8.8.8", 1_000, 100), resolved_early])
+            .unwrap();
+
+        storage
+            .upsert_hostnames(&[
+                ("8.8.8.8".into(), "dns.google".into()),
+                ("1.1.1.1".into(), "later.example".into()),
+            ])
+            .unwrap();
+        let rows = storage.query_packets(&PacketFilter::default(), 10).unwrap();
+        assert_eq!(rows[1].packet.dst_hostname.as_deref(), Some("dns.google"));
+        assert_eq!(rows[1].packet.src_hostname, None);
+        // The packet's name went to the table, so a later answer corrects it.
+        assert_eq!(rows[0].packet.dst_hostname.as_deref(), Some("later.example"));
+        let conn = Connection::open(&path).unwrap();
+        let stored: Option<String> = conn
+            .query_row("SELECT max(dst_hostname) FROM packets", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(stored, None);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_hostnames_migrated_from_packet_rows() {
+        let path = temp_db("hostnames-migration");
+        Storage::new(&path)
+            .unwrap()
+            .flush(&mut vec![
+                packet("10.0.0.1", "8.8.8.8", 1_000, 60),
+                packet("10.0.0.1", "1.1.1.1", 2_000, 60),
+                packet("10.0.0.2", "9.9.9.9", 3_000, 60),
+            ])
+            .unwrap();
+        // Rows as written before hostnames moved out of the packets table.
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "UPDATE packets SET src_hostname = 'old.lan', dst_hostname = 'dns.google'
+                 WHERE timestamp = 1000;
+             UPDATE packets SET src_hostname = 'laptop.lan' WHERE timestamp = 2000;
+             INSERT INTO hostnames (ip, hostname, resolved_at) VALUES ('1.1.1.1', 'one', 500);
+             DELETE FROM state WHERE key = 'hostnames_migrated';",
+        )
+        .unwrap();
+
+        let storage = Storage::new(&path).unwrap();
+        let mut stmt = conn
+            .prepare("SELECT ip, hostname, resolved_at FROM hostnames ORDER BY ip")
+            .unwrap();
+        let names: Vec<(String, String, i64)> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        // The latest name per address; one already in the table is kept.
+        assert_eq!(
+            names,
+            [
+                ("1.1.1.1".to_string(), "one".to_string(), 500),
+                ("10.0.0.1".to_string(), "laptop.lan".to_string(), 2000),
+                ("8.8.8.8".to_string(), "dns.google".to_string(), 1000),
+            ]
+        );
+        let rows = storage.query_packets(&PacketFilter::default(), 10).unwrap();
+        assert_eq!(rows[2].packet.src_hostname.as_deref(), Some("laptop.lan"));
+
+        // Only once: a name deleted by retention stays deleted.
+        conn.execute("DELETE FROM hostnames WHERE ip = '8.8.8.8'", []).unwrap();
+        drop(storage);
+        let storage = Storage::new(&path).unwrap();
+        let rows = storage.query_packets(&PacketFilter::default(), 10).unwrap();
+        // The row's own column is still read.
+        assert_eq!(rows[2].packet.dst_hostname.as_deref(), Some("dns.google"));
+        let count: i64 =
+            conn.query_row("SELECT count(*) FROM hostnames", [], |row| row.get(0)).unwrap();
+        assert_eq!(count, 2);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    /// Database size with hostnames in their own table, against the same
+    /// rows with the names stored on every row as before.
+    ///
+    /// `cargo test -p ayaflow --release -- --ignored --nocapture bench_`
+    #[test]
+    #[ignore]
+    fn bench_hostnames_table_size() {
+        const ROWS: usize = 500_000;
+        const HOSTS: usize = 2_000;
+        let path = temp_db("hostnames-size");
+        let storage = Storage::new(&path).unwrap();
+        for start in (0..ROWS).step_by(1000) {
+            let mut batch: Vec<PacketMetadata> = (start..start + 1000)
+                .map(|i| {
+                    let remote = format!("203.0.{}.{}", i % HOSTS / 250, i % HOSTS % 250);
+                    let mut packet = packet("10.0.0.1", &remote, i as i64, 1500);
+                    packet.src_hostname = Some("workstation-07.office.example.lan".into());
+                    let remote_name = format!("edge-{}.cdn.provider.example.net", i % HOSTS);
+                    packet.dst_hostname = Some(remote_name);
+                    packet
+                })
+                .collect();
+            storage.flush(&mut batch).unwrap();
+        }
+        let size = |conn: &Connection| -> i64 {
+            conn.execute_batch("VACUUM").unwrap();
+            conn.query_row(
+                "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap()
+        };
+        let conn = storage.conn.lock().unwrap();
+        let normalized = size(&conn);
+        conn.execute_batch(
+            "UPDATE packets SET
+                 src_hostname = (SELECT hostname FROM hostnames WHERE ip = src_ip),
+                 dst_hostname = (SELECT hostname FROM hostnames WHERE ip = dst_ip)",
+        )
+        .unwrap();
+        let per_row = size(&conn);
+        println!(
+            "{} rows, {} hosts: {} bytes with a hostnames table, {} with names on every row \
+             ({:.0}% smaller)",
+            ROWS,
+            HOSTS,
+            normalized,
+            per_row,
+            100.0 * (per_row - normalized) as f64 / per_row as f64
+        );
+        drop(conn);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_fill_hostnames_in_batches() {
+        let storage = Storage::new(":memory:").unwrap();
+        let mut named = packet("10.0.0.1", "1.1.1.1", 3_000, 100);
+        named.src_hostname = Some("laptop.lan".into());
+        named.dst_hostname = Some("one.one.one.one".into());
+        storage
+            .flush(&mut vec![
+                packet("10.0.0.1", "8.8.8.8", 1_000, 100),
+                named,
+                packet("8.8.8.8", "10.0.0.2", 4_000, 100),
+            ])
+            .unwrap();
+
+        // Fully named rows are passed over, and the named row's addresses
+        // count as named everywhere; `since` drops the oldest.
+        let all = storage.unresolved_rows(0, None, 10).unwrap();
+        assert_eq!((all.rows, all.through_id, all.last_id), (2, 3, 3));
+        assert_eq!(all.addresses, ["10.0.0.2", "8.8.8.8"]);
+        let recent = storage.unresolved_rows(0, Some(2_000), 10).unwrap();
+        assert_eq!((recent.rows, recent.through_id), (1, 3));
+
+        let first = storage.unresolved_rows(0, None, 1).unwrap();
+        assert_eq!((first.rows, first.through_id, first.addresses.len()), (1, 1, 1));
+        let names = [("8.8.8.8".to_string(), "dns.google".to_string())];
+        assert_eq!(storage.fill_hostnames(first.through_id, &names).unwrap(), 1);
+        assert_eq!(storage.load_state(BACKFILL_POSITION_KEY).unwrap().as_deref(), Some("1"));
+        let rows = storage.query_packets(&PacketFilter::default(), 10).unwrap();
+        // Every row with the address shows it, in the batch or not.
+        assert_eq!(rows[2].packet.dst_hostname.as_deref(), Some("dns.google"));
+        assert_eq!(rows[0].packet.src_hostname.as_deref(), Some("dns.google"));
+        assert_eq!(storage.fill_hostnames(first.through_id, &names).unwrap(), 0);
+
+        let rest = storage.unresolved_rows(1, None, 10).unwrap();
+        assert_eq!((rest.rows, rest.addresses.as_slice()), (1, &["10.0.0.2".to_string()][..]));
+        let names = [("10.0.0.2".to_string(), "nas.lan".to_string())];
+        assert_eq!(storage.fill_hostnames(rest.through_id, &names).unwrap(), 1);
+        assert_eq!(storage.unresolved_rows(0, None, 10).unwrap().rows, 0);
+    }
+
+    #[test]
+    fn test_reads_bypass_writer_and_wal_checkpoints() {
+        let path = temp_db("wal");
+        let sqlite = SqliteConfig {
+            wal_autocheckpoint_pages: 0,
+            ..SqliteConfig::default()
+        };
+        let storage = Storage::open(&path, &sqlite).unwrap();
+        let mut batch: Vec<PacketMetadata> =
+            (0..500).map(|i| packet("10.0.0.1", "8.8.8.8", i, 100)).collect();
+        storage.flush(&mut batch).unwrap();
+
+        // Queries use their own connection and do not wait for the writer.
+        let writer = storage.conn.lock().unwrap();
+        assert_eq!(storage.query_packets(&PacketFilter::default(), 1000).unwrap().len(), 500);
+        drop(writer);
+
+        let wal = storage.wal_size().unwrap();
+        assert!(wal > 0);
+        assert_eq!(storage.checkpoint_wal(wal).unwrap(), None);
+        let checkpoint = storage.checkpoint_wal(0).unwrap().unwrap();
+        assert!(checkpoint.complete);
+        assert_eq!(checkpoint.wal_bytes, wal);
+        assert_eq!(storage.wal_size(), Some(0));
+        assert_eq!(storage.metrics.wal_checkpoints.get(), 1);
+        assert_eq!(storage.query_history(1000).unwrap().len(), 500);
+
+        // In memory there is no WAL and a single shared connection.
+        let memory = Storage::new(":memory:").unwrap();
+        assert!(Arc::ptr_eq(&memory.conn, &memory.reader));
+        assert_eq!(memory.checkpoint_wal(0).unwrap(), None);
+
+        drop(storage);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path, suffix));
+        }
+    }
+
+    #[test]
+    fn test_snapshot_copies_database_and_cleans_up() {
+        let path = temp_db("snapshot");
+        let dir = std::env::temp_dir().join(format!("ayaflow-snapshots-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        let stale = dir.join(format!("{}crashed.db", SNAPSHOT_PREFIX));
+        let fresh = dir.join(format!("{}running.db", SNAPSHOT_PREFIX));
+        for file in [&stale, &fresh] {
+            std::fs::write(file, b"partial").unwrap();
+        }
+        let old = std::time::SystemTime::now() - SNAPSHOT_STALE_AFTER * 2;
+        std::fs::File::options().write(true).open(&stale).unwrap().set_modified(old).unwrap();
+
+        let sqlite = SqliteConfig {
+            snapshot_dir: Some(dir.to_string_lossy().into_owned()),
+            snapshot_min_free_mb: 0,
+            ..SqliteConfig::default()
+        };
+        let storage = Storage::open(&path, &sqlite).unwrap();
+        assert!(!stale.exists() && fresh.exists());
+        let mut batch: Vec<PacketMetadata> =
+            (0..50).map(|i| packet("10.0.0.1", "8.8.8.8", i, 100)).collect();
+        storage.flush(&mut batch).unwrap();
+
+        let mut snapshot = storage.snapshot().unwrap();
+        // Only the file left by another export remains; the copy is unlinked.
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        let mut bytes = Vec::new();
+        std::io::Read::read_to_end(&mut snapshot.file, &mut bytes).unwrap();
+        assert_eq!(bytes.len() as u64, snapshot.bytes);
+        let copy = dir.join("copy.db");
+        std::fs::write(&copy, &bytes).unwrap();
+        let copied = Storage::open_read_only(copy.to_str().unwrap()).unwrap();
+        assert_eq!(copied.query_history(1000).unwrap().len(), 50);
+
+        let cramped = SqliteConfig { snapshot_min_free_mb: u64::MAX, ..sqlite };
+        let err = Storage::open(&path, &cramped).unwrap().snapshot().unwrap_err().to_string();
+        assert!(err.contains("MiB free"), "{}", err);
+        let memory = Storage::new(":memory:").unwrap();
+        assert!(matches!(memory.snapshot(), Err(StorageError::Snapshot(_))));
+
+        drop(storage);
+        let _ = std::fs::remove_dir_all(&dir);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path, suffix));
+        }
+    }
+
+    #[test]
+    fn test_read_only_database_spills_and_replays() {
+        let path = temp_db("spill");
+        let _ = std::fs::remove_file(format!("{}.spill.jsonl", path));
+        let storage = Storage::open(&path, &SqliteConfig::default()).unwrap();
+        let read_only = Connection::open_with_flags(&path, OpenFlags::SQLITE_OPEN_READ_ONLY);
+        *storage.conn.lock().unwrap() = read_only.unwrap();
+
+        let mut buffer: Vec<PacketMetadata> =
+            (0..10).map(|i| packet("10.0.0.1", "8.8.8.8", i, 100)).collect();
+        for _ in 1..SPILL_AFTER_FAILURES {
+            assert!(storage.write_packets(&mut buffer).is_err());
+            assert_eq!(buffer.len(), 10);
+        }
+        assert_eq!(storage.spill_bytes(), 0);
+        assert!(storage.write_packets(&mut buffer).is_err());
+        assert!(buffer.is_empty());
+        assert!(storage.spill_bytes() > 0);
+
+        // Swept buckets have no buffer to wait in.
+        let swept = AggregatedBucket::from_packet(&packet("10.0.0.2", "1.1.1.1", 20, 60));
+        let result = storage.write_swept(vec![swept]);
+        let metrics = &storage.metrics;
+        assert_eq!(metrics.spilled_rows.get(), 11);
+        assert_eq!(metrics.transaction_failures.get(), 4);
+        assert_eq!(metrics.insert_failures.get(), 0);
+
+        let registry = Arc::new(crate::health::HealthRegistry::new());
+        let heartbeat = registry.register("storage_writer", true, None);
+        storage.report_write(&heartbeat, &result);
+        let error = registry.report().1[0].last_error.clone().unwrap();
+        assert!(error.contains("bytes spilled"), "{}", error);
+
+        // The next successful write brings the spilled rows back.
+        storage.reopen().unwrap();
+        let mut buffer = vec![packet("10.0.0.3", "8.8.8.8", 30, 100)];
+        storage.write_packets(&mut buffer).unwrap();
+        assert_eq!(metrics.replayed_rows.get(), 11);
+        assert_eq!(storage.spill_bytes(), 0);
+        assert_eq!(metrics.spill_size_bytes.get(), 0);
+        assert_eq!(storage.query_history(100).unwrap().len(), 12);
+
+        drop(storage);
+        for suffix in ["", "-wal", "-shm", ".spill.jsonl"] {
+            let _ = std::fs::remove_file(format!("{}{}", path, suffix));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_supervisor_restarts_panicked_writer() {
+        let path = temp_db("supervise");
+        let storage = Arc::new(Storage::open(&path, &SqliteConfig::default()).unwrap());
+        let registry = Arc::new(crate::health::HealthRegistry::new());
+        let heartbeat = registry.register("storage_writer", true, None);
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        let backend: Arc<dyn StorageBackend> = storage.clone();
+        tokio::spawn(supervise_writer(backend, rx, 0, heartbeat));
+
+        // The writer's next flush panics on the poisoned lock.
+        let conn = storage.conn.clone();
+        let poisoner = std::thread::spawn(move || {
+            let _conn = conn.lock().unwrap();
+            panic!("poisoning the writer connection");
+        });
+        assert!(poisoner.join().is_err());
+        let send = |timestamp: i64| {
+            let packets = vec![packet("10.0.0.1", "8.8.8.8", timestamp, 100)];
+            tx.send(StorageEvent::Packets(packets))
+        };
+        send(1).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        assert_eq!(storage.metrics.writer_restarts.get(), 1);
+        let error = registry.report().1[0].last_error.clone().unwrap();
+        assert!(error.contains("writer panicked"), "{}", error);
+
+        // After the backoff the writer runs again on fresh connections.
+        send(2).await.unwrap();
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        let stored = storage.query_history(10).unwrap();
+        assert_eq!(stored.iter().map(|row| row.packet.timestamp).collect::<Vec<_>>(), vec![2]);
+        assert_eq!(registry.report().0, crate::health::ComponentStatus::Ok);
+
+        drop(storage);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path, suffix));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_flush_limits() {
+        // Rows committed after each of three two-packet events, all well
+        // before the flush interval comes round.
+        async fn stored_after_each_event(flush_max_rows: usize) -> Vec<usize> {
+            let flush = StorageConfig {
+                flush_max_rows,
+                flush_interval_ms: 60_000,
+                ..StorageConfig::default()
+            };
+            let storage = Arc::new(Storage::new(":memory:").unwrap().with_storage_config(flush));
+            let registry = Arc::new(crate::health::HealthRegistry::new());
+            let heartbeat = registry.register("storage_writer", true, None);
+            let (tx, rx) = tokio::sync::mpsc::channel(16);
+            let backend: Arc<dyn StorageBackend> = storage.clone();
+            tokio::spawn(supervise_writer(backend, rx, 0, heartbeat));
+            // Let the ticker's immediate first tick pass on an empty buffer.
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            let mut stored = Vec::new();
+            for timestamp in 0..3 {
+                let packets = vec![packet("10.0.0.1", "8.8.8.8", timestamp, 100); 2];
+                tx.send(StorageEvent::Packets(packets)).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                stored.push(storage.query_history(100).unwrap().len());
+            }
+            stored
+        }
+
+        assert_eq!(stored_after_each_event(2).await, [2, 4, 6]);
+        assert_eq!(stored_after_each_event(4).await, [0, 4, 4]);
+        assert_eq!(stored_after_each_event(1000).await, [0, 0, 0]);
+    }
+
+    #[test]
+    fn test_host_pair_port_drops_ephemeral_ports() {
+        let storage = Storage::new(":memory:")
+            .unwrap()
+            .with_aggregation_key(AggregationKey::HostPairPort);
+        let mut buckets = Buckets::new();
+        for (src_port, length) in [(40000, 100), (40001, 200), (40002, 300)] {
+            let p = PacketMetadata { src_port, ..packet("10.0.0.1", "8.8.8.8", 1_000, length) };
+            storage.aggregate(&mut buckets, &p, 10_000, i64::MIN);
+        }
+        storage.flush_aggregated(&mut buckets, 0).unwrap();
+
+        let conn = storage.conn.lock().unwrap();
+        let row: (u16, u16, i64, String) = conn
+            .query_row(
+                "SELECT src_port, dst_port, length, aggregation FROM packets",
+                [],
+                |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)),
+            )
+            .unwrap();
+        assert_eq!(row, (0, 443, 600, "host_pair_port".to_string()));
+        drop(conn);
+
+        // Hostnames resolved mid-window reach the hostnames table.
+        let late = PacketMetadata {
+            dst_hostname: Some("dns.google".into()),
+            ..packet("10.0.0.1", "8.8.8.8", 11_000, 100)
+        };
+        storage.aggregate(&mut buckets, &packet("10.0.0.1", "8.8.8.8", 10_500, 100), 10_000, 0);
+        storage.aggregate(&mut buckets, &late, 10_000, 0);
+        storage.flush_aggregated(&mut buckets, 10_000).unwrap();
+        let conn = storage.conn.lock().unwrap();
+        let names: (Option<String>, Option<String>) = conn
+            .query_row(
+                "SELECT hs.hostname, hd.hostname FROM packets p
+                 LEFT JOIN hostnames hs ON hs.ip = p.src_ip
+                 LEFT JOIN hostnames hd ON hd.ip = p.dst_ip
+                 WHERE p.timestamp = 10500",
+                [],
+                |r| Ok((r.get(0)?, r.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(names, (None, Some("dns.google".to_string())));
+        conn.execute("DELETE FROM packets WHERE timestamp = 10500", []).unwrap();
+        drop(conn);
+
+        let mut buffer = vec![packet("10.0.0.1", "8.8.8.8", 20_000, 60)];
+        storage.flush(&mut buffer).unwrap();
+        let rows = storage.query_history(10).unwrap();
+        assert_eq!((rows[0].kind, rows[0].packet_count), (RowKind::Raw, 1));
+        assert_eq!((rows[1].kind, rows[1].packet_count), (RowKind::Aggregated, 3));
+
+        let json = serde_json::to_value(&rows[1]).unwrap();
+        assert_eq!(json["kind"], "aggregated");
+        assert_eq!((json["packet_count"].as_u64(), json["length"].as_u64()), (Some(3), Some(600)));
+        let labeled = HistoryRow {
+            packet: PacketMetadata {
+                ttl: Some(64),
+                dscp: Some(46),
+                dscp_class: Some("EF".into()),
+                icmp_type: Some(3),
+                icmp_code: Some(4),
+                icmp_name: Some("dest-unreachable/fragmentation-needed".into()),
+                src_hostname: Some("laptop".into()),
+                dst_hostname: Some("dns.google".into()),
+                domain: Some("example.com".into()),
+                country: Some("US".into()),
+                asn: Some(15169),
+                service: Some("https".into()),
+                flow_direction: Some(FlowDirection::Outbound),
+                cast: Some(crate::locality::Cast::Unicast),
+                src_mac: Some("aa:bb:cc:dd:ee:ff".into()),
+                dst_mac: Some("00:11:22:33:44:55".into()),
+                ..rows[0].packet.clone()
+            },
+            service_port: Some(443),
+            ..rows[0].clone()
+        };
+        crate::openapi::assert_matches_schema(&labeled);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_aggregated_flush_aligns_to_wall_clock() {
+        const MINUTE: i64 = 60_000;
+        let base = 1_700_000_040_000; // on a minute boundary
+        assert_eq!(window_bounds(base + 59_999, MINUTE), (base, base + MINUTE));
+        assert_eq!(window_bounds(base + MINUTE, MINUTE).0, base + MINUTE);
+
+        let storage = Arc::new(Storage::new(":memory:").unwrap());
+        let clock = WallClock::Mock {
+            epoch_ms: base + 37_000,
+            started: Instant::now(),
+        };
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        let registry = Arc::new(crate::health::HealthRegistry::new());
+        let heartbeat = registry.register("storage_writer", true, None);
+        let writer = storage.clone();
+        tokio::spawn(async move {
+            let mut rx = rx;
+            writer
+                .run_writer_aggregated(&mut rx, Duration::from_secs(60), &heartbeat, clock)
+                .await
+        });
+
+        let send = |timestamp: i64, length: usize| {
+            let packets = vec![packet("10.0.0.1", "8.8.8.8", timestamp, length)];
+            tx.send(StorageEvent::Packets(packets))
+        };
+        let settle = || async {
+            for _ in 0..10 {
+                tokio::task::yield_now().await;
+            }
+        };
+        let rows = |storage: &Storage| -> Vec<(i64, i64, i64, i64)> {
+            let conn = storage.conn.lock().unwrap();
+            let mut stmt = conn
+                .prepare(
+                    "SELECT window_start, window_end, length, timestamp FROM packets
+                     ORDER BY window_start",
+                )
+                .unwrap();
+            stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)))
+                .unwrap()
+                .map(|r| r.unwrap())
+                .collect()
+        };
+
+        // Started at :37, the first window still ends on the minute.
+        settle().await;
+        send(base + 37_100, 100).await.unwrap();
+        send(base + 59_900, 200).await.unwrap();
+        tokio::time::advance(Duration::from_millis(23_100)).await;
+        settle().await;
+        assert!(rows(&storage).is_empty());
+
+        // Arrives after the boundary but before the (late) flush, and must
+        // not be folded into the window that just closed.
+        send(base + 60_100, 400).await.unwrap();
+        tokio::time::advance(Duration::from_millis(500)).await;
+        settle().await;
+        assert_eq!(rows(&storage), vec![(base, base + MINUTE, 300, base + 37_100)]);
+
+        tokio::time::advance(Duration::from_secs(60)).await;
+        settle().await;
+        assert_eq!(
+            rows(&storage)[1],
+            (base + MINUTE, base + 2 * MINUTE, 400, base + 60_100)
+        );
+
+        // Stamped in a window already written, as after the clock stepped
+        // back: it goes into the open window, not a second row for the old.
+        send(base + 1_000, 500).await.unwrap();
+        tokio::time::advance(Duration::from_secs(60)).await;
+        settle().await;
+        let rows = rows(&storage);
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[2], (base + 2 * MINUTE, base + 3 * MINUTE, 500, base + 1_000));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_aggregated_flush_goes_on_while_the_clock_holds() {
+        const MINUTE: i64 = 60_000;
+        let base = 1_700_000_040_000;
+        let storage = Arc::new(Storage::new(":memory:").unwrap());
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        let registry = Arc::new(crate::health::HealthRegistry::new());
+        let heartbeat = registry.register("storage_writer", true, None);
+        let writer = storage.clone();
+        tokio::spawn(async move {
+            let mut rx = rx;
+            let (window, clock) = (Duration::from_secs(60), WallClock::Held(base + 10_000));
+            writer.run_writer_aggregated(&mut rx, window, &heartbeat, clock).await
+        });
+        let windows = |storage: &Storage| -> Vec<(i64, i64, i64)> {
+            let conn = storage.conn.lock().unwrap();
+            let mut stmt = conn
+                .prepare("SELECT window_start, window_end, length FROM packets ORDER BY id")
+                .unwrap();
+            stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))
+                .unwrap()
+                .map(|r| r.unwrap())
+                .collect()
+        };
+
+        // The ticks come every window on the monotonic clock, and each
+        // writes the open window, which stays the same while the clock
+        // does not move.
+        for (i, length) in [100, 200, 300].into_iter().enumerate() {
+            let packets = vec![packet("10.0.0.1", "8.8.8.8", base + 10_000, length)];
+            tx.send(StorageEvent::Packets(packets)).await.unwrap();
+            let wait = if i == 0 { 50_600 } else { 60_000 };
+            tokio::time::sleep(Duration::from_millis(wait)).await;
+            assert_eq!(windows(&storage).len(), i + 1);
+        }
+        let rows = windows(&storage);
+        assert!(rows.iter().all(|&(start, end, _)| (start, end) == (base, base + MINUTE)));
+        assert_eq!(rows.iter().map(|r| r.2).collect::<Vec<_>>(), [100, 200, 300]);
+    }
 }
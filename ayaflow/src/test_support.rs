@@ -0,0 +1,66 @@
+//! Fixtures shared by the unit tests.  Each returns one fixed value; a test
+//! names only the fields it is about and takes the rest from here, as in
+//! `PacketMetadata { dst_port: 53, ..packet() }`.
+
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::api::{AppState, CaptureState};
+use crate::health::HealthRegistry;
+use crate::state::{PacketMetadata, TrafficState};
+use crate::storage::Storage;
+
+/// A 60-byte TCP packet from 10.0.0.2:40000 to 10.0.0.1:443, seen ingress
+/// on eth0 at time 0, with nothing optional set.
+pub fn packet() -> PacketMetadata {
+    PacketMetadata {
+        timestamp: 0,
+        src_ip: "10.0.0.2".into(),
+        dst_ip: "10.0.0.1".into(),
+        src_port: 40000,
+        dst_port: 443,
+        protocol: "TCP".into(),
+        length: 60,
+        payload_length: 0,
+        direction: "ingress".into(),
+        interface: "eth0".into(),
+        flow_direction: None,
+        cast: None,
+        src_mac: None,
+        dst_mac: None,
+        ttl: None,
+        dscp: None,
+        dscp_class: None,
+        icmp_type: None,
+        icmp_code: None,
+        icmp_name: None,
+        src_hostname: None,
+        dst_hostname: None,
+        domain: None,
+        country: None,
+        asn: None,
+        service: None,
+    }
+}
+
+/// A capturing agent's state with no traffic, an in-memory database and
+/// every other part at its default.
+pub fn app_state() -> AppState {
+    AppState {
+        traffic: Arc::new(TrafficState::new()),
+        storage: Arc::new(Storage::new(":memory:").unwrap()),
+        health: Arc::new(HealthRegistry::new()),
+        start_time: Instant::now(),
+        config: Arc::default(),
+        services: Arc::default(),
+        devices: Arc::default(),
+        capture: CaptureState::Enabled,
+        diagnostics: Arc::default(),
+        blocklist: Arc::default(),
+        backfill: Arc::default(),
+        version: Arc::default(),
+        fleet: Arc::default(),
+        secondary: None,
+        connection_snapshots: Arc::default(),
+    }
+}
@@ -0,0 +1,110 @@
+//! Serving the API on a Unix domain socket.
+//!
+//! `axum::serve` only accepts TCP listeners, so connections are accepted
+//! here and handed to hyper directly.  Requests carry no `ConnectInfo`, so
+//! the IP allowlist lets them through; the socket's file mode is the access
+//! control.
+
+use anyhow::Context;
+use axum::Router;
+use hyper_util::rt::TokioIo;
+use hyper_util::service::TowerToHyperService;
+use std::fs;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::Path;
+use tokio::net::UnixListener;
+use tokio::time::Duration;
+
+/// Bind `path` and set its permission bits to `mode`.  A socket left behind
+/// by a previous run is replaced; a live socket or any other file is not.
+pub fn bind(path: &Path, mode: u32) -> anyhow::Result<UnixListener> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => {
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                anyhow::bail!("failed to bind API to {}: socket is in use", path.display());
+            }
+            fs::remove_file(path)
+                .with_context(|| format!("cannot remove stale socket {}", path.display()))?;
+        }
+        Ok(_) => anyhow::bail!(
+            "failed to bind API to {}: file exists and is not a socket",
+            path.display()
+        ),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).with_context(|| format!("cannot inspect {}", path.display())),
+    }
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("failed to bind API to {}", path.display()))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+        .with_context(|| format!("cannot set permissions on {}", path.display()))?;
+    Ok(listener)
+}
+
+/// Serve `app` on every accepted connection, WebSocket upgrades included.
+/// Only returns if the listener itself fails.
+pub async fn serve(listener: UnixListener, app: Router) -> std::io::Result<()> {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                // Typically EMFILE; back off like `axum::serve` does.
+                tracing::warn!("Unix socket accept failed: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let service = TowerToHyperService::new(app.clone());
+        tokio::spawn(async move {
+            let conn = hyper::server::conn::http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .with_upgrades();
+            if let Err(e) = conn.await {
+                tracing::debug!("Unix socket connection ended with error: {}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api;
+    use crate::config::ApiConfig;
+    use crate::test_support;
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_serves_api_over_socket() {
+        let dir = std::env::temp_dir().join(format!("ayaflow-sock-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("api.sock");
+
+        // A stale socket from an earlier run is replaced; a regular file is not.
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let listener = bind(&path, 0o600).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(bind(&path, 0o600).is_err(), "live socket must not be replaced");
+        let file = dir.join("not-a-socket");
+        fs::write(&file, "").unwrap();
+        assert!(bind(&file, 0o600).is_err());
+
+        // The allowlist has no client address to check and lets requests through.
+        let state = Arc::new(test_support::app_state());
+        let app = api::router(state, &["10.0.0.0/8".to_string()], false, &ApiConfig::default());
+        tokio::spawn(serve(listener, app));
+
+        let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(b"GET /api/health HTTP/1.1\r\nHost: ayaflow\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.contains("\"status\":\"ok\""), "{}", response);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,190 @@
+//! What this agent is running: build, eBPF object, kernel and attachment.
+//!
+//! Gathered once at startup, logged, and served by `GET /api/version` so a
+//! fleet can be audited box by box.  The eBPF object hash covers the bytes
+//! embedded in the binary, so two agents with the same hash load the same
+//! programs whatever their crate version says.
+
+use std::fs;
+use std::path::Path;
+
+pub use ayaflow_common::api::{AttachStatus, LoadedMap, LoadedObject, LoadedProgram, VersionInfo};
+
+pub fn gather(ebpf_object: &[u8], attach: AttachStatus, loaded: LoadedObject) -> VersionInfo {
+    let kernel_release = fs::read_to_string("/proc/sys/kernel/osrelease")
+        .ok()
+        .map(|release| release.trim().to_string());
+    let mut interfaces: Vec<String> = fs::read_dir("/sys/class/net")
+        .map(|dir| {
+            dir.filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+                .collect()
+        })
+        .unwrap_or_default();
+    interfaces.sort();
+    VersionInfo {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        git_commit: option_env!("AYAFLOW_GIT_COMMIT").map(str::to_string),
+        aya_version: option_env!("AYAFLOW_AYA_VERSION").map(str::to_string),
+        ebpf_sha256: sha256_hex(ebpf_object),
+        ebpf_abi_hash: ayaflow_common::embedded_abi_hash(ebpf_object)
+            .map(|hash| format!("{:08x}", hash)),
+        kernel_release,
+        attach,
+        interfaces,
+        btf_available: Path::new("/sys/kernel/btf/vmlinux").exists(),
+        loaded,
+    }
+}
+
+/// The startup log block.
+pub fn log(info: &VersionInfo) {
+    let unknown = || "unknown".to_string();
+    tracing::info!(
+        "ayaflow {} (commit {}), aya {}, kernel {}",
+        info.version,
+        info.git_commit.clone().unwrap_or_else(unknown),
+        info.aya_version.clone().unwrap_or_else(unknown),
+        info.kernel_release.clone().unwrap_or_else(unknown),
+    );
+    tracing::info!(
+        "eBPF object sha256 {} (layout {})",
+        info.ebpf_sha256,
+        info.ebpf_abi_hash.clone().unwrap_or_else(unknown),
+    );
+    if !info.attach.interface.is_empty() {
+        tracing::info!(
+            "Attached to {} ({}); host interfaces: {}",
+            info.attach.interface,
+            info.attach.hooks.join(", "),
+            info.interfaces.join(", "),
+        );
+    }
+    let programs: Vec<String> = info
+        .loaded
+        .programs
+        .iter()
+        .map(|program| match program.verified_instructions {
+            Some(instructions) => format!("{} ({} insns)", program.name, instructions),
+            None => program.name.clone(),
+        })
+        .collect();
+    if !programs.is_empty() {
+        tracing::info!(
+            "Loaded {}, {} maps; kernel BTF {}",
+            programs.join(", "),
+            info.loaded.maps.len(),
+            match info.btf_available {
+                true => "available",
+                false => "unavailable",
+            },
+        );
+    }
+}
+
+/// Every program and map on its own line, for `--verbose-bpf-load`.
+pub fn log_load_details(info: &VersionInfo) {
+    for program in &info.loaded.programs {
+        tracing::info!(
+            "Program {}: {} verified insns, {} bytes translated, {} bytes jited",
+            program.name,
+            display_or_unknown(program.verified_instructions),
+            display_or_unknown(program.translated_bytes),
+            program.jited_bytes,
+        );
+    }
+    for map in &info.loaded.maps {
+        tracing::info!(
+            "Map {}: {} entries of {}-byte keys and {}-byte values",
+            map.name,
+            map.max_entries,
+            map.key_size,
+            map.value_size,
+        );
+    }
+}
+
+
+fn display_or_unknown(value: Option<u32>) -> String {
+    value.map_or_else(|| "unknown".to_string(), |value| value.to_string())
+}
+
+const ROUND_CONSTANTS: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// SHA-256 (FIPS 180-4) of `data` as lowercase hex.  Run once at startup
+/// over the eBPF object, so simple beats fast.
+pub fn sha256_hex(data: &[u8]) -> String {
+    let mut state: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+        0x5be0cd19,
+    ];
+    // The message, a one bit, zeros up to 56 mod 64, then the bit length.
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+
+    for block in message.chunks_exact(64) {
+        let mut w = [0u32; 64];
+        for (word, bytes) in w.iter_mut().zip(block.chunks_exact(4)) {
+            *word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = state;
+        for (k, w) in ROUND_CONSTANTS.iter().zip(w) {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let choice = (e & f) ^ (!e & g);
+            let t1 = h.wrapping_add(s1).wrapping_add(choice).wrapping_add(*k).wrapping_add(w);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let majority = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(majority);
+            (h, g, f, e) = (g, f, e, d.wrapping_add(t1));
+            (d, c, b, a) = (c, b, a, t1.wrapping_add(t2));
+        }
+        for (word, add) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *word = word.wrapping_add(add);
+        }
+    }
+    state.iter().map(|word| format!("{:08x}", word)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sha256_vectors() {
+        assert_eq!(
+            sha256_hex(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            sha256_hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        // Two blocks once padded.
+        assert_eq!(
+            sha256_hex(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+        let million = vec![b'a'; 1_000_000];
+        assert_eq!(
+            sha256_hex(&million),
+            "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"
+        );
+    }
+}
@@ -1,12 +1,26 @@
+use crate::config::CaptureConfig;
+use crate::sniffer::CaptureMode;
 use crate::state::TrafficState;
-use crate::storage::Storage;
+use crate::storage::{HistoryRow, Storage};
 use axum::{
-    extract::{Query, State, WebSocketUpgrade, ws::{Message, WebSocket}},
-    response::IntoResponse,
+    extract::{
+        rejection::QueryRejection, ConnectInfo, Query, State, WebSocketUpgrade,
+        ws::{Message, WebSocket},
+    },
+    http::StatusCode,
+    middleware,
+    response::{IntoResponse, Response},
     routing::get,
     Json, Router,
 };
+use ipnet::IpNet;
+use prometheus_client::encoding::text::encode;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::registry::Registry;
 use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::Instant;
 
@@ -14,6 +28,143 @@ pub struct AppState {
     pub traffic: Arc<TrafficState>,
     pub storage: Arc<Storage>,
     pub start_time: Instant,
+    /// The device being captured on.
+    pub interface: String,
+    pub capture: CaptureConfig,
+    pub capture_state: CaptureState,
+}
+
+/// Whether this process captures traffic, as `/api/health` reports it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CaptureState {
+    Enabled,
+    /// API-only mode: live counters stay at zero.
+    Disabled,
+}
+
+// ── Prometheus Metrics ────────────────────────────────────────────────────────
+
+/// Exported counter fed from a running total, incremented by its growth
+/// since the last scrape.
+#[derive(Default)]
+struct SyncedCounter {
+    counter: Counter,
+    last_seen: AtomicU64,
+}
+
+impl SyncedCounter {
+    fn sync(&self, total: u64) {
+        let last = self.last_seen.swap(total, Ordering::Relaxed);
+        self.counter.inc_by(total.saturating_sub(last));
+    }
+}
+
+struct Metrics {
+    registry: Registry,
+    packets_total: SyncedCounter,
+    bytes_total: SyncedCounter,
+    active_connections: Gauge,
+    pcap_received_total: SyncedCounter,
+    pcap_dropped_total: SyncedCounter,
+    pcap_if_dropped_total: SyncedCounter,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let mut registry = Registry::default();
+        let packets_total = SyncedCounter::default();
+        let bytes_total = SyncedCounter::default();
+        let active_connections = Gauge::default();
+        let pcap_received_total = SyncedCounter::default();
+        let pcap_dropped_total = SyncedCounter::default();
+        let pcap_if_dropped_total = SyncedCounter::default();
+
+        registry.register(
+            "ayaflow_packets",
+            "Total number of observed packets",
+            packets_total.counter.clone(),
+        );
+        registry.register(
+            "ayaflow_bytes",
+            "Total bytes observed",
+            bytes_total.counter.clone(),
+        );
+        registry.register(
+            "ayaflow_active_connections",
+            "Currently active connections",
+            active_connections.clone(),
+        );
+        registry.register(
+            "ayaflow_pcap_received_packets",
+            "Packets received by the pcap capture, including dropped ones",
+            pcap_received_total.counter.clone(),
+        );
+        registry.register(
+            "ayaflow_pcap_dropped_packets",
+            "Packets pcap dropped because its buffer was full",
+            pcap_dropped_total.counter.clone(),
+        );
+        registry.register(
+            "ayaflow_pcap_if_dropped_packets",
+            "Packets dropped by the interface or driver, as reported by pcap",
+            pcap_if_dropped_total.counter.clone(),
+        );
+
+        Self {
+            registry,
+            packets_total,
+            bytes_total,
+            active_connections,
+            pcap_received_total,
+            pcap_dropped_total,
+            pcap_if_dropped_total,
+        }
+    }
+}
+
+/// Error returned by API handlers, rendered as
+/// `{ "error": { "code", "message" } }` with a matching status code.
+#[derive(Debug)]
+pub enum ApiError {
+    BadRequest(String),
+    /// Client address not in the allowlist.
+    Forbidden,
+    NotFound(String),
+    Storage(rusqlite::Error),
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let (status, code, message) = match self {
+            ApiError::BadRequest(msg) => (StatusCode::BAD_REQUEST, "bad_request", msg),
+            ApiError::Forbidden => (
+                StatusCode::FORBIDDEN,
+                "forbidden",
+                "client address is not allowed".to_string(),
+            ),
+            ApiError::NotFound(msg) => (StatusCode::NOT_FOUND, "not_found", msg),
+            ApiError::Storage(e) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, "storage_error", e.to_string())
+            }
+        };
+        let body = Json(serde_json::json!({
+            "error": { "code": code, "message": message },
+        }));
+        (status, body).into_response()
+    }
+}
+
+impl From<rusqlite::Error> for ApiError {
+    fn from(e: rusqlite::Error) -> Self {
+        ApiError::Storage(e)
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(rejection: QueryRejection) -> Self {
+        ApiError::BadRequest(rejection.body_text())
+    }
 }
 
 #[derive(Serialize)]
@@ -21,6 +172,16 @@ pub struct HealthResponse {
     status: String,
     active_connections: usize,
     total_packets: u64,
+    capture: CaptureState,
+    capture_mode: CaptureMode,
+}
+
+/// The effective capture settings.
+#[derive(Serialize)]
+pub struct ConfigResponse {
+    interface: String,
+    capture_mode: CaptureMode,
+    capture: CaptureConfig,
 }
 
 #[derive(Serialize)]
@@ -31,6 +192,10 @@ pub struct StatsResponse {
     active_connections: usize,
     packets_per_second: f64,
     bytes_per_second: f64,
+    /// libpcap's counters as of the last poll (every 10 seconds).
+    ps_recv: u64,
+    ps_drop: u64,
+    ps_ifdrop: u64,
 }
 
 #[derive(Deserialize)]
@@ -38,14 +203,78 @@ pub struct HistoryParams {
     limit: Option<usize>,
 }
 
-pub fn router(state: Arc<AppState>) -> Router {
-    Router::new()
+#[derive(Deserialize)]
+pub struct TotalsParams {
+    /// Epoch ms, inclusive; defaults to the beginning of the history.
+    from: Option<i64>,
+    /// Epoch ms, exclusive; unbounded by default.
+    to: Option<i64>,
+}
+
+/// Stored traffic over a period, scaled up for sampled stretches.
+#[derive(Serialize)]
+pub struct TotalsResponse {
+    rows: u64,
+    bytes: u64,
+}
+
+pub fn router(state: Arc<AppState>, allowed_ips: &[String]) -> Router {
+    let metrics = Arc::new(Metrics::new());
+
+    let mut app = Router::new()
         .route("/api/live", get(get_live_stats))
         .route("/api/history", get(get_history))
+        .route("/api/history/totals", get(get_history_totals))
         .route("/api/health", get(get_health))
+        .route("/api/config", get(get_config))
         .route("/api/stats", get(get_stats))
         .route("/api/stream", get(ws_handler))
-        .with_state(state)
+        .route("/metrics", get({
+            let m = metrics.clone();
+            let s = state.clone();
+            move || get_metrics(s.clone(), m.clone())
+        }))
+        .fallback(not_found);
+
+    // Apply IP allowlist middleware if configured.
+    if !allowed_ips.is_empty() {
+        let nets: Arc<Vec<IpNet>> = Arc::new(
+            allowed_ips
+                .iter()
+                .filter_map(|s| match s.parse::<IpNet>() {
+                    Ok(net) => Some(net),
+                    Err(e) => {
+                        tracing::warn!("Ignoring invalid allowed_ips entry {:?}: {}", s, e);
+                        None
+                    }
+                })
+                .collect(),
+        );
+        app = app.layer(middleware::from_fn(move |req, next| {
+            let nets = nets.clone();
+            ip_allowlist(req, next, nets)
+        }));
+    }
+
+    app.with_state(state)
+}
+
+// ── IP Allowlist Middleware ────────────────────────────────────────────────────
+
+async fn ip_allowlist(
+    req: axum::extract::Request,
+    next: middleware::Next,
+    allowed: Arc<Vec<IpNet>>,
+) -> impl IntoResponse {
+    if let Some(connect_info) = req.extensions().get::<ConnectInfo<SocketAddr>>() {
+        let ip = connect_info.0.ip();
+        if allowed.iter().any(|net| net.contains(&ip)) {
+            return next.run(req).await.into_response();
+        }
+        return ApiError::Forbidden.into_response();
+    }
+    // If there is no ConnectInfo, allow (should not happen with into_make_service_with_connect_info).
+    next.run(req).await.into_response()
 }
 
 async fn get_health(State(state): State<Arc<AppState>>) -> Json<HealthResponse> {
@@ -53,6 +282,16 @@ async fn get_health(State(state): State<Arc<AppState>>) -> Json<HealthResponse>
         status: "ok".to_string(),
         active_connections: state.traffic.active_connections.load(std::sync::atomic::Ordering::Relaxed),
         total_packets: state.traffic.total_packets.load(std::sync::atomic::Ordering::Relaxed),
+        capture: state.capture_state,
+        capture_mode: CaptureMode::from_config(&state.capture),
+    })
+}
+
+async fn get_config(State(state): State<Arc<AppState>>) -> Json<ConfigResponse> {
+    Json(ConfigResponse {
+        interface: state.interface.clone(),
+        capture_mode: CaptureMode::from_config(&state.capture),
+        capture: state.capture.clone(),
     })
 }
 
@@ -81,6 +320,9 @@ async fn get_stats(State(state): State<Arc<AppState>>) -> Json<StatsResponse> {
         active_connections,
         packets_per_second,
         bytes_per_second,
+        ps_recv: state.traffic.pcap_received.load(Ordering::Relaxed),
+        ps_drop: state.traffic.pcap_dropped.load(Ordering::Relaxed),
+        ps_ifdrop: state.traffic.pcap_if_dropped.load(Ordering::Relaxed),
     })
 }
 
@@ -116,13 +358,71 @@ async fn get_live_stats(State(state): State<Arc<AppState>>) -> Json<serde_json::
 
 async fn get_history(
     State(state): State<Arc<AppState>>,
-    Query(params): Query<HistoryParams>,
-) -> Json<serde_json::Value> {
-    let limit = params.limit.unwrap_or(100).min(1000);
-    match state.storage.query_history(limit) {
-        Ok(data) => Json(serde_json::json!(data)),
-        Err(e) => Json(serde_json::json!({ "error": e.to_string() })),
+    params: Result<Query<HistoryParams>, QueryRejection>,
+) -> Result<Json<Vec<HistoryRow>>, ApiError> {
+    let Query(params) = params?;
+    let limit = match params.limit {
+        None => 100,
+        Some(n) if (1..=1000).contains(&n) => n,
+        Some(n) => {
+            return Err(ApiError::BadRequest(format!(
+                "limit must be between 1 and 1000, got {}",
+                n
+            )))
+        }
+    };
+    Ok(Json(state.storage.query_history(limit)?))
+}
+
+async fn get_history_totals(
+    State(state): State<Arc<AppState>>,
+    params: Result<Query<TotalsParams>, QueryRejection>,
+) -> Result<Json<TotalsResponse>, ApiError> {
+    let Query(params) = params?;
+    let from = params.from.unwrap_or(i64::MIN);
+    let to = params.to.unwrap_or(i64::MAX);
+    if from > to {
+        return Err(ApiError::BadRequest(format!(
+            "from ({}) is after to ({})",
+            from, to
+        )));
     }
+    let (rows, bytes) = state.storage.estimated_totals(from, to)?;
+    Ok(Json(TotalsResponse { rows, bytes }))
+}
+
+async fn get_metrics(state: Arc<AppState>, metrics: Arc<Metrics>) -> impl IntoResponse {
+    // prometheus-client Counters are monotonic, so each is incremented by
+    // the growth of its source total since the last scrape.
+    let traffic = &state.traffic;
+    metrics
+        .packets_total
+        .sync(traffic.total_packets.load(Ordering::Relaxed));
+    metrics
+        .bytes_total
+        .sync(traffic.total_bytes.load(Ordering::Relaxed));
+    let active = traffic.active_connections.load(Ordering::Relaxed);
+    metrics.active_connections.set(active as i64);
+    metrics
+        .pcap_received_total
+        .sync(traffic.pcap_received.load(Ordering::Relaxed));
+    metrics
+        .pcap_dropped_total
+        .sync(traffic.pcap_dropped.load(Ordering::Relaxed));
+    metrics
+        .pcap_if_dropped_total
+        .sync(traffic.pcap_if_dropped.load(Ordering::Relaxed));
+
+    let mut buf = String::new();
+    encode(&mut buf, &metrics.registry).unwrap();
+    (
+        [(axum::http::header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        buf,
+    )
+}
+
+async fn not_found(uri: axum::http::Uri) -> ApiError {
+    ApiError::NotFound(format!("no route for {}", uri.path()))
 }
 
 async fn ws_handler(
@@ -150,3 +450,138 @@ async fn handle_socket(mut socket: WebSocket, state: Arc<AppState>) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::Request;
+    use tower::ServiceExt;
+
+    fn test_state() -> Arc<AppState> {
+        Arc::new(AppState {
+            traffic: Arc::new(TrafficState::new()),
+            storage: Arc::new(Storage::new(":memory:").unwrap()),
+            start_time: Instant::now(),
+            interface: "eth0".to_string(),
+            capture: CaptureConfig { promiscuous: false, ..CaptureConfig::default() },
+            capture_state: CaptureState::Enabled,
+        })
+    }
+
+    fn request_from(ip: [u8; 4], uri: &str) -> Request<Body> {
+        let mut req = Request::builder().uri(uri).body(Body::empty()).unwrap();
+        req.extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from((ip, 40000))));
+        req
+    }
+
+    #[tokio::test]
+    async fn test_ip_allowlist() {
+        let allowed = ["10.0.0.0/8".to_string(), "not-a-cidr".to_string()];
+        let app = router(test_state(), &allowed);
+
+        let resp = app
+            .clone()
+            .oneshot(request_from([10, 1, 2, 3], "/api/health"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = app
+            .clone()
+            .oneshot(request_from([192, 168, 1, 1], "/metrics"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body["error"]["code"], "forbidden");
+
+        // Without an allowlist every client gets through.
+        let app = router(test_state(), &[]);
+        let resp = app
+            .oneshot(request_from([192, 168, 1, 1], "/api/health"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_metrics_include_pcap_drops() {
+        let state = test_state();
+        state.traffic.pcap_dropped.store(7, Ordering::Relaxed);
+        let app = router(state.clone(), &[]);
+        let scrape = || async {
+            let resp = app
+                .clone()
+                .oneshot(request_from([127, 0, 0, 1], "/metrics"))
+                .await
+                .unwrap();
+            let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+            String::from_utf8(bytes.to_vec()).unwrap()
+        };
+
+        let text = scrape().await;
+        assert!(text.contains("ayaflow_pcap_dropped_packets_total 7"), "{}", text);
+        assert!(text.contains("ayaflow_pcap_if_dropped_packets_total 0"), "{}", text);
+
+        // Counters follow the cumulative pcap totals.
+        state.traffic.pcap_dropped.store(10, Ordering::Relaxed);
+        let text = scrape().await;
+        assert!(text.contains("ayaflow_pcap_dropped_packets_total 10"), "{}", text);
+    }
+
+    #[tokio::test]
+    async fn test_capture_mode_reported() {
+        let app = router(test_state(), &[]);
+        for uri in ["/api/health", "/api/config"] {
+            let resp = app.clone().oneshot(request_from([127, 0, 0, 1], uri)).await.unwrap();
+            let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            assert_eq!(body["capture_mode"], "host_only", "{}", uri);
+        }
+        let resp = app.oneshot(request_from([127, 0, 0, 1], "/api/config")).await.unwrap();
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body["interface"], "eth0");
+        assert_eq!(body["capture"]["promiscuous"], false);
+        assert_eq!(body["capture"]["snaplen"], 128);
+    }
+
+    #[tokio::test]
+    async fn test_health_reports_capture_state() {
+        let health = |state: Arc<AppState>| async move {
+            let req = request_from([127, 0, 0, 1], "/api/health");
+            let resp = router(state, &[]).oneshot(req).await.unwrap();
+            let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+            serde_json::from_slice::<serde_json::Value>(&bytes).unwrap()
+        };
+        assert_eq!(health(test_state()).await["capture"], "enabled");
+
+        let api_only = AppState {
+            interface: String::new(),
+            capture_state: CaptureState::Disabled,
+            ..Arc::into_inner(test_state()).unwrap()
+        };
+        let body = health(Arc::new(api_only)).await;
+        assert_eq!(body["capture"], "disabled");
+        assert_eq!(body["status"], "ok");
+    }
+
+    #[tokio::test]
+    async fn test_stats_report_capture_counters() {
+        let state = test_state();
+        state.traffic.pcap_received.store(1000, Ordering::Relaxed);
+        state.traffic.pcap_dropped.store(12, Ordering::Relaxed);
+        let resp = router(state, &[])
+            .oneshot(request_from([127, 0, 0, 1], "/api/stats"))
+            .await
+            .unwrap();
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body["ps_recv"], 1000);
+        assert_eq!(body["ps_drop"], 12);
+        assert_eq!(body["ps_ifdrop"], 0);
+    }
+}
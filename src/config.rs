@@ -1,10 +1,33 @@
-use serde::Deserialize;
+use ayaflow_common::config_check::{describe_unknown_field, ConfigError, ConfigProblems};
+use ayaflow_common::filter::{FilterSpec, Filters, IpFilter, PortSet};
+use ayaflow_common::AggregationKey;
+use serde::{Deserialize, Serialize};
 use std::fs;
+use std::net::{IpAddr, Ipv4Addr};
 use std::path::Path;
 
-/// Application configuration, loadable from CLI or YAML file.
+/// Whether the binary captures traffic or only serves a database.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RunMode {
+    /// Open the pcap capture and record traffic (default).
+    #[default]
+    Capture,
+    /// Serve the API over an existing database without opening a capture,
+    /// so no privileges are needed.
+    ApiOnly,
+}
+
+/// Application configuration, loadable from CLI or YAML file.  Unknown keys
+/// are rejected.
 #[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
+    /// `capture` (default) or `api-only`, which skips the capture, the
+    /// storage writer and preflight
+    #[serde(default)]
+    pub mode: RunMode,
+
     /// Network interface to capture on
     #[serde(default)]
     pub interface: Option<String>,
@@ -13,17 +36,23 @@ pub struct Config {
     #[serde(default = "default_port")]
     pub port: u16,
 
+    /// Address the API binds to (default: all interfaces)
+    #[serde(default = "default_listen_addr")]
+    pub listen_addr: IpAddr,
+
     /// Database path
     #[serde(default = "default_db_path")]
     pub db_path: String,
 
-    /// Filter by port (only capture traffic on this port)
+    /// Filter by port (only capture traffic on these ports), a port or a
+    /// list like "80,443,30000-32767"
     #[serde(default)]
-    pub filter_port: Option<u16>,
+    pub filter_port: Option<PortSet>,
 
-    /// Filter by IP (only capture traffic to/from this IP)
-    #[serde(default)]
-    pub filter_ip: Option<String>,
+    /// Filter by IP (only capture traffic to/from these addresses or
+    /// CIDRs, and none to/from those after a "!"); one entry or a list
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub filter_ip: Vec<String>,
 
     /// Filter by protocol (TCP, UDP)
     #[serde(default)]
@@ -51,21 +80,122 @@ pub struct Config {
     #[serde(default = "default_sample_rate")]
     pub sample_rate: u32,
 
+    /// Only these packets reach the live stats and storage (on top of the
+    /// filter_* flags)
+    #[serde(default)]
+    pub capture_filter: FilterSpec,
+
+    /// Only these packets are stored; live stats still see everything
+    /// captured
+    #[serde(default)]
+    pub storage_filter: FilterSpec,
+
     /// Aggregation window in seconds. When > 0, packets are collapsed into
     /// per-connection summary rows covering this time window before writing to the DB.
     /// 0 = disabled (default), store every sampled packet individually.
     #[serde(default = "default_aggregation_window")]
     pub aggregation_window_seconds: u64,
+
+    /// What aggregated rows are keyed on: `connection` (full 5-tuple, default),
+    /// `host_pair` (src_ip, dst_ip, protocol), or `host_pair_port` (host pair
+    /// plus the service port, dropping the client's ephemeral port).
+    #[serde(default)]
+    pub aggregation_key: AggregationKey,
+
+    /// Skip the startup capability and interface checks
+    #[serde(default)]
+    pub skip_preflight: bool,
+
+    /// List of CIDRs allowed to access the API (empty = allow all)
+    #[serde(default)]
+    pub allowed_ips: Vec<String>,
+
+    /// libpcap capture settings
+    #[serde(default)]
+    pub capture: CaptureConfig,
+}
+
+/// How the pcap capture is opened.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CaptureConfig {
+    /// Put the interface in promiscuous mode; disable to see only traffic
+    /// addressed to this host
+    #[serde(default = "default_promiscuous")]
+    pub promiscuous: bool,
+
+    /// Bytes kept per packet. Only headers are parsed, and lengths come from
+    /// the wire length, so the default 128 loses nothing but copy volume.
+    #[serde(default = "default_snaplen")]
+    pub snaplen: u32,
+
+    /// Kernel capture buffer in bytes; larger buffers survive longer bursts
+    /// (None = libpcap's default, typically 2 MiB)
+    #[serde(default)]
+    pub buffer_size: Option<u32>,
+
+    /// Read timeout in milliseconds: how long packets may wait in the
+    /// buffer before being delivered in a batch
+    #[serde(default = "default_capture_timeout_ms")]
+    pub timeout_ms: u32,
+
+    /// Deliver each packet as soon as it arrives instead of in batches, for
+    /// low-latency live views at some CPU cost
+    #[serde(default)]
+    pub immediate: bool,
+}
+
+fn default_promiscuous() -> bool {
+    true
+}
+
+fn default_snaplen() -> u32 {
+    128
+}
+
+fn default_capture_timeout_ms() -> u32 {
+    1000
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        Self {
+            promiscuous: default_promiscuous(),
+            snaplen: default_snaplen(),
+            buffer_size: None,
+            timeout_ms: default_capture_timeout_ms(),
+            immediate: false,
+        }
+    }
 }
 
 fn default_port() -> u16 {
     3000
 }
 
+fn default_listen_addr() -> IpAddr {
+    IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+}
+
 fn default_db_path() -> String {
     "traffic.db".to_string()
 }
 
+/// A list, or the single string older configs have.
+fn one_or_many<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Raw {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match Option::<Raw>::deserialize(deserializer)? {
+        None => Vec::new(),
+        Some(Raw::One(entry)) => vec![entry],
+        Some(Raw::Many(entries)) => entries,
+    })
+}
+
 fn default_connection_timeout() -> u64 {
     60
 }
@@ -85,18 +215,26 @@ fn default_aggregation_window() -> u64 {
 impl Default for Config {
     fn default() -> Self {
         Self {
+            mode: RunMode::default(),
             interface: None,
             port: default_port(),
+            listen_addr: default_listen_addr(),
             db_path: default_db_path(),
             filter_port: None,
-            filter_ip: None,
+            filter_ip: Vec::new(),
             filter_protocol: None,
             connection_timeout: default_connection_timeout(),
             resolve_dns: false,
             quiet: false,
             data_retention_seconds: default_data_retention(),
             sample_rate: default_sample_rate(),
+            capture_filter: FilterSpec::default(),
+            storage_filter: FilterSpec::default(),
             aggregation_window_seconds: default_aggregation_window(),
+            aggregation_key: AggregationKey::default(),
+            skip_preflight: false,
+            allowed_ips: Vec::new(),
+            capture: CaptureConfig::default(),
         }
     }
 }
@@ -104,26 +242,98 @@ impl Default for Config {
 impl Config {
     /// Load config from a YAML file
     pub fn from_file(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
-        let content = fs::read_to_string(path)?;
-        let config: Config = serde_yaml::from_str(&content)?;
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("{}: cannot read config: {}", path.display(), e))?;
+        let config: Config = serde_yaml::from_str(&content).map_err(|e| {
+            format!(
+                "{}: {}",
+                path.display(),
+                describe_unknown_field(&e.to_string())
+            )
+        })?;
         Ok(config)
     }
 
+    /// Check every setting, reporting all problems at once against `file`.
+    pub fn validate(&self, file: Option<&Path>) -> Result<(), ConfigError> {
+        let mut problems = ConfigProblems::default();
+        problems.ensure(self.port > 0, "port", "must be between 1 and 65535");
+        let lowest = self
+            .filter_port
+            .iter()
+            .filter_map(|set| set.ranges().first());
+        problems.ports("filter_port", lowest.map(|&(lo, _)| lo));
+        if let Err(e) = IpFilter::new(&self.filter_ip) {
+            problems.push("filter_ip", e);
+        }
+        if let Some(protocol) = &self.filter_protocol {
+            let known = ["TCP", "UDP", "IPv4", "IPv6"];
+            problems.ensure(
+                known.iter().any(|p| p.eq_ignore_ascii_case(protocol)),
+                "filter_protocol",
+                format!("must be one of TCP, UDP, IPv4, IPv6, got {:?}", protocol),
+            );
+        }
+        problems.ensure(
+            self.connection_timeout > 0,
+            "connection_timeout",
+            "must be at least 1",
+        );
+        problems.ensure(
+            self.sample_rate >= 1,
+            "sample_rate",
+            "must be at least 1 (1 keeps every packet)",
+        );
+        if let Err(e) = Filters::new(&self.capture_filter, &self.storage_filter) {
+            let (field, message) = e.split_once(": ").unwrap_or(("storage_filter", &e));
+            problems.push(field, message);
+        }
+        if let Some(retention) = self.data_retention_seconds {
+            problems.ensure(
+                retention >= self.aggregation_window_seconds,
+                "data_retention_seconds",
+                format!(
+                    "{}s is shorter than aggregation_window_seconds ({}s)",
+                    retention, self.aggregation_window_seconds
+                ),
+            );
+        }
+        problems.cidrs("allowed_ips", &self.allowed_ips);
+        problems.ensure(
+            self.capture.snaplen > 0,
+            "capture.snaplen",
+            "must be at least 1",
+        );
+        if self.db_path != ":memory:" {
+            problems.writable_file("db_path", Path::new(&self.db_path));
+        }
+        if let (Some(interface), false) = (&self.interface, self.skip_preflight) {
+            problems.interface("interface", interface);
+        }
+        problems.into_result(file)
+    }
+
     /// Merge CLI args into config (CLI takes precedence)
     pub fn merge_cli(&mut self, cli: &CliArgs) {
+        if cli.no_capture {
+            self.mode = RunMode::ApiOnly;
+        }
         if cli.interface.is_some() {
             self.interface = cli.interface.clone();
         }
         if cli.port != 3000 {
             self.port = cli.port;
         }
+        if let Some(addr) = cli.listen_addr {
+            self.listen_addr = addr;
+        }
         if cli.db_path != "traffic.db" {
             self.db_path = cli.db_path.clone();
         }
         if cli.filter_port.is_some() {
-            self.filter_port = cli.filter_port;
+            self.filter_port = cli.filter_port.clone();
         }
-        if cli.filter_ip.is_some() {
+        if !cli.filter_ip.is_empty() {
             self.filter_ip = cli.filter_ip.clone();
         }
         if cli.filter_protocol.is_some() {
@@ -147,6 +357,30 @@ impl Config {
         if cli.aggregation_window != 0 {
             self.aggregation_window_seconds = cli.aggregation_window;
         }
+        if let Some(key) = cli.aggregation_key {
+            self.aggregation_key = key;
+        }
+        if cli.skip_preflight {
+            self.skip_preflight = true;
+        }
+        if !cli.allowed_ips.is_empty() {
+            self.allowed_ips = cli.allowed_ips.clone();
+        }
+        if cli.no_promisc {
+            self.capture.promiscuous = false;
+        }
+        if let Some(snaplen) = cli.snaplen {
+            self.capture.snaplen = snaplen;
+        }
+        if cli.buffer_size.is_some() {
+            self.capture.buffer_size = cli.buffer_size;
+        }
+        if let Some(timeout_ms) = cli.capture_timeout_ms {
+            self.capture.timeout_ms = timeout_ms;
+        }
+        if cli.immediate {
+            self.capture.immediate = true;
+        }
     }
 }
 
@@ -164,6 +398,10 @@ pub struct CliArgs {
     #[arg(short, long, default_value_t = 3000)]
     pub port: u16,
 
+    /// Address to bind the API to (e.g., 127.0.0.1)
+    #[arg(long)]
+    pub listen_addr: Option<IpAddr>,
+
     /// Database path
     #[arg(long, default_value = "traffic.db")]
     pub db_path: String,
@@ -172,13 +410,14 @@ pub struct CliArgs {
     #[arg(short, long)]
     pub config: Option<String>,
 
-    /// Filter: only capture traffic on this port
+    /// Filter: only capture traffic on these ports, e.g. 80,443,30000-32767
     #[arg(long)]
-    pub filter_port: Option<u16>,
+    pub filter_port: Option<PortSet>,
 
-    /// Filter: only capture traffic to/from this IP
+    /// Filter: only capture traffic to/from this IP or CIDR, or with a "!"
+    /// none to/from it. Repeat for multiple.
     #[arg(long)]
-    pub filter_ip: Option<String>,
+    pub filter_ip: Vec<String>,
 
     /// Filter: only capture this protocol (TCP, UDP)
     #[arg(long)]
@@ -207,4 +446,200 @@ pub struct CliArgs {
     /// Aggregation window in seconds (0 = disabled, store raw packets)
     #[arg(long, default_value_t = 0)]
     pub aggregation_window: u64,
+
+    /// Aggregated row key: connection, host_pair, or host_pair_port
+    #[arg(long)]
+    pub aggregation_key: Option<AggregationKey>,
+
+    /// Skip the startup capability and interface checks
+    #[arg(long)]
+    pub skip_preflight: bool,
+
+    /// IP CIDRs allowed to access the API (e.g., 10.0.0.0/8). Repeat for multiple.
+    #[arg(long)]
+    pub allowed_ips: Vec<String>,
+
+    /// Do not put the interface in promiscuous mode
+    #[arg(long)]
+    pub no_promisc: bool,
+
+    /// Bytes captured per packet (default 128, enough for the headers)
+    #[arg(long)]
+    pub snaplen: Option<u32>,
+
+    /// Kernel capture buffer size in bytes
+    #[arg(long)]
+    pub buffer_size: Option<u32>,
+
+    /// Capture read timeout in milliseconds (default 1000)
+    #[arg(long)]
+    pub capture_timeout_ms: Option<u32>,
+
+    /// Deliver packets immediately instead of in batches
+    #[arg(long)]
+    pub immediate: bool,
+
+    /// Serve the API over an existing database without capturing
+    #[arg(long)]
+    pub no_capture: bool,
+
+    /// Validate the configuration and exit (0 when valid, 1 otherwise)
+    #[arg(long)]
+    pub check_config: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allowed_ips() {
+        assert!(Config::default().allowed_ips.is_empty());
+        let mut config: Config =
+            serde_yaml::from_str("allowed_ips:\n  - 10.0.0.0/8\n  - 192.168.1.5/32\n").unwrap();
+        assert_eq!(config.allowed_ips, vec!["10.0.0.0/8", "192.168.1.5/32"]);
+
+        // Flags replace the file's list rather than extending it.
+        let cli = CliArgs::try_parse_from([
+            "ayaflow",
+            "--allowed-ips",
+            "127.0.0.1/32",
+            "--allowed-ips",
+            "fd00::/8",
+        ])
+        .unwrap();
+        config.merge_cli(&cli);
+        assert_eq!(config.allowed_ips, vec!["127.0.0.1/32", "fd00::/8"]);
+
+        let cli = CliArgs::try_parse_from(["ayaflow"]).unwrap();
+        config.merge_cli(&cli);
+        assert_eq!(config.allowed_ips.len(), 2);
+    }
+
+    #[test]
+    fn test_api_only_mode() {
+        assert_eq!(Config::default().mode, RunMode::Capture);
+        let config: Config = serde_yaml::from_str("mode: api-only\n").unwrap();
+        assert_eq!(config.mode, RunMode::ApiOnly);
+        assert!(serde_yaml::from_str::<Config>("mode: replay\n").is_err());
+
+        let mut config = Config::default();
+        config.merge_cli(&CliArgs::try_parse_from(["ayaflow", "--no-capture"]).unwrap());
+        assert_eq!(config.mode, RunMode::ApiOnly);
+    }
+
+    #[test]
+    fn test_capture_settings() {
+        let defaults = Config::default().capture;
+        assert!(defaults.promiscuous);
+        assert_eq!(defaults.snaplen, 128);
+        assert_eq!(defaults.buffer_size, None);
+        assert!(!defaults.immediate);
+
+        let mut config: Config =
+            serde_yaml::from_str("capture:\n  promiscuous: false\n  buffer_size: 16777216\n")
+                .unwrap();
+        assert!(!config.capture.promiscuous);
+        assert_eq!(config.capture.buffer_size, Some(16 << 20));
+        // Unset keys in the section keep their defaults.
+        assert_eq!(config.capture.timeout_ms, 1000);
+
+        let cli = CliArgs::try_parse_from([
+            "ayaflow",
+            "--snaplen",
+            "96",
+            "--capture-timeout-ms",
+            "10",
+            "--immediate",
+        ])
+        .unwrap();
+        config.merge_cli(&cli);
+        let expected = CaptureConfig {
+            promiscuous: false,
+            snaplen: 96,
+            buffer_size: Some(16 << 20),
+            timeout_ms: 10,
+            immediate: true,
+        };
+        assert_eq!(config.capture, expected);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(serde_yaml::from_str::<Config>("sample_rte: 10\n").is_err());
+        let config = Config {
+            sample_rate: 0,
+            filter_protocol: Some("sctp".into()),
+            capture_filter: FilterSpec {
+                ports: vec!["http".into()],
+                ..FilterSpec::default()
+            },
+            allowed_ips: vec!["10.0.0.1".into()],
+            ..Config::default()
+        };
+        let error = config
+            .validate(Some(Path::new("lightshark.yaml")))
+            .unwrap_err();
+        let fields: Vec<&str> = error.problems.iter().map(|p| p.field.as_str()).collect();
+        let expected = [
+            "filter_protocol",
+            "sample_rate",
+            "capture_filter.ports",
+            "allowed_ips",
+        ];
+        assert_eq!(fields, expected);
+        assert!(error
+            .to_string()
+            .starts_with("lightshark.yaml: filter_protocol: must be"));
+    }
+
+    #[test]
+    fn test_filter_ip_cidrs() {
+        let config: Config = serde_yaml::from_str("filter_ip: 10.0.0.1\n").unwrap();
+        assert_eq!(config.filter_ip, vec!["10.0.0.1"]);
+        let yaml = "filter_ip: [10.0.0.0/8, \"!10.0.5.0/24\"]\n";
+        let mut config: Config = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(config.filter_ip, vec!["10.0.0.0/8", "!10.0.5.0/24"]);
+        assert!(config.validate(None).is_ok());
+
+        let cli = CliArgs::try_parse_from(["ayaflow", "--filter-ip", "fd00::/8"]).unwrap();
+        config.merge_cli(&cli);
+        assert_eq!(config.filter_ip, vec!["fd00::/8"]);
+
+        config.filter_ip = vec!["10.0.0.0/8".into(), "!10.0.5.0/33".into()];
+        let error = config.validate(None).unwrap_err();
+        assert_eq!(error.problems[0].field, "filter_ip");
+        assert_eq!(
+            error.problems[0].message,
+            "\"!10.0.5.0/33\" is not an address or CIDR"
+        );
+    }
+
+    #[test]
+    fn test_filter_port_lists() {
+        let config: Config = serde_yaml::from_str("filter_port: 443\n").unwrap();
+        assert_eq!(config.filter_port.unwrap().ranges(), [(443, 443)]);
+        let config: Config = serde_yaml::from_str("filter_port: 80,443,30000-32767\n").unwrap();
+        assert_eq!(
+            config.filter_port.unwrap().to_string(),
+            "80,443,30000-32767"
+        );
+        let err = serde_yaml::from_str::<Config>("filter_port: 80,https\n").unwrap_err();
+        assert!(
+            err.to_string().contains("\"https\" is not a port"),
+            "{}",
+            err
+        );
+
+        let cli = CliArgs::try_parse_from(["ayaflow", "--filter-port", "53,8000-8100"]).unwrap();
+        assert_eq!(cli.filter_port.unwrap().ranges(), [(53, 53), (8000, 8100)]);
+        assert!(CliArgs::try_parse_from(["ayaflow", "--filter-port", "80-"]).is_err());
+
+        let config = Config {
+            filter_port: Some("0-1024".parse().unwrap()),
+            ..Config::default()
+        };
+        let error = config.validate(None).unwrap_err();
+        assert_eq!(error.problems[0].field, "filter_port");
+    }
 }
@@ -8,12 +8,16 @@ use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 mod api;
 mod config;
+mod preflight;
 mod sniffer;
 mod state;
 mod storage;
 
-use config::{CliArgs, Config};
+use api::CaptureState;
+use config::{CliArgs, Config, RunMode};
 use sniffer::FilterConfig;
+use state::TrafficState;
+use storage::Storage;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -28,6 +32,15 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     // CLI args override config file
     config.merge_cli(&cli);
+    let config_file = cli.config.as_deref().map(Path::new);
+    config.validate(config_file)?;
+    if cli.check_config {
+        match config_file {
+            Some(file) => println!("{}: OK", file.display()),
+            None => println!("command line: OK"),
+        }
+        return Ok(());
+    }
 
     // Setup logging based on quiet mode
     if config.quiet {
@@ -44,21 +57,103 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             .init();
     }
 
+    // State & Storage
+    let traffic_state = Arc::new(TrafficState::new());
+    let storage = Arc::new(Storage::new(&config.db_path)?);
+    let capturing = config.mode == RunMode::Capture;
+
+    // Spawn Data Retention Cleanup Task (if enabled).  An API-only instance
+    // usually serves a database another agent writes, which owns
+    // retention, so only an explicit --data-retention applies to it.
+    let retention = match capturing {
+        true => config.data_retention_seconds,
+        false => cli.data_retention,
+    };
+    if config.data_retention_seconds.is_some() && retention.is_none() {
+        tracing::info!("API-only mode: data retention disabled (pass --data-retention to enable)");
+    }
+    if let Some(retention_seconds) = retention {
+        let storage_retention = storage.clone();
+        tokio::spawn(async move {
+            let mut retention_interval = interval(Duration::from_secs(60));
+            loop {
+                retention_interval.tick().await;
+                match storage_retention.delete_old_data(retention_seconds) {
+                    Ok(deleted) if deleted > 0 => {
+                        tracing::info!("Data retention: deleted {} old packets", deleted);
+                    }
+                    Err(e) => {
+                        tracing::error!("Data retention cleanup failed: {}", e);
+                    }
+                    _ => {}
+                }
+            }
+        });
+    }
+
+    let (device, capture_state) = if capturing {
+        let device = start_capture(&config, &traffic_state, &storage)?;
+        (device, CaptureState::Enabled)
+    } else {
+        tracing::info!("API-only mode: serving {} without capturing", config.db_path);
+        (config.interface.clone().unwrap_or_default(), CaptureState::Disabled)
+    };
+
+    // API
+    let app_state = Arc::new(api::AppState {
+        traffic: traffic_state.clone(),
+        storage: storage.clone(),
+        start_time: std::time::Instant::now(),
+        interface: device,
+        capture: config.capture.clone(),
+        capture_state,
+    });
+
+    let app = api::router(app_state, &config.allowed_ips);
+
+    let addr = std::net::SocketAddr::new(config.listen_addr, config.port);
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .map_err(|e| format!("failed to bind API to {}: {}", addr, e))?;
+    tracing::info!("Server running on http://{}", addr);
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Everything capturing needs: preflight, the storage writer, connection
+/// cleanup, the pcap capture and the sniffer thread.  Returns the device
+/// captured on.  API-only mode skips all of it.
+fn start_capture(
+    config: &Config,
+    traffic_state: &Arc<TrafficState>,
+    storage: &Arc<Storage>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    if !config.skip_preflight {
+        preflight::run(config.interface.as_deref(), config.capture.promiscuous)?;
+    }
+
     let running = Arc::new(AtomicBool::new(true));
     let r = running.clone();
 
     // Channels
     let (tx, rx) = mpsc::channel(10000);
 
-    // State & Storage
-    let traffic_state = Arc::new(state::TrafficState::new());
-    let storage = Arc::new(storage::Storage::new(&config.db_path)?);
+    // Mark where stored data starts being sampled at this rate, so history
+    // and totals scale each period by the rate it was captured at.
+    let sample_rate = config.sample_rate.max(1);
+    storage.record_sample_rate(sample_rate, chrono::Utc::now().timestamp_millis())?;
 
     // Spawn Writer Task
     let storage_clone = storage.clone();
     let aggregation_window = config.aggregation_window_seconds;
+    let aggregation_key = config.aggregation_key;
     tokio::spawn(async move {
-        storage_clone.run_writer(rx, aggregation_window).await;
+        storage_clone.run_writer(rx, aggregation_window, aggregation_key).await;
     });
 
     // Spawn Connection Cleanup Task
@@ -72,26 +167,6 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     });
 
-    // Spawn Data Retention Cleanup Task (if enabled)
-    if let Some(retention_seconds) = config.data_retention_seconds {
-        let storage_retention = storage.clone();
-        tokio::spawn(async move {
-            let mut retention_interval = interval(Duration::from_secs(60));
-            loop {
-                retention_interval.tick().await;
-                match storage_retention.delete_old_data(retention_seconds) {
-                    Ok(deleted) if deleted > 0 => {
-                        tracing::info!("Data retention: deleted {} old packets", deleted);
-                    }
-                    Err(e) => {
-                        tracing::error!("Data retention cleanup failed: {}", e);
-                    }
-                    _ => {}
-                }
-            }
-        });
-    }
-
     // Signal handler for graceful shutdown
     let _storage_for_shutdown = storage.clone();
     ctrlc::set_handler(move || {
@@ -102,31 +177,28 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     })
     .expect("Error setting Ctrl-C handler");
 
+    // Open the capture up front so a device or permission problem stops
+    // startup with its fix instead of killing the sniffer thread.
+    let opened = sniffer::open_capture(config.interface.as_deref(), &config.capture)?;
+    let capture_mode = sniffer::CaptureMode::from_config(&config.capture);
+    tracing::info!("Capture mode on {}: {}", opened.device, capture_mode.describe());
+
     // Start Sniffer Thread
-    let tx_clone = tx.clone();
-    let interface = config.interface.clone();
-    let running_sniffer = running.clone();
-    let traffic_state_clone = traffic_state.clone();
-    let filter = FilterConfig::from(&config);
+    let device = opened.device.clone();
+    let traffic_state_sniffer = traffic_state.clone();
+    let filter = FilterConfig::from(config);
+    if !config.capture_filter.is_empty() {
+        tracing::info!("Capturing only {}", config.capture_filter);
+    }
+    if !config.storage_filter.is_empty() {
+        tracing::info!("Storing only {}", config.storage_filter);
+    }
+    let capture = config.capture.clone();
     let quiet = config.quiet;
-    let sample_rate = config.sample_rate;
 
     std::thread::spawn(move || {
-        sniffer::start_sniffer(interface, tx_clone, running_sniffer, traffic_state_clone, filter, quiet, sample_rate);
-    });
-
-    // API
-    let app_state = Arc::new(api::AppState {
-        traffic: traffic_state.clone(),
-        storage: storage.clone(),
-        start_time: std::time::Instant::now(),
+        sniffer::start_sniffer(opened, tx, running, traffic_state_sniffer, filter, capture, quiet, sample_rate);
     });
 
-    let app = api::router(app_state);
-
-    let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", config.port)).await?;
-    tracing::info!("Server running on http://0.0.0.0:{}", config.port);
-    axum::serve(listener, app).await?;
-
-    Ok(())
+    Ok(device)
 }
@@ -0,0 +1,118 @@
+//! Startup checks for the pcap capture path.
+//!
+//! Opening a device without privileges fails inside libpcap with a terse
+//! "socket: Operation not permitted".  These checks report the missing
+//! capability or interface up front with a one-line fix.
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+// Capability bits from linux/capability.h.
+const CAP_NET_ADMIN: u32 = 12;
+const CAP_NET_RAW: u32 = 13;
+
+/// A failed check: what is wrong and how to fix it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Failure {
+    pub problem: String,
+    pub fix: String,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}; {}", self.problem, self.fix)
+    }
+}
+
+/// Run every check, logging each failure.  Checks whose inputs cannot be
+/// read (no procfs or sysfs) are skipped, and CAP_NET_ADMIN is only needed
+/// for promiscuous capture.
+pub fn run(
+    interface: Option<&str>,
+    promiscuous: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut failures = Vec::new();
+    if let Some(caps) = fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| effective_caps(&status))
+    {
+        failures.extend(check_capabilities(caps, promiscuous));
+    }
+    if let Some(name) = interface {
+        failures.extend(check_interface(Path::new("/sys/class/net"), name));
+    }
+    for failure in &failures {
+        tracing::error!("Preflight: {}", failure);
+    }
+    if !failures.is_empty() {
+        return Err(format!(
+            "{} preflight check(s) failed; fix them or pass --skip-preflight",
+            failures.len()
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// The effective capability set from `/proc/<pid>/status`.
+fn effective_caps(status: &str) -> Option<u64> {
+    let hex = status.lines().find_map(|line| line.strip_prefix("CapEff:"))?;
+    u64::from_str_radix(hex.trim(), 16).ok()
+}
+
+/// Raw sockets need CAP_NET_RAW; promiscuous mode needs CAP_NET_ADMIN.
+fn check_capabilities(caps: u64, promiscuous: bool) -> Vec<Failure> {
+    let has = |cap: u32| caps & (1 << cap) != 0;
+    let mut failures = Vec::new();
+    if !has(CAP_NET_RAW) {
+        failures.push(Failure {
+            problem: "missing CAP_NET_RAW, needed to open a capture socket".to_string(),
+            fix: "run as root or `sudo setcap cap_net_raw,cap_net_admin+ep <binary>`".to_string(),
+        });
+    }
+    if promiscuous && !has(CAP_NET_ADMIN) {
+        failures.push(Failure {
+            problem: "missing CAP_NET_ADMIN, needed for promiscuous mode".to_string(),
+            fix: "run as root, `sudo setcap cap_net_raw,cap_net_admin+ep <binary>`, or pass \
+                  --no-promisc"
+                .to_string(),
+        });
+    }
+    failures
+}
+
+/// `name` must exist under the sysfs `class/net` directory and not be down.
+fn check_interface(sysfs: &Path, name: &str) -> Option<Failure> {
+    let dir = sysfs.join(name);
+    if !dir.exists() {
+        return Some(Failure {
+            problem: format!("interface {} does not exist", name),
+            fix: "pass an existing one with --interface (see `ip link`)".to_string(),
+        });
+    }
+    let state = fs::read_to_string(dir.join("operstate")).unwrap_or_default();
+    (state.trim() == "down").then(|| Failure {
+        problem: format!("interface {} is down", name),
+        fix: format!("bring it up with `ip link set dev {} up`", name),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capability_checks() {
+        let caps = effective_caps("CapPrm:\t0\nCapEff:\t0000000000003000\n").unwrap();
+        assert!(check_capabilities(caps, true).is_empty());
+
+        let failures = check_capabilities(1 << CAP_NET_ADMIN, true);
+        assert_eq!(failures.len(), 1);
+        assert!(failures[0].problem.contains("CAP_NET_RAW"));
+        // Host-only capture needs no CAP_NET_ADMIN.
+        assert_eq!(check_capabilities(1 << CAP_NET_RAW, true).len(), 1);
+        assert!(check_capabilities(1 << CAP_NET_RAW, false).is_empty());
+        assert!(check_interface(Path::new("/nonexistent"), "eth0").is_some());
+    }
+}
@@ -1,25 +1,44 @@
-use crate::config::Config;
+use crate::config::{CaptureConfig, Config};
 use crate::state::{PacketMetadata, TrafficState};
-use etherparse::{NetSlice, SlicedPacket, TransportSlice};
-use pcap::Device;
+use ayaflow_common::filter::{Filters, IpFilter, PortSet};
+use ayaflow_common::Sampler;
+use etherparse::{LaxNetSlice, LaxSlicedPacket, LinkSlice, TransportSlice};
+use pcap::{Active, Capture, Device, Linktype};
+use serde::Serialize;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc::Sender;
 
+/// How often the capture's counters are copied into `TrafficState`.
+const STATS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Share of packets dropped over one interval above which the warning
+/// suggests capturing less.
+const DROP_RATE_WARN: f64 = 0.01;
+
 /// Filter configuration for packet capture
 #[derive(Clone, Debug, Default)]
 pub struct FilterConfig {
-    pub port: Option<u16>,
-    pub ip: Option<String>,
+    /// Either end must be on one of these ports.
+    pub port: Option<PortSet>,
+    /// `filter_ip`; empty lets every address through.
+    pub ip: IpFilter,
     pub protocol: Option<String>,
+    /// `capture_filter` and `storage_filter` from the config file.
+    pub filters: Filters,
 }
 
 impl From<&Config> for FilterConfig {
     fn from(config: &Config) -> Self {
         Self {
-            port: config.filter_port,
-            ip: config.filter_ip.clone(),
+            port: config.filter_port.clone(),
+            // Config::validate has already rejected an entry that does not parse.
+            ip: IpFilter::new(&config.filter_ip).unwrap_or_default(),
             protocol: config.filter_protocol.clone(),
+            // Config::validate has already rejected a spec that does not parse.
+            filters: Filters::new(&config.capture_filter, &config.storage_filter)
+                .unwrap_or_default(),
         }
     }
 }
@@ -28,17 +47,15 @@ impl FilterConfig {
     /// Check if a packet matches the filter criteria
     pub fn matches(&self, meta: &PacketMetadata) -> bool {
         // Port filter
-        if let Some(port) = self.port {
-            if meta.src_port != port && meta.dst_port != port {
+        if let Some(ref ports) = self.port {
+            if !ports.contains(meta.src_port) && !ports.contains(meta.dst_port) {
                 return false;
             }
         }
 
         // IP filter
-        if let Some(ref ip) = self.ip {
-            if meta.src_ip != *ip && meta.dst_ip != *ip {
-                return false;
-            }
+        if !self.ip.matches_str(&meta.src_ip, &meta.dst_ip) {
+            return false;
         }
 
         // Protocol filter
@@ -48,54 +65,209 @@ impl FilterConfig {
             }
         }
 
-        true
+        self.filters.capture.matches(
+            &meta.src_ip,
+            &meta.dst_ip,
+            meta.src_port,
+            meta.dst_port,
+            &meta.protocol,
+        )
+    }
+
+    /// Check if a captured packet should also be stored
+    pub fn stores(&self, meta: &PacketMetadata) -> bool {
+        self.filters.storage.matches(
+            &meta.src_ip,
+            &meta.dst_ip,
+            meta.src_port,
+            meta.dst_port,
+            &meta.protocol,
+        )
+    }
+}
+
+/// Length of the address-family header that precedes each packet on
+/// BSD-style loopback captures (DLT_NULL / DLT_LOOP).
+const NULL_HEADER_LEN: usize = 4;
+
+/// Link-layer framing of captured packets, chosen from the capture's datalink
+/// type so non-Ethernet interfaces (loopback, tun, WireGuard) parse correctly.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LinkLayer {
+    Ethernet,
+    /// A 4-byte address family header followed by the IP packet.
+    Null,
+    /// Bare IP packets with no link-layer header.
+    Raw,
+}
+
+impl LinkLayer {
+    pub fn from_datalink(linktype: Linktype) -> Result<Self, String> {
+        match linktype {
+            Linktype::ETHERNET => Ok(Self::Ethernet),
+            Linktype::NULL | Linktype::LOOP => Ok(Self::Null),
+            Linktype::RAW | Linktype::IPV4 | Linktype::IPV6 => Ok(Self::Raw),
+            other => Err(format!(
+                "unsupported link type {} ({})",
+                other.0,
+                other.get_name().unwrap_or_else(|_| "unknown".to_string())
+            )),
+        }
+    }
+
+    /// Slice a captured frame starting at its network layer.  Frames cut
+    /// short by the snaplen still yield the headers that were captured.
+    pub fn slice<'a>(self, data: &'a [u8]) -> Option<LaxSlicedPacket<'a>> {
+        match self {
+            Self::Ethernet => LaxSlicedPacket::from_ethernet(data).ok(),
+            Self::Null => data
+                .get(NULL_HEADER_LEN..)
+                .and_then(|ip| LaxSlicedPacket::from_ip(ip).ok()),
+            Self::Raw => LaxSlicedPacket::from_ip(data).ok(),
+        }
+    }
+}
+
+/// Whether the interface is in promiscuous mode, which decides whose
+/// traffic the capture sees.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CaptureMode {
+    /// Every frame that reaches the interface.
+    Promiscuous,
+    /// Only frames addressed to this host, broadcast and multicast.
+    HostOnly,
+}
+
+impl CaptureMode {
+    pub fn from_config(capture: &CaptureConfig) -> Self {
+        if capture.promiscuous {
+            Self::Promiscuous
+        } else {
+            Self::HostOnly
+        }
+    }
+
+    /// What the mode does and does not capture, for the startup log.
+    pub fn describe(self) -> &'static str {
+        match self {
+            Self::Promiscuous => {
+                "promiscuous: every frame reaching the interface is captured, including \
+                 traffic between other hosts on a mirror port, hub or shared segment; some \
+                 IDSes alert on interfaces in this mode (disable with --no-promisc)"
+            }
+            Self::HostOnly => {
+                "host only: traffic to and from this host, broadcast and multicast is \
+                 captured; other hosts' traffic on a mirror port or hub is not seen"
+            }
+        }
+    }
+}
+
+/// A capture opened on a device, ready for `start_sniffer`.
+pub struct OpenCapture {
+    pub device: String,
+    cap: Capture<Active>,
+    link_layer: LinkLayer,
+}
+
+/// Open the named device, or the default one, with the configured
+/// settings.  Errors name the device and, for permission problems, the fix.
+pub fn open_capture(
+    interface_name: Option<&str>,
+    capture: &CaptureConfig,
+) -> Result<OpenCapture, String> {
+    let device = match interface_name {
+        Some(name) => Device::list()
+            .map_err(|e| format!("cannot list capture devices: {}", e))?
+            .into_iter()
+            .find(|d| d.name == name)
+            .ok_or_else(|| format!("capture device {} not found (see `ip link`)", name))?,
+        None => Device::lookup()
+            .map_err(|e| format!("cannot look up the default capture device: {}", e))?
+            .ok_or("no default capture device; pass one with --interface")?,
+    };
+    let name = device.name.clone();
+    let failed = |e: pcap::Error| open_error(&name, capture.promiscuous, &e.to_string());
+
+    let mut inactive = Capture::from_device(device)
+        .map_err(failed)?
+        .promisc(capture.promiscuous)
+        .snaplen(to_c_int(capture.snaplen))
+        .timeout(to_c_int(capture.timeout_ms))
+        .immediate_mode(capture.immediate);
+    if let Some(buffer_size) = capture.buffer_size {
+        inactive = inactive.buffer_size(to_c_int(buffer_size));
     }
+    let cap = inactive.open().map_err(failed)?;
+    let link_layer = LinkLayer::from_datalink(cap.get_datalink())
+        .map_err(|e| format!("cannot capture on {}: {}", name, e))?;
+    Ok(OpenCapture { device: name, cap, link_layer })
 }
 
+/// The message for a device libpcap would not open.
+fn open_error(device: &str, promiscuous: bool, error: &str) -> String {
+    let lower = error.to_lowercase();
+    if !lower.contains("permission") && !lower.contains("not permitted") {
+        return format!("cannot open {} for capture: {}", device, error);
+    }
+    let caps = if promiscuous { "cap_net_raw,cap_net_admin" } else { "cap_net_raw" };
+    let mut message = format!(
+        "cannot open {} for capture: {}; run as root or `sudo setcap {}+ep <binary>`",
+        device, error, caps
+    );
+    if promiscuous {
+        message.push_str(" (without CAP_NET_ADMIN, pass --no-promisc)");
+    }
+    message
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn start_sniffer(
-    interface_name: Option<String>,
+    opened: OpenCapture,
     tx: Sender<PacketMetadata>,
     running: Arc<AtomicBool>,
     traffic_state: Arc<TrafficState>,
     filter: FilterConfig,
+    capture: CaptureConfig,
     quiet: bool,
     sample_rate: u32,
 ) {
-    let device = if let Some(name) = interface_name {
-        Device::list()
-            .unwrap()
-            .into_iter()
-            .find(|d| d.name == name)
-            .expect("Device not found")
-    } else {
-        Device::lookup().expect("Device lookup failed").expect("No default device")
-    };
+    let OpenCapture { device, mut cap, link_layer } = opened;
 
     if !quiet {
-        println!("Capturing on device: {}", device.name);
-        if filter.port.is_some() || filter.ip.is_some() || filter.protocol.is_some() {
-            println!("Filters: port={:?}, ip={:?}, protocol={:?}", 
-                filter.port, filter.ip, filter.protocol);
+        println!("Capturing on device: {}", device);
+        if filter.port.is_some() || !filter.ip.is_empty() || filter.protocol.is_some() {
+            let port = filter.port.as_ref().map(|ports| ports.to_string());
+            println!("Filters: port={:?}, ip={}, protocol={:?}", 
+                port, filter.ip, filter.protocol);
         }
+        println!(
+            "Capture: promiscuous={}, snaplen={}, buffer_size={}, timeout={}ms, immediate={}",
+            capture.promiscuous,
+            capture.snaplen,
+            capture
+                .buffer_size
+                .map_or_else(|| "default".to_string(), |size| size.to_string()),
+            capture.timeout_ms,
+            capture.immediate
+        );
     }
 
-    let mut cap = pcap::Capture::from_device(device)
-        .unwrap()
-        .promisc(true)
-        .snaplen(65535)
-        .timeout(1000)
-        .open()
-        .unwrap();
-
     // Sampling: keep 1 out of every sample_rate packets for storage.
     // A rate of 0 or 1 means keep everything.
-    let effective_rate = if sample_rate == 0 { 1 } else { sample_rate };
-    let mut sample_counter: u32 = 0;
+    let mut sampler = Sampler::new(sample_rate);
+    let mut stats_polled = Instant::now();
+    let mut last_stats = CaptureCounts::default();
 
     while running.load(Ordering::Relaxed) {
+        if stats_polled.elapsed() >= STATS_INTERVAL {
+            last_stats = record_capture_stats(&mut cap, &traffic_state, last_stats);
+            stats_polled = Instant::now();
+        }
         match cap.next_packet() {
             Ok(packet) => {
-                if let Ok(sliced) = SlicedPacket::from_ethernet(&packet.data) {
+                if let Some(sliced) = link_layer.slice(packet.data) {
                     let mut meta = PacketMetadata {
                         timestamp: chrono::Utc::now().timestamp_millis(),
                         src_ip: "?.?.?.?".to_string(),
@@ -104,16 +276,23 @@ pub fn start_sniffer(
                         dst_port: 0,
                         protocol: "Unknown".to_string(),
                         length: packet.header.len as usize,
+                        src_mac: None,
+                        dst_mac: None,
                     };
 
+                    if let Some(LinkSlice::Ethernet2(eth)) = &sliced.link {
+                        meta.src_mac = Some(format_mac(eth.source()));
+                        meta.dst_mac = Some(format_mac(eth.destination()));
+                    }
+
                     match sliced.net {
-                        Some(NetSlice::Ipv4(slice)) => {
+                        Some(LaxNetSlice::Ipv4(slice)) => {
                             let header = slice.header();
                             meta.src_ip = header.source_addr().to_string();
                             meta.dst_ip = header.destination_addr().to_string();
                             meta.protocol = "IPv4".to_string();
                         }
-                        Some(NetSlice::Ipv6(slice)) => {
+                        Some(LaxNetSlice::Ipv6(slice)) => {
                             let header = slice.header();
                             meta.src_ip = header.source_addr().to_string();
                             meta.dst_ip = header.destination_addr().to_string();
@@ -141,9 +320,9 @@ pub fn start_sniffer(
                         // Always update live in-memory stats (unaffected by sampling)
                         traffic_state.update(&meta);
 
-                        // Sampling gate: only forward every Nth packet to storage
-                        sample_counter = sample_counter.wrapping_add(1);
-                        if sample_counter % effective_rate == 0 {
+                        // Storage filter, then the sampling gate: only
+                        // forward every Nth stored packet to storage
+                        if filter.stores(&meta) && sampler.keep() {
                             if let Err(_) = tx.blocking_send(meta) {
                                 break;
                             }
@@ -158,3 +337,243 @@ pub fn start_sniffer(
         }
     }
 }
+
+/// Format an Ethernet address as "aa:bb:cc:dd:ee:ff"
+fn format_mac(mac: [u8; 6]) -> String {
+    mac.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(":")
+}
+
+/// libpcap takes sizes and timeouts as a C int.
+fn to_c_int(value: u32) -> i32 {
+    i32::try_from(value).unwrap_or(i32::MAX)
+}
+
+/// pcap's cumulative counters (`ps_recv`, `ps_drop`, `ps_ifdrop`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct CaptureCounts {
+    received: u32,
+    dropped: u32,
+    if_dropped: u32,
+}
+
+impl CaptureCounts {
+    /// A warning if packets were dropped since `previous`.  The counters
+    /// are 32-bit in libpcap and may wrap.
+    fn drop_warning(&self, previous: &CaptureCounts) -> Option<String> {
+        let received = self.received.wrapping_sub(previous.received);
+        let dropped = self.dropped.wrapping_sub(previous.dropped);
+        if dropped == 0 {
+            return None;
+        }
+        let rate = dropped as f64 / received.max(dropped) as f64;
+        let mut warning = format!(
+            "pcap dropped {} of {} packets in the last {}s ({:.1}%)",
+            dropped,
+            received,
+            STATS_INTERVAL.as_secs(),
+            rate * 100.0
+        );
+        if rate >= DROP_RATE_WARN {
+            warning.push_str(
+                "; consider increasing sample_rate or capture.buffer_size, or adding a BPF filter",
+            );
+        }
+        Some(warning)
+    }
+}
+
+/// Copy pcap's cumulative counters into the live state for `/api/stats` and
+/// `/metrics`, warning when the kernel dropped packets we were too slow to
+/// read.  Returns the counts for the next comparison.
+fn record_capture_stats(
+    cap: &mut pcap::Capture<pcap::Active>,
+    traffic_state: &TrafficState,
+    previous: CaptureCounts,
+) -> CaptureCounts {
+    let stats = match cap.stats() {
+        Ok(stats) => stats,
+        Err(e) => {
+            tracing::debug!("Cannot read capture statistics: {}", e);
+            return previous;
+        }
+    };
+    let counts = CaptureCounts {
+        received: stats.received,
+        dropped: stats.dropped,
+        if_dropped: stats.if_dropped,
+    };
+    traffic_state
+        .pcap_received
+        .store(counts.received as u64, Ordering::Relaxed);
+    traffic_state
+        .pcap_dropped
+        .store(counts.dropped as u64, Ordering::Relaxed);
+    traffic_state
+        .pcap_if_dropped
+        .store(counts.if_dropped as u64, Ordering::Relaxed);
+    if let Some(warning) = counts.drop_warning(&previous) {
+        tracing::warn!("{}", warning);
+    }
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// IPv4 + UDP 10.0.0.1:5353 -> 10.0.0.2:53 with a 4-byte payload.
+    const IPV4_UDP: [u8; 32] = [
+        0x45, 0x00, 0x00, 0x20, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00, 10, 0, 0, 1, 10,
+        0, 0, 2, 0x14, 0xe9, 0x00, 0x35, 0x00, 0x0c, 0x00, 0x00, 0xde, 0xad, 0xbe, 0xef,
+    ];
+
+    fn assert_udp(sliced: LaxSlicedPacket) {
+        match sliced.net {
+            Some(LaxNetSlice::Ipv4(slice)) => {
+                assert_eq!(slice.header().source_addr().to_string(), "10.0.0.1");
+                assert_eq!(slice.header().destination_addr().to_string(), "10.0.0.2");
+            }
+            other => panic!("expected IPv4, got {:?}", other),
+        }
+        match sliced.transport {
+            Some(TransportSlice::Udp(udp)) => {
+                assert_eq!(udp.source_port(), 5353);
+                assert_eq!(udp.destination_port(), 53);
+            }
+            other => panic!("expected UDP, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_filter_port_list() {
+        let filter = FilterConfig {
+            port: Some("53,8000-8100".parse().unwrap()),
+            ..FilterConfig::default()
+        };
+        let meta = |src_port, dst_port| PacketMetadata {
+            timestamp: 0,
+            src_ip: "10.0.0.1".into(),
+            dst_ip: "10.0.0.2".into(),
+            src_port,
+            dst_port,
+            protocol: "UDP".into(),
+            length: 60,
+            src_mac: None,
+            dst_mac: None,
+        };
+        assert!(filter.matches(&meta(5353, 53)));
+        assert!(filter.matches(&meta(8100, 40000)));
+        assert!(filter.matches(&meta(40000, 8000)));
+        assert!(!filter.matches(&meta(5353, 54)));
+        assert!(!filter.matches(&meta(7999, 8101)));
+    }
+
+    #[test]
+    fn test_filter_ip_cidrs() {
+        let filter = FilterConfig {
+            ip: IpFilter::new(&["10.0.0.0/8", "!10.0.0.2"]).unwrap(),
+            ..FilterConfig::default()
+        };
+        let meta = |src_ip: &str, dst_ip: &str| PacketMetadata {
+            timestamp: 0,
+            src_ip: src_ip.into(),
+            dst_ip: dst_ip.into(),
+            src_port: 5353,
+            dst_port: 53,
+            protocol: "UDP".into(),
+            length: 60,
+            src_mac: None,
+            dst_mac: None,
+        };
+        assert!(filter.matches(&meta("10.0.0.1", "192.0.2.1")));
+        assert!(filter.matches(&meta("::ffff:10.9.9.9", "192.0.2.1")));
+        assert!(!filter.matches(&meta("10.0.0.1", "10.0.0.2")));
+        assert!(!filter.matches(&meta("192.0.2.1", "2001:db8::1")));
+    }
+
+    #[test]
+    fn test_ethernet_frame() {
+        // Broadcast destination, then the source address.
+        let mut frame = vec![0xff; 6];
+        frame.extend_from_slice(&[0xb8, 0x27, 0xeb, 0x01, 0x02, 0x03]);
+        frame.extend_from_slice(&[0x08, 0x00]);
+        frame.extend_from_slice(&IPV4_UDP);
+        let sliced = LinkLayer::Ethernet.slice(&frame).unwrap();
+        match &sliced.link {
+            Some(LinkSlice::Ethernet2(eth)) => {
+                assert_eq!(format_mac(eth.source()), "b8:27:eb:01:02:03");
+                assert_eq!(format_mac(eth.destination()), "ff:ff:ff:ff:ff:ff");
+            }
+            other => panic!("expected Ethernet, got {:?}", other),
+        }
+        assert_udp(sliced);
+    }
+
+    #[test]
+    fn test_truncated_frame() {
+        // A snaplen that cuts into the payload keeps the addresses and ports.
+        let mut frame = vec![0u8; 12];
+        frame.extend_from_slice(&[0x08, 0x00]);
+        frame.extend_from_slice(&IPV4_UDP[..IPV4_UDP.len() - 2]);
+        assert_udp(LinkLayer::Ethernet.slice(&frame).unwrap());
+    }
+
+    #[test]
+    fn test_null_loopback_frame() {
+        // AF_INET (2) in host byte order.
+        let mut frame = 2u32.to_ne_bytes().to_vec();
+        frame.extend_from_slice(&IPV4_UDP);
+        assert_udp(LinkLayer::Null.slice(&frame).unwrap());
+        assert!(LinkLayer::Null.slice(&frame[..2]).is_none());
+    }
+
+    #[test]
+    fn test_raw_ip_frame() {
+        assert_udp(LinkLayer::Raw.slice(&IPV4_UDP).unwrap());
+        // Parsing a raw packet as Ethernet finds nothing useful.
+        assert!(LinkLayer::Ethernet
+            .slice(&IPV4_UDP)
+            .is_none_or(|sliced| sliced.net.is_none()));
+    }
+
+    #[test]
+    fn test_drop_warning() {
+        let counts = |received, dropped| CaptureCounts { received, dropped, if_dropped: 0 };
+        assert_eq!(counts(1000, 5).drop_warning(&counts(500, 5)), None);
+
+        let minor = counts(10_000, 1).drop_warning(&counts(0, 0)).unwrap();
+        assert!(minor.contains("dropped 1 of 10000"), "{}", minor);
+        assert!(!minor.contains("sample_rate"), "{}", minor);
+
+        let heavy = counts(2000, 150).drop_warning(&counts(1000, 50)).unwrap();
+        assert!(heavy.contains("dropped 100 of 1000"), "{}", heavy);
+        assert!(heavy.contains("(10.0%)"), "{}", heavy);
+        assert!(heavy.contains("consider increasing sample_rate"), "{}", heavy);
+
+        // Counters that wrapped past u32::MAX still give the interval's drops.
+        let wrapped = counts(100, 2).drop_warning(&counts(u32::MAX - 99, u32::MAX));
+        assert!(wrapped.unwrap().contains("dropped 3 of 200"));
+    }
+
+    #[test]
+    fn test_datalink_dispatch() {
+        assert_eq!(LinkLayer::from_datalink(Linktype::ETHERNET), Ok(LinkLayer::Ethernet));
+        assert_eq!(LinkLayer::from_datalink(Linktype::NULL), Ok(LinkLayer::Null));
+        assert_eq!(LinkLayer::from_datalink(Linktype::LOOP), Ok(LinkLayer::Null));
+        assert_eq!(LinkLayer::from_datalink(Linktype::RAW), Ok(LinkLayer::Raw));
+        assert!(LinkLayer::from_datalink(Linktype::IEEE802_11).is_err());
+    }
+
+    #[test]
+    fn test_open_error() {
+        let denied = open_error("eth0", true, "socket: Operation not permitted");
+        assert!(denied.starts_with("cannot open eth0 for capture"), "{}", denied);
+        assert!(denied.contains("setcap cap_net_raw,cap_net_admin+ep"), "{}", denied);
+        assert!(denied.contains("--no-promisc"), "{}", denied);
+        let denied = open_error("eth0", false, "socket: Operation not permitted");
+        assert!(denied.contains("setcap cap_net_raw+ep") && !denied.contains("--no-promisc"));
+        let other = open_error("wlan0", true, "wlan0: No such device exists");
+        assert_eq!(other, "cannot open wlan0 for capture: wlan0: No such device exists");
+        assert_eq!(CaptureMode::from_config(&CaptureConfig::default()), CaptureMode::Promiscuous);
+    }
+}
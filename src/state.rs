@@ -12,6 +12,13 @@ pub struct PacketMetadata {
     pub dst_port: u16,
     pub protocol: String,
     pub length: usize,
+    /// Ethernet source address, "aa:bb:cc:dd:ee:ff"; None when the link
+    /// layer has none (loopback, tun) and for rows stored before MACs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub src_mac: Option<String>,
+    /// Ethernet destination address, as `src_mac`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dst_mac: Option<String>,
 }
 
 #[derive(Debug, Serialize, Clone)]
@@ -39,6 +46,9 @@ impl Default for ConnectionStats {
 #[derive(Debug, Clone)]
 pub struct AggregatedBucket {
     pub first_timestamp: i64,
+    /// Bounds of the aggregation window, in epoch ms (end exclusive).
+    pub window_start: i64,
+    pub window_end: i64,
     pub src_ip: String,
     pub dst_ip: String,
     pub src_port: u16,
@@ -52,6 +62,8 @@ impl AggregatedBucket {
     pub fn from_packet(packet: &PacketMetadata) -> Self {
         Self {
             first_timestamp: packet.timestamp,
+            window_start: packet.timestamp,
+            window_end: packet.timestamp,
             src_ip: packet.src_ip.clone(),
             dst_ip: packet.dst_ip.clone(),
             src_port: packet.src_port,
@@ -73,6 +85,14 @@ pub struct TrafficState {
     pub total_packets: AtomicU64,
     pub total_bytes: AtomicU64,
     pub active_connections: AtomicUsize,
+    /// Packets pcap received (`ps_recv`), as of the sniffer's last
+    /// `Capture::stats()` poll.
+    pub pcap_received: AtomicU64,
+    /// Packets pcap dropped for lack of buffer space (`ps_drop`).
+    pub pcap_dropped: AtomicU64,
+    /// Packets dropped by the interface or its driver, where pcap can tell
+    /// (`ps_ifdrop`).
+    pub pcap_if_dropped: AtomicU64,
 }
 
 impl TrafficState {
@@ -82,6 +102,9 @@ impl TrafficState {
             total_packets: AtomicU64::new(0),
             total_bytes: AtomicU64::new(0),
             active_connections: AtomicUsize::new(0),
+            pcap_received: AtomicU64::new(0),
+            pcap_dropped: AtomicU64::new(0),
+            pcap_if_dropped: AtomicU64::new(0),
         }
     }
 
@@ -150,6 +173,8 @@ mod tests {
             dst_port: 1234,
             protocol: "TCP".into(),
             length: 100,
+            src_mac: None,
+            dst_mac: None,
         };
 
         state.update(&packet);
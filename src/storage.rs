@@ -1,10 +1,24 @@
 use crate::state::{AggregatedBucket, PacketMetadata};
+use ayaflow_common::AggregationKey;
 use chrono;
-use rusqlite::{params, Connection, Result};
+use rusqlite::{params, Connection, OptionalExtension, Result};
+use serde::Serialize;
 use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::mpsc::Receiver;
-use tokio::time::{interval, Duration};
+use tokio::time::{interval, sleep, Duration};
+
+/// A stored row as served by `/api/history`, with the sample rate that was
+/// in effect when it was captured.  Multiplying `length` (or a count of
+/// rows) by `scale_factor` estimates the traffic the row stands for.
+#[derive(Debug, Clone, Serialize)]
+pub struct HistoryRow {
+    #[serde(flatten)]
+    pub packet: PacketMetadata,
+    pub scale_factor: u32,
+    /// Whether the row was sampled, so its figures are estimates.
+    pub estimated: bool,
+}
 
 #[derive(Clone)]
 pub struct Storage {
@@ -33,11 +47,54 @@ impl Storage {
             [],
         )?;
         
+        // Granularity an aggregated row was keyed at; NULL for raw packets.
+        // Older databases lack the column, so add it when missing.
+        let has_aggregation: bool = conn.query_row(
+            "SELECT COUNT(*) FROM pragma_table_info('packets') WHERE name = 'aggregation'",
+            [],
+            |row| row.get::<_, i64>(0).map(|n| n > 0),
+        )?;
+        if !has_aggregation {
+            conn.execute("ALTER TABLE packets ADD COLUMN aggregation TEXT", [])?;
+        }
+
+        // Aggregation window bounds (epoch ms); NULL for raw packets.
+        let has_window: bool = conn.query_row(
+            "SELECT COUNT(*) FROM pragma_table_info('packets') WHERE name = 'window_start'",
+            [],
+            |row| row.get::<_, i64>(0).map(|n| n > 0),
+        )?;
+        if !has_window {
+            conn.execute("ALTER TABLE packets ADD COLUMN window_start INTEGER", [])?;
+            conn.execute("ALTER TABLE packets ADD COLUMN window_end INTEGER", [])?;
+        }
+
+        // Ethernet addresses; NULL off Ethernet and for aggregated rows.
+        let has_mac: bool = conn.query_row(
+            "SELECT COUNT(*) FROM pragma_table_info('packets') WHERE name = 'src_mac'",
+            [],
+            |row| row.get::<_, i64>(0).map(|n| n > 0),
+        )?;
+        if !has_mac {
+            conn.execute("ALTER TABLE packets ADD COLUMN src_mac TEXT", [])?;
+            conn.execute("ALTER TABLE packets ADD COLUMN dst_mac TEXT", [])?;
+        }
+
         conn.execute(
              "CREATE INDEX IF NOT EXISTS idx_timestamp ON packets(timestamp)",
              []
         )?;
 
+        // Sample rate in effect from `since` (epoch ms) until the next row.
+        // Rows stored before the table existed count as unsampled.
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS capture_meta (
+                since INTEGER PRIMARY KEY,
+                sample_rate INTEGER NOT NULL
+            )",
+            [],
+        )?;
+
         Ok(Self {
             conn: Arc::new(std::sync::Mutex::new(conn)),
         })
@@ -45,12 +102,17 @@ impl Storage {
 
     /// Main writer loop. Behavior depends on `aggregation_window_seconds`:
     ///   - 0: store every incoming packet individually (original behavior).
-    ///   - >0: accumulate per-connection stats and flush summary rows on a timer.
-    pub async fn run_writer(&self, rx: Receiver<PacketMetadata>, aggregation_window_seconds: u64) {
+    ///   - >0: accumulate stats per `aggregation_key` and flush summary rows on a timer.
+    pub async fn run_writer(
+        &self,
+        rx: Receiver<PacketMetadata>,
+        aggregation_window_seconds: u64,
+        aggregation_key: AggregationKey,
+    ) {
         if aggregation_window_seconds == 0 {
             self.run_writer_raw(rx).await;
         } else {
-            self.run_writer_aggregated(rx, aggregation_window_seconds).await;
+            self.run_writer_aggregated(rx, aggregation_window_seconds, aggregation_key).await;
         }
     }
 
@@ -76,27 +138,48 @@ impl Storage {
         }
     }
 
-    /// Aggregated mode: collapse packets per connection key over a time window.
-    async fn run_writer_aggregated(&self, mut rx: Receiver<PacketMetadata>, window_secs: u64) {
-        let mut buckets: HashMap<String, AggregatedBucket> = HashMap::new();
-        let mut ticker = interval(Duration::from_secs(window_secs));
+    /// Aggregated mode: collapse packets per aggregation key over wall-clock
+    /// aligned windows (60s windows start on the minute).  Each packet goes to
+    /// the window its timestamp falls in; a window is flushed shortly after it
+    /// closes.  Ports dropped by the key are stored as 0.
+    async fn run_writer_aggregated(
+        &self,
+        mut rx: Receiver<PacketMetadata>,
+        window_secs: u64,
+        aggregation_key: AggregationKey,
+    ) {
+        let window_ms = (window_secs * 1000) as i64;
+        let mut buckets: HashMap<(i64, String), AggregatedBucket> = HashMap::new();
+        let flush = sleep(until_next_flush(window_ms));
+        tokio::pin!(flush);
 
         loop {
             tokio::select! {
                 Some(packet) = rx.recv() => {
+                    let (src_port, dst_port) =
+                        aggregation_key.key_ports(packet.src_port, packet.dst_port);
                     let key = format!(
-                        "{}:{} -> {}:{}",
-                        packet.src_ip, packet.src_port, packet.dst_ip, packet.dst_port
+                        "{}:{} -> {}:{} {}",
+                        packet.src_ip, src_port, packet.dst_ip, dst_port, packet.protocol
                     );
+                    let window_start = packet.timestamp - packet.timestamp.rem_euclid(window_ms);
                     buckets
-                        .entry(key)
+                        .entry((window_start, key))
                         .and_modify(|b| b.merge(&packet))
-                        .or_insert_with(|| AggregatedBucket::from_packet(&packet));
+                        .or_insert_with(|| AggregatedBucket {
+                            src_port,
+                            dst_port,
+                            window_start,
+                            window_end: window_start + window_ms,
+                            ..AggregatedBucket::from_packet(&packet)
+                        });
                 }
-                _ = ticker.tick() => {
-                    if !buckets.is_empty() {
-                        self.flush_aggregated(&mut buckets);
+                _ = &mut flush => {
+                    let now = chrono::Utc::now().timestamp_millis();
+                    if buckets.values().any(|b| b.window_end <= now) {
+                        self.flush_aggregated(&mut buckets, aggregation_key, now);
                     }
+                    flush.as_mut().reset(tokio::time::Instant::now() + until_next_flush(window_ms));
                 }
             }
         }
@@ -114,8 +197,8 @@ impl Storage {
 
          {
              let mut stmt = match tx.prepare(
-                 "INSERT INTO packets (timestamp, src_ip, dst_ip, src_port, dst_port, protocol, length)
-                  VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)"
+                 "INSERT INTO packets (timestamp, src_ip, dst_ip, src_port, dst_port, protocol, length, src_mac, dst_mac)
+                  VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)"
              ) {
                  Ok(stmt) => stmt,
                  Err(e) => {
@@ -132,7 +215,9 @@ impl Storage {
                      packet.src_port,
                      packet.dst_port,
                      packet.protocol,
-                     packet.length
+                     packet.length,
+                     packet.src_mac,
+                     packet.dst_mac
                  ]) {
                      eprintln!("Failed to insert packet: {}", e);
                  }
@@ -146,9 +231,15 @@ impl Storage {
          }
     }
 
-    /// Flush aggregated buckets as summary rows. Each bucket becomes one row where
-    /// `length` holds the total bytes accumulated over the window.
-    fn flush_aggregated(&self, buckets: &mut HashMap<String, AggregatedBucket>) {
+    /// Flush the buckets of windows that closed by `now` as summary rows. Each
+    /// bucket becomes one row where `length` holds the total bytes accumulated
+    /// over the window and `aggregation` the key granularity.
+    fn flush_aggregated(
+        &self,
+        buckets: &mut HashMap<(i64, String), AggregatedBucket>,
+        aggregation_key: AggregationKey,
+        now: i64,
+    ) {
         let mut conn = self.conn.lock().unwrap();
         let tx = match conn.transaction() {
             Ok(tx) => tx,
@@ -160,8 +251,8 @@ impl Storage {
 
         {
             let mut stmt = match tx.prepare(
-                "INSERT INTO packets (timestamp, src_ip, dst_ip, src_port, dst_port, protocol, length)
-                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)"
+                "INSERT INTO packets (timestamp, src_ip, dst_ip, src_port, dst_port, protocol, length, aggregation, window_start, window_end)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)"
             ) {
                 Ok(stmt) => stmt,
                 Err(e) => {
@@ -170,7 +261,7 @@ impl Storage {
                 }
             };
 
-            for bucket in buckets.values() {
+            for bucket in buckets.values().filter(|b| b.window_end <= now) {
                 if let Err(e) = stmt.execute(params![
                     bucket.first_timestamp,
                     bucket.src_ip,
@@ -178,7 +269,10 @@ impl Storage {
                     bucket.src_port,
                     bucket.dst_port,
                     bucket.protocol,
-                    bucket.total_bytes as i64
+                    bucket.total_bytes as i64,
+                    aggregation_key.as_str(),
+                    bucket.window_start,
+                    bucket.window_end
                 ]) {
                     eprintln!("Failed to insert aggregated row: {}", e);
                 }
@@ -188,26 +282,57 @@ impl Storage {
         if let Err(e) = tx.commit() {
             eprintln!("Failed to commit transaction: {}", e);
         } else {
-            buckets.clear();
+            buckets.retain(|_, b| b.window_end > now);
         }
     }
     
-    pub fn query_history(&self, limit: usize) -> Result<Vec<PacketMetadata>> {
+    /// Record that packets captured from `since` on keep 1 in `sample_rate`.
+    /// Nothing is written when that rate is already in effect, so only
+    /// changes leave a boundary.
+    pub fn record_sample_rate(&self, sample_rate: u32, since: i64) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        let current: Option<u32> = conn
+            .query_row(
+                "SELECT sample_rate FROM capture_meta ORDER BY since DESC LIMIT 1",
+                [],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if current.unwrap_or(1) != sample_rate {
+            conn.execute(
+                "INSERT OR REPLACE INTO capture_meta (since, sample_rate) VALUES (?1, ?2)",
+                params![since, sample_rate],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Most recent rows, each scaled by the rate of the period it falls in.
+    pub fn query_history(&self, limit: usize) -> Result<Vec<HistoryRow>> {
          let conn = self.conn.lock().unwrap();
          let mut stmt = conn.prepare(
-             "SELECT timestamp, src_ip, dst_ip, src_port, dst_port, protocol, length 
-              FROM packets ORDER BY timestamp DESC LIMIT ?1"
+             "SELECT timestamp, src_ip, dst_ip, src_port, dst_port, protocol, length, src_mac, dst_mac,
+                     COALESCE((SELECT m.sample_rate FROM capture_meta m WHERE m.since <= p.timestamp
+                               ORDER BY m.since DESC LIMIT 1), 1)
+              FROM packets p ORDER BY timestamp DESC LIMIT ?1"
          )?;
          
          let rows = stmt.query_map([limit], |row| {
-             Ok(PacketMetadata {
-                 timestamp: row.get(0)?,
-                 src_ip: row.get(1)?,
-                 dst_ip: row.get(2)?,
-                 src_port: row.get(3)?,
-                 dst_port: row.get(4)?,
-                 protocol: row.get(5)?,
-                 length: row.get(6)?,
+             let scale_factor: u32 = row.get(9)?;
+             Ok(HistoryRow {
+                 packet: PacketMetadata {
+                     timestamp: row.get(0)?,
+                     src_ip: row.get(1)?,
+                     dst_ip: row.get(2)?,
+                     src_port: row.get(3)?,
+                     dst_port: row.get(4)?,
+                     protocol: row.get(5)?,
+                     length: row.get(6)?,
+                     src_mac: row.get(7)?,
+                     dst_mac: row.get(8)?,
+                 },
+                 scale_factor,
+                 estimated: scale_factor > 1,
              })
          })?;
          
@@ -218,6 +343,23 @@ impl Storage {
          Ok(result)
     }
 
+    /// Rows and bytes stored with timestamps in `[from, to)`, each period
+    /// multiplied by the sample rate in effect during it.  With aggregation
+    /// a row is a whole window rather than a packet.
+    pub fn estimated_totals(&self, from: i64, to: i64) -> Result<(u64, u64)> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "SELECT COALESCE(SUM(rate), 0), COALESCE(SUM(length * rate), 0) FROM (
+                 SELECT p.length,
+                        COALESCE((SELECT m.sample_rate FROM capture_meta m WHERE m.since <= p.timestamp
+                                  ORDER BY m.since DESC LIMIT 1), 1) AS rate
+                 FROM packets p WHERE p.timestamp >= ?1 AND p.timestamp < ?2
+             )",
+            params![from, to],
+            |row| Ok((row.get::<_, i64>(0)? as u64, row.get::<_, i64>(1)? as u64)),
+        )
+    }
+
     /// Delete packets older than the specified number of seconds
     /// Returns the number of deleted rows
     pub fn delete_old_data(&self, older_than_seconds: u64) -> Result<usize> {
@@ -231,3 +373,67 @@ impl Storage {
     }
 }
 
+/// Time until 500ms past the next multiple of `window_ms` since the epoch.
+/// The grace lets packets stamped just before the boundary reach their window.
+fn until_next_flush(window_ms: i64) -> Duration {
+    let now = chrono::Utc::now().timestamp_millis();
+    let remaining = window_ms - now.rem_euclid(window_ms);
+    Duration::from_millis(remaining as u64 + 500)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(timestamp: i64, length: usize) -> PacketMetadata {
+        PacketMetadata {
+            timestamp,
+            src_ip: "10.0.0.1".into(),
+            dst_ip: "10.0.0.2".into(),
+            src_port: 40000,
+            dst_port: 443,
+            protocol: "TCP".into(),
+            length,
+            src_mac: None,
+            dst_mac: None,
+        }
+    }
+
+    #[test]
+    fn test_history_scaled_across_rate_change() {
+        let storage = Storage::new(":memory:").unwrap();
+        // Rows from before any rate was recorded are unsampled.
+        storage.flush(&mut vec![packet(500, 100)]);
+        storage.record_sample_rate(1, 0).unwrap();
+        storage.record_sample_rate(10, 1_000).unwrap();
+        // Restarting at the same rate leaves no new boundary.
+        storage.record_sample_rate(10, 1_500).unwrap();
+        storage.record_sample_rate(4, 2_000).unwrap();
+        storage.flush(&mut vec![packet(1_200, 100), packet(1_800, 50), packet(2_500, 200)]);
+
+        let meta: i64 = storage
+            .conn
+            .lock()
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM capture_meta", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(meta, 2);
+
+        let rows = storage.query_history(10).unwrap();
+        let scales: Vec<(i64, u32, bool)> = rows
+            .iter()
+            .map(|r| (r.packet.timestamp, r.scale_factor, r.estimated))
+            .collect();
+        assert_eq!(
+            scales,
+            [(2_500, 4, true), (1_800, 10, true), (1_200, 10, true), (500, 1, false)]
+        );
+        let json = serde_json::to_value(&rows[0]).unwrap();
+        assert_eq!((json["length"].as_u64(), json["scale_factor"].as_u64()), (Some(200), Some(4)));
+
+        // 100 + 10 * (100 + 50) + 4 * 200 bytes; 1 + 10 * 2 + 4 rows.
+        assert_eq!(storage.estimated_totals(i64::MIN, i64::MAX).unwrap(), (25, 2_400));
+        assert_eq!(storage.estimated_totals(1_000, 2_000).unwrap(), (20, 1_500));
+        assert_eq!(storage.estimated_totals(3_000, 4_000).unwrap(), (0, 0));
+    }
+}
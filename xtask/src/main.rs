@@ -26,6 +26,9 @@ enum Cli {
         #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
         args: Vec<String>,
     },
+    /// Build the eBPF program and run the veth pipeline test as root.
+    /// Without root it re-runs the test binary with sudo, or skips.
+    IntegrationTest,
 }
 
 fn main() -> anyhow::Result<()> {
@@ -41,6 +44,10 @@ fn main() -> anyhow::Result<()> {
             build_userspace(release)?;
             run(release, &args)
         }
+        Cli::IntegrationTest => {
+            build_ebpf(false)?;
+            integration_test()
+        }
     }
 }
 
@@ -78,6 +85,50 @@ fn build_userspace(release: bool) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// The test binary is built as the invoking user, so only running it needs
+/// root.
+fn integration_test() -> anyhow::Result<()> {
+    let output = Command::new("cargo")
+        .args(["test", "-p", "ayaflow", "--features", "integration-test", "--no-run"])
+        .args(["--message-format", "json"])
+        .stderr(std::process::Stdio::inherit())
+        .output()
+        .context("failed to run cargo test")?;
+    anyhow::ensure!(output.status.success(), "building the integration test failed");
+    let executable = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .find_map(|message| message["executable"].as_str().map(str::to_string))
+        .context("cargo reported no test binary")?;
+
+    let args = ["integration::", "--test-threads=1", "--nocapture"];
+    let mut cmd = if is_root() {
+        Command::new(&executable)
+    } else {
+        let sudo_works = Command::new("sudo")
+            .args(["-v"])
+            .status()
+            .is_ok_and(|status| status.success());
+        if !sudo_works {
+            println!("Skipping integration tests: they need root and sudo is unavailable");
+            return Ok(());
+        }
+        let mut cmd = Command::new("sudo");
+        cmd.arg(&executable);
+        cmd
+    };
+    let status = cmd.args(args).status().context("failed to run the integration test")?;
+    anyhow::ensure!(status.success(), "integration test failed");
+    Ok(())
+}
+
+fn is_root() -> bool {
+    Command::new("id")
+        .arg("-u")
+        .output()
+        .is_ok_and(|output| String::from_utf8_lossy(&output.stdout).trim() == "0")
+}
+
 fn run(release: bool, extra_args: &[String]) -> anyhow::Result<()> {
     let profile = if release { "release" } else { "debug" };
     let bin = format!("target/{profile}/ayaflow");